@@ -0,0 +1,104 @@
+//! How much memory writing a huge season takes
+//!
+//! Run with `cargo bench --bench memory`.  The metadata and the season index are written from recordings that are made
+//! up one at a time, the way a season too big to load would be, and the peak of the heap is reported for each size.
+//! The metadata should take about the same at every size, and the index only what the page itself takes.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use cb_processor::{types::Recording, MetadataWriter, RecordingSummary};
+use support::{fake_tools_context, synthetic_season};
+
+/// The system allocator, keeping track of how much is allocated
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let now = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let ptr = System.realloc(ptr, layout, new_size);
+        if !ptr.is_null() {
+            if new_size > layout.size() {
+                let now = ALLOCATED.fetch_add(new_size - layout.size(), Ordering::Relaxed) + new_size - layout.size();
+                PEAK.fetch_max(now, Ordering::Relaxed);
+            } else {
+                ALLOCATED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+            }
+        }
+        ptr
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Runs `f`, returning how far above what was allocated before it the heap went, in KB
+fn peak_of(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    f();
+    (PEAK.load(Ordering::Relaxed) - before) / 1024
+}
+
+fn main() {
+    cb_processor::progress::enable_log(false);
+    let ctx = fake_tools_context();
+    let synthetic = synthetic_season(1, 8);
+    let template = synthetic.metadata.recordings[0].clone();
+    // the n-th recording of a season, made up when it's needed
+    let recording = |n: usize| Recording {
+        title: format!("S01E{:05} - Jam {}", n, n),
+        data_folder: format!("jam{:05}", n),
+        slug: format!("s01e{:05}-jam-{}", n, n),
+        ..template.clone()
+    };
+
+    for n in [1_000, 10_000, 100_000] {
+        let dir = tempfile::tempdir().unwrap();
+        let metadata = peak_of(|| {
+            let path = dir.path().join("metadata.json");
+            let mut writer = MetadataWriter::create(&path, "", "Synthetic Season", "Synthesizer").unwrap();
+            for i in 0..n {
+                writer.push(&recording(i)).unwrap();
+            }
+            writer.finish(&Default::default(), None, true).unwrap();
+        });
+        let output = dir.path().join("output");
+        let index = peak_of(|| {
+            cb_processor::write_season_index_of(
+                &ctx,
+                "Synthetic Season",
+                &[],
+                || (0..n).map(|i| RecordingSummary::of(&recording(i))),
+                &output,
+            )
+            .unwrap();
+        });
+        let page = std::fs::metadata(output.join("index.html")).unwrap().len() / 1024;
+        println!(
+            "{:>7} recordings: metadata.json peaked at {} KB, index.html at {} KB (the page is {} KB)",
+            n, metadata, index, page
+        );
+    }
+}
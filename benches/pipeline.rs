@@ -0,0 +1,100 @@
+//! Benchmarks for the parts of a publish run that don't depend on external tools
+//!
+//! Run with `cargo bench`.  The season is made up by tests/support, so it's the same shape the tests use.
+
+#[path = "../tests/support/mod.rs"]
+mod support;
+
+use std::{path::PathBuf, str::FromStr};
+
+use cb_processor::{
+    hasher::{Algorithm, HasherPool},
+    types::Season,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use support::{fake_ipfs, fake_tools_context, synthetic_season, FAKE_ROOT};
+
+fn load(c: &mut Criterion) {
+    let synthetic = synthetic_season(100, 8);
+    let ctx = fake_tools_context();
+    c.bench_function("load 100 recordings from metadata", |b| {
+        b.iter(|| Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap())
+    });
+}
+
+fn metadata(c: &mut Criterion) {
+    let synthetic = synthetic_season(100, 8);
+    c.bench_function("serialize metadata of 100 recordings", |b| {
+        b.iter(|| serde_json::to_vec_pretty(&synthetic.metadata).unwrap())
+    });
+}
+
+fn generate(c: &mut Criterion) {
+    let synthetic = synthetic_season(100, 8);
+    let ctx = fake_tools_context();
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let output = synthetic.dir.path().join("output");
+    c.bench_function("generate pages for 100 recordings", |b| {
+        b.iter(|| {
+            cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+            cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+        })
+    });
+}
+
+fn plan_patch(c: &mut Criterion) {
+    let synthetic = synthetic_season(20, 2);
+    let mut ctx = fake_tools_context();
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let output = synthetic.dir.path().join("output");
+    cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+
+    // half the recordings are already published
+    let published: Vec<String> = season.recordings[..10]
+        .iter()
+        .map(|r| format!("{}/", r.data_folder))
+        .chain(["index.html".to_string()])
+        .collect();
+    let published: Vec<&str> = published.iter().map(String::as_str).collect();
+    ctx.tools.ipfs = fake_ipfs(&synthetic.dir.path().join("ipfs"), &published);
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+
+    let mut group = c.benchmark_group("patch");
+    // every link runs the fake ipfs, so this is slow
+    group.sample_size(10);
+    group.bench_function("plan a patch for 20 recordings", |b| {
+        b.iter(|| cb_processor::ipfs::plan_patch(&ctx, &root, &output).unwrap())
+    });
+    group.finish();
+}
+
+fn hashing(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let files: Vec<PathBuf> = (0..20u8)
+        .map(|i| {
+            let path = dir.path().join(format!("{}.flac", i));
+            std::fs::write(&path, vec![i; 4 * 1024 * 1024]).unwrap();
+            path
+        })
+        .collect();
+    let ctx = fake_tools_context();
+
+    c.bench_function("hash 20 files for every consumer", |b| {
+        b.iter(|| {
+            // a fresh pool is a fresh run
+            let pool = HasherPool::default();
+            pool.digests_all(ctx.jobs, &files, &[Algorithm::Sha256, Algorithm::Xxh64]);
+            // conversion provenance, duplicates and the manifest all ask again
+            for _ in 0..3 {
+                for file in &files {
+                    pool.sha256(file).unwrap();
+                }
+            }
+            assert_eq!(pool.files_read(), files.len(), "a file was read more than once");
+        })
+    });
+}
+
+criterion_group!(benches, load, metadata, generate, plan_patch, hashing);
+criterion_main!(benches);
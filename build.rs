@@ -0,0 +1,20 @@
+use std::process::Command;
+
+fn main() {
+    // Record which commit this was built from.  When building from a tarball there's no .git, so fall back to "unknown"
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CB_GIT_HASH={}", hash);
+
+    if std::path::Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,460 @@
+//! Measurements that need the audio to be decoded
+//!
+//! These are slow (every flac has to be read in full), so they're only done when asked for, and the results are kept
+//! in the metadata file so that they only need to be done once per file.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::RunContext,
+    progress::{self, ProgressEvent, Stage},
+    types::{Season, Track},
+};
+
+/// EBU R128 loudness of a track
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoudnessInfo {
+    /// Integrated loudness, in LUFS
+    pub integrated: f64,
+    /// Loudness range, in LU
+    pub range: f64,
+    /// True peak, in dBFS
+    pub true_peak: f64,
+}
+
+/// Measures the loudness of a file with ffmpeg's ebur128 filter
+pub fn measure_loudness(ctx: &RunContext, path: &Path) -> anyhow::Result<LoudnessInfo> {
+    let output = Command::new(&ctx.tools.ffmpeg)
+        .arg("-nostats")
+        .arg("-i")
+        .arg(path)
+        .arg("-filter_complex")
+        .arg("ebur128=peak=true")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run ffmpeg on {}", path.display()))?;
+
+    if !output.status.success() {
+        bail!("ffmpeg returned {:?} for {}", output.status, path.display());
+    }
+
+    parse_ebur128_summary(&String::from_utf8_lossy(&output.stderr))
+        .with_context(|| format!("Failed to find the loudness summary for {}", path.display()))
+}
+
+/// Reads the numbers out of the summary that the ebur128 filter prints at the end
+fn parse_ebur128_summary(stderr: &str) -> anyhow::Result<LoudnessInfo> {
+    let summary = match stderr.rfind("Summary:") {
+        Some(idx) => &stderr[idx..],
+        None => bail!("No summary in ffmpeg output"),
+    };
+
+    let value = |label: &str| -> anyhow::Result<f64> {
+        for line in summary.lines() {
+            let line = line.trim();
+            if let Some(rest) = line.strip_prefix(label) {
+                if let Some(number) = rest.split_whitespace().next() {
+                    return Ok(number.parse()?);
+                }
+            }
+        }
+        bail!("No {:?} in summary", label)
+    };
+
+    Ok(LoudnessInfo {
+        integrated: value("I:")?,
+        range: value("LRA:")?,
+        true_peak: value("Peak:")?,
+    })
+}
+
+/// Runs `f` on every item, using up to `jobs` threads
+pub(crate) fn parallel_for_each<T: Send>(jobs: usize, items: Vec<T>, f: impl Fn(T) + Sync) {
+    let queue = Mutex::new(items.into_iter());
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| loop {
+                // take the lock just long enough to get the next item
+                let item = queue.lock().unwrap().next();
+                match item {
+                    Some(item) => f(item),
+                    None => break,
+                }
+            });
+        }
+    });
+}
+
+fn all_tracks_mut(season: &mut Season) -> impl Iterator<Item = (&str, &mut Track)> {
+    season.recordings.iter_mut().flat_map(|rec| {
+        let folder = rec.data_folder.as_str();
+        std::iter::once(&mut rec.stereo_mix)
+            .chain(rec.tracks.iter_mut())
+            .map(move |t| (folder, t))
+    })
+}
+
+/// Copies loudness measurements from an older copy of the season, for all the flacs that haven't changed
+pub fn carry_over_loudness(season: &mut Season, cached: &Season) {
+    for (folder, track) in all_tracks_mut(season) {
+        let old = cached
+            .recordings
+            .iter()
+            .filter(|r| r.data_folder == folder)
+            .flat_map(|r| std::iter::once(&r.stereo_mix).chain(&r.tracks))
+            .find(|t| t.id == track.id && t.flac == track.flac && t.flac_bytes == track.flac_bytes);
+        if let Some(old) = old {
+            if track.loudness.is_none() {
+                track.loudness = old.loudness.clone();
+            }
+        }
+    }
+}
+
+/// Measures the loudness of every selected track that doesn't have a measurement yet
+///
+/// Up to `ctx.jobs` files are measured at once.  Returns the number of files that were measured.
+pub fn measure_missing_loudness(ctx: &RunContext, season: &mut Season) -> anyhow::Result<usize> {
+    ctx.stage(Stage::Analyze, || {
+        let selected: Vec<String> = ctx.only.select(season)?.iter().map(|r| r.data_folder.clone()).collect();
+
+        let todo: Vec<&mut Track> = all_tracks_mut(season)
+            .filter(|(folder, track)| track.loudness.is_none() && selected.iter().any(|s| s == folder))
+            .map(|(_, track)| track)
+            .collect();
+        let total = todo.len();
+
+        let done = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+
+        parallel_for_each(ctx.jobs, todo, |track| {
+            let flac = match track.flac_ondisk() {
+                Some(flac) => flac,
+                None => return,
+            };
+            let start = Instant::now();
+            match measure_loudness(ctx, &flac) {
+                Ok(loudness) => track.loudness = Some(loudness),
+                Err(e) => errors.lock().unwrap().push(e),
+            }
+            ctx.timings
+                .record_item(Stage::Analyze, flac.display().to_string(), start.elapsed());
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Analyze,
+                item: flac.display().to_string(),
+                index: done.fetch_add(1, Ordering::SeqCst) + 1,
+                total,
+            });
+        });
+
+        let errors = errors.into_inner().unwrap();
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Analyze,
+            processed: total,
+            errors: errors.len(),
+            warnings: 0,
+        });
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+        Ok(total)
+    })
+}
+
+/// Tracks with more than this many samples at 0 dBFS are reported as clipped
+pub const CLIPPED_SAMPLES_THRESHOLD: u64 = 10;
+
+/// Tracks that are below this level ...
+pub const SILENCE_DB: f64 = -60.0;
+
+/// ... for more than this fraction of their length are reported as silent
+pub const SILENT_FRACTION: f64 = 0.95;
+
+/// Where a track clips, and where it is silent
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClipSilenceReport {
+    /// Total number of samples at 0 dBFS
+    pub clipped_samples: u64,
+    /// Start of each (roughly one second) window that has samples at 0 dBFS
+    pub clipped_at: Vec<f64>,
+    /// (start, end) of each silent stretch, in seconds
+    pub silences: Vec<(f64, f64)>,
+}
+
+impl ClipSilenceReport {
+    pub fn silent_secs(&self) -> f64 {
+        self.silences.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Human-readable problems with this track (if any)
+    pub fn warnings(&self, duration: f64) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.clipped_samples > CLIPPED_SAMPLES_THRESHOLD {
+            warnings.push(format!(
+                "{} samples at 0 dBFS, at {}",
+                self.clipped_samples,
+                format_times(self.clipped_at.iter().copied())
+            ));
+        }
+        if duration > 0.0 && self.silent_secs() > duration * SILENT_FRACTION {
+            warnings.push(format!(
+                "silent (below {} dBFS) for {:.0}% of the track, from {}",
+                SILENCE_DB,
+                self.silent_secs() / duration * 100.0,
+                format_times(self.silences.iter().map(|(start, _)| *start))
+            ));
+        }
+        warnings
+    }
+}
+
+/// Formats a list of times as m:ss, only showing the first few
+fn format_times(times: impl Iterator<Item = f64>) -> String {
+    const MAX_SHOWN: usize = 5;
+    let times: Vec<f64> = times.collect();
+    let mut shown: Vec<String> = times
+        .iter()
+        .take(MAX_SHOWN)
+        .map(|t| format!("{}:{:02}", (*t as u64) / 60, (*t as u64) % 60))
+        .collect();
+    if times.len() > MAX_SHOWN {
+        shown.push(format!("and {} more", times.len() - MAX_SHOWN));
+    }
+    shown.join(", ")
+}
+
+/// Decodes a file with ffmpeg, looking for clipping and silence
+pub fn find_clipping_and_silence(ctx: &RunContext, path: &Path, sample_rate: u32) -> anyhow::Result<ClipSilenceReport> {
+    // astats is reset about once a second, so that we know when the clipping happens
+    let filter = format!(
+        "asetnsamples=n={},astats=metadata=1:reset=1:measure_perchannel=none:measure_overall=Peak_level+Peak_count,\
+         ametadata=print:file=-,silencedetect=noise={}dB:d=1",
+        sample_rate.max(1),
+        SILENCE_DB
+    );
+    let output = Command::new(&ctx.tools.ffmpeg)
+        .arg("-nostats")
+        .arg("-i")
+        .arg(path)
+        .arg("-af")
+        .arg(filter)
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run ffmpeg on {}", path.display()))?;
+
+    if !output.status.success() {
+        bail!("ffmpeg returned {:?} for {}", output.status, path.display());
+    }
+
+    let (clipped_samples, clipped_at) = parse_astats(&String::from_utf8_lossy(&output.stdout));
+    Ok(ClipSilenceReport {
+        clipped_samples,
+        clipped_at,
+        silences: parse_silencedetect(&String::from_utf8_lossy(&output.stderr)),
+    })
+}
+
+/// Finds the windows with samples at 0 dBFS in the output of ametadata=print
+fn parse_astats(stdout: &str) -> (u64, Vec<f64>) {
+    let mut clipped_samples = 0;
+    let mut clipped_at = Vec::new();
+    let mut time = 0.0;
+    let mut at_full_scale = false;
+    for line in stdout.lines() {
+        let line = line.trim();
+        if line.starts_with("frame:") {
+            if let Some(t) = line.split("pts_time:").nth(1).and_then(|t| t.trim().parse().ok()) {
+                time = t;
+            }
+            at_full_scale = false;
+        } else if let Some(level) = line.strip_prefix("lavfi.astats.Overall.Peak_level=") {
+            at_full_scale = level.parse::<f64>().is_ok_and(|l| l >= -0.0001);
+        } else if let Some(count) = line.strip_prefix("lavfi.astats.Overall.Peak_count=") {
+            if at_full_scale {
+                clipped_samples += count.parse::<f64>().unwrap_or(0.0) as u64;
+                clipped_at.push(time);
+            }
+        }
+    }
+    (clipped_samples, clipped_at)
+}
+
+/// Finds the silent stretches in the log output of silencedetect
+fn parse_silencedetect(stderr: &str) -> Vec<(f64, f64)> {
+    let number_after =
+        |line: &str, label: &str| -> Option<f64> { line.split(label).nth(1)?.split_whitespace().next()?.parse().ok() };
+    let mut silences = Vec::new();
+    let mut start = None;
+    for line in stderr.lines() {
+        if let Some(s) = number_after(line, "silence_start:") {
+            start = Some(s);
+        } else if let Some(end) = number_after(line, "silence_end:") {
+            silences.push((start.take().unwrap_or(0.0), end));
+        }
+    }
+    // a file that ends in silence doesn't get a silence_end
+    if let Some(start) = start {
+        silences.push((start, f64::INFINITY));
+    }
+    silences
+}
+
+/// Checks every selected track for clipping and silence, reporting problems as warnings
+///
+/// Returns the number of warnings.  These don't fail validation, because some recordings really are that loud.
+pub fn analyze_season(ctx: &RunContext, season: &Season) -> anyhow::Result<usize> {
+    ctx.stage(Stage::Analyze, || {
+        let mut todo = Vec::new();
+        for rec in ctx.only.select(season)? {
+            for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+                if let Some(flac) = track.flac_ondisk() {
+                    todo.push((track, flac));
+                }
+            }
+        }
+        let total = todo.len();
+
+        let done = AtomicUsize::new(0);
+        let warnings = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+
+        parallel_for_each(ctx.jobs, todo, |(track, flac)| {
+            let start = Instant::now();
+            let sample_rate = track.media_info.sample_rate().unwrap_or(48000);
+            match find_clipping_and_silence(ctx, &flac, sample_rate) {
+                Ok(report) => {
+                    // silences that run to the end of the file end at the end of the track
+                    let duration = track.media_info.duration_secs().unwrap_or(0.0);
+                    let mut report = report;
+                    for silence in &mut report.silences {
+                        silence.1 = silence.1.min(duration);
+                    }
+                    for warning in report.warnings(duration) {
+                        warnings.fetch_add(1, Ordering::SeqCst);
+                        say!("Warning: {}: {}", flac.display(), warning);
+                        progress::emit(ProgressEvent::Warning {
+                            stage: Stage::Analyze,
+                            message: format!("{}: {}", flac.display(), warning),
+                        });
+                    }
+                }
+                Err(e) => errors.lock().unwrap().push(e),
+            }
+            ctx.timings
+                .record_item(Stage::Analyze, flac.display().to_string(), start.elapsed());
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Analyze,
+                item: flac.display().to_string(),
+                index: done.fetch_add(1, Ordering::SeqCst) + 1,
+                total,
+            });
+        });
+
+        let errors = errors.into_inner().unwrap();
+        let warnings = warnings.into_inner();
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Analyze,
+            processed: total,
+            errors: errors.len(),
+            warnings,
+        });
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+        Ok(warnings)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ebur128_summary() {
+        let stderr = "\
+[Parsed_ebur128_0 @ 0x55d5c4d0a940] t: 2.0        TARGET:-23 LUFS    M: -20.1 S:-120.7     I: -20.1 LUFS
+[Parsed_ebur128_0 @ 0x55d5c4d0a940] Summary:
+
+  Integrated loudness:
+    I:         -19.4 LUFS
+    Threshold: -29.6 LUFS
+
+  Loudness range:
+    LRA:         5.5 LU
+    Threshold: -39.6 LUFS
+    LRA low:   -22.9 LUFS
+    LRA high:  -17.4 LUFS
+
+  True peak:
+    Peak:       -0.5 dBFS
+";
+        assert_eq!(
+            parse_ebur128_summary(stderr).unwrap(),
+            LoudnessInfo {
+                integrated: -19.4,
+                range: 5.5,
+                true_peak: -0.5,
+            }
+        );
+        assert!(parse_ebur128_summary("no summary here").is_err());
+    }
+
+    #[test]
+    fn clipping_and_silence() {
+        let stdout = "\
+frame:0    pts:0       pts_time:0
+lavfi.astats.Overall.Peak_level=-3.100000
+lavfi.astats.Overall.Peak_count=2.000000
+frame:1    pts:48000   pts_time:1
+lavfi.astats.Overall.Peak_level=0.000000
+lavfi.astats.Overall.Peak_count=40.000000
+frame:2    pts:96000   pts_time:62
+lavfi.astats.Overall.Peak_level=0.000000
+lavfi.astats.Overall.Peak_count=3.000000
+";
+        assert_eq!(parse_astats(stdout), (43, vec![1.0, 62.0]));
+
+        let stderr = "\
+[silencedetect @ 0x5581] silence_start: 0.5
+[silencedetect @ 0x5581] silence_end: 50 | silence_duration: 49.5
+[silencedetect @ 0x5581] silence_start: 52
+";
+        let silences = parse_silencedetect(stderr);
+        assert_eq!(silences, vec![(0.5, 50.0), (52.0, f64::INFINITY)]);
+
+        let report = ClipSilenceReport {
+            clipped_samples: 43,
+            clipped_at: vec![1.0, 62.0],
+            silences: vec![(0.5, 50.0), (52.0, 100.0)],
+        };
+        let warnings = report.warnings(100.0);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0], "43 samples at 0 dBFS, at 0:01, 1:02");
+        assert!(warnings[1].starts_with("silent (below -60 dBFS) for 98% of the track, from 0:00, 0:52"));
+
+        // a few clipped samples, and a short silence, are fine
+        let report = ClipSilenceReport {
+            clipped_samples: 3,
+            clipped_at: vec![62.0],
+            silences: vec![(0.0, 5.0)],
+        };
+        assert!(report.warnings(100.0).is_empty());
+    }
+}
@@ -0,0 +1,349 @@
+//! Uploading stereo mixes to archive.org (`--archive-upload`)
+//!
+//! archive.org is a second permanent home for the stereo mixes, next to IPFS.  Each recording becomes an item, created
+//! through the S3-like IAS3 API by uploading its flac (and mp3, if there is one) with the item metadata in
+//! `x-archive-meta-*` headers.  IAS3 throttles a lot, so every request is retried with a growing delay.
+
+use std::{fs::File, path::Path, thread, time::Duration};
+
+use crate::{
+    context::{ArchiveOrgSettings, RunContext},
+    error::CbError,
+    progress::{self, ProgressEvent, Stage},
+    slug,
+    types::{Recording, Season},
+};
+
+const IAS3_ENDPOINT: &str = "https://s3.us.archive.org";
+const MAX_ATTEMPTS: u32 = 6;
+
+/// IAS3 keys, from https://archive.org/account/s3.php
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't print the secret in error messages
+        f.debug_struct("Credentials")
+            .field("access_key", &self.access_key)
+            .finish()
+    }
+}
+
+impl Credentials {
+    /// Reads the keys from `IAS3_ACCESS_KEY` and `IAS3_SECRET_KEY`
+    pub fn from_env() -> Option<Credentials> {
+        let access_key = std::env::var("IAS3_ACCESS_KEY").ok().filter(|k| !k.is_empty())?;
+        let secret_key = std::env::var("IAS3_SECRET_KEY").ok().filter(|k| !k.is_empty())?;
+        Some(Credentials { access_key, secret_key })
+    }
+}
+
+/// The archive.org identifier for a recording
+///
+/// Identifiers may only contain letters, digits, `-`, `_` and `.`, so anything else in the data_folder becomes `-`.
+pub fn identifier(settings: &ArchiveOrgSettings, data_folder: &str) -> String {
+    let folder: String = data_folder
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{}{}", settings.identifier_prefix, folder)
+}
+
+/// The page of an item
+pub fn item_url(identifier: &str) -> String {
+    format!("https://archive.org/details/{}", identifier)
+}
+
+/// Header values must be ASCII, so IAS3 accepts `uri(...)` with the value percent-encoded inside
+fn header_value(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return value.to_string();
+    }
+    let mut encoded = String::from("uri(");
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~ ".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded.push(')');
+    encoded
+}
+
+/// The `x-archive-*` headers that create the item for `recording`
+fn item_headers(settings: &ArchiveOrgSettings, recording: &Recording) -> Vec<(String, String)> {
+    let mut headers = vec![
+        ("x-archive-auto-make-bucket".to_string(), "1".to_string()),
+        ("x-archive-meta-mediatype".to_string(), "audio".to_string()),
+        ("x-archive-meta-collection".to_string(), settings.collection.clone()),
+        ("x-archive-meta-title".to_string(), header_value(&recording.title)),
+        (
+            "x-archive-meta-date".to_string(),
+            header_value(&recording.recorded_date),
+        ),
+    ];
+    if let Some(license) = &settings.license_url {
+        headers.push(("x-archive-meta-licenseurl".to_string(), header_value(license)));
+    }
+    // repeated fields are numbered
+    for (i, tag) in recording.tags.iter().enumerate() {
+        headers.push((
+            format!("x-archive-meta{:02}-subject", i + 1),
+            header_value(&tag.display),
+        ));
+    }
+    headers
+}
+
+/// How long to wait before trying again, after `attempt` (counting from 1) failed
+///
+/// If the server said how long to wait (in Retry-After), that's used instead, within reason.
+fn retry_delay(attempt: u32, retry_after: Option<&str>) -> Duration {
+    if let Some(secs) = retry_after.and_then(|r| r.trim().parse::<u64>().ok()) {
+        return Duration::from_secs(secs.min(600));
+    }
+    Duration::from_secs(5 * 2u64.pow(attempt.saturating_sub(1).min(6)))
+}
+
+/// The IAS3 URL of the file called `name` in the item
+///
+/// Both are percent-encoded, since a `#` or `?` in a file name would otherwise end the path.
+fn file_url(identifier: &str, name: &str) -> Result<reqwest::Url, CbError> {
+    reqwest::Url::parse(&format!(
+        "{}/{}/{}",
+        IAS3_ENDPOINT,
+        slug::encode_path_segment(identifier),
+        slug::encode_path_segment(name)
+    ))
+    .map_err(|e| CbError::parse(format!("Can't make an IAS3 URL for {}", name), e))
+}
+
+/// PUTs `file` into the item, retrying when IAS3 is busy
+fn put_file(
+    client: &reqwest::blocking::Client, credentials: &Credentials, identifier: &str, file: &Path,
+    headers: &[(String, String)],
+) -> Result<(), CbError> {
+    let name = file
+        .file_name()
+        .ok_or_else(|| CbError::MissingFile { path: file.to_owned() })?
+        .to_string_lossy();
+    let url = file_url(identifier, &name)?;
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let body = File::open(file).map_err(|e| CbError::io(format!("Failed to open {}", file.display()), e))?;
+        let size = body
+            .metadata()
+            .map_err(|e| CbError::io(format!("Failed to read {}", file.display()), e))?
+            .len();
+        let mut request = client
+            .put(url.clone())
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("LOW {}:{}", credentials.access_key, credentials.secret_key),
+            )
+            .header("x-archive-size-hint", size.to_string())
+            .body(reqwest::blocking::Body::sized(body, size));
+        for (name, value) in headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        let (retry_after, error) = match request.send() {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) => {
+                let status = resp.status();
+                let retry_after = resp
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                let error = CbError::tool("archive.org", format!("{} returned {}", url, status));
+                // anything other than "slow down" won't get better by waiting
+                if status != reqwest::StatusCode::SERVICE_UNAVAILABLE
+                    && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+                {
+                    return Err(error);
+                }
+                (retry_after, error)
+            }
+            Err(e) => (None, CbError::tool("archive.org", e)),
+        };
+        if attempt >= MAX_ATTEMPTS {
+            return Err(error);
+        }
+        let delay = retry_delay(attempt, retry_after.as_deref());
+        progress::emit(ProgressEvent::Warning {
+            stage: Stage::Archive,
+            message: format!("{} (attempt {}), retrying in {:?}", error, attempt, delay),
+        });
+        thread::sleep(delay);
+    }
+}
+
+/// Uploads the stereo mix of `recording` (flac, and mp3 if there is one) to its own item, returning the item's URL
+pub fn archive_org_upload(
+    ctx: &RunContext, recording: &Recording, credentials: &Credentials,
+) -> Result<String, CbError> {
+    let flac = recording.stereo_mix.flac_ondisk().ok_or_else(|| CbError::MissingFile {
+        path: recording.stereo_mix.flac.clone().into(),
+    })?;
+    let mut files = vec![flac];
+    files.extend(recording.stereo_mix.mp3_ondisk().filter(|mp3| mp3.exists()));
+
+    let identifier = identifier(&ctx.archive_org, &recording.data_folder);
+    let headers = item_headers(&ctx.archive_org, recording);
+    let client = ctx.http_client("archive.org", Duration::from_secs(60 * 60))?;
+    for file in &files {
+        if !file.exists() {
+            return Err(CbError::MissingFile { path: file.clone() });
+        }
+        put_file(&client, credentials, &identifier, file, &headers)?;
+    }
+    Ok(item_url(&identifier))
+}
+
+/// Uploads every selected recording that doesn't have an archive.org URL yet, and records the URLs in `season`
+///
+/// A recording that fails doesn't stop the others (so that the URLs of the ones that worked can still be saved), but
+/// the first error is returned at the end.  Returns the number of recordings uploaded.
+pub fn upload_missing(ctx: &RunContext, season: &mut Season, credentials: &Credentials) -> Result<usize, CbError> {
+    ctx.stage(Stage::Archive, || {
+        let selected: Vec<String> = ctx
+            .only
+            .select(season)?
+            .into_iter()
+            .filter(|rec| rec.archive_org_url.is_none())
+            .map(|rec| rec.data_folder.clone())
+            .collect();
+        let total = selected.len();
+
+        let mut uploaded = 0;
+        let mut errors = Vec::new();
+        for (index, folder) in selected.iter().enumerate() {
+            let rec = season
+                .recordings
+                .iter_mut()
+                .find(|r| &r.data_folder == folder)
+                .expect("selected from this season");
+            say!("Uploading {} to archive.org...", rec.title);
+            match archive_org_upload(ctx, rec, credentials) {
+                Ok(url) => {
+                    say!("  {}", url);
+                    rec.archive_org_url = Some(url);
+                    uploaded += 1;
+                }
+                Err(e) => {
+                    progress::emit(ProgressEvent::Error {
+                        stage: Stage::Archive,
+                        message: format!("{}: {}", folder, e),
+                    });
+                    errors.push(e);
+                }
+            }
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Archive,
+                item: folder.clone(),
+                index: index + 1,
+                total,
+            });
+        }
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Archive,
+            processed: total,
+            errors: errors.len(),
+            warnings: 0,
+        });
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(uploaded),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifiers() {
+        let settings = ArchiveOrgSettings::default();
+        assert_eq!(identifier(&settings, "S01E03 jam"), "benderfactory-S01E03-jam");
+        assert_eq!(
+            item_url(&identifier(&settings, "jam1")),
+            "https://archive.org/details/benderfactory-jam1"
+        );
+        assert_eq!(header_value("Morning Jam"), "Morning Jam");
+        assert_eq!(header_value("Café"), "uri(Caf%C3%A9)");
+    }
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(
+            file_url("benderfactory-jam1", "jam1_stereo.flac").unwrap().as_str(),
+            "https://s3.us.archive.org/benderfactory-jam1/jam1_stereo.flac"
+        );
+        // a fragment or a query would cut the name short
+        assert_eq!(
+            file_url("benderfactory-jam1", "Take #2?.flac").unwrap().as_str(),
+            "https://s3.us.archive.org/benderfactory-jam1/Take%20%232%3F.flac"
+        );
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_delay(1, None), Duration::from_secs(5));
+        assert_eq!(retry_delay(3, None), Duration::from_secs(20));
+        assert_eq!(retry_delay(100, None), Duration::from_secs(320));
+        assert_eq!(retry_delay(1, Some("30")), Duration::from_secs(30));
+        assert_eq!(
+            retry_delay(2, Some("Wed, 21 Oct 2015 07:28:00 GMT")),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn headers() {
+        let recording: Recording = serde_json::from_value(serde_json::json!({
+            "title": "Jam 1",
+            "data_folder": "jam1",
+            "recorded_date": "2021-03-04",
+            "torrent": null,
+            "tracks": [],
+            "tags": ["techno", "modular"],
+            "bpm": null,
+            "youtube_url": null,
+            "stereo_mix": {
+                "id": 0, "name": "Stereo", "flac": "jam1.flac", "vorbis": "jam1.ogg", "mp3": null,
+                "patch_notes": null, "ondisk_root": null, "flac_bytes": 1, "ogg_bytes": 1, "mp3_bytes": 0,
+                "media_info": {"@type": "Audio", "Format": "FLAC", "Channels": "2", "SamplingRate": "48000", "Duration": "60"}
+            }
+        }))
+        .unwrap();
+        let settings = ArchiveOrgSettings {
+            license_url: Some("https://creativecommons.org/licenses/by-nc-sa/4.0/".to_string()),
+            ..Default::default()
+        };
+        let headers = item_headers(&settings, &recording);
+        let get = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        assert_eq!(get("x-archive-meta-title"), Some("Jam 1"));
+        assert_eq!(get("x-archive-meta-date"), Some("2021-03-04"));
+        assert_eq!(
+            get("x-archive-meta-licenseurl"),
+            Some("https://creativecommons.org/licenses/by-nc-sa/4.0/")
+        );
+        assert_eq!(get("x-archive-meta01-subject"), Some("techno"));
+        assert_eq!(get("x-archive-meta02-subject"), Some("modular"));
+    }
+}
@@ -0,0 +1,122 @@
+//! Names for the stylesheets and scripts that change with their contents
+//!
+//! Gateways and browsers keep a file they've seen for as long as its path is the same, so a new `style.css` can take a
+//! while to reach anyone.  With `hashed_assets` on, each `.css` and `.js` file in the static dir is also copied as
+//! `style.3f9a2c.css` (with the start of its SHA-256), and the pages link to that copy.  The plain copy is still
+//! written, for anything else that links to it.
+
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{context::RunContext, error::CbError, hasher::HasherPool, listing};
+
+/// How many hex digits of the SHA-256 go in a name
+pub const HASH_LEN: usize = 6;
+
+/// Which files get a hashed copy
+const HASHED_EXTENSIONS: &[&str] = &["css", "js"];
+
+/// The hashed name of each static file that has one, by its path in the static dir (like `css/all.css`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetMap {
+    names: BTreeMap<String, String>,
+}
+
+/// `css/all.css` with a SHA-256 starting with `3f9a2c` becomes `css/all.3f9a2c.css`
+pub fn hashed_name(path: &str, sha256: &str) -> String {
+    let hash = &sha256[..HASH_LEN.min(sha256.len())];
+    let (dir, file) = match path.rfind('/') {
+        Some(idx) => path.split_at(idx + 1),
+        None => ("", path),
+    };
+    match file.rfind('.') {
+        Some(idx) if idx > 0 => format!("{}{}.{}{}", dir, &file[..idx], hash, &file[idx..]),
+        _ => format!("{}{}.{}", dir, file, hash),
+    }
+}
+
+impl AssetMap {
+    /// The hashed names of the files in `ctx.static_dir`, or no names at all unless `ctx.hashed_assets` is on
+    pub fn of_static_dir(ctx: &RunContext) -> Result<AssetMap, CbError> {
+        let mut map = AssetMap::default();
+        if ctx.hashed_assets {
+            map.add_dir(&ctx.hashes, &ctx.static_dir, &ctx.static_dir)?;
+        }
+        Ok(map)
+    }
+
+    fn add_dir(&mut self, hashes: &HasherPool, static_dir: &Path, dir: &Path) -> Result<(), CbError> {
+        let entries = listing::sorted(dir).map_err(|e| CbError::io(format!("Failed to read {}", dir.display()), e))?;
+        for path in entries.iter().map(|e| e.path()) {
+            if path.is_dir() {
+                self.add_dir(hashes, static_dir, &path)?;
+                continue;
+            }
+            let hashed = path
+                .extension()
+                .is_some_and(|ext| HASHED_EXTENSIONS.iter().any(|h| ext.eq_ignore_ascii_case(h)));
+            if let (true, Ok(rel)) = (hashed, path.strip_prefix(static_dir)) {
+                let rel = rel
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let name = hashed_name(&rel, &hashes.sha256(&path)?);
+                self.names.insert(rel, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// What a page should link to for the static file `path`: its hashed name if it has one, otherwise `path`
+    pub fn url<'a>(&'a self, path: &'a str) -> &'a str {
+        self.names.get(path).map_or(path, String::as_str)
+    }
+
+    /// The hashed name of `path`, if it has one
+    pub fn get(&self, path: &str) -> Option<&str> {
+        self.names.get(path).map(String::as_str)
+    }
+
+    /// Every file with a hashed name, and that name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.names.iter().map(|(path, name)| (path.as_str(), name.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(hashed_name("style.css", "3f9a2c00ff"), "style.3f9a2c.css");
+        assert_eq!(hashed_name("css/all.min.css", "3f9a2c00ff"), "css/all.min.3f9a2c.css");
+        assert_eq!(hashed_name("css/LICENSE", "3f9a2c00ff"), "css/LICENSE.3f9a2c");
+        assert_eq!(hashed_name(".hidden", "3f9a2c00ff"), ".hidden.3f9a2c");
+    }
+
+    #[test]
+    fn static_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("css")).unwrap();
+        std::fs::write(dir.path().join("style.css"), "body {}").unwrap();
+        std::fs::write(dir.path().join("css/all.css"), "p {}").unwrap();
+        std::fs::write(dir.path().join("ToS.txt"), "terms").unwrap();
+
+        let mut ctx = RunContext {
+            static_dir: dir.path().to_path_buf(),
+            ..RunContext::default()
+        };
+        assert_eq!(AssetMap::of_static_dir(&ctx).unwrap(), AssetMap::default());
+
+        ctx.hashed_assets = true;
+        let map = AssetMap::of_static_dir(&ctx).unwrap();
+        let style = hashed_name("style.css", &crate::checksum::sha256_bytes(b"body {}"));
+        assert_eq!(map.url("style.css"), style);
+        assert!(map.url("css/all.css").starts_with("css/all."));
+        assert_eq!(map.url("ToS.txt"), "ToS.txt");
+        assert_eq!(map.iter().count(), 2);
+        // the same files always get the same names
+        assert_eq!(AssetMap::of_static_dir(&ctx).unwrap(), map);
+    }
+}
@@ -0,0 +1,40 @@
+//! Copies of our static files and schemas that are built into the binary
+//!
+//! These are used by `init` so that a new season can be created without a checkout of this repo.
+
+/// Everything in the `static/` folder, as (relative path, contents)
+pub static STATIC_FILES: &[(&str, &[u8])] = &[
+    ("ToS.txt", include_bytes!("../static/ToS.txt")),
+    ("css/all.css", include_bytes!("../static/css/all.css")),
+    ("style.css", include_bytes!("../static/style.css")),
+    (
+        "webfonts/MavenPro-Medium.woff2",
+        include_bytes!("../static/webfonts/MavenPro-Medium.woff2"),
+    ),
+    (
+        "webfonts/fa-solid-900.eot",
+        include_bytes!("../static/webfonts/fa-solid-900.eot"),
+    ),
+    (
+        "webfonts/fa-solid-900.svg",
+        include_bytes!("../static/webfonts/fa-solid-900.svg"),
+    ),
+    (
+        "webfonts/fa-solid-900.ttf",
+        include_bytes!("../static/webfonts/fa-solid-900.ttf"),
+    ),
+    (
+        "webfonts/fa-solid-900.woff",
+        include_bytes!("../static/webfonts/fa-solid-900.woff"),
+    ),
+    (
+        "webfonts/fa-solid-900.woff2",
+        include_bytes!("../static/webfonts/fa-solid-900.woff2"),
+    ),
+];
+
+pub const SEASON_SCHEMA: &str = include_str!("../data/schema/season.json");
+pub const RECORDING_SCHEMA: &str = include_str!("../data/schema/recording.json");
+
+/// The default config file, with every option commented out
+pub const DEFAULT_CONFIG: &str = include_str!("../cb_processor.toml");
@@ -0,0 +1,132 @@
+//! Badges with the season's totals, for linking to from READMEs and merge requests (`badges/` in the output)
+//!
+//! They look like the ones from shields.io, but they're plain SVG files that are published with the pages, so they
+//! can be linked through any gateway without depending on another service.  Not to be confused with the
+//! [completeness badges](crate::completeness) on the season index.
+
+use crate::types::{Season, Track};
+
+/// The folder of the output the badges are written to
+pub const BADGES_DIR: &str = "badges";
+
+/// The colour of the value half of every badge
+const COLOR: &str = "#007ec6";
+
+/// What the badges show
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub recordings: usize,
+    /// The length of the stereo mixes, where mediainfo could tell
+    pub seconds: u64,
+    /// The size of every flac, ogg, mp3 and opus that's published
+    pub bytes: u64,
+}
+
+impl Totals {
+    pub fn of(season: &Season) -> Totals {
+        let mut totals = Totals {
+            recordings: season.recordings.len(),
+            ..Totals::default()
+        };
+        for recording in &season.recordings {
+            if let Ok(secs) = recording.stereo_mix.media_info.duration_secs() {
+                totals.seconds += secs.floor() as u64;
+            }
+            totals.bytes += std::iter::once(&recording.stereo_mix)
+                .chain(&recording.tracks)
+                .map(published_bytes)
+                .sum::<u64>();
+        }
+        totals
+    }
+
+    /// Each badge, with the name of its file in [`BADGES_DIR`]
+    pub fn badges(&self) -> Vec<(&'static str, Badge)> {
+        vec![
+            ("recordings.svg", Badge::new("recordings", self.recordings.to_string())),
+            (
+                "hours.svg",
+                Badge::new("hours", crate::one_decimal(self.seconds, 60 * 60)),
+            ),
+            ("size.svg", Badge::new("size", crate::human_size(self.bytes))),
+        ]
+    }
+}
+
+fn published_bytes(track: &Track) -> u64 {
+    track.flac_size_bytes() + track.ogg_size_bytes() + track.mp3_size_bytes() + track.opus_size_bytes()
+}
+
+/// A label and a value, side by side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Badge {
+    pub label: String,
+    pub value: String,
+}
+
+impl Badge {
+    pub fn new(label: impl Into<String>, value: impl Into<String>) -> Badge {
+        Badge {
+            label: label.into(),
+            value: value.into(),
+        }
+    }
+
+    /// The badge as a self-contained SVG
+    ///
+    /// The widths are estimated from the number of characters rather than measured, so that the same totals always
+    /// give the same file.
+    pub fn svg(&self) -> String {
+        let label_width = text_width(&self.label);
+        let value_width = text_width(&self.value);
+        let width = label_width + value_width;
+        let label = crate::markdown::escape(&self.label);
+        let value = crate::markdown::escape(&self.value);
+        let mut svg = format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {value}">
+<title>{label}: {value}</title>
+<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>
+<clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath>
+<g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{value_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g>
+<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">
+"##,
+            width = width,
+            label_width = label_width,
+            value_width = value_width,
+            label = label,
+            value = value,
+            color = COLOR,
+        );
+        for (x, text) in [(label_width / 2, &label), (label_width + value_width / 2, &value)] {
+            svg.push_str(&format!(
+                "<text x=\"{x}\" y=\"15\" fill=\"#010101\" fill-opacity=\".3\">{text}</text><text x=\"{x}\" y=\"14\">{text}</text>\n",
+                x = x,
+                text = text
+            ));
+        }
+        svg.push_str("</g>\n</svg>\n");
+        svg
+    }
+}
+
+/// The width of one half of a badge, from about 7 pixels a character and some padding
+fn text_width(text: &str) -> usize {
+    text.chars().count() * 7 + 10
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg() {
+        let svg = Badge::new("size", "1.5GB").svg();
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="83" height="20""#));
+        assert!(svg.contains("<title>size: 1.5GB</title>"));
+        assert!(svg.contains(r#"<text x="19" y="14">size</text>"#));
+        assert!(svg.contains(r#"<text x="60" y="14">1.5GB</text>"#));
+
+        let svg = Badge::new("a<b", "\"c\"").svg();
+        assert!(svg.contains("<title>a&lt;b: &quot;c&quot;</title>"));
+    }
+}
@@ -0,0 +1,196 @@
+//! Checksums of the files in the data dir
+
+use std::{convert::TryInto, fs::File, io::Read, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::CbError;
+
+/// The SHA-256 of a file, as lowercase hex
+pub fn sha256_file(path: &Path) -> Result<String, CbError> {
+    let mut file = File::open(path).map_err(|e| CbError::io(format!("Failed to open {}", path.display()), e))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1024 * 1024];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The SHA-256 of something that's about to be written, as lowercase hex
+pub fn sha256_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// XXH64 (with a seed of 0), which is much quicker than SHA-256 when all that's needed is telling files apart
+#[derive(Debug, Clone)]
+pub struct Xxh64 {
+    acc: [u64; 4],
+    buf: [u8; 32],
+    buffered: usize,
+    total: u64,
+}
+
+const P1: u64 = 11_400_714_785_074_694_791;
+const P2: u64 = 14_029_467_366_897_019_727;
+const P3: u64 = 1_609_587_929_392_839_161;
+const P4: u64 = 9_650_029_242_287_828_579;
+const P5: u64 = 2_870_177_450_012_600_261;
+
+fn xxh_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(P2))
+        .rotate_left(31)
+        .wrapping_mul(P1)
+}
+
+fn xxh_merge(hash: u64, acc: u64) -> u64 {
+    (hash ^ xxh_round(0, acc)).wrapping_mul(P1).wrapping_add(P4)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+impl Default for Xxh64 {
+    fn default() -> Self {
+        Xxh64 {
+            acc: [P1.wrapping_add(P2), P2, 0, 0u64.wrapping_sub(P1)],
+            buf: [0; 32],
+            buffered: 0,
+            total: 0,
+        }
+    }
+}
+
+impl Xxh64 {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len() as u64;
+        if self.buffered > 0 {
+            let take = bytes.len().min(32 - self.buffered);
+            self.buf[self.buffered..self.buffered + take].copy_from_slice(&bytes[..take]);
+            self.buffered += take;
+            bytes = &bytes[take..];
+            if self.buffered < 32 {
+                return;
+            }
+            let buf = self.buf;
+            self.stripe(&buf);
+            self.buffered = 0;
+        }
+        while bytes.len() >= 32 {
+            self.stripe(&bytes[..32]);
+            bytes = &bytes[32..];
+        }
+        self.buf[..bytes.len()].copy_from_slice(bytes);
+        self.buffered = bytes.len();
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (i, acc) in self.acc.iter_mut().enumerate() {
+            *acc = xxh_round(*acc, read_u64(&stripe[i * 8..]));
+        }
+    }
+
+    /// The digest, as 16 lowercase hex digits
+    pub fn finish(&self) -> String {
+        let [v1, v2, v3, v4] = self.acc;
+        let mut hash = if self.total >= 32 {
+            let hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            self.acc.iter().fold(hash, |hash, &acc| xxh_merge(hash, acc))
+        } else {
+            P5
+        };
+        hash = hash.wrapping_add(self.total);
+
+        let mut rest = &self.buf[..self.buffered];
+        while rest.len() >= 8 {
+            hash ^= xxh_round(0, read_u64(rest));
+            hash = hash.rotate_left(27).wrapping_mul(P1).wrapping_add(P4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            hash ^= u64::from(u32::from_le_bytes(rest[..4].try_into().unwrap())).wrapping_mul(P1);
+            hash = hash.rotate_left(23).wrapping_mul(P2).wrapping_add(P3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            hash ^= u64::from(byte).wrapping_mul(P5);
+            hash = hash.rotate_left(11).wrapping_mul(P1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(P2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(P3);
+        hash ^= hash >> 32;
+        format!("{:016x}", hash)
+    }
+}
+
+/// The CRC-32 (as used by gzip and zip) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("abc");
+        std::fs::write(&file, b"abc").unwrap();
+        assert_eq!(
+            sha256_file(&file).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(sha256_bytes(b"abc"), sha256_file(&file).unwrap());
+        assert!(matches!(
+            sha256_file(&dir.path().join("missing")),
+            Err(CbError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn xxh64() {
+        let digest = |bytes: &[u8]| {
+            let mut hasher = Xxh64::default();
+            hasher.update(bytes);
+            hasher.finish()
+        };
+        assert_eq!(digest(b""), "ef46db3751d8e999");
+        assert_eq!(digest(b"abc"), "44bc2cf5ad770999");
+
+        // however the input is split up
+        let long: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for chunk in [1, 5, 31, 32, 33, 999] {
+            let mut hasher = Xxh64::default();
+            long.chunks(chunk).for_each(|c| hasher.update(c));
+            assert_eq!(hasher.finish(), digest(&long), "in chunks of {}", chunk);
+        }
+    }
+
+    #[test]
+    fn crc32() {
+        assert_eq!(super::crc32(b""), 0);
+        assert_eq!(super::crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
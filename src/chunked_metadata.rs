@@ -0,0 +1,247 @@
+//! The metadata cache split into a file per recording, for seasons too big to read all at once
+//!
+//! Instead of metadata.json, there's a small `metadata.index.json` with what a [`Season`] has besides its recordings,
+//! and a list of the recordings in the season's order.  Each recording is in `metadata/<data_folder>.json` next to the
+//! index, and the index has the SHA-256 of each file, so one that was changed behind the index's back is caught.
+//! The layout is picked by the name of the `--metadata` file: one that ends in [`INDEX_SUFFIX`] is an index.
+//!
+//! With `--only` and no data dir, [`load_selected`] only reads the recordings that were selected, and
+//! [`write_selected`] only writes those back.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum, listing,
+    select::Selector,
+    types::{OggProfile, Recording, Season},
+};
+
+/// The end of the name of an index, like `metadata.index.json`
+pub const INDEX_SUFFIX: &str = ".index.json";
+
+/// The contents of an index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Index {
+    /// See [`Season::generator`]
+    #[serde(default)]
+    pub generator: String,
+    pub title: String,
+    #[serde(default)]
+    pub artist: String,
+    #[serde(default)]
+    pub redirects: BTreeMap<String, String>,
+    #[serde(default)]
+    pub low_quality_ogg: Option<OggProfile>,
+    /// In the order of the season
+    pub recordings: Vec<IndexEntry>,
+}
+
+/// A recording, as listed in the index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub data_folder: String,
+    /// So that `--only` can match titles without reading the recording
+    pub title: String,
+    /// Of the recording's file
+    pub sha256: String,
+}
+
+/// True if `path` is named like an index rather than a metadata.json
+pub fn is_index(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(INDEX_SUFFIX))
+}
+
+/// The file to read the metadata from: `path`, or the metadata.json it replaces when there's no index yet
+///
+/// That way a season that's switched to the index is still read from its old metadata, until the index is written.
+pub fn existing(path: &Path) -> PathBuf {
+    if !is_index(path) || path.exists() {
+        return path.to_path_buf();
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let monolithic = path.with_file_name(format!("{}.json", &name[..name.len() - INDEX_SUFFIX.len()]));
+    if monolithic.exists() {
+        monolithic
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// The folder the recordings of the index at `path` are in: `metadata/` for `metadata.index.json`
+pub fn recordings_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    match name.strip_suffix(INDEX_SUFFIX) {
+        Some(stem) if !stem.is_empty() => path.with_file_name(stem),
+        _ => path.with_file_name("metadata"),
+    }
+}
+
+fn recording_path(path: &Path, data_folder: &str) -> PathBuf {
+    recordings_dir(path).join(format!("{}.json", data_folder))
+}
+
+/// Every file [`write`] writes for `season`, the index first
+pub fn files(season: &Season, path: &Path) -> Vec<PathBuf> {
+    std::iter::once(path.to_path_buf())
+        .chain(season.recordings.iter().map(|r| recording_path(path, &r.data_folder)))
+        .collect()
+}
+
+pub fn read_index(path: &Path) -> anyhow::Result<Index> {
+    let bytes = std::fs::read(path).with_context(|| format!("Failed to open metadata file {}", path.display()))?;
+    serde_json::from_slice(&bytes).with_context(|| format!("Failed to read metadata file {}", path.display()))
+}
+
+fn read_recording(path: &Path, entry: &IndexEntry) -> anyhow::Result<Recording> {
+    let file = recording_path(path, &entry.data_folder);
+    let bytes = std::fs::read(&file).with_context(|| format!("Failed to open metadata file {}", file.display()))?;
+    if checksum::sha256_bytes(&bytes) != entry.sha256 {
+        bail!(
+            "{} has changed since {} was written, generate from the data dir to write them again",
+            file.display(),
+            path.display()
+        );
+    }
+    serde_json::from_slice(&bytes).with_context(|| format!("Failed to read metadata file {}", file.display()))
+}
+
+/// Reads the season from the index at `path` and every recording it lists
+pub fn load(path: &Path) -> anyhow::Result<Season> {
+    let index = read_index(path)?;
+    let recordings = index
+        .recordings
+        .iter()
+        .map(|entry| read_recording(path, entry))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(season(index, recordings))
+}
+
+/// Reads the season from the index at `path`, with only the recordings that `only` matches
+pub fn load_selected(path: &Path, only: &Selector) -> anyhow::Result<Season> {
+    let index = read_index(path)?;
+    let recordings = index
+        .recordings
+        .iter()
+        .filter(|entry| only.matches_name(&entry.data_folder) || only.matches_name(&entry.title))
+        .map(|entry| read_recording(path, entry))
+        .collect::<anyhow::Result<_>>()?;
+    Ok(season(index, recordings))
+}
+
+fn season(index: Index, recordings: Vec<Recording>) -> Season {
+    Season {
+        generator: index.generator,
+        title: index.title,
+        artist: index.artist,
+        recordings,
+        redirects: index.redirects,
+        low_quality_ogg: index.low_quality_ogg,
+        skipped: Vec::new(),
+    }
+}
+
+/// Writes the index at `path` and a file for each recording, deleting the files of recordings that aren't in the
+/// season any more
+///
+/// Like [`write_metadata`](crate::write_metadata), an index that lists more recordings than `season` is only replaced
+/// if `force` is set.  A recording's file is only written when it changed, and the index is written last, so a run that
+/// dies halfway leaves an index that still matches the files it lists (or fails to load, but never loads wrong ones).
+pub fn write(season: &Season, path: &Path, force: bool) -> anyhow::Result<()> {
+    if !force {
+        if let Ok(old) = read_index(path) {
+            if old.recordings.len() > season.recordings.len() {
+                bail!(
+                    "Refusing to overwrite {} ({} recordings) with a season that only has {} recordings. \
+                     Use --force-metadata if this is intended",
+                    path.display(),
+                    old.recordings.len(),
+                    season.recordings.len()
+                );
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut keep = Vec::new();
+    for recording in &season.recordings {
+        entries.push(write_recording(path, recording)?);
+        keep.push(recording_path(path, &recording.data_folder));
+    }
+    write_index(season, path, entries)?;
+    remove_unlisted(&recordings_dir(path), &keep)
+}
+
+/// Writes the recordings of a `season` that was loaded with [`load_selected`] into the index at `path`
+///
+/// The selected recordings replace their entries in the index (or go at its end, if they're new to it), and the rest
+/// of the index and the files of the recordings that weren't selected are left as they are.
+pub fn write_selected(season: &Season, path: &Path) -> anyhow::Result<()> {
+    let mut entries = read_index(path)?.recordings;
+    for recording in &season.recordings {
+        let entry = write_recording(path, recording)?;
+        match entries.iter_mut().find(|old| old.data_folder == entry.data_folder) {
+            Some(old) => *old = entry,
+            None => entries.push(entry),
+        }
+    }
+    write_index(season, path, entries)
+}
+
+/// Writes a recording's file (unless it's unchanged), returning its entry in the index
+fn write_recording(path: &Path, recording: &Recording) -> anyhow::Result<IndexEntry> {
+    let file = recording_path(path, &recording.data_folder);
+    let bytes = serde_json::to_vec(recording)?;
+    if std::fs::read(&file).ok().as_deref() != Some(&bytes[..]) {
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        crate::write_atomically(&file, &bytes)
+            .with_context(|| format!("Failed to write metadata file {}", file.display()))?;
+    }
+    Ok(IndexEntry {
+        data_folder: recording.data_folder.clone(),
+        title: recording.title.clone(),
+        sha256: checksum::sha256_bytes(&bytes),
+    })
+}
+
+/// Writes the index at `path`, with the rest of what it has from `season`
+fn write_index(season: &Season, path: &Path, recordings: Vec<IndexEntry>) -> anyhow::Result<()> {
+    let index = Index {
+        generator: season.generator.clone(),
+        title: season.title.clone(),
+        artist: season.artist.clone(),
+        redirects: season.redirects.clone(),
+        low_quality_ogg: season.low_quality_ogg.clone(),
+        recordings,
+    };
+    crate::write_atomically(path, &crate::to_json_bytes(&index)?)
+        .with_context(|| format!("Failed to write metadata file {}", path.display()))
+}
+
+/// Deletes the recordings' files in `dir` that aren't in `keep`, and the folders that leaves empty
+fn remove_unlisted(dir: &Path, keep: &[PathBuf]) -> anyhow::Result<()> {
+    let entries = match listing::sorted(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_unlisted(&path, keep)?;
+            // only empty folders go, anything else in them is left alone
+            let _ = std::fs::remove_dir(&path);
+        } else if path.extension().is_some_and(|ext| ext == "json") && !keep.contains(&path) {
+            std::fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,401 @@
+//! Deleting what the pipeline generated (`cb_processor clean`)
+//!
+//! Generated files live next to the sources (oggs next to flacs, pages next to the static copies), so this only
+//! deletes files that the season says we made: the oggs, mp3s, spectrograms and peaks of each track, and the pages, playlist
+//! and static copies in the output (with their compressed copies).  Flacs and JSON files are never deleted, whatever the scope.
+//!
+//! When the output is (or is inside) the data dir, the pages are only deleted if the build manifest lists them, and
+//! without a manifest nothing in the output is deleted at all.  Whatever the manifest says is no longer generated (a
+//! page under an old slug, a static file that was removed) is deleted too.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{
+    asset_map::AssetMap,
+    context::RunContext,
+    error::CbError,
+    listing,
+    manifest::{self, BuildManifest},
+    precompress::Encoding,
+    types::{peaks_path, spectrogram_path, SeasonInner, TrackInner},
+};
+
+/// What to clean
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanScope {
+    /// Oggs, mp3s, spectrograms and peaks in the data dir
+    pub derived_audio: bool,
+    /// Pages, the playlist and the static copies in the output dir
+    pub html: bool,
+}
+
+/// A file that would be deleted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CleanItem {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Just the parts of a recording JSON that say where things are (so that no media info has to be read)
+#[derive(Deserialize)]
+struct RecordingFiles {
+    title: String,
+    data_folder: String,
+    slug: Option<String>,
+    stereo_mix: TrackInner,
+    tracks: Vec<TrackInner>,
+    #[serde(default)]
+    tos: Option<String>,
+}
+
+/// Sources (and our own inputs) are never deleted, even if a template in the JSON says they were generated
+///
+/// The one JSON file we make in the data dir, `<FLACBASE>.peaks.json`, isn't protected.
+pub fn is_protected(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_ascii_lowercase();
+    if name.ends_with(".peaks.json") {
+        return false;
+    }
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| ext == "flac" || ext == "json")
+}
+
+/// Lists the generated files that exist, in the order they'd be deleted
+///
+/// `data_dir` is needed for [`CleanScope::derived_audio`], and `output` for [`CleanScope::html`].  Draft recordings are
+/// included, since they might have been converted before they were marked as drafts.
+pub fn plan_clean(
+    ctx: &RunContext, season_json: &Path, data_dir: Option<&Path>, output: Option<&Path>, scope: CleanScope,
+) -> Result<Vec<CleanItem>, CbError> {
+    let season: SeasonInner = serde_json::from_value(crate::get_validated_json(season_json)?)
+        .map_err(|e| CbError::parse(format!("Unexpected contents in {}", season_json.display()), e))?;
+    let mut recordings = Vec::new();
+    for listed in season.recording_paths(season_json)? {
+        let path = &listed.path;
+        let rec: RecordingFiles = crate::get_validated_json(path)
+            .and_then(|json| {
+                serde_json::from_value(json)
+                    .map_err(|e| CbError::parse(format!("Unexpected contents in {}", path.display()), e))
+            })
+            .map_err(|e| listed.context(e))?;
+        if ctx.only.matches_name(&rec.data_folder) {
+            recordings.push(rec);
+        }
+    }
+
+    let mut candidates = Vec::new();
+    if let (true, Some(data_dir)) = (scope.derived_audio, data_dir) {
+        for rec in &recordings {
+            let folder = data_dir.join(&rec.data_folder);
+            for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+                let vorbis = track.vorbis();
+                if let Some(vorbis) = &vorbis {
+                    candidates.push(folder.join(vorbis));
+                }
+                if let Some(mp3) = track.mp3() {
+                    candidates.push(folder.join(mp3));
+                }
+                if let Some(opus) = track.opus() {
+                    candidates.push(folder.join(opus));
+                }
+                if let Some(base) = Path::new(&track.flac).file_stem() {
+                    let next_to = vorbis.map_or_else(|| track.flac.clone(), |v| v.to_string_lossy().to_string());
+                    candidates.push(folder.join(spectrogram_path(&next_to, &base.to_string_lossy())));
+                    candidates.push(folder.join(peaks_path(&next_to, &base.to_string_lossy())));
+                }
+            }
+            // whether or not the season still has a profile for them
+            if let Some(lq) = rec.stereo_mix.lq_ogg() {
+                candidates.push(folder.join(lq));
+            }
+        }
+    }
+    if let (true, Some(output)) = (scope.html, output) {
+        let mut html = vec![
+            output.join("index.html"),
+            output.join("playlist.m3u"),
+            output.join(crate::types::LQ_PLAYLIST),
+        ];
+        let assets = AssetMap::of_static_dir(ctx)?;
+        for rec in &recordings {
+            let slug = crate::slug::for_recording(rec.slug.as_deref(), &rec.title, &rec.data_folder);
+            html.push(output.join(slug).join("index.html"));
+            html.push(output.join(&rec.data_folder).join("index.html"));
+            html.push(output.join(&rec.data_folder).join("style.css"));
+            if let Some(name) = assets.get("style.css") {
+                html.push(output.join(&rec.data_folder).join(name));
+            }
+            // unless the recording's own terms are kept under that name
+            if rec.tos.as_deref() != Some("ToS.txt") {
+                html.push(output.join(&rec.data_folder).join("ToS.txt"));
+            }
+        }
+        static_copies(&ctx.static_dir, &ctx.static_dir, output, &mut html)?;
+        html.extend(assets.iter().map(|(_, name)| output.join(name)));
+        let compressed: Vec<PathBuf> = html
+            .iter()
+            .flat_map(|path| Encoding::ALL.iter().map(move |encoding| encoding.sibling(path)))
+            .collect();
+        html.extend(compressed);
+
+        // the data dir isn't always given, so a stereo mix in the output also counts as the two overlapping
+        let overlapping = data_dir.is_some_and(|data_dir| manifest::overlaps(output, data_dir))
+            || recordings
+                .iter()
+                .any(|rec| output.join(&rec.data_folder).join(&rec.stereo_mix.flac).exists());
+        match BuildManifest::load(output)? {
+            Some(manifest) => {
+                html.retain(|path| manifest.contains(output, path));
+                html.extend(manifest.stale_files(output));
+            }
+            None if overlapping => {
+                return Err(CbError::MissingManifest {
+                    output: output.to_path_buf(),
+                })
+            }
+            None => {}
+        }
+        candidates.extend(html);
+    }
+
+    let mut items = Vec::new();
+    for path in candidates {
+        if is_protected(&path) || items.iter().any(|i: &CleanItem| i.path == path) {
+            continue;
+        }
+        if let Ok(md) = std::fs::metadata(&path) {
+            if md.is_file() {
+                items.push(CleanItem { path, bytes: md.len() });
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Adds the copy in `output` of every file under `dir` (which is somewhere in `static_dir`)
+fn static_copies(static_dir: &Path, dir: &Path, output: &Path, candidates: &mut Vec<PathBuf>) -> Result<(), CbError> {
+    let entries = listing::sorted(dir).map_err(|e| CbError::io(format!("Failed to read {}", dir.display()), e))?;
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            static_copies(static_dir, &path, output, candidates)?;
+        } else if let Ok(rel) = path.strip_prefix(static_dir) {
+            candidates.push(output.join(rel));
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the planned files, returning how many bytes were freed
+pub fn remove(items: &[CleanItem]) -> Result<u64, CbError> {
+    let mut freed = 0;
+    for item in items {
+        // plans are plain data, so check again
+        if is_protected(&item.path) {
+            continue;
+        }
+        std::fs::remove_file(&item.path)
+            .map_err(|e| CbError::io(format!("Failed to delete {}", item.path.display()), e))?;
+        freed += item.bytes;
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::HasherPool;
+
+    #[test]
+    fn only_generated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let audio = dir.path().join("audio");
+        let output = dir.path().join("output");
+        let static_dir = dir.path().join("static");
+        std::fs::create_dir_all(data.join("recordings")).unwrap();
+        std::fs::create_dir_all(audio.join("jam1/ogg")).unwrap();
+        std::fs::create_dir_all(output.join("jam1")).unwrap();
+        std::fs::create_dir_all(output.join("jam-1")).unwrap();
+        std::fs::create_dir_all(static_dir.join("css")).unwrap();
+
+        std::fs::write(
+            data.join("season.json"),
+            r#"{"$schema": "none", "title": "S", "recordings": ["recordings/jam1.json"]}"#,
+        )
+        .unwrap();
+        // a (broken) template that points the ogg at the flac must not get the flac deleted
+        std::fs::write(
+            data.join("recordings/jam1.json"),
+            r#"{"data_folder": "jam1",
+                "stereo_mix": {"id": 1, "name": "Stereo", "flac": "jam1.flac", "vorbis": "ogg/{FLACBASE}.ogg",
+                               "mp3": "jam1.mp3"},
+                "title": "Jam 1", "tracks": [{"id": 2, "name": "Kick", "flac": "kick.flac", "vorbis": "kick.flac"}]}"#,
+        )
+        .unwrap();
+        for f in [
+            "jam1.flac",
+            "kick.flac",
+            "ogg/jam1.ogg",
+            "ogg/jam1.spectrogram.png",
+            "ogg/jam1.peaks.json",
+            "notes.txt",
+        ] {
+            std::fs::write(audio.join("jam1").join(f), "audio").unwrap();
+        }
+        for f in ["style.css", "css/fonts.css"] {
+            std::fs::write(static_dir.join(f), "static").unwrap();
+        }
+        for f in [
+            "index.html",
+            "playlist.m3u",
+            "metadata.json",
+            "style.css",
+            "jam1/index.html",
+            "jam-1/index.html",
+            "mine.html",
+        ] {
+            std::fs::write(output.join(f), "generated").unwrap();
+        }
+
+        let ctx = RunContext {
+            static_dir,
+            ..Default::default()
+        };
+        let season_json = data.join("season.json");
+        let plan = |scope| {
+            let mut paths: Vec<String> = plan_clean(&ctx, &season_json, Some(&audio), Some(&output), scope)
+                .unwrap()
+                .into_iter()
+                .map(|i| i.path.strip_prefix(dir.path()).unwrap().display().to_string())
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        assert!(plan(CleanScope::default()).is_empty());
+        assert_eq!(
+            plan(CleanScope {
+                derived_audio: true,
+                html: false
+            }),
+            [
+                "audio/jam1/ogg/jam1.ogg",
+                "audio/jam1/ogg/jam1.peaks.json",
+                "audio/jam1/ogg/jam1.spectrogram.png"
+            ]
+        );
+        assert_eq!(
+            plan(CleanScope {
+                derived_audio: false,
+                html: true
+            }),
+            [
+                "output/index.html",
+                "output/jam-1/index.html",
+                "output/jam1/index.html",
+                "output/playlist.m3u",
+                "output/style.css"
+            ]
+        );
+
+        let all = plan_clean(
+            &ctx,
+            &season_json,
+            Some(&audio),
+            Some(&output),
+            CleanScope {
+                derived_audio: true,
+                html: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(remove(&all).unwrap(), 3 * 5 + 5 * 9);
+        assert!(audio.join("jam1/jam1.flac").exists());
+        assert!(audio.join("jam1/kick.flac").exists());
+        assert!(output.join("metadata.json").exists());
+        assert!(output.join("mine.html").exists());
+        assert!(!output.join("index.html").exists());
+    }
+
+    #[test]
+    fn overlapping_output_needs_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let static_dir = dir.path().join("static");
+        std::fs::create_dir_all(data.join("recordings")).unwrap();
+        std::fs::create_dir_all(data.join("jam1")).unwrap();
+        std::fs::create_dir_all(&static_dir).unwrap();
+        std::fs::write(
+            data.join("season.json"),
+            r#"{"$schema": "none", "title": "S", "recordings": ["recordings/jam1.json"]}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            data.join("recordings/jam1.json"),
+            r#"{"data_folder": "jam1", "title": "Jam 1", "tracks": [],
+                "stereo_mix": {"id": 1, "name": "Stereo", "flac": "jam1.flac", "vorbis": "{FLACBASE}.ogg"}}"#,
+        )
+        .unwrap();
+        std::fs::write(static_dir.join("style.css"), "static").unwrap();
+        // a style.css that the artist put there, and one we copied
+        for f in [
+            "jam1/jam1.flac",
+            "jam1/index.html",
+            "jam1/style.css",
+            "style.css",
+            "index.html",
+        ] {
+            std::fs::write(data.join(f), "data").unwrap();
+        }
+
+        let ctx = RunContext {
+            static_dir,
+            ..Default::default()
+        };
+        let html = CleanScope {
+            derived_audio: false,
+            html: true,
+        };
+        let season_json = data.join("season.json");
+        // with and without the data dir: the flac in the output gives the overlap away
+        for data_dir in [Some(data.as_path()), None] {
+            assert!(matches!(
+                plan_clean(&ctx, &season_json, data_dir, Some(&data), html),
+                Err(CbError::MissingManifest { .. })
+            ));
+        }
+
+        // a page under an old slug that the next full run no longer makes
+        std::fs::create_dir_all(data.join("old-jam")).unwrap();
+        std::fs::write(data.join("old-jam/index.html"), "old").unwrap();
+        BuildManifest::record(&HasherPool::default(), &data, &[data.join("old-jam/index.html")], false).unwrap();
+        BuildManifest::record(
+            &HasherPool::default(),
+            &data,
+            &[data.join("index.html"), data.join("jam1/index.html")],
+            true,
+        )
+        .unwrap();
+        let paths: Vec<_> = plan_clean(&ctx, &season_json, Some(&data), Some(&data), html)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                data.join("index.html"),
+                data.join("jam1/index.html"),
+                data.join("old-jam/index.html")
+            ]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! How much of each recording has been published (its badges on the season index, and `completeness` in the metadata)
+//!
+//! A recording is complete when it has every [`Facet`].  The facets come from fields the recording already has, and
+//! [`Facet::ALL`] is the only list of them: the badges, the season's summary and the JSON all go through it.
+
+use std::{borrow::Borrow, collections::BTreeMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::Recording;
+
+/// Something a recording should have
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Facet {
+    /// An ogg of the stereo mix
+    Ogg,
+    /// An mp3 of the stereo mix
+    Mp3,
+    Torrent,
+    /// At least one track has patch notes
+    PatchNotes,
+    Tags,
+    /// A video of the stream
+    Youtube,
+}
+
+impl Facet {
+    /// Every facet, in the order the badges are shown
+    pub const ALL: &'static [Facet] = &[
+        Facet::Ogg,
+        Facet::Mp3,
+        Facet::Torrent,
+        Facet::PatchNotes,
+        Facet::Tags,
+        Facet::Youtube,
+    ];
+
+    /// The text of the badge
+    pub fn label(self) -> &'static str {
+        match self {
+            Facet::Ogg => "ogg",
+            Facet::Mp3 => "mp3",
+            Facet::Torrent => "torrent",
+            Facet::PatchNotes => "patch notes",
+            Facet::Tags => "tags",
+            Facet::Youtube => "video",
+        }
+    }
+
+    fn present(self, recording: &Recording) -> bool {
+        match self {
+            Facet::Ogg => recording.stereo_mix.vorbis.is_some(),
+            Facet::Mp3 => recording.stereo_mix.mp3.is_some(),
+            Facet::Torrent => recording.torrent.is_some(),
+            Facet::PatchNotes => recording
+                .tracks
+                .iter()
+                .any(|t| t.patch_notes.as_deref().is_some_and(|notes| !notes.trim().is_empty())),
+            Facet::Tags => !recording.tags.is_empty(),
+            Facet::Youtube => recording.youtube_url.is_some(),
+        }
+    }
+}
+
+/// Which facets a recording has
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Completeness(BTreeMap<Facet, bool>);
+
+impl Completeness {
+    pub fn of(recording: &Recording) -> Completeness {
+        Completeness(Facet::ALL.iter().map(|f| (*f, f.present(recording))).collect())
+    }
+
+    pub fn has(&self, facet: Facet) -> bool {
+        self.0.get(&facet).copied().unwrap_or(false)
+    }
+
+    /// A badge for each facet, in the order of [`Facet::ALL`]
+    pub fn badges(&self) -> Vec<Badge> {
+        Facet::ALL
+            .iter()
+            .map(|f| Badge {
+                label: f.label(),
+                present: self.has(*f),
+            })
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        Facet::ALL.iter().all(|f| self.has(*f))
+    }
+}
+
+/// What the season index shows for a facet of a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Badge {
+    pub label: &'static str,
+    pub present: bool,
+}
+
+/// How complete a whole season is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// Of all the facets of all the recordings, how many are there, in percent (rounded down)
+    pub percent: usize,
+    pub complete: usize,
+    pub recordings: usize,
+}
+
+impl Summary {
+    pub fn of(recordings: &[Recording]) -> Summary {
+        Summary::of_each(recordings.iter().map(|r| &r.completeness))
+    }
+
+    /// The summary of the recordings with these facets, for when they aren't all in memory at once
+    pub fn of_each<C: Borrow<Completeness>>(recordings: impl IntoIterator<Item = C>) -> Summary {
+        let mut present = 0;
+        let mut complete = 0;
+        let mut count = 0;
+        for completeness in recordings {
+            let completeness = completeness.borrow();
+            present += Facet::ALL.iter().filter(|f| completeness.has(**f)).count();
+            if completeness.is_complete() {
+                complete += 1;
+            }
+            count += 1;
+        }
+        Summary {
+            percent: (present * 100).checked_div(count * Facet::ALL.len()).unwrap_or(100),
+            complete,
+            recordings: count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recording(torrent: Option<&str>, tags: &[&str]) -> Recording {
+        let track = json!({
+            "id": 1,
+            "name": "Stereo mix",
+            "flac": "mix.flac",
+            "vorbis": "ogg/mix.ogg",
+            "mp3": "mp3/mix.mp3",
+            "patch_notes": null,
+            "media_info": {
+                "@type": "Audio",
+                "Format": "FLAC",
+                "Channels": "2",
+                "SamplingRate": "48000",
+                "BitDepth": "24",
+                "Duration": "123.456"
+            },
+            "flac_bytes": 0,
+            "ogg_bytes": 0,
+            "mp3_bytes": 0
+        });
+        let mut patched = track.clone();
+        patched["patch_notes"] = "Straight from the drum machine".into();
+        serde_json::from_value(json!({
+            "title": "Jam",
+            "data_folder": "jam",
+            "stereo_mix": track,
+            "recorded_date": "2021/01/01",
+            "torrent": torrent,
+            "tracks": [patched],
+            "tags": tags,
+            "bpm": null,
+            "youtube_url": "https://youtu.be/abcdefghijk"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn facets() {
+        let mut complete = recording(Some("jam.torrent"), &["techno"]);
+        complete.completeness = Completeness::of(&complete);
+        assert!(complete.completeness.is_complete());
+
+        let mut partial = recording(None, &[]);
+        partial.completeness = Completeness::of(&partial);
+        let missing: Vec<&str> = partial
+            .completeness
+            .badges()
+            .iter()
+            .filter(|b| !b.present)
+            .map(|b| b.label)
+            .collect();
+        assert_eq!(missing, ["torrent", "tags"]);
+        assert_eq!(
+            serde_json::to_value(&partial.completeness).unwrap(),
+            json!({"ogg": true, "mp3": true, "torrent": false, "patch_notes": true, "tags": false, "youtube": true})
+        );
+
+        assert_eq!(
+            Summary::of(&[complete, partial]),
+            Summary {
+                percent: 83,
+                complete: 1,
+                recordings: 2
+            }
+        );
+        assert_eq!(Summary::of(&[]).percent, 100);
+    }
+}
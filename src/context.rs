@@ -0,0 +1,1099 @@
+//! Settings for a single run of the pipeline
+//!
+//! Everything that used to come from the environment (the current directory, env vars, tools on the PATH) is collected
+//! into a [`RunContext`] once, in main, and then passed explicitly to the library functions that need it.
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::{
+    diff::OutputDiff,
+    error::CbError,
+    hasher::HasherPool,
+    media_cache::MediaInfoCache,
+    metrics::Metrics,
+    precompress::Encoding,
+    progress::{self, Stage},
+    select::Selector,
+    state_store::Retention,
+    timing::Timings,
+    types::SkippedRecording,
+};
+
+/// The name of the config file that is loaded from the current directory (if it exists)
+pub const DEFAULT_CONFIG_FILE: &str = "cb_processor.toml";
+
+/// Where the roots made by `--patch` are recorded, unless the config says otherwise
+pub const DEFAULT_ROOTS_HISTORY: &str = "roots_history.jsonl";
+
+/// Where `import-stats` keeps the download counts, unless the config says otherwise
+pub const DEFAULT_STATS_FILE: &str = "stats.json";
+
+/// Where `--prime` keeps track of what it has primed, unless the config says otherwise
+pub const DEFAULT_PRIME_STATE: &str = "prime_state.json";
+
+/// How many runs in a row a gateway has to fail every request of before `--prime` skips it, unless the config says
+/// otherwise
+pub const DEFAULT_UNHEALTHY_AFTER: usize = 3;
+
+/// How much of the links expected at the top of the root given to `--patch` it has to have, unless the config says
+/// otherwise
+pub const DEFAULT_EXPECTED_ROOT_LINKS: f64 = 0.5;
+
+/// How many roots the roots history keeps before moving the older ones to its `.1`, unless the config says otherwise
+pub const DEFAULT_ROOTS_HISTORY_RETENTION: Retention = Retention {
+    max_entries: 1000,
+    max_bytes: 256 * 1024,
+};
+
+/// How many files the media info cache in a data dir remembers, unless the config says otherwise
+pub const DEFAULT_MEDIA_CACHE_RETENTION: Retention = Retention {
+    max_entries: 20_000,
+    max_bytes: 16 * 1024 * 1024,
+};
+
+/// How many min/max pairs `--peaks` works out per track, unless the config says otherwise
+pub const DEFAULT_PEAK_BUCKETS: usize = 1000;
+
+/// Set this (to anything) to do the same as `--offline`
+pub const OFFLINE_VAR: &str = "CB_OFFLINE";
+
+/// The contents of `cb_processor.toml`
+///
+/// Every field is optional, with the defaults coming from [`RunContext::default`]
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub static_dir: Option<PathBuf>,
+    pub base_url: Option<String>,
+    pub jobs: Option<usize>,
+    pub ipfs_api: Option<String>,
+    pub media_info_backend: MediaInfoBackend,
+    /// Seconds to wait for a tool (like mediainfo) before giving up on it
+    pub tool_timeout: Option<u64>,
+    /// Paths in the published root that aren't in the output any more, but are kept there on purpose
+    pub legacy_links: Vec<String>,
+    /// Where every root made by `--patch` is recorded
+    pub roots_history: Option<PathBuf>,
+    /// Where `--prime` keeps track of the gateways and links it has primed, so that it can carry on after dying
+    pub prime_state: Option<PathBuf>,
+    /// How many runs in a row a gateway has to fail every request of before `--prime` skips it (0 never skips any)
+    pub unhealthy_after: Option<usize>,
+    /// Where `import-stats` adds up the downloads, for the recording pages
+    pub stats: Option<PathBuf>,
+    /// Where to write the metrics for Prometheus after each run, if anywhere
+    pub metrics: Option<PathBuf>,
+    /// Also copy the stylesheets and scripts under names with their hash in them, and link to those
+    pub hashed_assets: Option<bool>,
+    /// How the pages show the dates of the recordings (see [`crate::dates`])
+    pub date_format: Option<String>,
+    /// How much of index.html and the season's data folders the root given to `--patch` has to have, from 0 to 1
+    pub expected_root_links: Option<f64>,
+    pub tools: ToolsConfig,
+    pub spectrogram: SpectrogramConfig,
+    pub peaks: PeaksConfig,
+    pub notify: NotifyConfig,
+    pub archive_org: ArchiveOrgConfig,
+    pub precompress: PrecompressConfig,
+    pub encode: EncodeConfig,
+    pub hooks: HooksConfig,
+    pub source: SourceConfig,
+    pub retention: RetentionConfig,
+    /// Gateways that need more than a plain request to be primed, and gateways to prime on top of the public ones
+    #[serde(rename = "gateway")]
+    pub gateways: Vec<GatewayConfig>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolsConfig {
+    pub ffmpeg: Option<PathBuf>,
+    pub ffprobe: Option<PathBuf>,
+    pub mediainfo: Option<PathBuf>,
+    pub ipfs: Option<PathBuf>,
+    pub brotli: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpectrogramConfig {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub legend: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeaksConfig {
+    pub buckets: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub webhook_url: Option<String>,
+    pub format: WebhookFormat,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveOrgConfig {
+    pub identifier_prefix: Option<String>,
+    pub collection: Option<String>,
+    pub license_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrecompressConfig {
+    pub gzip: Option<bool>,
+    pub brotli: Option<bool>,
+    pub min_size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncodeConfig {
+    pub ogg_quality: Option<f32>,
+    pub mp3_bitrate: Option<Mp3Bitrate>,
+    pub sample_rate: Option<u32>,
+}
+
+/// The snapshot of the season's JSON in the output (see [`crate::source`])
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    pub snapshot: Option<bool>,
+    /// Fields left out of the snapshot
+    pub sensitive: Vec<String>,
+}
+
+/// How much of the state files to keep (see [`crate::state_store`])
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub roots_history_entries: Option<usize>,
+    pub roots_history_kb: Option<u64>,
+    pub media_cache_entries: Option<usize>,
+    pub media_cache_kb: Option<u64>,
+}
+
+/// Commands to run around the stages of a run (see [`crate::hooks`])
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub pre_convert: Option<HookConfig>,
+    pub post_generate: Option<HookConfig>,
+    pub post_patch: Option<HookConfig>,
+    pub post_publish: Option<HookConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Run by the shell, so it can use the `CB_*` variables
+    pub command: String,
+    /// Only warn when the command fails, instead of failing the stage
+    #[serde(default)]
+    pub allow_failure: bool,
+}
+
+/// A `[[gateway]]` in the config
+///
+/// Tokens are never written in the config: `token_env` names the environment variable that has it, and it's sent as
+/// `Authorization: Bearer <token>`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// The gateway's URL for the root, written like the ones in `gateway::PUBLIC_GATEWAYS`
+    pub url: String,
+    /// Sent with every request to the gateway
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub token_env: Option<String>,
+}
+
+/// The shape of the JSON posted to the webhook after publishing
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// Our own JSON object, with a field for each part of the notification
+    #[default]
+    Json,
+    /// A Discord webhook message
+    Discord,
+}
+
+/// Which tool to get media info from (for files that aren't flacs)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaInfoBackend {
+    /// mediainfo if it's installed, otherwise ffprobe
+    #[default]
+    Auto,
+    Mediainfo,
+    Ffprobe,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Config::parse(&text).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Config> {
+        let config: Config = toml::from_str(text)?;
+        if let Some(quality) = config.encode.ogg_quality {
+            check_ogg_quality(quality).map_err(anyhow::Error::msg)?;
+        }
+        if let Some(format) = &config.date_format {
+            crate::dates::check_format(format).map_err(anyhow::Error::msg)?;
+        }
+        if let Some(fraction) = config.expected_root_links {
+            if !(0.0..=1.0).contains(&fraction) {
+                anyhow::bail!("Invalid expected_root_links {} (expected 0 to 1)", fraction);
+            }
+        }
+        for gateway in &config.gateways {
+            if gateway
+                .headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case("authorization"))
+            {
+                anyhow::bail!(
+                    "The gateway {} has an Authorization header; put the token in an environment variable and give its \
+                     name as token_env instead",
+                    gateway.url
+                );
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Paths to the external programs that we run
+#[derive(Debug, Clone)]
+pub struct Tools {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    pub mediainfo: PathBuf,
+    pub ipfs: PathBuf,
+    pub brotli: PathBuf,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Tools {
+            ffmpeg: PathBuf::from("ffmpeg"),
+            ffprobe: PathBuf::from("ffprobe"),
+            mediainfo: PathBuf::from("mediainfo"),
+            ipfs: PathBuf::from("ipfs"),
+            brotli: PathBuf::from("brotli"),
+        }
+    }
+}
+
+/// Where `--archive-upload` puts the stereo mixes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveOrgSettings {
+    /// Put in front of the data_folder to make the item identifier, since identifiers are global on archive.org
+    pub identifier_prefix: String,
+    pub collection: String,
+    pub license_url: Option<String>,
+}
+
+impl Default for ArchiveOrgSettings {
+    fn default() -> Self {
+        ArchiveOrgSettings {
+            identifier_prefix: "benderfactory-".to_string(),
+            collection: "opensource_audio".to_string(),
+            license_url: None,
+        }
+    }
+}
+
+/// How spectrograms are drawn (see `--spectrograms`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectrogramSettings {
+    /// Size of the spectrum itself, in pixels.  The legend (if any) goes around the outside of this
+    pub width: u32,
+    pub height: u32,
+    /// Whether to draw the frequency and time axes
+    pub legend: bool,
+}
+
+impl Default for SpectrogramSettings {
+    fn default() -> Self {
+        SpectrogramSettings {
+            width: 1024,
+            height: 512,
+            legend: true,
+        }
+    }
+}
+
+/// Which compressed copies of the generated text files to write (see [`crate::precompress`])
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrecompressSettings {
+    pub gzip: bool,
+    pub brotli: bool,
+    /// Smaller files aren't worth it
+    pub min_size: u64,
+}
+
+impl PrecompressSettings {
+    pub fn enabled(&self, encoding: Encoding) -> bool {
+        match encoding {
+            Encoding::Gzip => self.gzip,
+            Encoding::Brotli => self.brotli,
+        }
+    }
+}
+
+impl Default for PrecompressSettings {
+    fn default() -> Self {
+        PrecompressSettings {
+            gzip: false,
+            brotli: false,
+            min_size: 1024,
+        }
+    }
+}
+
+/// How ffmpeg encodes the oggs, mp3s and opus files that `--convert` makes.  Anything that isn't set is left to ffmpeg
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EncodeSettings {
+    /// Vorbis quality, from -1 to 10 (ffmpeg's `-q:a`, which defaults to 3)
+    pub ogg_quality: Option<f32>,
+    pub mp3_bitrate: Option<Mp3Bitrate>,
+    /// Resample the oggs and mp3s to this many Hz (ffmpeg's `-ar`).  Opus is always 48kHz
+    pub sample_rate: Option<u32>,
+}
+
+impl EncodeSettings {
+    /// The ffmpeg options for encoding to `output`, which is picked by its extension
+    pub fn encoder_args(&self, output: &Path) -> Result<Vec<String>, CbError> {
+        let ext = output
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut args = Vec::new();
+        match ext.as_str() {
+            "ogg" => {
+                if let Some(quality) = self.ogg_quality {
+                    args.extend(["-q:a".to_string(), quality.to_string()]);
+                }
+            }
+            "mp3" => match self.mp3_bitrate {
+                Some(Mp3Bitrate::Constant(kbps)) => args.extend(["-b:a".to_string(), format!("{}k", kbps)]),
+                Some(Mp3Bitrate::Vbr(preset)) => args.extend(["-q:a".to_string(), preset.to_string()]),
+                None => {}
+            },
+            // ffmpeg would pick its own (worse) opus encoder for .opus files, and libopus only does 48kHz
+            "opus" => return Ok(vec!["-c:a".to_string(), "libopus".to_string()]),
+            _ => {
+                return Err(CbError::UnsupportedFormat {
+                    path: output.to_path_buf(),
+                })
+            }
+        }
+        if let Some(rate) = self.sample_rate {
+            args.extend(["-ar".to_string(), rate.to_string()]);
+        }
+        Ok(args)
+    }
+}
+
+/// How big the mp3s are: a constant bitrate like `320k`, or a LAME VBR preset like `V0`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Mp3Bitrate {
+    /// In kbit/s (ffmpeg's `-b:a`)
+    Constant(u32),
+    /// 0 (best) to 9 (ffmpeg's `-q:a`)
+    Vbr(u8),
+}
+
+impl std::str::FromStr for Mp3Bitrate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid mp3 bitrate {:?} (expected kbit/s like 320k, or a VBR preset from V0 to V9)",
+                s
+            )
+        };
+        let s = s.trim();
+        if let Some(preset) = s.strip_prefix(['V', 'v']) {
+            return match preset.parse() {
+                Ok(preset) if preset <= 9 => Ok(Mp3Bitrate::Vbr(preset)),
+                _ => Err(invalid()),
+            };
+        }
+        match s.strip_suffix(['k', 'K']).unwrap_or(s).parse() {
+            Ok(kbps) if (8..=320).contains(&kbps) => Ok(Mp3Bitrate::Constant(kbps)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Mp3Bitrate {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Checks a vorbis quality (`--ogg-quality`, or `ogg_quality` in the config)
+pub fn check_ogg_quality(quality: f32) -> Result<f32, String> {
+    if (-1.0..=10.0).contains(&quality) {
+        Ok(quality)
+    } else {
+        Err(format!("Invalid ogg quality {} (expected -1 to 10)", quality))
+    }
+}
+
+/// What goes in the output's `source/` (see [`crate::source`])
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceSettings {
+    pub snapshot: bool,
+    /// Fields of the season and recording JSON that aren't published
+    pub sensitive: Vec<String>,
+}
+
+impl Default for SourceSettings {
+    fn default() -> Self {
+        SourceSettings {
+            snapshot: true,
+            sensitive: Vec::new(),
+        }
+    }
+}
+
+/// How much of each state file to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionSettings {
+    pub roots_history: Retention,
+    pub media_cache: Retention,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        RetentionSettings {
+            roots_history: DEFAULT_ROOTS_HISTORY_RETENTION,
+            media_cache: DEFAULT_MEDIA_CACHE_RETENTION,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunContext {
+    /// Folder containing style.css, ToS.txt, and the other files copied into the output
+    pub static_dir: PathBuf,
+    /// Where the published site can be reached (without a trailing slash)
+    pub base_url: String,
+    /// HTML snippet added to the head of every page (used for GitLab review apps)
+    pub review_snippet: String,
+    pub tools: Tools,
+    pub media_info_backend: MediaInfoBackend,
+    /// How long to wait for mediainfo before killing it
+    pub tool_timeout: Duration,
+    /// Address of the IPFS API to talk to.  If None, the ipfs command uses its own default
+    pub ipfs_api: Option<String>,
+    /// Paths (relative to the root) that `--verify` doesn't complain about when they're only in IPFS
+    pub legacy_links: Vec<String>,
+    pub roots_history: PathBuf,
+    pub prime_state: PathBuf,
+    /// See [`Config::unhealthy_after`]
+    pub unhealthy_after: usize,
+    /// Prime the gateways that `unhealthy_after` would skip too (`--include-unhealthy`)
+    pub include_unhealthy: bool,
+    pub stats_file: PathBuf,
+    /// The file for the node exporter's textfile collector, if there is one
+    pub metrics_file: Option<PathBuf>,
+    /// Only use what's in the local IPFS repo, instead of looking for blocks on the network
+    pub ipfs_offline: bool,
+    /// Don't touch the network at all (`--offline`).  Implies `ipfs_offline`
+    pub offline: bool,
+    pub spectrogram: SpectrogramSettings,
+    /// How many min/max pairs go in each `.peaks.json`
+    pub peak_buckets: usize,
+    /// Where to send a notification after publishing, if anywhere
+    pub webhook_url: Option<String>,
+    pub webhook_format: WebhookFormat,
+    pub archive_org: ArchiveOrgSettings,
+    pub precompress: PrecompressSettings,
+    pub encode: EncodeSettings,
+    /// The `[[gateway]]`s from the config
+    pub gateways: Vec<GatewayConfig>,
+    /// Link to the static files by names that change with their contents (see [`crate::asset_map`])
+    pub hashed_assets: bool,
+    /// The strftime format the pages show dates in
+    pub date_format: String,
+    /// See [`Config::expected_root_links`]
+    pub expected_root_links: f64,
+    /// Patch the root even if it doesn't look like the season's (`--force`, see [`crate::ipfs::check_root`])
+    pub force_patch: bool,
+    pub hooks: HooksConfig,
+    pub source: SourceSettings,
+    pub retention: RetentionSettings,
+    /// How many jobs to run at once
+    pub jobs: usize,
+    /// Which recordings to work on (from `--only`)
+    pub only: Selector,
+    /// Leave out the recordings that can't be loaded, instead of failing the whole season (`--keep-going`)
+    pub keep_going: bool,
+    /// Load the drafts that `only` selects as well, when loading from the data dir without a cache (which never has
+    /// drafts in it).  Only `share` does this, for sharing a recording before it's published
+    pub include_drafts: bool,
+    /// Convert every ogg, mp3 and opus file again, even the ones that are up to date (`--force-reconvert`)
+    pub force_reconvert: bool,
+    /// What `keep_going` left out, for the report at the end of the run.  Clones share the same list
+    pub skipped: Arc<Mutex<Vec<SkippedRecording>>>,
+    /// With `--diff-output`, generation compares what it would write with the output instead of writing it
+    pub diff_output: Option<OutputDiff>,
+    /// Fail generation when a page would link to a file that isn't in the data folder, instead of leaving the link
+    /// out (`--strict-links`)
+    pub strict_links: bool,
+    pub timings: Timings,
+    /// The numbers of this run for `metrics_file`.  Clones share the same data
+    pub metrics: Metrics,
+    /// Cache of media info for the files in the data dir, if there is one
+    pub media_cache: Option<Arc<MediaInfoCache>>,
+    /// Digests of files, shared by everything that hashes them
+    pub hashes: Arc<HasherPool>,
+}
+
+impl Default for RunContext {
+    fn default() -> Self {
+        RunContext {
+            static_dir: PathBuf::from("static"),
+            base_url: "https://ipfs.io/ipns/mm.em32.net".to_string(),
+            review_snippet: String::new(),
+            tools: Tools::default(),
+            media_info_backend: MediaInfoBackend::Auto,
+            tool_timeout: Duration::from_secs(60),
+            ipfs_api: None,
+            legacy_links: Vec::new(),
+            roots_history: PathBuf::from(DEFAULT_ROOTS_HISTORY),
+            prime_state: PathBuf::from(DEFAULT_PRIME_STATE),
+            unhealthy_after: DEFAULT_UNHEALTHY_AFTER,
+            include_unhealthy: false,
+            stats_file: PathBuf::from(DEFAULT_STATS_FILE),
+            metrics_file: None,
+            ipfs_offline: false,
+            offline: false,
+            spectrogram: SpectrogramSettings::default(),
+            peak_buckets: DEFAULT_PEAK_BUCKETS,
+            webhook_url: None,
+            webhook_format: WebhookFormat::Json,
+            archive_org: ArchiveOrgSettings::default(),
+            precompress: PrecompressSettings::default(),
+            encode: EncodeSettings::default(),
+            gateways: Vec::new(),
+            hashed_assets: false,
+            date_format: crate::dates::DEFAULT_FORMAT.to_string(),
+            expected_root_links: DEFAULT_EXPECTED_ROOT_LINKS,
+            force_patch: false,
+            hooks: HooksConfig::default(),
+            source: SourceSettings::default(),
+            retention: RetentionSettings::default(),
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            only: Selector::default(),
+            keep_going: false,
+            include_drafts: false,
+            force_reconvert: false,
+            skipped: Arc::default(),
+            diff_output: None,
+            strict_links: false,
+            timings: Timings::default(),
+            metrics: Metrics::default(),
+            media_cache: None,
+            hashes: Arc::new(HasherPool::default()),
+        }
+    }
+}
+
+impl RunContext {
+    /// Creates a context from a config file, using defaults for anything not in the config
+    pub fn from_config(config: &Config) -> RunContext {
+        let mut ctx = RunContext::default();
+        if let Some(static_dir) = &config.static_dir {
+            ctx.static_dir = static_dir.clone();
+        }
+        if let Some(base_url) = &config.base_url {
+            ctx.base_url = base_url.trim_end_matches('/').to_string();
+        }
+        if let Some(jobs) = config.jobs.filter(|j| *j > 0) {
+            ctx.jobs = jobs;
+        }
+        ctx.ipfs_api = config.ipfs_api.clone();
+        if let Some(roots_history) = &config.roots_history {
+            ctx.roots_history = roots_history.clone();
+        }
+        if let Some(prime_state) = &config.prime_state {
+            ctx.prime_state = prime_state.clone();
+        }
+        if let Some(runs) = config.unhealthy_after {
+            ctx.unhealthy_after = runs;
+        }
+        if let Some(stats) = &config.stats {
+            ctx.stats_file = stats.clone();
+        }
+        ctx.metrics_file = config.metrics.clone();
+        ctx.hashed_assets = config.hashed_assets.unwrap_or(false);
+        if let Some(format) = &config.date_format {
+            ctx.date_format = format.clone();
+        }
+        if let Some(fraction) = config.expected_root_links {
+            ctx.expected_root_links = fraction;
+        }
+        ctx.legacy_links = config
+            .legacy_links
+            .iter()
+            .map(|l| l.trim_matches('/').to_string())
+            .collect();
+        ctx.media_info_backend = config.media_info_backend;
+        if let Some(timeout) = config.tool_timeout {
+            ctx.tool_timeout = Duration::from_secs(timeout);
+        }
+        if let Some(width) = config.spectrogram.width.filter(|w| *w > 0) {
+            ctx.spectrogram.width = width;
+        }
+        if let Some(height) = config.spectrogram.height.filter(|h| *h > 0) {
+            ctx.spectrogram.height = height;
+        }
+        if let Some(legend) = config.spectrogram.legend {
+            ctx.spectrogram.legend = legend;
+        }
+        if let Some(buckets) = config.peaks.buckets.filter(|b| *b > 0) {
+            ctx.peak_buckets = buckets;
+        }
+        ctx.webhook_url = config.notify.webhook_url.clone().filter(|url| !url.is_empty());
+        ctx.webhook_format = config.notify.format;
+        if let Some(prefix) = &config.archive_org.identifier_prefix {
+            ctx.archive_org.identifier_prefix = prefix.clone();
+        }
+        if let Some(collection) = &config.archive_org.collection {
+            ctx.archive_org.collection = collection.clone();
+        }
+        ctx.archive_org.license_url = config.archive_org.license_url.clone();
+        if let Some(gzip) = config.precompress.gzip {
+            ctx.precompress.gzip = gzip;
+        }
+        if let Some(brotli) = config.precompress.brotli {
+            ctx.precompress.brotli = brotli;
+        }
+        if let Some(min_size) = config.precompress.min_size {
+            ctx.precompress.min_size = min_size;
+        }
+        ctx.encode = EncodeSettings {
+            ogg_quality: config.encode.ogg_quality,
+            mp3_bitrate: config.encode.mp3_bitrate,
+            sample_rate: config.encode.sample_rate.filter(|r| *r > 0),
+        };
+        ctx.gateways = config.gateways.clone();
+        ctx.hooks = config.hooks.clone();
+        if let Some(snapshot) = config.source.snapshot {
+            ctx.source.snapshot = snapshot;
+        }
+        ctx.source.sensitive = config.source.sensitive.clone();
+        let retention = &config.retention;
+        if let Some(entries) = retention.roots_history_entries.filter(|n| *n > 0) {
+            ctx.retention.roots_history.max_entries = entries;
+        }
+        if let Some(kb) = retention.roots_history_kb.filter(|n| *n > 0) {
+            ctx.retention.roots_history.max_bytes = kb * 1024;
+        }
+        if let Some(entries) = retention.media_cache_entries.filter(|n| *n > 0) {
+            ctx.retention.media_cache.max_entries = entries;
+        }
+        if let Some(kb) = retention.media_cache_kb.filter(|n| *n > 0) {
+            ctx.retention.media_cache.max_bytes = kb * 1024;
+        }
+        if let Some(ffmpeg) = &config.tools.ffmpeg {
+            ctx.tools.ffmpeg = ffmpeg.clone();
+        }
+        if let Some(ffprobe) = &config.tools.ffprobe {
+            ctx.tools.ffprobe = ffprobe.clone();
+        }
+        if let Some(mediainfo) = &config.tools.mediainfo {
+            ctx.tools.mediainfo = mediainfo.clone();
+        }
+        if let Some(ipfs) = &config.tools.ipfs {
+            ctx.tools.ipfs = ipfs.clone();
+        }
+        if let Some(brotli) = &config.tools.brotli {
+            ctx.tools.brotli = brotli.clone();
+        }
+        ctx
+    }
+
+    /// Runs a stage of the pipeline, sending progress events and recording how long it took
+    pub fn stage<T, E>(&self, stage: Stage, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let (result, elapsed) = progress::timed_stage(stage, f);
+        self.timings.record_stage(stage, elapsed);
+        result
+    }
+
+    /// Adds recordings that were left out of a season to the ones reported at the end of the run
+    ///
+    /// The season is often loaded more than once in a run, so a recording is only listed once.
+    pub fn record_skipped(&self, skipped: &[SkippedRecording]) {
+        let mut all = self.skipped.lock().unwrap();
+        for skip in skipped {
+            if !all.iter().any(|s| s.path == skip.path) {
+                all.push(skip.clone());
+            }
+        }
+    }
+
+    /// Prints the timings table, and sends it as a progress event
+    pub fn report_timings(&self) {
+        say_part!("{}", self.timings.table());
+        progress::emit(progress::ProgressEvent::Timings {
+            stages: self.timings.stages(),
+            slowest: self.timings.slowest_items(),
+        });
+    }
+
+    /// Writes the metrics file, if the config asks for one.  A run that failed still has metrics worth writing, so this
+    /// only warns when the file can't be written
+    pub fn write_metrics(&self) {
+        if let Some(path) = &self.metrics_file {
+            if let Err(e) = crate::metrics::write(path, &self.metrics, &self.timings.stages()) {
+                say!("Warning: failed to write the metrics: {:#}", anyhow::Error::new(e));
+            }
+        }
+    }
+
+    /// Which tool will actually be used for media info, after working out what `Auto` means
+    pub fn resolved_media_info_backend(&self) -> MediaInfoBackend {
+        match self.media_info_backend {
+            MediaInfoBackend::Auto if crate::command::find_tool(&self.tools.mediainfo).is_some() => {
+                MediaInfoBackend::Mediainfo
+            }
+            MediaInfoBackend::Auto => MediaInfoBackend::Ffprobe,
+            backend => backend,
+        }
+    }
+
+    /// Fails with [`CbError::Offline`] in an `--offline` run.  `what` is what needed the network, for the message
+    pub fn require_online(&self, what: &str) -> Result<(), CbError> {
+        if self.offline {
+            return Err(CbError::Offline { what: what.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Returns a new HTTP client, or an error in an `--offline` run
+    ///
+    /// Every request goes through a client from here, so that an offline run can't make one.
+    #[cfg(feature = "reqwest")]
+    pub fn http_client(&self, what: &str, timeout: Duration) -> Result<reqwest::blocking::Client, CbError> {
+        self.require_online(what)?;
+        reqwest::blocking::ClientBuilder::new()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| CbError::tool(what, e))
+    }
+
+    /// Returns a new `ipfs` command, pointed at the right API
+    pub fn ipfs_command(&self) -> Command {
+        let mut cmd = Command::new(&self.tools.ipfs);
+        if let Some(api) = &self.ipfs_api {
+            cmd.arg(format!("--api={}", api));
+        }
+        if self.ipfs_offline || self.offline {
+            cmd.arg("--offline");
+        }
+        cmd
+    }
+}
+
+/// Returns the snippet that enables the GitLab visual review toolbar for a merge request
+pub fn gitlab_review_snippet(merge_request_iid: &str) -> String {
+    format!(
+        r#"<script defer data-project-id="22680986" data-project-path="eminence/benderfactory" data-merge-request-id="{}" data-mr-url="https://gitlab.com" id="review-app-toolbar-script" src="https://gitlab.com/assets/webpack/visual_review_toolbar.js"></script>"#,
+        merge_request_iid
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateway_config() {
+        let config = Config::parse(
+            r#"
+            [[gateway]]
+            url = "https://{base32}.ipfs.example.com"
+            headers = { Host = "ipfs.example.com" }
+            token_env = "EXAMPLE_GATEWAY_TOKEN"
+            "#,
+        )
+        .unwrap();
+        let ctx = RunContext::from_config(&config);
+        assert_eq!(ctx.gateways.len(), 1);
+        assert_eq!(ctx.gateways[0].headers["Host"], "ipfs.example.com");
+        assert_eq!(ctx.gateways[0].token_env.as_deref(), Some("EXAMPLE_GATEWAY_TOKEN"));
+
+        // tokens go in the environment, not in the config
+        let e = Config::parse(
+            r#"
+            [[gateway]]
+            url = "https://ipfs.example.com/ipfs/{v0}"
+            headers = { authorization = "Bearer abc" }
+            "#,
+        )
+        .unwrap_err();
+        assert!(e.to_string().contains("token_env"), "{}", e);
+    }
+
+    #[test]
+    fn default_config_is_all_defaults() {
+        let config = Config::parse(crate::assets::DEFAULT_CONFIG).unwrap();
+        let ctx = RunContext::from_config(&config);
+        let default = RunContext::default();
+        assert_eq!(ctx.static_dir, default.static_dir);
+        assert_eq!(ctx.base_url, default.base_url);
+        assert_eq!(ctx.tools.ffmpeg, default.tools.ffmpeg);
+        assert_eq!(ctx.ipfs_api, None);
+        assert_eq!(ctx.media_info_backend, MediaInfoBackend::Auto);
+        assert_eq!(ctx.spectrogram, default.spectrogram);
+        assert_eq!(ctx.webhook_url, None);
+        assert_eq!(ctx.webhook_format, WebhookFormat::Json);
+        assert_eq!(ctx.archive_org, default.archive_org);
+        assert_eq!(ctx.precompress, default.precompress);
+        assert_eq!(ctx.encode, default.encode);
+        assert_eq!(ctx.retention, default.retention);
+        assert_eq!(ctx.unhealthy_after, DEFAULT_UNHEALTHY_AFTER);
+        assert_eq!(ctx.date_format, crate::dates::DEFAULT_FORMAT);
+        assert_eq!(ctx.expected_root_links, DEFAULT_EXPECTED_ROOT_LINKS);
+    }
+
+    #[test]
+    fn default_config_only_has_real_settings() {
+        // with every setting uncommented, a key that nothing reads is an unknown field.  A line of the explanations
+        // that happens to start like a setting isn't one on its own
+        let uncommented: String = crate::assets::DEFAULT_CONFIG
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting) if setting.parse::<toml::Value>().is_ok() => setting,
+                _ => line,
+            })
+            .flat_map(|line| [line, "\n"])
+            .collect();
+        assert!(uncommented.contains("\nunhealthy_after = 3\n"), "{}", uncommented);
+        if let Err(e) = Config::parse(&uncommented) {
+            panic!("{}\n{}", e, uncommented);
+        }
+    }
+
+    #[test]
+    fn encode_settings() {
+        let config = Config::parse(
+            r#"
+            [encode]
+            ogg_quality = 6
+            mp3_bitrate = "320k"
+            sample_rate = 44100
+            "#,
+        )
+        .unwrap();
+        let ctx = RunContext::from_config(&config);
+        let args = |output: &str| ctx.encode.encoder_args(Path::new(output)).unwrap();
+        assert_eq!(args("ogg/a.ogg"), ["-q:a", "6", "-ar", "44100"]);
+        assert_eq!(args("mp3/a.MP3"), ["-b:a", "320k", "-ar", "44100"]);
+        assert_eq!(args("opus/a.opus"), ["-c:a", "libopus"]);
+        let e = ctx.encode.encoder_args(Path::new("a.wav")).unwrap_err();
+        assert!(matches!(e, CbError::UnsupportedFormat { .. }), "{}", e);
+
+        // nothing is set by default, not even for a VBR preset that ogg doesn't have
+        let vbr = EncodeSettings {
+            mp3_bitrate: Some("V0".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(vbr.encoder_args(Path::new("a.mp3")).unwrap(), ["-q:a", "0"]);
+        assert!(vbr.encoder_args(Path::new("a.ogg")).unwrap().is_empty());
+
+        assert_eq!("128".parse(), Ok(Mp3Bitrate::Constant(128)));
+        for invalid in ["V10", "1000k", "loud", ""] {
+            assert!(invalid.parse::<Mp3Bitrate>().is_err(), "{}", invalid);
+        }
+        assert!(Config::parse("[encode]\nogg_quality = 11").is_err());
+        assert!(Config::parse("[encode]\nmp3_bitrate = \"V10\"").is_err());
+    }
+
+    #[test]
+    fn checked_settings() {
+        // a typo fails the config, rather than every page
+        let e = Config::parse("date_format = \"%-d %B %Q\"").unwrap_err();
+        assert!(e.to_string().contains("Invalid date format"), "{}", e);
+        assert!(Config::parse("date_format = \"%d.%m.%Y\"").is_ok());
+        assert!(Config::parse("expected_root_links = 1.5").is_err());
+    }
+
+    #[test]
+    fn config_overrides() {
+        let config = Config::parse(
+            r#"
+            static_dir = "/srv/static"
+            base_url = "https://example.com/archive/"
+            jobs = 3
+            ipfs_api = "/ip4/127.0.0.1/tcp/5002"
+            media_info_backend = "ffprobe"
+            unhealthy_after = 5
+            date_format = "%Y-%m-%d"
+            expected_root_links = 0.8
+
+            [tools]
+            mediainfo = "/opt/bin/mediainfo"
+
+            [spectrogram]
+            height = 256
+            legend = false
+
+            [peaks]
+            buckets = 500
+
+            [hooks]
+            post_publish = { command = "purge-cache", allow_failure = true }
+
+            [source]
+            sensitive = ["torrent"]
+
+            [retention]
+            roots_history_entries = 50
+
+            [notify]
+            webhook_url = "https://discord.com/api/webhooks/1/abc"
+            format = "discord"
+
+            [archive_org]
+            license_url = "https://creativecommons.org/licenses/by-nc-sa/4.0/"
+
+            [precompress]
+            gzip = true
+            min_size = 4096
+            "#,
+        )
+        .unwrap();
+        let ctx = RunContext::from_config(&config);
+        assert_eq!(ctx.static_dir, Path::new("/srv/static"));
+        assert_eq!(ctx.base_url, "https://example.com/archive");
+        assert_eq!(ctx.jobs, 3);
+        assert_eq!(ctx.tools.mediainfo, Path::new("/opt/bin/mediainfo"));
+        assert_eq!(ctx.tools.ffmpeg, Path::new("ffmpeg"));
+        assert_eq!(ctx.resolved_media_info_backend(), MediaInfoBackend::Ffprobe);
+        assert_eq!(ctx.peak_buckets, 500);
+        assert_eq!(ctx.unhealthy_after, 5);
+        assert_eq!(ctx.date_format, "%Y-%m-%d");
+        assert_eq!(ctx.expected_root_links, 0.8);
+        assert_eq!(
+            ctx.hooks.post_publish,
+            Some(HookConfig {
+                command: "purge-cache".to_string(),
+                allow_failure: true
+            })
+        );
+        assert_eq!(ctx.hooks.post_generate, None);
+        assert_eq!(
+            ctx.source,
+            SourceSettings {
+                snapshot: true,
+                sensitive: vec!["torrent".to_string()]
+            }
+        );
+        assert_eq!(
+            ctx.retention.roots_history,
+            Retention {
+                max_entries: 50,
+                max_bytes: DEFAULT_ROOTS_HISTORY_RETENTION.max_bytes
+            }
+        );
+        assert_eq!(ctx.retention.media_cache, DEFAULT_MEDIA_CACHE_RETENTION);
+        assert_eq!(
+            ctx.spectrogram,
+            SpectrogramSettings {
+                width: 1024,
+                height: 256,
+                legend: false
+            }
+        );
+
+        assert_eq!(
+            ctx.webhook_url.as_deref(),
+            Some("https://discord.com/api/webhooks/1/abc")
+        );
+        assert_eq!(ctx.webhook_format, WebhookFormat::Discord);
+        assert_eq!(ctx.archive_org.collection, "opensource_audio");
+        assert_eq!(
+            ctx.archive_org.license_url.as_deref(),
+            Some("https://creativecommons.org/licenses/by-nc-sa/4.0/")
+        );
+
+        assert_eq!(
+            ctx.precompress,
+            PrecompressSettings {
+                gzip: true,
+                brotli: false,
+                min_size: 4096
+            }
+        );
+
+        let cmd = ctx.ipfs_command();
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(args, ["--api=/ip4/127.0.0.1/tcp/5002"]);
+    }
+
+    #[test]
+    #[cfg(feature = "ipfs")]
+    fn offline() {
+        use std::str::FromStr;
+
+        // none of these get as far as running ipfs
+        let mut ctx = RunContext {
+            offline: true,
+            ..RunContext::default()
+        };
+        ctx.tools.ipfs = PathBuf::from("/nonexistent/ipfs");
+        let offline = |result: Result<(), CbError>| matches!(result, Err(CbError::Offline { .. }));
+        let root = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+
+        assert!(matches!(
+            ctx.http_client("test", Duration::from_secs(1)),
+            Err(CbError::Offline { .. })
+        ));
+        assert!(offline(
+            crate::ipfs::prime_public_gateways(&ctx, &root, &Default::default()).map(|_| ())
+        ));
+        assert!(offline(crate::ipfs::publish_name(&ctx, &root, "self")));
+        assert!(offline(crate::gateway::benchmark(&ctx, &root, 1).map(|_| ())));
+        assert!(offline(crate::notify::send(
+            &ctx,
+            "http://127.0.0.1:9/",
+            &serde_json::json!({})
+        )));
+        assert!(ctx.ipfs_command().get_args().any(|arg| arg == "--offline"));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Config::parse("statc_dir = \"typo\"").is_err());
+        assert!(Config::parse("media_info_backend = \"sox\"").is_err());
+    }
+}
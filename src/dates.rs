@@ -0,0 +1,86 @@
+//! Showing a recording's `recorded_date` on the pages, like "5 March 2021"
+//!
+//! The format is a strftime string (`date_format` in the config, [`DEFAULT_FORMAT`] otherwise), and month and day
+//! names are always English, so the pages come out the same wherever they're generated.  On the pages, the date is a
+//! `<time>` element with the date in ISO 8601 for machines.  A date that can't be read is shown as it's written in the
+//! recording's JSON.
+
+use std::fmt::Write;
+
+use chrono::NaiveDate;
+
+/// The format dates are shown in, unless the config says otherwise
+pub const DEFAULT_FORMAT: &str = "%-d %B %Y";
+
+/// Reads a `recorded_date`, which is written like 2021/03/05 (or 2021-03-05)
+pub fn parse(recorded_date: &str) -> Option<NaiveDate> {
+    let date = recorded_date.trim();
+    NaiveDate::parse_from_str(date, "%Y/%m/%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%Y-%m-%d"))
+        .ok()
+}
+
+/// Checks a `date_format` from the config
+///
+/// chrono only finds out that a format is wrong (or asks for a time, which a date doesn't have) while formatting, so
+/// this formats a date with it.
+pub fn check_format(format: &str) -> Result<(), String> {
+    let mut formatted = String::new();
+    match write!(formatted, "{}", NaiveDate::from_ymd(2021, 3, 5).format(format)) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(format!(
+            "Invalid date format {:?} (expected something like {:?})",
+            format, DEFAULT_FORMAT
+        )),
+    }
+}
+
+/// `recorded_date` in `format`, or as it is if it can't be read
+pub fn display(recorded_date: &str, format: &str) -> String {
+    let mut formatted = String::new();
+    match parse(recorded_date) {
+        Some(date) if write!(formatted, "{}", date.format(format)).is_ok() => formatted,
+        _ => recorded_date.to_string(),
+    }
+}
+
+/// `recorded_date` as a `<time>` element, shown in `format`, or as (escaped) text if it can't be read
+pub fn time_element(recorded_date: &str, format: &str) -> String {
+    let shown = crate::markdown::escape(&display(recorded_date, format));
+    match parse(recorded_date) {
+        Some(date) => format!("<time datetime=\"{}\">{}</time>", date.format("%Y-%m-%d"), shown),
+        None => shown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse("2021/03/05"), Some(NaiveDate::from_ymd(2021, 3, 5)));
+        assert_eq!(parse("2021-03-05"), Some(NaiveDate::from_ymd(2021, 3, 5)));
+        assert_eq!(parse("spring 2021"), None);
+        assert_eq!(parse("2021/02/30"), None);
+
+        assert_eq!(display("2021/03/05", DEFAULT_FORMAT), "5 March 2021");
+        assert_eq!(display("2021/03/05", "%a %d/%m/%y"), "Fri 05/03/21");
+        assert_eq!(display("unknown", DEFAULT_FORMAT), "unknown");
+
+        assert_eq!(
+            time_element("2021/03/05", DEFAULT_FORMAT),
+            "<time datetime=\"2021-03-05\">5 March 2021</time>"
+        );
+        assert_eq!(time_element("<soon>", DEFAULT_FORMAT), "&lt;soon&gt;");
+    }
+
+    #[test]
+    fn formats() {
+        assert!(check_format(DEFAULT_FORMAT).is_ok());
+        assert!(check_format("%Y-%m-%d").is_ok());
+        assert!(check_format("%-d %Q %Y").is_err());
+        // a date has no time to show
+        assert!(check_format("%H:%M").is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! What generating would change in the output (`--diff-output`)
+//!
+//! With [`RunContext::diff_output`](crate::context::RunContext::diff_output) set, generation renders everything as
+//! usual, but hands each file to an [`OutputDiff`] instead of writing it.  Files the build manifest says are already
+//! up to date aren't even read; the others are compared with what's in the output, and the ones that differ get a
+//! unified diff.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// How many lines of diff are shown for each file, unless `--diff-lines` says otherwise
+pub const DEFAULT_DIFF_LINES: usize = 40;
+
+/// Lines of unchanged text around each change
+const CONTEXT: usize = 3;
+
+/// Above this many (old lines × new lines) in the part of a file that changed, it's shown as all removed and all
+/// added rather than working out the smallest diff
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The file isn't in the output yet
+    Added,
+    Changed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// The unified diff, cut down to the first `max_lines` lines
+    pub diff: Vec<String>,
+    /// How many lines of the diff were cut
+    pub omitted: usize,
+}
+
+/// Collects what generation would change.  Clones share the same results
+#[derive(Debug, Clone)]
+pub struct OutputDiff {
+    max_lines: usize,
+    changes: Arc<Mutex<Vec<FileChange>>>,
+    unchanged: Arc<AtomicUsize>,
+}
+
+impl OutputDiff {
+    pub fn new(max_lines: usize) -> OutputDiff {
+        OutputDiff {
+            max_lines,
+            changes: Arc::default(),
+            unchanged: Arc::default(),
+        }
+    }
+
+    /// Counts a file that the build manifest already says is up to date
+    pub fn unchanged(&self) {
+        self.unchanged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Compares what would be written to `path` with what's there now
+    pub fn compare(&self, path: &Path, contents: &[u8]) {
+        let (kind, old) = match std::fs::read(path) {
+            Ok(old) if old == contents => return self.unchanged(),
+            Ok(old) => (ChangeKind::Changed, old),
+            Err(_) => (ChangeKind::Added, Vec::new()),
+        };
+        let diff = match (std::str::from_utf8(&old), std::str::from_utf8(contents)) {
+            (Ok(old), Ok(new)) => unified_diff(old, new),
+            _ => vec![format!(
+                "Binary file ({} bytes, was {} bytes)",
+                contents.len(),
+                old.len()
+            )],
+        };
+        self.record(path, kind, diff);
+    }
+
+    /// Compares a JSON file, after pretty-printing both versions, since it's written on a single line
+    pub fn compare_json<T: serde::Serialize>(&self, path: &Path, value: &T) -> Result<(), serde_json::Error> {
+        // both go through a Value, so that the fields are in the same order
+        let new = serde_json::to_string_pretty(&serde_json::to_value(value)?)? + "\n";
+        let old = std::fs::read(path).ok().map(|bytes| {
+            serde_json::from_slice::<serde_json::Value>(&bytes)
+                .ok()
+                .and_then(|json| serde_json::to_string_pretty(&json).ok())
+                .map_or_else(|| String::from_utf8_lossy(&bytes).to_string(), |pretty| pretty + "\n")
+        });
+        match old {
+            Some(old) if old == new => self.unchanged(),
+            Some(old) => self.record(path, ChangeKind::Changed, unified_diff(&old, &new)),
+            None => self.record(path, ChangeKind::Added, unified_diff("", &new)),
+        }
+        Ok(())
+    }
+
+    fn record(&self, path: &Path, kind: ChangeKind, mut diff: Vec<String>) {
+        let omitted = diff.len().saturating_sub(self.max_lines);
+        diff.truncate(self.max_lines);
+        self.changes.lock().unwrap().push(FileChange {
+            path: path.to_path_buf(),
+            kind,
+            diff,
+            omitted,
+        });
+    }
+
+    /// The files that would change, in the order they were generated
+    pub fn changes(&self) -> Vec<FileChange> {
+        self.changes.lock().unwrap().clone()
+    }
+
+    pub fn unchanged_count(&self) -> usize {
+        self.unchanged.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// The lines of a unified diff from `old` to `new` (just the hunks, without the `---`/`+++` header)
+pub fn unified_diff(old: &str, new: &str) -> Vec<String> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let ops = diff_ops(&a, &b);
+
+    // where each op is in the old and new files
+    let mut positions = Vec::with_capacity(ops.len() + 1);
+    let (mut i, mut j) = (0, 0);
+    for op in &ops {
+        positions.push((i, j));
+        match op {
+            Op::Same => {
+                i += 1;
+                j += 1;
+            }
+            Op::Removed => i += 1,
+            Op::Added => j += 1,
+        }
+    }
+    positions.push((i, j));
+
+    // changes that are close enough together share a hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (at, _) in ops.iter().enumerate().filter(|(_, op)| **op != Op::Same) {
+        let start = at.saturating_sub(CONTEXT);
+        let end = (at + 1 + CONTEXT).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for (start, end) in hunks {
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        let range = |start: usize, len: usize| {
+            // an empty range is given as the line before it
+            let first = if len == 0 { start } else { start + 1 };
+            format!("{},{}", first, len)
+        };
+        lines.push(format!(
+            "@@ -{} +{} @@",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        ));
+        for (op, &(i, j)) in ops[start..end].iter().zip(&positions[start..end]) {
+            lines.push(match op {
+                Op::Same => format!(" {}", a[i]),
+                Op::Removed => format!("-{}", a[i]),
+                Op::Added => format!("+{}", b[j]),
+            });
+        }
+    }
+    lines
+}
+
+/// The shortest way from `a` to `b`, found with the longest common subsequence of the part between the lines they
+/// start and end with
+fn diff_ops(a: &[&str], b: &[&str]) -> Vec<Op> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+
+    let mut ops = vec![Op::Same; prefix];
+    if (a_mid.len() + 1) * (b_mid.len() + 1) > MAX_DIFF_CELLS {
+        ops.extend(std::iter::repeat_n(Op::Removed, a_mid.len()));
+        ops.extend(std::iter::repeat_n(Op::Added, b_mid.len()));
+    } else {
+        // lcs[i][j] is the length of the longest common subsequence of a_mid[i..] and b_mid[j..]
+        let width = b_mid.len() + 1;
+        let mut lcs = vec![0u32; (a_mid.len() + 1) * width];
+        for i in (0..a_mid.len()).rev() {
+            for j in (0..b_mid.len()).rev() {
+                lcs[i * width + j] = if a_mid[i] == b_mid[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a_mid.len() || j < b_mid.len() {
+            if i < a_mid.len() && j < b_mid.len() && a_mid[i] == b_mid[j] {
+                ops.push(Op::Same);
+                i += 1;
+                j += 1;
+            } else if i < a_mid.len() && (j == b_mid.len() || lcs[(i + 1) * width + j] >= lcs[i * width + j + 1]) {
+                // removals go before additions, as in other diffs
+                ops.push(Op::Removed);
+                i += 1;
+            } else {
+                ops.push(Op::Added);
+                j += 1;
+            }
+        }
+    }
+    ops.extend(std::iter::repeat_n(Op::Same, suffix));
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hunks() {
+        let old: String = (1..=20).map(|i| format!("line {}\n", i)).collect();
+        let new = old
+            .replace("line 2\n", "line two\n")
+            .replace("line 4\n", "")
+            .replace("line 18\n", "line 18\nline 18.5\n");
+        assert_eq!(
+            unified_diff(&old, &new),
+            [
+                "@@ -1,7 +1,6 @@",
+                " line 1",
+                "-line 2",
+                "+line two",
+                " line 3",
+                "-line 4",
+                " line 5",
+                " line 6",
+                " line 7",
+                "@@ -16,5 +15,6 @@",
+                " line 16",
+                " line 17",
+                " line 18",
+                "+line 18.5",
+                " line 19",
+                " line 20",
+            ]
+        );
+        assert!(unified_diff(&old, &old).is_empty());
+        assert_eq!(unified_diff("", "a\nb\n"), ["@@ -0,0 +1,2 @@", "+a", "+b"]);
+    }
+
+    #[test]
+    fn collecting() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = dir.path().join("index.html");
+        std::fs::write(&page, "<p>old</p>\n").unwrap();
+        let diff = OutputDiff::new(3);
+        diff.compare(&page, b"<p>old</p>\n");
+        diff.compare(&page, b"<p>new</p>\n");
+        let long: String = (0..10).map(|i| format!("{}\n", i)).collect();
+        diff.compare(&dir.path().join("new.html"), long.as_bytes());
+
+        assert_eq!(diff.unchanged_count(), 1);
+        let changes = diff.changes();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].kind, ChangeKind::Changed);
+        assert_eq!(changes[0].diff, ["@@ -1,1 +1,1 @@", "-<p>old</p>", "+<p>new</p>"]);
+        assert_eq!(changes[1].kind, ChangeKind::Added);
+        assert_eq!(changes[1].diff.len(), 3);
+        assert_eq!(changes[1].omitted, 8);
+        // nothing was written
+        assert_eq!(std::fs::read_to_string(&page).unwrap(), "<p>old</p>\n");
+        assert!(!dir.path().join("new.html").exists());
+    }
+}
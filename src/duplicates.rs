@@ -0,0 +1,219 @@
+//! Finding flacs that were copied into more than one recording (`--find-duplicates`)
+//!
+//! IPFS already stores identical files once, so duplicates don't make the published root any bigger.  They do waste
+//! space in the source archive (and make it unclear which recording a stem really belongs to), which is what this
+//! report is for.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::{
+    analysis::parallel_for_each,
+    context::RunContext,
+    error::CbError,
+    progress::{self, ProgressEvent, Stage},
+    types::Season,
+};
+
+/// One copy of a duplicated flac
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DuplicateFile {
+    /// The recording's data_folder
+    pub recording: String,
+    pub track: String,
+    #[serde(serialize_with = "crate::serialize_slashed::path")]
+    pub path: PathBuf,
+}
+
+/// Flacs with the same contents
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// SHA-256 of the contents
+    pub sha256: String,
+    /// Size of each copy
+    pub bytes: u64,
+    pub files: Vec<DuplicateFile>,
+}
+
+impl DuplicateGroup {
+    /// Space that would be saved by keeping only one copy
+    pub fn wasted_bytes(&self) -> u64 {
+        self.bytes * (self.files.len() as u64 - 1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DuplicateReport {
+    pub groups: Vec<DuplicateGroup>,
+    /// Total of [`DuplicateGroup::wasted_bytes`] over all groups
+    pub duplicated_bytes: u64,
+    pub note: &'static str,
+}
+
+const NOTE: &str = "IPFS already stores identical content only once, so these duplicates don't grow the published \
+                    root.  They only waste space in the source archive, so that's where to clean them up.";
+
+impl DuplicateReport {
+    fn new(mut groups: Vec<DuplicateGroup>) -> DuplicateReport {
+        for group in &mut groups {
+            group.files.sort();
+        }
+        // biggest waste first
+        groups.sort_by(|a, b| b.wasted_bytes().cmp(&a.wasted_bytes()).then(a.files.cmp(&b.files)));
+        DuplicateReport {
+            duplicated_bytes: groups.iter().map(DuplicateGroup::wasted_bytes).sum(),
+            groups,
+            note: NOTE,
+        }
+    }
+
+    /// The report as text, for printing
+    pub fn human(&self) -> String {
+        let mut s = String::new();
+        if self.groups.is_empty() {
+            writeln!(s, "No duplicate flacs found").unwrap();
+            return s;
+        }
+        for group in &self.groups {
+            writeln!(
+                s,
+                "{} copies of {}MB ({}):",
+                group.files.len(),
+                group.bytes / 1024 / 1024,
+                group.sha256
+            )
+            .unwrap();
+            for file in &group.files {
+                writeln!(s, "  {} / {}: {}", file.recording, file.track, file.path.display()).unwrap();
+            }
+        }
+        writeln!(
+            s,
+            "\n{} groups of duplicates, {}MB could be saved",
+            self.groups.len(),
+            self.duplicated_bytes / 1024 / 1024
+        )
+        .unwrap();
+        writeln!(s, "Note: {}", self.note).unwrap();
+        s
+    }
+}
+
+/// Hashes the flacs of the selected recordings, and groups the ones with identical contents
+///
+/// Only flacs that have the same size as another flac are hashed, since files of different sizes can't be the same.
+pub fn find_duplicates(ctx: &RunContext, season: &Season) -> Result<DuplicateReport, CbError> {
+    ctx.stage(Stage::Analyze, || {
+        let mut by_size: HashMap<u64, Vec<DuplicateFile>> = HashMap::new();
+        for rec in ctx.only.select(season)? {
+            for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+                if let Some(path) = track.flac_ondisk() {
+                    by_size.entry(track.flac_bytes).or_default().push(DuplicateFile {
+                        recording: rec.data_folder.clone(),
+                        track: track.name.clone(),
+                        path,
+                    });
+                }
+            }
+        }
+        let todo: Vec<(u64, DuplicateFile)> = by_size
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .flat_map(|(size, files)| files.into_iter().map(move |f| (size, f)))
+            .collect();
+        let total = todo.len();
+
+        let done = AtomicUsize::new(0);
+        let hashed = Mutex::new(BTreeMap::<(String, u64), Vec<DuplicateFile>>::new());
+        let errors = Mutex::new(Vec::new());
+        parallel_for_each(ctx.jobs, todo, |(size, file)| {
+            match ctx.hashes.sha256(&file.path) {
+                Ok(digest) => hashed
+                    .lock()
+                    .unwrap()
+                    .entry((digest, size))
+                    .or_default()
+                    .push(file.clone()),
+                Err(e) => errors.lock().unwrap().push(e),
+            }
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Analyze,
+                item: file.path.display().to_string(),
+                index: done.fetch_add(1, Ordering::SeqCst) + 1,
+                total,
+            });
+        });
+
+        let errors = errors.into_inner().unwrap();
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Analyze,
+            processed: total,
+            errors: errors.len(),
+            warnings: 0,
+        });
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+
+        let groups = hashed
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|((sha256, bytes), files)| DuplicateGroup { sha256, bytes, files })
+            .collect();
+        Ok(DuplicateReport::new(groups))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(recording: &str, track: &str) -> DuplicateFile {
+        DuplicateFile {
+            recording: recording.to_string(),
+            track: track.to_string(),
+            path: PathBuf::from(format!("{}/{}.flac", recording, track)),
+        }
+    }
+
+    #[test]
+    fn report() {
+        let report = DuplicateReport::new(vec![
+            DuplicateGroup {
+                sha256: "aa".to_string(),
+                bytes: 1024 * 1024,
+                files: vec![file("jam2", "kick"), file("jam1", "kick")],
+            },
+            DuplicateGroup {
+                sha256: "bb".to_string(),
+                bytes: 3 * 1024 * 1024,
+                files: vec![file("jam1", "bass"), file("jam2", "bass"), file("jam3", "bass")],
+            },
+        ]);
+        assert_eq!(report.duplicated_bytes, 7 * 1024 * 1024);
+        assert_eq!(report.groups[0].sha256, "bb");
+        assert_eq!(report.groups[1].files[0], file("jam1", "kick"));
+
+        let human = report.human();
+        assert!(human.starts_with("3 copies of 3MB (bb):\n  jam1 / bass: jam1/bass.flac\n"));
+        assert!(human.contains("2 groups of duplicates, 7MB could be saved"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["duplicated_bytes"], 7 * 1024 * 1024);
+        assert_eq!(json["groups"][1]["files"][1]["recording"], "jam2");
+        assert!(json["note"].as_str().unwrap().contains("IPFS"));
+
+        assert_eq!(DuplicateReport::new(Vec::new()).human(), "No duplicate flacs found\n");
+    }
+}
@@ -0,0 +1,212 @@
+//! The error type returned by the library
+//!
+//! The command line just prints errors, but other tools built on this crate need to tell "the JSON is wrong" from
+//! "mediainfo isn't installed" without matching on strings.  Internal helpers still use anyhow, and their errors end up
+//! as the `source` of one of these.
+
+use std::path::PathBuf;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CbError {
+    /// A JSON file doesn't match its schema
+    #[error("{} is not valid, schema validation failed: {details}", path.display())]
+    SchemaValidation { path: PathBuf, details: String },
+
+    /// A schema file can't be used to check anything: it isn't JSON, or isn't a valid schema
+    #[error(
+        "The schema {} is broken at {}: {details}",
+        schema.display(),
+        if pointer.is_empty() { "its root" } else { pointer.as_str() }
+    )]
+    InvalidSchema {
+        schema: PathBuf,
+        /// JSON pointer to the part of the schema that's wrong ("" for the whole file)
+        pointer: String,
+        details: String,
+    },
+
+    /// A file that should exist doesn't
+    #[error("{} does not exist", path.display())]
+    MissingFile { path: PathBuf },
+
+    /// An external program (like ffmpeg or mediainfo) couldn't be run, or failed
+    #[error("{tool} failed")]
+    ExternalTool {
+        tool: String,
+        #[source]
+        source: BoxError,
+    },
+
+    /// A conversion's output is a format we don't know how to encode
+    #[error("Don't know how to encode {}: only .ogg, .mp3 and .opus files can be made", path.display())]
+    UnsupportedFormat { path: PathBuf },
+
+    /// The ipfs command ran, but the daemon reported an error
+    #[error("Failed to run ipfs {command}: {status} {stderr}")]
+    IpfsDaemon {
+        command: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+
+    /// Something (a JSON file, a CID, some tool's output) couldn't be understood
+    #[error("{context}")]
+    Parse {
+        context: String,
+        #[source]
+        source: BoxError,
+    },
+
+    /// A recording's slug isn't usable in a URL
+    #[error("{recording} has the slug {slug:?}, but slugs can only have lowercase letters, digits and single dashes")]
+    InvalidSlug { slug: String, recording: String },
+
+    /// A recording has a tag with no letters or digits in it
+    #[error("{recording} has the tag {tag:?}, which has no letters or digits to make a slug from")]
+    InvalidTag { tag: String, recording: String },
+
+    /// Two recordings would end up with their pages in the same folder
+    #[error("The slug {slug:?} is used by more than one recording: {}", recordings.join(", "))]
+    DuplicateSlug { slug: String, recordings: Vec<String> },
+
+    /// Nobody is named as the artist of a recording (there used to be a built-in default)
+    #[error(
+        "Neither the season nor {recording} says who the artist is; the name isn't built in any more, so add \
+         \"artist\": \"Colin Benders\" to season.json to keep the one that was used before"
+    )]
+    MissingArtist { recording: String },
+
+    /// The output is also where the sources are, and there's no record of which files in it were generated
+    #[error(
+        "{} has sources in it but no {}, so generated files can't be told apart from them; run the generation again \
+         first", output.display(), crate::manifest::MANIFEST_FILE
+    )]
+    MissingManifest { output: PathBuf },
+
+    /// Generated pages refer to files that don't exist (see [`crate::references`])
+    #[error(
+        "The generated pages have {} references to files that don't exist, like: {}",
+        references.len(),
+        references.first().map(ToString::to_string).unwrap_or_default()
+    )]
+    BrokenReferences {
+        references: Vec<crate::references::BrokenReference>,
+    },
+
+    /// An include file in season.json includes itself, directly or through others
+    #[error("The recordings include themselves: {}", include_chain(chain))]
+    IncludeCycle { chain: Vec<PathBuf> },
+
+    /// Include files in season.json are nested too deeply
+    #[error("Recordings can only be included {limit} levels deep: {}", include_chain(chain))]
+    IncludeTooDeep { chain: Vec<PathBuf>, limit: usize },
+
+    /// A recording listed in an include file couldn't be loaded
+    #[error("Failed to load {}, which is listed through {}", path.display(), include_chain(chain))]
+    Included {
+        path: PathBuf,
+        /// season.json, then each include file, outermost first
+        chain: Vec<PathBuf>,
+        #[source]
+        source: Box<CbError>,
+    },
+
+    /// `--prime --resume` was asked to carry on, but there's no earlier run for the root
+    #[error("{} has no priming of {root} to carry on with; leave out --resume to start one", path.display())]
+    NothingToResume { path: PathBuf, root: String },
+
+    /// Another run is working on the same folder (see [`crate::lock`])
+    #[error(
+        "{} is held by {holder}; if that run crashed, remove the lock with --force-unlock",
+        path.display()
+    )]
+    Locked { path: PathBuf, holder: String },
+
+    /// The root given to `--patch` doesn't look like the season's (see [`crate::ipfs::check_root`])
+    #[error("{root} doesn't look like the season's root ({}); use --force if it is", problems.join("; "))]
+    WrongRoot { root: String, problems: Vec<String> },
+
+    /// `--only` didn't select anything
+    #[error("--only {patterns} didn't match any {what}")]
+    NothingSelected { patterns: String, what: String },
+
+    /// A hook from the config exited with an error, and isn't allowed to fail
+    #[error("The {hook} hook failed ({status})")]
+    HookFailed {
+        hook: String,
+        status: std::process::ExitStatus,
+    },
+
+    /// Something needed the network in an `--offline` run
+    #[error("{what} needs the network, and this run is --offline")]
+    Offline { what: String },
+
+    /// A step of the [`Pipeline`](crate::Pipeline) failed somewhere that doesn't have an error of its own
+    #[error("Failed to {what}")]
+    Step {
+        what: String,
+        #[source]
+        source: BoxError,
+    },
+
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+fn include_chain(chain: &[PathBuf]) -> String {
+    chain
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+impl CbError {
+    pub(crate) fn tool(tool: impl Into<String>, source: impl Into<BoxError>) -> CbError {
+        CbError::ExternalTool {
+            tool: tool.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn parse(context: impl Into<String>, source: impl Into<BoxError>) -> CbError {
+        CbError::Parse {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn step(what: impl Into<String>, source: impl Into<BoxError>) -> CbError {
+        CbError::Step {
+            what: what.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn io(context: impl Into<String>, source: std::io::Error) -> CbError {
+        CbError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// Checks the output of an ipfs command
+    #[cfg(feature = "ipfs")]
+    pub(crate) fn check_ipfs(command: &str, output: &std::process::Output) -> Result<(), CbError> {
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(CbError::IpfsDaemon {
+                command: command.to_string(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            })
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Downloading published files back into a data dir (`--fetch`)
+//!
+//! This is for setting up a new machine (or recovering a lost disk): the season definition says which files belong in
+//! which data folder, the metadata says how big they should be, and the published root object has the files
+//! themselves.  Downloads go to a `.part` file next to the destination, so an interrupted fetch carries on from where
+//! it stopped, and a file is only moved into place once its size (and CID, when fetching through ipfs) checks out.
+//! Files that already exist are never touched.
+
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    context::RunContext,
+    error::CbError,
+    progress::{self, ProgressEvent, Stage},
+    types::Season,
+};
+
+/// The kinds of file that can be fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Flac,
+    Ogg,
+    Mp3,
+    Opus,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "flac" => Ok(Format::Flac),
+            "ogg" | "vorbis" => Ok(Format::Ogg),
+            "mp3" => Ok(Format::Mp3),
+            "opus" => Ok(Format::Opus),
+            other => Err(format!("Unknown format {:?} (expected flac, ogg, mp3 or opus)", other)),
+        }
+    }
+}
+
+/// Parses a comma separated list of formats, like `ogg,mp3`
+pub fn parse_formats(s: &str) -> Result<Vec<Format>, String> {
+    let mut formats = Vec::new();
+    for f in s.split(',').filter(|f| !f.trim().is_empty()) {
+        let f = f.parse()?;
+        if !formats.contains(&f) {
+            formats.push(f);
+        }
+    }
+    if formats.is_empty() {
+        return Err("No formats given".to_string());
+    }
+    Ok(formats)
+}
+
+/// Where to download from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The local IPFS node (`ipfs cat`)
+    Ipfs,
+    /// An HTTP gateway, like `https://ipfs.io`
+    Gateway(String),
+}
+
+/// One file to download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchItem {
+    /// Path inside the root object, like `jam1/jam1_stereo.ogg`
+    pub ipfs_path: String,
+    pub dest: PathBuf,
+    /// Size according to the metadata, if it's known
+    pub size: Option<u64>,
+}
+
+impl FetchItem {
+    /// Where the download goes until it's complete
+    pub fn part_path(&self) -> PathBuf {
+        let mut name = self.dest.file_name().unwrap_or_default().to_os_string();
+        name.push(".part");
+        self.dest.with_file_name(name)
+    }
+}
+
+/// Works out which files of the selected recordings are missing from `data_dir`
+///
+/// `season` should be loaded from the metadata (there's usually nothing on disk to load it from), so that the sizes
+/// are known.  A size of 0 in the metadata means the file wasn't there when the metadata was written, so it isn't
+/// checked.
+pub fn plan_fetch(
+    ctx: &RunContext, season: &Season, data_dir: &Path, formats: &[Format],
+) -> Result<Vec<FetchItem>, CbError> {
+    let mut items = Vec::new();
+    for rec in ctx.only.select(season)? {
+        for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+            for format in formats {
+                let (file, size) = match format {
+                    Format::Flac => (Some(&track.flac), track.flac_bytes),
+                    Format::Ogg => (track.vorbis.as_ref(), track.ogg_bytes),
+                    Format::Mp3 => (track.mp3.as_ref(), track.mp3_bytes),
+                    Format::Opus => (track.opus.as_ref(), track.opus_bytes),
+                };
+                let file = match file {
+                    Some(file) => file,
+                    None => continue,
+                };
+                let dest = data_dir.join(&rec.data_folder).join(file);
+                if dest.exists() {
+                    continue;
+                }
+                items.push(FetchItem {
+                    ipfs_path: format!("{}/{}", rec.data_folder, file),
+                    dest,
+                    size: Some(size).filter(|s| *s > 0),
+                });
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Downloads every item from the root object `root`
+///
+/// Returns the number of files that were fetched.  A file that fails doesn't stop the others, but the first error is
+/// returned at the end.
+pub fn fetch_all(ctx: &RunContext, root: &cid::Cid, items: &[FetchItem], source: &Source) -> Result<usize, CbError> {
+    ctx.stage(Stage::Fetch, || {
+        let total = items.len();
+        let mut errors = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            let start = Instant::now();
+            say_part!("Fetching {} -> {}... ", item.ipfs_path, item.dest.display());
+            match fetch_one(ctx, root, item, source) {
+                Ok(()) => say!("done"),
+                Err(e) => {
+                    say!("failed");
+                    progress::emit(ProgressEvent::Error {
+                        stage: Stage::Fetch,
+                        message: format!("{}: {}", item.ipfs_path, e),
+                    });
+                    errors.push(e);
+                }
+            }
+            ctx.timings
+                .record_item(Stage::Fetch, item.ipfs_path.clone(), start.elapsed());
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Fetch,
+                item: item.ipfs_path.clone(),
+                index: index + 1,
+                total,
+            });
+        }
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Fetch,
+            processed: total,
+            errors: errors.len(),
+            warnings: 0,
+        });
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(total),
+        }
+    })
+}
+
+fn fetch_one(ctx: &RunContext, root: &cid::Cid, item: &FetchItem, source: &Source) -> Result<(), CbError> {
+    if item.dest.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = item.dest.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| CbError::io(format!("Failed to create {}", parent.display()), e))?;
+    }
+
+    let part = item.part_path();
+    let offset = resume_offset(&part, item.size)?;
+    let expected_cid = match source {
+        Source::Ipfs => Some(resolve(ctx, root, &item.ipfs_path)?),
+        Source::Gateway(_) => None,
+    };
+
+    if item.size.is_none_or(|size| offset < size) {
+        match source {
+            Source::Ipfs => cat(ctx, expected_cid.as_ref().unwrap(), &part, offset)?,
+            Source::Gateway(gateway) => download(ctx, gateway, root, item, &part, offset)?,
+        }
+    }
+
+    let len = std::fs::metadata(&part)
+        .map_err(|e| CbError::io(format!("Failed to read {}", part.display()), e))?
+        .len();
+    if let Some(size) = item.size {
+        if len != size {
+            let _ = std::fs::remove_file(&part);
+            return Err(CbError::parse(
+                format!("Downloaded {} is the wrong size", item.ipfs_path),
+                format!("expected {} bytes, got {}", size, len),
+            ));
+        }
+    }
+    if let Some(expected) = expected_cid {
+        let got = only_hash(ctx, &part)?;
+        if got.hash() != expected.hash() {
+            let _ = std::fs::remove_file(&part);
+            return Err(CbError::parse(
+                format!("Downloaded {} has the wrong CID", item.ipfs_path),
+                format!("expected {}, got {}", expected, got),
+            ));
+        }
+    }
+
+    // somebody might have put the file in place while we were downloading it
+    if item.dest.exists() {
+        let _ = std::fs::remove_file(&part);
+        return Ok(());
+    }
+    std::fs::rename(&part, &item.dest)
+        .map_err(|e| CbError::io(format!("Failed to move {} into place", part.display()), e))
+}
+
+/// How much of a partial download can be kept
+///
+/// A partial file that's already bigger than the expected size can't be right, so it's thrown away.
+fn resume_offset(part: &Path, size: Option<u64>) -> Result<u64, CbError> {
+    let len = match std::fs::metadata(part) {
+        Ok(md) => md.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(CbError::io(format!("Failed to read {}", part.display()), e)),
+    };
+    if size.is_some_and(|size| len > size) {
+        std::fs::remove_file(part).map_err(|e| CbError::io(format!("Failed to remove {}", part.display()), e))?;
+        return Ok(0);
+    }
+    Ok(len)
+}
+
+fn open_part(part: &Path, append: bool) -> Result<File, CbError> {
+    OpenOptions::new()
+        .create(true)
+        .append(append)
+        .write(true)
+        .truncate(!append)
+        .open(part)
+        .map_err(|e| CbError::io(format!("Failed to open {}", part.display()), e))
+}
+
+/// Finds the CID of `path` inside `root`
+fn resolve(ctx: &RunContext, root: &cid::Cid, path: &str) -> Result<cid::Cid, CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("resolve")
+        .arg("-r")
+        .arg(format!("/ipfs/{}/{}", root, path))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("resolve", &output)?;
+    let resolved = String::from_utf8_lossy(&output.stdout);
+    let resolved = resolved.trim();
+    cid::Cid::from_str(resolved.trim_start_matches("/ipfs/"))
+        .map_err(|e| CbError::parse(format!("Unexpected output from ipfs resolve: {:?}", resolved), e))
+}
+
+/// Appends the contents of `cid` (starting at `offset`) to `part`
+fn cat(ctx: &RunContext, cid: &cid::Cid, part: &Path, offset: u64) -> Result<(), CbError> {
+    let file = open_part(part, true)?;
+    let output = ctx
+        .ipfs_command()
+        .arg("cat")
+        .arg(format!("--offset={}", offset))
+        .arg(format!("/ipfs/{}", cid))
+        .stdout(Stdio::from(file))
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("cat", &output)
+}
+
+/// The CID that `ipfs add` would give `file`, without adding it
+fn only_hash(ctx: &RunContext, file: &Path) -> Result<cid::Cid, CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("add")
+        .arg("--only-hash")
+        .arg("-Q")
+        .arg(file)
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("add --only-hash", &output)?;
+    let hash = String::from_utf8_lossy(&output.stdout);
+    cid::Cid::from_str(hash.trim())
+        .map_err(|e| CbError::parse(format!("Unexpected output from ipfs add: {:?}", hash.trim()), e))
+}
+
+/// The gateway URL for `path` inside `root`
+fn gateway_url(gateway: &str, root: &cid::Cid, path: &str) -> Result<reqwest::Url, CbError> {
+    let mut url =
+        reqwest::Url::parse(gateway).map_err(|e| CbError::parse(format!("Invalid gateway URL {}", gateway), e))?;
+    if url.cannot_be_a_base() {
+        return Err(CbError::parse(
+            format!("Invalid gateway URL {}", gateway),
+            "it can't have a path",
+        ));
+    }
+    let full_path = format!(
+        "{}/ipfs/{}/{}",
+        url.path().trim_end_matches('/'),
+        root,
+        crate::slug::encode_path(path)
+    );
+    url.set_path(&full_path);
+    Ok(url)
+}
+
+/// Downloads `item` from a gateway into `part`, asking for just the missing bytes
+///
+/// A gateway that doesn't support ranges sends the whole file, which then replaces the partial one.
+fn download(
+    ctx: &RunContext, gateway: &str, root: &cid::Cid, item: &FetchItem, part: &Path, offset: u64,
+) -> Result<(), CbError> {
+    let url = gateway_url(gateway, root, &item.ipfs_path)?;
+    let client = ctx.http_client("gateway", Duration::from_secs(60 * 60))?;
+    let mut request = client.get(url.clone());
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut resp = request.send().map_err(|e| CbError::tool("gateway", e))?;
+    if !resp.status().is_success() {
+        return Err(CbError::tool("gateway", format!("{} returned {}", url, resp.status())));
+    }
+
+    let resumed = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let mut file = open_part(part, resumed)?;
+    resp.copy_to(&mut file).map_err(|e| CbError::tool("gateway", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        assert_eq!(parse_formats("ogg,mp3").unwrap(), [Format::Ogg, Format::Mp3]);
+        assert_eq!(parse_formats("FLAC, ogg,flac").unwrap(), [Format::Flac, Format::Ogg]);
+        assert!(parse_formats("wav").is_err());
+        assert!(parse_formats(",").is_err());
+    }
+
+    #[test]
+    fn resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let item = FetchItem {
+            ipfs_path: "jam1/jam1.ogg".to_string(),
+            dest: dir.path().join("jam1/jam1.ogg"),
+            size: Some(4),
+        };
+        let part = item.part_path();
+        assert_eq!(part, dir.path().join("jam1/jam1.ogg.part"));
+        assert_eq!(resume_offset(&part, item.size).unwrap(), 0);
+
+        std::fs::create_dir_all(part.parent().unwrap()).unwrap();
+        std::fs::write(&part, b"abc").unwrap();
+        assert_eq!(resume_offset(&part, item.size).unwrap(), 3);
+        assert_eq!(resume_offset(&part, None).unwrap(), 3);
+
+        // too big to be a partial copy of this file
+        std::fs::write(&part, b"abcdef").unwrap();
+        assert_eq!(resume_offset(&part, item.size).unwrap(), 0);
+        assert!(!part.exists());
+    }
+
+    #[test]
+    fn urls() {
+        let root = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+        assert_eq!(
+            gateway_url("https://ipfs.io/", &root, "jam 1/a#b.ogg")
+                .unwrap()
+                .as_str(),
+            "https://ipfs.io/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/jam%201/a%23b.ogg"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! The tags written into the oggs, mp3s and opus files, so that a downloaded track shows up in a player as what it is
+//! rather than "Unknown Artist"
+//!
+//! ffmpeg writes them as vorbis comments in oggs and opus files, and as ID3v2 in mp3s.  [`convert_all`] tags every file
+//! it makes, and `--retag` ([`retag_all`]) rewrites the tags of the files that were made before, without encoding
+//! them again.
+//!
+//! [`convert_all`]: crate::convert_all
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+#[cfg(feature = "cli")]
+use colored::Colorize;
+
+#[cfg(not(feature = "cli"))]
+use crate::plain::Colorize;
+use crate::{
+    context::RunContext,
+    error::CbError,
+    progress::{self, ProgressEvent, Stage},
+    types::{Recording, Season, Track},
+};
+
+/// What a track's files are tagged with
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileTags {
+    /// The recording's title for the stereo mix, and the track's name for the others
+    pub title: String,
+    pub artist: String,
+    /// The recording's title, so that all of its tracks go together
+    pub album: String,
+    /// Like 2021-01-02
+    pub date: String,
+    /// The track's id
+    pub track: u8,
+    /// The recording's tags, separated by commas
+    pub genre: String,
+}
+
+impl FileTags {
+    pub fn of(recording: &Recording, track: &Track) -> FileTags {
+        let is_stereo_mix = std::ptr::eq(track, &recording.stereo_mix);
+        FileTags {
+            title: if is_stereo_mix {
+                recording.title.clone()
+            } else {
+                track.name.clone()
+            },
+            artist: recording.artist.clone(),
+            album: recording.title.clone(),
+            date: recording.display_date("%Y-%m-%d").replace('/', "-"),
+            track: track.id,
+            genre: recording
+                .tags
+                .iter()
+                .map(|tag| tag.display.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+
+    /// The ffmpeg options that write these tags into `output`
+    pub fn ffmpeg_args(&self, output: &Path) -> Vec<String> {
+        let tags = [
+            ("title", self.title.clone()),
+            ("artist", self.artist.clone()),
+            ("album", self.album.clone()),
+            ("date", self.date.clone()),
+            ("track", self.track.to_string()),
+            ("genre", self.genre.clone()),
+        ];
+        let mut args = Vec::new();
+        for (key, value) in IntoIterator::into_iter(tags).filter(|(_, value)| !value.is_empty()) {
+            args.push("-metadata".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        // ffmpeg writes ID3v2.4 unless told otherwise, which a lot of players (Windows' included) can't read
+        let is_mp3 = output
+            .extension()
+            .is_some_and(|ext| ext.to_string_lossy().eq_ignore_ascii_case("mp3"));
+        if is_mp3 {
+            args.extend(["-id3v2_version".to_string(), "3".to_string()]);
+        }
+        args
+    }
+}
+
+/// Rewrites the tags of the oggs, mp3s and opus files of the selected recordings that exist, returning the files that
+/// were retagged
+///
+/// The audio is copied as it is (`-c copy`), so this is quick.  Like a conversion, the retagged file is written next to
+/// the old one and only replaces it once ffmpeg has finished.  A file that fails is reported and left as it was, and the
+/// first error is returned once the others are done.
+pub fn retag_all(ctx: &RunContext, season: &Season) -> Result<Vec<PathBuf>, CbError> {
+    ctx.stage(Stage::Convert, || {
+        let mut files = Vec::new();
+        for recording in ctx.only.select(season)? {
+            for track in std::iter::once(&recording.stereo_mix).chain(&recording.tracks) {
+                let tags = FileTags::of(recording, track);
+                let lossy = [
+                    track.ogg_ondisk(),
+                    track.lq_ogg_ondisk(),
+                    track.mp3_ondisk(),
+                    track.opus_ondisk(),
+                ];
+                files.extend(
+                    IntoIterator::into_iter(lossy)
+                        .flatten()
+                        .filter(|file| file.exists())
+                        .map(|file| (file, tags.clone())),
+                );
+            }
+        }
+
+        let total = files.len();
+        let mut retagged = Vec::new();
+        let mut first_error = None;
+        for (index, (file, tags)) in files.into_iter().enumerate() {
+            match retag(ctx, &file, &tags) {
+                Ok(()) => retagged.push(file.clone()),
+                Err(e) => {
+                    say!("{}: {}", "ERROR".red(), e);
+                    progress::emit(ProgressEvent::Error {
+                        stage: Stage::Convert,
+                        message: e.to_string(),
+                    });
+                    first_error.get_or_insert(e);
+                }
+            }
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Convert,
+                item: file.display().to_string(),
+                index: index + 1,
+                total,
+            });
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(retagged),
+        }
+    })
+}
+
+/// Rewrites the tags of `file`, through a temporary file next to it
+fn retag(ctx: &RunContext, file: &Path, tags: &FileTags) -> Result<(), CbError> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = file.with_file_name(format!("{}{}", crate::TEMP_PREFIX, name));
+    let _ = std::fs::remove_file(&tmp);
+    let output = Command::new(&ctx.tools.ffmpeg)
+        .arg("-i")
+        .arg(file)
+        .args(["-map", "0", "-c", "copy"])
+        .args(tags.ffmpeg_args(file))
+        .arg(&tmp)
+        .stdout(Stdio::null())
+        .output()
+        .map_err(|e| CbError::tool("ffmpeg", e))?;
+    let moved = if output.status.success() {
+        std::fs::rename(&tmp, file).map_err(|e| CbError::io(format!("Failed to replace {}", file.display()), e))
+    } else {
+        Err(CbError::tool(
+            "ffmpeg",
+            format!(
+                "ffmpeg returned {:?} retagging {}: {}",
+                output.status,
+                file.display(),
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ),
+        ))
+    };
+    if moved.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    moved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ffmpeg_args() {
+        let tags = FileTags {
+            title: "Kick".to_string(),
+            artist: "Bender".to_string(),
+            album: "S01E01 - Jam 1".to_string(),
+            date: "2021-01-02".to_string(),
+            track: 2,
+            genre: String::new(),
+        };
+        assert_eq!(
+            tags.ffmpeg_args(Path::new("ogg/jam1_kick.ogg")),
+            [
+                "-metadata",
+                "title=Kick",
+                "-metadata",
+                "artist=Bender",
+                "-metadata",
+                "album=S01E01 - Jam 1",
+                "-metadata",
+                "date=2021-01-02",
+                "-metadata",
+                "track=2",
+            ]
+        );
+        let mp3 = tags.ffmpeg_args(Path::new("mp3/jam1_kick.MP3"));
+        assert_eq!(&mp3[mp3.len() - 2..], ["-id3v2_version", "3"]);
+    }
+}
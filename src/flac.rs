@@ -0,0 +1,155 @@
+//! Reading technical info straight out of a FLAC file
+//!
+//! Everything we need (channels, sample rate, bit depth, and length) is in the STREAMINFO block at the start of the
+//! file, so there's no need to run mediainfo for flacs.  See <https://xiph.org/flac/format.html#metadata_block_streaminfo>
+
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{bail, Context};
+
+use crate::MediaInfo;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    /// Number of samples per channel.  0 means the encoder didn't know
+    pub total_samples: u64,
+}
+
+impl StreamInfo {
+    pub fn read(path: &Path) -> anyhow::Result<StreamInfo> {
+        let mut f = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        // "fLaC", then the metadata block header, then the 34 byte STREAMINFO block
+        let mut buf = [0; 4 + 4 + 34];
+        f.read_exact(&mut buf)
+            .with_context(|| format!("{} is too short to be a flac", path.display()))?;
+        StreamInfo::parse(&buf).with_context(|| format!("Failed to read STREAMINFO from {}", path.display()))
+    }
+
+    fn parse(buf: &[u8; 42]) -> anyhow::Result<StreamInfo> {
+        if &buf[0..4] != b"fLaC" {
+            bail!("Not a flac file");
+        }
+        // STREAMINFO must be the first metadata block
+        let block_type = buf[4] & 0x7f;
+        let block_len = u32::from_be_bytes([0, buf[5], buf[6], buf[7]]);
+        if block_type != 0 || block_len != 34 {
+            bail!("First metadata block isn't STREAMINFO");
+        }
+
+        // after the block and frame sizes: 20 bits of sample rate, 3 bits of (channels - 1), 5 bits of
+        // (bits per sample - 1), and 36 bits of total samples
+        let packed = u64::from_be_bytes([buf[18], buf[19], buf[20], buf[21], buf[22], buf[23], buf[24], buf[25]]);
+        let sample_rate = (packed >> 44) as u32;
+        let channels = ((packed >> 41) & 0x7) as u8 + 1;
+        let bits_per_sample = ((packed >> 36) & 0x1f) as u8 + 1;
+        let total_samples = packed & 0xf_ffff_ffff;
+
+        if sample_rate == 0 {
+            bail!("Invalid sample rate");
+        }
+
+        Ok(StreamInfo {
+            sample_rate,
+            channels,
+            bits_per_sample,
+            total_samples,
+        })
+    }
+
+    /// Converts to the same MediaInfo that mediainfo would have given us
+    ///
+    /// Returns None if the length of the stream isn't known
+    pub fn to_media_info(&self) -> Option<MediaInfo> {
+        if self.total_samples == 0 {
+            return None;
+        }
+        // mediainfo reports the duration in seconds, rounded to the millisecond
+        let millis = (self.total_samples * 1000 + u64::from(self.sample_rate) / 2) / u64::from(self.sample_rate);
+        Some(MediaInfo {
+            t: "Audio".to_string(),
+            format: "FLAC".to_string(),
+            channels: self.channels.to_string(),
+            sample_rate: self.sample_rate.to_string(),
+            bit_depth: self.bits_per_sample.to_string(),
+            duration: format!("{}.{:03}", millis / 1000, millis % 1000),
+            bitrate: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streaminfo() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/silence.flac");
+        let info = StreamInfo::read(&path).unwrap();
+        assert_eq!(
+            info,
+            StreamInfo {
+                sample_rate: 48000,
+                channels: 2,
+                bits_per_sample: 24,
+                total_samples: 52345,
+            }
+        );
+
+        // this is what mediainfo reports for the fixture
+        let media_info = info.to_media_info().unwrap();
+        assert_eq!(media_info.t, "Audio");
+        assert_eq!(media_info.format, "FLAC");
+        assert_eq!(media_info.channels, "2");
+        assert_eq!(media_info.sample_rate, "48000");
+        assert_eq!(media_info.bit_depth, "24");
+        assert_eq!(media_info.duration, "1.091");
+
+        assert!(StreamInfo::read(Path::new("Cargo.toml")).is_err());
+    }
+
+    #[test]
+    fn matches_mediainfo() {
+        let ctx = crate::context::RunContext::default();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/silence.flac");
+        let ours = MediaInfo::new(&ctx, &path).unwrap();
+        match MediaInfo::from_mediainfo(&ctx, &path) {
+            // we don't work out the bitrate of flacs
+            Ok(theirs) => assert_eq!(
+                ours,
+                MediaInfo {
+                    bitrate: None,
+                    ..theirs
+                }
+            ),
+            Err(e) if not_installed(&e) => eprintln!("mediainfo isn't installed, only checking the flac reader"),
+            Err(e) => panic!("mediainfo failed: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn backends_agree() {
+        let ctx = crate::context::RunContext::default();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/silence.flac");
+        let (mediainfo, ffprobe) = match (
+            MediaInfo::from_mediainfo(&ctx, &path),
+            MediaInfo::from_ffprobe(&ctx, &path),
+        ) {
+            (Ok(mediainfo), Ok(ffprobe)) => (mediainfo, ffprobe),
+            (Err(e), _) | (_, Err(e)) if not_installed(&e) => {
+                return eprintln!("mediainfo or ffprobe isn't installed, skipping");
+            }
+            (Err(e), _) | (_, Err(e)) => panic!("getting media info failed: {:?}", e),
+        };
+        assert_eq!(mediainfo.channels().unwrap(), ffprobe.channels().unwrap());
+        assert_eq!(mediainfo.sample_rate().unwrap(), ffprobe.sample_rate().unwrap());
+        let (a, b) = (mediainfo.duration_secs().unwrap(), ffprobe.duration_secs().unwrap());
+        assert!((a - b).abs() <= 0.001, "{} vs {}", a, b);
+    }
+
+    fn not_installed(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<std::io::Error>().map(|e| e.kind()) == Some(std::io::ErrorKind::NotFound)
+    }
+}
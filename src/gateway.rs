@@ -0,0 +1,817 @@
+//! The public gateways that `--prime` warms up, and how quickly they answer (`--benchmark-gateways`)
+//!
+//! Priming goes through every link on every gateway, which takes a long time.  Each URL that a gateway served is
+//! recorded in a [`PrimeState`], so that a run that dies can carry on where it stopped instead of starting over.  The
+//! state only ever has one root: starting on another one moves the state for the one before to the file's `.1`.
+//!
+//! The root, and what the patch that made it changed (from its `--patch-report`, with `--prime-first`), are primed on
+//! every gateway before the rest of its links, which have been around longer and matter less.  A `--budget` stops the
+//! run early, leaving the rest to the next one.
+//!
+//! The state also keeps how many requests each gateway answered in each of its last runs, whatever the root.  A
+//! gateway that failed every request in the last `unhealthy_after` runs is skipped by `--prime` (unless
+//! `--include-unhealthy` is given), since a dead gateway only makes priming take longer; `gateways status` shows the
+//! table this is decided from.
+//!
+//! The benchmark fetches the smallest file in the root from every gateway a few times, one request at a time per
+//! gateway, and ranks the gateways by their median time.  Up to `jobs` gateways are measured at once.
+//!
+//! Both go through the [`PUBLIC_GATEWAYS`] and the `[[gateway]]`s in the config, which can add headers (and a token) to
+//! the requests.  Header values are never printed, only their names.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analysis::parallel_for_each,
+    context::{GatewayConfig, RunContext},
+    error::CbError,
+    ipfs::IPFSObject,
+    progress::{self, ProgressEvent, Stage},
+    state_store,
+};
+
+/// Where the root can be found: `{base32}` is replaced with the CIDv1 of the root, and `{v0}` with the CIDv0
+pub const PUBLIC_GATEWAYS: &[&str] = &[
+    "https://{base32}.ipfs.dweb.link",
+    "https://ipfs.io/ipfs/{v0}",
+    "https://ipfs.overpi.com/ipfs/{v0}",
+    // "https://{base32}.ipfs.ipfs.stibarc.com",
+    "https://{base32}.ipfs.cf-ipfs.com",
+    "https://{base32}.ipfs.astyanax.io",
+    "https://gateway.pinata.cloud/ipfs/{base32}",
+];
+
+/// How long to wait for a gateway before giving up on a request
+pub const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The URL of `root` on each of the [`PUBLIC_GATEWAYS`]
+pub fn root_urls(root: &cid::Cid) -> Result<Vec<String>, CbError> {
+    PUBLIC_GATEWAYS.iter().map(|gw| root_url(gw, root)).collect()
+}
+
+/// Fills in the root in a gateway URL like the ones in [`PUBLIC_GATEWAYS`]
+fn root_url(template: &str, root: &cid::Cid) -> Result<String, CbError> {
+    let b32 = cid::Cid::new_v1(root.codec(), root.hash().to_owned());
+    let v0 = cid::Cid::new_v0(root.hash().to_owned())
+        .map_err(|e| CbError::parse(format!("{} can't be used as a v0 CID", root), e))?;
+    Ok(template
+        .replace("{base32}", &b32.to_string())
+        .replace("{v0}", &v0.to_string()))
+}
+
+/// A gateway to prime and benchmark, with the headers its requests need
+#[derive(Clone, PartialEq, Eq)]
+pub struct Gateway {
+    /// The gateway's URL for the root, like the ones in [`PUBLIC_GATEWAYS`]
+    pub template: String,
+    pub headers: BTreeMap<String, String>,
+    /// The environment variable with the token to send as `Authorization: Bearer <token>`
+    pub token_env: Option<String>,
+}
+
+impl std::fmt::Debug for Gateway {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the header values can be secrets too
+        f.debug_struct("Gateway")
+            .field("template", &self.template)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("token_env", &self.token_env)
+            .finish()
+    }
+}
+
+impl Gateway {
+    fn public(template: &str) -> Gateway {
+        Gateway {
+            template: template.to_string(),
+            headers: BTreeMap::new(),
+            token_env: None,
+        }
+    }
+
+    /// The URL of `root` on this gateway
+    pub fn root_url(&self, root: &cid::Cid) -> Result<String, CbError> {
+        root_url(&self.template, root)
+    }
+
+    /// The headers to send with each request, with the token read from its environment variable
+    pub fn header_map(&self) -> Result<reqwest::header::HeaderMap, CbError> {
+        let mut map = reqwest::header::HeaderMap::new();
+        let invalid = |name: &str, e: Box<dyn std::error::Error + Send + Sync>| {
+            CbError::parse(format!("Invalid {} header for the gateway {}", name, self.template), e)
+        };
+        for (name, value) in &self.headers {
+            let header =
+                reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(name, e.into()))?;
+            let mut value = reqwest::header::HeaderValue::from_str(value).map_err(|e| invalid(name, e.into()))?;
+            value.set_sensitive(true);
+            map.insert(header, value);
+        }
+        if let Some(var) = &self.token_env {
+            let token = std::env::var(var).ok().filter(|t| !t.is_empty()).ok_or_else(|| {
+                CbError::parse(
+                    format!("The gateway {} needs a token", self.template),
+                    format!("{} isn't set", var),
+                )
+            })?;
+            let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|e| invalid("Authorization", e.into()))?;
+            value.set_sensitive(true);
+            map.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        Ok(map)
+    }
+
+    /// What the requests to this gateway have on top of a plain GET, for the logs, like ` (with Host, Authorization)`
+    pub fn describe_headers(&self) -> String {
+        let mut names: Vec<&str> = self.headers.keys().map(String::as_str).collect();
+        if self.token_env.is_some() {
+            names.push("Authorization");
+        }
+        if names.is_empty() {
+            String::new()
+        } else {
+            format!(" (with {})", names.join(", "))
+        }
+    }
+}
+
+/// The [`PUBLIC_GATEWAYS`] (with what the config adds to them), then the other gateways in the config
+pub fn gateways(configured: &[GatewayConfig]) -> Vec<Gateway> {
+    let mut gateways: Vec<Gateway> = PUBLIC_GATEWAYS.iter().map(|gw| Gateway::public(gw)).collect();
+    for config in configured {
+        let gateway = Gateway {
+            template: config.url.clone(),
+            headers: config.headers.clone(),
+            token_env: config.token_env.clone(),
+        };
+        match gateways.iter_mut().find(|gw| gw.template == config.url) {
+            Some(public) => *public = gateway,
+            None => gateways.push(gateway),
+        }
+    }
+    gateways
+}
+
+/// The URL of the file at `path` (a `/`-separated path of link names) inside one of the [`root_urls`]
+pub fn link_url(root_url: &str, path: &str) -> Result<reqwest::Url, CbError> {
+    let url = format!("{}/{}", root_url, crate::slug::encode_path(path));
+    reqwest::Url::parse(&url).map_err(|e| CbError::parse(format!("Invalid gateway URL {}", url), e))
+}
+
+/// Whether to carry on with an earlier `--prime` run of the same root
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrimeMode {
+    /// Carry on if there was one, start over if not (or if it was for another root)
+    #[default]
+    Auto,
+    /// Carry on, and fail if there's nothing to carry on with (`--resume`)
+    Resume,
+    /// Start over, whatever was primed before (`--fresh`)
+    Fresh,
+}
+
+/// When `--prime` stops, whatever is left (`--budget`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrimeBudget {
+    /// After this many requests, over all the gateways
+    Requests(usize),
+    /// Once this long has passed (the request that's under way is finished first)
+    Time(Duration),
+}
+
+impl PrimeBudget {
+    /// Whether the budget is used up after `requests` requests in `elapsed`
+    pub fn is_spent(&self, requests: usize, elapsed: Duration) -> bool {
+        match *self {
+            PrimeBudget::Requests(n) => requests >= n,
+            PrimeBudget::Time(limit) => elapsed >= limit,
+        }
+    }
+}
+
+impl std::str::FromStr for PrimeBudget {
+    type Err = String;
+
+    /// A number of requests (`500`), or a time in seconds, minutes or hours (`90s`, `20m`, `2h`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || {
+            format!(
+                "Invalid budget {:?} (expected a number of requests, or a time like 90s, 20m or 2h)",
+                s
+            )
+        };
+        let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(idx) => s.split_at(idx),
+            None => (s, ""),
+        };
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        let budget = match unit {
+            "" => PrimeBudget::Requests(n as usize),
+            "s" => PrimeBudget::Time(Duration::from_secs(n)),
+            "m" => PrimeBudget::Time(Duration::from_secs(n * 60)),
+            "h" => PrimeBudget::Time(Duration::from_secs(n * 3600)),
+            _ => return Err(invalid()),
+        };
+        match budget {
+            PrimeBudget::Requests(0) => Err(invalid()),
+            PrimeBudget::Time(t) if t.as_secs() == 0 => Err(invalid()),
+            budget => Ok(budget),
+        }
+    }
+}
+
+/// How `--prime` goes about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrimeOptions {
+    pub mode: PrimeMode,
+    /// Links in the root to prime on every gateway before the rest (what the latest patch changed, from its
+    /// `--patch-report`)
+    pub first: Vec<String>,
+    /// Stop early, leaving the rest for the next run
+    pub budget: Option<PrimeBudget>,
+}
+
+/// What `--prime` has primed so far
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PrimeState {
+    /// The root that was being primed
+    pub root: String,
+    /// The links that each gateway served, by the gateway's URL for the root ("" is the root itself)
+    pub primed: BTreeMap<String, BTreeSet<String>>,
+    /// How each gateway did in its last runs, by its URL as listed in [`PUBLIC_GATEWAYS`].  Kept across roots
+    #[serde(default)]
+    pub health: BTreeMap<String, GatewayHealth>,
+}
+
+/// How many requests a gateway answered in one `--prime` run
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunHealth {
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// How a gateway did in its last runs, oldest first
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct GatewayHealth {
+    pub runs: Vec<RunHealth>,
+}
+
+/// How many runs of each gateway are kept, unless `unhealthy_after` needs more
+pub const HEALTH_RUNS: usize = 10;
+
+impl GatewayHealth {
+    /// Whether every request in each of the last `runs` runs failed
+    pub fn is_unhealthy(&self, runs: usize) -> bool {
+        runs > 0
+            && self.runs.len() >= runs
+            && self.runs[self.runs.len() - runs..]
+                .iter()
+                .all(|run| run.succeeded == 0 && run.failed > 0)
+    }
+
+    fn record(&mut self, run: RunHealth, keep: usize) {
+        self.runs.push(run);
+        let older = self.runs.len().saturating_sub(keep);
+        self.runs.drain(..older);
+    }
+}
+
+impl PrimeState {
+    /// Reads the state in `path`, starting over unless it's for `root` (or `mode` says to)
+    ///
+    /// The health of the gateways is kept whatever the root and the mode.
+    pub fn load(path: &Path, root: &cid::Cid, mode: PrimeMode) -> Result<PrimeState, CbError> {
+        let earlier: Option<PrimeState> = state_store::read_doc(path)?;
+        let fresh = PrimeState {
+            root: root.to_string(),
+            primed: BTreeMap::new(),
+            health: earlier.as_ref().map(|e| e.health.clone()).unwrap_or_default(),
+        };
+        if mode == PrimeMode::Fresh {
+            return Ok(fresh);
+        }
+        match earlier {
+            Some(earlier) if earlier.root == fresh.root => Ok(earlier),
+            _ if mode == PrimeMode::Resume => Err(CbError::NothingToResume {
+                path: path.to_path_buf(),
+                root: fresh.root,
+            }),
+            Some(earlier) => {
+                say!(
+                    "{} was priming {}, starting over for {} (moved it to {})",
+                    path.display(),
+                    earlier.root,
+                    root,
+                    state_store::archive_path(path).display()
+                );
+                state_store::archive(path)?;
+                Ok(fresh)
+            }
+            None => Ok(fresh),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CbError> {
+        state_store::write_doc(path, self)
+    }
+
+    pub fn is_primed(&self, gateway: &str, link: &str) -> bool {
+        self.primed.get(gateway).is_some_and(|links| links.contains(link))
+    }
+
+    pub fn mark(&mut self, gateway: &str, link: &str) {
+        self.primed
+            .entry(gateway.to_string())
+            .or_default()
+            .insert(link.to_string());
+    }
+
+    /// The requests that are left, as indexes into `gateways` (their URLs for the root) and `names` (with whether
+    /// they go first): the first names on every gateway, then the rest on every gateway
+    pub fn queue(&self, gateways: &[&str], names: &[(&str, bool)]) -> Vec<(usize, usize)> {
+        let mut queue = Vec::new();
+        for first in [true, false] {
+            for (g, gw) in gateways.iter().enumerate() {
+                for (n, (name, _)) in names.iter().enumerate().filter(|(_, (_, f))| *f == first) {
+                    if !self.is_primed(gw, name) {
+                        queue.push((g, n));
+                    }
+                }
+            }
+        }
+        queue
+    }
+
+    /// Adds a run of `gateway` (as listed in [`PUBLIC_GATEWAYS`]) to its health, keeping its last `keep` runs
+    pub fn record_run(&mut self, gateway: &str, run: RunHealth, keep: usize) {
+        self.health.entry(gateway.to_string()).or_default().record(run, keep);
+    }
+
+    /// Whether `gateway` failed every request in its last `runs` runs
+    pub fn is_unhealthy(&self, gateway: &str, runs: usize) -> bool {
+        self.health.get(gateway).is_some_and(|h| h.is_unhealthy(runs))
+    }
+
+    /// The health of each of `gateways`, in the same order
+    pub fn health_rows(&self, gateways: &[Gateway], unhealthy_after: usize) -> Vec<HealthRow> {
+        gateways
+            .iter()
+            .map(|gateway| {
+                let runs = self.health.get(&gateway.template).map_or(&[][..], |h| &h.runs[..]);
+                let succeeded = runs.iter().map(|r| r.succeeded).sum();
+                let requests = runs.iter().map(|r| r.succeeded + r.failed).sum();
+                HealthRow {
+                    gateway: gateway.template.clone(),
+                    runs: runs.len(),
+                    requests,
+                    succeeded,
+                    success_percent: (succeeded * 100).checked_div(requests),
+                    unhealthy: self.is_unhealthy(&gateway.template, unhealthy_after),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How a gateway has been doing, for `gateways status` and the `--prime-report`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthRow {
+    /// As listed in [`PUBLIC_GATEWAYS`]
+    pub gateway: String,
+    /// How many runs this covers (the last ones)
+    pub runs: usize,
+    pub requests: usize,
+    pub succeeded: usize,
+    /// Rounded down, `None` if there were no requests
+    pub success_percent: Option<usize>,
+    /// Skipped by `--prime` unless `--include-unhealthy` is given
+    pub unhealthy: bool,
+}
+
+/// The health of the gateways as a table
+pub fn health_table(rows: &[HealthRow]) -> String {
+    let mut out = format!(
+        "{:<44}  {:>4}  {:>10}  {:>8}  {}\n",
+        "gateway", "runs", "succeeded", "rate", "status"
+    );
+    for row in rows {
+        out.push_str(&format!(
+            "{:<44}  {:>4}  {:>10}  {:>8}  {}\n",
+            row.gateway,
+            row.runs,
+            format!("{}/{}", row.succeeded, row.requests),
+            row.success_percent
+                .map_or_else(|| "-".to_string(), |p| format!("{}%", p)),
+            if row.unhealthy {
+                "unhealthy, skipped"
+            } else if row.runs == 0 {
+                "not primed yet"
+            } else {
+                "ok"
+            }
+        ));
+    }
+    out
+}
+
+pub fn client(ctx: &RunContext) -> Result<reqwest::blocking::Client, CbError> {
+    ctx.http_client("gateway", TIMEOUT)
+}
+
+/// How one gateway did.  The times are `None` if none of its requests worked
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct GatewayTimes {
+    /// As listed in [`PUBLIC_GATEWAYS`]
+    pub gateway: String,
+    pub requests: usize,
+    pub failures: usize,
+    /// Time to the first byte (the response headers)
+    pub ttfb_median_ms: Option<u64>,
+    pub ttfb_p95_ms: Option<u64>,
+    /// Time to the end of the file
+    pub total_median_ms: Option<u64>,
+    pub total_p95_ms: Option<u64>,
+    /// Why the last failed request failed
+    pub last_error: Option<String>,
+}
+
+impl GatewayTimes {
+    /// Summarizes the (ttfb, total) times of the requests that worked
+    pub fn new(
+        gateway: String, requests: usize, mut ttfb: Vec<u64>, mut total: Vec<u64>, last_error: Option<String>,
+    ) -> Self {
+        ttfb.sort_unstable();
+        total.sort_unstable();
+        GatewayTimes {
+            gateway,
+            requests,
+            failures: requests - total.len(),
+            ttfb_median_ms: percentile(&ttfb, 50),
+            ttfb_p95_ms: percentile(&ttfb, 95),
+            total_median_ms: percentile(&total, 50),
+            total_p95_ms: percentile(&total, 95),
+            last_error,
+        }
+    }
+}
+
+/// The benchmark as written by `--benchmark-json`, so that runs can be compared over time
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BenchmarkReport {
+    pub root: String,
+    /// The file that was fetched, inside the root
+    pub path: String,
+    /// Seconds since the Unix epoch
+    pub measured: u64,
+    /// Fastest first
+    pub gateways: Vec<GatewayTimes>,
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+/// Fastest median first; gateways where nothing worked go last
+pub fn rank(gateways: &mut [GatewayTimes]) {
+    gateways.sort_by_key(|g| (g.total_median_ms.is_none(), g.total_median_ms, g.failures));
+}
+
+/// Fetches the smallest file in `root` from each of the [`gateways`], `samples` times
+pub fn benchmark(ctx: &RunContext, root: &cid::Cid, samples: usize) -> Result<BenchmarkReport, CbError> {
+    ctx.require_online("Benchmarking gateways")?;
+    let ipfs_root = IPFSObject::get(ctx, root)?;
+    let link = ipfs_root
+        .links
+        .iter()
+        .min_by_key(|l| l.size)
+        .ok_or_else(|| CbError::parse(format!("{} has no links", root), "there is nothing to fetch"))?;
+    let mut urls = Vec::new();
+    for gateway in gateways(&ctx.gateways) {
+        let url = link_url(&gateway.root_url(root)?, &link.name)?;
+        let headers = gateway.header_map()?;
+        urls.push((gateway.template, url, headers));
+    }
+
+    let client = client(ctx)?;
+    let total = urls.len();
+    let done = Mutex::new((0, Vec::new()));
+    parallel_for_each(ctx.jobs, urls, |(template, url, headers)| {
+        let times = measure(&client, &template, &url, &headers, samples);
+        if let Some(error) = &times.last_error {
+            progress::emit(ProgressEvent::Warning {
+                stage: Stage::Benchmark,
+                message: format!("{}: {}", url, error),
+            });
+        }
+        let mut done = done.lock().unwrap();
+        done.0 += 1;
+        progress::emit(ProgressEvent::ItemProcessed {
+            stage: Stage::Benchmark,
+            item: url.to_string(),
+            index: done.0,
+            total,
+        });
+        done.1.push(times);
+    });
+    let mut gateways = done.into_inner().unwrap().1;
+    rank(&mut gateways);
+
+    progress::emit(ProgressEvent::Summary {
+        stage: Stage::Benchmark,
+        processed: total,
+        errors: 0,
+        warnings: gateways.iter().filter(|g| g.failures > 0).count(),
+    });
+
+    Ok(BenchmarkReport {
+        root: root.to_string(),
+        path: link.name.clone(),
+        measured: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        gateways,
+    })
+}
+
+fn measure(
+    client: &reqwest::blocking::Client, gateway: &str, url: &reqwest::Url, headers: &reqwest::header::HeaderMap,
+    samples: usize,
+) -> GatewayTimes {
+    let (mut ttfb, mut total, mut last_error) = (Vec::new(), Vec::new(), None);
+    for _ in 0..samples {
+        let start = Instant::now();
+        let result = client
+            .get(url.clone())
+            .headers(headers.clone())
+            .send()
+            .and_then(|resp| {
+                let first_byte = start.elapsed();
+                let status = resp.status();
+                resp.bytes().map(|_| (status, first_byte))
+            });
+        match result {
+            Ok((status, first_byte)) if status.is_success() => {
+                ttfb.push(first_byte.as_millis() as u64);
+                total.push(start.elapsed().as_millis() as u64);
+            }
+            Ok((status, _)) => last_error = Some(format!("returned {}", status)),
+            Err(e) => last_error = Some(e.to_string()),
+        }
+    }
+    GatewayTimes::new(gateway.to_string(), samples, ttfb, total, last_error)
+}
+
+/// The report as a table, fastest first
+pub fn table(report: &BenchmarkReport) -> String {
+    let ms = |t: Option<u64>| t.map_or_else(|| "-".to_string(), |t| format!("{}ms", t));
+    let mut out = format!(
+        "{:<4}  {:<44}  {:>10}  {:>10}  {:>10}  {:>10}  {:>8}\n",
+        "rank", "gateway", "ttfb p50", "ttfb p95", "total p50", "total p95", "failed"
+    );
+    for (n, g) in report.gateways.iter().enumerate() {
+        out.push_str(&format!(
+            "{:<4}  {:<44}  {:>10}  {:>10}  {:>10}  {:>10}  {:>8}\n",
+            n + 1,
+            g.gateway,
+            ms(g.ttfb_median_ms),
+            ms(g.ttfb_p95_ms),
+            ms(g.total_median_ms),
+            ms(g.total_p95_ms),
+            format!("{}/{}", g.failures, g.requests)
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn configured_gateways() {
+        let configured = [
+            GatewayConfig {
+                url: "https://ipfs.io/ipfs/{v0}".to_string(),
+                headers: std::iter::once(("Host".to_string(), "ipfs.io".to_string())).collect(),
+                token_env: None,
+            },
+            GatewayConfig {
+                url: "https://{base32}.ipfs.example.com".to_string(),
+                headers: BTreeMap::new(),
+                token_env: Some("CB_TEST_GATEWAY_TOKEN".to_string()),
+            },
+        ];
+        let gateways = gateways(&configured);
+        assert_eq!(gateways.len(), PUBLIC_GATEWAYS.len() + 1);
+        // the public gateways stay where they were, without headers unless the config gives them some
+        assert_eq!(gateways[0], Gateway::public(PUBLIC_GATEWAYS[0]));
+        assert_eq!(gateways[0].header_map().unwrap().len(), 0);
+        assert_eq!(gateways[1].describe_headers(), " (with Host)");
+        assert_eq!(gateways[1].header_map().unwrap()["host"], "ipfs.io");
+
+        let private = gateways.last().unwrap();
+        std::env::remove_var("CB_TEST_GATEWAY_TOKEN");
+        assert!(private.header_map().is_err());
+        std::env::set_var("CB_TEST_GATEWAY_TOKEN", "s3cret");
+        let headers = private.header_map().unwrap();
+        assert_eq!(headers[reqwest::header::AUTHORIZATION], "Bearer s3cret");
+        assert_eq!(private.describe_headers(), " (with Authorization)");
+        assert!(!format!("{:?}", headers).contains("s3cret"));
+        assert_eq!(
+            format!("{:?}", gateways[1]),
+            r#"Gateway { template: "https://ipfs.io/ipfs/{v0}", headers: ["Host"], token_env: None }"#
+        );
+    }
+
+    #[test]
+    fn percentiles() {
+        let times: Vec<u64> = (1..=20).collect();
+        assert_eq!(percentile(&times, 50), Some(10));
+        assert_eq!(percentile(&times, 95), Some(19));
+        assert_eq!(percentile(&[7], 95), Some(7));
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn ranking() {
+        let times = |name: &str, total: Vec<u64>| {
+            GatewayTimes::new(name.to_string(), 3, total.clone(), total, Some("timed out".to_string()))
+        };
+        let mut gateways = vec![
+            times("dead", vec![]),
+            times("slow", vec![900, 1000, 5000]),
+            times("fast", vec![100, 120]),
+        ];
+        rank(&mut gateways);
+        let names: Vec<_> = gateways.iter().map(|g| g.gateway.as_str()).collect();
+        assert_eq!(names, ["fast", "slow", "dead"]);
+        assert_eq!(gateways[0].failures, 1);
+        assert_eq!(gateways[1].total_p95_ms, Some(5000));
+
+        let table = table(&BenchmarkReport {
+            root: String::new(),
+            path: String::new(),
+            measured: 0,
+            gateways,
+        });
+        let lines: Vec<_> = table.lines().collect();
+        assert!(lines[1].starts_with("1     fast"));
+        assert!(lines[3].ends_with("-           -           -           -       3/3"));
+    }
+
+    #[test]
+    fn urls() {
+        let root = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+        let urls = root_urls(&root).unwrap();
+        assert_eq!(urls.len(), PUBLIC_GATEWAYS.len());
+        assert_eq!(
+            urls[1],
+            "https://ipfs.io/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh"
+        );
+        assert!(urls[0].starts_with("https://bafy"));
+    }
+
+    #[test]
+    fn health() {
+        let dead = RunHealth {
+            succeeded: 0,
+            failed: 4,
+        };
+        let flaky = RunHealth {
+            succeeded: 1,
+            failed: 3,
+        };
+        let mut health = GatewayHealth::default();
+        assert!(!health.is_unhealthy(2));
+        health.record(dead, 3);
+        assert!(!health.is_unhealthy(2));
+        health.record(dead, 3);
+        assert!(health.is_unhealthy(2));
+        assert!(!health.is_unhealthy(3));
+        assert!(!health.is_unhealthy(0));
+        health.record(flaky, 3);
+        assert!(!health.is_unhealthy(1));
+        health.record(dead, 3);
+        assert_eq!(health.runs, [dead, flaky, dead]);
+        // a run without any requests isn't a failure
+        health.record(RunHealth::default(), 3);
+        assert!(!health.is_unhealthy(1));
+
+        let mut state = PrimeState::default();
+        state.record_run("https://ipfs.io/ipfs/{v0}", dead, 3);
+        state.record_run("https://ipfs.io/ipfs/{v0}", flaky, 3);
+        let rows = state.health_rows(&gateways(&[]), 3);
+        assert_eq!(rows.len(), PUBLIC_GATEWAYS.len());
+        let row = rows.iter().find(|r| r.gateway == "https://ipfs.io/ipfs/{v0}").unwrap();
+        assert_eq!((row.runs, row.requests, row.succeeded), (2, 8, 1));
+        assert_eq!(row.success_percent, Some(12));
+        assert!(!row.unhealthy);
+        assert!(rows
+            .iter()
+            .all(|r| r.gateway == row.gateway || r.success_percent.is_none()));
+    }
+
+    #[test]
+    fn prime_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prime_state.json");
+        let root = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+        let other = cid::Cid::from_str("QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn").unwrap();
+
+        assert!(matches!(
+            PrimeState::load(&path, &root, PrimeMode::Resume),
+            Err(CbError::NothingToResume { .. })
+        ));
+        let mut state = PrimeState::load(&path, &root, PrimeMode::Auto).unwrap();
+        state.mark("https://ipfs.io/ipfs/root", "");
+        state.mark("https://ipfs.io/ipfs/root", "jam1");
+        let run = RunHealth {
+            succeeded: 2,
+            failed: 0,
+        };
+        state.record_run("https://ipfs.io/ipfs/{v0}", run, HEALTH_RUNS);
+        state.save(&path).unwrap();
+
+        for mode in [PrimeMode::Auto, PrimeMode::Resume] {
+            let state = PrimeState::load(&path, &root, mode).unwrap();
+            assert!(state.is_primed("https://ipfs.io/ipfs/root", "jam1"));
+            assert!(!state.is_primed("https://ipfs.io/ipfs/root", "jam2"));
+            assert!(!state.is_primed("https://dweb.link/root", "jam1"));
+        }
+        let fresh = PrimeState::load(&path, &root, PrimeMode::Fresh).unwrap();
+        assert!(fresh.primed.is_empty());
+        // the gateways' health is kept whatever the root
+        assert_eq!(fresh.health["https://ipfs.io/ipfs/{v0}"].runs, [run]);
+        // a new root starts over
+        let other_state = PrimeState::load(&path, &other, PrimeMode::Auto).unwrap();
+        assert!(other_state.primed.is_empty());
+        assert_eq!(other_state.health, state.health);
+        assert!(PrimeState::load(&path, &other, PrimeMode::Resume).is_err());
+        let archived: PrimeState = state_store::read_doc(&state_store::archive_path(&path))
+            .unwrap()
+            .unwrap();
+        assert!(archived.is_primed("https://ipfs.io/ipfs/root", "jam1"));
+
+        // a state cut off while it was written starts over too
+        std::fs::write(
+            &path,
+            r#"{"root": "QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh", "pri"#,
+        )
+        .unwrap();
+        assert!(PrimeState::load(&path, &root, PrimeMode::Auto)
+            .unwrap()
+            .primed
+            .is_empty());
+    }
+
+    #[test]
+    fn priority() {
+        let mut state = PrimeState::default();
+        state.mark("https://dweb.link/root", "");
+        state.mark("https://ipfs.io/ipfs/root", "jam2");
+        let names = [("", true), ("jam2", true), ("index.html", false), ("jam1", false)];
+        let queue = state.queue(&["https://ipfs.io/ipfs/root", "https://dweb.link/root"], &names);
+        let queue: Vec<(usize, &str)> = queue.into_iter().map(|(g, n)| (g, names[n].0)).collect();
+        // the root and the new recording on both gateways, before anything else
+        assert_eq!(
+            queue,
+            [
+                (0, ""),
+                (1, "jam2"),
+                (0, "index.html"),
+                (0, "jam1"),
+                (1, "index.html"),
+                (1, "jam1")
+            ]
+        );
+    }
+
+    #[test]
+    fn budgets() {
+        assert_eq!("500".parse(), Ok(PrimeBudget::Requests(500)));
+        assert_eq!("90s".parse(), Ok(PrimeBudget::Time(Duration::from_secs(90))));
+        assert_eq!(" 20m".parse(), Ok(PrimeBudget::Time(Duration::from_secs(1200))));
+        assert_eq!("2h".parse(), Ok(PrimeBudget::Time(Duration::from_secs(7200))));
+        for invalid in ["", "0", "0s", "2d", "h", "1.5h", "-3"] {
+            assert!(invalid.parse::<PrimeBudget>().is_err(), "{}", invalid);
+        }
+
+        assert!(!PrimeBudget::Requests(2).is_spent(1, Duration::from_secs(3600)));
+        assert!(PrimeBudget::Requests(2).is_spent(2, Duration::ZERO));
+        assert!(!PrimeBudget::Time(Duration::from_secs(60)).is_spent(1000, Duration::from_secs(59)));
+        assert!(PrimeBudget::Time(Duration::from_secs(60)).is_spent(0, Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,261 @@
+//! Hashing files once per run (`.cb_hash_cache.json` in the data dir)
+//!
+//! Conversion provenance, duplicate detection and the build manifest all need digests of files, often of the same
+//! ones: a flac is fingerprinted once for its ogg and again for its mp3, and the same style.css is copied next to every
+//! page.  They all ask the [`HasherPool`] in the [`RunContext`](crate::context::RunContext), which reads a file once
+//! for every digest that's wanted of it, and remembers the results for as long as the file's size and modification
+//! time stay the same.  What it learns about the data dir is kept for the next run, like the media info cache.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{analysis::parallel_for_each, checksum::Xxh64, error::CbError, media_cache::Fingerprint};
+
+/// Name of the cache file, in the root of the data dir
+pub const HASH_CACHE_FILE: &str = ".cb_hash_cache.json";
+
+/// Files are read this much at a time, however big they are
+const READ_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Xxh64,
+}
+
+/// The digests known for a file, as lowercase hex
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Digests {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xxh64: Option<String>,
+}
+
+impl Digests {
+    fn has(&self, algorithm: Algorithm) -> bool {
+        match algorithm {
+            Algorithm::Sha256 => self.sha256.is_some(),
+            Algorithm::Xxh64 => self.xxh64.is_some(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    fingerprint: Fingerprint,
+    digests: Digests,
+}
+
+#[derive(Debug, Default)]
+pub struct HasherPool {
+    /// The data dir, whose files are remembered between runs (nothing is, without one)
+    root: Option<PathBuf>,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+    dirty: AtomicBool,
+    files_read: AtomicUsize,
+}
+
+impl HasherPool {
+    /// Opens the pool for a data dir, with what earlier runs hashed in it
+    pub fn open(root: &Path) -> HasherPool {
+        let entries = Self::read(&root.join(HASH_CACHE_FILE))
+            .into_iter()
+            .map(|(key, entry)| (root.join(key), entry))
+            .collect();
+        HasherPool {
+            root: Some(root.to_path_buf()),
+            entries: Mutex::new(entries),
+            ..Default::default()
+        }
+    }
+
+    /// Reads a cache file, treating a missing or corrupt file as empty
+    fn read(path: &Path) -> HashMap<String, Entry> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return HashMap::new(),
+        };
+        match serde_json::from_slice(&bytes) {
+            Ok(entries) => entries,
+            Err(e) => {
+                say!("Warning: ignoring corrupt hash cache {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        }
+    }
+
+    /// How many times a file was read since the pool was made (hashing the same file twice is a bug)
+    pub fn files_read(&self) -> usize {
+        self.files_read.load(Ordering::SeqCst)
+    }
+
+    pub fn sha256(&self, path: &Path) -> Result<String, CbError> {
+        Ok(self.digests(path, &[Algorithm::Sha256])?.sha256.unwrap())
+    }
+
+    /// Returns (at least) the `wanted` digests of a file, reading it only if they aren't known yet
+    pub fn digests(&self, path: &Path, wanted: &[Algorithm]) -> Result<Digests, CbError> {
+        // taken before reading, so that a file that changes while it's read is hashed again next time
+        let fingerprint = Fingerprint::of(path);
+        let mut known = Digests::default();
+        if let Some(fingerprint) = &fingerprint {
+            if let Some(entry) = self.entries.lock().unwrap().get(path) {
+                if entry.fingerprint == *fingerprint {
+                    known = entry.digests.clone();
+                }
+            }
+        }
+        let missing: Vec<Algorithm> = wanted.iter().copied().filter(|a| !known.has(*a)).collect();
+        if missing.is_empty() {
+            return Ok(known);
+        }
+
+        let digests = self.hash_file(path, &missing)?;
+        known.sha256 = known.sha256.or(digests.sha256);
+        known.xxh64 = known.xxh64.or(digests.xxh64);
+        if let Some(fingerprint) = fingerprint {
+            let entry = Entry {
+                fingerprint,
+                digests: known.clone(),
+            };
+            self.entries.lock().unwrap().insert(path.to_path_buf(), entry);
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        Ok(known)
+    }
+
+    /// Reads the file once, feeding every hasher
+    fn hash_file(&self, path: &Path, algorithms: &[Algorithm]) -> Result<Digests, CbError> {
+        let mut file = File::open(path).map_err(|e| CbError::io(format!("Failed to open {}", path.display()), e))?;
+        self.files_read.fetch_add(1, Ordering::SeqCst);
+        let mut sha256 = algorithms.contains(&Algorithm::Sha256).then(Sha256::new);
+        let mut xxh64 = algorithms.contains(&Algorithm::Xxh64).then(Xxh64::default);
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            let n = file
+                .read(&mut buf)
+                .map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?;
+            if n == 0 {
+                break;
+            }
+            if let Some(hasher) = &mut sha256 {
+                hasher.update(&buf[..n]);
+            }
+            if let Some(hasher) = &mut xxh64 {
+                hasher.update(&buf[..n]);
+            }
+        }
+        Ok(Digests {
+            sha256: sha256.map(|hasher| format!("{:x}", hasher.finalize())),
+            xxh64: xxh64.map(|hasher| hasher.finish()),
+        })
+    }
+
+    /// Hashes many files, up to `jobs` at once, returning their digests in the same order
+    pub fn digests_all(&self, jobs: usize, paths: &[PathBuf], wanted: &[Algorithm]) -> Vec<Result<Digests, CbError>> {
+        // a file that's listed twice is still only read once
+        let mut unique: Vec<&PathBuf> = paths.iter().collect();
+        unique.sort();
+        unique.dedup();
+        let results = Mutex::new(HashMap::new());
+        parallel_for_each(jobs, unique, |path| {
+            let digests = self.digests(path, wanted);
+            results.lock().unwrap().insert(path.clone(), digests);
+        });
+        let mut results = results.into_inner().unwrap();
+        paths
+            .iter()
+            .map(|path| match results.get(path) {
+                Some(Ok(digests)) => Ok(digests.clone()),
+                // errors can't be cloned, so a path that's listed again gets asked again
+                _ => results.remove(path).unwrap_or_else(|| self.digests(path, wanted)),
+            })
+            .collect()
+    }
+
+    /// Writes what's known about the data dir's files back to disk, if anything changed
+    ///
+    /// Entries written by other processes in the meantime are kept, and the file is replaced atomically so that a
+    /// concurrent reader never sees half a file.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let root = match &self.root {
+            Some(root) if self.dirty.load(Ordering::SeqCst) => root,
+            _ => return Ok(()),
+        };
+        let path = root.join(HASH_CACHE_FILE);
+        let mut merged = Self::read(&path);
+        for (file, entry) in self.entries.lock().unwrap().iter() {
+            if let Ok(rel) = file.strip_prefix(root) {
+                merged.insert(rel.to_string_lossy().replace('\\', "/"), entry.clone());
+            }
+        }
+        // only keep entries for files that still exist
+        merged.retain(|key, _| root.join(key).exists());
+
+        crate::write_atomically(&path, &serde_json::to_vec(&merged)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_read_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..4).map(|i| dir.path().join(format!("{}.flac", i))).collect();
+        for (i, file) in files.iter().enumerate() {
+            std::fs::write(file, vec![i as u8; READ_SIZE + i]).unwrap();
+        }
+
+        let pool = HasherPool::open(dir.path());
+        let mut listed = files.clone();
+        listed.push(files[0].clone());
+        let digests = pool.digests_all(3, &listed, &[Algorithm::Sha256, Algorithm::Xxh64]);
+        assert_eq!(digests.len(), 5);
+        assert_eq!(digests[0].as_ref().unwrap(), digests[4].as_ref().unwrap());
+        assert_eq!(
+            digests[1].as_ref().unwrap().sha256.as_deref().unwrap(),
+            crate::checksum::sha256_file(&files[1]).unwrap()
+        );
+        // asking again, for either digest, doesn't read anything
+        for file in &files {
+            pool.sha256(file).unwrap();
+            pool.digests(file, &[Algorithm::Xxh64]).unwrap();
+        }
+        assert_eq!(pool.files_read(), files.len());
+
+        // a changed file is read again
+        std::fs::write(&files[2], b"changed").unwrap();
+        assert_eq!(
+            pool.sha256(&files[2]).unwrap(),
+            crate::checksum::sha256_bytes(b"changed")
+        );
+        assert_eq!(pool.files_read(), files.len() + 1);
+        assert!(pool.sha256(&dir.path().join("missing.flac")).is_err());
+
+        // and the next run remembers everything
+        pool.save().unwrap();
+        let pool = HasherPool::open(dir.path());
+        for file in &files {
+            pool.sha256(file).unwrap();
+        }
+        assert_eq!(pool.files_read(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+//! The roots made by `--patch`, and how the archive grew over them (`cb_processor history-report`)
+//!
+//! Every new root is appended to the roots history file (`roots_history` in the config) as a line of JSON.  The report
+//! looks each of them up in the local IPFS repo only, since old roots are often not pinned anywhere any more.
+//! `cb_processor rollback` publishes one of the earlier roots again, and appends it as a new entry, so the entries are
+//! never changed.  Once there are more of them than `[retention]` allows, the oldest are moved to the file's `.1` (see
+//! [`crate::state_store`]).
+
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::RunContext,
+    error::CbError,
+    ipfs::DagSizes,
+    state_store::{self, Retention},
+    timing,
+};
+
+/// A line of the roots history file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RootEntry {
+    pub root: String,
+    /// Seconds since the Unix epoch
+    pub published: u64,
+    /// The root that was published before, when this entry is a rollback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back_from: Option<String>,
+    /// The [`crate::GENERATOR`] that made the entry, which is empty for entries from before it was recorded
+    #[serde(default)]
+    pub generator: String,
+}
+
+/// Reads the roots history, oldest first.  A missing file is an empty history
+pub fn load(path: &Path) -> Result<Vec<RootEntry>, CbError> {
+    state_store::read_log(path)
+}
+
+/// Adds `root` to the end of the history, unless it's already the latest entry
+pub fn record(path: &Path, root: &cid::Cid, when: SystemTime, retention: Retention) -> Result<(), CbError> {
+    let root = root.to_string();
+    if load(path)?.last().is_some_and(|last| last.root == root) {
+        return Ok(());
+    }
+    let entry = RootEntry {
+        root,
+        published: when.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        rolled_back_from: None,
+        generator: crate::GENERATOR.to_string(),
+    };
+    state_store::append_log(path, &entry, retention)
+}
+
+/// Adds a rollback from `from` to `root` to the end of the history
+pub fn record_rollback(
+    path: &Path, root: &cid::Cid, from: &str, when: SystemTime, retention: Retention,
+) -> Result<(), CbError> {
+    let entry = RootEntry {
+        root: root.to_string(),
+        published: when.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        rolled_back_from: Some(from.to_string()),
+        generator: crate::GENERATOR.to_string(),
+    };
+    state_store::append_log(path, &entry, retention)
+}
+
+/// A row of the report.  The sizes are `None` if the root isn't in the local repo any more
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryRow {
+    pub root: String,
+    pub published: u64,
+    pub total: Option<u64>,
+    pub links: Option<usize>,
+    pub generator: String,
+}
+
+/// Looks up the size of every root in the history (without going to the network)
+pub fn report(ctx: &RunContext, entries: &[RootEntry]) -> Vec<HistoryRow> {
+    let mut offline = ctx.clone();
+    offline.ipfs_offline = true;
+    entries
+        .iter()
+        .map(|entry| {
+            let sizes = entry
+                .root
+                .parse::<cid::Cid>()
+                .ok()
+                .and_then(|root| DagSizes::get(&offline, &root).ok());
+            HistoryRow {
+                root: entry.root.clone(),
+                published: entry.published,
+                total: sizes.as_ref().map(|s| s.total),
+                links: sizes.as_ref().map(|s| s.links.len()),
+                generator: entry.generator.clone(),
+            }
+        })
+        .collect()
+}
+
+/// The report as a table, with the change in size from the previous root that could be found
+pub fn table(rows: &[HistoryRow]) -> String {
+    let mut out = format!(
+        "{:<16}  {:<62}  {:>12}  {:>6}  {:>12}  {}\n",
+        "published (UTC)", "root", "size", "links", "change", "generator"
+    );
+    let mut previous = None;
+    for row in rows {
+        let (size, links, change) = match (row.total, row.links) {
+            (Some(total), Some(links)) => {
+                let change = match previous {
+                    Some(previous) if total >= previous => format!("+{}", megabytes(total - previous)),
+                    Some(previous) => format!("-{}", megabytes(previous - total)),
+                    None => String::new(),
+                };
+                previous = Some(total);
+                (megabytes(total), links.to_string(), change)
+            }
+            _ => ("not local".to_string(), String::new(), String::new()),
+        };
+        out.push_str(&format!(
+            "{:<16}  {:<62}  {:>12}  {:>6}  {:>12}  {}\n",
+            timing::utc_time(row.published),
+            row.root,
+            size,
+            links,
+            change,
+            row.generator
+        ));
+    }
+    out
+}
+
+/// The history as a numbered list to roll back from, oldest first
+pub fn choices(entries: &[RootEntry]) -> String {
+    let mut out = String::new();
+    for (n, entry) in entries.iter().enumerate() {
+        let note = if n + 1 == entries.len() {
+            " (published now)".to_string()
+        } else {
+            entry
+                .rolled_back_from
+                .as_ref()
+                .map_or_else(String::new, |from| format!(" (rollback from {})", from))
+        };
+        out.push_str(&format!(
+            "{:>4}  {}  {}{}\n",
+            n + 1,
+            timing::utc_time(entry.published),
+            entry.root,
+            note
+        ));
+    }
+    out
+}
+
+/// The entry that `choice` names: its number in [`choices`], or its root
+pub fn find<'a>(entries: &'a [RootEntry], choice: &str) -> Option<&'a RootEntry> {
+    let choice = choice.trim();
+    match choice.parse::<usize>() {
+        Ok(n) => n.checked_sub(1).and_then(|i| entries.get(i)),
+        Err(_) => entries.iter().rev().find(|e| e.root == choice),
+    }
+}
+
+fn megabytes(bytes: u64) -> String {
+    format!("{}MB", crate::one_decimal(bytes, 1024 * 1024))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    const KEEP: Retention = crate::context::DEFAULT_ROOTS_HISTORY_RETENTION;
+
+    #[test]
+    fn record_and_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("roots_history.jsonl");
+        assert!(load(&path).unwrap().is_empty());
+
+        let a = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+        let b = cid::Cid::from_str("QmXdCEDuqTgR2gfmVUyYCojvmxqRuQaL97RGNDjozrYCxE").unwrap();
+        let t = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        record(&path, &a, t, KEEP).unwrap();
+        // patching without changes makes the same root again
+        record(&path, &a, t, KEEP).unwrap();
+        record(&path, &b, t + Duration::from_secs(60), KEEP).unwrap();
+        let entries = load(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].root, b.to_string());
+        assert_eq!(entries[1].published, 1_700_000_060);
+        assert_eq!(entries[1].generator, crate::GENERATOR);
+
+        // a rollback is a new entry, even though the root is already in the history
+        record_rollback(&path, &a, &entries[1].root, t + Duration::from_secs(120), KEEP).unwrap();
+        let entries = load(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].root, a.to_string());
+        assert_eq!(entries[2].rolled_back_from.as_deref(), Some(b.to_string().as_str()));
+        assert_eq!(entries[0].rolled_back_from, None);
+        assert_eq!(entries[2].generator, crate::GENERATOR);
+
+        let listed = choices(&entries);
+        assert!(listed.lines().nth(1).unwrap().ends_with(&b.to_string()), "{}", listed);
+        assert!(
+            listed.lines().nth(2).unwrap().ends_with("(published now)"),
+            "{}",
+            listed
+        );
+        assert_eq!(find(&entries, "2"), Some(&entries[1]));
+        assert_eq!(find(&entries, &b.to_string()), Some(&entries[1]));
+        assert_eq!(find(&entries, "0"), None);
+        assert_eq!(find(&entries, "4"), None);
+
+        let row = |total, links, generator: &str| HistoryRow {
+            root: a.to_string(),
+            published: 0,
+            total,
+            links,
+            generator: generator.to_string(),
+        };
+        let table = table(&[
+            row(Some(10 << 20), Some(3), ""),
+            row(None, None, "cb_processor 0.1.0 (abc1234)"),
+            row(Some(15 << 20), Some(4), "cb_processor 0.2.0 (def5678)"),
+        ]);
+        let lines: Vec<_> = table.lines().collect();
+        assert!(lines[0].ends_with("  change  generator"));
+        assert!(lines[1].ends_with("10.0MB       3                "));
+        assert!(lines[2].contains("not local"));
+        assert!(lines[2].ends_with("  cb_processor 0.1.0 (abc1234)"));
+        // the change is from the last root that was found
+        assert!(lines[3].ends_with("15.0MB       4        +5.0MB  cb_processor 0.2.0 (def5678)"));
+    }
+
+    #[test]
+    fn cut_off_and_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("roots_history.jsonl");
+        let a = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+        let b = cid::Cid::from_str("QmXdCEDuqTgR2gfmVUyYCojvmxqRuQaL97RGNDjozrYCxE").unwrap();
+        let first = format!("{{\"root\":\"{}\",\"published\":1}}\n", a);
+        // a run that died while writing the second entry
+        std::fs::write(&path, format!("{}{{\"root\":\"{}\",\"publ", first, b)).unwrap();
+        assert_eq!(load(&path).unwrap().len(), 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), first);
+
+        let two = Retention {
+            max_entries: 2,
+            max_bytes: 1024,
+        };
+        let t = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        record(&path, &b, t, two).unwrap();
+        record(&path, &a, t, two).unwrap();
+        let entries = load(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].root, b.to_string());
+        let archived = load(&crate::state_store::archive_path(&path)).unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].published, 1);
+        // which was written before the generator was recorded
+        assert_eq!(archived[0].generator, "");
+    }
+}
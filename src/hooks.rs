@@ -0,0 +1,173 @@
+//! Site-specific commands run around the stages of a run
+//!
+//! Each hook in the `[hooks]` section of the config is a command line for the shell, run with `CB_*` variables that
+//! say what the stage did.  What it prints is logged line by line, with the hook's name in front.
+
+use std::{
+    io::{BufRead, BufReader, Read},
+    process::{Command, Stdio},
+};
+
+use crate::{
+    context::{HookConfig, RunContext},
+    error::CbError,
+    progress::{self, ProgressEvent, Stage},
+};
+
+/// The places a hook can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// Before `--convert` converts anything
+    PreConvert,
+    /// After the pages and metadata are written
+    PostGenerate,
+    /// After `--patch` made the new root
+    PostPatch,
+    /// After `--publish` pointed the IPNS name at the new root
+    PostPublish,
+}
+
+impl Hook {
+    /// The name of the hook in the config
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreConvert => "pre_convert",
+            Hook::PostGenerate => "post_generate",
+            Hook::PostPatch => "post_patch",
+            Hook::PostPublish => "post_publish",
+        }
+    }
+
+    /// The stage that fails when the hook does
+    pub fn stage(self) -> Stage {
+        match self {
+            Hook::PreConvert => Stage::Convert,
+            Hook::PostGenerate => Stage::Generate,
+            Hook::PostPatch => Stage::Patch,
+            Hook::PostPublish => Stage::Publish,
+        }
+    }
+
+    fn config(self, ctx: &RunContext) -> Option<&HookConfig> {
+        let hooks = &ctx.hooks;
+        match self {
+            Hook::PreConvert => hooks.pre_convert.as_ref(),
+            Hook::PostGenerate => hooks.post_generate.as_ref(),
+            Hook::PostPatch => hooks.post_patch.as_ref(),
+            Hook::PostPublish => hooks.post_publish.as_ref(),
+        }
+    }
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    }
+}
+
+/// Prints each line of `output` with the hook's name in front, as it comes
+fn log_lines(hook: Hook, output: impl Read) {
+    for line in BufReader::new(output).lines() {
+        match line {
+            Ok(line) => say!("  {}: {}", hook.name(), line),
+            Err(_) => break,
+        }
+    }
+}
+
+/// Runs `hook`, if the config has one, with `vars` in its environment
+///
+/// With `dry_run` the command is only printed.  A hook that exits with an error fails with
+/// [`CbError::HookFailed`], unless it has `allow_failure`, which makes it a warning.
+pub fn run(ctx: &RunContext, hook: Hook, vars: &[(&str, String)], dry_run: bool) -> Result<(), CbError> {
+    let config = match hook.config(ctx) {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    if dry_run {
+        say!("Would run the {} hook: {}", hook.name(), config.command);
+        return Ok(());
+    }
+
+    say!("Running the {} hook: {}", hook.name(), config.command);
+    let mut child = shell(&config.command)
+        .envs(vars.iter().map(|(name, value)| (name, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| CbError::tool(format!("The {} hook", hook.name()), e))?;
+
+    let stdout = child.stdout.take().expect("the hook's stdout is piped");
+    let stderr = child.stderr.take().expect("the hook's stderr is piped");
+    let status = std::thread::scope(|s| {
+        s.spawn(|| log_lines(hook, stdout));
+        s.spawn(|| log_lines(hook, stderr));
+        child.wait()
+    })
+    .map_err(|e| CbError::tool(format!("The {} hook", hook.name()), e))?;
+
+    if status.success() {
+        return Ok(());
+    }
+    let error = CbError::HookFailed {
+        hook: hook.name().to_string(),
+        status,
+    };
+    if !config.allow_failure {
+        return Err(error);
+    }
+    let message = format!("{}, carrying on since it's allowed to fail", error);
+    say!("  {}", message);
+    progress::emit(ProgressEvent::Warning {
+        stage: hook.stage(),
+        message,
+    });
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::context::HooksConfig;
+
+    fn with_hook(command: &str, allow_failure: bool) -> RunContext {
+        RunContext {
+            hooks: HooksConfig {
+                post_patch: Some(HookConfig {
+                    command: command.to_string(),
+                    allow_failure,
+                }),
+                ..HooksConfig::default()
+            },
+            ..RunContext::default()
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let vars = [("CB_NEW_ROOT_CID", "QmNew".to_string())];
+
+        let ctx = with_hook(&format!("echo \"$CB_NEW_ROOT_CID\" > {}", out.display()), false);
+        run(&ctx, Hook::PostPatch, &vars, true).unwrap();
+        assert!(!out.exists());
+        run(&ctx, Hook::PostPatch, &vars, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "QmNew\n");
+        // hooks that aren't configured don't run
+        run(&ctx, Hook::PostPublish, &vars, false).unwrap();
+
+        assert!(matches!(
+            run(&with_hook("exit 3", false), Hook::PostPatch, &vars, false),
+            Err(CbError::HookFailed { .. })
+        ));
+        run(&with_hook("exit 3", true), Hook::PostPatch, &vars, false).unwrap();
+    }
+}
@@ -1,11 +1,23 @@
-use anyhow::bail;
 use serde::Deserialize;
 use serde::Serialize;
 
 use std::str::FromStr;
-use std::time::Duration;
+
+#[cfg(feature = "cli")]
+use colored::Colorize;
+
+use crate::context::RunContext;
+use crate::error::CbError;
+use crate::gateway::{HealthRow, RunHealth};
+use crate::listing;
+#[cfg(not(feature = "cli"))]
+use crate::plain::Colorize;
+use crate::progress::{self, ProgressEvent, Stage};
+use crate::select::Selector;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use std::{convert::TryFrom, ffi::OsStr};
-use std::{path::Path, process::Command};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct IPFSHash {
@@ -17,27 +29,28 @@ struct IPFSHash {
 pub struct IPFSObject {
     #[serde(rename = "Links")]
     pub links: Vec<IPFSLink>,
-    // #[serde(rename="Data")]
-    // pub data: String,
+    /// The object's own data (for directories, just the UnixFS header)
+    #[serde(rename = "Data", default)]
+    pub data: String,
     #[serde(skip)]
     hash: Option<cid::Cid>,
 }
 
 impl IPFSObject {
-    pub fn get(hash: &cid::Cid) -> anyhow::Result<IPFSObject> {
-        let output = Command::new("ipfs")
+    pub fn get(ctx: &RunContext, hash: &cid::Cid) -> Result<IPFSObject, CbError> {
+        let output = ctx
+            .ipfs_command()
             .arg("object")
             .arg("get")
             .arg(format!("{}", hash))
             .arg("--encoding=json")
-            .output()?;
+            .output()
+            .map_err(|e| CbError::tool("ipfs", e))?;
+        CbError::check_ipfs("object get", &output)?;
 
-        if !output.status.success() {
-            bail!("Failed to run ipfs object patch: {}", output.status);
-        }
-
-        let mut ipfs_object: IPFSObject = serde_json::from_slice(&output.stdout)?;
-        ipfs_object.hash = Some(hash.clone());
+        let mut ipfs_object: IPFSObject = serde_json::from_slice(&output.stdout)
+            .map_err(|e| CbError::parse(format!("Unexpected output from ipfs object get {}", hash), e))?;
+        ipfs_object.hash = Some(*hash);
 
         Ok(ipfs_object)
     }
@@ -46,8 +59,9 @@ impl IPFSObject {
         self.hash.as_ref().unwrap()
     }
 
-    pub fn add_link(&self, link_name: &str, link_hash: &cid::Cid) -> anyhow::Result<IPFSObject> {
-        let output = Command::new("ipfs")
+    pub fn add_link(&self, ctx: &RunContext, link_name: &str, link_hash: &cid::Cid) -> Result<IPFSObject, CbError> {
+        let output = ctx
+            .ipfs_command()
             .arg("object")
             .arg("patch")
             .arg("add-link")
@@ -55,90 +69,702 @@ impl IPFSObject {
             .arg(link_name)
             .arg(format!("{}", link_hash))
             .arg("--encoding=json")
-            .output()?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            bail!("Failed to run ipfs object patch: {} {}", output.status, stderr);
-        }
-        let new_hash: IPFSHash = serde_json::from_slice(&output.stdout)?;
+            .output()
+            .map_err(|e| CbError::tool("ipfs", e))?;
+        CbError::check_ipfs("object patch", &output)?;
 
-        let new_cid = cid::Cid::try_from(new_hash.hash.as_str())?;
+        let new_hash: IPFSHash = serde_json::from_slice(&output.stdout)
+            .map_err(|e| CbError::parse("Unexpected output from ipfs object patch", e))?;
+        let new_cid = cid::Cid::try_from(new_hash.hash.as_str()).map_err(|e| {
+            CbError::parse(
+                format!("ipfs object patch returned an invalid CID {:?}", new_hash.hash),
+                e,
+            )
+        })?;
 
-        IPFSObject::get(&new_cid)
+        IPFSObject::get(ctx, &new_cid)
     }
 }
 
-fn ipfs_add<P: AsRef<Path>>(path: P, is_folder: bool) -> anyhow::Result<cid::Cid> {
-    let mut cmd = Command::new("ipfs");
+/// Adds a file or folder to the local IPFS repo, or with `only_hash` just works out what its CID would be
+fn ipfs_add<P: AsRef<Path>>(ctx: &RunContext, path: P, is_folder: bool, only_hash: bool) -> Result<cid::Cid, CbError> {
+    let start = Instant::now();
+    let mut cmd = ctx.ipfs_command();
     cmd.arg("add").arg("--pin=false").arg("-Q").arg(path.as_ref());
     if is_folder {
         cmd.arg("-r");
     }
-    let output = cmd.output()?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        bail!("Failed to run ipfs add: {} {}", output.status, stderr);
+    if only_hash {
+        cmd.arg("--only-hash");
     }
+    let output = cmd.output().map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("add", &output)?;
 
     let new_hash = String::from_utf8_lossy(&output.stdout);
-    let new_cid = cid::Cid::from_str(new_hash.trim())?;
+    let new_cid = cid::Cid::from_str(new_hash.trim())
+        .map_err(|e| CbError::parse(format!("ipfs add returned an invalid CID {:?}", new_hash.trim()), e))?;
+    ctx.timings
+        .record_item(Stage::Patch, path.as_ref().display().to_string(), start.elapsed());
+    if !only_hash {
+        ctx.metrics.add_ipfs_bytes(size_on_disk(path.as_ref()));
+    }
 
     Ok(new_cid)
 }
 
-pub fn patch_root_object<P: AsRef<Path>>(root_hash: &cid::Cid, root_dir: P) -> anyhow::Result<cid::Cid> {
-    let root_dir: &Path = root_dir.as_ref();
+/// Counts for progress reporting while patching
+struct PatchProgress {
+    index: usize,
+    total: usize,
+    warnings: usize,
+}
+
+/// The size of a file, or of all the files under a folder
+fn size_on_disk(path: &Path) -> u64 {
+    match std::fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| size_on_disk(&entry.path())).sum(),
+        Err(_) => path.metadata().map_or(0, |meta| meta.len()),
+    }
+}
+
+/// Counts all the files and directories under `dir`
+fn count_entries(dir: &Path) -> Result<usize, CbError> {
+    let mut count = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        count += 1;
+        if entry.path().is_dir() {
+            count += count_entries(&entry.path())?;
+        }
+    }
+    Ok(count)
+}
+
+/// Lists a directory (sorted, see [`crate::listing`]), with the directory in any error
+fn read_dir(dir: &Path) -> Result<impl Iterator<Item = Result<std::fs::DirEntry, CbError>>, CbError> {
+    let entries = listing::sorted(dir).map_err(|e| CbError::io(format!("Failed to read {}", dir.display()), e))?;
+    Ok(entries.into_iter().map(Ok))
+}
+
+/// What patching will do to a single link in an IPFS directory object
+#[derive(Debug)]
+pub enum LinkChange {
+    /// A link that doesn't exist yet
+    Add { name: String, cid: cid::Cid },
+    /// An existing link whose content has changed
+    Replace { name: String, old: cid::Cid, new: cid::Cid },
+    /// An existing directory that has changes somewhere inside of it
+    Patch { name: String, plan: PatchPlan },
+}
+
+/// The list of changes needed to make an IPFS directory object match a local directory
+///
+/// Creating a plan adds the new files to the local IPFS repo (so that we know their CIDs), but doesn't modify any
+/// directory objects.  Use [`apply_patch`] to do that.
+#[derive(Debug)]
+pub struct PatchPlan {
+    pub root: cid::Cid,
+    pub changes: Vec<LinkChange>,
+}
+
+impl PatchPlan {
+    /// Returns the number of links that will be added or replaced (at any depth)
+    pub fn num_changes(&self) -> usize {
+        self.changes
+            .iter()
+            .map(|c| match c {
+                LinkChange::Add { .. } | LinkChange::Replace { .. } => 1,
+                LinkChange::Patch { plan, .. } => plan.num_changes(),
+            })
+            .sum()
+    }
+
+    /// Returns a human readable list of every changed link, one per line
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        self.summarize_into("", &mut out);
+        out
+    }
+
+    /// The names of the links in the root that would be added or replaced, or have something changed inside of them
+    pub fn changed_names(&self) -> Vec<String> {
+        self.changes
+            .iter()
+            .map(|c| match c {
+                LinkChange::Add { name, .. } | LinkChange::Replace { name, .. } | LinkChange::Patch { name, .. } => {
+                    name.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Every link that would be added or replaced, at any depth
+    pub fn changed_links(&self) -> Vec<ChangedLink> {
+        let mut out = Vec::new();
+        self.changed_links_into("", &mut out);
+        out
+    }
+
+    fn changed_links_into(&self, prefix: &str, out: &mut Vec<ChangedLink>) {
+        for change in &self.changes {
+            match change {
+                LinkChange::Add { name, cid } => out.push(ChangedLink {
+                    path: format!("{}{}", prefix, name),
+                    old: None,
+                    new: cid.to_string(),
+                }),
+                LinkChange::Replace { name, old, new } => out.push(ChangedLink {
+                    path: format!("{}{}", prefix, name),
+                    old: Some(old.to_string()),
+                    new: new.to_string(),
+                }),
+                LinkChange::Patch { name, plan } => plan.changed_links_into(&format!("{}{}/", prefix, name), out),
+            }
+        }
+    }
+
+    fn summarize_into(&self, prefix: &str, out: &mut String) {
+        for change in &self.changes {
+            match change {
+                LinkChange::Add { name, cid } => out.push_str(&format!("  add     {}{} ({})\n", prefix, name, cid)),
+                LinkChange::Replace { name, new, .. } => {
+                    out.push_str(&format!("  replace {}{} ({})\n", prefix, name, new))
+                }
+                LinkChange::Patch { name, plan } => plan.summarize_into(&format!("{}{}/", prefix, name), out),
+            }
+        }
+    }
+}
+
+/// A link that a [`PatchPlan`] adds or replaces
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangedLink {
+    /// `/`-separated, from the root
+    pub path: String,
+    /// `None` if the link is new
+    pub old: Option<String>,
+    pub new: String,
+}
+
+/// What `drift-check --json` writes
+#[derive(Serialize, Debug)]
+pub struct DriftReport {
+    pub root: String,
+    /// The folder of the root that was checked, with `--path`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Whether anything would change; the same as `changes` not being empty
+    pub drifted: bool,
+    /// Whether the snapshot of the season's JSON in `source/` differs, that is the JSON was edited since (see
+    /// [`crate::source`])
+    pub source_edited: bool,
+    pub changes: Vec<ChangedLink>,
+}
+
+/// Patches the IPFS object `root_hash` so that it matches `root_dir`, returning the new root
+///
+/// This is [`check_root`] (which has to pass, unless [`RunContext::force_patch`]), then [`plan_patch`] followed by
+/// [`apply_patch`].
+pub fn patch_root_object<P: AsRef<Path>>(
+    ctx: &RunContext, root_hash: &cid::Cid, root_dir: P,
+) -> Result<cid::Cid, CbError> {
+    ctx.stage(Stage::Patch, || {
+        check_root(ctx, root_hash, root_dir.as_ref())?.enforce(ctx.force_patch)?;
+        let plan = plan_patch(ctx, root_hash, root_dir.as_ref())?;
+        apply_patch(ctx, &plan)
+    })
+}
+
+/// How much of a published metadata.json is read for the season's title, which is near the start
+const TITLE_HEAD_BYTES: u64 = 64 * 1024;
+
+/// What [`check_root`] found out about a root, before anything is patched onto it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RootCheck {
+    pub root: cid::Cid,
+    /// The links the root should have (`/`-separated): index.html and the data folders of each season, in the folder
+    /// that has its metadata.json
+    pub expected: Vec<String>,
+    /// Those of `expected` that the root doesn't have
+    pub missing: Vec<String>,
+    /// How much of `expected` the root has to have, from 0 to 1 (see [`RunContext::expected_root_links`])
+    pub min_fraction: f64,
+    /// The seasons in the output, which is one in the output itself, or one in each of its folders like `S01/`
+    pub seasons: Vec<SeasonTitles>,
+    /// The root has no links at all, which is what a new site starts from
+    pub empty: bool,
+}
+
+/// A season's title in the output and in the root, to tell whether the root is for another season
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeasonTitles {
+    /// The season's folder in the output, like `S01/`, or nothing for the output itself
+    pub prefix: String,
+    /// The season's title in its metadata.json in the output
+    pub local: String,
+    /// The season's title in the root's metadata.json in the same folder, if it has one that can be read
+    pub published: Option<String>,
+}
+
+impl RootCheck {
+    /// Adds `path` to the links the root should have, and to the missing ones if it isn't among the `links` of its
+    /// folder in the root
+    fn expect(&mut self, path: String, links: Option<&[IPFSLink]>) {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if !links.is_some_and(|links| links.iter().any(|link| link.name == name)) {
+            self.missing.push(path.clone());
+        }
+        self.expected.push(path);
+    }
+
+    /// How much of `expected` the root has, from 0 to 1
+    pub fn fraction_found(&self) -> f64 {
+        if self.expected.is_empty() {
+            return 1.0;
+        }
+        (self.expected.len() - self.missing.len()) as f64 / self.expected.len() as f64
+    }
+
+    /// Why the root doesn't look like the season's, if it doesn't
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.empty {
+            return problems;
+        }
+        if self.fraction_found() < self.min_fraction {
+            const SHOWN: usize = 10;
+            let mut missing = self.missing[..self.missing.len().min(SHOWN)].join(", ");
+            if self.missing.len() > SHOWN {
+                missing.push_str(&format!(" and {} more", self.missing.len() - SHOWN));
+            }
+            problems.push(format!(
+                "it has {} of the {} links expected, where at least {:.0}% should be there (missing {})",
+                self.expected.len() - self.missing.len(),
+                self.expected.len(),
+                self.min_fraction * 100.0,
+                missing
+            ));
+        }
+        for season in &self.seasons {
+            if let Some(published) = season
+                .published
+                .as_ref()
+                .filter(|published| **published != season.local)
+            {
+                problems.push(format!(
+                    "its {}metadata.json is for the season {:?}, not {:?}",
+                    season.prefix, published, season.local
+                ));
+            }
+        }
+        problems
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problems().is_empty()
+    }
+
+    /// Whether the root looks like the season's, and why not, for the plan
+    pub fn verdict(&self) -> String {
+        let problems = self.problems();
+        if !problems.is_empty() {
+            let mut verdict = format!("{} doesn't look like the season's root:\n", self.root);
+            for problem in &problems {
+                verdict.push_str(&format!("  - {}\n", problem));
+            }
+            return verdict;
+        }
+        if self.empty {
+            return format!(
+                "{} is empty, so there's nothing for the season to be mixed up with\n",
+                self.root
+            );
+        }
+        let titles: Vec<_> = self
+            .seasons
+            .iter()
+            .filter_map(|season| {
+                let published = season.published.as_ref()?;
+                Some(format!("{}metadata.json is for {:?}", season.prefix, published))
+            })
+            .collect();
+        let title = if titles.is_empty() {
+            String::new()
+        } else {
+            format!(", and its {}", titles.join(", "))
+        };
+        format!(
+            "{} looks like the season's root: it has {} of the {} links expected{}\n",
+            self.root,
+            self.expected.len() - self.missing.len(),
+            self.expected.len(),
+            title
+        )
+    }
+
+    /// Fails with [`CbError::WrongRoot`] if the root doesn't look like the season's, unless `force`
+    pub fn enforce(&self, force: bool) -> Result<(), CbError> {
+        let problems = self.problems();
+        if problems.is_empty() || force {
+            return Ok(());
+        }
+        Err(CbError::WrongRoot {
+            root: self.root.to_string(),
+            problems,
+        })
+    }
+}
+
+/// Checks that `root_hash` looks like an earlier version of the season generated into `root_dir`, so that a stale or
+/// unrelated `--hash` isn't patched
+///
+/// The root has to have enough of the links the season's root would have, and its metadata.json has to be for the same
+/// season as the one in `root_dir`.  When `root_dir` holds several seasons, each in a folder of its own with its
+/// metadata.json (like `S01/` and `S02/`), that's checked for each of them in the same folder of the root.  Nothing is
+/// changed, so this is done for a dry run too.
+pub fn check_root(ctx: &RunContext, root_hash: &cid::Cid, root_dir: &Path) -> Result<RootCheck, CbError> {
+    let root_obj = IPFSObject::get(ctx, root_hash)?;
+    let mut check = RootCheck {
+        root: *root_hash,
+        expected: Vec::new(),
+        missing: Vec::new(),
+        min_fraction: ctx.expected_root_links,
+        seasons: Vec::new(),
+        empty: root_obj.links.is_empty(),
+    };
+    let mut folders = Vec::new();
+    for entry in read_dir(root_dir)? {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            if let Ok(season) = crate::load_metadata(&entry.path().join("metadata.json")) {
+                folders.push((entry.file_name().to_string_lossy().to_string(), season));
+            }
+        }
+    }
+
+    // without any season in a folder, the output is a season's even if its metadata.json can't be read
+    match crate::load_metadata(&root_dir.join("metadata.json")) {
+        Ok(season) => check_season(ctx, "", &season, Some(&root_obj.links), &mut check)?,
+        Err(_) if folders.is_empty() => check.expect("index.html".to_string(), Some(&root_obj.links)),
+        Err(_) => {}
+    }
+    for (name, season) in &folders {
+        let folder = match root_obj.links.iter().find(|link| link.name == *name) {
+            Some(link) => Some(IPFSObject::get(ctx, &link.hash)?),
+            None => None,
+        };
+        let links = folder.as_ref().map(|folder| &folder.links[..]);
+        check_season(ctx, &format!("{}/", name), season, links, &mut check)?;
+    }
+    Ok(check)
+}
+
+/// Adds the links that the season in the folder `prefix` should have to `check`, and compares its title with the
+/// root's, given the `links` of that folder in the root (`None` if the root doesn't have it)
+fn check_season(
+    ctx: &RunContext, prefix: &str, season: &crate::types::Season, links: Option<&[IPFSLink]>, check: &mut RootCheck,
+) -> Result<(), CbError> {
+    check.expect(format!("{}index.html", prefix), links);
+    for recording in &season.recordings {
+        check.expect(format!("{}{}", prefix, recording.data_folder), links);
+    }
+    let published = match links.and_then(|links| links.iter().find(|link| link.name == "metadata.json")) {
+        Some(link) => season_title(&cat_head(ctx, &link.hash, TITLE_HEAD_BYTES)?),
+        None => None,
+    };
+    check.seasons.push(SeasonTitles {
+        prefix: prefix.to_string(),
+        local: season.title.clone(),
+        published,
+    });
+    Ok(())
+}
+
+/// The season's title from the start of a metadata.json, which comes before the recordings and their own titles
+fn season_title(head: &[u8]) -> Option<String> {
+    const KEY: &[u8] = b"\"title\"";
+    let at = head.windows(KEY.len()).position(|window| window == KEY)? + KEY.len();
+    // the head can end in the middle of a character, which doesn't matter unless it's in the title
+    let rest = String::from_utf8_lossy(&head[at..]);
+    let value = rest.trim_start().strip_prefix(':')?;
+    serde_json::Deserializer::from_str(value)
+        .into_iter::<String>()
+        .next()?
+        .ok()
+}
+
+/// Which folders of the output `--only` patches
+///
+/// A folder of a recording in the output's metadata.json (its data folder, the folder of its page, or a redirect to
+/// it) is patched when the recording is selected, and every other folder (the styles, the fonts, the snapshot of the
+/// season's JSON...) always is.  A folder that has recordings' data folders in it is looked into.  Without a
+/// metadata.json to tell them apart, only the folders named like a selected recording are patched, and the snapshot of
+/// the season's JSON.
+struct PatchSelection<'a> {
+    selector: &'a Selector,
+    /// Whether each folder of a recording is selected, by its path in the output
+    recordings: Option<BTreeMap<String, bool>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FolderScope {
+    Skipped,
+    Patched,
+    /// Only some of what's in it is patched
+    Partly,
+}
+
+impl<'a> PatchSelection<'a> {
+    fn new(selector: &'a Selector, root_dir: &Path) -> PatchSelection<'a> {
+        let recordings = crate::load_metadata(&root_dir.join("metadata.json"))
+            .ok()
+            .map(|season| {
+                let mut folders = BTreeMap::new();
+                for rec in &season.recordings {
+                    let selected = selector.matches(rec);
+                    folders.insert(rec.data_folder.clone(), selected);
+                    // metadata from before slugs has none
+                    if !rec.slug.is_empty() {
+                        folders.insert(rec.slug.clone(), selected);
+                    }
+                }
+                for (old, new) in &season.redirects {
+                    if let Some(rec) = season.recordings.iter().find(|rec| rec.slug == *new) {
+                        folders.insert(old.clone(), selector.matches(rec));
+                    }
+                }
+                folders
+            });
+        PatchSelection { selector, recordings }
+    }
+
+    /// `path` is the folder's path in the output, separated by `/`
+    fn scope(&self, path: &str) -> FolderScope {
+        let recordings = match &self.recordings {
+            Some(recordings) => recordings,
+            None if path == crate::source::SOURCE_DIR || self.selector.matches_name(path) => {
+                return FolderScope::Patched
+            }
+            None => return FolderScope::Skipped,
+        };
+        match recordings.get(path) {
+            Some(true) => FolderScope::Patched,
+            Some(false) => FolderScope::Skipped,
+            None if Self::inside(recordings.keys(), path).next().is_some() => FolderScope::Partly,
+            None => FolderScope::Patched,
+        }
+    }
+
+    /// Whether the folder at `path` has a selected recording in it (or is one), which `--only` has to find
+    fn has_selected(&self, path: &str) -> bool {
+        match &self.recordings {
+            Some(recordings) => {
+                recordings.get(path) == Some(&true)
+                    || Self::inside(
+                        recordings.iter().filter(|(_, selected)| **selected).map(|(k, _)| k),
+                        path,
+                    )
+                    .next()
+                    .is_some()
+            }
+            None => path != crate::source::SOURCE_DIR && self.selector.matches_name(path),
+        }
+    }
+
+    fn inside<'b>(folders: impl Iterator<Item = &'b String>, path: &str) -> impl Iterator<Item = &'b String> {
+        let prefix = format!("{}/", path);
+        folders.filter(move |folder| folder.starts_with(&prefix))
+    }
+}
+
+/// Files of ours in the output that aren't part of the site (and the `.1`s that broken ones are moved to), and the
+/// temporary files that an interrupted run leaves behind (see [`crate::is_temp_file`])
+fn is_bookkeeping(name: &std::ffi::OsStr) -> bool {
+    let archived = |of| crate::state_store::is_archive_of(name, of);
+    archived(crate::media_cache::CACHE_FILE)
+        || archived(crate::provenance::PROVENANCE_FILE)
+        || name == crate::media_cache::CACHE_FILE
+        || name == crate::hasher::HASH_CACHE_FILE
+        || name == crate::manifest::MANIFEST_FILE
+        || name == crate::provenance::PROVENANCE_FILE
+        || name == crate::quarantine::QUARANTINE_FILE
+        || name == crate::lock::DATA_LOCK
+        || name == crate::lock::OUTPUT_LOCK
+        || crate::is_temp_file(name)
+}
+
+/// Works out which links need to change so that `root_hash` matches `root_dir`
+///
+/// With `--only`, the files directly in `root_dir` are still patched, but of the recordings' folders only those of the
+/// selected recordings are looked at (see [`PatchSelection`]).
+pub fn plan_patch(ctx: &RunContext, root_hash: &cid::Cid, root_dir: &Path) -> Result<PatchPlan, CbError> {
+    let selection = PatchSelection::new(&ctx.only, root_dir);
+    let mut total = 0;
+    let mut selected_dirs = 0;
+    for entry in read_dir(root_dir)? {
+        let entry = entry?;
+        total += 1;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() && selection.scope(&name) != FolderScope::Skipped {
+            if selection.has_selected(&name) {
+                selected_dirs += 1;
+            }
+            total += count_entries(&entry.path())?;
+        }
+    }
+    if !ctx.only.is_all() && selected_dirs == 0 {
+        return Err(CbError::NothingSelected {
+            patterns: ctx.only.describe(),
+            what: format!("folders in {}", root_dir.display()),
+        });
+    }
+
+    let mut progress = PatchProgress {
+        index: 0,
+        total,
+        warnings: 0,
+    };
+    let plan = plan_object(ctx, root_hash, root_dir, Some((&selection, "")), false, &mut progress)?;
+    progress::emit(ProgressEvent::Summary {
+        stage: Stage::Patch,
+        processed: progress.index,
+        errors: 0,
+        warnings: progress.warnings,
+    });
+    Ok(plan)
+}
+
+/// Works out which links of `root_hash` differ from `generated`, a folder with just the generated files in it
+///
+/// This is [`plan_patch`] without touching the local IPFS repo: files are only hashed.  Links that aren't in
+/// `generated` (the audio) aren't looked at, and neither is audio that's already linked, at any depth.  The pages and
+/// metadata say which build of cb_processor made them (see [`crate::GENERATOR`]), so a file whose hash differs is
+/// compared with the published one without that before it counts as changed (see [`same_but_generator`]).
+pub fn plan_drift(ctx: &RunContext, root_hash: &cid::Cid, generated: &Path) -> Result<PatchPlan, CbError> {
+    let mut progress = PatchProgress {
+        index: 0,
+        total: count_entries(generated)?,
+        warnings: 0,
+    };
+    let plan = plan_object(ctx, root_hash, generated, None, true, &mut progress)?;
+    progress::emit(ProgressEvent::Summary {
+        stage: Stage::Patch,
+        processed: progress.index,
+        errors: 0,
+        warnings: progress.warnings,
+    });
+    Ok(plan)
+}
+
+/// True for the flacs, oggs, mp3s and opus files, which are added once and then left alone
+fn is_audio(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        IntoIterator::into_iter(["flac", "ogg", "mp3", "opus"]).any(|audio| ext.eq_ignore_ascii_case(audio))
+    })
+}
+
+/// `selection` comes with the path of `root_dir` in the output.  With `only_hash`, nothing is added to the local IPFS
+/// repo, and links with nothing local are expected (the audio isn't generated, so it's never there)
+fn plan_object(
+    ctx: &RunContext, root_hash: &cid::Cid, root_dir: &Path, selection: Option<(&PatchSelection, &str)>,
+    only_hash: bool, progress: &mut PatchProgress,
+) -> Result<PatchPlan, CbError> {
     // let patchable = vec!["ToS.txt", "index.html", "style.css", "metadata.json", "css", "webfonst"];
-    let mut root_obj = IPFSObject::get(root_hash)?;
+    let root_obj = IPFSObject::get(ctx, root_hash)?;
+    let mut changes = Vec::new();
 
-    for local_link in root_dir.read_dir()? {
+    for local_link in read_dir(root_dir)? {
         let local_link = local_link?;
         let local_link_path = local_link.path();
 
+        // the media info cache lives in the data dir, which is often also the output dir, and the manifest is only
+        // for clean
+        if is_bookkeeping(&local_link.file_name()) {
+            continue;
+        }
+
+        // the folders that are only partly patched are looked into with the same selection
+        let mut partly = None;
+        if let Some((selection, prefix)) = selection {
+            if local_link_path.is_dir() {
+                let name = local_link.file_name().to_string_lossy().to_string();
+                let path = if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                };
+                match selection.scope(&path) {
+                    FolderScope::Skipped => continue,
+                    FolderScope::Partly => partly = Some((selection, path)),
+                    FolderScope::Patched => {}
+                }
+            }
+        }
+
+        progress.index += 1;
+        progress::emit(ProgressEvent::ItemProcessed {
+            stage: Stage::Patch,
+            item: local_link_path.display().to_string(),
+            index: progress.index,
+            total: progress.total,
+        });
+
         // find the corresponding link in the IPFS structure (if it exists)
         let maybe_link = root_obj
             .links
             .iter()
             .find(|l| local_link.file_name() == AsRef::<OsStr>::as_ref(&l.name));
-        if let Some(ext) = local_link_path.extension() {
-            if (ext == "ogg" || ext == "flac") && maybe_link.is_some() {
-                // we don't patch ogg/flac audio files if they already exist in IPFS
-                continue;
-            };
+        if is_audio(&local_link_path) && maybe_link.is_some() {
+            // we don't patch audio files if they already exist in IPFS, however deep in the recording's folder they are
+            continue;
         }
 
         if local_link_path.is_file() {
             if let Some(link) = maybe_link {
-                let new_cid = ipfs_add(&local_link_path, false)?;
-                if new_cid != link.hash {
-                    println!(
+                let new_cid = ipfs_add(ctx, &local_link_path, false, only_hash)?;
+                if new_cid != link.hash
+                    && !(only_hash && same_but_generator(ctx, &root_obj, &local_link_path, &link.hash)?)
+                {
+                    say!(
                         "Patching {} with {} ({})",
                         link.name,
                         local_link_path.display(),
                         new_cid
                     );
-                    root_obj = root_obj.add_link(&link.name, &new_cid)?;
+                    changes.push(LinkChange::Replace {
+                        name: link.name.clone(),
+                        old: link.hash,
+                        new: new_cid,
+                    });
                 }
             } else {
-                let new_cid = ipfs_add(&local_link_path, true)?;
+                let new_cid = ipfs_add(ctx, &local_link_path, true, only_hash)?;
                 let new_link_name = local_link.file_name();
-                root_obj = root_obj.add_link(&new_link_name.to_string_lossy(), &new_cid)?;
-                println!("Added new link to {:?} ({})", new_link_name, new_cid);
+                say!("Adding new link to {:?} ({})", new_link_name, new_cid);
+                changes.push(LinkChange::Add {
+                    name: new_link_name.to_string_lossy().to_string(),
+                    cid: new_cid,
+                });
             }
         } else if local_link_path.is_dir() {
             if let Some(link) = maybe_link {
                 // link already exists, so recurse
-                let new_cid = patch_root_object(&link.hash, &local_link_path)?;
-                if new_cid != link.hash {
-                    root_obj = root_obj.add_link(&link.name, &new_cid)?;
+                let selection = partly.as_ref().map(|(selection, path)| (*selection, path.as_str()));
+                let plan = plan_object(ctx, &link.hash, &local_link_path, selection, only_hash, progress)?;
+                if !plan.changes.is_empty() {
+                    changes.push(LinkChange::Patch {
+                        name: link.name.clone(),
+                        plan,
+                    });
                 }
             } else {
-                let new_cid = ipfs_add(&local_link_path, true)?;
+                let new_cid = ipfs_add(ctx, &local_link_path, true, only_hash)?;
                 let new_link_name = local_link.file_name();
-                root_obj = root_obj.add_link(&new_link_name.to_string_lossy(), &new_cid)?;
-                println!("Added new link to {:?} ({})", new_link_name, new_cid);
+                say!("Adding new link to {:?} ({})", new_link_name, new_cid);
+                changes.push(LinkChange::Add {
+                    name: new_link_name.to_string_lossy().to_string(),
+                    cid: new_cid,
+                });
             }
         }
     }
@@ -146,15 +772,470 @@ pub fn patch_root_object<P: AsRef<Path>>(root_hash: &cid::Cid, root_dir: P) -> a
     // now look for links the the IPFS object that don't exist locally and print a warning about them
     for link in &root_obj.links {
         let maybe_local = root_dir.join(&link.name);
-        if !maybe_local.exists() {
-            println!(
+        if !maybe_local.exists() && !only_hash {
+            say!(
                 "Warning: {} exists in IPFS, but not on the filesystem {:?}",
-                link.name, maybe_local
+                link.name,
+                maybe_local
             );
+            progress.warnings += 1;
+            progress::emit(ProgressEvent::Warning {
+                stage: Stage::Patch,
+                message: format!("{} exists in IPFS, but not on the filesystem", maybe_local.display()),
+            });
+        }
+    }
+
+    Ok(PatchPlan {
+        root: *root_hash,
+        changes,
+    })
+}
+
+/// Whether the local file only differs from the published `linked` in the generator it names
+///
+/// Only the generated text files name their generator.  A compressed copy of one (see [`crate::precompress`]) is
+/// judged by the file it's a copy of, which is compared instead if `root_obj` has a link for it.
+fn same_but_generator(
+    ctx: &RunContext, root_obj: &IPFSObject, local: &Path, linked: &cid::Cid,
+) -> Result<bool, CbError> {
+    let compressed = crate::precompress::Encoding::ALL
+        .iter()
+        .any(|encoding| local.extension().is_some_and(|ext| ext == encoding.extension()));
+    if compressed {
+        let original = local.with_extension("");
+        let link = original.file_name().and_then(|name| {
+            root_obj
+                .links
+                .iter()
+                .find(|link| AsRef::<OsStr>::as_ref(&link.name) == name)
+        });
+        return match link {
+            Some(link) if original.is_file() => same_but_generator(ctx, root_obj, &original, &link.hash),
+            _ => Ok(false),
+        };
+    }
+    let names_generator = local
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("json"));
+    if !names_generator {
+        return Ok(false);
+    }
+    let contents = std::fs::read(local).map_err(|e| CbError::io(format!("Failed to read {}", local.display()), e))?;
+    let published = cat(ctx, linked)?;
+    Ok(without_generator(&contents) == without_generator(&published))
+}
+
+/// `contents` with the generator left out of the page's "Generated by" comment and metadata's `generator` field
+fn without_generator(contents: &[u8]) -> Vec<u8> {
+    let comment = regex::bytes::Regex::new(r"<!-- Generated by [^>]*-->").unwrap();
+    let field = regex::bytes::Regex::new(r#""generator"\s*:\s*"(?:[^"\\]|\\.)*""#).unwrap();
+    let contents = comment.replace(contents, &b"<!-- Generated by -->"[..]);
+    field.replace(&contents, &br#""generator":"""#[..]).into_owned()
+}
+
+/// Applies the changes in a plan, returning the CID of the new root object
+pub fn apply_patch(ctx: &RunContext, plan: &PatchPlan) -> Result<cid::Cid, CbError> {
+    let mut root_obj = IPFSObject::get(ctx, &plan.root)?;
+
+    for change in &plan.changes {
+        root_obj = match change {
+            LinkChange::Add { name, cid } => root_obj.add_link(ctx, name, cid)?,
+            LinkChange::Replace { name, new, .. } => root_obj.add_link(ctx, name, new)?,
+            LinkChange::Patch { name, plan } => {
+                let new_cid = apply_patch(ctx, plan)?;
+                root_obj.add_link(ctx, name, &new_cid)?
+            }
+        };
+    }
+
+    Ok(*root_obj.cid())
+}
+
+/// Something in a patched root that doesn't match the local output
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Discrepancy {
+    /// In the output, but not in IPFS
+    Missing { path: String },
+    /// In IPFS, but neither in the output nor one of the legacy links
+    Unexpected { path: String },
+    /// A file whose link is the wrong size for the local file
+    WrongSize { path: String, local: u64, linked: u64 },
+}
+
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Discrepancy::Missing { path } => write!(f, "{} is missing from IPFS", path),
+            Discrepancy::Unexpected { path } => write!(f, "{} is in IPFS, but not in the output", path),
+            Discrepancy::WrongSize { path, local, linked } => write!(
+                f,
+                "{} is {} bytes, but its link in IPFS is {} bytes",
+                path, local, linked
+            ),
+        }
+    }
+}
+
+/// Whether a link's size fits a file of `local` bytes
+///
+/// Link sizes are cumulative, so they include the UnixFS and block overhead on top of the file's contents.  That's a
+/// few bytes per 256KiB chunk, plus the chunk list, so anything more than a small fraction over is wrong.
+fn link_size_fits(local: u64, linked: u64) -> bool {
+    linked >= local && linked <= local + local / 100 + 4096
+}
+
+/// Checks that the tree under `root` matches `root_dir`, after a patch
+///
+/// Every local file needs a link, except for the ones that patching leaves out (the media info cache, and with `--only`
+/// the folders that weren't selected).  Oggs and flacs that were already in IPFS aren't replaced by patching, so only
+/// their presence is checked.  Links without a local file are only allowed if they're in `legacy_links`.
+pub fn verify_patch(ctx: &RunContext, root: &cid::Cid, root_dir: &Path) -> Result<Vec<Discrepancy>, CbError> {
+    let mut found = Vec::new();
+    verify_object(ctx, root, root_dir, "", Some(&ctx.only), &mut found)?;
+    for discrepancy in &found {
+        progress::emit(ProgressEvent::Error {
+            stage: Stage::Verify,
+            message: discrepancy.to_string(),
+        });
+    }
+    progress::emit(ProgressEvent::Summary {
+        stage: Stage::Verify,
+        processed: 1,
+        errors: found.len(),
+        warnings: 0,
+    });
+    Ok(found)
+}
+
+fn verify_object(
+    ctx: &RunContext, hash: &cid::Cid, dir: &Path, prefix: &str, selector: Option<&Selector>,
+    found: &mut Vec<Discrepancy>,
+) -> Result<(), CbError> {
+    let object = IPFSObject::get(ctx, hash)?;
+    let mut local_names = Vec::new();
+
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if is_bookkeeping(&entry.file_name()) {
+            continue;
+        }
+        local_names.push(name.clone());
+        if let Some(selector) = selector {
+            if path.is_dir() && !selector.matches_name(&name) {
+                continue;
+            }
+        }
+
+        let rel = format!("{}{}", prefix, name);
+        let link = match object.links.iter().find(|l| l.name == name) {
+            Some(link) => link,
+            None => {
+                found.push(Discrepancy::Missing { path: rel });
+                continue;
+            }
+        };
+        if path.is_dir() {
+            verify_object(ctx, &link.hash, &path, &format!("{}/", rel), None, found)?;
+            continue;
+        }
+        let kept_audio = is_audio(&path);
+        let local = entry
+            .metadata()
+            .map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?
+            .len();
+        if !kept_audio && !link_size_fits(local, link.size as u64) {
+            found.push(Discrepancy::WrongSize {
+                path: rel,
+                local,
+                linked: link.size as u64,
+            });
+        }
+    }
+
+    for link in &object.links {
+        let rel = format!("{}{}", prefix, link.name);
+        if !local_names.contains(&link.name) && !ctx.legacy_links.contains(&rel) {
+            found.push(Discrepancy::Unexpected { path: rel });
+        }
+    }
+    Ok(())
+}
+
+/// The multicodec of raw blocks, which hold part of a file and have no links
+const RAW_CODEC: u64 = 0x55;
+
+/// Walks everything under `root` in the local IPFS repo only, and returns the paths whose blocks aren't there
+///
+/// Unlike [`verify_patch`], nothing is compared with the output: this is for old roots, which can have been garbage
+/// collected since they were published.  The path of a missing root is its CID.  A block that's linked more than once
+/// (like an unchanged file in two folders) is only looked up once.
+pub fn missing_blocks(ctx: &RunContext, root: &cid::Cid) -> Result<Vec<String>, CbError> {
+    let mut offline = ctx.clone();
+    offline.ipfs_offline = true;
+    let mut missing = std::collections::BTreeSet::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut queue = vec![(*root, root.to_string())];
+    while let Some((hash, path)) = queue.pop() {
+        if !seen.insert(hash.to_string()) {
+            continue;
+        }
+        let found = if hash.codec() == RAW_CODEC {
+            has_block(&offline, &hash).map(|_| Vec::new())
+        } else {
+            IPFSObject::get(&offline, &hash).map(|object| object.links)
+        };
+        match found {
+            Ok(links) => {
+                for link in links {
+                    // the chunks of a file have no names, and are part of the file's path
+                    let path = if link.name.is_empty() {
+                        path.clone()
+                    } else {
+                        format!("{}/{}", path, link.name)
+                    };
+                    queue.push((link.hash, path));
+                }
+            }
+            Err(CbError::IpfsDaemon { .. }) => {
+                missing.insert(path);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(missing.into_iter().collect())
+}
+
+fn has_block(ctx: &RunContext, hash: &cid::Cid) -> Result<(), CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("block")
+        .arg("stat")
+        .arg(format!("{}", hash))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("block stat", &output)
+}
+
+/// Pins `cid` (and everything under it) in the local IPFS repo, so that garbage collection keeps it
+pub fn pin(ctx: &RunContext, cid: &cid::Cid) -> Result<(), CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("pin")
+        .arg("add")
+        .arg("--progress=false")
+        .arg(format!("/ipfs/{}", cid))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("pin add", &output)
+}
+
+/// Unpins `cid`, so that garbage collection can remove whatever nothing else that's pinned needs
+pub fn unpin(ctx: &RunContext, cid: &cid::Cid) -> Result<(), CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("pin")
+        .arg("rm")
+        .arg(format!("/ipfs/{}", cid))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("pin rm", &output)
+}
+
+/// Adds a folder to the local IPFS repo as a root of its own (without pinning it), returning its CID
+pub fn add_folder(ctx: &RunContext, path: &Path) -> Result<cid::Cid, CbError> {
+    ipfs_add(ctx, path, true, false)
+}
+
+/// The file `cid`
+fn cat(ctx: &RunContext, cid: &cid::Cid) -> Result<Vec<u8>, CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("cat")
+        .arg(format!("/ipfs/{}", cid))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("cat", &output)?;
+    Ok(output.stdout)
+}
+
+/// The CID of the file or folder at `path` (`/`-separated) under `root`, if there's anything there
+pub fn link_at(ctx: &RunContext, root: &cid::Cid, path: &str) -> Result<Option<cid::Cid>, CbError> {
+    let mut cid = *root;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        match IPFSObject::get(ctx, &cid)?
+            .links
+            .into_iter()
+            .find(|link| link.name == name)
+        {
+            Some(link) => cid = link.hash,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(cid))
+}
+
+/// The first `length` bytes of the file `cid` (or all of it, if it's smaller)
+pub fn cat_head(ctx: &RunContext, cid: &cid::Cid, length: u64) -> Result<Vec<u8>, CbError> {
+    let output = ctx
+        .ipfs_command()
+        .arg("cat")
+        .arg(format!("--length={}", length))
+        .arg(format!("/ipfs/{}", cid))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("cat", &output)?;
+    Ok(output.stdout)
+}
+
+/// Points an IPNS name at `cid`
+///
+/// `key` is the name of the IPNS key in the local IPFS node ("self" is the node's own key)
+pub fn publish_name(ctx: &RunContext, cid: &cid::Cid, key: &str) -> Result<(), CbError> {
+    ctx.require_online("Publishing to IPNS")?;
+    let output = ctx
+        .ipfs_command()
+        .arg("name")
+        .arg("publish")
+        .arg(format!("--key={}", key))
+        .arg(format!("/ipfs/{}", cid))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("name publish", &output)?;
+    say_part!("{}", String::from_utf8_lossy(&output.stdout));
+
+    Ok(())
+}
+
+/// The size of everything under `cid`, as it would be downloaded (blocks and all)
+pub fn dag_size(ctx: &RunContext, cid: &cid::Cid) -> Result<u64, CbError> {
+    #[derive(Deserialize)]
+    struct FilesStat {
+        #[serde(rename = "CumulativeSize")]
+        cumulative_size: u64,
+    }
+
+    let output = ctx
+        .ipfs_command()
+        .arg("files")
+        .arg("stat")
+        .arg("--enc=json")
+        .arg(format!("/ipfs/{}", cid))
+        .output()
+        .map_err(|e| CbError::tool("ipfs", e))?;
+    CbError::check_ipfs("files stat", &output)?;
+    let stat: FilesStat = serde_json::from_slice(&output.stdout)
+        .map_err(|e| CbError::parse(format!("Unexpected output from ipfs files stat {}", cid), e))?;
+    Ok(stat.cumulative_size)
+}
+
+/// The size of a root, and of each link in it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DagSizes {
+    pub root: String,
+    pub total: u64,
+    /// Every link directly in the root (mostly recording folders), sorted by name
+    pub links: Vec<LinkSize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkSize {
+    pub name: String,
+    pub bytes: u64,
+}
+
+impl DagSizes {
+    /// Sizes of `root` and its links
+    ///
+    /// The sizes of the links come from the root object itself (they're cumulative already), so this only asks ipfs
+    /// for the root object and its total size.
+    pub fn get(ctx: &RunContext, root: &cid::Cid) -> Result<DagSizes, CbError> {
+        let object = IPFSObject::get(ctx, root)?;
+        let mut links: Vec<LinkSize> = object
+            .links
+            .iter()
+            .map(|l| LinkSize {
+                name: l.name.clone(),
+                bytes: l.size as u64,
+            })
+            .collect();
+        links.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(DagSizes {
+            root: root.to_string(),
+            total: dag_size(ctx, root)?,
+            links,
+        })
+    }
+
+    /// A table of the sizes, with how much each changed since `previous`
+    pub fn human(&self, previous: Option<&DagSizes>) -> String {
+        let old_size = |name: &str| {
+            previous
+                .and_then(|p| p.links.iter().find(|l| l.name == name))
+                .map(|l| l.bytes)
+        };
+        let width = self.links.iter().map(|l| l.name.len()).max().unwrap_or(0).max(5);
+        let mut out = String::new();
+        for link in &self.links {
+            out.push_str(&format!(
+                "  {:<width$} {:>10}",
+                link.name,
+                megabytes(link.bytes),
+                width = width
+            ));
+            match (previous, old_size(&link.name)) {
+                (Some(_), None) => out.push_str("  (new)"),
+                (Some(_), Some(old)) if old != link.bytes => {
+                    out.push_str(&format!("  ({})", difference(old, link.bytes)))
+                }
+                _ => {}
+            }
+            out.push('\n');
+        }
+        out.push_str(&format!(
+            "  {:<width$} {:>10}",
+            "total",
+            megabytes(self.total),
+            width = width
+        ));
+        if let Some(previous) = previous {
+            if previous.total != self.total {
+                out.push_str(&format!("  ({})", difference(previous.total, self.total)));
+            }
         }
+        out.push('\n');
+        out
     }
+}
 
-    Ok(root_obj.cid().clone())
+fn megabytes(bytes: u64) -> String {
+    format!("{}MB", crate::one_decimal(bytes, 1024 * 1024))
+}
+
+fn difference(old: u64, new: u64) -> String {
+    if new >= old {
+        format!("+{}", megabytes(new - old))
+    } else {
+        format!("-{}", megabytes(old - new))
+    }
+}
+
+/// What `--patch-report` writes
+#[derive(Serialize, Debug)]
+pub struct PatchReport {
+    pub old_root: String,
+    pub new_root: String,
+    /// Number of links that were added or replaced, at any depth
+    pub changes: usize,
+    /// The links in the root that the patch touched (see [`PatchPlan::changed_names`]), which `--prime-first` primes
+    /// before the rest
+    #[serde(default)]
+    pub changed: Vec<String>,
+    pub old_sizes: DagSizes,
+    pub new_sizes: DagSizes,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -189,45 +1270,303 @@ mod serde_cid {
     }
 }
 
-pub fn prime_public_gateways(root_hash: &cid::Cid) -> anyhow::Result<()> {
-    let gateways = vec![
-        "https://{base32}.ipfs.dweb.link",
-        "https://ipfs.io/ipfs/{v0}",
-        "https://ipfs.overpi.com/ipfs/{v0}",
-        // "https://{base32}.ipfs.ipfs.stibarc.com",
-        "https://{base32}.ipfs.cf-ipfs.com",
-        "https://{base32}.ipfs.astyanax.io",
-        "https://gateway.pinata.cloud/ipfs/{base32}"
-    ];
-
-    let b32 = cid::Cid::new_v1(root_hash.codec(), root_hash.hash().to_owned());
-    let v0 = cid::Cid::new_v0(root_hash.hash().to_owned())?;
-
-    let client = reqwest::blocking::ClientBuilder::new()
-        .timeout(Duration::from_secs(120))
-        .build().unwrap();
-
-    let ipfs_root = IPFSObject::get(&root_hash)?;
-
-    for gw in gateways {
-        let gw = gw
-            .replace("{base32}", &format!("{}", b32))
-            .replace("{v0}", &format!("{}", v0));
-        let base_url = reqwest::Url::parse(&gw)?;
-        print!("Priming {}... ", base_url);
-        let resp = client.get(base_url.clone()).send()?;
-        println!(" {}", resp.status());
-
-        for link in &ipfs_root.links {
-            let url = reqwest::Url::parse(&format!("{}/{}", gw, link.name))?;
-            print!("  {}...", url);
-            let resp = client.get(url.clone()).send()?;
-            println!(" {}", resp.status());
+/// What a run of `--prime` did, as written by `--prime-report`
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrimeReport {
+    /// URLs that a gateway served this run
+    pub primed: usize,
+    /// URLs that an earlier run already primed
+    pub skipped: usize,
+    pub failed: usize,
+    /// Requests that weren't made because the `--budget` ran out, for the next run to make
+    pub over_budget: usize,
+    /// Each link of the root (the root itself is ""), in the order they were primed in
+    pub links: Vec<LinkCoverage>,
+    /// The gateways that weren't primed because they're unhealthy
+    pub unhealthy_skipped: Vec<String>,
+    /// How each gateway has been doing, including this run, which is what the unhealthy ones were picked by
+    pub health: Vec<HealthRow>,
+}
+
+/// How many of the gateways have a link of the root, in a [`PrimeReport`]
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkCoverage {
+    pub name: String,
+    /// Primed on every gateway before the rest: the root, and what the latest patch changed
+    pub first: bool,
+    /// Gateways that served it, this run or an earlier one
+    pub primed: usize,
+    /// Gateways that it wasn't asked from because the budget ran out
+    pub over_budget: usize,
+}
+
+/// The new root and what changed in it, from a `--patch-report`
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PatchedLinks {
+    pub new_root: String,
+    /// Missing from reports written before this was recorded
+    #[serde(default)]
+    pub changed: Vec<String>,
+}
+
+impl PatchedLinks {
+    /// Reads the `--patch-report` at `path`
+    pub fn read(path: &Path) -> Result<PatchedLinks, CbError> {
+        let bytes = std::fs::read(path).map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| CbError::parse(format!("Unexpected contents in {}", path.display()), e))
+    }
+}
+
+/// Requests the root and each of its links from every public gateway, so that they start caching them
+///
+/// The root and the links in `options.first` go first, on every gateway, then the rest of the links, until the
+/// budget (if any) runs out.  What each gateway served is kept in `ctx.prime_state` as it goes, and skipped by the
+/// next run for the same root (see [`PrimeMode`](crate::gateway::PrimeMode)).
+pub fn prime_public_gateways(
+    ctx: &RunContext, root_hash: &cid::Cid, options: &crate::gateway::PrimeOptions,
+) -> Result<PrimeReport, CbError> {
+    ctx.require_online("Priming gateways")?;
+    ctx.stage(Stage::Prime, || prime_gateways(ctx, root_hash, options))
+}
+
+fn prime_gateways(
+    ctx: &RunContext, root_hash: &cid::Cid, options: &crate::gateway::PrimeOptions,
+) -> Result<PrimeReport, CbError> {
+    let configured = crate::gateway::gateways(&ctx.gateways);
+    let gateways = configured
+        .iter()
+        .map(|gateway| {
+            Ok((
+                gateway.root_url(root_hash)?,
+                gateway.header_map()?,
+                gateway.describe_headers(),
+                gateway.template.as_str(),
+            ))
+        })
+        .collect::<Result<Vec<_>, CbError>>()?;
+    let client = crate::gateway::client(ctx)?;
+    let mut state = crate::gateway::PrimeState::load(&ctx.prime_state, root_hash, options.mode)?;
+
+    let ipfs_root = IPFSObject::get(ctx, root_hash)?;
+    // the root itself and what the latest patch changed, then the rest of the links
+    let is_first = |name: &str| options.first.iter().any(|first| first == name);
+    let names: Vec<(&str, bool)> = std::iter::once(("", true))
+        .chain(
+            ipfs_root
+                .links
+                .iter()
+                .filter(|link| is_first(&link.name))
+                .map(|link| (link.name.as_str(), true)),
+        )
+        .chain(
+            ipfs_root
+                .links
+                .iter()
+                .filter(|link| !is_first(&link.name))
+                .map(|link| (link.name.as_str(), false)),
+        )
+        .collect();
+
+    let mut warnings = 0;
+    let mut unhealthy_skipped = Vec::new();
+    let gateways: Vec<_> = gateways
+        .into_iter()
+        .filter(|(_, _, _, template)| {
+            if !state.is_unhealthy(template, ctx.unhealthy_after) {
+                return true;
+            }
+            if ctx.include_unhealthy {
+                say!(
+                    "{}: priming {} anyway, although it failed every request in its last {} runs",
+                    "WARNING".yellow(),
+                    template,
+                    ctx.unhealthy_after
+                );
+                return true;
+            }
+            let message = format!(
+                "skipping {}, which failed every request in its last {} runs.  Consider removing it (see \
+                 `gateways status`), or prime it anyway with --include-unhealthy",
+                template, ctx.unhealthy_after
+            );
+            say!("{}: {}", "WARNING".yellow(), message);
+            progress::emit(ProgressEvent::Warning {
+                stage: Stage::Prime,
+                message,
+            });
+            warnings += 1;
+            unhealthy_skipped.push(template.to_string());
+            false
+        })
+        .collect();
+
+    for (gw, _, _, _) in &gateways {
+        if names.iter().all(|(name, _)| state.is_primed(gw, name)) {
+            say!("Skipping {}, which is already primed", gw);
+        }
+    }
+    let skipped: usize = gateways
+        .iter()
+        .map(|(gw, _, _, _)| names.iter().filter(|(name, _)| state.is_primed(gw, name)).count())
+        .sum();
+    let urls: Vec<&str> = gateways.iter().map(|(gw, _, _, _)| gw.as_str()).collect();
+    let queue = state.queue(&urls, &names);
+    let total = queue.len();
+    let mut index = 0;
+    let mut primed = 0;
+    let mut failed = 0;
+    let mut runs = vec![RunHealth::default(); gateways.len()];
+    let mut given_up = vec![false; gateways.len()];
+    let mut over_budget = vec![0; names.len()];
+    let started = Instant::now();
+    let mut current = None;
+
+    for (g, n) in queue {
+        let (gw, headers, with_headers, template) = &gateways[g];
+        let name = names[n].0;
+        if given_up[g] {
+            continue;
+        }
+        if let Some(budget) = &options.budget {
+            if budget.is_spent(index, started.elapsed()) {
+                over_budget[n] += 1;
+                continue;
+            }
+        }
+        let url = if name.is_empty() {
+            reqwest::Url::parse(gw).map_err(|e| CbError::parse(format!("Invalid gateway URL {}", gw), e))?
+        } else {
+            crate::gateway::link_url(gw, name)?
+        };
+        if name.is_empty() {
+            say_part!("Priming {}{}... ", url, with_headers);
+        } else {
+            if current != Some(g) {
+                say!("Priming {}{}", gw, with_headers);
+            }
+            say_part!("  {}...", url);
+        }
+        current = Some(g);
+        index += 1;
+        let resp = client.get(url.clone()).headers(headers.clone()).send();
+        progress::emit(ProgressEvent::ItemProcessed {
+            stage: Stage::Prime,
+            item: url.to_string(),
+            index,
+            total,
+        });
+        let unreachable = resp.is_err();
+        let problem = match resp {
+            Ok(resp) if resp.status().is_success() => {
+                say!(" {}", resp.status());
+                None
+            }
+            Ok(resp) => {
+                say!(" {}", resp.status());
+                Some(format!("{} returned {}", url, resp.status()))
+            }
+            Err(e) => {
+                say!(" {}", e);
+                Some(format!("{} couldn't be reached: {}", url, e))
+            }
+        };
+        match problem {
+            None => {
+                primed += 1;
+                runs[g].succeeded += 1;
+                // saved every time, since the point is surviving a run that dies
+                state.mark(gw, name);
+                state.save(&ctx.prime_state)?;
+            }
+            Some(message) => {
+                failed += 1;
+                runs[g].failed += 1;
+                ctx.metrics.record_prime_failure(template);
+                progress::emit(ProgressEvent::Warning {
+                    stage: Stage::Prime,
+                    message,
+                });
+            }
+        }
+        if unreachable {
+            // the rest of its links would only wait for the same timeout
+            say!("Giving up on {} for this run", gw);
+            given_up[g] = true;
+        } else if !name.is_empty() {
             std::thread::sleep(Duration::from_millis(423));
         }
     }
 
-    Ok(())
+    // enough runs to tell an unhealthy gateway, whatever unhealthy_after is
+    let keep = ctx.unhealthy_after.max(crate::gateway::HEALTH_RUNS);
+    for ((_, _, _, template), run) in gateways.iter().zip(runs) {
+        if run != RunHealth::default() {
+            state.record_run(template, run, keep);
+        }
+    }
+    state.save(&ctx.prime_state)?;
+
+    say!(
+        "Primed {} URLs this run, skipped {} that were already primed, {} failed",
+        primed,
+        skipped,
+        failed
+    );
+    let links: Vec<LinkCoverage> = names
+        .iter()
+        .zip(over_budget)
+        .map(|((name, first), over_budget)| LinkCoverage {
+            name: name.to_string(),
+            first: *first,
+            primed: gateways
+                .iter()
+                .filter(|(gw, _, _, _)| state.is_primed(gw, name))
+                .count(),
+            over_budget,
+        })
+        .collect();
+    let over_budget: usize = links.iter().map(|link| link.over_budget).sum();
+    if over_budget > 0 {
+        let left: Vec<&str> = links
+            .iter()
+            .filter(|link| link.over_budget > 0)
+            .map(|link| {
+                if link.name.is_empty() {
+                    "the root"
+                } else {
+                    link.name.as_str()
+                }
+            })
+            .collect();
+        let message = format!(
+            "the budget ran out with {} requests left, for {} (the next run carries on with them)",
+            over_budget,
+            left.join(", ")
+        );
+        say!("{}: {}", "WARNING".yellow(), message);
+        progress::emit(ProgressEvent::Warning {
+            stage: Stage::Prime,
+            message,
+        });
+        warnings += 1;
+    }
+    progress::emit(ProgressEvent::Summary {
+        stage: Stage::Prime,
+        processed: index,
+        errors: 0,
+        warnings: warnings + failed,
+    });
+
+    Ok(PrimeReport {
+        primed,
+        skipped,
+        failed,
+        over_budget,
+        links,
+        unhealthy_skipped,
+        health: state.health_rows(&configured, ctx.unhealthy_after),
+    })
 }
 
 #[cfg(test)]
@@ -243,14 +1582,143 @@ mod tests {
     }
 
     #[test]
+    fn bookkeeping() {
+        assert!(is_bookkeeping(OsStr::new(crate::manifest::MANIFEST_FILE)));
+        // what a conversion or write_atomically was in the middle of when the run was killed
+        for name in [".tmp.jam1_stereo.ogg", ".metadata.json.12345.tmp"] {
+            assert!(is_bookkeeping(OsStr::new(name)), "{}", name);
+        }
+        // the compressed copies are part of the site, like the pages they're copies of
+        for name in [
+            "index.html.gz",
+            "metadata.json.br",
+            "style.css",
+            ".notes.tmp",
+            "tmp.html",
+        ] {
+            assert!(!is_bookkeeping(OsStr::new(name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn patch_selection() {
+        let selector = Selector::new(["jam2"]).unwrap();
+        let folders = [
+            ("jam1", false),
+            ("first-jam", false),
+            ("old/jam2", true),
+            ("old/jam3", false),
+        ];
+        let selection = PatchSelection {
+            selector: &selector,
+            recordings: Some(folders.iter().map(|(f, s)| (f.to_string(), *s)).collect()),
+        };
+        assert_eq!(selection.scope("jam1"), FolderScope::Skipped);
+        assert_eq!(selection.scope("first-jam"), FolderScope::Skipped);
+        assert_eq!(selection.scope("css"), FolderScope::Patched);
+        assert_eq!(selection.scope("old"), FolderScope::Partly);
+        assert_eq!(selection.scope("old/jam2"), FolderScope::Patched);
+        assert_eq!(selection.scope("old/jam3"), FolderScope::Skipped);
+        assert!(selection.has_selected("old"));
+        assert!(!selection.has_selected("css"));
+
+        // without metadata.json, folders are only known by their names
+        let selection = PatchSelection {
+            selector: &selector,
+            recordings: None,
+        };
+        assert_eq!(selection.scope("jam2"), FolderScope::Patched);
+        assert_eq!(selection.scope("css"), FolderScope::Skipped);
+        assert_eq!(selection.scope(crate::source::SOURCE_DIR), FolderScope::Patched);
+    }
+
+    #[test]
+    fn link_sizes() {
+        // a single block file has a few bytes of overhead
+        assert!(link_size_fits(1000, 1011));
+        // a 100MB file in 256KiB chunks has a few bytes per chunk, and a link to each
+        assert!(link_size_fits(100 << 20, (100 << 20) + 400 * 56));
+        assert!(!link_size_fits(1000, 999));
+        assert!(!link_size_fits(1000, 10_000));
+    }
+
+    #[test]
+    fn dag_sizes() {
+        let old = DagSizes {
+            root: "old".to_string(),
+            total: 30 << 20,
+            links: vec![
+                LinkSize {
+                    name: "index.html".to_string(),
+                    bytes: 10 << 10,
+                },
+                LinkSize {
+                    name: "jam1".to_string(),
+                    bytes: 30 << 20,
+                },
+            ],
+        };
+        let mut new = old.clone();
+        new.total = 95 << 20;
+        new.links[1].bytes = 20 << 20;
+        new.links.push(LinkSize {
+            name: "jam2".to_string(),
+            bytes: 75 << 20,
+        });
+        assert_eq!(
+            new.human(Some(&old)),
+            "  index.html      0.0MB\n  jam1           20.0MB  (-10.0MB)\n  jam2           75.0MB  (new)\n  total          95.0MB  (+65.0MB)\n"
+        );
+        assert!(!new.human(None).contains('('));
+    }
+
+    #[test]
+    fn season_titles() {
+        let head =
+            br#"{"generator":"cb_processor 0.1.0","title":"Season \"1\"","artist":"","recordings":[{"title":"S01"#;
+        assert_eq!(season_title(head).as_deref(), Some("Season \"1\""));
+        // metadata.json from older versions was pretty-printed
+        assert_eq!(
+            season_title(b"{\n  \"title\" : \"Season 2\",\n").as_deref(),
+            Some("Season 2")
+        );
+        assert_eq!(season_title(br#"{"generator":"cb_processor","title":"Seas"#), None);
+        assert_eq!(season_title(b"<html>"), None);
+    }
+
+    #[test]
+    fn generator_left_out() {
+        let page = |generator: &str| format!("<head>\n    <!-- Generated by {} -->\n</head>", generator);
+        assert_eq!(
+            without_generator(page("cb_processor 0.1.0 (abc1234)").as_bytes()),
+            without_generator(page("cb_processor 0.2.0 (def5678-dirty)").as_bytes())
+        );
+        let metadata = br#"{"generator":"cb_processor 0.1.0 (abc1234)","title":"S01"}"#;
+        let pretty = b"{\n  \"generator\" : \"cb_processor \\\"0.2.0\\\"\",\n  \"title\": \"S01\"}";
+        assert_eq!(without_generator(metadata), br#"{"generator":"","title":"S01"}"#);
+        assert_eq!(
+            without_generator(pretty),
+            b"{\n  \"generator\":\"\",\n  \"title\": \"S01\"}"
+        );
+        // anything else that differs still does
+        assert_ne!(
+            without_generator(br#"{"generator":"a","title":"S01"}"#),
+            without_generator(br#"{"generator":"a","title":"S02"}"#)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs ipfs, and fetches a public root over the network (tests/ipfs_daemon.rs doesn't)"]
     fn object() {
         let cid = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
-        let obj = IPFSObject::get(&cid).unwrap();
+        let ctx = RunContext::default();
+        let obj = IPFSObject::get(&ctx, &cid).unwrap();
         // for link in obj.links {
         //     println!("{} {:?}", link.name, link.hash);
         // }
         let new = obj
             .add_link(
+                &ctx,
                 "ToS.txt",
                 &cid::Cid::from_str("QmXdCEDuqTgR2gfmVUyYCojvmxqRuQaL97RGNDjozrYCxE").unwrap(),
             )
@@ -0,0 +1,358 @@
+//! Building the metadata of recordings that are only in a published root (`cb_processor import-from-ipfs`)
+//!
+//! The flacs of older recordings can have been archived away, leaving the published root as the only copy of them.
+//! Instead of restoring the archive, the sizes and CIDs of their files are read from the links in the root, and the
+//! media info from the first [`HEAD_BYTES`] of each file.  That's all of a flac's STREAMINFO, but only the start of a
+//! bigger ogg or mp3, so their durations can be estimates.  What's found is then loaded as the metadata cache would
+//! be, so the season is the same as any other that's loaded without its data dir.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    analysis::parallel_for_each,
+    context::RunContext,
+    error::CbError,
+    ipfs::{self, IPFSLink, IPFSObject},
+    progress::{self, ProgressEvent, Stage},
+    slug, tag,
+    types::{is_draft, Recording, RecordingInner, Season, SeasonInner, Track},
+    MediaInfo,
+};
+
+/// How much of each file is fetched to read its media info from
+pub const HEAD_BYTES: u64 = 1 << 20;
+
+/// What an import found, and what it couldn't
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Files of the season's tracks that are in the root
+    pub found: usize,
+    /// Files whose media info could be read
+    pub analyzed: usize,
+    /// What couldn't be found out, one line per file.  These are also kept in each track's `import_warnings`
+    pub warnings: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "Found {} files, read the media info of {}, {} warnings\n",
+            self.found,
+            self.analyzed,
+            self.warnings.len()
+        )
+    }
+}
+
+/// The folders of a root, fetched when they're first needed
+struct Tree<'a> {
+    ctx: &'a RunContext,
+    root: cid::Cid,
+    /// The links of each folder (by its path in the root), or `None` for a folder that isn't there
+    folders: HashMap<String, Option<Vec<IPFSLink>>>,
+}
+
+/// Splits a path in the root into its folder and name
+fn split(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("", path),
+    }
+}
+
+impl Tree<'_> {
+    fn links(&mut self, folder: &str) -> Result<Option<Vec<IPFSLink>>, CbError> {
+        if let Some(links) = self.folders.get(folder) {
+            return Ok(links.clone());
+        }
+        let hash = if folder.is_empty() {
+            Some(self.root)
+        } else {
+            let (parent, name) = split(folder);
+            self.links(parent)?
+                .and_then(|links| links.into_iter().find(|l| l.name == name))
+                .map(|l| l.hash)
+        };
+        let links = match hash {
+            Some(hash) => Some(IPFSObject::get(self.ctx, &hash)?.links),
+            None => None,
+        };
+        self.folders.insert(folder.to_string(), links.clone());
+        Ok(links)
+    }
+
+    /// The link to the file at `path`, like `jam1/ogg/jam1_kick.ogg`, if it's there
+    fn find(&mut self, path: &str) -> Result<Option<IPFSLink>, CbError> {
+        let (folder, name) = split(path);
+        Ok(self
+            .links(folder)?
+            .and_then(|links| links.into_iter().find(|l| l.name == name)))
+    }
+}
+
+/// A path from a recording JSON, without the `./`s and doubled slashes that don't change where it points
+fn normalize(path: &Path) -> String {
+    path.to_string_lossy()
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FileKind {
+    Flac,
+    Ogg,
+    Mp3,
+    Opus,
+    LqOgg,
+}
+
+/// A file to read the media info of
+struct Todo {
+    recording: usize,
+    /// 0 is the stereo mix
+    track: usize,
+    kind: FileKind,
+    /// In the data folder
+    path: String,
+    cid: cid::Cid,
+    /// Where the start of the file goes
+    head: PathBuf,
+}
+
+/// Loads the season from what's in `root`, instead of from the data dir
+///
+/// Every file of every track that isn't a draft is looked for in `root`, under the recording's data folder.  Files that
+/// aren't there, or whose media info can't be read, don't stop the import: the tracks get what's known about them
+/// (their sizes are 0 and their media info is empty if nothing is), and a warning for each, which is also reported as a
+/// validation warning.
+pub fn import_season(ctx: &RunContext, season_json: &Path, root: &cid::Cid) -> Result<(Season, ImportReport), CbError> {
+    let inner = crate::get_validated_json(season_json)?;
+    let inner: SeasonInner = serde_json::from_value(inner)
+        .map_err(|e| CbError::parse(format!("Unexpected contents in {}", season_json.display()), e))?;
+
+    let mut tree = Tree {
+        ctx,
+        root: *root,
+        folders: HashMap::new(),
+    };
+    let mut report = ImportReport::default();
+    let mut recordings = Vec::new();
+    let mut todo = Vec::new();
+    let heads = std::env::temp_dir().join(format!("cb_processor-import-{}", std::process::id()));
+
+    for listed in inner.recording_paths(season_json)? {
+        let rec = crate::get_validated_json(&listed.path).map_err(|e| listed.context(e))?;
+        if is_draft(&rec) {
+            continue;
+        }
+        let rec: RecordingInner = serde_json::from_value(rec).map_err(|e| {
+            listed.context(CbError::parse(
+                format!("Unexpected contents in {}", listed.path.display()),
+                e,
+            ))
+        })?;
+
+        let mut tracks = Vec::new();
+        for (index, track) in std::iter::once(&rec.stereo_mix).chain(&rec.tracks).enumerate() {
+            let mut cached = Track::unknown(track.id, &track.flac);
+            let lq_ogg = track.lq_ogg().filter(|_| index == 0 && inner.low_quality_ogg.is_some());
+            let files = [
+                (FileKind::Flac, Some(PathBuf::from(&track.flac))),
+                (FileKind::Ogg, track.vorbis().map(|p| p.into_owned())),
+                (FileKind::Mp3, track.mp3().map(|p| p.into_owned())),
+                (FileKind::Opus, track.opus().map(|p| p.into_owned())),
+                (FileKind::LqOgg, lq_ogg),
+            ];
+            for (kind, path) in files {
+                let path = match path {
+                    Some(path) => normalize(&path),
+                    None => continue,
+                };
+                let link = match tree.find(&format!("{}/{}", rec.data_folder, path))? {
+                    Some(link) => link,
+                    // the low-quality oggs are newer than most of what's imported
+                    None if kind == FileKind::LqOgg => continue,
+                    None => {
+                        cached.import_warnings.push(format!("{} is not in {}", path, root));
+                        continue;
+                    }
+                };
+                report.found += 1;
+                let bytes = link.size as u64;
+                match kind {
+                    FileKind::Flac => cached.flac_bytes = bytes,
+                    FileKind::Ogg => cached.ogg_bytes = bytes,
+                    FileKind::Mp3 => cached.mp3_bytes = bytes,
+                    FileKind::Opus => cached.opus_bytes = bytes,
+                    FileKind::LqOgg => cached.lq_ogg_bytes = bytes,
+                }
+                cached.cids.insert(path.clone(), link.hash.to_string());
+                if kind != FileKind::LqOgg {
+                    todo.push(Todo {
+                        recording: recordings.len(),
+                        track: index,
+                        kind,
+                        path: path.clone(),
+                        cid: link.hash,
+                        head: heads.join(format!("{}-{}", todo.len(), split(&path).1)),
+                    });
+                }
+            }
+            tracks.push(cached);
+        }
+
+        let stereo_mix = tracks.remove(0);
+        let tags = tag::from_json(&rec.tags, &rec.title)?;
+        recordings.push(Recording {
+            slug: slug::for_recording(rec.slug.as_deref(), &rec.title, &rec.data_folder),
+            title: rec.title,
+            data_folder: rec.data_folder,
+            stereo_mix,
+            recorded_date: rec.recorded_date,
+            artist: rec.artist.unwrap_or_default(),
+            torrent: rec.torrent,
+            tracks,
+            tags,
+            bpm: rec.bpm,
+            youtube_url: rec.youtube_url,
+            archive_org_url: None,
+            description: rec.description,
+            tos: rec.tos,
+            completeness: Default::default(),
+            mirrors: rec.mirrors,
+            related: rec.related,
+        });
+    }
+
+    std::fs::create_dir_all(&heads).map_err(|e| CbError::io(format!("Failed to create {}", heads.display()), e))?;
+    let found = read_media_info(ctx, todo);
+    let _ = std::fs::remove_dir_all(&heads);
+
+    for (rec, by_track) in recordings.iter_mut().zip(found) {
+        for (index, mut infos) in by_track {
+            let track = match index {
+                0 => &mut rec.stereo_mix,
+                i => &mut rec.tracks[i - 1],
+            };
+            let warnings = &mut track.import_warnings;
+            let analyzed = &mut report.analyzed;
+            let mut info = |kind| match infos.remove(&kind) {
+                Some(Ok(info)) => {
+                    *analyzed += 1;
+                    Some(info)
+                }
+                Some(Err(e)) => {
+                    warnings.push(e);
+                    None
+                }
+                None => None,
+            };
+            let (flac, ogg, mp3, opus) = (
+                info(FileKind::Flac),
+                info(FileKind::Ogg),
+                info(FileKind::Mp3),
+                info(FileKind::Opus),
+            );
+            match (flac, &ogg) {
+                (Some(flac), _) => track.media_info = flac,
+                // the length and channels of the ogg are better than nothing
+                (None, Some(ogg)) => track.media_info = ogg.clone(),
+                (None, None) => {}
+            }
+            track.ogg_info = ogg;
+            track.mp3_info = mp3;
+            track.opus_info = opus;
+        }
+    }
+    for rec in &recordings {
+        for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+            for warning in &track.import_warnings {
+                let warning = format!("`{}` track {}: {}", rec.title, track.id, warning);
+                progress::emit(ProgressEvent::Warning {
+                    stage: Stage::Validate,
+                    message: warning.clone(),
+                });
+                report.warnings.push(warning);
+            }
+        }
+    }
+
+    let cache = Season {
+        generator: crate::GENERATOR.to_string(),
+        title: inner.title.clone(),
+        artist: inner.artist.clone().unwrap_or_default(),
+        recordings,
+        redirects: BTreeMap::new(),
+        low_quality_ogg: inner.low_quality_ogg.clone(),
+        skipped: Vec::new(),
+    };
+    let season = Season::load(ctx, season_json, None, Some(&cache))?;
+    Ok((season, report))
+}
+
+/// What was read from each file: by recording, then by track, then by kind of file
+type Found = Vec<BTreeMap<usize, HashMap<FileKind, Result<MediaInfo, String>>>>;
+
+/// Fetches the start of each file and reads its media info, up to `ctx.jobs` files at a time
+fn read_media_info(ctx: &RunContext, todo: Vec<Todo>) -> Found {
+    // the heads are only read once, so there's no point in caching what's in them
+    let mut uncached = ctx.clone();
+    uncached.media_cache = None;
+
+    let total = todo.len();
+    let done = AtomicUsize::new(0);
+    let found = Mutex::new(Found::new());
+    parallel_for_each(ctx.jobs, todo, |file| {
+        let info = ipfs::cat_head(&uncached, &file.cid, HEAD_BYTES)
+            .and_then(|head| {
+                std::fs::write(&file.head, head)
+                    .map_err(|e| CbError::io(format!("Failed to write {}", file.head.display()), e))
+            })
+            .and_then(|()| MediaInfo::new(&uncached, &file.head))
+            .map_err(|e| {
+                format!(
+                    "couldn't read the media info of {}: {:#}",
+                    file.path,
+                    anyhow::Error::new(e)
+                )
+            });
+        let _ = std::fs::remove_file(&file.head);
+
+        let mut found = found.lock().unwrap();
+        if found.len() <= file.recording {
+            found.resize_with(file.recording + 1, BTreeMap::new);
+        }
+        found[file.recording]
+            .entry(file.track)
+            .or_default()
+            .insert(file.kind, info);
+        progress::emit(ProgressEvent::ItemProcessed {
+            stage: Stage::Fetch,
+            item: file.cid.to_string(),
+            index: done.fetch_add(1, Ordering::SeqCst) + 1,
+            total,
+        });
+    });
+    found.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(split("jam1/ogg/kick.ogg"), ("jam1/ogg", "kick.ogg"));
+        assert_eq!(split("jam1"), ("", "jam1"));
+        assert_eq!(normalize(Path::new("./ogg//kick.ogg")), "ogg/kick.ogg");
+    }
+}
@@ -0,0 +1,307 @@
+//! The canonical style of season.json, its include files and the recording JSONs (`cb_processor fmt`)
+//!
+//! Keys are written in the order of the schema's properties (with `$schema` first), and any key the schema doesn't know
+//! about is kept, after them in alphabetical order.  Tracks are sorted by id.  Indentation is two spaces, lines end in
+//! LF, and the file ends with a newline.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::{
+    error::CbError,
+    types::{RecordingInner, SeasonInner},
+};
+
+/// The properties of season.json (and of include files), in the order of the season schema
+const SEASON_KEYS: &[&str] = &["$schema", "title", "artist", "low_quality_ogg", "mirrors", "recordings"];
+const OGG_PROFILE_KEYS: &[&str] = &["quality", "bitrate_kbps"];
+const MIRROR_KEYS: &[&str] = &["label", "url"];
+/// The properties of a recording, in the order of the recording schema
+const RECORDING_KEYS: &[&str] = &[
+    "$schema",
+    "title",
+    "data_folder",
+    "slug",
+    "tags",
+    "recorded_date",
+    "artist",
+    "twitch_url",
+    "youtube_url",
+    "bpm",
+    "torrent_url",
+    "torrent",
+    "tos",
+    "draft",
+    "description",
+    "mirrors",
+    "related",
+    "stereo_mix",
+    "tracks",
+];
+/// The properties of a track, in the order of the recording schema's `track_listing`
+const TRACK_KEYS: &[&str] = &[
+    "id",
+    "patch_notes",
+    "group",
+    "usage",
+    "flac",
+    "vorbis",
+    "mp3",
+    "opus",
+    "name",
+];
+
+/// What part of which file a JSON value is, which decides the order of its keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Season,
+    OggProfile,
+    Mirror,
+    Recording,
+    Track,
+    /// Anything else, whose keys are sorted
+    Other,
+}
+
+impl Shape {
+    fn keys(self) -> &'static [&'static str] {
+        match self {
+            Shape::Season => SEASON_KEYS,
+            Shape::OggProfile => OGG_PROFILE_KEYS,
+            Shape::Mirror => MIRROR_KEYS,
+            Shape::Recording => RECORDING_KEYS,
+            Shape::Track => TRACK_KEYS,
+            Shape::Other => &[],
+        }
+    }
+
+    /// The shape of the value under `key` (or of each item, if it's an array)
+    fn child(self, key: &str) -> Shape {
+        match (self, key) {
+            (Shape::Season, "low_quality_ogg") => Shape::OggProfile,
+            (Shape::Season, "mirrors") | (Shape::Recording, "mirrors") => Shape::Mirror,
+            (Shape::Recording, "stereo_mix") | (Shape::Recording, "tracks") => Shape::Track,
+            _ => Shape::Other,
+        }
+    }
+}
+
+/// season.json (or an include file) in the canonical style
+pub fn season(value: &Value) -> String {
+    canonical(value, Shape::Season)
+}
+
+/// A recording JSON in the canonical style, with its tracks sorted by id
+///
+/// Tracks without a number for an id keep their order, after the others.
+pub fn recording(value: &Value) -> String {
+    let mut value = value.clone();
+    if let Some(Value::Array(tracks)) = value.get_mut("tracks") {
+        tracks.sort_by(|a, b| {
+            let id = |t: &Value| t.get("id").and_then(Value::as_f64);
+            match (id(a), id(b)) {
+                (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }
+        });
+    }
+    canonical(&value, Shape::Recording)
+}
+
+fn canonical(value: &Value, shape: Shape) -> String {
+    let mut out = String::new();
+    render(value, shape, 0, &mut out);
+    out.push('\n');
+    out
+}
+
+fn render(value: &Value, shape: Shape, depth: usize, out: &mut String) {
+    let indent = |depth: usize| "  ".repeat(depth);
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            let known = shape.keys().iter().copied().filter(|k| map.contains_key(*k));
+            let unknown = map.keys().map(String::as_str).filter(|k| !shape.keys().contains(k));
+            let keys: Vec<&str> = known.chain(unknown).collect();
+            out.push_str("{\n");
+            for (i, key) in keys.iter().enumerate() {
+                out.push_str(&indent(depth + 1));
+                out.push_str(&Value::from(*key).to_string());
+                out.push_str(": ");
+                render(&map[*key], shape.child(key), depth + 1, out);
+                out.push_str(if i + 1 < keys.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&indent(depth));
+            out.push('}');
+        }
+        Value::Array(items) if !items.is_empty() => {
+            out.push_str("[\n");
+            for (i, item) in items.iter().enumerate() {
+                out.push_str(&indent(depth + 1));
+                render(item, shape, depth + 1, out);
+                out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&indent(depth));
+            out.push(']');
+        }
+        // scalars, and empty objects and arrays
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+/// Reformats season.json, its include files and every recording they list, and returns the files that changed
+///
+/// Each file is validated first (against its schema, and as the season or recording it is), and nothing is written
+/// until all of them are.  With `check`, nothing is written at all, and the files that would change are returned.
+pub fn format_season(season_json: &Path, check: bool) -> Result<Vec<PathBuf>, CbError> {
+    let value = crate::get_validated_json(season_json)?;
+    let parsed: SeasonInner = serde_json::from_value(value.clone())
+        .map_err(|e| CbError::parse(format!("Unexpected contents in {}", season_json.display()), e))?;
+    let mut files = vec![(season_json.to_path_buf(), season(&value))];
+
+    for listed in parsed.recording_paths(season_json)? {
+        for include in listed.chain.iter().skip(1) {
+            if !files.iter().any(|(path, _)| path == include) {
+                files.push((include.clone(), season(&crate::get_validated_json(include)?)));
+            }
+        }
+        let value = crate::get_validated_json(&listed.path).map_err(|e| listed.context(e))?;
+        let _: RecordingInner = serde_json::from_value(value.clone()).map_err(|e| {
+            listed.context(CbError::parse(
+                format!("Unexpected contents in {}", listed.path.display()),
+                e,
+            ))
+        })?;
+        files.push((listed.path.clone(), recording(&value)));
+    }
+
+    let mut changed = Vec::new();
+    for (path, formatted) in files {
+        let current = std::fs::read(&path).map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?;
+        if current == formatted.as_bytes() {
+            continue;
+        }
+        if !check {
+            crate::write_atomically(&path, formatted.as_bytes())
+                .map_err(|e| CbError::io(format!("Failed to write {}", path.display()), e))?;
+        }
+        changed.push(path);
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A schema object, with its properties in the order they're written in
+    #[derive(serde::Deserialize, Default)]
+    struct SchemaObject {
+        #[serde(default)]
+        properties: Ordered,
+        #[serde(default)]
+        definitions: std::collections::BTreeMap<String, SchemaObject>,
+        #[serde(default)]
+        items: Option<Box<SchemaObject>>,
+    }
+
+    #[derive(Default)]
+    struct Ordered(Vec<(String, SchemaObject)>);
+
+    impl<'de> serde::Deserialize<'de> for Ordered {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Ordered, D::Error> {
+            struct Visitor;
+            impl<'de> serde::de::Visitor<'de> for Visitor {
+                type Value = Ordered;
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("the properties of a schema")
+                }
+                fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Ordered, A::Error> {
+                    let mut entries = Vec::new();
+                    while let Some(entry) = map.next_entry()? {
+                        entries.push(entry);
+                    }
+                    Ok(Ordered(entries))
+                }
+            }
+            deserializer.deserialize_map(Visitor)
+        }
+    }
+
+    impl SchemaObject {
+        fn keys(&self) -> Vec<&str> {
+            self.properties.0.iter().map(|(k, _)| k.as_str()).collect()
+        }
+
+        fn property(&self, name: &str) -> &SchemaObject {
+            &self.properties.0.iter().find(|(k, _)| k == name).unwrap().1
+        }
+    }
+
+    #[test]
+    fn keys_follow_the_schemas() {
+        let without_schema = |keys: &[&'static str]| keys[1..].to_vec();
+        let recording: SchemaObject = serde_json::from_str(crate::assets::RECORDING_SCHEMA).unwrap();
+        assert_eq!(recording.keys(), without_schema(RECORDING_KEYS));
+        assert_eq!(recording.definitions["track_listing"].keys(), TRACK_KEYS);
+        let season: SchemaObject = serde_json::from_str(crate::assets::SEASON_SCHEMA).unwrap();
+        assert_eq!(season.keys(), without_schema(SEASON_KEYS));
+        assert_eq!(season.property("low_quality_ogg").keys(), OGG_PROFILE_KEYS);
+        for schema in [&season, &recording] {
+            assert_eq!(schema.property("mirrors").items.as_ref().unwrap().keys(), MIRROR_KEYS);
+        }
+    }
+
+    #[test]
+    fn canonical_recording() {
+        let value = json!({
+            "tracks": [
+                {"name": "Snare", "flac": "snare.flac", "id": 3},
+                {"name": "Kick", "flac": "kick.flac", "id": 2, "color": "red"}
+            ],
+            "title": "Jam",
+            "stereo_mix": {"name": "Stereo mix", "flac": "stereo.flac", "id": 1},
+            "tags": [],
+            "$schema": "../schema/recording.json",
+            "data_folder": "jam",
+            "recorded_date": "2021/01/02",
+            "extra": {"b": 1, "a": "\u{e9}"}
+        });
+        assert_eq!(
+            recording(&value),
+            r#"{
+  "$schema": "../schema/recording.json",
+  "title": "Jam",
+  "data_folder": "jam",
+  "tags": [],
+  "recorded_date": "2021/01/02",
+  "stereo_mix": {
+    "id": 1,
+    "flac": "stereo.flac",
+    "name": "Stereo mix"
+  },
+  "tracks": [
+    {
+      "id": 2,
+      "flac": "kick.flac",
+      "name": "Kick",
+      "color": "red"
+    },
+    {
+      "id": 3,
+      "flac": "snare.flac",
+      "name": "Snare"
+    }
+  ],
+  "extra": {
+    "a": "é",
+    "b": 1
+  }
+}
+"#
+        );
+        let formatted: Value = serde_json::from_str(&recording(&value)).unwrap();
+        assert_eq!(recording(&formatted), recording(&value));
+    }
+}
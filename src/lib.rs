@@ -1,118 +1,675 @@
 use std::io::Write;
 use std::{
-    collections::HashSet,
     fs::File,
     path::{Path, PathBuf},
     process::{Command, Stdio},
-    time::Duration,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::bail;
+use analysis::parallel_for_each;
+use anyhow::{bail, Context};
+#[cfg(feature = "cli")]
 use colored::Colorize;
+use context::{MediaInfoBackend, RunContext};
+use error::CbError;
+use file_tags::FileTags;
+#[cfg(not(feature = "cli"))]
+use plain::Colorize;
+use progress::{ProgressEvent, Stage};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use types::{Recording, RecordingInner, Season};
-use valico::json_schema;
+use types::{RecordingInner, Season, Track};
 
+/// Writes a line of the human-readable output (see [`progress::log`])
+macro_rules! say {
+    () => {
+        $crate::progress::log(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::progress::log(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Writes part of a line of the human-readable output, for a line that's finished once something is done
+macro_rules! say_part {
+    ($($arg:tt)*) => {
+        $crate::progress::log(format_args!($($arg)*))
+    };
+}
+
+pub mod analysis;
+#[cfg(feature = "archive_org")]
+pub mod archive_org;
+pub mod asset_map;
+pub mod assets;
+pub mod badges;
+pub mod checksum;
+pub mod chunked_metadata;
+pub mod clean;
+pub mod command;
+pub mod completeness;
+pub mod context;
+pub mod dates;
+pub mod diff;
+pub mod duplicates;
+pub mod error;
+#[cfg(feature = "ipfs")]
+pub mod fetch;
+pub mod file_tags;
+pub mod flac;
+#[cfg(feature = "ipfs")]
+pub mod gateway;
+pub mod hasher;
+#[cfg(feature = "ipfs")]
+pub mod history;
+pub mod hooks;
+#[cfg(feature = "ipfs")]
 pub mod ipfs;
+#[cfg(feature = "ipfs")]
+pub mod ipfs_import;
+pub mod json_format;
+pub mod listing;
+pub mod lock;
+pub mod manifest;
+pub mod markdown;
+pub mod media_cache;
+pub mod metrics;
+pub mod mirror;
+#[cfg(feature = "ipfs")]
+pub mod notify;
+pub mod peaks;
+pub mod pipeline;
+pub mod precompress;
+pub mod progress;
+pub mod provenance;
+pub mod quarantine;
+pub mod references;
+pub mod related;
+pub mod scaffold;
+#[cfg(feature = "schema")]
+mod schema;
+pub mod select;
+#[cfg(all(feature = "templates", feature = "ipfs"))]
+pub mod share;
+#[cfg(feature = "templates")]
+mod site;
+pub mod slug;
+pub mod source;
+pub mod spectrogram;
+pub mod state_store;
+pub mod stats;
+pub mod tag;
+pub mod timing;
 pub mod types;
+#[cfg(feature = "cli")]
+pub mod update;
+pub mod youtube;
+
+pub use pipeline::Pipeline;
+#[cfg(feature = "templates")]
+pub use site::{
+    write_all_recording_index, write_badges, write_season_index, write_season_index_of, write_source_snapshot,
+    RecordingIndexTemplate, RecordingSummary, SeasonIndexTemplate,
+};
 
-pub fn get_validated_json(json_path: &Path) -> Result<serde_json::Value, anyhow::Error> {
-    let file = File::open(json_path)?;
-    let json: Value = serde_json::from_reader(file)?;
+/// The version of cb_processor, and the git commit it was built from
+pub const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), " (", env!("CB_GIT_HASH"), ")");
+
+/// Identifies this build in generated files
+pub const GENERATOR: &str = concat!(
+    "cb_processor ",
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("CB_GIT_HASH"),
+    ")"
+);
+
+/// Reads a JSON file, and checks it against the schema named by its `$schema` (if that's a local file)
+///
+/// Without the `schema` feature, the file is returned without being checked.
+#[cfg(feature = "schema")]
+pub fn get_validated_json(json_path: &Path) -> Result<serde_json::Value, CbError> {
+    let json = read_json(json_path)?;
 
     if let Value::Object(map) = &json {
         if let Some(Value::String(schema)) = map.get("$schema") {
             if schema.starts_with("./") || schema.starts_with("../") {
                 // local file, fine it relative to json_path
                 let schema_path = json_path.parent().unwrap().join(schema);
-                let schema_file = File::open(schema_path)?;
-                let schema_json = serde_json::from_reader(schema_file)?;
-
-                let mut scope = json_schema::Scope::new();
-                let schema = scope.compile_and_return(schema_json, false).unwrap();
-                let res = schema.validate(&json);
-                if res.is_valid() {
-                    return Ok(json);
-                } else {
-                    bail!("JSON not valid, schema validation failed: {:?}", res)
-                }
+                schema::validate(json_path, &json, &schema_path)?;
+                return Ok(json);
             }
         }
     }
 
     // no schema found, just return it unvalidated
-    return Ok(json);
+    Ok(json)
 }
 
-pub fn convert_all(season: &Season) -> Result<(), anyhow::Error> {
-    for rec in &season.recordings {
-        let p = rec.stereo_mix.ogg_ondisk();
-        let p = p.as_ref().unwrap();
-        if !p.exists() {
-            convert_to_fileformat(&rec.stereo_mix.flac_ondisk().as_ref().unwrap(), &p)?;
+#[cfg(not(feature = "schema"))]
+pub fn get_validated_json(json_path: &Path) -> Result<serde_json::Value, CbError> {
+    read_json(json_path)
+}
+
+/// Stand-ins for the `colored` methods, for builds without the `cli` feature
+#[cfg(not(feature = "cli"))]
+mod plain {
+    pub trait Colorize {
+        fn red(&self) -> &str;
+        fn green(&self) -> &str;
+        fn yellow(&self) -> &str;
+        fn cyan(&self) -> &str;
+    }
+
+    impl Colorize for str {
+        fn red(&self) -> &str {
+            self
+        }
+        fn green(&self) -> &str {
+            self
+        }
+        fn yellow(&self) -> &str {
+            self
         }
+        fn cyan(&self) -> &str {
+            self
+        }
+    }
+}
 
-        if let Some(mp3) = rec.stereo_mix.mp3_ondisk() {
-            if !mp3.exists() {
-                convert_to_fileformat(&rec.stereo_mix.flac_ondisk().as_ref().unwrap(), &mp3)?;
-            }
+/// Reads a JSON file, without any validation
+fn read_json(path: &Path) -> Result<Value, CbError> {
+    let file = File::open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => CbError::MissingFile {
+            path: path.to_path_buf(),
+        },
+        _ => CbError::io(format!("Failed to open {}", path.display()), e),
+    })?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| CbError::parse(format!("Failed to parse {}", path.display()), e))
+}
+
+/// Serializes JSON using the same formatting as our hand-written data files (4 space indent)
+pub fn to_json_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut ser = serde_json::Serializer::with_formatter(&mut buf, formatter);
+    value.serialize(&mut ser)?;
+    buf.push(b'\n');
+    Ok(buf)
+}
+
+/// Writes out a JSON file using the same formatting as our hand-written data files
+pub fn write_json_file<T: Serialize>(path: &Path, value: &T) -> Result<(), anyhow::Error> {
+    std::fs::write(path, to_json_bytes(value)?)?;
+    Ok(())
+}
+
+/// What the files that conversions and retagging write before they're renamed into place start with (`.tmp.<name>`)
+pub const TEMP_PREFIX: &str = ".tmp.";
+
+/// Whether `name` is one of the temporary files next to what's being written: a conversion's or retagging's
+/// (`.tmp.<name>`), or [`write_atomically`]'s (`.<name>.<pid>.tmp`), which a run that was killed leaves behind
+pub fn is_temp_file(name: &std::ffi::OsStr) -> bool {
+    let name = name.to_string_lossy();
+    if name.starts_with(TEMP_PREFIX) {
+        return true;
+    }
+    let atomic = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".tmp"))
+        .and_then(|name| name.rsplit_once('.'));
+    atomic.is_some_and(|(_, pid)| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Replaces `path` with `contents` all at once: they're written next to it first, and renamed over it
+///
+/// Someone reading the file (or a run that fails halfway) sees either the old contents or the new ones, never part of
+/// them.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let written = std::fs::write(&tmp, contents).and_then(|()| std::fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Bit depth that the lossy (ogg and mp3) files are made from
+pub const LOSSY_BIT_DEPTH: u8 = 16;
+
+/// What a file was converted from, and how
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversion {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// The ffmpeg audio filter chain that was used
+    pub filters: String,
+    /// Encoder options, like the quality of a low-quality ogg (empty for ffmpeg's defaults)
+    pub encoder_args: Vec<String>,
+    /// What the output is tagged with
+    pub tags: FileTags,
+    /// The output was there already, and was converted again because it was stale (or because of
+    /// [`RunContext::force_reconvert`])
+    pub replaced: bool,
+}
+
+impl Conversion {
+    /// The options that go after the filters on ffmpeg's command line: the encoder's, then the tags'
+    fn output_args(&self) -> Vec<String> {
+        let mut args = self.encoder_args.clone();
+        args.extend(self.tags.ffmpeg_args(&self.output));
+        args
+    }
+}
+
+/// The ffmpeg filters for reducing a source to `target_bits`
+///
+/// The sample format is always given explicitly, and triangular dither is added when bits are being thrown away (so
+/// that quiet passages don't pick up truncation distortion).  If the source depth isn't known, it's assumed to be
+/// deeper than the target.
+pub fn bit_depth_filters(source_bits: Option<u8>, target_bits: u8) -> String {
+    let format = format!("aresample=osf=s{}", target_bits);
+    match source_bits {
+        Some(bits) if bits <= target_bits => format,
+        _ => format!("{}:dither_method=triangular", format),
+    }
+}
+
+/// How far apart the lengths of a flac and a file converted from it can be before the file is taken for one that was
+/// made from an older flac
+const STALE_DURATION_SECS: f64 = 1.0;
+
+/// Whether a file converted from a flac still goes with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Missing,
+    UpToDate,
+    /// The flac was changed after the file was made, or they're not the same length
+    Stale,
+}
+
+/// Whether `output`, converted from `track`'s flac, is up to date
+///
+/// `output_info` is what mediainfo said about `output` when the season was loaded, if it was asked.  The lengths are
+/// compared as well as the modification times, since a flac that's replaced by copying it over often keeps the time
+/// of the old one.
+pub fn freshness(track: &Track, output: &Path, output_info: Option<&MediaInfo>) -> Freshness {
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|md| md.modified()).ok();
+    let output_modified = match modified(output) {
+        Some(modified) => modified,
+        None => return Freshness::Missing,
+    };
+    if let Some(flac_modified) = track.flac_ondisk().as_deref().and_then(modified) {
+        if flac_modified > output_modified {
+            return Freshness::Stale;
         }
+    }
+    let durations = (
+        track.media_info.duration_secs().ok(),
+        output_info.and_then(|info| info.duration_secs().ok()),
+    );
+    match durations {
+        (Some(flac), Some(output)) if (flac - output).abs() > STALE_DURATION_SECS => Freshness::Stale,
+        _ => Freshness::UpToDate,
+    }
+}
 
-        for track in &rec.tracks {
-            let p = track.ogg_ondisk();
-            let p = p.as_ref().unwrap();
-            if !p.exists() {
-                convert_to_fileformat(&track.flac_ondisk().as_ref().unwrap(), &p)?;
+/// The files `track` is converted to, with what mediainfo said about each when the season was loaded
+///
+/// The low-quality ogg is only there when the season has a profile for it.
+fn lossy_outputs<'t>(track: &'t Track, season: &Season) -> Vec<(PathBuf, Option<&'t MediaInfo>)> {
+    // flac-only tracks don't get an ogg
+    let outputs = [
+        track.ogg_ondisk().map(|ogg| (ogg, track.ogg_info.as_ref())),
+        track.mp3_ondisk().map(|mp3| (mp3, track.mp3_info.as_ref())),
+        track.opus_ondisk().map(|opus| (opus, track.opus_info.as_ref())),
+        track
+            .lq_ogg_ondisk()
+            .filter(|_| season.low_quality_ogg.is_some())
+            .map(|lq| (lq, None)),
+    ];
+    IntoIterator::into_iter(outputs).flatten().collect()
+}
+
+/// How many of the files the selected recordings are converted to are up to date (none, with
+/// [`RunContext::force_reconvert`])
+pub fn count_up_to_date(ctx: &RunContext, season: &Season) -> Result<usize, CbError> {
+    if ctx.force_reconvert {
+        return Ok(0);
+    }
+    let mut count = 0;
+    for rec in ctx.only.select(season)? {
+        for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+            count += lossy_outputs(track, season)
+                .into_iter()
+                .filter(|(output, info)| freshness(track, output, *info) == Freshness::UpToDate)
+                .count();
+        }
+    }
+    Ok(count)
+}
+
+/// Makes any ogg, mp3 and opus files that don't exist yet or are stale (see [`freshness`]) (with the encoder settings of `ctx`, and tagged with what
+/// [`FileTags::of`] the track says), returning what was made
+///
+/// Up to `ctx.jobs` sources are converted at once, each to all of its outputs in turn.  A source that fails doesn't
+/// stop the others: every failure is listed at the end, and the first error is returned.  Sources in quarantine (see
+/// [`quarantine`]) are skipped.
+pub fn convert_all(ctx: &RunContext, season: &Season) -> Result<Vec<Conversion>, CbError> {
+    ctx.stage(Stage::Convert, || {
+        // figure out everything that needs converting first, so that we can report progress
+        let mut sources = Vec::new();
+        let mut warnings = 0;
+        for rec in ctx.only.select(season)? {
+            let quarantine = match rec.stereo_mix.folder_ondisk() {
+                Some(folder) => quarantine::Quarantine::load(folder)?,
+                None => Default::default(),
+            };
+            for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+                let tags = FileTags::of(rec, track);
+                let folder = track.folder_ondisk().unwrap().to_path_buf();
+                let flac = track.flac_ondisk().unwrap();
+                let filters = bit_depth_filters(track.media_info.bit_depth(), LOSSY_BIT_DEPTH);
+                let lq_ogg = track.lq_ogg_ondisk();
+                let mut outputs = Vec::new();
+                for (output, info) in lossy_outputs(track, season) {
+                    let replaced = match freshness(track, &output, info) {
+                        Freshness::Missing => false,
+                        Freshness::UpToDate if !ctx.force_reconvert => continue,
+                        Freshness::UpToDate | Freshness::Stale => true,
+                    };
+                    // only stereo mixes have a low-quality ogg, and only when the season has a profile for it
+                    let encoder_args = match &season.low_quality_ogg {
+                        Some(profile) if lq_ogg.as_ref() == Some(&output) => profile.encoder_args(),
+                        _ => ctx.encode.encoder_args(&output)?,
+                    };
+                    outputs.push((output, encoder_args, replaced));
+                }
+                if outputs.is_empty() {
+                    continue;
+                }
+                if quarantine.is_quarantined(&folder, &flac) {
+                    say!(
+                        "{}: skipping {}, which is in quarantine after failing to convert",
+                        "WARNING".yellow(),
+                        flac.display()
+                    );
+                    progress::emit(ProgressEvent::Warning {
+                        stage: Stage::Convert,
+                        message: format!("{} is in quarantine, so it wasn't converted", flac.display()),
+                    });
+                    warnings += 1;
+                    continue;
+                }
+                let jobs: Vec<Conversion> = outputs
+                    .into_iter()
+                    .map(|(output, encoder_args, replaced)| Conversion {
+                        input: flac.clone(),
+                        output,
+                        filters: filters.clone(),
+                        encoder_args,
+                        tags: tags.clone(),
+                        replaced,
+                    })
+                    .collect();
+                sources.push((sources.len(), folder, jobs));
             }
+        }
 
-            if let Some(mp3) = track.mp3_ondisk() {
-                if !mp3.exists() {
-                    convert_to_fileformat(&track.flac_ondisk().as_ref().unwrap(), &mp3)?;
+        let total = sources.iter().map(|(_, _, jobs)| jobs.len()).sum();
+        let ffmpeg_version = if sources.is_empty() {
+            String::new()
+        } else {
+            provenance::ffmpeg_version(ctx)
+        };
+        let done = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+        let made = Mutex::new(Vec::new());
+        // the quarantine and provenance files of a folder are read and written whole, one conversion at a time
+        let bookkeeping = Mutex::new(());
+
+        parallel_for_each(ctx.jobs, sources, |(source_index, folder, jobs)| {
+            // a source that failed for the ogg will fail for the mp3 too, and should only count once
+            let mut failed = false;
+            for (job_index, job) in jobs.into_iter().enumerate() {
+                let start = Instant::now();
+                if !failed {
+                    if let Err(e) = convert_one(ctx, &folder, &job, &ffmpeg_version, &bookkeeping) {
+                        failed = true;
+                        errors.lock().unwrap().push((job.input.clone(), e));
+                    }
                 }
+                ctx.timings
+                    .record_item(Stage::Convert, job.output.display().to_string(), start.elapsed());
+                progress::emit(ProgressEvent::ItemProcessed {
+                    stage: Stage::Convert,
+                    item: job.output.display().to_string(),
+                    index: done.fetch_add(1, Ordering::SeqCst) + 1,
+                    total,
+                });
+                if !failed {
+                    made.lock().unwrap().push(((source_index, job_index), job));
+                }
+            }
+        });
+
+        let errors = errors.into_inner().unwrap();
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Convert,
+            processed: total,
+            errors: errors.len(),
+            warnings,
+        });
+        if errors.len() > 1 {
+            say!("{} flacs failed to convert:", errors.len());
+            for (input, _) in &errors {
+                say!("  {}", input.display());
             }
         }
+        // in the order they would have been made one at a time
+        let mut made = made.into_inner().unwrap();
+        made.sort_by_key(|(order, _)| *order);
+        match errors.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(made.into_iter().map(|(_, job)| job).collect()),
+        }
+    })
+}
+
+/// Runs one conversion of [`convert_all`], and records how it went in the quarantine and provenance of `folder`
+fn convert_one(
+    ctx: &RunContext, folder: &Path, job: &Conversion, ffmpeg_version: &str, bookkeeping: &Mutex<()>,
+) -> Result<(), CbError> {
+    let output_args = job.output_args();
+    match convert_with_filters(ctx, &job.input, &job.output, Some(&job.filters), &output_args) {
+        Ok(()) => {
+            if let Ok(meta) = job.output.metadata() {
+                ctx.metrics.add_converted_bytes(meta.len());
+            }
+            // the ogg and the mp3 come from the same flac, which the pool only reads once
+            let fingerprint = ctx.hashes.sha256(&job.input)?;
+            let _guard = bookkeeping.lock().unwrap();
+            quarantine::Quarantine::succeeded(folder, &job.input)?;
+            provenance::Provenance::record(
+                folder,
+                &job.output,
+                &job.input,
+                &fingerprint,
+                ffmpeg_version,
+                &ffmpeg_command(ctx, &job.input, &job.output, Some(&job.filters), &output_args),
+            )
+        }
+        Err(e) => {
+            let message = match std::error::Error::source(&e) {
+                Some(source) => format!("{}: {}", e, source),
+                None => e.to_string(),
+            };
+            say!("{}: {}", "ERROR".red(), message);
+            progress::emit(ProgressEvent::Error {
+                stage: Stage::Convert,
+                message: message.clone(),
+            });
+            let _guard = bookkeeping.lock().unwrap();
+            if quarantine::Quarantine::failed(folder, &job.input, &message)? {
+                say!(
+                    "{}: {} failed {} runs in a row, so it's in quarantine until --retry-quarantined",
+                    "WARNING".yellow(),
+                    job.input.display(),
+                    quarantine::QUARANTINE_AFTER
+                );
+            }
+            Err(e)
+        }
     }
+}
 
-    Ok(())
+/// Converts input to output format (based on the extension of output path), with the encoder settings of `ctx`
+pub fn convert_to_fileformat(ctx: &RunContext, input: &Path, output: &Path) -> Result<(), CbError> {
+    let encoder_args = ctx.encode.encoder_args(output)?;
+    convert_with_filters(ctx, input, output, None, &encoder_args)
 }
 
-/// Converts input to output format (based on the extension of output path)
-pub fn convert_to_fileformat(input: &Path, output: &Path) -> Result<(), anyhow::Error> {
+/// How many times ffmpeg is run on a file before giving up, when it fails in a way that might not happen again
+const FFMPEG_ATTEMPTS: u32 = 3;
+
+/// How far the length of a conversion can be from the flac's before it's taken for a broken one
+const DURATION_TOLERANCE_SECS: f64 = 0.5;
+
+/// Converts input to output format (based on the extension of output path), through an audio filter chain and with
+/// the given encoder options
+///
+/// ffmpeg writes to a temporary file next to `output` (`.tmp.<name>`), which only becomes `output` once ffmpeg has
+/// finished and the file has about the length of the flac (see [`check_conversion`]).  A conversion that's interrupted
+/// or fails leaves nothing at `output`, so it can't be mistaken for a finished one, and what was there stays.
+///
+/// If ffmpeg couldn't start or was killed (rather than giving up on the file), it's tried again a couple of times.
+pub fn convert_with_filters(
+    ctx: &RunContext, input: &Path, output: &Path, filters: Option<&str>, encoder_args: &[String],
+) -> Result<(), CbError> {
     // create the output directory if needed
     let parent = output.parent().expect("no parent");
     if !parent.exists() {
-        std::fs::create_dir_all(&parent)?;
+        std::fs::create_dir_all(parent)
+            .map_err(|e| CbError::io(format!("Failed to create {}", parent.display()), e))?;
     }
-
-    let mut ffmpeg = Command::new("ffmpeg")
-        .arg("-i")
-        .arg(input)
-        .arg(output)
-        .stdout(Stdio::null())
-        .spawn()?;
-
-    let exit_status = ffmpeg.wait()?;
-    if exit_status.success() {
-        Ok(())
-    } else {
-        bail!("ffmpeg returned {:?}", exit_status)
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = output.with_file_name(format!("{}{}", TEMP_PREFIX, name));
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        // left behind by a run that was killed, and ffmpeg would ask before overwriting it
+        let _ = std::fs::remove_file(&tmp);
+        let mut ffmpeg = ffmpeg_command(ctx, input, &tmp, filters, encoder_args);
+        ffmpeg.stdout(Stdio::null());
+        let status = if ctx.jobs > 1 {
+            // other conversions might be running, so what ffmpeg says is kept and only shown (in one piece) if it fails
+            ffmpeg.stderr(Stdio::piped()).output().map(|out| {
+                if !out.status.success() && !out.stderr.is_empty() {
+                    say!(
+                        "ffmpeg converting {}:\n{}",
+                        input.display(),
+                        String::from_utf8_lossy(&out.stderr).trim_end()
+                    );
+                }
+                out.status
+            })
+        } else {
+            ffmpeg.status()
+        };
+        let (transient, error) = match status {
+            Ok(exit_status) if exit_status.success() => {
+                let finished = check_conversion(ctx, input, &tmp).and_then(|info| {
+                    std::fs::rename(&tmp, output).map_err(|e| {
+                        CbError::io(format!("Failed to move the conversion to {}", output.display()), e)
+                    })?;
+                    // the rename keeps the size and the modification time, so the next load doesn't look at it again
+                    if let Some(cache) = &ctx.media_cache {
+                        cache.insert(output, &info);
+                    }
+                    Ok(())
+                });
+                if finished.is_err() {
+                    let _ = std::fs::remove_file(&tmp);
+                }
+                return finished;
+            }
+            // no exit code means a signal, like the OOM killer's
+            Ok(exit_status) => (
+                exit_status.code().is_none(),
+                CbError::tool(
+                    "ffmpeg",
+                    format!("ffmpeg returned {:?} converting {}", exit_status, input.display()),
+                ),
+            ),
+            Err(e) => (
+                matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::OutOfMemory
+                ),
+                CbError::tool("ffmpeg", e),
+            ),
+        };
+        let _ = std::fs::remove_file(&tmp);
+        if !transient || attempt >= FFMPEG_ATTEMPTS {
+            return Err(error);
+        }
+        let delay = Duration::from_secs(2 * attempt as u64);
+        progress::emit(ProgressEvent::Warning {
+            stage: Stage::Convert,
+            message: format!(
+                "ffmpeg failed converting {} (attempt {}), retrying in {:?}",
+                input.display(),
+                attempt,
+                delay
+            ),
+        });
+        std::thread::sleep(delay);
     }
 }
 
-#[derive(Debug)]
-struct AudioFile {
-    pub orig_path: PathBuf,
-    pub orig_size_bytes: u64,
-    pub ogg_path: PathBuf,
-    pub ogg_size_bytes: u64,
-    pub duration: Duration,
-    pub format_str: String,
+/// Checks that ffmpeg made something at `converted` that's as long as the flac it was made from, returning its media
+/// info
+fn check_conversion(ctx: &RunContext, input: &Path, converted: &Path) -> Result<MediaInfo, CbError> {
+    let broken = |reason: String| CbError::tool("ffmpeg", format!("the conversion of {} {}", input.display(), reason));
+    match converted.metadata() {
+        Ok(meta) if meta.len() > 0 => {}
+        Ok(_) => return Err(broken("came out empty".to_string())),
+        Err(e) => return Err(CbError::io(format!("ffmpeg didn't write {}", converted.display()), e)),
+    }
+    let expected = MediaInfo::new(ctx, input)?.duration_secs().ok();
+    let info = MediaInfo::probe(ctx, converted)?;
+    let actual = info
+        .duration_secs()
+        .map_err(|e| broken(format!("can't be read: {}", e)))?;
+    match expected {
+        Some(expected) if (expected - actual).abs() > DURATION_TOLERANCE_SECS => Err(broken(format!(
+            "is {:.1}s long, but the flac is {:.1}s",
+            actual, expected
+        ))),
+        _ => Ok(info),
+    }
 }
-impl AudioFile {
-    pub fn filename(&self) -> String {
-        format!("{}", self.orig_path.file_stem().unwrap().to_string_lossy())
+
+/// The ffmpeg command line for a conversion, as recorded in the provenance (it's run with the temporary file that
+/// becomes `output`, see [`convert_with_filters`])
+fn ffmpeg_command(
+    ctx: &RunContext, input: &Path, output: &Path, filters: Option<&str>, encoder_args: &[String],
+) -> Command {
+    let mut ffmpeg = Command::new(&ctx.tools.ffmpeg);
+    ffmpeg.arg("-i").arg(input);
+    if let Some(filters) = filters {
+        ffmpeg.arg("-af").arg(filters);
     }
+    ffmpeg.args(encoder_args);
+    ffmpeg.arg(output);
+    ffmpeg
 }
 
 // #[derive(Deserialize)]
@@ -122,7 +679,7 @@ impl AudioFile {
 // }
 
 /// MediaInfo for the flac track
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct MediaInfo {
     #[serde(rename = "@type")]
     pub t: String,
@@ -132,317 +689,1064 @@ pub struct MediaInfo {
     pub channels: String,
     #[serde(rename = "SamplingRate")]
     pub sample_rate: String,
-    #[serde(rename = "BitDepth")]
+    #[serde(rename = "BitDepth", default)]
     pub bit_depth: String,
     #[serde(rename = "Duration")]
     pub duration: String,
+    /// Bits per second.  Not known for flacs read without mediainfo, or for metadata from older versions
+    #[serde(rename = "BitRate", default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<String>,
 }
 
 impl MediaInfo {
     /// Get technical info about a piece of media
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<MediaInfo, anyhow::Error> {
+    ///
+    /// Flac files are read directly, and anything else (or any flac we can't read) is passed to mediainfo, or to
+    /// ffprobe if mediainfo isn't installed (see `media_info_backend` in the config)
+    pub fn new<P: AsRef<Path>>(ctx: &RunContext, path: P) -> Result<MediaInfo, CbError> {
         let path = path.as_ref();
 
         // make sure the path exists first
         if !path.exists() {
-            bail!("Path {} does not exist", path.display());
+            return Err(CbError::MissingFile {
+                path: path.to_path_buf(),
+            });
         }
 
-        let mediainfo = Command::new("mediainfo")
-            .arg("--Output=JSON")
-            .arg(path)
-            .stdout(Stdio::piped())
-            .spawn()?;
+        if let Some(cached) = ctx.media_cache.as_ref().and_then(|c| c.get(path)) {
+            return Ok(cached);
+        }
 
-        let output = mediainfo.wait_with_output()?;
+        let flac_info = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("flac")) {
+            flac::StreamInfo::read(path).ok().and_then(|si| si.to_media_info())
+        } else {
+            None
+        };
+        let media_info = match flac_info {
+            Some(media_info) => media_info,
+            None => MediaInfo::probe(ctx, path)?,
+        };
+
+        if let Some(cache) = &ctx.media_cache {
+            cache.insert(path, &media_info);
+        }
+        Ok(media_info)
+    }
 
-        let output = String::from_utf8_lossy(&output.stdout);
+    /// Runs mediainfo or ffprobe (whichever the config says) on a file, without the cache
+    fn probe(ctx: &RunContext, path: &Path) -> Result<MediaInfo, CbError> {
+        match ctx.resolved_media_info_backend() {
+            MediaInfoBackend::Ffprobe => MediaInfo::from_ffprobe(ctx, path).map_err(|e| CbError::tool("ffprobe", e)),
+            _ => MediaInfo::from_mediainfo(ctx, path).map_err(|e| CbError::tool("mediainfo", e)),
+        }
+    }
+
+    /// Get technical info about a piece of media by running mediainfo
+    pub fn from_mediainfo(ctx: &RunContext, path: &Path) -> Result<MediaInfo, anyhow::Error> {
+        let output = command::run_with_timeout(
+            Command::new(&ctx.tools.mediainfo).arg("--Output=JSON").arg(path),
+            ctx.tool_timeout,
+        )
+        .with_context(|| format!("Failed to get media info for {}", path.display()))?;
+
+        if !output.status.success() {
+            bail!(
+                "mediainfo returned {} for {}: {}",
+                output.status,
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
 
-        let json: Value = serde_json::from_str(&output)?;
+        let json: Value = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("mediainfo gave invalid JSON for {}", path.display()))?;
 
+        let mut found_types = Vec::new();
         if let Value::Object(mut map) = json {
             if let Some(Value::Object(mut map)) = map.remove("media") {
                 if let Some(Value::Array(arr)) = map.remove("track") {
                     for arr in arr {
                         if let Value::Object(ref obj) = arr {
-                            if obj
-                                .get("@type")
-                                .and_then(|obj| obj.as_str())
-                                .map_or(false, |s| s == "Audio")
-                            {
-                                let media_info: MediaInfo = serde_json::from_value(arr)?;
+                            let t = obj.get("@type").and_then(|obj| obj.as_str()).unwrap_or("unknown");
+                            if t == "Audio" {
+                                let media_info: MediaInfo = serde_json::from_value(arr)
+                                    .with_context(|| format!("Unexpected media info for {}", path.display()))?;
                                 return Ok(media_info);
                             }
+                            found_types.push(t.to_string());
                         }
                     }
                 }
             }
         }
 
-        bail!("Failed to find media info data")
-    }
-}
-
-use askama::Template;
-
-#[derive(Template)]
-#[template(path = "season_index.html")]
-pub struct SeasonIndexTemplate<'a> {
-    gitlab_review: String,
-    season: &'a Season,
-    tag_list: Vec<&'a str>,
-}
-
-#[derive(Template)]
-#[template(path = "recording_index.html")]
-pub struct RecordingIndexTemplate<'a> {
-    gitlab_review: String,
-    season: &'a Season,
-    recording: &'a Recording,
-}
-
-// impl From<&AudioFile> for AudioFileHB {
-//     fn from(af: &AudioFile) -> Self {
-//         AudioFileHB {
-//             filename_url: af.filename().replace(' ', "%20"),
-//             filename: af.filename(),
-//             format: af.format_str.clone(),
-//             duration: {
-//                 let sec = af.duration.as_secs();
-//                 if sec <= 59 {
-//                     format!("{}s", sec)
-//                 } else {
-//                     let min = (sec as f32 / 60.0).floor() as u64;
-//                     let sec = sec - (min * 60);
-//                     format!("{}m {}s", min, sec)
-//                 }
-//             },
-//             flac_size: format!("{}MB", af.orig_size_bytes / 1024 / 1024),
-//             ogg_size: format!("{}MB", af.ogg_size_bytes / 1024 / 1024),
-//         }
-//     }
-// }
-
-// handlebars_helper!(filename: |v: u32| f.filename());
+        bail!(
+            "Failed to find an Audio track in the media info for {} (found: {})",
+            path.display(),
+            if found_types.is_empty() {
+                "nothing".to_string()
+            } else {
+                found_types.join(", ")
+            }
+        )
+    }
 
-fn get_gitlab_review_string() -> String {
-    if let Ok(mr) = std::env::var("CI_MERGE_REQUEST_IID") {
-        format!(
-            r#"<script defer data-project-id="22680986" data-project-path="eminence/benderfactory" data-merge-request-id="{}" data-mr-url="https://gitlab.com" id="review-app-toolbar-script" src="https://gitlab.com/assets/webpack/visual_review_toolbar.js"></script>"#,
-            mr
+    /// Get technical info about a piece of media by running ffprobe
+    pub fn from_ffprobe(ctx: &RunContext, path: &Path) -> Result<MediaInfo, anyhow::Error> {
+        let output = command::run_with_timeout(
+            Command::new(&ctx.tools.ffprobe)
+                .args(["-v", "error", "-print_format", "json", "-show_streams", "-show_format"])
+                .arg(path),
+            ctx.tool_timeout,
         )
-    } else {
-        "".to_string()
+        .with_context(|| format!("Failed to get media info for {}", path.display()))?;
+
+        if !output.status.success() {
+            bail!(
+                "ffprobe returned {} for {}: {}",
+                output.status,
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        MediaInfo::from_ffprobe_json(&output.stdout)
+            .with_context(|| format!("Unexpected ffprobe output for {}", path.display()))
     }
-}
 
-fn copy_all_files<P: AsRef<Path>, T: AsRef<Path>>(from_dir: P, to_dir: T) -> Result<(), anyhow::Error> {
-    let from_dir = from_dir.as_ref();
-    let to_dir = to_dir.as_ref();
-    for file in from_dir.read_dir()? {
-        let file = file?;
-        let dst = to_dir.join(file.file_name());
+    /// Maps ffprobe's description of the first audio stream onto the fields mediainfo would have given us
+    fn from_ffprobe_json(json: &[u8]) -> anyhow::Result<MediaInfo> {
+        let json: Value = serde_json::from_slice(json).context("Invalid JSON")?;
+        let streams = json.get("streams").and_then(Value::as_array);
+        let stream = match streams.and_then(|s| s.iter().find(|s| s["codec_type"] == "audio")) {
+            Some(stream) => stream,
+            None => bail!("No audio stream found"),
+        };
+        let field = |name: &str| match &stream[name] {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        };
 
-        if file.file_type()?.is_file() {
-            let src = file.path().canonicalize()?;
-            println!("{:?} --> {:?}", src, dst);
-            std::fs::copy(src, dst)?;
-        } else if file.file_type()?.is_dir() {
-            std::fs::create_dir_all(&dst)?;
-            copy_all_files(file.path(), &dst)?;
-        }
+        let format = match field("codec_name").as_deref() {
+            Some("flac") => "FLAC".to_string(),
+            Some("vorbis") => "Vorbis".to_string(),
+            Some("opus") => "Opus".to_string(),
+            Some("mp3") => "MPEG Audio".to_string(),
+            Some(other) => other.to_uppercase(),
+            None => bail!("Audio stream has no codec"),
+        };
+        let channels = field("channels").context("Audio stream has no channel count")?;
+        let sample_rate = field("sample_rate").context("Audio stream has no sample rate")?;
+        // lossy codecs report 0 bits per sample
+        let bit_depth = field("bits_per_raw_sample").filter(|b| b != "0").unwrap_or_default();
+        // flacs and oggs may only have a duration and bitrate for the whole file
+        let duration = field("duration")
+            .or_else(|| json["format"]["duration"].as_str().map(str::to_string))
+            .context("Audio stream has no duration")?;
+        let duration = parse_duration(&duration).with_context(|| format!("Invalid duration {:?}", duration))?;
+        let bitrate = field("bit_rate").or_else(|| json["format"]["bit_rate"].as_str().map(str::to_string));
+
+        Ok(MediaInfo {
+            t: "Audio".to_string(),
+            format,
+            channels,
+            sample_rate,
+            bit_depth,
+            // rounded to the millisecond, like mediainfo
+            duration: format!("{:.3}", duration.as_secs_f64()),
+            bitrate,
+        })
     }
 
-    Ok(())
-}
+    pub fn channels(&self) -> anyhow::Result<u8> {
+        first_number("Channels", &self.channels)
+    }
+
+    pub fn sample_rate(&self) -> anyhow::Result<u32> {
+        let rate: f64 = first_number("SamplingRate", &self.sample_rate)?;
+        Ok(rate.round() as u32)
+    }
 
-pub fn write_season_index(season: &Season, output_root: &Path) -> Result<(), anyhow::Error> {
-    let mut tag_set = HashSet::new();
-    for rec in &season.recordings {
-        for tag in &rec.tags {
-            tag_set.insert(tag.as_ref());
+    pub fn duration_secs(&self) -> anyhow::Result<f64> {
+        match parse_duration(&self.duration) {
+            Some(duration) => Ok(duration.as_secs_f64()),
+            None => bail!("Can't read a duration from {:?}", self.duration),
         }
-        // tag_set.extend(rec.tags.as_ref());
     }
 
-    // convert tag_set to a vec and sort, so that the output is deterministic
-    let mut tag_list: Vec<_> = tag_set.into_iter().collect();
-    tag_list.sort();
+    /// Lossy formats (like ogg) don't have a bit depth
+    pub fn bit_depth(&self) -> Option<u8> {
+        first_number("BitDepth", &self.bit_depth).ok()
+    }
 
-    let context = SeasonIndexTemplate {
-        season,
-        tag_list,
-        gitlab_review: get_gitlab_review_string(),
-    };
+    pub fn bitrate_kbps(&self) -> Option<u32> {
+        let bitrate: f64 = first_number("BitRate", self.bitrate.as_deref()?).ok()?;
+        Some((bitrate / 1000.0).round() as u32)
+    }
+}
 
-    std::fs::create_dir_all(output_root)?;
-    let f = output_root.join("index.html");
-    let mut output = File::create(&f)?;
+/// Parses a duration from mediainfo or ffprobe
+///
+/// Accepts plain seconds ("371.760", or "371,760" from some locales) and clock times ("01:02:03.456" or "02:03.456").
+/// Like other mediainfo fields, only the first of several values ("371.760 / 371.755") is used.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.split(" / ").next()?.trim().replace(',', ".");
+    let mut parts = value.rsplit(':');
+    let secs: f64 = parts.next()?.parse().ok()?;
+    let mut total = secs;
+    for (part, scale) in parts.zip([60.0, 3600.0]) {
+        let n: u32 = part.parse().ok()?;
+        total += f64::from(n) * scale;
+    }
+    // at most hours, minutes and seconds, and anything but the hours has to be less than 60
+    if value.matches(':').count() > 2 || (value.contains(':') && secs >= 60.0) {
+        return None;
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
 
-    let rendered: String = context.render()?;
-    output.write_all(rendered.as_bytes())?;
+/// Parses the first number in a mediainfo field
+///
+/// mediainfo sometimes gives more than one value for a field (like "2 / 1" for channels), so only the first is used.
+/// Like [`parse_duration`], it takes a decimal comma ("44,1") from some locales.
+fn first_number<T: std::str::FromStr>(field: &str, value: &str) -> anyhow::Result<T> {
+    let value = value.trim_start();
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == ','))
+        .unwrap_or(value.len());
+    match value[..end].replace(',', ".").parse() {
+        Ok(n) => Ok(n),
+        Err(_) => bail!("Can't read a number from {} {:?}", field, value),
+    }
+}
 
-    copy_all_files("static/", &output_root)?;
+/// `value / unit` with one decimal, like "1.5", rounded half up
+///
+/// This is worked out in whole numbers, so that sizes and times come out the same on every machine.
+pub(crate) fn one_decimal(value: u64, unit: u64) -> String {
+    let tenths = (u128::from(value) * 10 + u128::from(unit) / 2) / u128::from(unit);
+    format!("{}.{}", tenths / 10, tenths % 10)
+}
 
-    println!("Write season index to {}", f.display());
+/// A size like "12.3MB", or "1.2GB" from a gigabyte up
+pub(crate) fn human_size(bytes: u64) -> String {
+    const GB: u64 = 1024 * 1024 * 1024;
+    if bytes >= GB {
+        format!("{}GB", one_decimal(bytes, GB))
+    } else {
+        format!("{}MB", one_decimal(bytes, 1024 * 1024))
+    }
+}
 
-    Ok(())
+/// A path as a string with `/` between its parts, whatever the platform uses
+pub(crate) fn slashed(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    match std::path::MAIN_SEPARATOR {
+        '/' => path.into_owned(),
+        separator => path.replace(separator, "/"),
+    }
 }
 
-pub fn write_all_recording_index(season: &Season, output_root: &Path) -> Result<(), anyhow::Error> {
-    let mut m3u = File::create(output_root.join("playlist.m3u"))?;
+/// For `#[serde(serialize_with)]` on paths that end up in JSON, see [`slashed`]
+pub(crate) mod serialize_slashed {
+    use std::path::{Path, PathBuf};
 
-    writeln!(m3u, "#EXTM3U")?;
+    use serde::Serializer;
 
+    pub fn path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::slashed(path))
+    }
+
+    pub fn option<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+        match path {
+            Some(path) => serializer.serialize_some(&super::slashed(path)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn paths<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| super::slashed(path)))
+    }
+}
+
+/// Reads the metadata cache of a season, from a metadata.json or an index (see [`chunked_metadata`])
+pub fn load_metadata(path: &Path) -> anyhow::Result<Season> {
+    let path = &chunked_metadata::existing(path);
+    if chunked_metadata::is_index(path) {
+        return chunked_metadata::load(path);
+    }
+    let f = File::open(path).with_context(|| format!("Failed to open metadata file {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("Failed to read metadata file {}", path.display()))
+}
+
+/// Writes the metadata cache for a season
+///
+/// An existing metadata file that lists more recordings than `season` won't be replaced unless `force` is set, because
+/// that usually means the season was only partially loaded.  When `path` is an index, the metadata is split up (see
+/// [`chunked_metadata`]).
+pub fn write_metadata(season: &Season, path: &Path, force: bool) -> anyhow::Result<()> {
+    if chunked_metadata::is_index(path) {
+        return chunked_metadata::write(season, path, force);
+    }
+    let mut writer = MetadataWriter::create(path, &season.generator, &season.title, &season.artist)?;
     for recording in &season.recordings {
-        let context = RecordingIndexTemplate {
-            season,
-            recording,
-            gitlab_review: get_gitlab_review_string(),
+        writer.push(recording)?;
+    }
+    writer.finish(&season.redirects, season.low_quality_ogg.as_ref(), force)
+}
+
+/// Writes a metadata cache one recording at a time, so that a huge season never has to be serialized all at once
+///
+/// The file is the same as [`write_metadata`] would write for a season with those recordings.  It's written next to
+/// `path` and only replaces it in [`MetadataWriter::finish`], so a run that dies halfway leaves the old one.
+pub struct MetadataWriter {
+    path: PathBuf,
+    tmp: PathBuf,
+    writer: Option<std::io::BufWriter<File>>,
+    recordings: usize,
+}
+
+impl MetadataWriter {
+    /// Starts the metadata for a season, which takes the rest of [`Season`]'s fields when it's finished
+    pub fn create(path: &Path, generator: &str, title: &str, artist: &str) -> anyhow::Result<MetadataWriter> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+        let f = File::create(&tmp).with_context(|| format!("Failed to create metadata file {}", path.display()))?;
+        let mut writer = MetadataWriter {
+            path: path.to_path_buf(),
+            tmp,
+            writer: Some(std::io::BufWriter::new(f)),
+            recordings: 0,
         };
+        writer.write(|w| {
+            w.write_all(b"{\"generator\":")?;
+            serde_json::to_writer(&mut *w, generator)?;
+            w.write_all(b",\"title\":")?;
+            serde_json::to_writer(&mut *w, title)?;
+            w.write_all(b",\"artist\":")?;
+            serde_json::to_writer(&mut *w, artist)?;
+            Ok(w.write_all(b",\"recordings\":[")?)
+        })?;
+        Ok(writer)
+    }
 
-        std::fs::create_dir_all(output_root.join(&recording.data_folder))?;
-        let f = output_root.join(&recording.data_folder).join("index.html");
-        let mut output = File::create(&f)?;
+    /// Adds the next recording
+    pub fn push(&mut self, recording: &types::Recording) -> anyhow::Result<()> {
+        let first = self.recordings == 0;
+        self.write(|w| {
+            if !first {
+                w.write_all(b",")?;
+            }
+            Ok(serde_json::to_writer(w, recording)?)
+        })?;
+        self.recordings += 1;
+        Ok(())
+    }
 
-        let rendered: String = context.render()?;
-        output.write_all(rendered.as_bytes())?;
+    /// Finishes the file and puts it in place, unless it would replace one with more recordings and `force` isn't set
+    pub fn finish(
+        mut self, redirects: &std::collections::BTreeMap<String, String>, low_quality_ogg: Option<&types::OggProfile>,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        self.write(|w| {
+            w.write_all(b"],\"redirects\":")?;
+            serde_json::to_writer(&mut *w, redirects)?;
+            w.write_all(b",\"low_quality_ogg\":")?;
+            serde_json::to_writer(&mut *w, &low_quality_ogg)?;
+            w.write_all(b"}")?;
+            Ok(w.flush()?)
+        })?;
+        drop(self.writer.take());
+
+        #[derive(Deserialize)]
+        struct OldSeason {
+            recordings: Vec<serde::de::IgnoredAny>,
+        }
 
-        std::fs::copy("static/style.css", f.with_file_name("style.css"))?;
-        std::fs::copy("static/ToS.txt", f.with_file_name("ToS.txt"))?;
+        if !force && self.path.exists() {
+            let old: Option<OldSeason> = File::open(&self.path)
+                .ok()
+                .and_then(|f| serde_json::from_reader(std::io::BufReader::new(f)).ok());
+            if let Some(old) = old {
+                if old.recordings.len() > self.recordings {
+                    bail!(
+                        "Refusing to overwrite {} ({} recordings) with a season that only has {} recordings. \
+                         Use --force-metadata if this is intended",
+                        self.path.display(),
+                        old.recordings.len(),
+                        self.recordings
+                    );
+                }
+            }
+        }
 
-        println!("Wrote recording index to {}", f.display());
+        std::fs::rename(&self.tmp, &self.path)
+            .with_context(|| format!("Failed to write metadata file {}", self.path.display()))
+    }
 
-        let duration: f32 = recording.stereo_mix.media_info.duration.parse()?;
-        writeln!(
-            m3u,
-            "#EXTINF:{},Colin Benders - {}",
-            duration.round() as u32,
-            recording.title
-        )?;
-        writeln!(
-            m3u,
-            "https://ipfs.io/ipns/mm.em32.net/{}/{}",
-            recording.data_folder,
-            recording.stereo_mix.vorbis.replace(' ', "%20")
-        )?;
+    fn write(&mut self, f: impl FnOnce(&mut std::io::BufWriter<File>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let writer = self.writer.as_mut().expect("the metadata is still being written");
+        f(writer).with_context(|| format!("Failed to write metadata file {}", self.path.display()))
     }
+}
 
-    Ok(())
+impl Drop for MetadataWriter {
+    fn drop(&mut self) {
+        // a metadata file that was never finished, or wasn't wanted, is thrown away
+        drop(self.writer.take());
+        let _ = std::fs::remove_file(&self.tmp);
+    }
+}
+
+/// Reports a validation error as a progress event (the human readable version is printed separately)
+fn validation_error(message: String) {
+    progress::emit(ProgressEvent::Error {
+        stage: Stage::Validate,
+        message,
+    });
+}
+
+/// What validating a season found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ValidationReport {
+    /// Recordings that were checked
+    pub recordings: usize,
+    pub errors: usize,
+    pub warnings: usize,
 }
 
 /// Returns the number of errors found
-pub fn validate_and_print(json_path: &Path, data_dir: &Path) -> anyhow::Result<usize> {
-    let mut errors = 0;
+pub fn validate_and_print(ctx: &RunContext, json_path: &Path, data_dir: &Path) -> Result<usize, CbError> {
+    validate(ctx, json_path, data_dir).map(|report| report.errors)
+}
+
+/// Checks a season against its data dir, saying what's wrong as it goes
+pub fn validate(ctx: &RunContext, json_path: &Path, data_dir: &Path) -> Result<ValidationReport, CbError> {
+    ctx.stage(Stage::Validate, || validate_season(ctx, json_path, data_dir))
+}
 
-    let json_root = json_path.parent().unwrap();
+fn validate_season(ctx: &RunContext, json_path: &Path, data_dir: &Path) -> Result<ValidationReport, CbError> {
+    let mut errors = 0;
+    let mut warnings = 0;
 
     let season = get_validated_json(json_path)?;
-    let season: types::SeasonInner = serde_json::from_value(season)?;
+    let season: types::SeasonInner = serde_json::from_value(season)
+        .map_err(|e| CbError::parse(format!("Unexpected contents in {}", json_path.display()), e))?;
 
     // let mut stdout = StandardStream::stdout(colors);
 
     // stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
     // writeln!(stdout, "Checking Season {:?}:", season.title)?;
     // stdout.reset()?;
-    println!("Checking season {}:", season.title.green());
+    say!("Checking season {}:", season.title.green());
+
+    // say!("{:#?}", season);
+
+    let listed = season.recording_paths(json_path)?;
+    let total = listed.len();
+    let mut pages = Vec::new();
+    let mut relations = Vec::new();
+    let mut unreadable = 0;
+    for (index, listed) in listed.into_iter().enumerate() {
+        progress::emit(ProgressEvent::ItemProcessed {
+            stage: Stage::Validate,
+            item: listed.name.clone(),
+            index: index + 1,
+            total,
+        });
+        match listed.chain.last().filter(|_| listed.chain.len() > 1) {
+            Some(include) => say!(
+                "\n  Reading recording {} (from {})...",
+                listed.name.yellow(),
+                include.display()
+            ),
+            None => say!("\n  Reading recording {}...", listed.name.yellow()),
+        }
+        let recording_path = &listed.path;
+        let recording: RecordingInner = match get_validated_json(recording_path).and_then(|json| {
+            serde_json::from_value(json)
+                .map_err(|e| CbError::parse(format!("Unexpected contents in {}", recording_path.display()), e))
+        }) {
+            Ok(recording) => recording,
+            // the recordings that use other schemas can still be checked
+            Err(e @ CbError::InvalidSchema { .. }) => {
+                say!(" {}: {}", "ERROR".red(), e);
+                validation_error(e.to_string());
+                errors += 1;
+                unreadable += 1;
+                continue;
+            }
+            Err(e) if ctx.keep_going => {
+                say!(" {}: {:#}", "ERROR".red(), anyhow::Error::new(listed.context(e)));
+                validation_error(format!("{} couldn't be loaded", recording_path.display()));
+                errors += 1;
+                unreadable += 1;
+                continue;
+            }
+            Err(e) => return Err(listed.context(e)),
+        };
+
+        if recording.draft {
+            say!("  {} is a draft, skipping file checks", recording.title.cyan());
+            continue;
+        }
+        pages.push((
+            slug::for_recording(recording.slug.as_deref(), &recording.title, &recording.data_folder),
+            recording.data_folder.clone(),
+        ));
+        if !recording.related.is_empty() {
+            relations.push((recording.data_folder.clone(), recording.related.clone()));
+        }
 
-    // println!("{:#?}", season);
+        if season.artist.is_none() && recording.artist.is_none() {
+            let e = CbError::MissingArtist {
+                recording: recording.title.clone(),
+            };
+            say!(" {}: {}", "ERROR".red(), e);
+            validation_error(e.to_string());
+            errors += 1;
+        }
 
-    for recording in season.recordings {
-        println!("\n  Reading recording {}...", recording.yellow());
-        let recording = get_validated_json(&json_root.join(recording))?;
-        let recording: RecordingInner = serde_json::from_value(recording)?;
+        // publishing the recording at all means sharing its stereo mix
+        if recording.stereo_mix.usage == types::Usage::ListenOnly {
+            let message = format!(
+                "The stereo mix of {} is listen_only, but it's what the recording is published as",
+                recording.title
+            );
+            say!(" {}: {}", "ERROR".red(), message);
+            validation_error(message);
+            errors += 1;
+        }
 
         // each recording specifies their own local data folder relative to the global data_root
         let data_dir = data_dir.join(recording.data_folder);
 
-        let stereo_mix = data_dir.join(&recording.stereo_mix.vorbis());
-        if !stereo_mix.exists() {
-            println!(
+        // a flac-only recording has no ogg, so its flac is the stereo mix file
+        let stereo_mix = match recording.stereo_mix.vorbis() {
+            Some(ogg) => types::linked_file(&data_dir, ogg),
+            None => Some(data_dir.join(&recording.stereo_mix.flac)),
+        };
+        if let Some(stereo_mix) = stereo_mix.filter(|p| !p.exists()) {
+            say!(
                 " {}: Stereo mix file doesn't exist {}",
                 "ERROR".red(),
                 format!("{}", stereo_mix.display()).yellow()
             );
+            validation_error(format!("Stereo mix file doesn't exist {}", stereo_mix.display()));
             errors += 1;
-        } else {
-            // println!("  {} Stereo mix", "OK".green());
         }
-        if let Some(mp3) = recording.stereo_mix.mp3() {
-            let mp3 = data_dir.join(mp3);
+        if let Some(mp3) = recording
+            .stereo_mix
+            .mp3()
+            .and_then(|mp3| types::linked_file(&data_dir, mp3))
+        {
             if !mp3.exists() {
-                println!(
+                say!(
                     " {}: Stereo mix mp3 file doesn't exist {}",
                     "ERROR".red(),
                     format!("{}", mp3.display()).yellow()
                 );
+                validation_error(format!("Stereo mix mp3 file doesn't exist {}", mp3.display()));
+                errors += 1;
+            }
+        }
+        if let Some(opus) = recording
+            .stereo_mix
+            .opus()
+            .and_then(|opus| types::linked_file(&data_dir, opus))
+        {
+            if !opus.exists() {
+                say!(
+                    " {}: Stereo mix opus file doesn't exist {}",
+                    "ERROR".red(),
+                    format!("{}", opus.display()).yellow()
+                );
+                validation_error(format!("Stereo mix opus file doesn't exist {}", opus.display()));
+                errors += 1;
+            }
+        }
+        let lq = recording
+            .stereo_mix
+            .lq_ogg()
+            .and_then(|lq| types::linked_file(&data_dir, lq));
+        if let (Some(_), Some(lq)) = (&season.low_quality_ogg, lq) {
+            if !lq.exists() {
+                say!(
+                    " {}: Stereo mix low-quality ogg doesn't exist {}",
+                    "ERROR".red(),
+                    format!("{}", lq.display()).yellow()
+                );
+                validation_error(format!("Stereo mix low-quality ogg doesn't exist {}", lq.display()));
                 errors += 1;
             }
         }
 
-        if let Some(torrent) = &recording.torrent {
-            let torrent_file = data_dir.join(torrent);
+        if let Some(url) = &recording.youtube_url {
+            if youtube::parse(url).is_none() {
+                say!(
+                    " {}: youtube_url isn't a youtube.com/watch or youtu.be link to a video {}",
+                    "ERROR".red(),
+                    url.yellow()
+                );
+                validation_error(format!("youtube_url isn't a link to a video: {}", url));
+                errors += 1;
+            }
+        }
+
+        for mirror in &recording.mirrors {
+            if let Some(problem) = mirror.problem() {
+                say!(" {}: mirror: {}", "ERROR".red(), problem.yellow());
+                validation_error(format!("mirror: {}", problem));
+                errors += 1;
+            }
+        }
+
+        // a torrent can also be a link to a tracker
+        if let Some(torrent_file) = recording
+            .torrent
+            .as_ref()
+            .and_then(|t| types::linked_file(&data_dir, t))
+        {
             if !torrent_file.exists() {
-                println!(
+                say!(
                     " {}: torrent file doesn't exist {}",
                     "ERROR".red(),
                     format!("{}", torrent_file.display()).yellow()
                 );
+                validation_error(format!("torrent file doesn't exist {}", torrent_file.display()));
                 errors += 1;
             } else {
-                println!("  {} torrent file", "OK".green());
+                say!("  {} torrent file", "OK".green());
+            }
+        }
+
+        if let Some(tos) = &recording.tos {
+            let tos_file = data_dir.join(tos);
+            if !tos_file.is_file() {
+                say!(
+                    " {}: terms of service file doesn't exist {}",
+                    "ERROR".red(),
+                    format!("{}", tos_file.display()).yellow()
+                );
+                validation_error(format!("terms of service file doesn't exist {}", tos_file.display()));
+                errors += 1;
+            } else {
+                say!("  {} own terms of service", "OK".green());
+            }
+        }
+
+        let readme = data_dir.join(types::DESCRIPTION_FILE);
+        if recording.description.is_some() && readme.exists() {
+            say!(
+                " {}: `{}` has a description in its JSON and in {}, remove one of them",
+                "ERROR".red(),
+                recording.title,
+                format!("{}", readme.display()).yellow()
+            );
+            validation_error(format!(
+                "`{}` has a description in its JSON and in {}",
+                recording.title,
+                readme.display()
+            ));
+            errors += 1;
+        }
+
+        // sources that keep failing to convert are skipped until someone has looked at them
+        for (source, failed) in quarantine::Quarantine::load(&data_dir)?.quarantined() {
+            say!(
+                " {}: {} is in quarantine after failing to convert {} times: {}",
+                "ERROR".red(),
+                format!("{}", data_dir.join(source).display()).yellow(),
+                failed.failures,
+                failed.error
+            );
+            validation_error(format!(
+                "{} is in quarantine after failing to convert {} times (clear it with --retry-quarantined): {}",
+                data_dir.join(source).display(),
+                failed.failures,
+                failed.error
+            ));
+            errors += 1;
+        }
+
+        // a group with a single track is usually a typo in the name of another group
+        for track in &recording.tracks {
+            if let Some(group) = &track.group {
+                if recording
+                    .tracks
+                    .iter()
+                    .filter(|t| t.group.as_ref() == Some(group))
+                    .count()
+                    == 1
+                {
+                    say!(
+                        "  {}: track {} is the only one in the group {:?}",
+                        "WARNING".yellow(),
+                        track.id,
+                        group
+                    );
+                    progress::emit(ProgressEvent::Warning {
+                        stage: Stage::Validate,
+                        message: format!(
+                            "`{}` track {} is the only one in the group {:?}",
+                            recording.title, track.id, group
+                        ),
+                    });
+                    warnings += 1;
+                }
             }
         }
 
-        println!("  Tracks for {}:", recording.title.cyan());
+        for (i, track) in recording.tracks.iter().enumerate() {
+            if recording.tracks[..i].iter().any(|t| t.id == track.id) {
+                say!(
+                    "  {}: more than one track has the id {}, so links to #track-{} only find the first",
+                    "WARNING".yellow(),
+                    track.id,
+                    track.id
+                );
+                progress::emit(ProgressEvent::Warning {
+                    stage: Stage::Validate,
+                    message: format!("`{}` has more than one track with the id {}", recording.title, track.id),
+                });
+                warnings += 1;
+            }
+        }
+
+        say!("  Tracks for {}:", recording.title.cyan());
 
-        // println!("{:#?}", recording);
+        // say!("{:#?}", recording);
 
         for track in &recording.tracks {
-            println!("    Checking track {}", format!("{}", track.id).cyan());
+            say!("    Checking track {}", format!("{}", track.id).cyan());
             let flac_path = data_dir.join(&track.flac);
             if !flac_path.exists() {
-                println!(
+                say!(
                     "      {}: Flac file for `{}` track {} does not exist ({})",
                     "ERROR".red(),
                     recording.title,
                     track.id,
                     flac_path.display()
                 );
+                validation_error(format!(
+                    "Flac file for `{}` track {} does not exist ({})",
+                    recording.title,
+                    track.id,
+                    flac_path.display()
+                ));
                 errors += 1;
             } else {
-                println!("      {} Flac orginal", "OK".green());
+                say!("      {} Flac orginal", "OK".green());
+                // the length is only used for display, so a strange one isn't an error
+                if let Err(e) = MediaInfo::new(ctx, &flac_path)
+                    .map_err(anyhow::Error::new)
+                    .and_then(|info| info.duration_secs())
+                {
+                    say!("      {}: {}: {:#}", "WARNING".yellow(), flac_path.display(), e);
+                    progress::emit(ProgressEvent::Warning {
+                        stage: Stage::Validate,
+                        message: format!("{}: {:#}", flac_path.display(), e),
+                    });
+                    warnings += 1;
+                }
             }
 
-            let ogg_path = data_dir.join(&track.vorbis());
-            if !ogg_path.exists() {
-                println!(
+            let ogg_path = track.vorbis().and_then(|ogg| types::linked_file(&data_dir, ogg));
+            if let Some(ogg_path) = ogg_path.filter(|p| !p.exists()) {
+                say!(
                     "      {}: OGG Vorbis file for `{}` track {} does not exist ({})",
                     "ERROR".red(),
                     recording.title,
                     track.id,
                     ogg_path.display()
                 );
+                validation_error(format!(
+                    "OGG Vorbis file for `{}` track {} does not exist ({})",
+                    recording.title,
+                    track.id,
+                    ogg_path.display()
+                ));
                 errors += 1;
-            } else {
-                // println!("      {} Ogg vorbis", "OK".green());
             }
 
-            if let Some(mp3) = track.mp3() {
-                let mp3 = data_dir.join(mp3);
+            if let Some(mp3) = track.mp3().and_then(|mp3| types::linked_file(&data_dir, mp3)) {
                 if !mp3.exists() {
-                    println!(
+                    say!(
                         "      {}: MP3 file for `{}` track {} does not exist ({})",
                         "ERROR".red(),
                         recording.title,
                         track.id,
                         mp3.display()
                     );
+                    validation_error(format!(
+                        "MP3 file for `{}` track {} does not exist ({})",
+                        recording.title,
+                        track.id,
+                        mp3.display()
+                    ));
+                    errors += 1;
+                }
+            }
+
+            if let Some(opus) = track.opus().and_then(|opus| types::linked_file(&data_dir, opus)) {
+                if !opus.exists() {
+                    say!(
+                        "      {}: Opus file for `{}` track {} does not exist ({})",
+                        "ERROR".red(),
+                        recording.title,
+                        track.id,
+                        opus.display()
+                    );
+                    validation_error(format!(
+                        "Opus file for `{}` track {} does not exist ({})",
+                        recording.title,
+                        track.id,
+                        opus.display()
+                    ));
                     errors += 1;
                 }
             }
         }
     }
 
-    Ok(errors)
+    // the other takes can be anywhere in the season, so they can only be checked once every recording has been read,
+    // and not at all when some couldn't be (they'd be the missing ones, which is already an error)
+    for (data_folder, references) in relations.iter().filter(|_| unreadable == 0) {
+        for problem in related::problems(data_folder, references, &pages) {
+            say!("\n {}: {}", "ERROR".red(), problem);
+            validation_error(problem);
+            errors += 1;
+        }
+    }
+
+    let pages: Vec<(&str, &str)> = pages.iter().map(|(s, d)| (s.as_str(), d.as_str())).collect();
+    if let Err(e) = slug::check(&pages) {
+        say!("\n {}: {}", "ERROR".red(), e);
+        validation_error(e.to_string());
+        errors += 1;
+    }
+
+    progress::emit(ProgressEvent::Summary {
+        stage: Stage::Validate,
+        processed: total,
+        errors,
+        warnings,
+    });
+    ctx.metrics.record_validation(errors, warnings);
+
+    Ok(ValidationReport {
+        recordings: total,
+        errors,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_info_numbers() {
+        let media_info = MediaInfo {
+            t: "Audio".to_string(),
+            format: "FLAC".to_string(),
+            channels: "2 / 1".to_string(),
+            sample_rate: "44100".to_string(),
+            bit_depth: "".to_string(),
+            duration: "371.760".to_string(),
+            bitrate: Some("192000".to_string()),
+        };
+        assert_eq!(media_info.channels().unwrap(), 2);
+        assert_eq!(media_info.sample_rate().unwrap(), 44100);
+        assert_eq!(media_info.duration_secs().unwrap(), 371.76);
+        assert_eq!(media_info.bit_depth(), None);
+        assert_eq!(media_info.bitrate_kbps(), Some(192));
+
+        let broken = MediaInfo {
+            channels: "stereo".to_string(),
+            ..media_info
+        };
+        assert!(broken.channels().is_err());
+
+        let broken = MediaInfo {
+            duration: "about a minute".to_string(),
+            ..broken
+        };
+        assert!(broken.duration_secs().is_err());
+    }
+
+    #[test]
+    fn atomic_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let playlist = dir.path().join("playlist.m3u");
+        write_atomically(&playlist, b"#EXTM3U\nold\n").unwrap();
+        write_atomically(&playlist, b"#EXTM3U\nnew\n").unwrap();
+        assert_eq!(std::fs::read(&playlist).unwrap(), b"#EXTM3U\nnew\n");
+
+        // a write that fails leaves nothing behind
+        assert!(write_atomically(&dir.path().join("missing/playlist.m3u"), b"x").is_err());
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["playlist.m3u"]);
+    }
+
+    #[test]
+    fn error_kinds() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+        assert!(matches!(
+            get_validated_json(&missing),
+            Err(CbError::MissingFile { path }) if path == missing
+        ));
+
+        let broken = dir.path().join("broken.json");
+        std::fs::write(&broken, "{ not json").unwrap();
+        assert!(matches!(get_validated_json(&broken), Err(CbError::Parse { .. })));
+
+        std::fs::write(
+            dir.path().join("schema.json"),
+            r#"{"type": "object", "required": ["title"]}"#,
+        )
+        .unwrap();
+        let invalid = dir.path().join("invalid.json");
+        std::fs::write(&invalid, r#"{"$schema": "./schema.json"}"#).unwrap();
+        #[cfg(feature = "schema")]
+        {
+            let err = get_validated_json(&invalid).unwrap_err();
+            assert!(matches!(err, CbError::SchemaValidation { .. }));
+            assert!(err.to_string().contains("schema validation failed"), "{}", err);
+        }
+        // without the schema feature, files are loaded unchecked
+        #[cfg(not(feature = "schema"))]
+        assert!(get_validated_json(&invalid).is_ok());
+
+        let ctx = RunContext::default();
+        assert!(matches!(
+            MediaInfo::new(&ctx, &missing),
+            Err(CbError::MissingFile { .. })
+        ));
+    }
+
+    #[test]
+    fn dither() {
+        let dithered = "aresample=osf=s16:dither_method=triangular";
+        assert_eq!(bit_depth_filters(Some(24), 16), dithered);
+        assert_eq!(bit_depth_filters(None, 16), dithered);
+        assert_eq!(bit_depth_filters(Some(16), 16), "aresample=osf=s16");
+        assert_eq!(bit_depth_filters(Some(8), 16), "aresample=osf=s16");
+    }
+
+    #[test]
+    fn durations() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(parse_duration("371.760"), ms(371_760));
+        assert_eq!(parse_duration("371,760"), ms(371_760));
+        assert_eq!(parse_duration("371"), ms(371_000));
+        assert_eq!(parse_duration("371.760 / 371.755"), ms(371_760));
+        assert_eq!(parse_duration("01:02:03.456"), ms(3_723_456));
+        assert_eq!(parse_duration("02:03,456"), ms(123_456));
+        assert_eq!(parse_duration(" 0:00:01 "), ms(1_000));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("N/A"), None);
+        assert_eq!(parse_duration("-1.5"), None);
+        assert_eq!(parse_duration("01:75.000"), None);
+        assert_eq!(parse_duration("1:01:02:03"), None);
+        assert_eq!(parse_duration("6 min 11 s"), None);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(first_number::<f64>("SamplingRate", "44,1 kHz").unwrap(), 44.1);
+        assert_eq!(first_number::<u8>("Channels", "2 / 1").unwrap(), 2);
+        assert_eq!(one_decimal(44_100, 1000), "44.1");
+        assert_eq!(one_decimal(48_000, 1000), "48.0");
+        // half up, where floats would round 0.25 to even
+        assert_eq!(one_decimal(250, 1000), "0.3");
+        assert_eq!(one_decimal(3 * 1024 * 1024 / 2, 1024 * 1024), "1.5");
+        assert_eq!(one_decimal(u64::MAX, 1), format!("{}.0", u64::MAX));
+        assert_eq!(human_size(512 * 1024), "0.5MB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024 / 2), "1.5GB");
+        assert_eq!(slashed(&Path::new("audio").join("jam1").join("ogg")), "audio/jam1/ogg");
+    }
+
+    #[test]
+    fn ffprobe_json() {
+        // trimmed down from `ffprobe -print_format json -show_streams -show_format tests/fixtures/silence.flac`
+        let json = br#"{
+            "streams": [{
+                "index": 0, "codec_name": "flac", "codec_type": "audio", "sample_fmt": "s32",
+                "sample_rate": "48000", "channels": 2, "bits_per_raw_sample": "24", "duration": "1.090521"
+            }],
+            "format": {"format_name": "flac", "duration": "1.090521", "bit_rate": "440"}
+        }"#;
+        let ffprobe = MediaInfo::from_ffprobe_json(json).unwrap();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/silence.flac");
+        let ours = flac::StreamInfo::read(&path).unwrap().to_media_info().unwrap();
+        assert_eq!(
+            ffprobe,
+            MediaInfo {
+                bitrate: Some("440".to_string()),
+                ..ours
+            }
+        );
+
+        let ogg = br#"{"streams": [
+            {"codec_type": "video", "codec_name": "theora"},
+            {"codec_type": "audio", "codec_name": "vorbis", "sample_rate": "44100", "channels": 2,
+             "bits_per_raw_sample": "0", "duration": "371.76", "bit_rate": "192000"}
+        ]}"#;
+        let ogg = MediaInfo::from_ffprobe_json(ogg).unwrap();
+        assert_eq!(ogg.format, "Vorbis");
+        assert_eq!(ogg.duration, "371.760");
+        assert_eq!(ogg.bit_depth(), None);
+        assert_eq!(ogg.bitrate_kbps(), Some(192));
+
+        assert!(MediaInfo::from_ffprobe_json(br#"{"streams": []}"#).is_err());
+    }
+
+    /// Writes a shell script to use in place of mediainfo
+    #[cfg(unix)]
+    fn fake_mediainfo(dir: &Path, script: &str) -> RunContext {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join("mediainfo");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut ctx = RunContext::default();
+        ctx.tools.mediainfo = path;
+        ctx
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn mediainfo_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Path::new("Cargo.toml");
+
+        let mut ctx = fake_mediainfo(dir.path(), "sleep 10");
+        ctx.tool_timeout = std::time::Duration::from_millis(200);
+        let start = std::time::Instant::now();
+        let err = MediaInfo::from_mediainfo(&ctx, file).unwrap_err();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert!(format!("{:#}", err).contains("killed"), "{:#}", err);
+
+        let ctx = fake_mediainfo(dir.path(), "echo 'Cannot open file' >&2; exit 3");
+        let err = format!("{:#}", MediaInfo::from_mediainfo(&ctx, file).unwrap_err());
+        assert!(
+            err.contains("Cannot open file") && err.contains("Cargo.toml"),
+            "{}",
+            err
+        );
+
+        let ctx = fake_mediainfo(
+            dir.path(),
+            r#"echo '{"media": {"track": [{"@type": "General"}, {"@type": "Video"}]}}'"#,
+        );
+        let err = format!("{:#}", MediaInfo::from_mediainfo(&ctx, file).unwrap_err());
+        assert!(err.contains("found: General, Video"), "{}", err);
+    }
 }
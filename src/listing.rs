@@ -0,0 +1,60 @@
+//! Folder listings in the same order on every machine
+//!
+//! `read_dir` lists a folder in whatever order the filesystem keeps it in, which isn't the same on ext4 and APFS (or
+//! even on two copies of the same folder).  Everything that walks a folder to make output lists it with [`sorted`],
+//! so that the pages, the manifest and the patched roots only change when the files do.
+
+use std::{
+    fs::DirEntry,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The seed set with [`shuffle`], 0 when listings aren't shuffled
+static SHUFFLE: AtomicU64 = AtomicU64::new(0);
+
+/// Shuffles every listing with `seed` before it's sorted, or stops shuffling them with `None`
+///
+/// This is for tests: output that still comes out the same with listings in a different order doesn't depend on the
+/// filesystem.
+pub fn shuffle(seed: Option<u64>) {
+    SHUFFLE.store(seed.map_or(0, |seed| seed.max(1)), Ordering::Relaxed);
+}
+
+/// The entries of `dir`, sorted by name
+pub fn sorted(dir: &Path) -> std::io::Result<Vec<DirEntry>> {
+    let mut entries = dir.read_dir()?.collect::<Result<Vec<_>, _>>()?;
+    let mut state = SHUFFLE.load(Ordering::Relaxed);
+    if state != 0 {
+        // Fisher-Yates with xorshift, nothing here needs better randomness than that
+        for i in (1..entries.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            entries.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+    }
+    entries.sort_by_key(DirEntry::file_name);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorted_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b", "a", "c.flac", "B"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+        let names = |entries: Vec<DirEntry>| entries.iter().map(DirEntry::file_name).collect::<Vec<_>>();
+        let expected = ["B", "a", "b", "c.flac"];
+        assert_eq!(names(sorted(dir.path()).unwrap()), expected);
+        shuffle(Some(7));
+        let shuffled = sorted(dir.path());
+        shuffle(None);
+        assert_eq!(names(shuffled.unwrap()), expected);
+        assert!(sorted(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+//! Keeps two runs from working on the same folders at once
+//!
+//! Converting, generating, patching and publishing each take a lock file in the folders they change: [`DATA_LOCK`] in
+//! the data dir and [`OUTPUT_LOCK`] in the output.  The file says which process has it, and is removed when the
+//! [`RunLock`] is dropped, which also happens when the run panics.  A run that was killed leaves its lock behind, and
+//! `--force-unlock` takes it over, as long as the process that had it isn't running any more.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::CbError, timing::utc_time};
+
+/// The lock file in the data dir
+pub const DATA_LOCK: &str = ".cb_processor.lock";
+/// The lock file in the output, which is a separate lock so that the output can be the data dir
+pub const OUTPUT_LOCK: &str = ".cb_processor-output.lock";
+
+/// What's in a lock file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub host: String,
+    /// When the run took the lock, in seconds since the Unix epoch
+    pub started: u64,
+}
+
+impl LockHolder {
+    fn current() -> LockHolder {
+        LockHolder {
+            pid: std::process::id(),
+            host: hostname(),
+            started: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        }
+    }
+
+    /// Whether the process that took the lock has gone.  Only a process on this machine can be checked, so a lock held
+    /// from another machine is never stale
+    pub fn is_stale(&self) -> bool {
+        self.host == hostname() && !process_alive(self.pid)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "pid {} on {}, since {} UTC{}",
+            self.pid,
+            self.host,
+            utc_time(self.started),
+            if self.is_stale() { " (no longer running)" } else { "" }
+        )
+    }
+}
+
+/// A lock file that this run holds, until it's dropped
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Takes the lock file `name` in `dir`, or fails with [`CbError::Locked`] if another run has it
+///
+/// With `force_unlock`, a lock whose process is gone (or that can't be read) is taken over.  A lock held from another
+/// machine is taken over too, since there's no way to check it.  A lock held by a process that's still running on this
+/// machine is never taken.
+pub fn acquire(dir: &Path, name: &str, force_unlock: bool) -> Result<RunLock, CbError> {
+    let path = dir.join(name);
+    let holder = LockHolder::current();
+    let contents = serde_json::to_vec_pretty(&holder).expect("the lock holder is always valid JSON");
+
+    // the other run can let go between failing to create the file and reading it, so try again
+    for _ in 0..3 {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut f) => {
+                let lock = RunLock { path };
+                f.write_all(&contents)
+                    .map_err(|e| CbError::io(format!("Failed to write {}", lock.path.display()), e))?;
+                return Ok(lock);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(CbError::io(format!("Failed to create {}", path.display()), e)),
+        }
+
+        let existing = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<LockHolder>(&bytes).ok(),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+        };
+        let running_here = existing
+            .as_ref()
+            .is_some_and(|h| h.host == hostname() && process_alive(h.pid));
+        if !force_unlock || running_here {
+            return Err(CbError::Locked {
+                holder: existing.map_or_else(|| "a run that didn't say who it is".to_string(), |h| h.describe()),
+                path,
+            });
+        }
+        say!("Taking over {}", path.display());
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(CbError::io(format!("Failed to remove {}", path.display()), e)),
+        }
+    }
+    Err(CbError::Locked {
+        holder: "another run that keeps taking it".to_string(),
+        path,
+    })
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length, and gethostname doesn't write past it
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    use std::convert::TryFrom;
+
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // SAFETY: signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // it exists, but belongs to someone else
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Without a way to check, a process is assumed to still be running
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_and_unlock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = acquire(dir.path(), DATA_LOCK, false).unwrap();
+        let holder: LockHolder = serde_json::from_slice(&std::fs::read(lock.path()).unwrap()).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert!(!holder.is_stale());
+
+        // this process is still running, so even --force-unlock doesn't take the lock
+        for force in [false, true].iter().copied() {
+            match acquire(dir.path(), DATA_LOCK, force) {
+                Err(CbError::Locked { holder, .. }) => assert!(holder.contains(&std::process::id().to_string())),
+                other => panic!("{:?}", other),
+            }
+        }
+        // the output's lock is a different one
+        let output = acquire(dir.path(), OUTPUT_LOCK, false).unwrap();
+        drop(output);
+
+        drop(lock);
+        assert!(!dir.path().join(DATA_LOCK).exists());
+        acquire(dir.path(), DATA_LOCK, false).unwrap();
+        assert!(!dir.path().join(DATA_LOCK).exists());
+    }
+
+    #[test]
+    fn stale_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DATA_LOCK);
+        let crashed = LockHolder {
+            // higher than any pid Linux hands out
+            pid: 99_999_999,
+            host: hostname(),
+            started: 1_700_000_000,
+        };
+        std::fs::write(&path, serde_json::to_vec(&crashed).unwrap()).unwrap();
+        assert!(crashed.is_stale());
+
+        let e = acquire(dir.path(), DATA_LOCK, false).unwrap_err();
+        assert!(
+            e.to_string().contains("2023-11-14 22:13 UTC (no longer running)"),
+            "{}",
+            e
+        );
+        assert!(e.to_string().contains("--force-unlock"), "{}", e);
+
+        let lock = acquire(dir.path(), DATA_LOCK, true).unwrap();
+        let holder: LockHolder = serde_json::from_slice(&std::fs::read(lock.path()).unwrap()).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+    }
+}
@@ -1,29 +1,516 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::bail;
-use cb_processor::{types::Season, validate_and_print};
-use clap::{App, Arg};
+use anyhow::{bail, Context};
+use cb_processor::{
+    analysis, archive_org, clean, command,
+    context::{self, Config, MediaInfoBackend, RunContext},
+    diff::{self, ChangeKind, OutputDiff},
+    duplicates,
+    error::CbError,
+    fetch, gateway,
+    hasher::HasherPool,
+    history, ipfs, ipfs_import, json_format, lock,
+    media_cache::MediaInfoCache,
+    pipeline::{ConvertOptions, Generation},
+    precompress,
+    progress::{self, Stage},
+    provenance, scaffold,
+    select::Selector,
+    share, source, state_store, stats, timing,
+    types::Season,
+    update, Pipeline,
+};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use colored::Colorize;
 use std::fs::File;
+use std::io::{BufRead, IsTerminal, Write};
 use std::str::FromStr;
+use std::sync::Arc;
+
+/// Assembles the settings for this run from the config file, the environment, and the command line
+fn run_context(matches: &ArgMatches) -> Result<RunContext, anyhow::Error> {
+    let config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None if Path::new(context::DEFAULT_CONFIG_FILE).exists() => {
+            Config::load(Path::new(context::DEFAULT_CONFIG_FILE))?
+        }
+        None => Config::default(),
+    };
+    let mut ctx = RunContext::from_config(&config);
+
+    if let Some(static_dir) = matches.value_of("static-dir") {
+        ctx.static_dir = static_dir.into();
+    }
+    if let Some(url) = matches.value_of("webhook-url").filter(|url| !url.is_empty()) {
+        ctx.webhook_url = Some(url.to_string());
+    }
+    if let Some(only) = matches.values_of("only") {
+        ctx.only = Selector::new(only)?;
+    }
+    ctx.keep_going = matches.is_present("keep-going");
+    ctx.strict_links = matches.is_present("strict-links");
+    ctx.force_reconvert = matches.is_present("force-reconvert");
+    ctx.force_patch = matches.is_present("force");
+    ctx.include_unhealthy = matches.is_present("include-unhealthy");
+    ctx.offline = matches.is_present("offline") || std::env::var_os(context::OFFLINE_VAR).is_some();
+    if let Some(quality) = matches.value_of("ogg-quality") {
+        let quality = quality
+            .parse()
+            .map_err(|_| format!("Invalid ogg quality {:?} (expected -1 to 10)", quality))
+            .and_then(context::check_ogg_quality);
+        ctx.encode.ogg_quality = Some(quality.unwrap_or_else(|e| usage_error(&e)));
+    }
+    if let Some(bitrate) = matches.value_of("mp3-bitrate") {
+        ctx.encode.mp3_bitrate = Some(bitrate.parse().unwrap_or_else(|e: String| usage_error(&e)));
+    }
+    match matches.value_of("sample-rate").map(str::parse::<u32>) {
+        None => {}
+        Some(Ok(rate)) if rate > 0 => ctx.encode.sample_rate = Some(rate),
+        Some(_) => usage_error("--sample-rate must be a number of Hz, like 44100"),
+    }
+    match matches.value_of("jobs").map(str::parse::<usize>) {
+        None | Some(Ok(0)) => {}
+        Some(Ok(jobs)) => ctx.jobs = jobs,
+        Some(Err(_)) => usage_error("--jobs must be a number"),
+    }
+    if matches.is_present("diff-output") {
+        let lines = match matches.value_of("diff-lines").map(str::parse) {
+            None => diff::DEFAULT_DIFF_LINES,
+            Some(Ok(n)) => n,
+            Some(Err(_)) => usage_error("--diff-lines must be a number"),
+        };
+        ctx.diff_output = Some(OutputDiff::new(lines));
+    }
+    // the subcommands that load a season from the data dir take their own --data
+    let data_dir = matches
+        .value_of("data-dir")
+        .or_else(|| matches.subcommand().1.and_then(|sub| sub.value_of("data-dir")));
+    if let Some(data_dir) = data_dir.filter(|d| Path::new(d).is_dir()) {
+        ctx.media_cache = Some(Arc::new(MediaInfoCache::open(
+            Path::new(data_dir),
+            matches.is_present("refresh-mediainfo"),
+            ctx.retention.media_cache,
+        )));
+        ctx.hashes = Arc::new(HasherPool::open(Path::new(data_dir)));
+    }
+    if let Ok(mr) = std::env::var("CI_MERGE_REQUEST_IID") {
+        ctx.review_snippet = context::gitlab_review_snippet(&mr);
+    }
+
+    Ok(ctx)
+}
+
+/// Refuses what can't be done without the network in an `--offline` run, before any of it is started
+fn refuse_network(ctx: &RunContext, matches: &ArgMatches) -> Result<(), CbError> {
+    for flag in [
+        "publish",
+        "prime",
+        "benchmark-gateways",
+        "archive-upload",
+        "check-update",
+    ] {
+        if matches.is_present(flag) {
+            ctx.require_online(&format!("--{}", flag))?;
+        }
+    }
+    if matches.is_present("fetch") && matches.is_present("gateway") {
+        ctx.require_online("--fetch --gateway")?;
+    }
+    if matches.subcommand_matches("rollback").is_some() {
+        // publishing the older root is the whole point
+        ctx.require_online("rollback")?;
+    }
+    Ok(())
+}
+
+/// Reports on the external tools we use
+fn doctor(ctx: &RunContext) -> Result<(), anyhow::Error> {
+    let tools = [
+        ("ffmpeg", &ctx.tools.ffmpeg),
+        ("ffprobe", &ctx.tools.ffprobe),
+        ("mediainfo", &ctx.tools.mediainfo),
+        ("ipfs", &ctx.tools.ipfs),
+        ("brotli", &ctx.tools.brotli),
+    ];
+    for (name, path) in tools {
+        match command::find_tool(path) {
+            Some(found) => println!("{:>10}: {}", name, found.display()),
+            None => println!("{:>10}: {} ({})", name, "not found".red(), path.display()),
+        }
+    }
+
+    let backend = ctx.resolved_media_info_backend();
+    let (tool, path) = match backend {
+        MediaInfoBackend::Ffprobe => ("ffprobe", &ctx.tools.ffprobe),
+        _ => ("mediainfo", &ctx.tools.mediainfo),
+    };
+    println!(
+        "Media info for files that aren't flacs comes from {}{}",
+        tool,
+        if ctx.media_info_backend == MediaInfoBackend::Auto {
+            " (chosen automatically)"
+        } else {
+            ""
+        }
+    );
+    if command::find_tool(path).is_none() {
+        bail!("{} isn't installed, so media info can't be collected", tool);
+    }
+    Ok(())
+}
+
+/// Loads the season from the data dir, or from the metadata if there's no data dir
+fn load_for_generation(
+    ctx: &RunContext, matches: &ArgMatches, season_json_path: &Path,
+) -> Result<Season, anyhow::Error> {
+    let data_dir = matches.value_of("data-dir").map(Path::new);
+    let md_file = matches.value_of("metadata").map(Path::new);
+    if data_dir.is_none() && md_file.is_none() {
+        usage_error("either --data or --metadata must be provided for generation; see --help");
+    }
+    Ok(Pipeline::new(ctx).load(season_json_path, data_dir, md_file)?)
+}
+
+/// Locks the data dir (if generating from it) and the output, so that no other run changes them while generating
+///
+/// `--diff-output` doesn't write anything, so it doesn't lock the output either.
+fn generation_locks(matches: &ArgMatches, output_root: &Path) -> Result<Vec<lock::RunLock>, anyhow::Error> {
+    let force = matches.is_present("force-unlock");
+    let mut locks = Vec::new();
+    if let Some(data_dir) = matches.value_of("data-dir").map(Path::new) {
+        locks.push(lock::acquire(data_dir, lock::DATA_LOCK, force)?);
+    }
+    if !matches.is_present("diff-output") {
+        std::fs::create_dir_all(output_root).with_context(|| format!("Failed to create {}", output_root.display()))?;
+        locks.push(lock::acquire(output_root, lock::OUTPUT_LOCK, force)?);
+    }
+    Ok(locks)
+}
+
+/// Generates the pages (and metadata) into `generated`, and compares them with `root` (or its folder at `--path`)
+fn drift_check(
+    ctx: &RunContext, matches: &ArgMatches, season_json: &Path, season: &Season, root: &cid::Cid, generated: &Path,
+) -> Result<(), anyhow::Error> {
+    ctx.stage(Stage::Generate, || {
+        let mut written = cb_processor::write_season_index(ctx, season, generated)?;
+        written.extend(cb_processor::write_all_recording_index(ctx, season, generated)?);
+        written.extend(cb_processor::write_source_snapshot(ctx, season_json, generated)?);
+        written.extend(cb_processor::write_badges(ctx, season, generated)?);
+        if let Some(md_file) = matches.value_of("metadata").map(Path::new) {
+            let name = md_file.file_name().unwrap_or_else(|| "metadata.json".as_ref());
+            cb_processor::write_metadata(season, &generated.join(name), true)?;
+            written.push(generated.join(name));
+        }
+        precompress::write_siblings(ctx, generated, &written)?;
+        Ok::<_, anyhow::Error>(())
+    })?;
+
+    let path = matches.value_of("path");
+    let checked = match path {
+        Some(path) => match ipfs::link_at(ctx, root, path)? {
+            Some(cid) => cid,
+            None => bail!("{} has nothing at {}", root, path),
+        },
+        None => *root,
+    };
+    let plan = ctx.stage(Stage::Patch, || ipfs::plan_drift(ctx, &checked, generated))?;
+    let changes = plan.changed_links();
+    let source_prefix = format!("{}/", source::SOURCE_DIR);
+    let report = ipfs::DriftReport {
+        root: root.to_string(),
+        path: path.map(str::to_string),
+        drifted: !changes.is_empty(),
+        source_edited: changes
+            .iter()
+            .any(|c| c.path == source::SOURCE_DIR || c.path.starts_with(&source_prefix)),
+        changes,
+    };
+    if let Some(json_file) = matches.value_of("json") {
+        let f = File::create(json_file).with_context(|| format!("Failed to create {}", json_file))?;
+        serde_json::to_writer_pretty(f, &report).with_context(|| format!("Failed to write {}", json_file))?;
+    }
+    let name = match path {
+        Some(path) => format!("{}/{}", root, path.trim_matches('/')),
+        None => root.to_string(),
+    };
+    if !report.drifted {
+        println!("{} matches what generation makes", name);
+        return Ok(());
+    }
+    print!("{}", plan.summary());
+    if report.source_edited {
+        println!(
+            "The season's JSON isn't what {} was made from: it was edited without being published, or the other way \
+             around",
+            name
+        );
+    }
+    bail!("{} has drifted: {} links would change", name, report.changes.len());
+}
+
+/// Shows a summary of what's about to happen, and asks the user to confirm it (unless --yes was given)
+fn confirm(matches: &ArgMatches, summary: &str) -> Result<(), anyhow::Error> {
+    println!("{}", summary);
+    if matches.is_present("yes") {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!("Not continuing without confirmation because stdin is not a terminal. Use --yes to skip confirmation");
+    }
+    print!("Continue? [y/N] ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => bail!("Aborted"),
+    }
+}
+
+/// Returns the value of a flag, or asks for it on the terminal if it wasn't given
+fn arg_or_prompt(matches: &ArgMatches, name: &str, question: &str) -> Result<String, anyhow::Error> {
+    if let Some(v) = matches.value_of(name) {
+        return Ok(v.to_string());
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "Missing --{} argument (and stdin is not a terminal, so can't prompt for it)",
+            name
+        );
+    }
+    print!("{}: ", question);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Prints a usage error and exits with the usage exit code (which is also used for clap's own argument errors)
+fn usage_error(msg: &str) -> ! {
+    eprintln!("error: {}\n\nFor more information try --help", msg);
+    std::process::exit(USAGE_EXIT_CODE);
+}
+
+const USAGE_EXIT_CODE: i32 = 2;
+
+/// Returns the value of an argument that is required for the current mode
+fn required_arg<'a>(matches: &'a ArgMatches, name: &str, why: &str) -> &'a str {
+    match matches.value_of(name) {
+        Some(v) => v,
+        None => {
+            // the data dir is the only argument whose name doesn't match its flag
+            let flag = if name == "data-dir" { "data" } else { name };
+            usage_error(&format!("--{} must be provided {}; see --help", flag, why))
+        }
+    }
+}
+
+fn root_hash_arg(matches: &ArgMatches, why: &str) -> cid::Cid {
+    let hash = required_arg(matches, "hash", why);
+    match cid::Cid::from_str(hash) {
+        Ok(cid) => cid,
+        Err(e) => usage_error(&format!("--hash {:?} is not a valid CID: {}", hash, e)),
+    }
+}
+
+/// The root given with --hash, or else the last one in the roots history
+fn root_hash_or_latest(ctx: &RunContext, matches: &ArgMatches, why: &str) -> Result<cid::Cid, anyhow::Error> {
+    if matches.value_of("hash").is_some() {
+        return Ok(root_hash_arg(matches, why));
+    }
+    match history::load(&ctx.roots_history)?.last() {
+        Some(entry) => cid::Cid::from_str(&entry.root)
+            .with_context(|| format!("{} has an invalid root", ctx.roots_history.display())),
+        None => usage_error(&format!(
+            "--hash must be provided {}, since there are no roots in {}",
+            why,
+            ctx.roots_history.display()
+        )),
+    }
+}
 
 fn main() -> Result<(), anyhow::Error> {
     let matches = App::new("cb_processor")
-        .version("0.0.1")
+        .version(cb_processor::VERSION)
         .arg(
             Arg::with_name("patch")
             .long("patch")
             .takes_value(false)
             .requires_all(&["hash", "output"])
         )
+        .arg(
+            Arg::with_name("dry-run")
+            .long("dry-run")
+            .requires("patch")
+            .conflicts_with_all(&["verify", "publish", "patch-report"])
+            .help("Only show what --patch would change, and whether the root looks like the season's")
+        )
+        .arg(
+            Arg::with_name("force")
+            .long("force")
+            .requires("patch")
+            .help("Patch the root even if it doesn't look like an earlier version of the season (see \
+                   expected_root_links in the config)")
+        )
+        .arg(
+            Arg::with_name("verify")
+            .long("verify")
+            .requires("patch")
+            .help("After patching, check that the new root matches the output folder (before anything is published)")
+        )
+        .arg(
+            Arg::with_name("patch-report")
+            .long("patch-report")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("patch")
+            .help("Also write what was patched, and the size of the old and new roots, to this file as JSON")
+        )
+        .arg(
+            Arg::with_name("publish")
+            .long("publish")
+            .requires("patch")
+            .help("After patching, publish the new root object to IPNS")
+        )
+        .arg(
+            Arg::with_name("webhook-url")
+            .long("webhook-url")
+            .takes_value(true)
+            .env("CB_WEBHOOK_URL")
+            .help("Where to POST a notification after publishing (overrides the config file)")
+        )
+        .arg(
+            Arg::with_name("notify-dry-run")
+            .long("notify-dry-run")
+            .requires("publish")
+            .help("Print the publish notification instead of sending it")
+        )
+        .arg(
+            Arg::with_name("ipns-key")
+            .long("ipns-key")
+            .takes_value(true)
+            .default_value("self")
+            .help("Name of the IPNS key to publish to")
+        )
+        .arg(
+            Arg::with_name("yes")
+            .long("yes")
+            .short("y")
+            .help("Don't ask for confirmation before patching or publishing")
+        )
         .arg(
             Arg::with_name("prime")
             .long("prime")
+            .requires("hash")
+            .help("Ask the public gateways for the root and each of its links, so that they have them cached.  What's \
+                   done is kept in prime_state, for the root being primed only (the state for the root before is \
+                   moved to prime_state's .1)")
+        )
+        .arg(
+            Arg::with_name("resume")
+            .long("resume")
+            .requires("prime")
+            .help("Only carry on with an earlier --prime of the same root, and fail if there isn't one (carrying on is \
+                   the default when there is)")
+        )
+        .arg(
+            Arg::with_name("fresh")
+            .long("fresh")
+            .requires("prime")
+            .conflicts_with("resume")
+            .help("Prime every gateway again, even the links an earlier --prime of the same root already did")
+        )
+        .arg(
+            Arg::with_name("include-unhealthy")
+            .long("include-unhealthy")
+            .requires("prime")
+            .help("Also prime the gateways that failed every request in their last unhealthy_after runs (3 by \
+                   default), which are skipped otherwise (see the gateways status subcommand)")
+        )
+        .arg(
+            Arg::with_name("prime-first")
+            .long("prime-first")
+            .takes_value(true)
+            .value_name("PATCH_REPORT")
+            .requires("prime")
+            .help("Prime what the patch in this --patch-report changed (the recordings it added or changed) on every \
+                   gateway before the rest of the root")
+        )
+        .arg(
+            Arg::with_name("budget")
+            .long("budget")
+            .takes_value(true)
+            .value_name("REQUESTS|TIME")
+            .requires("prime")
+            .help("Stop priming after this many requests, or this long (like 20m or 2h), leaving the rest for the next \
+                   run")
+        )
+        .arg(
+            Arg::with_name("prime-report")
+            .long("prime-report")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("prime")
+            .help("Also write what was primed, and the health of each gateway, to this file as JSON")
+        )
+        .arg(
+            Arg::with_name("benchmark-gateways")
+            .long("benchmark-gateways")
+            .requires("hash")
+            .conflicts_with_all(&["patch", "prime"])
+            .help("Times how long each public gateway takes to serve a small file from the root, and ranks them")
+        )
+        .arg(
+            Arg::with_name("samples")
+            .long("samples")
+            .takes_value(true)
+            .value_name("N")
+            .requires("benchmark-gateways")
+            .help("With --benchmark-gateways, how many times to fetch the file from each gateway (5 by default)")
+        )
+        .arg(
+            Arg::with_name("benchmark-json")
+            .long("benchmark-json")
+            .takes_value(true)
+            .value_name("FILE")
+            .requires("benchmark-gateways")
+            .help("Also write the --benchmark-gateways results to this file as JSON")
+        )
+        .arg(
+            Arg::with_name("fetch")
+            .long("fetch")
+            .conflicts_with_all(&["validate", "convert", "patch", "prime"])
+            .requires_all(&["input", "data-dir", "metadata"])
+            .help("Downloads the files of the season that are missing from the data dir, from the root object given with --hash (or else the last one in the roots history)")
+        )
+        .arg(
+            Arg::with_name("formats")
+            .long("formats")
+            .takes_value(true)
+            .default_value("ogg,mp3")
+            .help("With --fetch, which kinds of file to download (any of flac, ogg, mp3 and opus, separated by commas)")
+        )
+        .arg(
+            Arg::with_name("gateway")
+            .long("gateway")
+            .takes_value(true)
+            .help("With --fetch, download through this HTTP gateway (like https://ipfs.io) instead of the local ipfs node")
         )
         .arg(
             Arg::with_name("hash")
             .long("hash")
             .short("h")
             .takes_value(true)
+            .env("CB_HASH")
+            .help("CID of the currently published root object")
+        )
+        .arg(
+            Arg::with_name("show-provenance")
+            .long("show-provenance")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Prints the ffmpeg command that made this ogg or mp3, from the conversions.json next to it (which \
+                   only has the latest conversion of each file)")
         )
         .arg(
             Arg::with_name("validate")
@@ -45,6 +532,7 @@ fn main() -> Result<(), anyhow::Error> {
             .short("i")
             .long("input")
             .takes_value(true)
+            .env("CB_INPUT")
             .help("Path to season.json")
         )
         .arg(
@@ -52,6 +540,7 @@ fn main() -> Result<(), anyhow::Error> {
                 .short("d")
                 .long("data")
                 .takes_value(true)
+                .env("CB_DATA_DIR")
                 .help("Path to data directory")
                 .long_help("Path to data directory\n\nThis is the directory containing the files references in the recordings json file")
         )
@@ -60,29 +549,1107 @@ fn main() -> Result<(), anyhow::Error> {
                 .short("m")
                 .long("metadata")
                 .takes_value(true)
-                .help("Path to metadata file")
+                .env("CB_METADATA")
+                .help("Path to metadata file (name it like metadata.index.json to keep each recording in a file of its own)")
+        )
+        .arg(
+            Arg::with_name("force-metadata")
+                .long("force-metadata")
+                .takes_value(false)
+                .help("Overwrite the metadata file even if it has more recordings than the current season")
         )
         .arg(
             Arg::with_name("output")
                 .short("o")
                 .long("output")
                 .takes_value(true)
+                .env("CB_OUTPUT")
+                .help("Path to the output directory for the generated webpage")
+        )
+        .arg(
+            Arg::with_name("diff-output")
+                .long("diff-output")
+                .takes_value(false)
+                .help("Show how generating would change the output without writing anything, and fail if it would")
+        )
+        .arg(
+            Arg::with_name("diff-lines")
+                .long("diff-lines")
+                .takes_value(true)
+                .value_name("N")
+                .requires("diff-output")
+                .help("How many lines of --diff-output to show for each file [default: 40]")
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .global(true)
+                .help("Leave out recordings that can't be loaded instead of stopping, and fail at the end of the run")
+        )
+        .arg(
+            Arg::with_name("force-unlock")
+                .long("force-unlock")
+                .global(true)
+                .help("Take over the lock files of a run that crashed (a run that's still going keeps its locks)")
+        )
+        .arg(
+            Arg::with_name("offline")
+                .long("offline")
+                .global(true)
+                .help("Don't touch the network: refuse to publish, prime or upload, and skip the update check (or set CB_OFFLINE)")
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .takes_value(true)
+                .value_name("N")
+                .global(true)
+                .help("How many jobs (like ffmpeg conversions) to run at once, overriding the config (0 or by default, one per CPU)")
+        )
+        .arg(
+            Arg::with_name("strict-links")
+                .long("strict-links")
+                .global(true)
+                .help("Fail to generate pages that link to files missing from the data dir, instead of leaving the links out")
+        )
+        .arg(
+            Arg::with_name("progress-format")
+                .long("progress-format")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .global(true)
+                .help("With \"json\", progress events are written to stderr as one JSON object per line")
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .env("CB_CONFIG")
+                .global(true)
+                .help("Path to the config file (defaults to cb_processor.toml, if it exists)")
+        )
+        .arg(
+            Arg::with_name("static-dir")
+                .long("static-dir")
+                .takes_value(true)
+                .env("CB_STATIC_DIR")
+                .help("Folder with the static files to copy into the output (overrides the config file)")
+        )
+        .arg(
+            Arg::with_name("analyze")
+                .long("analyze")
+                .requires("validate")
+                .help("While validating, decode every flac to look for clipping and silence (slow)")
+        )
+        .arg(
+            Arg::with_name("spectrograms")
+                .long("spectrograms")
+                .requires("convert")
+                .help("While converting, also draw a spectrogram of every flac that doesn't have an up to date one")
+        )
+        .arg(
+            Arg::with_name("peaks")
+                .long("peaks")
+                .requires("convert")
+                .help("While converting, also work out the waveform peaks of every flac that doesn't have up to date ones")
+        )
+        .arg(
+            Arg::with_name("ogg-quality")
+                .long("ogg-quality")
+                .takes_value(true)
+                .value_name("Q")
+                .requires("convert")
+                .allow_hyphen_values(true)
+                .help("Vorbis quality of the oggs, from -1 to 10 (overrides the config; ffmpeg's default is 3)")
+        )
+        .arg(
+            Arg::with_name("mp3-bitrate")
+                .long("mp3-bitrate")
+                .takes_value(true)
+                .value_name("RATE")
+                .requires("convert")
+                .help("Bitrate of the mp3s, like 320k, or a VBR preset from V0 to V9 (overrides the config; ffmpeg's default is 128k)")
+        )
+        .arg(
+            Arg::with_name("sample-rate")
+                .long("sample-rate")
+                .takes_value(true)
+                .value_name("HZ")
+                .requires("convert")
+                .help("Resample the oggs and mp3s to this rate (overrides the config; by default they keep the flac's)")
+        )
+        .arg(
+            Arg::with_name("retag")
+                .long("retag")
+                .requires("convert")
+                .help("Before converting, rewrite the title, artist, album, date and track number tags of the oggs, \
+                       mp3s and opus files that are already there (without encoding them again)")
+        )
+        .arg(
+            Arg::with_name("force-reconvert")
+                .long("force-reconvert")
+                .requires("convert")
+                .help("Convert every ogg, mp3 and opus file again, instead of only the ones that are missing or older \
+                       than their flac (or not as long as it)")
+        )
+        .arg(
+            Arg::with_name("retry-quarantined")
+                .long("retry-quarantined")
+                .requires("convert")
+                .help("Before converting, take the flacs that kept failing to convert out of quarantine")
+        )
+        .arg(
+            Arg::with_name("measure-loudness")
+                .long("measure-loudness")
+                .conflicts_with_all(&["validate", "convert"])
+                .requires_all(&["input", "data-dir", "metadata"])
+                .help("Measures the loudness of every flac that isn't already measured in the metadata file")
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("find-duplicates")
+                .long("find-duplicates")
+                .conflicts_with_all(&["validate", "convert", "measure-loudness"])
+                .requires_all(&["input", "data-dir"])
+                .help("Reports flacs with identical contents in more than one place")
+        )
+        .arg(
+            Arg::with_name("duplicates-json")
+                .long("duplicates-json")
+                .takes_value(true)
+                .requires("find-duplicates")
+                .help("Also write the duplicates report to this file, as JSON")
+        )
+        .arg(
+            Arg::with_name("archive-upload")
+                .long("archive-upload")
+                .conflicts_with_all(&["validate", "convert", "measure-loudness", "find-duplicates"])
+                .requires_all(&["input", "data-dir", "metadata"])
+                .help("Uploads the stereo mix of every recording that isn't on archive.org yet (needs IAS3_ACCESS_KEY and IAS3_SECRET_KEY)")
+        )
+        .arg(
+            Arg::with_name("refresh-mediainfo")
+                .long("refresh-mediainfo")
+                .help("Ignore the media info cache in the data dir, and rebuild it.  The cache keeps the 20000 files \
+                       changed most recently (or 16MB) by default, see [retention] in the config")
+        )
+        .arg(
+            Arg::with_name("check-update")
+                .long("check-update")
+                .help("Check for a newer release of cb_processor and exit (set CB_NO_UPDATE_CHECK to turn off the daily check)")
+        )
+        .arg(
+            Arg::with_name("only")
+                .long("only")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only work on recordings whose data_folder or title matches this glob (can be given multiple times)")
+        )
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Creates the skeleton of a new season")
+                .arg(Arg::with_name("dir").required(true).help("Directory to create the season in"))
+                .arg(
+                    Arg::with_name("title")
+                        .long("title")
+                        .takes_value(true)
+                        .default_value("Season 1")
+                        .help("Title of the new season")
+                )
+                .arg(
+                    Arg::with_name("artist")
+                        .long("artist")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Who made the recordings (recordings can name someone else)")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Rewrites season.json, its include files and every recording JSON in the canonical style (keys in the schema's order, two-space indents, tracks sorted by id)")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required(true)
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only list the files that would change, and fail if there are any (for CI)")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("history-report")
+                .about("Lists the roots made by --patch, with how big each one is (if it's still in the local IPFS repo)")
+                .arg(
+                    Arg::with_name("history")
+                        .long("history")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("The roots history file (defaults to roots_history from the config).  It keeps the \
+                               last 1000 roots or 256KB by default (see [retention] in the config), the ones before \
+                               are in its .1")
+                )
+                .arg(Arg::with_name("json").long("json").help("Print the report as JSON instead of a table"))
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("Publishes an earlier root from the roots history again: pins it, points the IPNS name at it, and adds it to the history.  The root has to be complete in the local IPFS repo")
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .takes_value(true)
+                        .value_name("ROOT")
+                        .help("The root to roll back to, or its number in the list (asks for it if not given)")
+                )
+                .arg(
+                    Arg::with_name("history")
+                        .long("history")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("The roots history file (defaults to roots_history from the config).  It keeps the \
+                               last 1000 roots or 256KB by default (see [retention] in the config), the ones before \
+                               are in its .1")
+                )
+                .arg(
+                    Arg::with_name("ipns-key")
+                        .long("ipns-key")
+                        .takes_value(true)
+                        .default_value("self")
+                        .help("Name of the IPNS key to publish to")
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .help("Don't ask for confirmation before publishing")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("gateways")
+                .about("Shows what --prime knows about the gateways")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Prints how many requests each gateway answered in its last runs of --prime, and which \
+                                ones it skips as unhealthy, without priming anything")
+                        .arg(Arg::with_name("json").long("json").help("Print the table as JSON"))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("doctor").about("Checks which external tools are installed, and which will be used")
+        )
+        .subcommand(
+            SubCommand::with_name("clean")
+                .about("Deletes generated files (lists them unless --yes is given).  Flacs and JSON files are never deleted")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required(true)
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("data-dir")
+                        .short("d")
+                        .long("data")
+                        .takes_value(true)
+                        .env("CB_DATA_DIR")
+                        .help("Path to data directory")
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .env("CB_OUTPUT")
+                        .help("Path to the output directory")
+                )
+                .arg(
+                    Arg::with_name("derived-audio")
+                        .long("derived-audio")
+                        .requires("data-dir")
+                        .help("Delete the oggs, mp3s, spectrograms and peaks made from the flacs")
+                )
+                .arg(
+                    Arg::with_name("html")
+                        .long("html")
+                        .requires("output")
+                        .help("Delete the pages, the playlist and the copies of the static files in the output")
+                )
+                .arg(
+                    Arg::with_name("all-generated")
+                        .long("all-generated")
+                        .help("Both --derived-audio and --html (for whichever of --data and --output are given)")
+                )
+                .group(ArgGroup::with_name("scope").args(&["derived-audio", "html", "all-generated"]).multiple(true).required(true))
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .help("Actually delete the files")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("drift-check")
+                .about("Regenerates the pages into a temporary folder and fails if the published root doesn't match them.  Nothing is changed, and nothing is added to IPFS")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required(true)
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("data-dir")
+                        .short("d")
+                        .long("data")
+                        .takes_value(true)
+                        .env("CB_DATA_DIR")
+                        .help("Path to data directory")
+                )
+                .arg(
+                    Arg::with_name("metadata")
+                        .long("metadata")
+                        .takes_value(true)
+                        .env("CB_METADATA")
+                        .help("Path to metadata.json (read instead of the data directory, and regenerated too)")
+                )
+                .group(ArgGroup::with_name("source").args(&["data-dir", "metadata"]).multiple(true).required(true))
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .short("h")
+                        .takes_value(true)
+                        .env("CB_HASH")
+                        .help("CID of the published root (defaults to the latest one in the roots history)")
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .value_name("FOLDER")
+                        .help("The season's folder in the root, like S01, when the root has more than one season")
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("Also write the links that would change to this file as JSON")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-stats")
+                .about("Counts the downloads of each track in an access log of the gateway, for the recording pages.  A log that has already been imported isn't counted again")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required(true)
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("data-dir")
+                        .short("d")
+                        .long("data")
+                        .takes_value(true)
+                        .env("CB_DATA_DIR")
+                        .help("Path to data directory")
+                )
+                .arg(
+                    Arg::with_name("metadata")
+                        .long("metadata")
+                        .takes_value(true)
+                        .env("CB_METADATA")
+                        .help("Path to metadata.json (read instead of the data directory)")
+                )
+                .group(ArgGroup::with_name("source").args(&["data-dir", "metadata"]).multiple(true).required(true))
+                .arg(
+                    Arg::with_name("log")
+                        .takes_value(true)
+                        .required(true)
+                        .value_name("LOG")
+                        .help("The access log")
+                )
+                .arg(
+                    Arg::with_name("log-format")
+                        .long("log-format")
+                        .takes_value(true)
+                        .value_name("REGEX")
+                        .help("A regex for a line of the log, capturing the request path as `path` and the HTTP status as `status` (defaults to the combined log format)")
+                )
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .help("The stats file to add to (defaults to `stats` in the config)")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-from-ipfs")
+                .about("Writes the metadata for a season from what's in a published root, for recordings whose flacs are only in IPFS.  Files it can't read are kept with what's known about them, and listed as warnings")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required(true)
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("hash")
+                        .long("hash")
+                        .short("h")
+                        .takes_value(true)
+                        .env("CB_HASH")
+                        .help("CID of the published root to import from")
+                )
+                .arg(
+                    Arg::with_name("metadata")
+                        .long("metadata")
+                        .takes_value(true)
+                        .env("CB_METADATA")
+                        .required(true)
+                        .help("Path to the metadata.json to write (what only earlier runs know is kept from it, if it exists)")
+                )
+                .arg(
+                    Arg::with_name("force-metadata")
+                        .long("force-metadata")
+                        .help("Overwrite the metadata even if it has more recordings than the season")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("share")
+                .about("Shares a recording (even a draft) without publishing it: adds its page and files to IPFS as a \
+                        root of their own, pins it until it expires, and prints its URLs on the gateways.  With \
+                        --expire, unpins the shares that have expired instead")
+                .arg(
+                    Arg::with_name("data-folder")
+                        .required_unless("expire")
+                        .help("The data_folder of the recording to share")
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required_unless("expire")
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("data-dir")
+                        .short("d")
+                        .long("data")
+                        .takes_value(true)
+                        .env("CB_DATA_DIR")
+                        .required_unless("expire")
+                        .help("Path to data directory")
+                )
+                .arg(
+                    Arg::with_name("expires-in")
+                        .long("expires-in")
+                        .takes_value(true)
+                        .value_name("DAYS")
+                        .help("How many days the share stays pinned for [default: 14]")
+                )
+                .arg(
+                    Arg::with_name("expire")
+                        .long("expire")
+                        .conflicts_with_all(&["data-folder", "expires-in"])
+                        .help("Unpin the shares that have expired (they're listed in the roots history file's \
+                               .shares.json)")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("add-recording")
+                .about("Creates a new recording JSON from a folder of flac files, and adds it to season.json")
+                .arg(
+                    Arg::with_name("folder")
+                        .required(true)
+                        .help("Folder containing the flac files for this recording")
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .takes_value(true)
+                        .env("CB_INPUT")
+                        .required(true)
+                        .help("Path to season.json")
+                )
+                .arg(
+                    Arg::with_name("data-dir")
+                        .short("d")
+                        .long("data")
+                        .takes_value(true)
+                        .env("CB_DATA_DIR")
+                        .help("Path to data directory (the recording folder must be inside of it)")
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(true)
+                        .help("Where to write the recording JSON (defaults to next to the other recordings)")
+                )
+                .arg(Arg::with_name("title").long("title").takes_value(true).help("Title of the recording"))
+                .arg(Arg::with_name("date").long("date").takes_value(true).help("Recorded date, in YYYY/MM/DD format"))
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Tag for this recording (can be given multiple times)")
+                )
+                .arg(
+                    Arg::with_name("track-id-regex")
+                        .long("track-id-regex")
+                        .takes_value(true)
+                        .default_value(scaffold::DEFAULT_TRACK_ID_REGEX)
+                        .help("Regex to find the track id in each flac filename (the first capture group is the id)")
+                )
+                .arg(
+                    Arg::with_name("stereo-mix-id")
+                        .long("stereo-mix-id")
+                        .takes_value(true)
+                        .help("Track id of the stereo mix (defaults to the flac with \"stereo\" in its name)")
+                )
+        )
+        .get_matches_safe()
+        .unwrap_or_else(|e| match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                std::process::exit(USAGE_EXIT_CODE);
+            }
+        });
+
+    progress::enable_json(matches.value_of("progress-format") == Some("json"));
+    let ctx = run_context(&matches)?;
+    // on stderr, so that what --json prints can be piped into something that reads it
+    eprintln!("cb_processor {}", cb_processor::VERSION);
+    refuse_network(&ctx, &matches)?;
+    let update_notice = update::check(&ctx, matches.is_present("check-update"));
+    if let Some(notice) = &update_notice {
+        println!("{}", notice);
+    }
+    if matches.is_present("check-update") {
+        if update_notice.is_none() {
+            println!("No newer release found");
+        }
+        return Ok(());
+    }
+
+    let result = run(&matches, &ctx).and_then(|()| report_skipped(&ctx));
+    if let Some(cache) = &ctx.media_cache {
+        if let Err(e) = cache.save() {
+            println!("Warning: failed to save the media info cache: {:#}", e);
+        }
+    }
+    if let Err(e) = ctx.hashes.save() {
+        println!("Warning: failed to save the hash cache: {:#}", e);
+    }
+    ctx.report_timings();
+    ctx.write_metrics();
+    result
+}
+
+/// Lists the recordings that `--keep-going` left out, which fails the run if there were any
+fn report_skipped(ctx: &RunContext) -> Result<(), anyhow::Error> {
+    let skipped = ctx.skipped.lock().unwrap();
+    if skipped.is_empty() {
+        return Ok(());
+    }
+    println!("\n{} recordings couldn't be loaded, and were left out:", skipped.len());
+    for skip in skipped.iter() {
+        println!("  {}: {}", skip.path.display(), skip.error);
+    }
+    bail!("{} recordings couldn't be loaded", skipped.len());
+}
+
+/// Prints what `--diff-output` found, which fails the run if anything would change
+fn report_diff(diff: &OutputDiff) -> Result<(), anyhow::Error> {
+    let changes = diff.changes();
+    for change in &changes {
+        let old = match change.kind {
+            ChangeKind::Added => "/dev/null".to_string(),
+            ChangeKind::Changed => change.path.display().to_string(),
+        };
+        println!("{}", format!("--- {}", old).bold());
+        println!("{}", format!("+++ {}", change.path.display()).bold());
+        for line in &change.diff {
+            match line.chars().next() {
+                Some('+') => println!("{}", line.green()),
+                Some('-') => println!("{}", line.red()),
+                Some('@') => println!("{}", line.cyan()),
+                _ => println!("{}", line),
+            }
+        }
+        if change.omitted > 0 {
+            println!("... {} more lines", change.omitted);
+        }
+    }
+    let added = changes.iter().filter(|c| c.kind == ChangeKind::Added).count();
+    println!(
+        "\n{} files would change ({} of them new), {} are unchanged",
+        changes.len(),
+        added,
+        diff.unchanged_count()
+    );
+    if !changes.is_empty() {
+        bail!("Generating would change {} files", changes.len());
+    }
+    Ok(())
+}
+
+fn run(matches: &ArgMatches, ctx: &RunContext) -> Result<(), anyhow::Error> {
+    if let Some(matches) = matches.subcommand_matches("fmt") {
+        let check = matches.is_present("check");
+        let changed = json_format::format_season(Path::new(matches.value_of("input").unwrap()), check)?;
+        for path in &changed {
+            if check {
+                println!("{} is not formatted", path.display());
+            } else {
+                println!("Formatted {}", path.display());
+            }
+        }
+        if check && !changed.is_empty() {
+            bail!(
+                "{} files are not formatted, run cb_processor fmt to fix them",
+                changed.len()
+            );
+        }
+        if changed.is_empty() {
+            println!("Everything is formatted already");
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("init") {
+        let dir = Path::new(matches.value_of("dir").unwrap());
+        let created = scaffold::init(
+            dir,
+            matches.value_of("title").unwrap(),
+            matches.value_of("artist").unwrap(),
+        )?;
+        println!("Created {} files in {}", created.len(), dir.display());
+        println!(
+            "Check that everything is in order with:\n  cb_processor --validate --input {} --data {}",
+            dir.join("data/season.json").display(),
+            dir.join("audio").display()
+        );
+
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("history-report") {
+        let path = matches
+            .value_of("history")
+            .map_or(ctx.roots_history.as_path(), Path::new);
+        let entries = history::load(path)?;
+        if entries.is_empty() {
+            println!("No roots recorded in {} yet", path.display());
+            return Ok(());
+        }
+        let rows = history::report(ctx, &entries);
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        } else {
+            print!("{}", history::table(&rows));
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rollback") {
+        let path = matches
+            .value_of("history")
+            .map_or(ctx.roots_history.as_path(), Path::new);
+        let entries = history::load(path)?;
+        let current = match entries.last() {
+            Some(entry) => entry.root.clone(),
+            None => usage_error(&format!("There are no roots in {} to roll back to", path.display())),
+        };
+        if !matches.is_present("to") {
+            print!("{}", history::choices(&entries));
+        }
+        let choice = arg_or_prompt(matches, "to", "Roll back to (number or root)")?;
+        let target = match history::find(&entries, &choice) {
+            Some(entry) => entry.root.clone(),
+            None => usage_error(&format!("{:?} is not one of the roots in {}", choice, path.display())),
+        };
+        if target == current {
+            bail!("{} is already the latest root, there's nothing to roll back", target);
+        }
+        let root = cid::Cid::from_str(&target).with_context(|| format!("{} has an invalid root", path.display()))?;
+
+        let missing = ctx.stage(Stage::Verify, || ipfs::missing_blocks(ctx, &root))?;
+        if !missing.is_empty() {
+            for path in missing.iter().take(10) {
+                println!(" {}: {} is not in the local IPFS repo", "ERROR".red(), path);
+            }
+            if missing.len() > 10 {
+                println!(" ... and {} more", missing.len() - 10);
+            }
+            bail!(
+                "Not rolling back to {}, since {} parts of it are missing locally",
+                root,
+                missing.len()
+            );
+        }
+
+        let key = matches.value_of("ipns-key").unwrap();
+        // the stage is timed from after the confirmation, so that it doesn't count how long it took to answer
+        confirm(
+            matches,
+            &format!("About to roll back the IPNS key {:?} from {} to {}", key, current, root),
+        )?;
+        ctx.stage(Stage::Publish, || {
+            ipfs::pin(ctx, &root)?;
+            Ok::<_, anyhow::Error>(ipfs::publish_name(ctx, &root, key)?)
+        })?;
+        let now = std::time::SystemTime::now();
+        history::record_rollback(path, &root, &current, now, ctx.retention.roots_history)?;
+        println!("Rolled back to {}", root);
+        return Ok(());
+    }
+
+    if matches.subcommand_matches("doctor").is_some() {
+        return doctor(ctx);
+    }
+
+    if let Some(matches) = matches
+        .subcommand_matches("gateways")
+        .and_then(|m| m.subcommand_matches("status"))
+    {
+        let state: gateway::PrimeState = state_store::read_doc(&ctx.prime_state)?.unwrap_or_default();
+        let rows = state.health_rows(&gateway::gateways(&ctx.gateways), ctx.unhealthy_after);
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        } else {
+            print!("{}", gateway::health_table(&rows));
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("clean") {
+        let all = matches.is_present("all-generated");
+        let data_dir = matches.value_of("data-dir").map(Path::new);
+        let output = matches.value_of("output").map(Path::new);
+        if all && data_dir.is_none() && output.is_none() {
+            usage_error("--all-generated needs --data, --output, or both");
+        }
+        let scope = clean::CleanScope {
+            derived_audio: all || matches.is_present("derived-audio"),
+            html: all || matches.is_present("html"),
+        };
+        let items = clean::plan_clean(
+            ctx,
+            Path::new(matches.value_of("input").unwrap()),
+            data_dir,
+            output,
+            scope,
+        )?;
+        let total: u64 = items.iter().map(|i| i.bytes).sum();
+        for item in &items {
+            println!("{:>8}KB  {}", item.bytes / 1024, item.path.display());
+        }
+        if !matches.is_present("yes") {
+            println!(
+                "Would delete {} files ({}MB), run again with --yes to delete them",
+                items.len(),
+                total / 1024 / 1024
+            );
+            return Ok(());
+        }
+        let freed = clean::remove(&items)?;
+        println!("Deleted {} files ({}MB)", items.len(), freed / 1024 / 1024);
+
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("drift-check") {
+        let root = root_hash_or_latest(ctx, matches, "to check against")?;
+        let season_json = Path::new(matches.value_of("input").unwrap());
+        let season = load_for_generation(ctx, matches, season_json)?;
+
+        let generated = std::env::temp_dir().join(format!("cb_processor-drift-{}", std::process::id()));
+        let result = drift_check(ctx, matches, season_json, &season, &root, &generated);
+        let _ = std::fs::remove_dir_all(&generated);
+        return result;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-stats") {
+        let format = matches.value_of("log-format").unwrap_or(stats::COMBINED_LOG_FORMAT);
+        let format = match regex::Regex::new(format) {
+            Ok(format) => format,
+            Err(e) => usage_error(&format!("--log-format is not a valid regex: {}", e)),
+        };
+        if !format.capture_names().any(|name| name == Some("path")) {
+            usage_error("--log-format has to capture the request path as `path`, like (?P<path>\\S+)");
+        }
+        let stats_file = matches
+            .value_of("stats")
+            .map_or_else(|| ctx.stats_file.clone(), PathBuf::from);
+        let log_path = Path::new(matches.value_of("log").unwrap());
+        let log = std::fs::read(log_path).with_context(|| format!("Failed to read {}", log_path.display()))?;
+
+        let season_json = Path::new(matches.value_of("input").unwrap());
+        let season = load_for_generation(ctx, matches, season_json)?;
+        let mut counts = stats::Stats::load(&stats_file)?;
+        let report = stats::import(&mut counts, &season, &log, &format);
+        print!("{}", report.summary());
+        if !report.already_imported {
+            counts.save(&stats_file)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-from-ipfs") {
+        let root = root_hash_arg(matches, "to import from");
+        let season_json = Path::new(matches.value_of("input").unwrap());
+        let md_file = Path::new(matches.value_of("metadata").unwrap());
+        let (mut season, report) = ctx.stage(Stage::Fetch, || ipfs_import::import_season(ctx, season_json, &root))?;
+        if md_file.exists() {
+            let cached = cb_processor::load_metadata(md_file)?;
+            analysis::carry_over_loudness(&mut season, &cached);
+            season.carry_over(&cached);
+        }
+        for warning in &report.warnings {
+            println!("  {}: {}", "WARNING".yellow(), warning);
+        }
+        print!("{}", report.summary());
+        cb_processor::write_metadata(&season, md_file, matches.is_present("force-metadata"))?;
+        println!("Wrote {}", md_file.display());
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("share") {
+        let now = std::time::SystemTime::now();
+        if matches.is_present("expire") {
+            let expired = share::expire(ctx, now)?;
+            for share in &expired {
+                println!("Unpinned {} (shared {})", share.root, share.data_folder);
+            }
+            println!("{} shares expired", expired.len());
+            return Ok(());
+        }
+        let days = match matches.value_of("expires-in").map(str::parse::<u64>) {
+            None => share::DEFAULT_EXPIRY_DAYS,
+            Some(Ok(days)) if days > 0 && days <= share::MAX_EXPIRY_DAYS => days,
+            Some(_) => usage_error(&format!(
+                "--expires-in has to be a whole number of days, up to {}",
+                share::MAX_EXPIRY_DAYS
+            )),
+        };
+        let data_folder = matches.value_of("data-folder").unwrap();
+        let dir = std::env::temp_dir().join(format!("cb_processor-share-{}", std::process::id()));
+        let shared = share::prepare(
+            ctx,
+            Path::new(matches.value_of("input").unwrap()),
+            Path::new(matches.value_of("data-dir").unwrap()),
+            data_folder,
+            &dir,
+        )
+        .and_then(|recording| {
+            let expires_in = std::time::Duration::from_secs(days * 24 * 60 * 60);
+            Ok(share::publish(ctx, &dir, &recording, expires_in, now)?)
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+        let shared = shared?;
+        println!(
+            "Shared {} as {}, pinned until {} (UTC)",
+            data_folder,
+            shared.root,
+            timing::utc_time(shared.expires)
+        );
+        for url in share::urls(ctx, &shared)? {
+            println!("  {}", url);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("add-recording") {
+        let title = arg_or_prompt(matches, "title", "Title (like S02EXX - Jam Y)")?;
+        let recorded_date = arg_or_prompt(matches, "date", "Recorded date (YYYY/MM/DD)")?;
+        let tags = if let Some(tags) = matches.values_of("tag") {
+            tags.map(String::from).collect()
+        } else {
+            arg_or_prompt(matches, "tag", "Tags (comma separated)")?
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect()
+        };
+        let stereo_mix_id = matches.value_of("stereo-mix-id").map(|id| match id.parse() {
+            Ok(id) => id,
+            Err(_) => usage_error(&format!("--stereo-mix-id {:?} is not a valid track id", id)),
+        });
+
+        let json_path = scaffold::add_recording(&scaffold::NewRecordingOptions {
+            season_json: Path::new(matches.value_of("input").unwrap()),
+            folder: Path::new(matches.value_of("folder").unwrap()),
+            data_dir: matches.value_of("data-dir").map(Path::new),
+            json_path: matches.value_of("json").map(Path::new),
+            title,
+            recorded_date,
+            tags,
+            track_id_regex: matches.value_of("track-id-regex").unwrap(),
+            stereo_mix_id,
+        })?;
+        println!("Wrote new recording to {}", json_path.display());
+
+        return Ok(());
+    }
 
     if matches.is_present("prime") {
-        let root_hash = matches.value_of("hash").expect("Missing --hash argument");
-        let root_hash = cid::Cid::from_str(root_hash).unwrap();
-        cb_processor::ipfs::prime_public_gateways(&root_hash)?;
+        let root_hash = root_hash_arg(matches, "for priming");
+        let mode = if matches.is_present("fresh") {
+            gateway::PrimeMode::Fresh
+        } else if matches.is_present("resume") {
+            gateway::PrimeMode::Resume
+        } else {
+            gateway::PrimeMode::Auto
+        };
+        let budget = matches.value_of("budget").map(|budget| {
+            budget
+                .parse::<gateway::PrimeBudget>()
+                .unwrap_or_else(|e| usage_error(&format!("--budget: {}", e)))
+        });
+        let first = match matches.value_of("prime-first") {
+            Some(report_file) => {
+                let patched = ipfs::PatchedLinks::read(Path::new(report_file))?;
+                if patched.new_root == root_hash.to_string() {
+                    patched.changed
+                } else {
+                    println!(
+                        "{}: {} is the report of the patch that made {}, not {}, so nothing is primed first",
+                        "WARNING".yellow(),
+                        report_file,
+                        patched.new_root,
+                        root_hash
+                    );
+                    Vec::new()
+                }
+            }
+            None => Vec::new(),
+        };
+        let options = gateway::PrimeOptions { mode, first, budget };
+        let report = Pipeline::new(ctx).prime(&root_hash, &options)?;
+        if let Some(report_file) = matches.value_of("prime-report") {
+            let f = File::create(report_file).with_context(|| format!("Failed to create {}", report_file))?;
+            serde_json::to_writer_pretty(f, &report).with_context(|| format!("Failed to write {}", report_file))?;
+        }
+
+        return Ok(());
+    }
+
+    if matches.is_present("benchmark-gateways") {
+        let root_hash = root_hash_arg(matches, "to benchmark with");
+        let samples = match matches.value_of("samples").unwrap_or("5").parse() {
+            Ok(n) if n > 0 => n,
+            _ => usage_error("--samples must be a number greater than 0"),
+        };
+        let report = ctx.stage(Stage::Benchmark, || gateway::benchmark(ctx, &root_hash, samples))?;
+        println!("Fetched {} {} times from each gateway", report.path, samples);
+        print!("{}", gateway::table(&report));
+        if let Some(json_file) = matches.value_of("benchmark-json") {
+            let f = File::create(json_file).with_context(|| format!("Failed to create {}", json_file))?;
+            serde_json::to_writer_pretty(f, &report).with_context(|| format!("Failed to write {}", json_file))?;
+        }
+
+        return Ok(());
+    }
+
+    if matches.is_present("fetch") {
+        let root_hash = root_hash_or_latest(ctx, matches, "to fetch from")?;
+        let formats = fetch::parse_formats(matches.value_of("formats").unwrap())
+            .unwrap_or_else(|e| usage_error(&format!("--formats: {}", e)));
+        let source = match matches.value_of("gateway") {
+            Some(gateway) => fetch::Source::Gateway(gateway.to_string()),
+            None => fetch::Source::Ipfs,
+        };
+        let input = Path::new(required_arg(matches, "input", "to fetch"));
+        let data_dir = Path::new(required_arg(matches, "data-dir", "to fetch into"));
+        let md_file = Path::new(required_arg(matches, "metadata", "to know which files to fetch"));
+
+        let cached = cb_processor::load_metadata(md_file)?;
+        let season = ctx.stage(Stage::Load, || Season::load(ctx, input, None, Some(&cached)))?;
+        let items = fetch::plan_fetch(ctx, &season, data_dir, &formats)?;
+        if items.is_empty() {
+            println!("Nothing to fetch, {} already has every file", data_dir.display());
+            return Ok(());
+        }
+        let fetched = fetch::fetch_all(ctx, &root_hash, &items, &source)?;
+        println!("Fetched {} files into {}", fetched, data_dir.display());
 
         return Ok(());
     }
 
     if matches.is_present("patch") {
-        let root_hash = matches.value_of("hash").expect("Missing --hash argument");
-        let root_dir = Path::new(matches.value_of("output").expect("Missing --output argument"));
-        let root_hash = cid::Cid::from_str(root_hash).unwrap();
-        let new_cid = cb_processor::ipfs::patch_root_object(&root_hash, root_dir)?;
+        let root_hash = root_hash_arg(matches, "for patching");
+        let root_dir = Path::new(required_arg(matches, "output", "for patching"));
+        let _lock = lock::acquire(root_dir, lock::OUTPUT_LOCK, matches.is_present("force-unlock"))?;
+
+        let pipeline = Pipeline::new(ctx);
+
+        // a dry run shows the verdict with the plan, a real one doesn't add anything for a root it won't patch
+        let dry_run = matches.is_present("dry-run");
+        let check = pipeline.check_root(&root_hash, root_dir)?;
+        if !dry_run {
+            check.enforce(ctx.force_patch)?;
+        }
+        let plan = pipeline.plan_patch(&root_hash, root_dir)?;
+        if dry_run {
+            if plan.changes.is_empty() {
+                println!("Nothing to patch, {} already matches {}", root_hash, root_dir.display());
+            } else {
+                print!(
+                    "Would patch {} links in {}:\n{}",
+                    plan.num_changes(),
+                    root_hash,
+                    plan.summary()
+                );
+            }
+            print!("{}", check.verdict());
+            check.enforce(ctx.force_patch)?;
+            return Ok(());
+        }
+        print!("{}", check.verdict());
+        if !check.passed() {
+            println!("Patching it anyway, because of --force");
+        }
+        // asked between the planning and the patching stages, so that the wait isn't timed as part of either
+        if plan.changes.is_empty() {
+            println!("Nothing to patch, {} already matches {}", root_hash, root_dir.display());
+        } else {
+            confirm(
+                matches,
+                &format!(
+                    "About to replace the root {} with one that has {} links patched:\n{}",
+                    root_hash,
+                    plan.num_changes(),
+                    plan.summary()
+                ),
+            )?;
+        }
+        let patched = pipeline.patch(&root_hash, root_dir, plan)?;
+        let new_cid = patched.new_root;
 
         println!("New root object {}", new_cid);
         let b32 = cid::Cid::new_v1(new_cid.codec(), new_cid.hash().to_owned());
@@ -90,56 +1657,205 @@ fn main() -> Result<(), anyhow::Error> {
             "https://{}.ipfs.dweb.link",
             b32.to_string_of_base(multibase::Base::Base32Lower).unwrap()
         );
+        if let Some(report) = &patched.report {
+            let previous = if root_hash == new_cid {
+                None
+            } else {
+                Some(&report.old_sizes)
+            };
+            print!("Size of {}:\n{}", new_cid, report.new_sizes.human(previous));
+            if let Some(report_file) = matches.value_of("patch-report") {
+                let f = File::create(report_file).with_context(|| format!("Failed to create {}", report_file))?;
+                serde_json::to_writer_pretty(f, report).with_context(|| format!("Failed to write {}", report_file))?;
+            }
+        }
+
+        if matches.is_present("verify") {
+            let discrepancies = ctx.stage(Stage::Verify, || ipfs::verify_patch(ctx, &new_cid, root_dir))?;
+            if !discrepancies.is_empty() {
+                for discrepancy in &discrepancies {
+                    println!(" {}: {}", "ERROR".red(), discrepancy);
+                }
+                bail!(
+                    "{} doesn't match {} ({} differences), not publishing it",
+                    new_cid,
+                    root_dir.display(),
+                    discrepancies.len()
+                );
+            }
+            println!("{} matches {}", new_cid, root_dir.display());
+        }
+
+        if matches.is_present("publish") {
+            let key = matches.value_of("ipns-key").unwrap();
+            confirm(
+                matches,
+                &format!(
+                    "About to publish {} to the IPNS key {:?}, replacing {}",
+                    new_cid, key, root_hash
+                ),
+            )?;
+            pipeline.publish(&patched, key, matches.is_present("notify-dry-run"))?;
+        }
+
+        // and for scripts, the new root on a line of its own after everything else about the patch
         println!("{}", new_cid);
+        return Ok(());
+    }
 
+    if let Some(file) = matches.value_of("show-provenance") {
+        let (folder, record) =
+            provenance::find(Path::new(file))?.with_context(|| format!("No conversion of {} was recorded", file))?;
+        println!("{}", provenance::shell_command(&record.command));
+        println!("  ffmpeg: {}", record.ffmpeg_version);
+        println!(
+            "  source: {} (sha256 {})",
+            folder.join(&record.input).display(),
+            record.input_sha256
+        );
         return Ok(());
     }
 
-    let season_json_path = Path::new(matches.value_of("input").expect("Missing --input argument"));
+    let season_json_path = Path::new(required_arg(matches, "input", "to read the season"));
 
     if matches.is_present("validate") {
-        let data_dir_path = Path::new(matches.value_of("data-dir").expect("Missing --data argument"));
-        let errors_found = validate_and_print(season_json_path, data_dir_path)?;
-        if errors_found > 0 {
-            bail!("Found {} errors, review the logs above", errors_found);
+        let data_dir_path = Path::new(required_arg(matches, "data-dir", "for validation"));
+        let pipeline = Pipeline::new(ctx);
+        let report = pipeline.validate(season_json_path, data_dir_path)?;
+        if report.errors > 0 {
+            bail!("Found {} errors, review the logs above", report.errors);
+        }
+
+        if matches.is_present("analyze") {
+            let season = pipeline.load(season_json_path, Some(data_dir_path), None)?;
+            let warnings = analysis::analyze_season(ctx, &season)?;
+            println!("\nNo errors found, {} warnings from analysis", warnings);
         } else {
             println!("\nNo errors found");
-            return Ok(());
         }
+        return Ok(());
     }
 
-    if matches.is_present("convert") {
-        // convert mode needs access to the latest data, we can't run this from metadata
-        let data_dir_path = Path::new(matches.value_of("data-dir").expect("Missing --data argument"));
-        let season = Season::load(season_json_path, Some(data_dir_path), None)?;
+    if matches.is_present("find-duplicates") {
+        let data_dir_path = Path::new(required_arg(matches, "data-dir", "to find duplicates in"));
+        let season = ctx.stage(Stage::Load, || {
+            Season::load(ctx, season_json_path, Some(data_dir_path), None)
+        })?;
+        let report = duplicates::find_duplicates(ctx, &season)?;
+        print!("{}", report.human());
+        if let Some(json_file) = matches.value_of("duplicates-json") {
+            let f = File::create(json_file).with_context(|| format!("Failed to create {}", json_file))?;
+            serde_json::to_writer_pretty(f, &report).with_context(|| format!("Failed to write {}", json_file))?;
+        }
+        return Ok(());
+    }
 
-        cb_processor::convert_all(&season)?;
+    if matches.is_present("archive-upload") {
+        let data_dir_path = Path::new(required_arg(matches, "data-dir", "to upload from"));
+        let md_file = Path::new(required_arg(matches, "metadata", "to record the archive.org links"));
+        let credentials = archive_org::Credentials::from_env()
+            .unwrap_or_else(|| usage_error("IAS3_ACCESS_KEY and IAS3_SECRET_KEY must be set for --archive-upload"));
+        let mut season = ctx.stage(Stage::Load, || {
+            Season::load(ctx, season_json_path, Some(data_dir_path), None)
+        })?;
+        if md_file.exists() {
+            let cached = cb_processor::load_metadata(md_file)?;
+            analysis::carry_over_loudness(&mut season, &cached);
+            season.carry_over(&cached);
+        }
+
+        // save the links of the recordings that did get uploaded, even if some didn't
+        let result = archive_org::upload_missing(ctx, &mut season, &credentials);
+        cb_processor::write_metadata(&season, md_file, matches.is_present("force-metadata"))?;
+        println!("Uploaded {} recordings to archive.org", result?);
 
         return Ok(());
     }
 
-    let season: Season = if let Some(data_dir_path) = matches.value_of("data-dir") {
-        Season::load(season_json_path, Some(Path::new(data_dir_path)), None)?
-    } else {
-        let md_file = matches
-            .value_of("metadata")
-            .expect("Missing --data or --metadata argment");
-        let f = File::open(md_file)?;
-        let cached_season: Season = serde_json::from_reader(f)?;
+    if matches.is_present("measure-loudness") {
+        let data_dir_path = Path::new(required_arg(matches, "data-dir", "for measuring loudness"));
+        let md_file = Path::new(required_arg(matches, "metadata", "to store loudness measurements"));
+        let mut season = ctx.stage(Stage::Load, || {
+            Season::load(ctx, season_json_path, Some(data_dir_path), None)
+        })?;
+        if md_file.exists() {
+            let cached = cb_processor::load_metadata(md_file)?;
+            analysis::carry_over_loudness(&mut season, &cached);
+            season.carry_over(&cached);
+        }
 
-        Season::load(season_json_path, None, Some(&cached_season))?
-    };
+        let measured = analysis::measure_missing_loudness(ctx, &mut season)?;
+        println!("Measured the loudness of {} files", measured);
+        cb_processor::write_metadata(&season, md_file, matches.is_present("force-metadata"))?;
 
-    // Output dir for html and stuff (should probably the same as the --data dir)
-    let output_root = Path::new(matches.value_of("output").expect("Missing --output argument"));
+        return Ok(());
+    }
+
+    if matches.is_present("convert") {
+        // convert mode needs access to the latest data, we can't run this from metadata
+        let data_dir_path = Path::new(required_arg(matches, "data-dir", "for conversion"));
+        let _lock = lock::acquire(data_dir_path, lock::DATA_LOCK, matches.is_present("force-unlock"))?;
+        let pipeline = Pipeline::new(ctx);
+        let season = pipeline.load(season_json_path, Some(data_dir_path), None)?;
+
+        let options = ConvertOptions {
+            retry_quarantined: matches.is_present("retry-quarantined"),
+            spectrograms: matches.is_present("spectrograms"),
+            peaks: matches.is_present("peaks"),
+            retag: matches.is_present("retag"),
+        };
+        let report = pipeline.convert(&season, data_dir_path, options)?;
+        if options.retry_quarantined {
+            println!("Took {} flacs out of quarantine", report.unquarantined);
+        }
+        if options.retag {
+            println!("Retagged {} files", report.retagged.len());
+        }
+        for conversion in &report.conversions {
+            println!(
+                "Converted {} to {} with {}",
+                conversion.input.display(),
+                conversion.output.display(),
+                conversion.filters
+            );
+        }
+        if options.spectrograms {
+            println!("Drew {} spectrograms", report.spectrograms);
+        }
+        if options.peaks {
+            println!("Worked out the peaks of {} tracks", report.peaks);
+        }
+        let replaced = report.conversions.iter().filter(|c| c.replaced).count();
+        println!(
+            "{} files were up to date, {} were converted again and {} were new",
+            report.up_to_date,
+            replaced,
+            report.conversions.len() - replaced
+        );
 
-    cb_processor::write_season_index(&season, output_root)?;
+        return Ok(());
+    }
 
-    cb_processor::write_all_recording_index(&season, output_root)?;
+    // Output dir for html and stuff (should probably the same as the --data dir)
+    let output_root = Path::new(required_arg(matches, "output", "for generation"));
+    let _locks = generation_locks(matches, output_root)?;
 
-    // write out metadata file
-    if let Some(f) = matches.value_of("metadata").and_then(|s| File::create(s).ok()) {
-        serde_json::to_writer(f, &season)?;
+    let season = load_for_generation(ctx, matches, season_json_path)?;
+    let report = Pipeline::new(ctx).generate(
+        &season,
+        Generation {
+            season_json: season_json_path,
+            output: output_root,
+            data_dir: matches.value_of("data-dir").map(Path::new),
+            metadata: matches.value_of("metadata").map(Path::new),
+            force_metadata: matches.is_present("force-metadata"),
+        },
+    )?;
+    if let Some(diff) = &ctx.diff_output {
+        return report_diff(diff);
+    }
+    for name in report.stale {
+        println!("{} is no longer generated, clean --html will delete it", name);
     }
 
     Ok(())
@@ -0,0 +1,348 @@
+//! The list of files that generation wrote (`build_manifest.json` in the output)
+//!
+//! The output is often the data dir too, so the pages end up next to the flacs.  The manifest is what lets `clean`
+//! tell them apart: when the two folders overlap, only files listed in it are ever deleted from the output.
+//!
+//! Each file is listed with its size, SHA-256 and what kind of file it is.  The next generation uses that to leave
+//! files that would come out the same alone, and a run over the whole season moves whatever it no longer makes to
+//! `stale`, for `clean` to delete.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{error::CbError, hasher::HasherPool};
+
+pub const MANIFEST_FILE: &str = "build_manifest.json";
+/// Seconds since the Unix epoch to record as the generation time, in place of the clock, for reproducible builds
+pub const SOURCE_DATE_EPOCH_VAR: &str = "SOURCE_DATE_EPOCH";
+
+/// What a generated file is for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Html,
+    Feed,
+    Playlist,
+    Static,
+    /// Data for other programs, like metadata.json
+    Api,
+}
+
+impl Category {
+    /// Tells the kind of file from its name
+    pub fn of(path: &Path) -> Category {
+        // a compressed copy is the same kind of file as what it's a copy of
+        let path = match path.extension() {
+            Some(ext) if ext == "gz" || ext == "br" => Path::new(path.file_stem().unwrap_or_default()),
+            _ => path,
+        };
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "html" => Category::Html,
+            "rss" | "atom" | "xml" => Category::Feed,
+            "m3u" | "m3u8" => Category::Playlist,
+            "json" => Category::Api,
+            _ => Category::Static,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub sha256: String,
+    pub category: Category,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildManifest {
+    pub generator: String,
+    /// When the last generation finished, in seconds since the Unix epoch (or [`SOURCE_DATE_EPOCH_VAR`], if it's set)
+    #[serde(default)]
+    pub generated_at: u64,
+    /// Paths relative to the output, with `/` separators
+    #[serde(deserialize_with = "files_or_names")]
+    pub files: BTreeMap<String, ManifestEntry>,
+    /// Files that earlier runs generated but the last full run didn't, and that are still around
+    #[serde(default)]
+    pub stale: BTreeSet<String>,
+}
+
+/// Manifests used to only list the names of the files, which read as entries that never match anything
+fn files_or_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, ManifestEntry>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Files {
+        Entries(BTreeMap<String, ManifestEntry>),
+        Names(BTreeSet<String>),
+    }
+    Ok(match Files::deserialize(deserializer)? {
+        Files::Entries(entries) => entries,
+        Files::Names(names) => names
+            .into_iter()
+            .map(|name| {
+                let entry = ManifestEntry {
+                    size: 0,
+                    sha256: String::new(),
+                    category: Category::of(Path::new(&name)),
+                };
+                (name, entry)
+            })
+            .collect(),
+    })
+}
+
+impl BuildManifest {
+    /// Reads the manifest in `output`, if there is one
+    pub fn load(output: &Path) -> Result<Option<BuildManifest>, CbError> {
+        let path = output.join(MANIFEST_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|e| CbError::parse(format!("Unexpected contents in {}", path.display()), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+        }
+    }
+
+    /// Adds the files that were just written (or found to be up to date) to the manifest in `output`
+    ///
+    /// Files from earlier runs stay listed, since `--only` regenerates just some of the pages, unless the run was
+    /// `complete`: then the ones it didn't write are moved to the stale list, and returned.  Files outside of `output`
+    /// are ignored.
+    pub fn record(
+        hashes: &HasherPool, output: &Path, written: &[PathBuf], complete: bool,
+    ) -> Result<Vec<String>, CbError> {
+        let mut manifest = BuildManifest::load(output)?.unwrap_or_default();
+        manifest.generator = crate::GENERATOR.to_string();
+        manifest.generated_at = match std::env::var(SOURCE_DATE_EPOCH_VAR)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+        {
+            Some(epoch) => epoch,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        };
+
+        let mut current = BTreeSet::new();
+        for path in written {
+            if let Ok(rel) = path.strip_prefix(output) {
+                let size = std::fs::metadata(path)
+                    .map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?
+                    .len();
+                let entry = ManifestEntry {
+                    size,
+                    sha256: hashes.sha256(path)?,
+                    category: Category::of(path),
+                };
+                let name = relative_name(rel);
+                manifest.stale.remove(&name);
+                manifest.files.insert(name.clone(), entry);
+                current.insert(name);
+            }
+        }
+        let mut newly_stale = Vec::new();
+        if complete {
+            let gone: Vec<String> = manifest
+                .files
+                .keys()
+                .filter(|name| !current.contains(*name))
+                .cloned()
+                .collect();
+            for name in gone {
+                manifest.files.remove(&name);
+                manifest.stale.insert(name.clone());
+                newly_stale.push(name);
+            }
+        }
+        // once clean has deleted them, there's nothing left to remember
+        manifest.stale.retain(|name| output.join(name).exists());
+        newly_stale.retain(|name| manifest.stale.contains(name));
+
+        let path = output.join(MANIFEST_FILE);
+        let bytes = crate::to_json_bytes(&manifest).expect("a manifest is always valid JSON");
+        std::fs::write(&path, bytes).map_err(|e| CbError::io(format!("Failed to write {}", path.display()), e))?;
+        Ok(newly_stale)
+    }
+
+    /// Whether `path` (somewhere in `output`) was generated, now or by an earlier run
+    pub fn contains(&self, output: &Path, path: &Path) -> bool {
+        path.strip_prefix(output).is_ok_and(|rel| {
+            let name = relative_name(rel);
+            self.files.contains_key(&name) || self.stale.contains(&name)
+        })
+    }
+
+    /// The files that are no longer generated, in `output`
+    pub fn stale_files(&self, output: &Path) -> Vec<PathBuf> {
+        self.stale.iter().map(|name| output.join(name)).collect()
+    }
+
+    /// Whether `path` (somewhere in `output`) already holds what has the SHA-256 `sha256`
+    ///
+    /// Only the size of the file on disk is checked, so that deciding not to write a page doesn't mean reading it.
+    pub fn unchanged(&self, output: &Path, path: &Path, sha256: &str) -> bool {
+        self.current_entry(output, path)
+            .is_some_and(|entry| entry.sha256 == sha256)
+    }
+
+    /// Whether `path` (somewhere in `output`) is still what the last generation left there, going by its size
+    pub fn is_current(&self, output: &Path, path: &Path) -> bool {
+        self.current_entry(output, path).is_some()
+    }
+
+    fn current_entry(&self, output: &Path, path: &Path) -> Option<&ManifestEntry> {
+        let entry = self.files.get(&relative_name(path.strip_prefix(output).ok()?))?;
+        let md = std::fs::metadata(path).ok()?;
+        Some(entry).filter(|entry| md.is_file() && md.len() == entry.size)
+    }
+}
+
+fn relative_name(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Whether `a` and `b` are the same folder, or one is inside the other
+///
+/// Both are canonicalized first (as far as they exist), so symlinks and `..` don't hide an overlap.
+pub fn overlaps(a: &Path, b: &Path) -> bool {
+    let (a, b) = (canonical(a), canonical(b));
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+/// Canonicalizes the part of `path` that exists, and adds the rest back on
+fn canonical(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir().unwrap_or_default().join(path)
+    };
+    let mut existing = absolute.as_path();
+    let mut rest = Vec::new();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return rest.iter().rev().fold(canonical, |p, c| p.join(c));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_owned());
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum;
+
+    #[test]
+    fn overlapping_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        std::fs::create_dir_all(data.join("jam1")).unwrap();
+        std::fs::create_dir_all(dir.path().join("site")).unwrap();
+
+        assert!(overlaps(&data, &data));
+        assert!(overlaps(&data, &data.join("jam1/..")));
+        assert!(overlaps(&data.join("jam1"), &data));
+        assert!(overlaps(&data, &data.join("not/made/yet")));
+        assert!(!overlaps(&data, &dir.path().join("site")));
+        // a sibling whose name starts the same isn't inside
+        assert!(!overlaps(&data, &dir.path().join("data2")));
+
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("output");
+            std::os::unix::fs::symlink(&data, &link).unwrap();
+            assert!(overlaps(&link, &data));
+            assert!(overlaps(&data.join("jam1"), &link));
+        }
+    }
+
+    #[test]
+    fn record() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path();
+        assert_eq!(BuildManifest::load(output).unwrap(), None);
+        std::fs::create_dir_all(output.join("jam1")).unwrap();
+        std::fs::write(output.join("index.html"), "<html>").unwrap();
+        std::fs::write(output.join("jam1/index.html"), "jam").unwrap();
+        std::fs::write(output.join("playlist.m3u"), "#EXTM3U").unwrap();
+
+        let all = [
+            output.join("index.html"),
+            output.join("jam1/index.html"),
+            output.join("playlist.m3u"),
+        ];
+        assert!(BuildManifest::record(&HasherPool::default(), output, &all, true)
+            .unwrap()
+            .is_empty());
+        // --only keeps what it didn't write
+        let stale = BuildManifest::record(
+            &HasherPool::default(),
+            output,
+            &[output.join("index.html"), PathBuf::from("/elsewhere/x.html")],
+            false,
+        );
+        assert!(stale.unwrap().is_empty());
+        let manifest = BuildManifest::load(output).unwrap().unwrap();
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            ["index.html", "jam1/index.html", "playlist.m3u"]
+        );
+        assert_eq!(manifest.files["index.html"].size, 6);
+        assert_eq!(manifest.files["index.html"].sha256, checksum::sha256_bytes(b"<html>"));
+        assert_eq!(manifest.files["playlist.m3u"].category, Category::Playlist);
+        assert!(manifest.generated_at > 0);
+        assert!(manifest.contains(output, &output.join("jam1/index.html")));
+        assert!(!manifest.contains(output, &output.join("jam1/jam1.flac")));
+
+        assert!(manifest.unchanged(output, &output.join("index.html"), &checksum::sha256_bytes(b"<html>")));
+        assert!(!manifest.unchanged(output, &output.join("index.html"), &checksum::sha256_bytes(b"<body>")));
+        std::fs::write(output.join("index.html"), "edited by hand").unwrap();
+        assert!(!manifest.unchanged(output, &output.join("index.html"), &checksum::sha256_bytes(b"<html>")));
+
+        // a full run that no longer makes jam1's page leaves it for clean
+        let stale = BuildManifest::record(
+            &HasherPool::default(),
+            output,
+            &[output.join("index.html"), output.join("playlist.m3u")],
+            true,
+        );
+        assert_eq!(stale.unwrap(), ["jam1/index.html"]);
+        let manifest = BuildManifest::load(output).unwrap().unwrap();
+        assert!(!manifest.files.contains_key("jam1/index.html"));
+        assert!(manifest.contains(output, &output.join("jam1/index.html")));
+        assert_eq!(manifest.stale_files(output), [output.join("jam1/index.html")]);
+        std::fs::remove_file(output.join("jam1/index.html")).unwrap();
+        BuildManifest::record(&HasherPool::default(), output, &[output.join("index.html")], false).unwrap();
+        assert!(BuildManifest::load(output).unwrap().unwrap().stale.is_empty());
+    }
+
+    #[test]
+    fn old_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(MANIFEST_FILE),
+            r#"{"generator": "old", "files": ["index.html", "metadata.json"]}"#,
+        )
+        .unwrap();
+        let manifest = BuildManifest::load(dir.path()).unwrap().unwrap();
+        assert!(manifest.contains(dir.path(), &dir.path().join("index.html")));
+        assert_eq!(manifest.files["metadata.json"].category, Category::Api);
+        assert!(!manifest.unchanged(dir.path(), &dir.path().join("index.html"), ""));
+    }
+}
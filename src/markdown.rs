@@ -0,0 +1,177 @@
+//! The bit of markdown that recording descriptions can use
+//!
+//! Rather than rendering all of markdown and then sanitizing the HTML, everything is escaped and only these are turned
+//! into tags: paragraphs, `#` headings, `-` and `*` lists, `**bold**`, `*italics*`, `` `code` `` and
+//! `[links](https://example.com)`.  Links can only go to http, https and mailto URLs, or be relative.  Headings start
+//! at `<h3>`, since the page already has its own.
+
+/// Renders `markdown` to HTML that's safe to put in a page as it is
+pub fn to_html(markdown: &str) -> String {
+    let mut blocks = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut list: Vec<String> = Vec::new();
+
+    fn close(blocks: &mut Vec<String>, paragraph: &mut Vec<&str>, list: &mut Vec<String>) {
+        if !paragraph.is_empty() {
+            blocks.push(format!("<p>{}</p>", inline(&paragraph.join("\n"))));
+            paragraph.clear();
+        }
+        if !list.is_empty() {
+            let items: Vec<String> = list.iter().map(|item| format!("<li>{}</li>", inline(item))).collect();
+            blocks.push(format!("<ul>\n{}\n</ul>", items.join("\n")));
+            list.clear();
+        }
+    }
+
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+        if trimmed.is_empty() {
+            close(&mut blocks, &mut paragraph, &mut list);
+        } else if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+            close(&mut blocks, &mut paragraph, &mut list);
+            let level = (hashes + 2).min(6);
+            blocks.push(format!("<h{}>{}</h{}>", level, inline(trimmed[hashes..].trim()), level));
+        } else if let Some(item) = trimmed.strip_prefix("- ").or_else(|| trimmed.strip_prefix("* ")) {
+            if !paragraph.is_empty() {
+                close(&mut blocks, &mut paragraph, &mut list);
+            }
+            list.push(item.to_string());
+        } else if !list.is_empty() && line.starts_with(char::is_whitespace) {
+            // an indented line carries on the list item
+            let last = list.last_mut().unwrap();
+            last.push('\n');
+            last.push_str(trimmed);
+        } else {
+            if !list.is_empty() {
+                close(&mut blocks, &mut paragraph, &mut list);
+            }
+            paragraph.push(trimmed);
+        }
+    }
+    close(&mut blocks, &mut paragraph, &mut list);
+    blocks.join("\n")
+}
+
+/// Renders the emphasis, code and links in a line (or lines) of text
+fn inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let after = &rest[c.len_utf8()..];
+        match c {
+            '\\' if after.starts_with(|c: char| c.is_ascii_punctuation()) => {
+                let escaped = after.chars().next().unwrap();
+                out.push_str(&escape(&escaped.to_string()));
+                rest = &after[1..];
+                continue;
+            }
+            '`' => {
+                if let Some(end) = after.find('`') {
+                    out.push_str(&format!("<code>{}</code>", escape(&after[..end])));
+                    rest = &after[end + 1..];
+                    continue;
+                }
+            }
+            '*' if after.starts_with('*') => {
+                if let Some(end) = after[1..].find("**").filter(|&end| end > 0) {
+                    out.push_str(&format!("<strong>{}</strong>", inline(&after[1..end + 1])));
+                    rest = &after[end + 3..];
+                    continue;
+                }
+            }
+            // not when it's surrounded by spaces, like in 2 * 3
+            '*' if !after.starts_with(' ') => {
+                if let Some(end) = after.find('*').filter(|&end| end > 0) {
+                    out.push_str(&format!("<em>{}</em>", inline(&after[..end])));
+                    rest = &after[end + 1..];
+                    continue;
+                }
+            }
+            '[' => {
+                if let Some((label, url, len)) = link(after) {
+                    if safe_url(url) {
+                        out.push_str(&format!("<a href=\"{}\">{}</a>", escape(url), inline(label)));
+                    } else {
+                        out.push_str(&inline(label));
+                    }
+                    rest = &after[len..];
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        out.push_str(&escape(&c.to_string()));
+        rest = after;
+    }
+    out
+}
+
+/// Splits `label](url)...` into the label, the URL and how much of the text they took up
+fn link(text: &str) -> Option<(&str, &str, usize)> {
+    let label_end = text.find("](")?;
+    let url_start = label_end + 2;
+    let url_end = url_start + text[url_start..].find(')')?;
+    Some((&text[..label_end], text[url_start..url_end].trim(), url_end + 1))
+}
+
+/// Only links that can't run anything (so no `javascript:` and the like)
+fn safe_url(url: &str) -> bool {
+    let lower = url.to_ascii_lowercase();
+    match lower.find([':', '/', '?', '#']) {
+        Some(i) if lower.as_bytes()[i] == b':' => {
+            lower.starts_with("http://") || lower.starts_with("https://") || lower.starts_with("mailto:")
+        }
+        _ => true,
+    }
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#x27;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks() {
+        let html = to_html(
+            "# Guests\n\nWith **Jane** on *bass*\nand `the 303`.\n\n- kick drum\n  (borrowed)\n- no track 7\nThe end",
+        );
+        assert_eq!(
+            html,
+            "<h3>Guests</h3>\n<p>With <strong>Jane</strong> on <em>bass</em>\nand <code>the 303</code>.</p>\n\
+             <ul>\n<li>kick drum\n(borrowed)</li>\n<li>no track 7</li>\n</ul>\n<p>The end</p>"
+        );
+    }
+
+    #[test]
+    fn nothing_gets_through() {
+        assert_eq!(
+            to_html("<script>alert(1)</script> & \"quotes\""),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt; &amp; &quot;quotes&quot;</p>"
+        );
+        assert_eq!(
+            to_html("[ok](https://example.com/?a=1&b=2) [rel](../jam2/) [bad](javascript:alert)"),
+            "<p><a href=\"https://example.com/?a=1&amp;b=2\">ok</a> <a href=\"../jam2/\">rel</a> bad</p>"
+        );
+        assert_eq!(
+            to_html("[x](\" onclick=\"evil)"),
+            "<p><a href=\"&quot; onclick=&quot;evil\">x</a></p>"
+        );
+        assert_eq!(to_html("`<b>`"), "<p><code>&lt;b&gt;</code></p>");
+        assert_eq!(to_html("2 * 3 * 4, \\*not\\*, a_b_c"), "<p>2 * 3 * 4, *not*, a_b_c</p>");
+    }
+}
@@ -0,0 +1,244 @@
+//! A cache of MediaInfo results, kept next to the audio files
+//!
+//! Getting media info means running mediainfo (or at least opening the file), which adds up when a data dir has
+//! hundreds of files.  The cache remembers the result for each file along with its size and modification time, so a
+//! file is only looked at again once it has changed.  Once it has more files than `[retention]` allows, the ones that
+//! were changed longest ago are forgotten.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    state_store::{self, Retention},
+    MediaInfo,
+};
+
+/// Name of the cache file, in the root of the data dir
+pub const CACHE_FILE: &str = ".cb_mediainfo_cache.json";
+
+/// Enough about a file to tell if it has changed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Fingerprint {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+}
+
+impl Fingerprint {
+    pub(crate) fn of(path: &Path) -> Option<Fingerprint> {
+        let md = std::fs::metadata(path).ok()?;
+        let mtime = md.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Fingerprint {
+            size: md.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    fingerprint: Fingerprint,
+    info: MediaInfo,
+}
+
+#[derive(Debug)]
+pub struct MediaInfoCache {
+    root: PathBuf,
+    retention: Retention,
+    entries: Mutex<HashMap<String, Entry>>,
+    dirty: AtomicBool,
+}
+
+impl MediaInfoCache {
+    /// Opens the cache for a data dir.  With `refresh`, the existing cache is ignored (and replaced when saved)
+    pub fn open(root: &Path, refresh: bool, retention: Retention) -> MediaInfoCache {
+        let entries = if refresh {
+            HashMap::new()
+        } else {
+            Self::read(&root.join(CACHE_FILE))
+        };
+        MediaInfoCache {
+            root: root.to_path_buf(),
+            retention,
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(refresh),
+        }
+    }
+
+    /// Reads a cache file, treating a missing or corrupt file as empty
+    fn read(path: &Path) -> HashMap<String, Entry> {
+        match state_store::read_doc(path) {
+            Ok(entries) => entries.unwrap_or_default(),
+            Err(e) => {
+                say!("Warning: ignoring the media info cache: {:#}", anyhow::Error::new(e));
+                HashMap::new()
+            }
+        }
+    }
+
+    /// The key for a file, or None if it isn't in this data dir
+    fn key(&self, path: &Path) -> Option<String> {
+        path.strip_prefix(&self.root)
+            .ok()
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+    }
+
+    /// Returns the cached info for a file, if the file hasn't changed since it was cached
+    pub fn get(&self, path: &Path) -> Option<MediaInfo> {
+        let key = self.key(path)?;
+        let fingerprint = Fingerprint::of(path)?;
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)
+            .filter(|e| e.fingerprint == fingerprint)
+            .map(|e| e.info.clone())
+    }
+
+    pub fn insert(&self, path: &Path, info: &MediaInfo) {
+        if let (Some(key), Some(fingerprint)) = (self.key(path), Fingerprint::of(path)) {
+            let entry = Entry {
+                fingerprint,
+                info: info.clone(),
+            };
+            self.entries.lock().unwrap().insert(key, entry);
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Writes the cache back to disk, if anything changed
+    ///
+    /// Entries written by other processes in the meantime are kept, and the file is replaced atomically so that a
+    /// concurrent reader never sees half a file.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if !self.dirty.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let path = self.root.join(CACHE_FILE);
+        let entries = self.entries.lock().unwrap();
+        let mut merged = Self::read(&path);
+        for (key, entry) in entries.iter() {
+            merged.insert(key.clone(), entry.clone());
+        }
+        // only keep entries for files that still exist, and the newest of those that fit
+        merged.retain(|key, _| self.root.join(key).exists());
+        let mut by_age: Vec<(&String, &Entry)> = merged.iter().collect();
+        by_age.sort_by_key(|(key, entry)| (entry.fingerprint.mtime_secs, entry.fingerprint.mtime_nanos, *key));
+        let sizes: Vec<u64> = by_age
+            .iter()
+            .map(|(key, entry)| (key.len() + serde_json::to_vec(entry).map_or(0, |e| e.len())) as u64)
+            .collect();
+        let kept: HashMap<&String, &Entry> = by_age
+            .split_off(by_age.len() - self.retention.keep(&sizes))
+            .into_iter()
+            .collect();
+
+        state_store::write_doc(&path, &kept)?;
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEEP: Retention = crate::context::DEFAULT_MEDIA_CACHE_RETENTION;
+
+    fn info(duration: &str) -> MediaInfo {
+        MediaInfo {
+            t: "Audio".to_string(),
+            format: "FLAC".to_string(),
+            channels: "2".to_string(),
+            sample_rate: "48000".to_string(),
+            bit_depth: "24".to_string(),
+            duration: duration.to_string(),
+            bitrate: None,
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.flac");
+        std::fs::write(&file, b"first").unwrap();
+
+        let cache = MediaInfoCache::open(dir.path(), false, KEEP);
+        assert_eq!(cache.get(&file), None);
+        cache.insert(&file, &info("1.000"));
+        cache.save().unwrap();
+
+        let cache = MediaInfoCache::open(dir.path(), false, KEEP);
+        assert_eq!(cache.get(&file), Some(info("1.000")));
+        // files outside the data dir aren't cached
+        assert_eq!(cache.get(Path::new("Cargo.toml")), None);
+
+        // changing the file invalidates the entry
+        std::fs::write(&file, b"second, longer").unwrap();
+        assert_eq!(cache.get(&file), None);
+
+        // refreshing ignores what's there
+        std::fs::write(&file, b"first").unwrap();
+        let cache = MediaInfoCache::open(dir.path(), true, KEEP);
+        assert_eq!(cache.get(&file), None);
+    }
+
+    #[test]
+    fn corrupt_cache_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(CACHE_FILE), b"{ not json").unwrap();
+        let file = dir.path().join("a.flac");
+        std::fs::write(&file, b"data").unwrap();
+
+        let cache = MediaInfoCache::open(dir.path(), false, KEEP);
+        assert_eq!(cache.get(&file), None);
+        cache.insert(&file, &info("2.000"));
+        cache.save().unwrap();
+        assert_eq!(
+            MediaInfoCache::open(dir.path(), false, KEEP).get(&file),
+            Some(info("2.000"))
+        );
+    }
+
+    #[test]
+    fn forgets_the_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let two = Retention {
+            max_entries: 2,
+            max_bytes: 1 << 20,
+        };
+        let cache = MediaInfoCache::open(dir.path(), false, two);
+        let now = std::time::SystemTime::now();
+        let files: Vec<PathBuf> = (0..3u64)
+            .map(|n| {
+                let file = dir.path().join(format!("{}.flac", n));
+                std::fs::write(&file, b"data").unwrap();
+                // the first file is the one that was changed longest ago
+                let mtime = now - std::time::Duration::from_secs(100 - n);
+                std::fs::File::options()
+                    .write(true)
+                    .open(&file)
+                    .unwrap()
+                    .set_modified(mtime)
+                    .unwrap();
+                cache.insert(&file, &info("1.000"));
+                file
+            })
+            .collect();
+        cache.save().unwrap();
+
+        let cache = MediaInfoCache::open(dir.path(), false, two);
+        assert_eq!(cache.get(&files[0]), None);
+        assert!(cache.get(&files[1]).is_some());
+        assert!(cache.get(&files[2]).is_some());
+    }
+}
@@ -0,0 +1,360 @@
+//! Pipeline health for Prometheus, as a file for the node exporter's textfile collector (`metrics` in the config)
+//!
+//! A run collects its numbers in [`Metrics`] (shared by every clone of the [`RunContext`](crate::context::RunContext)),
+//! and [`write`] renders them at the end of the run.  Each run only does some of the stages, so the file it replaces is
+//! read first: the gauges of what this run didn't do are kept, and the counters keep counting from where they were.
+//!
+//! The names and labels below are what the dashboards and alerts use, so don't change them lightly.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{error::CbError, progress::Stage, timing::StageTiming};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Gauge,
+    Counter,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+        }
+    }
+}
+
+/// A metric in the file, with the name of its label if it has one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    pub label: Option<&'static str>,
+}
+
+pub const LAST_RUN: Metric = Metric {
+    name: "cb_processor_last_run_timestamp_seconds",
+    help: "When the last run finished",
+    kind: Kind::Gauge,
+    label: None,
+};
+pub const STAGE_LAST_RUN: Metric = Metric {
+    name: "cb_processor_stage_last_run_timestamp_seconds",
+    help: "When each stage last finished",
+    kind: Kind::Gauge,
+    label: Some("stage"),
+};
+pub const STAGE_DURATION: Metric = Metric {
+    name: "cb_processor_stage_duration_seconds",
+    help: "How long each stage took the last time it ran",
+    kind: Kind::Gauge,
+    label: Some("stage"),
+};
+pub const VALIDATION_ERRORS: Metric = Metric {
+    name: "cb_processor_validation_errors",
+    help: "Errors found by the last validation",
+    kind: Kind::Gauge,
+    label: None,
+};
+pub const VALIDATION_WARNINGS: Metric = Metric {
+    name: "cb_processor_validation_warnings",
+    help: "Warnings from the last validation",
+    kind: Kind::Gauge,
+    label: None,
+};
+pub const CONVERTED_BYTES: Metric = Metric {
+    name: "cb_processor_converted_bytes_total",
+    help: "Size of the files made by conversion",
+    kind: Kind::Counter,
+    label: None,
+};
+pub const IPFS_ADDED_BYTES: Metric = Metric {
+    name: "cb_processor_ipfs_added_bytes_total",
+    help: "Size of the files added to IPFS",
+    kind: Kind::Counter,
+    label: None,
+};
+pub const PRIME_FAILURES: Metric = Metric {
+    name: "cb_processor_prime_failures_total",
+    help: "Requests to a gateway that failed while priming",
+    kind: Kind::Counter,
+    label: Some("gateway"),
+};
+pub const ROOT_DAG_SIZE: Metric = Metric {
+    name: "cb_processor_root_dag_size_bytes",
+    help: "Size of the latest root, blocks and all",
+    kind: Kind::Gauge,
+    label: None,
+};
+
+/// Every metric, in the order they're written
+pub const ALL: &[Metric] = &[
+    LAST_RUN,
+    STAGE_LAST_RUN,
+    STAGE_DURATION,
+    VALIDATION_ERRORS,
+    VALIDATION_WARNINGS,
+    CONVERTED_BYTES,
+    IPFS_ADDED_BYTES,
+    PRIME_FAILURES,
+    ROOT_DAG_SIZE,
+];
+
+/// Values by metric name and label value ("" for metrics without a label)
+type Samples = BTreeMap<(&'static str, String), f64>;
+
+/// Collects the numbers of a run.  Clones share the same data
+#[derive(Default, Debug, Clone)]
+pub struct Metrics {
+    samples: Arc<Mutex<Samples>>,
+}
+
+impl Metrics {
+    fn set(&self, metric: Metric, label: &str, value: f64) {
+        self.samples
+            .lock()
+            .unwrap()
+            .insert((metric.name, label.to_string()), value);
+    }
+
+    fn add(&self, metric: Metric, label: &str, value: f64) {
+        *self
+            .samples
+            .lock()
+            .unwrap()
+            .entry((metric.name, label.to_string()))
+            .or_default() += value;
+    }
+
+    pub fn record_validation(&self, errors: usize, warnings: usize) {
+        self.set(VALIDATION_ERRORS, "", errors as f64);
+        self.set(VALIDATION_WARNINGS, "", warnings as f64);
+    }
+
+    pub fn add_converted_bytes(&self, bytes: u64) {
+        self.add(CONVERTED_BYTES, "", bytes as f64);
+    }
+
+    pub fn add_ipfs_bytes(&self, bytes: u64) {
+        self.add(IPFS_ADDED_BYTES, "", bytes as f64);
+    }
+
+    pub fn record_prime_failure(&self, gateway: &str) {
+        self.add(PRIME_FAILURES, gateway, 1.0);
+    }
+
+    pub fn set_root_dag_size(&self, bytes: u64) {
+        self.set(ROOT_DAG_SIZE, "", bytes as f64);
+    }
+
+    /// This run's samples, with the stages that finished `now` (in seconds since the Unix epoch)
+    fn run_samples(&self, stages: &[StageTiming], now: u64) -> Samples {
+        let mut samples = self.samples.lock().unwrap().clone();
+        samples.insert((LAST_RUN.name, String::new()), now as f64);
+        for timing in stages {
+            let stage = stage_label(timing.stage);
+            samples.insert((STAGE_LAST_RUN.name, stage.clone()), now as f64);
+            // a stage can run more than once (like loading the season), and the durations add up
+            *samples.entry((STAGE_DURATION.name, stage)).or_default() += timing.duration_ms as f64 / 1000.0;
+        }
+        samples
+    }
+}
+
+fn stage_label(stage: Stage) -> String {
+    serde_json::to_value(stage)
+        .ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_else(|| format!("{:?}", stage).to_lowercase())
+}
+
+/// Writes the metrics of this run to `path`, on top of the ones already there
+///
+/// The file is replaced in one go, so the node exporter never reads half of it.
+pub fn write(path: &Path, metrics: &Metrics, stages: &[StageTiming]) -> Result<(), CbError> {
+    let previous = match std::fs::read_to_string(path) {
+        Ok(text) => parse(&text),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Samples::new(),
+        Err(e) => return Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let samples = merge(previous, metrics.run_samples(stages, now));
+    crate::write_atomically(path, render(&samples).as_bytes())
+        .map_err(|e| CbError::io(format!("Failed to write {}", path.display()), e))
+}
+
+/// The previous samples with this run's on top: gauges are replaced, and counters added to
+fn merge(mut previous: Samples, run: Samples) -> Samples {
+    for (key, value) in run {
+        let counter = ALL.iter().any(|m| m.name == key.0 && m.kind == Kind::Counter);
+        let sample = previous.entry(key).or_default();
+        if counter {
+            *sample += value;
+        } else {
+            *sample = value;
+        }
+    }
+    previous
+}
+
+/// The file, in the text exposition format
+fn render(samples: &Samples) -> String {
+    let mut out = String::new();
+    for metric in ALL {
+        let values: Vec<_> = samples.iter().filter(|((name, _), _)| *name == metric.name).collect();
+        if values.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(out, "# TYPE {} {}", metric.name, metric.kind.name());
+        for ((_, label), value) in values {
+            match metric.label {
+                Some(name) => {
+                    let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", metric.name, name, escape(label), value);
+                }
+                None => {
+                    let _ = writeln!(out, "{} {}", metric.name, value);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn unescape(label: &str) -> String {
+    let mut out = String::new();
+    let mut chars = label.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => {}
+            },
+            (c, false) => out.push(c),
+        }
+    }
+    out
+}
+
+/// Reads back a file written by [`render`].  Lines that aren't one of our metrics are dropped
+fn parse(text: &str) -> Samples {
+    let mut samples = Samples::new();
+    for line in text.lines().filter(|l| !l.starts_with('#')) {
+        let (series, value) = match line.rsplit_once(' ') {
+            Some((series, value)) => (series, value),
+            None => continue,
+        };
+        let value: f64 = match value.parse() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let (name, label) = match series.split_once('{') {
+            Some((name, rest)) => {
+                let label = rest
+                    .strip_suffix("\"}")
+                    .and_then(|rest| rest.split_once("=\""))
+                    .map(|(_, value)| unescape(value));
+                match label {
+                    Some(label) => (name, label),
+                    None => continue,
+                }
+            }
+            None => (series, String::new()),
+        };
+        if let Some(metric) = ALL.iter().find(|m| m.name == name) {
+            samples.insert((metric.name, label), value);
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_and_merge() {
+        let metrics = Metrics::default();
+        metrics.record_validation(2, 5);
+        metrics.add_converted_bytes(1000);
+        metrics.add_converted_bytes(500);
+        metrics.record_prime_failure("https://{base32}.ipfs.dweb.link");
+        metrics.record_prime_failure("https://ipfs.io/ipfs/{v0}");
+        let stages = [
+            StageTiming {
+                stage: Stage::Load,
+                duration_ms: 250,
+            },
+            StageTiming {
+                stage: Stage::Convert,
+                duration_ms: 1500,
+            },
+            StageTiming {
+                stage: Stage::Load,
+                duration_ms: 250,
+            },
+        ];
+        let first = metrics.run_samples(&stages, 1_700_000_000);
+        assert_eq!(
+            render(&first),
+            r#"# HELP cb_processor_last_run_timestamp_seconds When the last run finished
+# TYPE cb_processor_last_run_timestamp_seconds gauge
+cb_processor_last_run_timestamp_seconds 1700000000
+# HELP cb_processor_stage_last_run_timestamp_seconds When each stage last finished
+# TYPE cb_processor_stage_last_run_timestamp_seconds gauge
+cb_processor_stage_last_run_timestamp_seconds{stage="convert"} 1700000000
+cb_processor_stage_last_run_timestamp_seconds{stage="load"} 1700000000
+# HELP cb_processor_stage_duration_seconds How long each stage took the last time it ran
+# TYPE cb_processor_stage_duration_seconds gauge
+cb_processor_stage_duration_seconds{stage="convert"} 1.5
+cb_processor_stage_duration_seconds{stage="load"} 0.5
+# HELP cb_processor_validation_errors Errors found by the last validation
+# TYPE cb_processor_validation_errors gauge
+cb_processor_validation_errors 2
+# HELP cb_processor_validation_warnings Warnings from the last validation
+# TYPE cb_processor_validation_warnings gauge
+cb_processor_validation_warnings 5
+# HELP cb_processor_converted_bytes_total Size of the files made by conversion
+# TYPE cb_processor_converted_bytes_total counter
+cb_processor_converted_bytes_total 1500
+# HELP cb_processor_prime_failures_total Requests to a gateway that failed while priming
+# TYPE cb_processor_prime_failures_total counter
+cb_processor_prime_failures_total{gateway="https://ipfs.io/ipfs/{v0}"} 1
+cb_processor_prime_failures_total{gateway="https://{base32}.ipfs.dweb.link"} 1
+"#
+        );
+        assert_eq!(parse(&render(&first)), first);
+
+        // a later run that only primes keeps the conversion's gauges, and adds to the counters
+        let later = Metrics::default();
+        later.record_prime_failure("https://ipfs.io/ipfs/{v0}");
+        later.set_root_dag_size(123_456);
+        let prime = [StageTiming {
+            stage: Stage::Prime,
+            duration_ms: 3000,
+        }];
+        let merged = merge(parse(&render(&first)), later.run_samples(&prime, 1_700_000_060));
+        let get = |metric: Metric, label: &str| merged.get(&(metric.name, label.to_string())).copied();
+        assert_eq!(get(LAST_RUN, ""), Some(1_700_000_060.0));
+        assert_eq!(get(STAGE_LAST_RUN, "convert"), Some(1_700_000_000.0));
+        assert_eq!(get(STAGE_LAST_RUN, "prime"), Some(1_700_000_060.0));
+        assert_eq!(get(CONVERTED_BYTES, ""), Some(1500.0));
+        assert_eq!(get(PRIME_FAILURES, "https://ipfs.io/ipfs/{v0}"), Some(2.0));
+        assert_eq!(get(ROOT_DAG_SIZE, ""), Some(123_456.0));
+
+        assert_eq!(unescape(&escape("a \"b\"\\\n")), "a \"b\"\\\n");
+    }
+}
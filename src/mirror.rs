@@ -0,0 +1,117 @@
+//! Other places a recording's files can be downloaded from (`mirrors` in season.json or a recording's JSON)
+//!
+//! A mirror is a label and a URL template, in which `{filename}` is replaced with the file's path in the recording's
+//! data folder and `{data_folder}` with the data folder itself (both percent-encoded), so one template covers every
+//! file.  The season's mirrors are offered for every recording, before the recording's own.
+
+use serde::{Deserialize, Serialize};
+
+use crate::slug;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    /// What the links say, like "archive.org"
+    pub label: String,
+    pub url: String,
+}
+
+/// The placeholders a template can have
+const PLACEHOLDERS: [&str; 2] = ["{filename}", "{data_folder}"];
+
+impl Mirror {
+    /// The URL of `filename` (relative to `data_folder`) on this mirror
+    pub fn expand(&self, data_folder: &str, filename: &str) -> String {
+        self.url
+            .replace("{data_folder}", &slug::encode_path(data_folder))
+            .replace("{filename}", &slug::encode_path(filename))
+    }
+
+    /// What's wrong with the mirror, if anything
+    pub fn problem(&self) -> Option<String> {
+        if self.label.trim().is_empty() {
+            return Some(format!("the mirror for {} has no label", self.url));
+        }
+        let rest = match self
+            .url
+            .strip_prefix("https://")
+            .or_else(|| self.url.strip_prefix("http://"))
+        {
+            Some(rest) => rest,
+            None => return Some(format!("{} isn't an http(s) URL", self.url)),
+        };
+        let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        if host.is_empty() || host.contains('{') {
+            return Some(format!("{} has no host", self.url));
+        }
+        if self.url.chars().any(char::is_whitespace) {
+            return Some(format!("{} has spaces in it", self.url));
+        }
+        if !self.url.contains("{filename}") {
+            return Some(format!("{} has no {{filename}} in it", self.url));
+        }
+        let mut rest = self.url.clone();
+        for placeholder in PLACEHOLDERS {
+            rest = rest.replace(placeholder, "");
+        }
+        if rest.contains(['{', '}']) {
+            return Some(format!(
+                "{} has a placeholder other than {}",
+                self.url,
+                PLACEHOLDERS.join(" and ")
+            ));
+        }
+        None
+    }
+}
+
+/// A mirror's links to the files of a recording, for its page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorLinks<'a> {
+    pub label: &'a str,
+    /// The name of each file, and its URL on the mirror
+    pub files: Vec<(String, String)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(url: &str) -> Mirror {
+        Mirror {
+            label: "archive.org".to_string(),
+            url: url.to_string(),
+        }
+    }
+
+    #[test]
+    fn expand() {
+        assert_eq!(
+            mirror("https://example.com/cb/{data_folder}/{filename}").expand("S01E01 Jam", "flac/Kick & Snare.flac"),
+            "https://example.com/cb/S01E01%20Jam/flac/Kick%20%26%20Snare.flac"
+        );
+    }
+
+    #[test]
+    fn problems() {
+        assert_eq!(
+            mirror("https://archive.org/download/cb-s01e01/{filename}").problem(),
+            None
+        );
+        assert_eq!(mirror("http://example.com/{data_folder}/{filename}").problem(), None);
+        for url in [
+            "ftp://example.com/{filename}",
+            "https:///{filename}",
+            "https://{data_folder}.example.com/{filename}",
+            "https://example.com/all.zip",
+            "https://example.com/{file}",
+            "https://example.com/my files/{filename}",
+        ] {
+            assert!(mirror(url).problem().is_some(), "{}", url);
+        }
+        let unlabeled = Mirror {
+            label: " ".to_string(),
+            ..mirror("https://example.com/{filename}")
+        };
+        assert!(unlabeled.problem().is_some());
+    }
+}
@@ -0,0 +1,236 @@
+//! Telling people about a new publish (`[notify]` in the config)
+//!
+//! After `--patch --publish` succeeds, a JSON message is POSTed to the configured webhook, either in our own format
+//! or as a Discord message.  A failure to notify is only a warning, since the publish itself has already happened.
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    context::{RunContext, WebhookFormat},
+    error::CbError,
+    ipfs::{LinkChange, PatchPlan},
+    types::Season,
+};
+
+/// Discord rejects messages longer than this
+const DISCORD_MAX_LENGTH: usize = 2000;
+
+/// What changed in a publish
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Notification {
+    /// The title of the season, if it's known
+    pub season: Option<String>,
+    pub root: String,
+    /// The titles of the recordings that weren't in the old root
+    pub added: Vec<String>,
+    /// The titles of the recordings that were in the old root, but had files changed
+    pub changed: Vec<String>,
+    /// Where the new root can be seen
+    pub urls: Vec<String>,
+}
+
+impl Notification {
+    /// Builds the notification for a patch of `season`'s root that resulted in `new_root`
+    ///
+    /// Only the links named after a recording's data folder or its page count, so each recording is listed once and
+    /// everything else (the season's pages, redirects, badges...) is left out.  A recording is new if all of its
+    /// links were added.  Without the season, no recordings are listed.
+    pub fn from_patch(
+        ctx: &RunContext, season: Option<&Season>, plan: &PatchPlan, new_root: &cid::Cid,
+    ) -> Notification {
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for recording in season.map_or(&[][..], |s| &s.recordings) {
+            let mut changes = plan
+                .changes
+                .iter()
+                .filter(|change| {
+                    let name = match change {
+                        LinkChange::Add { name, .. }
+                        | LinkChange::Replace { name, .. }
+                        | LinkChange::Patch { name, .. } => name,
+                    };
+                    *name == recording.data_folder || *name == recording.slug
+                })
+                .peekable();
+            if changes.peek().is_none() {
+                continue;
+            }
+            if changes.all(|change| matches!(change, LinkChange::Add { .. })) {
+                added.push(recording.title.clone());
+            } else {
+                changed.push(recording.title.clone());
+            }
+        }
+
+        let b32 = cid::Cid::new_v1(new_root.codec(), new_root.hash().to_owned());
+        let urls = vec![
+            format!(
+                "https://{}.ipfs.dweb.link",
+                b32.to_string_of_base(multibase::Base::Base32Lower)
+                    .unwrap_or_else(|_| b32.to_string())
+            ),
+            format!("https://ipfs.io/ipfs/{}", new_root),
+            ctx.base_url.clone(),
+        ];
+
+        Notification {
+            season: season.map(|s| s.title.clone()),
+            root: new_root.to_string(),
+            added,
+            changed,
+            urls,
+        }
+    }
+
+    /// The message as plain text
+    pub fn text(&self) -> String {
+        let mut s = format!(
+            "Published {}\n",
+            self.season.as_deref().unwrap_or("a new version of the archive")
+        );
+        if !self.added.is_empty() {
+            s.push_str(&format!("New recordings: {}\n", self.added.join(", ")));
+        }
+        if !self.changed.is_empty() {
+            s.push_str(&format!("Updated recordings: {}\n", self.changed.join(", ")));
+        }
+        s.push_str(&format!("Root: {}\n", self.root));
+        for url in &self.urls {
+            s.push_str(url);
+            s.push('\n');
+        }
+        s
+    }
+
+    /// The JSON body to POST
+    pub fn payload(&self, format: WebhookFormat) -> serde_json::Value {
+        match format {
+            WebhookFormat::Json => serde_json::to_value(self).expect("a notification is always valid JSON"),
+            WebhookFormat::Discord => {
+                let mut content = self.text();
+                if content.chars().count() > DISCORD_MAX_LENGTH {
+                    content = content.chars().take(DISCORD_MAX_LENGTH - 1).collect();
+                    content.push('…');
+                }
+                json!({
+                    "username": "cb_processor",
+                    "content": content,
+                })
+            }
+        }
+    }
+}
+
+/// POSTs `payload` to `url`
+pub fn send(ctx: &RunContext, url: &str, payload: &serde_json::Value) -> Result<(), CbError> {
+    let client = ctx.http_client("webhook", std::time::Duration::from_secs(30))?;
+    let resp = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send()
+        .map_err(|e| CbError::tool("webhook", e))?;
+    if !resp.status().is_success() {
+        return Err(CbError::tool("webhook", format!("{} returned {}", url, resp.status())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn season() -> Season {
+        let track = serde_json::json!({
+            "id": 1, "name": "Stereo mix", "flac": "mix.flac", "vorbis": null, "mp3": null, "patch_notes": null,
+            "media_info": {"@type": "Audio", "Format": "FLAC", "Channels": "2", "SamplingRate": "48000",
+                "BitDepth": "24", "Duration": "1.0"},
+            "flac_bytes": 0, "ogg_bytes": 0, "mp3_bytes": 0
+        });
+        let recording = |title: &str, slug: &str, data_folder: &str| {
+            serde_json::json!({
+                "title": title, "slug": slug, "data_folder": data_folder, "stereo_mix": track,
+                "recorded_date": "2021/01/01", "torrent": null, "tracks": [], "tags": [], "bpm": null,
+                "youtube_url": null
+            })
+        };
+        serde_json::from_value(serde_json::json!({
+            "title": "Season 1",
+            "recordings": [
+                recording("S01E01 - Jam 1", "s01e01-jam-1", "jam1"),
+                recording("S01E02 - Jam 2", "jam2", "jam2"),
+                recording("S01E03 - Jam 3", "s01e03-jam-3", "jam3"),
+                recording("S01E04 - Jam 4", "s01e04-jam-4", "jam4"),
+            ],
+            "redirects": {"first-jam": "s01e01-jam-1"},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn from_patch() {
+        let cid = cid::Cid::from_str("QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh").unwrap();
+        let add = |name: &str| LinkChange::Add {
+            name: name.to_string(),
+            cid,
+        };
+        let patch = |name: &str| LinkChange::Patch {
+            name: name.to_string(),
+            plan: PatchPlan {
+                root: cid,
+                changes: Vec::new(),
+            },
+        };
+        let plan = PatchPlan {
+            root: cid,
+            changes: vec![
+                LinkChange::Replace {
+                    name: "index.html".to_string(),
+                    old: cid,
+                    new: cid,
+                },
+                patch("badges"),
+                patch("compare"),
+                patch("metadata"),
+                patch("source"),
+                // jam1's page and audio both changed, and its old slug became a redirect
+                patch("jam1"),
+                patch("s01e01-jam-1"),
+                add("first-jam"),
+                patch("jam2"),
+                add("jam3"),
+                add("s01e03-jam-3"),
+            ],
+        };
+        let season = season();
+        let ctx = RunContext::default();
+        let notification = Notification::from_patch(&ctx, Some(&season), &plan, &cid);
+        assert_eq!(notification.added, ["S01E03 - Jam 3"]);
+        assert_eq!(notification.changed, ["S01E01 - Jam 1", "S01E02 - Jam 2"]);
+        assert_eq!(notification.urls.len(), 3);
+        assert_eq!(notification.urls[2], ctx.base_url);
+
+        let json = notification.payload(WebhookFormat::Json);
+        assert_eq!(json["season"], "Season 1");
+        assert_eq!(json["root"], cid.to_string());
+
+        let discord = notification.payload(WebhookFormat::Discord);
+        let content = discord["content"].as_str().unwrap();
+        assert!(content.starts_with(
+            "Published Season 1\nNew recordings: S01E03 - Jam 3\nUpdated recordings: S01E01 - Jam 1, S01E02 - Jam 2\n"
+        ));
+
+        // without the season, there's nothing to match the links with
+        let unknown = Notification::from_patch(&ctx, None, &plan, &cid);
+        assert!(unknown.added.is_empty() && unknown.changed.is_empty());
+        assert_eq!(unknown.season, None);
+
+        let mut long = notification;
+        long.added = vec!["x".repeat(3000)];
+        let discord = long.payload(WebhookFormat::Discord);
+        assert_eq!(discord["content"].as_str().unwrap().chars().count(), DISCORD_MAX_LENGTH);
+    }
+}
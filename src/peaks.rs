@@ -0,0 +1,295 @@
+//! Waveform peaks of each track, for the player to draw
+//!
+//! The peaks are written in the JSON format of BBC's audiowaveform (which peaks.js and wavesurfer.js read too): the
+//! lowest and highest sample of each bucket of samples, in 8 bits, over all channels at once.  At the default 1000
+//! buckets that's under 10KB per track.  Like spectrograms, they're only computed again when the flac is newer.
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    analysis::parallel_for_each,
+    context::RunContext,
+    flac::StreamInfo,
+    progress::{self, ProgressEvent, Stage},
+    spectrogram::is_up_to_date,
+    types::Season,
+};
+
+/// The contents of a `.peaks.json` file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Peaks {
+    /// Version 2 of audiowaveform's format is the one with `channels`
+    pub version: u32,
+    /// Always 1: the channels are mixed together
+    pub channels: u32,
+    pub sample_rate: u32,
+    /// How many samples (per channel) each min/max pair covers
+    pub samples_per_pixel: u64,
+    /// Always 8
+    pub bits: u32,
+    /// The number of min/max pairs
+    pub length: usize,
+    /// `min, max, min, max, ...`
+    pub data: Vec<i8>,
+}
+
+/// Folds a stream of interleaved 16 bit samples into min/max pairs
+struct Buckets {
+    channels: usize,
+    frames_per_bucket: u64,
+    /// Samples of the current frame seen so far
+    in_frame: usize,
+    /// Frames of the current bucket seen so far
+    frames: u64,
+    min: i16,
+    max: i16,
+    data: Vec<i8>,
+}
+
+impl Buckets {
+    fn new(channels: usize, frames_per_bucket: u64) -> Buckets {
+        Buckets {
+            channels: channels.max(1),
+            frames_per_bucket: frames_per_bucket.max(1),
+            in_frame: 0,
+            frames: 0,
+            min: i16::MAX,
+            max: i16::MIN,
+            data: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, sample: i16) {
+        self.min = self.min.min(sample);
+        self.max = self.max.max(sample);
+        self.in_frame += 1;
+        if self.in_frame == self.channels {
+            self.in_frame = 0;
+            self.frames += 1;
+            if self.frames == self.frames_per_bucket {
+                self.flush();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.frames > 0 || self.in_frame > 0 {
+            self.data.push(to_8_bits(self.min));
+            self.data.push(to_8_bits(self.max));
+        }
+        self.frames = 0;
+        self.in_frame = 0;
+        self.min = i16::MAX;
+        self.max = i16::MIN;
+    }
+
+    /// The min/max pairs, including the last bucket even if it's short
+    fn finish(mut self) -> Vec<i8> {
+        self.flush();
+        self.data
+    }
+}
+
+fn to_8_bits(sample: i16) -> i8 {
+    (sample >> 8) as i8
+}
+
+/// Merges neighbouring min/max pairs until there are no more than `buckets` of them, and returns how many of the
+/// original pairs went into each one
+fn shrink(data: &mut Vec<i8>, buckets: usize) -> u64 {
+    let mut merged = 1;
+    while data.len() / 2 > buckets.max(1) {
+        *data = data
+            .chunks(4)
+            .flat_map(|c| {
+                let min = c.iter().step_by(2).copied().min().unwrap_or(0);
+                let max = c.iter().skip(1).step_by(2).copied().max().unwrap_or(0);
+                vec![min, max]
+            })
+            .collect();
+        merged *= 2;
+    }
+    merged
+}
+
+/// Decodes `flac` with ffmpeg and works out its peaks in (at most) `buckets` buckets
+pub fn compute(ctx: &RunContext, flac: &Path, buckets: usize) -> anyhow::Result<Peaks> {
+    let info = StreamInfo::read(flac)?;
+    // when the flac doesn't say how long it is, go by seconds and merge buckets afterwards
+    let frames_per_bucket = match info.total_samples {
+        0 => u64::from(info.sample_rate),
+        total => total.div_ceil(buckets.max(1) as u64),
+    };
+    let mut folded = Buckets::new(usize::from(info.channels), frames_per_bucket);
+
+    let mut child = Command::new(&ctx.tools.ffmpeg)
+        .arg("-nostats")
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(flac)
+        .arg("-f")
+        .arg("s16le")
+        .arg("-acodec")
+        .arg("pcm_s16le")
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run ffmpeg on {}", flac.display()))?;
+
+    let mut stdout = child.stdout.take().expect("ffmpeg's stdout is piped");
+    let mut buf = vec![0; 1 << 16];
+    let mut odd_byte = None;
+    loop {
+        let n = stdout
+            .read(&mut buf)
+            .with_context(|| format!("Failed to read the samples of {}", flac.display()))?;
+        if n == 0 {
+            break;
+        }
+        let mut bytes = &buf[..n];
+        if let Some(low) = odd_byte.take() {
+            folded.push(i16::from_le_bytes([low, bytes[0]]));
+            bytes = &bytes[1..];
+        }
+        let mut pairs = bytes.chunks_exact(2);
+        for pair in &mut pairs {
+            folded.push(i16::from_le_bytes([pair[0], pair[1]]));
+        }
+        odd_byte = pairs.remainder().first().copied();
+    }
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to run ffmpeg on {}", flac.display()))?;
+    if !output.status.success() {
+        bail!(
+            "ffmpeg returned {:?} decoding {}: {}",
+            output.status,
+            flac.display(),
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .last()
+                .unwrap_or("")
+                .trim()
+        );
+    }
+
+    let mut data = folded.finish();
+    let merged = shrink(&mut data, buckets);
+    Ok(Peaks {
+        version: 2,
+        channels: 1,
+        sample_rate: info.sample_rate,
+        samples_per_pixel: frames_per_bucket * merged,
+        bits: 8,
+        length: data.len() / 2,
+        data,
+    })
+}
+
+/// Works out the peaks of `flac` and writes them to `json`
+pub fn render(ctx: &RunContext, flac: &Path, json: &Path) -> anyhow::Result<()> {
+    let peaks = compute(ctx, flac, ctx.peak_buckets)?;
+    // compact, unlike the other JSON files: this one is only ever read by the player
+    let bytes = serde_json::to_vec(&peaks)?;
+    crate::write_atomically(json, &bytes).with_context(|| format!("Failed to write {}", json.display()))
+}
+
+/// Works out the peaks of every selected track whose `.peaks.json` is missing or older than its flac
+///
+/// Up to `ctx.jobs` tracks are decoded at once.  Returns the number of files that were written.
+pub fn render_missing(ctx: &RunContext, season: &Season) -> anyhow::Result<usize> {
+    ctx.stage(Stage::Analyze, || {
+        let mut todo: Vec<(PathBuf, PathBuf)> = Vec::new();
+        for rec in ctx.only.select(season)? {
+            for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+                if let (Some(flac), Some(json)) = (track.flac_ondisk(), track.peaks_ondisk()) {
+                    if !is_up_to_date(&flac, &json) {
+                        todo.push((flac, json));
+                    }
+                }
+            }
+        }
+        let total = todo.len();
+
+        let done = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+
+        parallel_for_each(ctx.jobs, todo, |(flac, json)| {
+            let start = Instant::now();
+            if let Err(e) = render(ctx, &flac, &json) {
+                errors.lock().unwrap().push(e);
+            }
+            ctx.timings
+                .record_item(Stage::Analyze, json.display().to_string(), start.elapsed());
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Analyze,
+                item: json.display().to_string(),
+                index: done.fetch_add(1, Ordering::SeqCst) + 1,
+                total,
+            });
+        });
+
+        let errors = errors.into_inner().unwrap();
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Analyze,
+            processed: total,
+            errors: errors.len(),
+            warnings: 0,
+        });
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+        Ok(total)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        // two channels, two frames per bucket, and a short last bucket
+        let mut folded = Buckets::new(2, 2);
+        for sample in [0, 256, -512, 1024, 32767, -32768, 5 << 8, 0, 7 << 8, -(3 << 8)] {
+            folded.push(sample);
+        }
+        assert_eq!(folded.finish(), vec![-2, 4, -128, 127, -3, 7]);
+
+        let mut data = vec![-1, 1, -4, 2, -2, 8, 0, 3, -5, 5];
+        assert_eq!(shrink(&mut data, 5), 1);
+        assert_eq!(shrink(&mut data, 2), 4);
+        assert_eq!(data, vec![-4, 8, -5, 5]);
+    }
+
+    #[test]
+    fn small_enough() {
+        // the loudest a track can be, at the default resolution
+        let peaks = Peaks {
+            version: 2,
+            channels: 1,
+            sample_rate: 48000,
+            samples_per_pixel: 172_800,
+            bits: 8,
+            length: crate::context::DEFAULT_PEAK_BUCKETS,
+            data: [-128, 127].repeat(crate::context::DEFAULT_PEAK_BUCKETS),
+        };
+        assert!(serde_json::to_vec(&peaks).unwrap().len() < 20 * 1024);
+    }
+}
@@ -0,0 +1,401 @@
+//! The stages of a run, for driving them from another program
+//!
+//! Each method of [`Pipeline`] does what one of the command line's modes does (the binary is a thin layer over them)
+//! and returns what it did as a report, instead of only printing it.  What's printed along the way goes through
+//! [`progress::log`](crate::progress::log), which [`progress::enable_log`](crate::progress::enable_log) turns off.
+//!
+//! Nothing here locks the folders it works on; hold the locks from [`crate::lock`] around the calls, like the
+//! command line does.
+
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "cli")]
+use colored::Colorize;
+use serde::Serialize;
+
+#[cfg(all(feature = "ipfs", not(feature = "cli")))]
+use crate::plain::Colorize;
+use crate::{
+    analysis,
+    context::RunContext,
+    error::CbError,
+    hooks::{self, Hook},
+    progress::Stage,
+    quarantine,
+    types::Season,
+    Conversion, ValidationReport,
+};
+#[cfg(feature = "ipfs")]
+use crate::{
+    history, ipfs, notify,
+    progress::{self, ProgressEvent},
+};
+
+/// The stages of a run, all with the same context
+pub struct Pipeline<'a> {
+    ctx: &'a RunContext,
+}
+
+/// What [`Pipeline::convert`] should do besides converting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConvertOptions {
+    /// Take the flacs that failed before out of quarantine first
+    pub retry_quarantined: bool,
+    /// Draw the spectrograms that are missing
+    pub spectrograms: bool,
+    /// Work out the peaks of the tracks that don't have them
+    pub peaks: bool,
+    /// Rewrite the tags of the files that were converted before (see [`crate::file_tags`])
+    pub retag: bool,
+}
+
+/// What [`Pipeline::convert`] did
+#[derive(Debug, Default)]
+pub struct ConvertReport {
+    pub unquarantined: usize,
+    /// The files that were there already, and didn't need converting again
+    pub up_to_date: usize,
+    pub conversions: Vec<Conversion>,
+    /// The files that were there already, and had their tags rewritten
+    pub retagged: Vec<PathBuf>,
+    pub spectrograms: usize,
+    pub peaks: usize,
+}
+
+/// Where [`Pipeline::generate`] writes to, and what from
+#[derive(Debug, Clone, Copy)]
+pub struct Generation<'p> {
+    /// The season.json the season was loaded from, for the snapshot in `source/` (see [`crate::source`])
+    pub season_json: &'p Path,
+    pub output: &'p Path,
+    /// The data dir the season was loaded from, if it was
+    pub data_dir: Option<&'p Path>,
+    /// Where to write the metadata, if anywhere
+    pub metadata: Option<&'p Path>,
+    /// Overwrite metadata that has more recordings than the season
+    pub force_metadata: bool,
+}
+
+/// What [`Pipeline::generate`] did
+#[derive(Debug, Default, Serialize)]
+pub struct GenerateReport {
+    /// Every file that was written (nothing, with `--diff-output`)
+    #[serde(serialize_with = "crate::serialize_slashed::paths")]
+    pub written: Vec<PathBuf>,
+    /// Files that earlier runs generated, but this one didn't (see [`crate::manifest`])
+    pub stale: Vec<String>,
+}
+
+/// What [`Pipeline::patch`] did
+#[cfg(feature = "ipfs")]
+#[derive(Debug)]
+pub struct PatchOutcome {
+    pub output: PathBuf,
+    pub old_root: cid::Cid,
+    /// The same as `old_root` if there was nothing to patch
+    pub new_root: cid::Cid,
+    pub plan: ipfs::PatchPlan,
+    /// The sizes of the roots, if they could be found out
+    pub report: Option<ipfs::PatchReport>,
+}
+
+/// What [`Pipeline::publish`] did
+#[cfg(feature = "ipfs")]
+#[derive(Debug, Serialize)]
+pub struct PublishReport {
+    pub root: String,
+    pub key: String,
+    /// What was sent to the webhook (or only printed, in a dry run), if there is one
+    pub notification: Option<notify::Notification>,
+}
+
+/// Prints a warning about something that went wrong after the stage's real work was done
+#[cfg(feature = "ipfs")]
+fn warn(stage: Stage, message: String) {
+    say!("{}", message.yellow());
+    progress::emit(ProgressEvent::Warning { stage, message });
+}
+
+/// The `CB_*` variables for the hooks after patching and publishing
+///
+/// The season title comes from the metadata in the published folder, if there is one.
+#[cfg(feature = "ipfs")]
+fn patch_vars(patched: &PatchOutcome) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        ("CB_OUTPUT_DIR", patched.output.display().to_string()),
+        ("CB_OLD_ROOT_CID", patched.old_root.to_string()),
+        ("CB_NEW_ROOT_CID", patched.new_root.to_string()),
+    ];
+    if let Ok(season) = crate::load_metadata(&patched.output.join("metadata.json")) {
+        vars.push(("CB_SEASON_TITLE", season.title));
+    }
+    vars
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(ctx: &'a RunContext) -> Pipeline<'a> {
+        Pipeline { ctx }
+    }
+
+    pub fn context(&self) -> &'a RunContext {
+        self.ctx
+    }
+
+    /// Checks a season against its data dir (see [`crate::validate`])
+    pub fn validate(&self, season_json: &Path, data_dir: &Path) -> Result<ValidationReport, CbError> {
+        crate::validate(self.ctx, season_json, data_dir)
+    }
+
+    /// Loads a season from its data dir, or from the metadata if there's no data dir
+    ///
+    /// With both, what's only in the metadata (like loudness measurements) is carried over, if it exists yet.  With
+    /// only a metadata index and `--only`, just the selected recordings are read (see [`crate::chunked_metadata`]).
+    pub fn load(
+        &self, season_json: &Path, data_dir: Option<&Path>, metadata: Option<&Path>,
+    ) -> Result<Season, CbError> {
+        let ctx = self.ctx;
+        ctx.stage(Stage::Load, || {
+            if let (None, Some(path)) = (data_dir, metadata) {
+                let path = crate::chunked_metadata::existing(path);
+                if crate::chunked_metadata::is_index(&path) && !ctx.only.is_all() {
+                    let cached = crate::chunked_metadata::load_selected(&path, &ctx.only)
+                        .map_err(|e| CbError::step("load the metadata", e))?;
+                    return Season::load_selected(ctx, season_json, &cached);
+                }
+            }
+            let cached =
+                match metadata.filter(|path| data_dir.is_none() || crate::chunked_metadata::existing(path).exists()) {
+                    Some(path) => Some(crate::load_metadata(path).map_err(|e| CbError::step("load the metadata", e))?),
+                    None => None,
+                };
+            match data_dir {
+                Some(data_dir) => {
+                    let mut season = Season::load(ctx, season_json, Some(data_dir), None)?;
+                    // keep the measurements that only --measure-loudness makes, and what only earlier runs know
+                    if let Some(cached) = &cached {
+                        analysis::carry_over_loudness(&mut season, cached);
+                        season.carry_over(cached);
+                    }
+                    Ok(season)
+                }
+                None => Season::load(ctx, season_json, None, cached.as_ref()),
+            }
+        })
+    }
+
+    /// Converts the flacs of a season loaded from `data_dir` to the other formats, running the `pre_convert` hook
+    /// first
+    pub fn convert(&self, season: &Season, data_dir: &Path, options: ConvertOptions) -> Result<ConvertReport, CbError> {
+        let ctx = self.ctx;
+        let vars = [
+            ("CB_DATA_DIR", data_dir.display().to_string()),
+            ("CB_SEASON_TITLE", season.title.clone()),
+        ];
+        hooks::run(ctx, Hook::PreConvert, &vars, ctx.diff_output.is_some())?;
+
+        let mut report = ConvertReport::default();
+        if options.retry_quarantined {
+            report.unquarantined = quarantine::clear(ctx, season)?;
+        }
+        // before converting, since what's converted is tagged already
+        if options.retag {
+            report.retagged = crate::file_tags::retag_all(ctx, season)?;
+        }
+        report.up_to_date = crate::count_up_to_date(ctx, season)?;
+        report.conversions = crate::convert_all(ctx, season)?;
+        if options.spectrograms {
+            report.spectrograms = crate::spectrogram::render_missing(ctx, season)
+                .map_err(|e| CbError::step("draw the spectrograms", e))?;
+        }
+        if options.peaks {
+            report.peaks =
+                crate::peaks::render_missing(ctx, season).map_err(|e| CbError::step("work out the peaks", e))?;
+        }
+        Ok(report)
+    }
+
+    /// Writes the pages, the metadata and the build manifest, then runs the `post_generate` hook
+    ///
+    /// With `--diff-output` ([`RunContext::diff_output`]) nothing is written: the pages (and metadata) are only
+    /// compared with what's there, for the caller to report.
+    #[cfg(feature = "templates")]
+    pub fn generate(&self, season: &Season, to: Generation) -> Result<GenerateReport, CbError> {
+        let ctx = self.ctx;
+        // check the selection before writing anything
+        ctx.only.select(season)?;
+
+        if let Some(data_dir) = to.data_dir {
+            if crate::manifest::overlaps(to.output, data_dir) {
+                say!(
+                    "The output overlaps the data dir, clean will only delete what {} lists",
+                    crate::manifest::MANIFEST_FILE
+                );
+            }
+        }
+        let mut written = ctx
+            .stage(Stage::Generate, || {
+                let mut written = crate::write_season_index(ctx, season, to.output)?;
+                written.extend(crate::write_all_recording_index(ctx, season, to.output)?);
+                written.extend(crate::write_source_snapshot(ctx, to.season_json, to.output)?);
+                written.extend(crate::write_badges(ctx, season, to.output)?);
+                Ok::<_, anyhow::Error>(written)
+            })
+            .map_err(|e| CbError::step("generate the pages", e))?;
+
+        if let Some(diff) = &ctx.diff_output {
+            if let Some(md_file) = to.metadata {
+                diff.compare_json(md_file, season)
+                    .map_err(|e| CbError::step("compare the metadata", e))?;
+            }
+            return Ok(GenerateReport::default());
+        }
+
+        if let Some(md_file) = to.metadata {
+            // with --only and no data dir, the season only has the recordings that were read from the index (see
+            // `load`), which mustn't replace the rest of it
+            let selected = to.data_dir.is_none()
+                && !ctx.only.is_all()
+                && crate::chunked_metadata::is_index(md_file)
+                && md_file.exists();
+            if selected {
+                crate::chunked_metadata::write_selected(season, md_file)
+            } else {
+                crate::write_metadata(season, md_file, to.force_metadata)
+            }
+            .map_err(|e| CbError::step("write the metadata", e))?;
+            if crate::chunked_metadata::is_index(md_file) {
+                written.extend(crate::chunked_metadata::files(season, md_file));
+            } else {
+                written.push(md_file.to_path_buf());
+            }
+        }
+        let compressed = crate::precompress::write_siblings(ctx, to.output, &written)?;
+        written.extend(compressed);
+        // with --only, what wasn't regenerated might still be current, and so might the pages of recordings that were
+        // left out because they're broken right now
+        let complete = ctx.only.is_all() && season.skipped.is_empty();
+        let stale = crate::manifest::BuildManifest::record(&ctx.hashes, to.output, &written, complete)?;
+        // nothing can be published before the pages are known to only refer to files that are there
+        crate::references::check_generated(season, to.output, &written)?;
+
+        let mut vars = vec![
+            ("CB_OUTPUT_DIR", to.output.display().to_string()),
+            ("CB_SEASON_TITLE", season.title.clone()),
+        ];
+        if let Some(data_dir) = to.data_dir {
+            vars.push(("CB_DATA_DIR", data_dir.display().to_string()));
+        }
+        hooks::run(ctx, Hook::PostGenerate, &vars, false)?;
+
+        Ok(GenerateReport { written, stale })
+    }
+
+    /// Checks that `root` looks like an earlier version of the season in `output`, before it's patched
+    #[cfg(feature = "ipfs")]
+    pub fn check_root(&self, root: &cid::Cid, output: &Path) -> Result<ipfs::RootCheck, CbError> {
+        self.ctx
+            .stage(Stage::Patch, || ipfs::check_root(self.ctx, root, output))
+    }
+
+    /// Works out which links of `root` have to change for it to match `output`
+    #[cfg(feature = "ipfs")]
+    pub fn plan_patch(&self, root: &cid::Cid, output: &Path) -> Result<ipfs::PatchPlan, CbError> {
+        self.ctx
+            .stage(Stage::Patch, || ipfs::plan_patch(self.ctx, root, output))
+    }
+
+    /// Makes the new root from a [`plan_patch`](Self::plan_patch), records it in the history and runs the
+    /// `post_patch` hook
+    ///
+    /// The patch has already happened when the history or the sizes of the roots fail, so those are only warnings.
+    #[cfg(feature = "ipfs")]
+    pub fn patch(&self, root: &cid::Cid, output: &Path, plan: ipfs::PatchPlan) -> Result<PatchOutcome, CbError> {
+        let ctx = self.ctx;
+        let new_root = if plan.changes.is_empty() {
+            *root
+        } else {
+            ctx.stage(Stage::Patch, || ipfs::apply_patch(ctx, &plan))?
+        };
+
+        let now = std::time::SystemTime::now();
+        if let Err(e) = history::record(&ctx.roots_history, &new_root, now, ctx.retention.roots_history) {
+            let message = format!("Failed to add the new root to the history: {:#}", anyhow::Error::new(e));
+            warn(Stage::Patch, message);
+        }
+        let sizes = ipfs::DagSizes::get(ctx, root).and_then(|old| Ok((old, ipfs::DagSizes::get(ctx, &new_root)?)));
+        let report = match sizes {
+            Ok((old_sizes, new_sizes)) => {
+                ctx.metrics.set_root_dag_size(new_sizes.total);
+                Some(ipfs::PatchReport {
+                    old_root: root.to_string(),
+                    new_root: new_root.to_string(),
+                    changes: plan.num_changes(),
+                    changed: plan.changed_names(),
+                    old_sizes,
+                    new_sizes,
+                })
+            }
+            Err(e) => {
+                let message = format!("Failed to get the size of the new root: {:#}", anyhow::Error::new(e));
+                warn(Stage::Patch, message);
+                None
+            }
+        };
+
+        let patched = PatchOutcome {
+            output: output.to_path_buf(),
+            old_root: *root,
+            new_root,
+            plan,
+            report,
+        };
+        hooks::run(ctx, Hook::PostPatch, &patch_vars(&patched), false)?;
+        Ok(patched)
+    }
+
+    /// Has the public gateways start caching `root`
+    #[cfg(feature = "ipfs")]
+    pub fn prime(&self, root: &cid::Cid, options: &crate::gateway::PrimeOptions) -> Result<ipfs::PrimeReport, CbError> {
+        ipfs::prime_public_gateways(self.ctx, root, options)
+    }
+
+    /// Points the IPNS name `key` at the new root of a patch, sends the webhook notification and runs the
+    /// `post_publish` hook
+    ///
+    /// With `notify_dry_run` the notification is printed instead, and the hook isn't run.  The name has already been
+    /// published when the notification fails, so that's only a warning.
+    #[cfg(feature = "ipfs")]
+    pub fn publish(&self, patched: &PatchOutcome, key: &str, notify_dry_run: bool) -> Result<PublishReport, CbError> {
+        let ctx = self.ctx;
+        ctx.stage(Stage::Publish, || ipfs::publish_name(ctx, &patched.new_root, key))?;
+
+        let url = match &ctx.webhook_url {
+            Some(url) => Some(url.as_str()),
+            None if notify_dry_run => Some("(no webhook configured)"),
+            None => None,
+        };
+        let notification = url.map(|url| {
+            let season = crate::load_metadata(&patched.output.join("metadata.json")).ok();
+            let notification = notify::Notification::from_patch(ctx, season.as_ref(), &patched.plan, &patched.new_root);
+            let payload = notification.payload(ctx.webhook_format);
+            if notify_dry_run {
+                say!(
+                    "Would POST to {}:\n{}",
+                    url,
+                    serde_json::to_string_pretty(&payload).unwrap()
+                );
+            } else if let Err(e) = notify::send(ctx, url, &payload) {
+                let message = format!("Failed to send the publish notification: {:#}", anyhow::Error::new(e));
+                warn(Stage::Publish, message);
+            }
+            notification
+        });
+
+        hooks::run(ctx, Hook::PostPublish, &patch_vars(patched), notify_dry_run)?;
+        Ok(PublishReport {
+            root: patched.new_root.to_string(),
+            key: key.to_string(),
+            notification,
+        })
+    }
+}
@@ -0,0 +1,366 @@
+//! Compressed copies of the text files in the output (`index.html.gz` next to `index.html`)
+//!
+//! Gateways serve the pages, the playlist and metadata.json uncompressed, but gateways and reverse proxies that look
+//! for a precompressed copy of a file can send that instead.  With `[precompress]` turned on in the config, every
+//! generated html, css, json, xml and m3u file of at least `min_size` bytes gets a gzip copy (made here) and/or a
+//! brotli one (made by the brotli command).  A copy is remade whenever the file it's a copy of changes, and one that's
+//! no longer wanted is deleted, since it would still hold what the file used to say.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{checksum, command, context::RunContext, error::CbError, manifest::BuildManifest};
+
+/// The kinds of files that are worth compressing
+const TEXT_EXTENSIONS: &[&str] = &["html", "css", "json", "xml", "m3u"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 2] = [Encoding::Gzip, Encoding::Brotli];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    /// Where the copy of `path` in this encoding goes
+    pub fn sibling(self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".");
+        name.push(self.extension());
+        PathBuf::from(name)
+    }
+}
+
+fn is_text(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| TEXT_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Writes the compressed copies of the `written` files that are in `output`, returning every copy that's there now
+///
+/// This has to run before the build manifest is updated, since the manifest is what says whether a file has changed
+/// since the copies were made.
+pub fn write_siblings(ctx: &RunContext, output: &Path, written: &[PathBuf]) -> Result<Vec<PathBuf>, CbError> {
+    let settings = &ctx.precompress;
+    let previous = BuildManifest::load(output)?.unwrap_or_default();
+    let mut siblings = Vec::new();
+    for path in written {
+        if path.strip_prefix(output).is_err() {
+            continue;
+        }
+        let size = std::fs::metadata(path)
+            .map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?
+            .len();
+        let compressible = is_text(path) && size >= settings.min_size;
+        let source_unchanged = compressible && previous.unchanged(output, path, &ctx.hashes.sha256(path)?);
+
+        for encoding in Encoding::ALL {
+            let sibling = encoding.sibling(path);
+            if !compressible || !settings.enabled(encoding) {
+                // only a copy we made ourselves, never a file that just happens to have the name
+                if previous.contains(output, &sibling) && sibling.is_file() {
+                    std::fs::remove_file(&sibling)
+                        .map_err(|e| CbError::io(format!("Failed to delete {}", sibling.display()), e))?;
+                    say!("Deleted {}, which is no longer wanted", sibling.display());
+                }
+                continue;
+            }
+            if source_unchanged && previous.is_current(output, &sibling) {
+                siblings.push(sibling);
+                continue;
+            }
+
+            let compressed = match encoding {
+                Encoding::Gzip => {
+                    let contents = std::fs::read(path)
+                        .map_err(|e| CbError::io(format!("Failed to read {}", path.display()), e))?;
+                    gzip(&contents)
+                }
+                Encoding::Brotli => brotli(ctx, path)?,
+            };
+            std::fs::write(&sibling, &compressed)
+                .map_err(|e| CbError::io(format!("Failed to write {}", sibling.display()), e))?;
+            say!(
+                "Compressed {} ({} bytes) to {} ({} bytes)",
+                path.display(),
+                size,
+                sibling.display(),
+                compressed.len()
+            );
+            siblings.push(sibling);
+        }
+    }
+    Ok(siblings)
+}
+
+fn brotli(ctx: &RunContext, path: &Path) -> Result<Vec<u8>, CbError> {
+    let mut cmd = Command::new(&ctx.tools.brotli);
+    cmd.arg("--stdout").arg("--quality=11").arg(path);
+    let output = command::run_with_timeout(&mut cmd, ctx.tool_timeout).map_err(|e| CbError::tool("brotli", e))?;
+    if !output.status.success() {
+        return Err(CbError::tool(
+            "brotli",
+            format!(
+                "compressing {} returned {}: {}",
+                path.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// `bytes` as a gzip file, with no name or modification time in the header so that the same input always gives the
+/// same output
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    // magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend(deflate(bytes));
+    out.extend(checksum::crc32(bytes).to_le_bytes());
+    out.extend((bytes.len() as u32).to_le_bytes());
+    out
+}
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier places with the same first bytes are tried, which is plenty for html
+const MAX_CHAIN: usize = 64;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Writes bits the way deflate packs them: starting from the lowest bit of each byte
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes go in starting from their highest bit
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// Writes a literal/length symbol with the fixed Huffman codes
+fn write_symbol(w: &mut BitWriter, symbol: u16) {
+    let symbol = u32::from(symbol);
+    match symbol {
+        0..=143 => w.write_code(0x30 + symbol, 8),
+        144..=255 => w.write_code(0x190 + symbol - 144, 9),
+        256..=279 => w.write_code(symbol - 256, 7),
+        _ => w.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .unwrap();
+    write_symbol(w, 257 + code as u16);
+    w.write(
+        (length - usize::from(LENGTH_BASE[code])) as u32,
+        u32::from(LENGTH_EXTRA[code]),
+    );
+    let code = DIST_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .unwrap();
+    w.write_code(code as u32, 5);
+    w.write(
+        (distance - usize::from(DIST_BASE[code])) as u32,
+        u32::from(DIST_EXTRA[code]),
+    );
+}
+
+/// A single deflate block with the fixed Huffman codes, which is a long way from the best compression but gets most of
+/// what's there in text full of repeated markup
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    const HASH_SIZE: usize = 1 << 15;
+    let hash = |at: usize| {
+        let key = u32::from(bytes[at]) << 16 | u32::from(bytes[at + 1]) << 8 | u32::from(bytes[at + 2]);
+        (key.wrapping_mul(2_654_435_761) >> 17) as usize % HASH_SIZE
+    };
+    // the latest place each hash was seen, and for every place, the one before it with the same hash
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; bytes.len()];
+    let insert = |at: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if at + MIN_MATCH <= bytes.len() {
+            let h = hash(at);
+            prev[at] = head[h];
+            head[h] = at;
+        }
+    };
+
+    let mut w = BitWriter::default();
+    // final block, fixed codes
+    w.write(1, 1);
+    w.write(1, 2);
+    let mut at = 0;
+    while at < bytes.len() {
+        let mut best = (0, 0);
+        if at + MIN_MATCH <= bytes.len() {
+            let longest = (bytes.len() - at).min(MAX_MATCH);
+            let mut candidate = head[hash(at)];
+            let mut tries = 0;
+            while candidate != usize::MAX && at - candidate <= WINDOW && tries < MAX_CHAIN {
+                let length = bytes[candidate..]
+                    .iter()
+                    .zip(&bytes[at..at + longest])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, at - candidate);
+                    if length == longest {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                tries += 1;
+            }
+        }
+
+        let (length, distance) = best;
+        if length >= MIN_MATCH {
+            write_match(&mut w, length, distance);
+            for i in at..at + length {
+                insert(i, &mut head, &mut prev);
+            }
+            at += length;
+        } else {
+            write_symbol(&mut w, u16::from(bytes[at]));
+            insert(at, &mut head, &mut prev);
+            at += 1;
+        }
+    }
+    write_symbol(&mut w, 256);
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Just enough of inflate to read back what [`deflate`] writes
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut pos = 0;
+        let mut bit = |count: u32| {
+            let mut value = 0;
+            for i in 0..count {
+                value |= u32::from(data[pos / 8] >> (pos % 8) & 1) << i;
+                pos += 1;
+            }
+            value
+        };
+        assert_eq!(bit(1), 1, "final block");
+        assert_eq!(bit(2), 1, "fixed codes");
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            // read the code a bit at a time, highest bit first, until it's one of the fixed ones
+            let mut code = 0;
+            let mut len = 0;
+            let symbol = loop {
+                code = code << 1 | bit(1);
+                len += 1;
+                match (len, code) {
+                    (7, 0..=0x17) => break code + 256,
+                    (8, 0x30..=0xbf) => break code - 0x30,
+                    (8, 0xc0..=0xc7) => break code - 0xc0 + 280,
+                    (9, 0x190..=0x1ff) => break code - 0x190 + 144,
+                    _ => assert!(len < 9),
+                }
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let i = (symbol - 257) as usize;
+                    let length = usize::from(LENGTH_BASE[i]) + bit(u32::from(LENGTH_EXTRA[i])) as usize;
+                    let d = (0..5).fold(0, |acc, _| acc << 1 | bit(1)) as usize;
+                    let distance = usize::from(DIST_BASE[d]) + bit(u32::from(DIST_EXTRA[d])) as usize;
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let page = "<li><a href=\"../jam/\">Jam</a></li>\n".repeat(200);
+        let samples: [&[u8]; 4] = [b"", b"a", b"abcabcabcabcabcabc", page.as_bytes()];
+        for sample in samples {
+            let gz = gzip(sample);
+            assert_eq!(&gz[..4], [0x1f, 0x8b, 8, 0]);
+            let trailer = &gz[gz.len() - 8..];
+            assert_eq!(trailer[..4], checksum::crc32(sample).to_le_bytes());
+            assert_eq!(trailer[4..], (sample.len() as u32).to_le_bytes());
+            assert_eq!(inflate(&gz[10..gz.len() - 8]), sample);
+        }
+        assert!(gzip(page.as_bytes()).len() < page.len() / 20);
+
+        // everything from a byte to a long way back
+        let varied: Vec<u8> = (0..100_000u64).map(|i| (i * i / 7 % 251) as u8).collect();
+        assert_eq!(inflate(&gzip(&varied)[10..]), varied);
+    }
+
+    #[test]
+    fn siblings() {
+        assert_eq!(
+            Encoding::Gzip.sibling(Path::new("out/jam/index.html")),
+            Path::new("out/jam/index.html.gz")
+        );
+        assert_eq!(
+            Encoding::Brotli.sibling(Path::new("style.css")),
+            Path::new("style.css.br")
+        );
+        assert!(is_text(Path::new("metadata.JSON")));
+        assert!(!is_text(Path::new("ToS.txt")));
+        assert!(!is_text(Path::new("index.html.gz")));
+    }
+}
@@ -0,0 +1,168 @@
+//! Machine-readable progress events
+//!
+//! When enabled with `--progress-format json`, each event is written to stderr as a single line of JSON, so that CI can
+//! show what stage we're in without having to parse the human-readable output on stdout.
+//!
+//! The names of the events (and their fields) are part of our CI interface, so don't change them lightly:
+//!
+//! ```
+//! use cb_processor::progress::{ProgressEvent, Stage};
+//! use cb_processor::timing::{ItemTiming, StageTiming};
+//!
+//! let events = [
+//!     ProgressEvent::StageStarted { stage: Stage::Convert },
+//!     ProgressEvent::ItemProcessed { stage: Stage::Convert, item: "a.ogg".into(), index: 1, total: 2 },
+//!     ProgressEvent::Warning { stage: Stage::Patch, message: "not local".into() },
+//!     ProgressEvent::Error { stage: Stage::Validate, message: "missing".into() },
+//!     ProgressEvent::Summary { stage: Stage::Patch, processed: 3, errors: 0, warnings: 1 },
+//!     ProgressEvent::StageFinished { stage: Stage::Prime, success: true, duration_ms: 1500 },
+//!     ProgressEvent::Timings {
+//!         stages: vec![StageTiming { stage: Stage::Load, duration_ms: 20 }],
+//!         slowest: vec![ItemTiming { stage: Stage::Convert, item: "a.ogg".into(), duration_ms: 900 }],
+//!     },
+//! ];
+//! let json: Vec<String> = events.iter().map(|e| serde_json::to_string(e).unwrap()).collect();
+//! assert_eq!(
+//!     json,
+//!     [
+//!         r#"{"event":"stage_started","stage":"convert"}"#,
+//!         r#"{"event":"item_processed","stage":"convert","item":"a.ogg","index":1,"total":2}"#,
+//!         r#"{"event":"warning","stage":"patch","message":"not local"}"#,
+//!         r#"{"event":"error","stage":"validate","message":"missing"}"#,
+//!         r#"{"event":"summary","stage":"patch","processed":3,"errors":0,"warnings":1}"#,
+//!         r#"{"event":"stage_finished","stage":"prime","success":true,"duration_ms":1500}"#,
+//!         concat!(
+//!             r#"{"event":"timings","stages":[{"stage":"load","duration_ms":20}],"#,
+//!             r#""slowest":[{"stage":"convert","item":"a.ogg","duration_ms":900}]}"#
+//!         ),
+//!     ]
+//! );
+//! ```
+
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::timing::{ItemTiming, StageTiming};
+
+/// The major pipeline stages that report progress
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Load,
+    Validate,
+    Convert,
+    Analyze,
+    Generate,
+    Patch,
+    Publish,
+    Prime,
+    Fetch,
+    Archive,
+    Verify,
+    Benchmark,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    StageStarted {
+        stage: Stage,
+    },
+    /// One unit of work (a recording, a converted file, a patched link, a primed URL) is done
+    ///
+    /// `index` counts from 1, and `total` is the number of items expected in this stage
+    ItemProcessed {
+        stage: Stage,
+        item: String,
+        index: usize,
+        total: usize,
+    },
+    Warning {
+        stage: Stage,
+        message: String,
+    },
+    Error {
+        stage: Stage,
+        message: String,
+    },
+    /// Final counts for a stage, sent just before it finishes
+    Summary {
+        stage: Stage,
+        processed: usize,
+        errors: usize,
+        warnings: usize,
+    },
+    StageFinished {
+        stage: Stage,
+        success: bool,
+        duration_ms: u64,
+    },
+    /// How long each stage took, and the slowest items in each stage.  Sent at the end of a run
+    Timings {
+        stages: Vec<StageTiming>,
+        slowest: Vec<ItemTiming>,
+    },
+}
+
+static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+static LOG: AtomicBool = AtomicBool::new(true);
+
+/// Turns the human-readable output on stdout on or off.  It's on unless a program embedding the library turns it off
+pub fn enable_log(enabled: bool) {
+    LOG.store(enabled, Ordering::Relaxed);
+}
+
+/// Writes to the human-readable output on stdout, if it's on
+///
+/// Everything the library says goes through here (with the `say!` macros), never straight to stdout.
+pub fn log(args: std::fmt::Arguments) {
+    if !LOG.load(Ordering::Relaxed) {
+        return;
+    }
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = stdout.write_fmt(args);
+    let _ = stdout.flush();
+}
+
+/// Turns on JSON progress events on stderr
+pub fn enable_json(enabled: bool) {
+    JSON_PROGRESS.store(enabled, Ordering::Relaxed);
+}
+
+/// Sends a progress event, if progress events are enabled
+pub fn emit(event: ProgressEvent) {
+    if !JSON_PROGRESS.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(line) = serde_json::to_string(&event) {
+        // lock so that events from different threads don't get interleaved
+        let stderr = std::io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = writeln!(stderr, "{}", line);
+    }
+}
+
+/// Runs a stage, sending the started and finished events around it
+pub fn stage<T, E>(stage: Stage, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    timed_stage(stage, f).0
+}
+
+/// Like [`stage`], but also returns how long the stage took
+pub fn timed_stage<T, E>(stage: Stage, f: impl FnOnce() -> Result<T, E>) -> (Result<T, E>, Duration) {
+    emit(ProgressEvent::StageStarted { stage });
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    emit(ProgressEvent::StageFinished {
+        stage,
+        success: result.is_ok(),
+        duration_ms: elapsed.as_millis() as u64,
+    });
+    (result, elapsed)
+}
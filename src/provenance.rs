@@ -0,0 +1,179 @@
+//! How each ogg and mp3 was made (`conversions.json` in each recording's folder)
+//!
+//! Every conversion records the exact ffmpeg command line, the version of ffmpeg that ran it and a fingerprint of the
+//! source, so that a file can be made again the same way (`--show-provenance`).  Entries are keyed by the output's
+//! path in the recording folder, so regenerating a file replaces its entry, and they're kept sorted so that the file
+//! only changes where a conversion did.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{context::RunContext, error::CbError, state_store};
+
+pub const PROVENANCE_FILE: &str = "conversions.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConversionRecord {
+    /// The source, relative to the recording's folder
+    pub input: String,
+    /// SHA-256 of the source when it was converted
+    pub input_sha256: String,
+    /// The first line of `ffmpeg -version`
+    pub ffmpeg_version: String,
+    /// Everything that was run, starting with ffmpeg itself
+    pub command: Vec<String>,
+}
+
+/// The conversions made in one recording folder, by output path (relative to the folder, with `/` separators)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub conversions: BTreeMap<String, ConversionRecord>,
+}
+
+impl Provenance {
+    /// Reads the provenance in `folder`, or an empty one if there's none yet (or it can't be read)
+    pub fn load(folder: &Path) -> Result<Provenance, CbError> {
+        Ok(state_store::read_doc(&folder.join(PROVENANCE_FILE))?.unwrap_or_default())
+    }
+
+    /// Records how `output` (somewhere in `folder`) was made, replacing what was recorded for it before
+    pub fn record(
+        folder: &Path, output: &Path, input: &Path, input_sha256: &str, ffmpeg_version: &str, command: &Command,
+    ) -> Result<(), CbError> {
+        let mut provenance = Provenance::load(folder)?;
+        provenance.conversions.insert(
+            relative_name(folder, output),
+            ConversionRecord {
+                input: relative_name(folder, input),
+                input_sha256: input_sha256.to_string(),
+                ffmpeg_version: ffmpeg_version.to_string(),
+                command: argv(command),
+            },
+        );
+        state_store::write_doc(&folder.join(PROVENANCE_FILE), &provenance)
+    }
+}
+
+/// `path` relative to `folder` if it's inside it, as it is otherwise
+fn relative_name(folder: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(folder).unwrap_or(path);
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn argv(command: &Command) -> Vec<String> {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|a: &OsStr| a.to_string_lossy().to_string())
+        .collect()
+}
+
+/// The first line of `ffmpeg -version`, or why there isn't one
+pub fn ffmpeg_version(ctx: &RunContext) -> String {
+    match Command::new(&ctx.tools.ffmpeg).arg("-version").output() {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        Ok(out) => format!("unknown (-version returned {})", out.status),
+        Err(e) => format!("unknown ({})", e),
+    }
+}
+
+/// Finds what was recorded for `output`, looking for a [`PROVENANCE_FILE`] in each of the folders above it
+///
+/// Returns the folder the record was found in along with it.
+pub fn find(output: &Path) -> Result<Option<(PathBuf, ConversionRecord)>, CbError> {
+    let output =
+        std::fs::canonicalize(output).map_err(|e| CbError::io(format!("Failed to find {}", output.display()), e))?;
+    for folder in output.ancestors().skip(1) {
+        if !folder.join(PROVENANCE_FILE).is_file() {
+            continue;
+        }
+        let mut provenance = Provenance::load(folder)?;
+        if let Some(record) = provenance.conversions.remove(&relative_name(folder, &output)) {
+            return Ok(Some((folder.to_path_buf(), record)));
+        }
+    }
+    Ok(None)
+}
+
+/// The command as it would be typed into a POSIX shell
+pub fn shell_command(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+            if plain {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_find() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().canonicalize().unwrap().join("jam1");
+        std::fs::create_dir_all(folder.join("ogg")).unwrap();
+        let flac = folder.join("kick.flac");
+        let ogg = folder.join("ogg/kick.ogg");
+        std::fs::write(&ogg, "ogg").unwrap();
+        std::fs::write(folder.join("ogg/snare.ogg"), "ogg").unwrap();
+
+        let command = |filters: &str| {
+            let mut cmd = Command::new("ffmpeg");
+            cmd.arg("-i").arg(&flac).arg("-af").arg(filters).arg(&ogg);
+            cmd
+        };
+        Provenance::record(&folder, &ogg, &flac, "aaaa", "ffmpeg version 4", &command("old")).unwrap();
+        Provenance::record(&folder, &folder.join("ogg/a.ogg"), &flac, "aaaa", "v", &command("x")).unwrap();
+        Provenance::record(&folder, &ogg, &flac, "bbbb", "ffmpeg version 5", &command("new")).unwrap();
+
+        let provenance = Provenance::load(&folder).unwrap();
+        assert_eq!(
+            provenance.conversions.keys().collect::<Vec<_>>(),
+            ["ogg/a.ogg", "ogg/kick.ogg"]
+        );
+        let (found_in, record) = find(&ogg).unwrap().unwrap();
+        assert_eq!(found_in, folder);
+        assert_eq!(record.input, "kick.flac");
+        assert_eq!(record.input_sha256, "bbbb");
+        assert_eq!(record.ffmpeg_version, "ffmpeg version 5");
+        assert_eq!(record.command[0], "ffmpeg");
+        assert_eq!(record.command[4], "new");
+        assert_eq!(find(&folder.join("ogg/snare.ogg")).unwrap(), None);
+    }
+
+    #[test]
+    fn quoting() {
+        let command: Vec<String> = ["ffmpeg", "-i", "/data/Kick Drum.flac", "it's", "aresample=osf=s16", ""]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            shell_command(&command),
+            r"ffmpeg -i '/data/Kick Drum.flac' 'it'\''s' aresample=osf=s16 ''"
+        );
+    }
+}
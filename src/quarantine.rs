@@ -0,0 +1,156 @@
+//! Sources that ffmpeg keeps failing on (`quarantine.json` in each recording's folder)
+//!
+//! A corrupt flac would otherwise fail every conversion run.  Each run that fails to convert a source counts against
+//! it, and once it has failed [`QUARANTINE_AFTER`] runs in a row it's skipped until someone looks at it (validation
+//! lists it as an error) and clears it with `--retry-quarantined`.  A successful conversion wipes its slate clean.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::CbError, types::Season};
+
+pub const QUARANTINE_FILE: &str = "quarantine.json";
+
+/// How many runs in a row a source can fail before it's skipped
+pub const QUARANTINE_AFTER: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FailedSource {
+    /// Runs in a row that failed to convert it
+    pub failures: u32,
+    /// What went wrong the last time
+    pub error: String,
+}
+
+impl FailedSource {
+    pub fn quarantined(&self) -> bool {
+        self.failures >= QUARANTINE_AFTER
+    }
+}
+
+/// The failing sources in one recording folder, by path (relative to the folder, with `/` separators)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Quarantine {
+    pub sources: BTreeMap<String, FailedSource>,
+}
+
+impl Quarantine {
+    /// Reads the quarantine in `folder`, or an empty one if there's none
+    pub fn load(folder: &Path) -> Result<Quarantine, CbError> {
+        let path = folder.join(QUARANTINE_FILE);
+        match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| CbError::parse(format!("Unexpected contents in {}", path.display()), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Quarantine::default()),
+            Err(e) => Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+        }
+    }
+
+    /// Writes the quarantine to `folder`, or removes the file if nothing is failing any more
+    fn save(&self, folder: &Path) -> Result<(), CbError> {
+        let path = folder.join(QUARANTINE_FILE);
+        if self.sources.is_empty() {
+            return match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(CbError::io(format!("Failed to delete {}", path.display()), e))
+                }
+                _ => Ok(()),
+            };
+        }
+        let bytes = crate::to_json_bytes(self).expect("a quarantine is always valid JSON");
+        std::fs::write(&path, bytes).map_err(|e| CbError::io(format!("Failed to write {}", path.display()), e))
+    }
+
+    /// The sources that are being skipped
+    pub fn quarantined(&self) -> impl Iterator<Item = (&String, &FailedSource)> {
+        self.sources.iter().filter(|(_, s)| s.quarantined())
+    }
+
+    pub fn is_quarantined(&self, folder: &Path, source: &Path) -> bool {
+        self.sources
+            .get(&relative_name(folder, source))
+            .is_some_and(FailedSource::quarantined)
+    }
+
+    /// Counts a failed run against `source`, returning whether that put it in quarantine
+    pub fn failed(folder: &Path, source: &Path, error: &str) -> Result<bool, CbError> {
+        let mut quarantine = Quarantine::load(folder)?;
+        let entry = quarantine
+            .sources
+            .entry(relative_name(folder, source))
+            .or_insert(FailedSource {
+                failures: 0,
+                error: String::new(),
+            });
+        entry.failures += 1;
+        entry.error = error.to_string();
+        let quarantined = entry.failures == QUARANTINE_AFTER;
+        quarantine.save(folder)?;
+        Ok(quarantined)
+    }
+
+    /// Forgets earlier failures of `source`, now that it converted
+    pub fn succeeded(folder: &Path, source: &Path) -> Result<(), CbError> {
+        let mut quarantine = Quarantine::load(folder)?;
+        if quarantine.sources.remove(&relative_name(folder, source)).is_some() {
+            quarantine.save(folder)?;
+        }
+        Ok(())
+    }
+}
+
+fn relative_name(folder: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(folder).unwrap_or(path);
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Clears the quarantine of every selected recording (`--retry-quarantined`), returning how many sources it held
+pub fn clear(ctx: &crate::context::RunContext, season: &Season) -> Result<usize, CbError> {
+    let mut cleared = 0;
+    for rec in ctx.only.select(season)? {
+        if let Some(folder) = rec.stereo_mix.folder_ondisk() {
+            let quarantine = Quarantine::load(folder)?;
+            cleared += quarantine.quarantined().count();
+            Quarantine::default().save(folder)?;
+        }
+    }
+    Ok(cleared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path();
+        let kick = folder.join("kick.flac");
+        let snare = folder.join("snare.flac");
+
+        for run in 1..=QUARANTINE_AFTER {
+            assert!(!Quarantine::load(folder).unwrap().is_quarantined(folder, &kick));
+            let quarantined = Quarantine::failed(folder, &kick, &format!("run {}", run)).unwrap();
+            assert_eq!(quarantined, run == QUARANTINE_AFTER);
+        }
+        Quarantine::failed(folder, &snare, "once").unwrap();
+
+        let quarantine = Quarantine::load(folder).unwrap();
+        assert!(quarantine.is_quarantined(folder, &kick));
+        assert!(!quarantine.is_quarantined(folder, &snare));
+        let quarantined: Vec<_> = quarantine.quarantined().collect();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].0, "kick.flac");
+        assert_eq!(quarantined[0].1.error, format!("run {}", QUARANTINE_AFTER));
+
+        // a success starts the count again, and the file goes when nothing is failing
+        Quarantine::succeeded(folder, &snare).unwrap();
+        Quarantine::succeeded(folder, &kick).unwrap();
+        assert_eq!(Quarantine::load(folder).unwrap(), Quarantine::default());
+        assert!(!folder.join(QUARANTINE_FILE).exists());
+    }
+}
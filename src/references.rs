@@ -0,0 +1,668 @@
+//! Checks that the generated pages only refer to files that exist
+//!
+//! A page that links to a stylesheet or script that isn't there still renders, just without it, so nothing else would
+//! notice (the season index once pointed at `css/site.css` for a week after the static files were reorganized).  After
+//! the pages are written, every `href`, `src` and `srcset` in them that isn't a URL of another site is resolved against
+//! the page (and its `<base>`) and looked for in the output.  The recordings' audio is often only in the data dir, so a
+//! reference into a recording's data folder can be in its folder on disk instead.
+//!
+//! The pages are read with a tokenizer that follows HTML's rules for comments, quoting, character references and the
+//! contents of `<script>` and `<style>` (which aren't markup, so the `<a href="${slug}/">` in a script is left alone),
+//! rather than by searching for `href=`.  It only has to find the attributes in our own pages, so it's a lot smaller
+//! than a whole HTML parser: it doesn't build a tree, and only knows the named character references that can stand
+//! for a character of a URL or of Latin-1.
+
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "cli")]
+use colored::Colorize;
+
+#[cfg(not(feature = "cli"))]
+use crate::plain::Colorize;
+use crate::{
+    error::CbError,
+    progress::{self, ProgressEvent, Stage},
+    types::Season,
+};
+
+/// A reference in a generated page to a file that doesn't exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenReference {
+    /// The page, relative to the output
+    pub page: String,
+    /// The `href` or `src`, as it is in the page
+    pub reference: String,
+    /// What it refers to, relative to the output (`None` if it's above the output)
+    pub target: Option<String>,
+}
+
+impl std::fmt::Display for BrokenReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.target {
+            Some(target) => write!(
+                f,
+                "{} refers to {:?}, but {} doesn't exist",
+                self.page, self.reference, target
+            ),
+            None => write!(
+                f,
+                "{} refers to {:?}, which is outside the site",
+                self.page, self.reference
+            ),
+        }
+    }
+}
+
+/// What a page refers to
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Scan {
+    /// The href of the page's `<base>`, if it has one
+    base: Option<String>,
+    /// Every `href` and `src` besides the base's, and every URL in a `srcset`, in the order they're in the page
+    references: Vec<String>,
+}
+
+/// Elements whose contents are text up to their end tag, rather than markup
+const RAW_TEXT_ELEMENTS: &[&str] = &[
+    "script", "style", "textarea", "title", "xmp", "iframe", "noembed", "noframes",
+];
+
+/// Finds the `href`, `src` and `srcset` attributes of the tags in `html`
+fn scan(html: &str) -> Scan {
+    let mut scan = Scan::default();
+    let mut rest = html;
+    while let Some(idx) = rest.find('<') {
+        rest = &rest[idx..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = skip_comment(comment);
+            continue;
+        }
+        let is_start_tag = rest[1..].starts_with(|c: char| c.is_ascii_alphabetic());
+        if !is_start_tag {
+            // an end tag, a doctype or a processing instruction, or a `<` that doesn't start anything
+            rest = if rest[1..].starts_with(['/', '!', '?']) {
+                rest.find('>').map_or("", |end| &rest[end + 1..])
+            } else {
+                &rest[1..]
+            };
+            continue;
+        }
+
+        let (name, attributes, after) = start_tag(&rest[1..]);
+        for (attribute, value) in attributes {
+            match (name.as_str(), attribute.as_str()) {
+                ("base", "href") => scan.base = scan.base.or(Some(value)),
+                (_, "href") | (_, "src") => scan.references.push(value),
+                (_, "srcset") | ("link", "imagesrcset") => scan.references.extend(srcset_urls(&value)),
+                _ => {}
+            }
+        }
+        rest = after;
+        if name == "plaintext" {
+            // there's no end tag, everything after it is text
+            break;
+        }
+        if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            rest = skip_raw_text(rest, &name);
+        }
+    }
+    scan
+}
+
+/// Skips a comment (after its `<!--`), returning what's after it
+///
+/// `<!-->` and `<!--->` are whole (empty) comments, and `--!>` ends one as well as `-->` does.
+fn skip_comment(comment: &str) -> &str {
+    if let Some(after) = comment.strip_prefix('>').or_else(|| comment.strip_prefix("->")) {
+        return after;
+    }
+    let end = [
+        comment.find("-->").map(|end| end + 3),
+        comment.find("--!>").map(|end| end + 4),
+    ];
+    match end.iter().flatten().min() {
+        Some(end) => &comment[*end..],
+        None => "",
+    }
+}
+
+/// The URLs in a `srcset`, which is a comma-separated list of URLs each followed by descriptors like `2x` or `640w`
+///
+/// A URL can have commas in it, but not at its end, where they separate it from the next one.
+fn srcset_urls(srcset: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            return urls;
+        }
+        let end = rest.find(|c: char| c.is_ascii_whitespace()).unwrap_or(rest.len());
+        let url = &rest[..end];
+        rest = &rest[end..];
+        let trimmed = url.trim_end_matches(',');
+        if trimmed.len() == url.len() {
+            // the descriptors go up to the next comma that isn't in parentheses
+            let mut depth = 0;
+            let end = rest
+                .find(|c: char| {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth = 0,
+                        ',' if depth == 0 => return true,
+                        _ => {}
+                    }
+                    false
+                })
+                .unwrap_or(rest.len());
+            rest = &rest[end..];
+        }
+        if !trimmed.is_empty() {
+            urls.push(trimmed.to_string());
+        }
+    }
+}
+
+/// Reads a start tag (after its `<`), returning its lowercased name, its attributes with their values decoded, and
+/// what's after its `>`
+fn start_tag(tag: &str) -> (String, Vec<(String, String)>, &str) {
+    let is_name_end = |c: char| c.is_ascii_whitespace() || c == '/' || c == '>';
+    let end = tag.find(is_name_end).unwrap_or(tag.len());
+    let name = tag[..end].to_ascii_lowercase();
+    let mut rest = &tag[end..];
+    let mut attributes = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if rest.is_empty() {
+            return (name, attributes, rest);
+        }
+        if let Some(after) = rest.strip_prefix('>') {
+            return (name, attributes, after);
+        }
+
+        // a name can start with `=`, but not have one after that
+        let first = rest.chars().next().map_or(0, char::len_utf8);
+        let end = rest[first..]
+            .find(|c: char| is_name_end(c) || c == '=')
+            .map_or(rest.len(), |end| end + first);
+        let attribute = rest[..end].to_ascii_lowercase();
+        let duplicate = attributes.iter().any(|(name, _)| *name == attribute);
+        rest = rest[end..].trim_start_matches(|c: char| c.is_ascii_whitespace());
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start_matches(|c: char| c.is_ascii_whitespace());
+                let (value, after) = match after.chars().next() {
+                    Some(quote @ ('"' | '\'')) => match after[1..].find(quote) {
+                        Some(end) => (&after[1..end + 1], &after[end + 2..]),
+                        None => (&after[1..], ""),
+                    },
+                    _ => {
+                        let end = after
+                            .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                            .unwrap_or(after.len());
+                        after.split_at(end)
+                    }
+                };
+                rest = after;
+                decode_entities(value)
+            }
+            None => String::new(),
+        };
+        // only the first of an attribute counts
+        if !duplicate {
+            attributes.push((attribute, value));
+        }
+    }
+}
+
+/// Skips the contents of a raw text element, returning what's after its end tag
+fn skip_raw_text<'h>(html: &'h str, name: &str) -> &'h str {
+    let mut from = 0;
+    while let Some(idx) = html[from..].find("</") {
+        let after = &html[from + idx + 2..];
+        let is_end_tag = after.get(..name.len()).is_some_and(|n| n.eq_ignore_ascii_case(name))
+            && after[name.len()..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>');
+        if is_end_tag {
+            return after.find('>').map_or("", |end| &after[end + 1..]);
+        }
+        from += idx + 2;
+    }
+    ""
+}
+
+/// The named character references for the characters of URLs, besides letters and digits
+const ASCII_REFERENCES: &[(&str, char)] = &[
+    ("Tab", '\t'),
+    ("NewLine", '\n'),
+    ("excl", '!'),
+    ("quot", '"'),
+    ("QUOT", '"'),
+    ("num", '#'),
+    ("dollar", '$'),
+    ("percnt", '%'),
+    ("amp", '&'),
+    ("AMP", '&'),
+    ("apos", '\''),
+    ("lpar", '('),
+    ("rpar", ')'),
+    ("ast", '*'),
+    ("midast", '*'),
+    ("plus", '+'),
+    ("comma", ','),
+    ("period", '.'),
+    ("sol", '/'),
+    ("colon", ':'),
+    ("semi", ';'),
+    ("lt", '<'),
+    ("LT", '<'),
+    ("equals", '='),
+    ("gt", '>'),
+    ("GT", '>'),
+    ("quest", '?'),
+    ("commat", '@'),
+    ("lsqb", '['),
+    ("lbrack", '['),
+    ("bsol", '\\'),
+    ("rsqb", ']'),
+    ("rbrack", ']'),
+    ("Hat", '^'),
+    ("lowbar", '_'),
+    ("UnderBar", '_'),
+    ("grave", '`'),
+    ("DiacriticalGrave", '`'),
+    ("lcub", '{'),
+    ("lbrace", '{'),
+    ("verbar", '|'),
+    ("vert", '|'),
+    ("VerticalLine", '|'),
+    ("rcub", '}'),
+    ("rbrace", '}'),
+    ("NonBreakingSpace", '\u{a0}'),
+];
+
+/// The named character references for U+00A0 to U+00FF, in order
+const LATIN1_REFERENCES: [&str; 96] = [
+    "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf", "laquo", "not", "shy",
+    "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro", "para", "middot", "cedil", "sup1", "ordm",
+    "raquo", "frac14", "frac12", "frac34", "iquest", "Agrave", "Aacute", "Acirc", "Atilde", "Auml", "Aring", "AElig",
+    "Ccedil", "Egrave", "Eacute", "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml", "ETH", "Ntilde", "Ograve",
+    "Oacute", "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute", "Ucirc", "Uuml", "Yacute", "THORN",
+    "szlig", "agrave", "aacute", "acirc", "atilde", "auml", "aring", "aelig", "ccedil", "egrave", "eacute", "ecirc",
+    "euml", "igrave", "iacute", "icirc", "iuml", "eth", "ntilde", "ograve", "oacute", "ocirc", "otilde", "ouml",
+    "divide", "oslash", "ugrave", "uacute", "ucirc", "uuml", "yacute", "thorn", "yuml",
+];
+
+/// The character a named reference (without its `&` and `;`) stands for
+fn named_reference(name: &str) -> Option<char> {
+    if let Some((_, c)) = ASCII_REFERENCES.iter().find(|(known, _)| *known == name) {
+        return Some(*c);
+    }
+    let idx = LATIN1_REFERENCES.iter().position(|known| *known == name)?;
+    char::from_u32(0xa0 + idx as u32)
+}
+
+/// Whether a named reference can be used without its `;`, as it could before HTML5
+fn is_legacy(name: &str) -> bool {
+    ["amp", "AMP", "lt", "LT", "gt", "GT", "quot", "QUOT"].contains(&name) || LATIN1_REFERENCES.contains(&name)
+}
+
+/// Decodes the character reference at the start of `reference` (after its `&`), returning the character and how much
+/// of `reference` it took up, or `None` if it isn't one
+fn character_reference(reference: &str) -> Option<(char, usize)> {
+    if let Some(number) = reference.strip_prefix('#') {
+        let (digits, radix, prefix) = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => (hex, 16, 2),
+            None => (number, 10, 1),
+        };
+        let len = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+        if len == 0 {
+            return None;
+        }
+        // a number that's too big, a surrogate or NUL is U+FFFD, like a browser makes of it
+        let c = u32::from_str_radix(&digits[..len], radix)
+            .ok()
+            .filter(|code| *code != 0)
+            .and_then(char::from_u32)
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        let semicolon = usize::from(digits[len..].starts_with(';'));
+        return Some((c, prefix + len + semicolon));
+    }
+
+    let len = reference
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(reference.len());
+    if reference[len..].starts_with(';') {
+        if let Some(c) = named_reference(&reference[..len]) {
+            return Some((c, len + 1));
+        }
+    }
+    // without its `;`, the longest legacy name it starts with, unless that runs on into something like `&copy=`, which
+    // is left alone in an attribute so that the query strings of old pages still work
+    let (name, c) = (1..=len)
+        .rev()
+        .map(|end| &reference[..end])
+        .filter(|name| is_legacy(name))
+        .find_map(|name| Some((name, named_reference(name)?)))?;
+    let next = reference[name.len()..].chars().next();
+    if next.is_some_and(|next| next.is_ascii_alphanumeric() || next == '=') {
+        return None;
+    }
+    Some((c, name.len()))
+}
+
+/// Decodes the character references in an attribute value
+fn decode_entities(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(idx) = rest.find('&') {
+        decoded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        match character_reference(rest) {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => decoded.push('&'),
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Whether a reference is to another site (or isn't to a file at all), like `https://...`, `//host/...` or `mailto:`
+fn is_external(reference: &str) -> bool {
+    if reference.starts_with("//") {
+        return true;
+    }
+    match reference.find(':') {
+        Some(idx) => {
+            let scheme = &reference[..idx];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        None => false,
+    }
+}
+
+/// Resolves `reference` against the folder `dir` (both relative to the output), as a browser would
+///
+/// Returns the path segments of what it refers to, with `index.html` for a folder, or `None` if it's above the output.
+fn resolve(dir: &[String], reference: &str) -> Option<Vec<String>> {
+    let path = reference.split(['?', '#']).next().unwrap_or_default();
+    let mut segments: Vec<String> = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        dir.to_vec()
+    };
+    let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    for (i, part) in parts.iter().enumerate() {
+        let last = i + 1 == parts.len();
+        match *part {
+            "" | "." if last => segments.push("index.html".to_string()),
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+                if last {
+                    segments.push("index.html".to_string());
+                }
+            }
+            part => segments.push(crate::slug::decode_path(part)),
+        }
+    }
+    Some(segments)
+}
+
+/// The folder that a page's references are relative to, as path segments relative to the output
+fn base_dir(page: &[String], base: Option<&str>) -> Option<Vec<String>> {
+    let dir = page[..page.len().saturating_sub(1)].to_vec();
+    match base.filter(|base| !is_external(base)) {
+        Some(base) => {
+            let mut resolved = resolve(&dir, base)?;
+            // the base's own file name (usually the `index.html` of a folder) doesn't matter
+            resolved.pop();
+            Some(resolved)
+        }
+        None => Some(dir),
+    }
+}
+
+/// Checks every reference in `pages` (the HTML files that were written into `output`)
+///
+/// A reference is fine if what it refers to is in the output, or in the folder on disk of the recording whose
+/// data_folder it's in.  When a recording was loaded from the metadata, the files in its data folder can't be checked,
+/// so references to them are taken on trust.
+pub fn check_pages(season: &Season, output: &Path, pages: &[PathBuf]) -> Result<Vec<BrokenReference>, CbError> {
+    let mut broken = Vec::new();
+    for page in pages {
+        let rel = match page.strip_prefix(output) {
+            Ok(rel) => rel,
+            Err(_) => continue,
+        };
+        let page_segments: Vec<String> = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let page_name = page_segments.join("/");
+        let html =
+            std::fs::read_to_string(page).map_err(|e| CbError::io(format!("Failed to read {}", page.display()), e))?;
+        let scan = scan(&html);
+
+        let dir = base_dir(&page_segments, scan.base.as_deref());
+        for reference in scan.references {
+            if reference.is_empty() || reference.starts_with('#') || is_external(&reference) {
+                continue;
+            }
+            let target = match dir.as_ref().and_then(|dir| resolve(dir, &reference)) {
+                Some(target) => target,
+                None => {
+                    broken.push(BrokenReference {
+                        page: page_name.clone(),
+                        reference,
+                        target: None,
+                    });
+                    continue;
+                }
+            };
+            if exists(season, output, &target) {
+                continue;
+            }
+            broken.push(BrokenReference {
+                page: page_name.clone(),
+                reference,
+                target: Some(target.join("/")),
+            });
+        }
+    }
+    Ok(broken)
+}
+
+/// Checks the pages among the files that were `written` into `output`, failing if any refers to a missing file
+pub fn check_generated(season: &Season, output: &Path, written: &[PathBuf]) -> Result<(), CbError> {
+    let pages: Vec<PathBuf> = written
+        .iter()
+        .filter(|path| path.extension().is_some_and(|ext| ext == "html"))
+        .cloned()
+        .collect();
+    let broken = check_pages(season, output, &pages)?;
+    if broken.is_empty() {
+        return Ok(());
+    }
+    for reference in &broken {
+        say!(" {}: {}", "ERROR".red(), reference);
+        progress::emit(ProgressEvent::Error {
+            stage: Stage::Generate,
+            message: reference.to_string(),
+        });
+    }
+    Err(CbError::BrokenReferences { references: broken })
+}
+
+/// Whether the file at `target` (path segments relative to the output) exists
+fn exists(season: &Season, output: &Path, target: &[String]) -> bool {
+    let in_output = target
+        .iter()
+        .fold(output.to_path_buf(), |path, segment| path.join(segment));
+    if in_output.exists() {
+        return true;
+    }
+    let (folder, rest) = match target.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    season
+        .recordings
+        .iter()
+        .filter(|recording| &recording.data_folder == folder)
+        .any(|recording| match recording.stereo_mix.folder_ondisk() {
+            Some(ondisk) => rest
+                .iter()
+                .fold(ondisk.to_path_buf(), |path, segment| path.join(segment))
+                .exists(),
+            None => true,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanning() {
+        let html = r#"<!DOCTYPE html>
+<html><head>
+    <base href="../jam1/" />
+    <link rel=stylesheet href=style.css>
+    <!-- <link rel="stylesheet" href="old.css" /> -->
+    <script src='player.js'></script>
+    <script>
+        const row = `<a href="${slug}/">${title}</a>`;
+        if (a < b) { document.write("</scripts>"); }
+    </script>
+    <style>a[href="x.css"] { color: red }</STYLE>
+</head>
+<body>
+    <a href="ogg/Kick &amp; Snare.ogg" download>Ogg</a>
+    <img src="peaks.png" alt="1 < 2">
+    <p>3 < 4 and <b>bold</b></p>
+    <a href = "../s01e01-jam-1/#track-2" title="&#x27;quoted&#39;">&para;</a>
+</body></html>"#;
+        let scan = scan(html);
+        assert_eq!(scan.base.as_deref(), Some("../jam1/"));
+        assert_eq!(
+            scan.references,
+            [
+                "style.css",
+                "player.js",
+                "ogg/Kick & Snare.ogg",
+                "peaks.png",
+                "../s01e01-jam-1/#track-2"
+            ]
+        );
+        assert_eq!(decode_entities("&#x27;a&#39; &amp;&nope; & x"), "'a' &&nope; & x");
+    }
+
+    #[test]
+    fn malformed_markup() {
+        let references = |html: &str| scan(html).references;
+        // comments that end early, or never
+        assert_eq!(references(r#"<!--><a href="a"><!---><a href="b">"#), ["a", "b"]);
+        assert_eq!(references(r#"<!-- x --!><a href="a"><!-- <a href="b">"#), ["a"]);
+        // quotes that never close take the rest of the page
+        assert_eq!(references(r#"<a href="a><img src="b">"#), ["a><img src="]);
+        assert_eq!(references("<a href='a"), ["a"]);
+        // unquoted values end at whitespace or `>`, and a `/` before the `>` is part of them
+        assert_eq!(references("<a href=a/><a href=>b</a><a href= c >"), ["a/", "", "c"]);
+        assert_eq!(references("<a href=a\"b'c title=x>"), ["a\"b'c"]);
+        // attributes without space between them, in any case, and only the first of each
+        assert_eq!(
+            references(r#"<A HREF="a"title='t'><img/src="b"/><a href="c" href="d">"#),
+            ["a", "b", "c"]
+        );
+        // what isn't a tag
+        assert_eq!(
+            references(r#"<?xml href="a"?></ href="b"><></><3 <a href="c"> a<b"#),
+            ["c"]
+        );
+        // text that isn't markup, up to an end tag that may never come
+        assert_eq!(
+            references(r#"<title><a href="a"></title ><iframe><img src="b">"#),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            references(r#"<plaintext></plaintext><a href="a">"#),
+            Vec::<String>::new()
+        );
+        assert_eq!(references(r#"<script/><a href="a"></script><a href="b">"#), ["b"]);
+        assert_eq!(references("<a href"), [""]);
+        assert_eq!(references("<a"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn srcsets() {
+        let html = r#"<img srcset="peaks.png, peaks@2x.png 2x,peaks,wide.png 640w , , x.png (min-width: 1px, 2px) 1x">
+<picture><source srcset="a.webp"></picture><link rel=preload imagesrcset="b.png 1x, c.png 2x">"#;
+        assert_eq!(
+            scan(html).references,
+            [
+                "peaks.png",
+                "peaks@2x.png",
+                "peaks,wide.png",
+                "x.png",
+                "a.webp",
+                "b.png",
+                "c.png"
+            ]
+        );
+        assert_eq!(srcset_urls("a.png,, b.png,"), ["a.png", "b.png"]);
+        assert_eq!(srcset_urls(" \t"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn character_references() {
+        assert_eq!(
+            decode_entities("ogg/Caf&eacute;&nbsp;&sol;&lpar;1&rpar;.ogg"),
+            "ogg/Caf\u{e9}\u{a0}/(1).ogg"
+        );
+        assert_eq!(decode_entities("&#38&#x26;&#X26x&#;&#x;"), "&&&x&#;&#x;");
+        // numbers that aren't characters
+        assert_eq!(
+            decode_entities("&#0;&#xD800;&#1114112;&#99999999999;"),
+            "\u{fffd}".repeat(4)
+        );
+        // without a `;`, only the old names, and not when they run into a query string's `=` or a longer word
+        assert_eq!(decode_entities("a?x=1&amp;y=2&amp z&copy"), "a?x=1&y=2& z\u{a9}");
+        assert_eq!(decode_entities("?a=1&copy=2&notit&sol"), "?a=1&copy=2&notit&sol");
+        assert_eq!(decode_entities("&not;&noti;&notit;"), "\u{ac}&noti;&notit;");
+        assert_eq!(decode_entities("&unknown; &AMP; &"), "&unknown; & &");
+    }
+
+    #[test]
+    fn resolving() {
+        let page = ["s01e01-jam-1".to_string(), "index.html".to_string()];
+        let dir = base_dir(&page, Some("../jam1/")).unwrap();
+        assert_eq!(dir, ["jam1"]);
+        assert_eq!(
+            resolve(&dir, "ogg/Kick%20%26%20Snare.ogg?x=1").unwrap(),
+            ["jam1", "ogg", "Kick & Snare.ogg"]
+        );
+        assert_eq!(resolve(&dir, "../jam2/#rec").unwrap(), ["jam2", "index.html"]);
+        assert_eq!(resolve(&dir, "/style.css").unwrap(), ["style.css"]);
+        assert_eq!(resolve(&dir, "../../style.css"), None);
+        assert_eq!(base_dir(&page, None).unwrap(), ["s01e01-jam-1"]);
+        assert_eq!(base_dir(&page, Some("https://example.com/")).unwrap(), ["s01e01-jam-1"]);
+
+        for external in [
+            "https://ipfs.io/",
+            "//cdn.example.com/a.js",
+            "mailto:a@b.c",
+            "data:image/png;base64,x",
+        ] {
+            assert!(is_external(external), "{}", external);
+        }
+        for local in ["style.css", "./x:y", "ogg/jam1_stereo.ogg"] {
+            assert!(!is_external(local), "{}", local);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Recordings that are takes of the same patch on different days (`related` in a recording's JSON)
+//!
+//! A recording names the others by their data_folder or slug.  The relation goes both ways, so naming it on one side
+//! is enough: [`resolve`] turns the names into the slugs of the recordings on both sides.  Each recording's page links
+//! to the others, and each related pair gets a page comparing the two, in [`COMPARE_DIR`].
+
+use crate::types::Recording;
+
+/// Where the comparison pages go in the output, one folder for each pair
+pub const COMPARE_DIR: &str = "compare";
+
+/// Whether `reference` (from a recording's `related`) names the recording with this slug and data_folder
+pub fn names(reference: &str, slug: &str, data_folder: &str) -> bool {
+    reference == data_folder || reference == slug
+}
+
+/// What's wrong with the references in the `related` of the recording in `data_folder`, given the `(slug,
+/// data_folder)` of every recording in the season
+pub fn problems(data_folder: &str, related: &[String], pages: &[(String, String)]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for reference in related {
+        match pages.iter().find(|(slug, folder)| names(reference, slug, folder)) {
+            None => problems.push(format!(
+                "{} is related to {:?}, which isn't a recording in the season",
+                data_folder, reference
+            )),
+            Some((_, folder)) if folder == data_folder => {
+                problems.push(format!("{} is related to itself", data_folder))
+            }
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+/// Replaces the references in each recording's `related` with the slugs of the recordings related to it (either
+/// way), in the order of the season, and returns a warning for each reference that isn't to another recording
+pub fn resolve(recordings: &mut [Recording]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut pairs = Vec::new();
+    for (i, rec) in recordings.iter().enumerate() {
+        for reference in &rec.related {
+            match recordings
+                .iter()
+                .position(|other| names(reference, &other.slug, &other.data_folder))
+            {
+                Some(j) if j != i => pairs.push((i, j)),
+                _ => warnings.push(format!(
+                    "{} is related to {:?}, which isn't another recording in the season, leaving it out",
+                    rec.title, reference
+                )),
+            }
+        }
+    }
+    let related: Vec<Vec<String>> = (0..recordings.len())
+        .map(|i| {
+            recordings
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| pairs.contains(&(i, *j)) || pairs.contains(&(*j, i)))
+                .map(|(_, other)| other.slug.clone())
+                .collect()
+        })
+        .collect();
+    for (rec, related) in recordings.iter_mut().zip(related) {
+        rec.related = related;
+    }
+    warnings
+}
+
+/// Each related pair of recordings once, in the order of the season
+pub fn pairs(recordings: &[Recording]) -> Vec<(&Recording, &Recording)> {
+    let mut pairs = Vec::new();
+    for (i, a) in recordings.iter().enumerate() {
+        for b in &recordings[i + 1..] {
+            if a.related.contains(&b.slug) {
+                pairs.push((a, b));
+            }
+        }
+    }
+    pairs
+}
+
+/// The folder of the page comparing the recordings with these slugs (in either order), relative to the root
+///
+/// Slugs never have two dashes in a row, so the names can't run into each other.
+pub fn comparison_path(a: &str, b: &str) -> String {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    format!("{}/{}--{}/", COMPARE_DIR, first, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recording(slug: &str, related: &[&str]) -> Recording {
+        let track = serde_json::json!({
+            "id": 1, "name": "Stereo mix", "flac": "mix.flac", "vorbis": null, "mp3": null, "patch_notes": null,
+            "media_info": {"@type": "Audio", "Format": "FLAC", "Channels": "2", "SamplingRate": "48000",
+                "BitDepth": "24", "Duration": "1.0"},
+            "flac_bytes": 0, "ogg_bytes": 0, "mp3_bytes": 0
+        });
+        serde_json::from_value(serde_json::json!({
+            "title": slug.to_uppercase(), "slug": slug, "data_folder": slug.replace("-", ""), "stereo_mix": track,
+            "recorded_date": "2021/01/01", "torrent": null, "tracks": [], "tags": [], "bpm": null,
+            "youtube_url": null, "related": related
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn symmetric() {
+        let mut recordings = vec![
+            recording("jam-1", &[]),
+            recording("jam-2", &["jam1", "jam-2", "jam9"]),
+            recording("jam-3", &["jam-1"]),
+        ];
+        let warnings = resolve(&mut recordings);
+        assert_eq!(
+            warnings,
+            [
+                r#"JAM-2 is related to "jam-2", which isn't another recording in the season, leaving it out"#,
+                r#"JAM-2 is related to "jam9", which isn't another recording in the season, leaving it out"#,
+            ]
+        );
+        assert_eq!(recordings[0].related, ["jam-2", "jam-3"]);
+        assert_eq!(recordings[1].related, ["jam-1"]);
+        assert_eq!(recordings[2].related, ["jam-1"]);
+
+        let pairs: Vec<_> = pairs(&recordings)
+            .into_iter()
+            .map(|(a, b)| (a.slug.as_str(), b.slug.as_str()))
+            .collect();
+        assert_eq!(pairs, [("jam-1", "jam-2"), ("jam-1", "jam-3")]);
+        assert_eq!(comparison_path("jam-3", "jam-1"), "compare/jam-1--jam-3/");
+        assert_eq!(comparison_path("jam-1", "jam-3"), "compare/jam-1--jam-3/");
+    }
+
+    #[test]
+    fn checked() {
+        let pages = [
+            ("jam-1".to_string(), "jam1".to_string()),
+            ("jam-2".to_string(), "jam2".to_string()),
+        ];
+        let related = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(problems("jam2", &related(&["jam1", "jam-1"]), &pages).is_empty());
+        assert_eq!(
+            problems("jam2", &related(&["jam-2", "jam3"]), &pages),
+            [
+                "jam2 is related to itself",
+                r#"jam2 is related to "jam3", which isn't a recording in the season"#
+            ]
+        );
+    }
+}
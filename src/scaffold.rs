@@ -0,0 +1,492 @@
+//! Helpers for creating new data files, so that nobody has to hand-write a recording JSON
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{assets, listing, types::SeasonInner};
+
+/// Matches the multitrack exports, which look like `Colin Benders - S02E02 - Jam 1 - 02-201016_1815.flac`
+pub const DEFAULT_TRACK_ID_REGEX: &str = r"(\d+)-\d{6}_\d{4}\.flac$";
+
+/// Everything needed to write a new recording JSON file
+pub struct NewRecordingOptions<'a> {
+    /// Path to season.json.  The new recording will be appended to its recordings array
+    pub season_json: &'a Path,
+    /// Folder containing the flac files for this recording
+    pub folder: &'a Path,
+    /// The global data directory.  If given, `folder` must be inside of it
+    pub data_dir: Option<&'a Path>,
+    /// Where to write the recording JSON.  If not given, it'll be placed next to the other recordings
+    pub json_path: Option<&'a Path>,
+    pub title: String,
+    pub recorded_date: String,
+    pub tags: Vec<String>,
+    /// Regex used to find the track id in a flac filename.  The first capture group is the id
+    pub track_id_regex: &'a str,
+    /// Track id of the stereo mix.  If not given, a flac with "stereo" in its name is used
+    pub stereo_mix_id: Option<u8>,
+}
+
+#[derive(Serialize)]
+struct NewRecording {
+    #[serde(rename = "$schema")]
+    schema: String,
+    title: String,
+    recorded_date: String,
+    data_folder: String,
+    stereo_mix: NewTrack,
+    tags: Vec<String>,
+    tracks: Vec<NewTrack>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    draft: bool,
+}
+
+#[derive(Serialize)]
+struct NewSeason {
+    #[serde(rename = "$schema")]
+    schema: String,
+    title: String,
+    artist: String,
+    recordings: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct NewTrack {
+    id: u8,
+    name: String,
+    flac: String,
+    vorbis: String,
+    mp3: String,
+}
+
+impl NewTrack {
+    fn new(id: u8, name: String, flac: String) -> NewTrack {
+        // a flac in a folder has its ogg and mp3 in the same folder under ogg/ and mp3/, so that two stems with the same
+        // name in different folders don't end up as the same ogg
+        let folder = flac[..flac.rfind('/').map_or(0, |i| i + 1)].to_string();
+        NewTrack {
+            id,
+            name,
+            flac,
+            vorbis: format!("ogg/{}{{FLACBASE}}.ogg", folder),
+            mp3: format!("mp3/{}{{FLACBASE}}.mp3", folder),
+        }
+    }
+}
+
+/// Scans a folder (and the folders in it) for flac files, and returns their paths in it keyed by their track id
+///
+/// Stems are sometimes sorted into folders, like `stems/drums/01 kick.flac`.  The track id is looked for in the file's
+/// own name, and the paths are separated by `/` whatever the platform, like the ones in recording JSONs.  The stereo mix
+/// is often named differently, so one with "stereo" in its name but no track id gets the lowest id no track has.
+pub fn scan_flacs(folder: &Path, track_id_regex: &str) -> anyhow::Result<BTreeMap<u8, String>> {
+    let re = Regex::new(track_id_regex).with_context(|| format!("Invalid track id regex {:?}", track_id_regex))?;
+
+    let mut flacs = BTreeMap::new();
+    let mut stereo = None;
+    scan_folder(folder, "", &re, &mut flacs, &mut stereo)?;
+    if let Some(flac) = stereo {
+        let id = (0..=u8::MAX)
+            .find(|id| !flacs.contains_key(id))
+            .with_context(|| format!("There is no track id left for {:?}", flac))?;
+        flacs.insert(id, flac);
+    }
+    if flacs.is_empty() {
+        bail!("No flac files found in {}", folder.display());
+    }
+
+    Ok(flacs)
+}
+
+/// Adds the flacs under `dir` to `flacs`, with `prefix` (the path of `dir` in the recording's folder) in front
+///
+/// A stereo mix without a track id goes in `stereo` instead, to get its id once all the tracks have theirs.
+fn scan_folder(
+    dir: &Path, prefix: &str, re: &Regex, flacs: &mut BTreeMap<u8, String>, stereo: &mut Option<String>,
+) -> anyhow::Result<()> {
+    let entries = listing::sorted(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if file_type.is_dir() {
+            scan_folder(&path, &format!("{}{}/", prefix, filename), re, flacs, stereo)?;
+            continue;
+        }
+        if !file_type.is_file() || path.extension() != Some(OsStr::new("flac")) {
+            continue;
+        }
+        let id = re
+            .captures(&filename)
+            .and_then(|c| c.get(1))
+            .and_then(|m| m.as_str().parse::<u8>().ok());
+        let flac = format!("{}{}", prefix, filename);
+        let id = match id {
+            Some(id) => id,
+            None if filename.to_lowercase().contains("stereo") => {
+                if let Some(other) = stereo.replace(flac.clone()) {
+                    bail!("Neither {:?} nor {:?} has a track id", other, flac);
+                }
+                continue;
+            }
+            None => bail!(
+                "Can't find a track id in {:?} using {:?}, try a different --track-id-regex",
+                filename,
+                re.as_str()
+            ),
+        };
+        if let Some(other) = flacs.insert(id, flac.clone()) {
+            bail!("Both {:?} and {:?} have track id {}", other, flac, id);
+        }
+    }
+    Ok(())
+}
+
+/// Returns a relative path that gets from the `from` directory to `to`
+///
+/// Both paths must be absolute (or both relative to the same directory)
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().filter(|c| *c != Component::CurDir).collect();
+    let to: Vec<_> = to.components().filter(|c| *c != Component::CurDir).collect();
+    let common = from.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+
+    let mut rel = PathBuf::new();
+    for _ in common..from.len() {
+        rel.push("..");
+    }
+    for c in &to[common..] {
+        rel.push(c.as_os_str());
+    }
+    rel
+}
+
+/// Like `relative_path`, but formatted the way we write paths in our JSON files (always starting with `./` or `../`,
+/// and with forward slashes)
+fn json_relative_path(from: &Path, to: &Path) -> String {
+    let rel = relative_path(from, to);
+    let rel: Vec<_> = rel.iter().map(|c| c.to_string_lossy()).collect();
+    let rel = rel.join("/");
+    if rel.starts_with("../") {
+        rel
+    } else {
+        format!("./{}", rel)
+    }
+}
+
+/// Writes a new recording JSON file and adds it to the season.json recordings list
+///
+/// Returns the path to the newly created recording JSON
+pub fn add_recording(opts: &NewRecordingOptions) -> anyhow::Result<PathBuf> {
+    let season_json = opts.season_json.canonicalize()?;
+    let season_root = season_json.parent().unwrap();
+    let season: SeasonInner = serde_json::from_value(crate::get_validated_json(&season_json)?)?;
+
+    let folder = opts.folder.canonicalize()?;
+    let data_folder = if let Some(data_dir) = opts.data_dir {
+        let data_dir = data_dir.canonicalize()?;
+        let rel = folder.strip_prefix(&data_dir).with_context(|| {
+            format!(
+                "Recording folder {} is not inside the data dir {}",
+                folder.display(),
+                data_dir.display()
+            )
+        })?;
+        rel.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join("/")
+    } else {
+        folder.file_name().unwrap().to_string_lossy().to_string()
+    };
+
+    let mut flacs = scan_flacs(&folder, opts.track_id_regex)?;
+    let stereo_id = match opts.stereo_mix_id {
+        Some(id) => id,
+        None => match flacs.iter().find(|(_, f)| f.to_lowercase().contains("stereo")) {
+            Some((id, _)) => *id,
+            None => bail!("Can't figure out which flac is the stereo mix, use --stereo-mix-id"),
+        },
+    };
+    let stereo_flac = match flacs.remove(&stereo_id) {
+        Some(f) => f,
+        None => bail!("There is no flac with track id {} to use as the stereo mix", stereo_id),
+    };
+
+    // By default, put new recordings next to the most recently added one
+    let json_path = match opts.json_path {
+        Some(p) => p.to_owned(),
+        None => {
+            let dir = season
+                .recording_paths(&season_json)?
+                .last()
+                .and_then(|r| r.path.parent().map(Path::to_owned))
+                .unwrap_or_else(|| season_root.to_owned());
+            dir.join(format!("{}.json", data_folder.replace('/', "_")))
+        }
+    };
+    if json_path.exists() {
+        bail!("{} already exists", json_path.display());
+    }
+    let json_dir = match json_path.parent() {
+        Some(p) if p != Path::new("") => p.canonicalize()?,
+        _ => std::env::current_dir()?,
+    };
+    let json_path = json_dir.join(json_path.file_name().unwrap());
+
+    // the recording schema lives next to the season schema
+    let schema_path = if season.schema.starts_with("./") || season.schema.starts_with("../") {
+        season_root.join(&season.schema).with_file_name("recording.json")
+    } else {
+        season_root.join("schema").join("recording.json")
+    };
+    let schema_path = schema_path.canonicalize().unwrap_or(schema_path);
+
+    let recording = NewRecording {
+        schema: json_relative_path(&json_dir, &schema_path),
+        title: opts.title.clone(),
+        recorded_date: opts.recorded_date.clone(),
+        data_folder,
+        stereo_mix: NewTrack::new(stereo_id, "Stereo mix".to_string(), stereo_flac),
+        tags: opts.tags.clone(),
+        tracks: flacs
+            .into_iter()
+            .map(|(id, flac)| NewTrack::new(id, format!("Track {}", id), flac))
+            .collect(),
+        draft: false,
+    };
+
+    crate::write_json_file(&json_path, &recording)?;
+    if let Err(e) = crate::get_validated_json(&json_path) {
+        std::fs::remove_file(&json_path)?;
+        return Err(anyhow::Error::new(e).context("Generated recording JSON doesn't pass schema validation"));
+    }
+
+    let rel = relative_path(season_root, &json_path);
+    let rel: Vec<_> = rel.iter().map(|c| c.to_string_lossy()).collect();
+    append_season_recording(&season_json, &rel.join("/"))?;
+
+    Ok(json_path)
+}
+
+/// Adds a new entry to the end of the recordings array in season.json
+///
+/// The file is written back in the style of `cb_processor fmt` (see [`crate::json_format`]), and replaced only once
+/// the new one is complete.
+fn append_season_recording(season_json: &Path, rec_path: &str) -> anyhow::Result<()> {
+    let text = std::fs::read(season_json).with_context(|| format!("Failed to read {}", season_json.display()))?;
+    let mut value: serde_json::Value =
+        serde_json::from_slice(&text).with_context(|| format!("Failed to parse {}", season_json.display()))?;
+    value
+        .get_mut("recordings")
+        .and_then(serde_json::Value::as_array_mut)
+        .context("No recordings array in season.json")?
+        .push(rec_path.into());
+    crate::write_atomically(season_json, crate::json_format::season(&value).as_bytes())
+        .with_context(|| format!("Failed to write {}", season_json.display()))?;
+
+    Ok(())
+}
+
+/// Creates the skeleton of a new season in `dir`
+///
+/// The example recording is marked as a draft (it has no audio files), so the new season passes validation straight
+/// away.  Returns the list of files that were created.
+pub fn init(dir: &Path, title: &str, artist: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let season = NewSeason {
+        schema: "./schema/season.json".to_string(),
+        title: title.to_string(),
+        artist: artist.to_string(),
+        recordings: vec!["recordings/example.json".to_string()],
+    };
+    let example = NewRecording {
+        schema: "../schema/recording.json".to_string(),
+        title: "Example recording".to_string(),
+        recorded_date: "unknown".to_string(),
+        data_folder: "example".to_string(),
+        stereo_mix: NewTrack::new(1, "Stereo mix".to_string(), "example_stereo.flac".to_string()),
+        tags: vec!["example".to_string()],
+        tracks: vec![NewTrack::new(2, "Kick".to_string(), "example_kick.flac".to_string())],
+        draft: true,
+    };
+
+    let mut files: Vec<(PathBuf, Vec<u8>)> = vec![
+        (PathBuf::from("cb_processor.toml"), assets::DEFAULT_CONFIG.into()),
+        (
+            PathBuf::from("data/season.json"),
+            crate::json_format::season(&serde_json::to_value(&season)?).into(),
+        ),
+        (PathBuf::from("data/schema/season.json"), assets::SEASON_SCHEMA.into()),
+        (
+            PathBuf::from("data/schema/recording.json"),
+            assets::RECORDING_SCHEMA.into(),
+        ),
+        (
+            PathBuf::from("data/recordings/example.json"),
+            crate::to_json_bytes(&example)?,
+        ),
+    ];
+    for (name, contents) in assets::STATIC_FILES {
+        files.push((Path::new("static").join(name), contents.to_vec()));
+    }
+
+    // check everything first, so that we don't leave a half-created season behind
+    for (name, _) in &files {
+        let path = dir.join(name);
+        if path.exists() {
+            bail!("{} already exists, refusing to overwrite it", path.display());
+        }
+    }
+
+    let mut created = Vec::new();
+    for (name, contents) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+        created.push(path);
+    }
+    // the audio files for each recording go in here
+    std::fs::create_dir_all(dir.join("audio"))?;
+
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative() {
+        assert_eq!(
+            json_relative_path(Path::new("/data/S02"), Path::new("/data/schema/recording.json")),
+            "../schema/recording.json"
+        );
+        assert_eq!(
+            json_relative_path(Path::new("/data"), Path::new("/data/schema/recording.json")),
+            "./schema/recording.json"
+        );
+    }
+
+    #[test]
+    fn track_ids() {
+        let re = Regex::new(DEFAULT_TRACK_ID_REGEX).unwrap();
+        let caps = re
+            .captures("Colin Benders - S02E02 - Jam 1 - 02-201016_1815.flac")
+            .unwrap();
+        assert_eq!(&caps[1], "02");
+        let caps = re
+            .captures("Colin Benders - Day 38 Jam 3 10-16-200424_1425.flac")
+            .unwrap();
+        assert_eq!(&caps[1], "16");
+    }
+
+    #[test]
+    fn nested_flacs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "jam_stereo.flac",
+            "stems/drums/02 kick.flac",
+            "stems/synths/03 pad.flac",
+            "ogg/x.ogg",
+        ] {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        let flacs = scan_flacs(dir.path(), r"^(\d+) ").unwrap();
+        assert_eq!(
+            flacs.values().collect::<Vec<_>>(),
+            [
+                "jam_stereo.flac",
+                "stems/drums/02 kick.flac",
+                "stems/synths/03 pad.flac"
+            ]
+        );
+        let track = NewTrack::new(2, "Kick".to_string(), flacs[&2].clone());
+        assert_eq!(track.vorbis, "ogg/stems/drums/{FLACBASE}.ogg");
+        assert_eq!(
+            NewTrack::new(0, "Stereo".to_string(), flacs[&0].clone()).mp3,
+            "mp3/{FLACBASE}.mp3"
+        );
+
+        // a track numbered 0 keeps its id, and the stereo mix gets the next free one
+        std::fs::write(dir.path().join("stems/00 click.flac"), "").unwrap();
+        std::fs::write(dir.path().join("stems/01 bass.flac"), "").unwrap();
+        let flacs = scan_flacs(dir.path(), r"^(\d+) ").unwrap();
+        assert_eq!(flacs[&0], "stems/00 click.flac");
+        assert_eq!(flacs[&4], "jam_stereo.flac");
+
+        std::fs::write(dir.path().join("other_stereo.flac"), "").unwrap();
+        assert!(scan_flacs(dir.path(), r"^(\d+) ").is_err());
+    }
+
+    #[test]
+    fn init_validates() {
+        let dir = tempfile::tempdir().unwrap();
+        init(dir.path(), "Test season", "Tester").unwrap();
+        let errors = crate::validate_and_print(
+            &crate::context::RunContext::default(),
+            &dir.path().join("data/season.json"),
+            &dir.path().join("audio"),
+        )
+        .unwrap();
+        assert_eq!(errors, 0);
+
+        // running it a second time must not clobber anything
+        assert!(init(dir.path(), "Test season", "Tester").is_err());
+    }
+
+    #[test]
+    fn append_keeps_the_style() {
+        let dir = tempfile::tempdir().unwrap();
+        init(dir.path(), "Test season", "Tester").unwrap();
+        let season_json = dir.path().join("data/season.json");
+        let before = std::fs::read_to_string(&season_json).unwrap();
+        append_season_recording(&season_json, "recordings/jam1.json").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&season_json).unwrap(),
+            before.replace(
+                "\"recordings/example.json\"\n",
+                "\"recordings/example.json\",\n    \"recordings/jam1.json\"\n"
+            )
+        );
+
+        std::fs::write(&season_json, "{\n  \"title\": \"Test\",\n  \"recordings\": []\n}\n").unwrap();
+        append_season_recording(&season_json, "recordings/jam1.json").unwrap();
+        append_season_recording(&season_json, "recordings/jam2.json").unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&std::fs::read(&season_json).unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&season_json).unwrap(),
+            crate::json_format::season(&value)
+        );
+    }
+
+    #[test]
+    fn append_after_brackets_and_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let season_json = dir.path().join("season.json");
+        std::fs::write(
+            &season_json,
+            r#"{"title": "Jams [2021]", "recordings": ["a.json", {"include": "more.json"}], "mirrors": []}"#,
+        )
+        .unwrap();
+        append_season_recording(&season_json, "b.json").unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&std::fs::read(&season_json).unwrap()).unwrap();
+        assert_eq!(value["title"], "Jams [2021]");
+        assert_eq!(
+            value["recordings"],
+            serde_json::json!(["a.json", {"include": "more.json"}, "b.json"])
+        );
+        assert_eq!(value["mirrors"], serde_json::json!([]));
+
+        // nothing is left behind next to it
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["season.json"]);
+    }
+}
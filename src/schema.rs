@@ -0,0 +1,306 @@
+//! The JSON schemas that data files name in their `$schema`
+//!
+//! Every recording names the same schema, so each schema file is read and compiled once per run (and again only if it
+//! changes on disk).  A `$ref` to another local file (like `./common.defs.json#/definitions/track`) is resolved relative
+//! to the schema that has it, and that file is compiled along with the schema, so that splitting a schema into shared
+//! definitions still checks everything.  A schema that can't be used is reported as a [`CbError::InvalidSchema`], naming the schema file
+//! and where in it the problem is, instead of as a failure of whichever data file happened to be checked first.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde_json::Value;
+use valico::json_schema::{SchemaError, Scope};
+
+use crate::{error::CbError, media_cache::Fingerprint};
+
+/// Why a schema can't be used, kept so that every file that names it gets the same error
+#[derive(Debug, Clone)]
+struct Problem {
+    /// The file with the problem, which is the schema itself or one that it refers to
+    file: PathBuf,
+    pointer: String,
+    details: String,
+}
+
+impl Problem {
+    fn in_file(file: &Path, details: impl ToString) -> Problem {
+        Problem {
+            file: file.to_path_buf(),
+            pointer: String::new(),
+            details: details.to_string(),
+        }
+    }
+}
+
+struct Compiled {
+    /// The schema file and every local file it refers to, with how each was when it was compiled
+    files: Vec<(PathBuf, Option<Fingerprint>)>,
+    /// Only has this schema and the files it refers to, so that recompiling one starts from nothing
+    scope: Scope,
+    schema: Result<url::Url, Problem>,
+}
+
+impl Compiled {
+    fn up_to_date(&self) -> bool {
+        self.files
+            .iter()
+            .all(|(path, fingerprint)| fingerprint.is_some() && Fingerprint::of(path) == *fingerprint)
+    }
+}
+
+/// Shared by every thread, so the schemas are compiled once per run rather than once per thread.  By canonical path
+static SCHEMAS: Mutex<Option<HashMap<PathBuf, Compiled>>> = Mutex::new(None);
+
+/// Checks `json` (read from `json_path`) against the schema in `schema_path`
+pub(crate) fn validate(json_path: &Path, json: &Value, schema_path: &Path) -> Result<(), CbError> {
+    let canonical = schema_path.canonicalize().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => CbError::MissingFile {
+            path: schema_path.to_path_buf(),
+        },
+        _ => CbError::io(format!("Failed to open {}", schema_path.display()), e),
+    })?;
+
+    let mut schemas = SCHEMAS.lock().unwrap_or_else(|e| e.into_inner());
+    let compiled = schemas.get_or_insert_with(HashMap::new);
+    if !compiled.get(&canonical).is_some_and(Compiled::up_to_date) {
+        compiled.insert(canonical.clone(), compile(&canonical));
+    }
+    let compiled = &compiled[&canonical];
+
+    let invalid = |problem: &Problem| CbError::InvalidSchema {
+        // the schema by the name it was given, and the files it refers to by theirs
+        schema: if problem.file == canonical {
+            schema_path.to_path_buf()
+        } else {
+            problem.file.clone()
+        },
+        pointer: problem.pointer.clone(),
+        details: problem.details.clone(),
+    };
+    let id = compiled.schema.as_ref().map_err(invalid)?;
+    let res = compiled
+        .scope
+        .resolve(id)
+        .expect("compiled schemas stay in the scope")
+        .validate(json);
+    if let Some(missing) = res.missing.first() {
+        return Err(invalid(&Problem::in_file(
+            &canonical,
+            format!("$ref to {} can't be found", missing),
+        )));
+    }
+    if res.is_valid() {
+        Ok(())
+    } else {
+        Err(CbError::SchemaValidation {
+            path: json_path.to_path_buf(),
+            details: format!("{:?}", res),
+        })
+    }
+}
+
+/// Reads and compiles a schema file and the local files it refers to, which checks that they're schemas at all
+fn compile(path: &Path) -> Compiled {
+    let mut scope = Scope::new();
+    let mut files = Vec::new();
+    let schema = url::Url::from_file_path(path)
+        .map_err(|()| Problem::in_file(path, "not an absolute path"))
+        .and_then(|id| compile_file(&mut scope, &mut files, &id, path).map(|()| id));
+    Compiled { files, scope, schema }
+}
+
+/// Compiles the file at `path` as `id`, and then each file it refers to that isn't in `files` yet (so that schemas
+/// that refer to each other are only compiled once)
+fn compile_file(
+    scope: &mut Scope, files: &mut Vec<(PathBuf, Option<Fingerprint>)>, id: &url::Url, path: &Path,
+) -> Result<(), Problem> {
+    files.push((path.to_path_buf(), Fingerprint::of(path)));
+    let text = std::fs::read_to_string(path).map_err(|e| Problem::in_file(path, e))?;
+    let def: Value = serde_json::from_str(&text).map_err(|e| Problem::in_file(path, e))?;
+    let mut refs = Vec::new();
+    local_refs(id, &def, String::new(), &mut refs);
+
+    scope.compile_with_id(id, def, false).map_err(|e| match e {
+        SchemaError::Malformed { path: pointer, detail } => Problem {
+            file: path.to_path_buf(),
+            pointer: if pointer.is_empty() {
+                pointer
+            } else {
+                format!("/{}", pointer)
+            },
+            details: detail,
+        },
+        SchemaError::UnknownKey(key) => Problem {
+            file: path.to_path_buf(),
+            pointer: format!("/{}", key),
+            details: "unknown keyword".to_string(),
+        },
+        e => Problem::in_file(path, e),
+    })?;
+
+    for (pointer, target) in refs {
+        let target_path = match target.to_file_path() {
+            Ok(target_path) => target_path,
+            Err(()) => continue,
+        };
+        // by the URL it's referred to by, which is what the scope looks it up by
+        let known = files
+            .iter()
+            .any(|(known, _)| url::Url::from_file_path(known).is_ok_and(|known| known == target));
+        if known {
+            continue;
+        }
+        if !target_path.is_file() {
+            return Err(Problem {
+                file: path.to_path_buf(),
+                pointer,
+                details: format!("$ref to {}, which doesn't exist", target_path.display()),
+            });
+        }
+        compile_file(scope, files, &target, &target_path)?;
+    }
+    Ok(())
+}
+
+/// Every `$ref` in `def` to a local file (without the fragment), along with where in `def` it is
+fn local_refs(base: &url::Url, def: &Value, pointer: String, refs: &mut Vec<(String, url::Url)>) {
+    match def {
+        Value::Object(map) => {
+            // a subschema can have an id of its own, which its refs are relative to
+            let rebased = map.get("$id").and_then(Value::as_str).and_then(|id| base.join(id).ok());
+            let base = rebased.as_ref().unwrap_or(base);
+            if let Some(target) = map.get("$ref").and_then(Value::as_str).and_then(|r| base.join(r).ok()) {
+                let mut target = target;
+                target.set_fragment(None);
+                if target.scheme() == "file" && target != *base && !refs.iter().any(|(_, r)| *r == target) {
+                    refs.push((format!("{}/$ref", pointer), target));
+                }
+            }
+            for (key, value) in map {
+                local_refs(base, value, format!("{}/{}", pointer, key), refs);
+            }
+        }
+        Value::Array(items) => {
+            for (i, value) in items.iter().enumerate() {
+                local_refs(base, value, format!("{}/{}", pointer, i), refs);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema = dir.path().join("schema.json");
+        let json = dir.path().join("data.json");
+        let check = |value: Value| validate(&json, &value, &schema);
+
+        std::fs::write(
+            &schema,
+            r#"{"type": "object", "properties": {"title": {"type": "strnig"}}}"#,
+        )
+        .unwrap();
+        match check(serde_json::json!({"title": "Jam"})) {
+            Err(CbError::InvalidSchema { pointer, details, .. }) => {
+                assert_eq!(pointer, "/properties/title");
+                assert!(details.contains("strnig"), "{}", details);
+            }
+            other => panic!("expected an invalid schema, got {:?}", other),
+        }
+
+        // fixing the schema is noticed
+        std::fs::write(
+            &schema,
+            r#"{"type": "object", "properties": {"title": {"type": "string"}}}"#,
+        )
+        .unwrap();
+        assert!(check(serde_json::json!({"title": "Jam"})).is_ok());
+        assert!(matches!(
+            check(serde_json::json!({"title": 1})),
+            Err(CbError::SchemaValidation { .. })
+        ));
+
+        std::fs::write(&schema, r#"{"type": "object",, }"#).unwrap();
+        match check(serde_json::json!({})) {
+            Err(e @ CbError::InvalidSchema { .. }) => assert!(e.to_string().contains("line 1"), "{}", e),
+            other => panic!("expected an invalid schema, got {:?}", other),
+        }
+        assert!(matches!(
+            validate(&json, &Value::Null, &dir.path().join("missing.json")),
+            Err(CbError::MissingFile { .. })
+        ));
+    }
+
+    #[test]
+    fn refs_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let schema = dir.path().join("recording.json");
+        let defs = dir.path().join("defs/common.defs.json");
+        let json = dir.path().join("data.json");
+        let check = |value: Value| validate(&json, &value, &schema);
+        std::fs::create_dir_all(defs.parent().unwrap()).unwrap();
+        std::fs::write(
+            &schema,
+            r##"{"type": "object", "properties": {"stereo_mix": {"$ref": "./defs/common.defs.json#/definitions/track"}}}"##,
+        )
+        .unwrap();
+        // which refers back to the first file, and to itself
+        std::fs::write(
+            &defs,
+            r##"{"definitions": {
+                "track": {"type": "object", "required": ["flac"], "properties": {
+                    "flac": {"$ref": "#/definitions/flac"},
+                    "recording": {"$ref": "../recording.json"}
+                }},
+                "flac": {"type": "string"}
+            }}"##,
+        )
+        .unwrap();
+
+        assert!(check(serde_json::json!({"stereo_mix": {"flac": "a.flac"}})).is_ok());
+        for invalid in [
+            serde_json::json!({"stereo_mix": {}}),
+            serde_json::json!({"stereo_mix": {"flac": 1}}),
+            serde_json::json!({"stereo_mix": {"flac": "a.flac", "recording": {"stereo_mix": 2}}}),
+        ] {
+            assert!(
+                matches!(check(invalid.clone()), Err(CbError::SchemaValidation { .. })),
+                "{}",
+                invalid
+            );
+        }
+
+        // changing only the shared definitions is noticed
+        std::fs::write(
+            &defs,
+            r#"{"definitions": {"track": {"type": "object", "required": ["flac", "name"]}}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            check(serde_json::json!({"stereo_mix": {"flac": "a.flac"}})),
+            Err(CbError::SchemaValidation { .. })
+        ));
+
+        std::fs::write(
+            &schema,
+            r#"{"type": "object", "properties": {"tracks": {"$ref": "./missing.json"}}}"#,
+        )
+        .unwrap();
+        match check(serde_json::json!({})) {
+            Err(CbError::InvalidSchema { pointer, details, .. }) => {
+                assert_eq!(pointer, "/properties/tracks/$ref");
+                assert!(details.contains("missing.json"), "{}", details);
+            }
+            other => panic!("expected an invalid schema, got {:?}", other),
+        }
+    }
+}
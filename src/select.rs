@@ -0,0 +1,97 @@
+//! Selecting a subset of recordings with `--only`
+
+use regex::Regex;
+
+use crate::{
+    error::CbError,
+    types::{Recording, Season},
+};
+
+/// A set of glob patterns matched against a recording's data_folder or title
+///
+/// An empty selector matches every recording.  Patterns support `*` (any run of characters) and `?` (any single
+/// character), and must match the whole data_folder or title.
+#[derive(Debug, Clone, Default)]
+pub struct Selector {
+    patterns: Vec<(String, Regex)>,
+}
+
+impl Selector {
+    pub fn new<I, S>(patterns: I) -> anyhow::Result<Selector>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut compiled = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let mut re = String::from("^");
+            for c in pattern.chars() {
+                match c {
+                    '*' => re.push_str(".*"),
+                    '?' => re.push('.'),
+                    c => re.push_str(&regex::escape(&c.to_string())),
+                }
+            }
+            re.push('$');
+            compiled.push((pattern.to_string(), Regex::new(&re)?));
+        }
+        Ok(Selector { patterns: compiled })
+    }
+
+    /// True if this selector doesn't filter anything out
+    pub fn is_all(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Checks a single name (like a folder in the output directory) against the patterns
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.is_all() || self.patterns.iter().any(|(_, re)| re.is_match(name))
+    }
+
+    pub fn matches(&self, recording: &Recording) -> bool {
+        self.matches_name(&recording.data_folder) || self.matches_name(&recording.title)
+    }
+
+    /// Returns the selected recordings, or an error if nothing was selected
+    pub fn select<'a>(&self, season: &'a Season) -> Result<Vec<&'a Recording>, CbError> {
+        let selected: Vec<_> = season.recordings.iter().filter(|r| self.matches(r)).collect();
+        if selected.is_empty() && !self.is_all() {
+            return Err(CbError::NothingSelected {
+                patterns: self.describe(),
+                what: "recordings".to_string(),
+            });
+        }
+        Ok(selected)
+    }
+
+    /// The patterns, for use in messages
+    pub fn describe(&self) -> String {
+        let patterns: Vec<_> = self.patterns.iter().map(|(p, _)| format!("{:?}", p)).collect();
+        patterns.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        let sel = Selector::new(["S02E0?", "*Jam 2"]).unwrap();
+        assert!(sel.matches_name("S02E05"));
+        assert!(!sel.matches_name("S02E10"));
+        assert!(sel.matches_name("S01E01 - Jam 2"));
+        assert!(!sel.matches_name("S01E01 - Jam 2 (part 2)"));
+        assert!(!sel.is_all());
+
+        // regex characters in a pattern are matched literally
+        let sel = Selector::new(["S01E01 (live)"]).unwrap();
+        assert!(sel.matches_name("S01E01 (live)"));
+        assert!(!sel.matches_name("S01E01 live"));
+
+        let all = Selector::new(Vec::<String>::new()).unwrap();
+        assert!(all.is_all());
+        assert!(all.matches_name("anything"));
+    }
+}
@@ -0,0 +1,235 @@
+//! Links for showing a single recording to collaborators before it's published (`cb_processor share`)
+//!
+//! The recording's page and files are generated into a folder of their own, drafts included, which is added to IPFS
+//! as a root of its own: it isn't linked into the season's root, so nothing else on the site leads to it.  The share is
+//! pinned until it expires, and listed in the shares file next to the roots history (see [`shares_path`]), which
+//! `share --expire` goes through to unpin the shares that are past their expiry.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::RunContext,
+    error::CbError,
+    gateway, ipfs,
+    select::Selector,
+    state_store,
+    types::{Recording, Season, Track},
+};
+
+/// How long a share is pinned for, unless `--expires-in` says otherwise
+pub const DEFAULT_EXPIRY_DAYS: u64 = 14;
+
+/// The longest `--expires-in` there can be, a hundred years
+pub const MAX_EXPIRY_DAYS: u64 = 36_525;
+
+/// A recording that was shared
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub root: String,
+    pub data_folder: String,
+    /// Where the recording's page is in the root
+    pub slug: String,
+    /// Seconds since the Unix epoch
+    pub shared: u64,
+    /// Seconds since the Unix epoch
+    pub expires: u64,
+}
+
+/// The contents of the shares file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Shares {
+    /// Oldest first
+    pub shares: Vec<Share>,
+}
+
+impl Shares {
+    pub fn load(path: &Path) -> Result<Shares, CbError> {
+        Ok(state_store::read_doc(path)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CbError> {
+        state_store::write_doc(path, self)
+    }
+
+    /// The shares that have expired by `now` (in seconds since the Unix epoch)
+    pub fn expired(&self, now: u64) -> Vec<&Share> {
+        self.shares.iter().filter(|share| share.expires <= now).collect()
+    }
+}
+
+/// The shares file that goes with the roots history at `roots_history`: `roots_history.shares.json` for
+/// `roots_history.jsonl`
+pub fn shares_path(roots_history: &Path) -> PathBuf {
+    let stem = roots_history.file_stem().unwrap_or_default().to_string_lossy();
+    roots_history.with_file_name(format!("{}.shares.json", stem))
+}
+
+/// Generates the page of the recording in `data_folder` into `dir`, with the files it links to, returning the
+/// recording
+///
+/// The recording is loaded from the data dir even if it's a draft, and the page is generated as if it was the only
+/// recording in the season.  The audio files are hard links to the ones in the data dir where possible.
+pub fn prepare(
+    ctx: &RunContext, season_json: &Path, data_dir: &Path, data_folder: &str, dir: &Path,
+) -> Result<Recording, anyhow::Error> {
+    let mut ctx = ctx.clone();
+    ctx.only = Selector::new([data_folder])?;
+    ctx.include_drafts = true;
+    let season = Season::load(&ctx, season_json, Some(data_dir), None)?;
+    let recording = season
+        .recordings
+        .iter()
+        .find(|r| r.data_folder == data_folder)
+        .ok_or_else(|| {
+            anyhow!(
+                "No recording in {} has the data folder {:?}",
+                season_json.display(),
+                data_folder
+            )
+        })?;
+    let alone = Season {
+        generator: season.generator.clone(),
+        title: season.title.clone(),
+        artist: season.artist.clone(),
+        recordings: vec![recording.clone()],
+        redirects: Default::default(),
+        low_quality_ogg: season.low_quality_ogg.clone(),
+        skipped: Vec::new(),
+    };
+    crate::write_all_recording_index(&ctx, &alone, dir)?;
+
+    let folder = dir.join(&recording.data_folder);
+    for track in std::iter::once(&recording.stereo_mix).chain(&recording.tracks) {
+        for file in linked_files(track) {
+            // a link out of the data folder isn't in the share, like the page leaves out links that go elsewhere
+            if let Ok(relative) = file.strip_prefix(data_dir.join(&recording.data_folder)) {
+                link_or_copy(&file, &folder.join(relative))?;
+            }
+        }
+    }
+    Ok(recording.clone())
+}
+
+/// The files of `track` that exist, and that its part of the page links to
+fn linked_files(track: &Track) -> Vec<PathBuf> {
+    let files = [
+        track.flac_ondisk().filter(|_| track.downloadable()),
+        track.ogg_ondisk(),
+        track.lq_ogg_ondisk(),
+        track.mp3_ondisk(),
+        track.opus_ondisk(),
+        track.spectrogram_ondisk(),
+        track.peaks_ondisk(),
+    ];
+    IntoIterator::into_iter(files)
+        .flatten()
+        .filter(|file| file.exists())
+        .collect()
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to).with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+    }
+    Ok(())
+}
+
+/// Adds the folder that [`prepare`] made to IPFS and pins it, and records it in the shares file
+pub fn publish(
+    ctx: &RunContext, dir: &Path, recording: &Recording, expires_in: Duration, now: SystemTime,
+) -> Result<Share, CbError> {
+    let root = ipfs::add_folder(ctx, dir)?;
+    ipfs::pin(ctx, &root)?;
+    let shared = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let share = Share {
+        root: root.to_string(),
+        data_folder: recording.data_folder.clone(),
+        slug: recording.slug.clone(),
+        shared,
+        expires: shared + expires_in.as_secs(),
+    };
+    let path = shares_path(&ctx.roots_history);
+    let mut shares = Shares::load(&path)?;
+    shares.shares.push(share.clone());
+    shares.save(&path)?;
+    Ok(share)
+}
+
+/// The URLs of the shared page on each gateway
+pub fn urls(ctx: &RunContext, share: &Share) -> Result<Vec<String>, CbError> {
+    let root = share
+        .root
+        .parse::<cid::Cid>()
+        .map_err(|e| CbError::parse(format!("Invalid root {:?} in the shares file", share.root), e))?;
+    gateway::gateways(&ctx.gateways)
+        .iter()
+        .map(|gw| Ok(gateway::link_url(&gw.root_url(&root)?, &share.slug)?.to_string() + "/"))
+        .collect()
+}
+
+/// Unpins the shares that have expired by `now`, and takes them out of the shares file, returning them
+///
+/// A share that can't be unpinned stays in the file, so that the next run tries again.
+pub fn expire(ctx: &RunContext, now: SystemTime) -> Result<Vec<Share>, CbError> {
+    let path = shares_path(&ctx.roots_history);
+    let mut shares = Shares::load(&path)?;
+    let now = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut unpinned = Vec::new();
+    for share in shares.expired(now) {
+        let root = share
+            .root
+            .parse::<cid::Cid>()
+            .map_err(|e| CbError::parse(format!("Invalid root {:?} in {}", share.root, path.display()), e))?;
+        match ipfs::unpin(ctx, &root) {
+            Ok(()) => unpinned.push(share.clone()),
+            Err(e) => say!(
+                "Warning: {} (shared {}) is still pinned: {}",
+                share.root,
+                share.data_folder,
+                e
+            ),
+        }
+    }
+    shares.shares.retain(|share| !unpinned.contains(share));
+    shares.save(&path)?;
+    Ok(unpinned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            shares_path(Path::new("state/roots_history.jsonl")),
+            Path::new("state/roots_history.shares.json")
+        );
+        assert_eq!(shares_path(Path::new("history")), Path::new("history.shares.json"));
+    }
+
+    #[test]
+    fn expired() {
+        let share = |root: &str, expires| Share {
+            root: root.to_string(),
+            data_folder: "jam1".to_string(),
+            slug: "s01e01-jam-1".to_string(),
+            shared: 100,
+            expires,
+        };
+        let shares = Shares {
+            shares: vec![share("a", 200), share("b", 300), share("c", 250)],
+        };
+        assert!(shares.expired(199).is_empty());
+        assert_eq!(shares.expired(250), [&shares.shares[0], &shares.shares[2]]);
+    }
+}
@@ -0,0 +1,730 @@
+//! The HTML pages and playlist of the published site
+
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::BTreeSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use askama::Template;
+#[cfg(feature = "cli")]
+use colored::Colorize;
+
+#[cfg(not(feature = "cli"))]
+use crate::plain::Colorize;
+use crate::{
+    asset_map::AssetMap,
+    badges, checksum,
+    completeness::{self, Completeness},
+    context::RunContext,
+    diff::OutputDiff,
+    hasher::HasherPool,
+    listing,
+    manifest::BuildManifest,
+    markdown,
+    precompress::Encoding,
+    progress::{self, ProgressEvent, Stage},
+    related, slug, source, stats,
+    tag::Tag,
+    types::{self, Recording, Season, SkippedRecording},
+    youtube, GENERATOR,
+};
+
+mod filters {
+    /// `{{ track.flac|url_path|safe }}`: a file name as it has to appear in a link
+    pub fn url_path<T: std::fmt::Display>(path: T) -> askama::Result<String> {
+        Ok(crate::slug::encode_path(&path.to_string()))
+    }
+
+    /// `{{ recording.recorded_date|time(date_format)|safe }}`: a `<time>` element with the date in the config's format
+    pub fn time<T: std::fmt::Display>(date: T, format: &str) -> askama::Result<String> {
+        Ok(crate::dates::time_element(&date.to_string(), format))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "season_index.html")]
+pub struct SeasonIndexTemplate<'a, I: Iterator<Item = RecordingSummary>> {
+    gitlab_review: String,
+    generator: &'static str,
+    title: &'a str,
+    recordings: Rows<I>,
+    skipped: &'a [SkippedRecording],
+    tag_list: Vec<Tag>,
+    completeness: completeness::Summary,
+    /// See [`RunContext::date_format`]
+    date_format: &'a str,
+    /// The names to link to the static files by
+    assets: &'a AssetMap,
+}
+
+/// What the season index shows of a recording: its row, without its tracks or anything else on its own page
+///
+/// The index is written from these one at a time (see [`write_season_index_of`]), so that a huge season doesn't have
+/// to be in memory to write it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingSummary {
+    pub title: String,
+    pub slug: String,
+    pub anchor: String,
+    pub data_folder: String,
+    /// What the preview player plays, if the stereo mix has an ogg
+    pub stream: Option<String>,
+    pub recorded_date: String,
+    pub bpm: Option<String>,
+    pub tracks: usize,
+    pub duration: String,
+    pub format_info: String,
+    pub tags: Vec<Tag>,
+    pub completeness: Completeness,
+}
+
+impl RecordingSummary {
+    pub fn of(recording: &Recording) -> RecordingSummary {
+        let stream = match recording.stereo_mix.vorbis {
+            Some(_) => recording.stereo_mix.stream().cloned(),
+            None => None,
+        };
+        RecordingSummary {
+            title: recording.title.clone(),
+            slug: recording.slug.clone(),
+            anchor: recording.anchor(),
+            data_folder: recording.data_folder.clone(),
+            stream,
+            recorded_date: recording.recorded_date.clone(),
+            bpm: recording.bpm.clone(),
+            tracks: recording.tracks.len(),
+            duration: recording.duration(),
+            format_info: recording.format_info(),
+            tags: recording.tags.clone(),
+            completeness: recording.completeness.clone(),
+        }
+    }
+}
+
+/// The rows of the season index, which its template can only go through once
+struct Rows<I>(Cell<Option<I>>);
+
+impl<I: Iterator<Item = RecordingSummary>> IntoIterator for &Rows<I> {
+    type Item = RecordingSummary;
+    type IntoIter = std::iter::Flatten<std::option::IntoIter<I>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.take().into_iter().flatten()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "recording_index.html")]
+pub struct RecordingIndexTemplate<'a> {
+    gitlab_review: String,
+    generator: &'static str,
+    #[allow(dead_code)]
+    season: &'a Season,
+    recording: &'a Recording,
+    /// The video to embed, if the recording has a `youtube_url`
+    youtube: Option<youtube::Video>,
+    /// The description, rendered from markdown
+    description: Option<String>,
+    /// From `import-stats`
+    downloads: stats::Downloads,
+    /// The other takes of the same patch
+    related: Vec<RelatedLink<'a>>,
+    date_format: &'a str,
+    /// The names to link to the static files by
+    assets: &'a AssetMap,
+}
+
+/// Another take of the same patch, for the "related sessions" on a recording's page
+pub struct RelatedLink<'a> {
+    pub recording: &'a Recording,
+    /// The page comparing the two, relative to the root
+    pub comparison: String,
+}
+
+/// The page comparing two takes of the same patch (see [`crate::related`])
+#[derive(Template)]
+#[template(path = "comparison.html")]
+pub struct ComparisonTemplate<'a> {
+    gitlab_review: String,
+    generator: &'static str,
+    /// In the order of the season
+    a: &'a Recording,
+    b: &'a Recording,
+    date_format: &'a str,
+    /// The names to link to the static files by
+    assets: &'a AssetMap,
+}
+
+impl ComparisonTemplate<'_> {
+    /// The names of the tracks of both, side by side, with None where one has fewer
+    fn track_rows(&self) -> Vec<(Option<&str>, Option<&str>)> {
+        let rows = self.a.tracks.len().max(self.b.tracks.len());
+        (0..rows)
+            .map(|i| {
+                (
+                    self.a.tracks.get(i).map(|t| t.name.as_str()),
+                    self.b.tracks.get(i).map(|t| t.name.as_str()),
+                )
+            })
+            .collect()
+    }
+}
+
+// impl From<&AudioFile> for AudioFileHB {
+//     fn from(af: &AudioFile) -> Self {
+//         AudioFileHB {
+//             filename_url: af.filename().replace(' ', "%20"),
+//             filename: af.filename(),
+//             format: af.format_str.clone(),
+//             duration: {
+//                 let sec = af.duration.as_secs();
+//                 if sec <= 59 {
+//                     format!("{}s", sec)
+//                 } else {
+//                     let min = (sec as f32 / 60.0).floor() as u64;
+//                     let sec = sec - (min * 60);
+//                     format!("{}m {}s", min, sec)
+//                 }
+//             },
+//             flac_size: format!("{}MB", af.orig_size_bytes / 1024 / 1024),
+//             ogg_size: format!("{}MB", af.ogg_size_bytes / 1024 / 1024),
+//         }
+//     }
+// }
+
+// handlebars_helper!(filename: |v: u32| f.filename());
+
+/// Where generation puts its files: the output directory, or with `--diff-output`, nowhere (they're only compared
+/// with what's there)
+struct Output<'a> {
+    root: &'a Path,
+    /// What the last generation left in `root`
+    previous: BuildManifest,
+    diff: Option<&'a OutputDiff>,
+}
+
+impl<'a> Output<'a> {
+    fn open(ctx: &'a RunContext, root: &'a Path) -> Result<Output<'a>, anyhow::Error> {
+        Ok(Output {
+            root,
+            previous: BuildManifest::load(root)?.unwrap_or_default(),
+            diff: ctx.diff_output.as_ref(),
+        })
+    }
+
+    /// Says what was written, which only gets in the way of the diff
+    fn note(&self, message: std::fmt::Arguments) {
+        if self.diff.is_none() {
+            say!("{}", message);
+        }
+    }
+
+    fn create_dir(&self, dir: &Path) -> Result<(), anyhow::Error> {
+        if self.diff.is_none() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Writes `contents` to `path` (somewhere in the output), unless the last generation left exactly that there
+    ///
+    /// Unchanged files keep their modification times, so that syncing the output only sends what really changed.  The
+    /// others are replaced all at once, so a run that fails partway leaves each one as it was or as it should be.
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+        if self
+            .previous
+            .unchanged(self.root, path, &checksum::sha256_bytes(contents))
+        {
+            match self.diff {
+                Some(diff) => diff.unchanged(),
+                None => say!("{} is unchanged", path.display()),
+            }
+            return Ok(());
+        }
+        match self.diff {
+            Some(diff) => diff.compare(path, contents),
+            None => crate::write_atomically(path, contents)
+                .with_context(|| format!("Failed to write {}", path.display()))?,
+        }
+        Ok(())
+    }
+
+    /// Copies `src` to `dst` (somewhere in the output), unless the last generation already did
+    fn copy(&self, hashes: &HasherPool, src: &Path, dst: &Path) -> Result<(), anyhow::Error> {
+        if self.previous.unchanged(self.root, dst, &hashes.sha256(src)?) {
+            if let Some(diff) = self.diff {
+                diff.unchanged();
+            }
+            return Ok(());
+        }
+        match self.diff {
+            Some(diff) => {
+                let contents = std::fs::read(src).with_context(|| format!("Failed to read {}", src.display()))?;
+                diff.compare(dst, &contents);
+            }
+            None => {
+                std::fs::copy(src, dst).with_context(|| format!("Failed to copy {}", src.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_all_files<P: AsRef<Path>, T: AsRef<Path>>(
+    hashes: &HasherPool, output: &Output, from_dir: P, to_dir: T, written: &mut Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    let from_dir = from_dir.as_ref();
+    let to_dir = to_dir.as_ref();
+    for file in listing::sorted(from_dir).with_context(|| format!("Failed to read {}", from_dir.display()))? {
+        let dst = to_dir.join(file.file_name());
+
+        if file.file_type()?.is_file() {
+            let src = file.path().canonicalize()?;
+            output.note(format_args!("{:?} --> {:?}", src, dst));
+            output.copy(hashes, &src, &dst)?;
+            written.push(dst);
+        } else if file.file_type()?.is_dir() {
+            output.create_dir(&dst)?;
+            copy_all_files(hashes, output, file.path(), &dst, written)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies each static file that has a hashed name to that name in `to_dir`, next to the plain copy
+fn copy_hashed_assets(
+    hashes: &HasherPool, output: &Output, assets: &AssetMap, static_dir: &Path, to_dir: &Path,
+    written: &mut Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    for (path, name) in assets.iter() {
+        let dst = to_dir.join(name);
+        output.copy(hashes, &static_dir.join(path), &dst)?;
+        written.push(dst);
+    }
+    Ok(())
+}
+
+/// Writes index.html and copies the static files, returning every file that was written
+pub fn write_season_index(
+    ctx: &RunContext, season: &Season, output_root: &Path,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    write_season_index_of(
+        ctx,
+        &season.title,
+        &season.skipped,
+        || season.recordings.iter().map(RecordingSummary::of),
+        output_root,
+    )
+}
+
+/// Writes index.html and copies the static files like [`write_season_index`], for a season that's only available one
+/// recording at a time
+///
+/// `recordings` is called twice, once for the tags and completeness at the top of the page and once for the rows, and
+/// has to give the same recordings both times.
+pub fn write_season_index_of<F, I>(
+    ctx: &RunContext, title: &str, skipped: &[SkippedRecording], recordings: F, output_root: &Path,
+) -> Result<Vec<PathBuf>, anyhow::Error>
+where
+    F: Fn() -> I,
+    I: Iterator<Item = RecordingSummary>,
+{
+    let mut tag_set = BTreeSet::new();
+    let completeness = completeness::Summary::of_each(recordings().map(|rec| {
+        tag_set.extend(rec.tags);
+        rec.completeness
+    }));
+
+    // sorted by slug, so that the output is deterministic
+    let mut tag_list: Vec<_> = tag_set.into_iter().collect();
+    tag_list.sort_by(|a, b| a.slug.cmp(&b.slug));
+
+    let assets = AssetMap::of_static_dir(ctx)?;
+    let context = SeasonIndexTemplate {
+        title,
+        recordings: Rows(Cell::new(Some(recordings()))),
+        skipped,
+        tag_list,
+        completeness,
+        date_format: &ctx.date_format,
+        gitlab_review: ctx.review_snippet.clone(),
+        generator: GENERATOR,
+        assets: &assets,
+    };
+
+    let output = Output::open(ctx, output_root)?;
+    output.create_dir(output_root)?;
+    let f = output_root.join("index.html");
+    let rendered: String = context.render()?;
+    output.write(&f, rendered.as_bytes())?;
+
+    let mut written = vec![f.clone()];
+    copy_all_files(&ctx.hashes, &output, &ctx.static_dir, output_root, &mut written)?;
+    copy_hashed_assets(
+        &ctx.hashes,
+        &output,
+        &assets,
+        &ctx.static_dir,
+        output_root,
+        &mut written,
+    )?;
+
+    output.note(format_args!("Write season index to {}", f.display()));
+
+    Ok(written)
+}
+
+/// Writes the badges with the season's totals to `badges/` (see [`crate::badges`]), returning every file that was
+/// written
+pub fn write_badges(ctx: &RunContext, season: &Season, output_root: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let output = Output::open(ctx, output_root)?;
+    let dir = output_root.join(badges::BADGES_DIR);
+    output.create_dir(&dir)?;
+    let mut written = Vec::new();
+    for (name, badge) in badges::Totals::of(season).badges() {
+        let f = dir.join(name);
+        output.write(&f, badge.svg().as_bytes())?;
+        written.push(f);
+    }
+    output.note(format_args!("Wrote the badges to {}", dir.display()));
+    Ok(written)
+}
+
+/// Deletes the files in `dir` that aren't in `keep` (or compressed copies of one that is)
+fn remove_unlisted(dir: &Path, keep: &[PathBuf]) -> Result<(), anyhow::Error> {
+    let entries = match listing::sorted(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_unlisted(&path, keep)?;
+        } else if !keep
+            .iter()
+            .any(|k| *k == path || Encoding::ALL.iter().any(|e| e.sibling(k) == path))
+        {
+            say!("Deleting {}, which isn't in the snapshot any more", path.display());
+            std::fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the snapshot of the season's JSON to `source/` (see [`crate::source`]), returning every file that was
+/// written
+///
+/// What earlier runs left in `source/` that isn't part of the snapshot any more is deleted, so that it isn't published.
+pub fn write_source_snapshot(
+    ctx: &RunContext, season_json: &Path, output_root: &Path,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    if !ctx.source.snapshot {
+        return Ok(Vec::new());
+    }
+    let output = Output::open(ctx, output_root)?;
+    let dir = output_root.join(source::SOURCE_DIR);
+    let mut written = Vec::new();
+    for (path, contents) in source::snapshot(ctx, season_json)? {
+        let dst = dir.join(path);
+        output.create_dir(dst.parent().unwrap_or(&dir))?;
+        output.write(&dst, &contents)?;
+        written.push(dst);
+    }
+    if output.diff.is_none() {
+        remove_unlisted(&dir, &written)?;
+    }
+    output.note(format_args!(
+        "Wrote a snapshot of {} to {}",
+        season_json.display(),
+        dir.display()
+    ));
+    Ok(written)
+}
+
+/// Writes the playlist and the selected recording pages, returning every file that was written
+pub fn write_all_recording_index(
+    ctx: &RunContext, season: &Season, output_root: &Path,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let output = Output::open(ctx, output_root)?;
+    let mut m3u = String::new();
+    let mut written = vec![output_root.join("playlist.m3u")];
+    // the same playlist with the low-quality oggs, when the season has them
+    let mut lq_m3u = season.low_quality_ogg.as_ref().map(|_| String::new());
+
+    writeln!(m3u, "#EXTM3U")?;
+    if let Some(lq_m3u) = &mut lq_m3u {
+        writeln!(lq_m3u, "#EXTM3U")?;
+    }
+
+    let selected = ctx.only.select(season)?;
+    let total = selected.len();
+    let stats = stats::Stats::load(&ctx.stats_file)?;
+    let assets = AssetMap::of_static_dir(ctx)?;
+    let mut index = 0;
+    for recording in &season.recordings {
+        let recording = &*check_links(ctx, recording)?;
+        // flac-only recordings have nothing to stream
+        if let Some(ogg) = &recording.stereo_mix.vorbis {
+            // -1 is the m3u way of saying the length is unknown
+            let duration = match recording.stereo_mix.media_info.duration_secs() {
+                Ok(secs) => secs.round() as i64,
+                Err(e) => {
+                    say!(
+                        "{}: {} has no length in the playlist: {}",
+                        "WARNING".yellow(),
+                        recording.title,
+                        e
+                    );
+                    progress::emit(ProgressEvent::Warning {
+                        stage: Stage::Generate,
+                        message: format!("{} has no length in the playlist: {}", recording.title, e),
+                    });
+                    -1
+                }
+            };
+            let folder = slug::encode_path_segment(&recording.data_folder);
+            writeln!(m3u, "#EXTINF:{},{} - {}", duration, recording.artist, recording.title)?;
+            writeln!(m3u, "{}/{}/{}", ctx.base_url, folder, slug::encode_path(ogg))?;
+            if let Some(lq_m3u) = &mut lq_m3u {
+                let lq = recording.stereo_mix.lq_ogg.as_ref().unwrap_or(ogg);
+                writeln!(
+                    lq_m3u,
+                    "#EXTINF:{},{} - {}",
+                    duration, recording.artist, recording.title
+                )?;
+                writeln!(lq_m3u, "{}/{}/{}", ctx.base_url, folder, slug::encode_path(lq))?;
+            }
+        }
+
+        // the playlist always lists the whole season, but only the selected pages are regenerated
+        if !ctx.only.matches(recording) {
+            continue;
+        }
+        index += 1;
+        progress::emit(ProgressEvent::ItemProcessed {
+            stage: Stage::Generate,
+            item: recording.data_folder.clone(),
+            index,
+            total,
+        });
+        let context = RecordingIndexTemplate {
+            season,
+            recording,
+            youtube: recording.youtube_url.as_deref().and_then(youtube::parse),
+            description: recording.description.as_deref().map(markdown::to_html),
+            downloads: stats.for_recording(recording),
+            related: recording
+                .related
+                .iter()
+                .filter_map(|slug| season.recordings.iter().find(|r| r.slug == *slug))
+                .map(|other| RelatedLink {
+                    recording: other,
+                    comparison: related::comparison_path(&recording.slug, &other.slug),
+                })
+                .collect(),
+            gitlab_review: ctx.review_snippet.clone(),
+            generator: GENERATOR,
+            date_format: &ctx.date_format,
+            assets: &assets,
+        };
+
+        // the page's relative links go to the data folder (see Recording::page_base), so that's where these live
+        let data_folder = output_root.join(&recording.data_folder);
+        output.create_dir(&data_folder)?;
+        let style = ctx.static_dir.join("style.css");
+        output.copy(&ctx.hashes, &style, &data_folder.join("style.css"))?;
+        written.push(data_folder.join("style.css"));
+        if let Some(name) = assets.get("style.css") {
+            output.copy(&ctx.hashes, &style, &data_folder.join(name))?;
+            written.push(data_folder.join(name));
+        }
+        let tos = terms_of_service(ctx, recording, output_root)?;
+        // when the output is the data dir, the recording's own terms might already be the ToS.txt (and copying a file
+        // onto itself empties it)
+        if data_folder.join("ToS.txt").canonicalize().ok() != tos.canonicalize().ok() {
+            output.copy(&ctx.hashes, &tos, &data_folder.join("ToS.txt"))?;
+            written.push(data_folder.join("ToS.txt"));
+        }
+
+        output.create_dir(&output_root.join(&recording.slug))?;
+        let f = output_root.join(&recording.slug).join("index.html");
+        let rendered: String = context.render()?;
+        output.write(&f, rendered.as_bytes())?;
+
+        output.note(format_args!("Wrote recording index to {}", f.display()));
+        written.push(f);
+    }
+
+    output.write(&output_root.join("playlist.m3u"), m3u.as_bytes())?;
+    if let Some(lq_m3u) = lq_m3u {
+        output.write(&output_root.join(types::LQ_PLAYLIST), lq_m3u.as_bytes())?;
+        written.push(output_root.join(types::LQ_PLAYLIST));
+    }
+    write_redirects(&output, season, &mut written)?;
+    write_comparisons(ctx, &output, season, &assets, &mut written)?;
+
+    Ok(written)
+}
+
+/// Writes the page comparing each related pair of recordings, for the pairs with a recording that's selected
+fn write_comparisons(
+    ctx: &RunContext, output: &Output, season: &Season, assets: &AssetMap, written: &mut Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    for (a, b) in related::pairs(&season.recordings) {
+        if !ctx.only.matches(a) && !ctx.only.matches(b) {
+            continue;
+        }
+        let context = ComparisonTemplate {
+            gitlab_review: ctx.review_snippet.clone(),
+            generator: GENERATOR,
+            a,
+            b,
+            date_format: &ctx.date_format,
+            assets,
+        };
+        let dir = output.root.join(related::comparison_path(&a.slug, &b.slug));
+        output.create_dir(&dir)?;
+        let f = dir.join("index.html");
+        let rendered: String = context.render()?;
+        output.write(&f, rendered.as_bytes())?;
+        output.note(format_args!(
+            "Wrote comparison of {} and {} to {}",
+            a.slug,
+            b.slug,
+            f.display()
+        ));
+        written.push(f);
+    }
+    Ok(())
+}
+
+/// A file that a recording's page links to
+#[derive(Debug, Clone, Copy)]
+enum Link {
+    Ogg,
+    LowQualityOgg,
+    Mp3,
+    Opus,
+    Torrent,
+}
+
+/// The recording, without the links to files that aren't in its data folder
+///
+/// With `--strict-links`, a missing file is an error instead.  A season loaded from the metadata has no data folder to
+/// look in, so its links are kept as they are.
+fn check_links<'a>(ctx: &RunContext, recording: &'a Recording) -> Result<Cow<'a, Recording>, anyhow::Error> {
+    let folder = match recording.stereo_mix.folder_ondisk() {
+        Some(folder) => folder,
+        None => return Ok(Cow::Borrowed(recording)),
+    };
+
+    // (track, or None for the stereo mix, what links to the file, the file)
+    let mut missing = Vec::new();
+    let tracks = std::iter::once((None, &recording.stereo_mix))
+        .chain(recording.tracks.iter().enumerate().map(|(i, track)| (Some(i), track)));
+    for (i, track) in tracks {
+        let files = [
+            (Link::Ogg, track.ogg_ondisk()),
+            (Link::LowQualityOgg, track.lq_ogg_ondisk()),
+            (Link::Mp3, track.mp3_ondisk()),
+            (Link::Opus, track.opus_ondisk()),
+        ];
+        for (link, file) in IntoIterator::into_iter(files) {
+            if let Some(file) = file.filter(|f| !f.exists()) {
+                missing.push((i, link, file));
+            }
+        }
+    }
+    let torrent = recording.torrent.as_ref().and_then(|t| types::linked_file(folder, t));
+    if let Some(file) = torrent.filter(|f| !f.exists()) {
+        missing.push((None, Link::Torrent, file));
+    }
+
+    if missing.is_empty() {
+        return Ok(Cow::Borrowed(recording));
+    }
+    if ctx.strict_links {
+        let files: Vec<String> = missing.iter().map(|(_, _, file)| file.display().to_string()).collect();
+        bail!(
+            "The page of {} links to files that aren't in the data dir: {}",
+            recording.title,
+            files.join(", ")
+        );
+    }
+
+    let mut recording = recording.clone();
+    for (i, link, file) in missing {
+        say!(
+            "{}: leaving the link to {} out of the page of {}, the file isn't in the data dir",
+            "WARNING".yellow(),
+            file.display(),
+            recording.title
+        );
+        progress::emit(ProgressEvent::Warning {
+            stage: Stage::Generate,
+            message: format!(
+                "{} links to {}, which isn't in the data dir",
+                recording.title,
+                file.display()
+            ),
+        });
+        let track = match i {
+            Some(i) => &mut recording.tracks[i],
+            None => &mut recording.stereo_mix,
+        };
+        match link {
+            Link::Ogg => track.vorbis = None,
+            Link::LowQualityOgg => track.lq_ogg = None,
+            Link::Mp3 => track.mp3 = None,
+            Link::Opus => track.opus = None,
+            Link::Torrent => recording.torrent = None,
+        }
+    }
+    Ok(Cow::Owned(recording))
+}
+
+/// The file to publish as the recording's ToS.txt: its own terms if it has some, or the global ones
+///
+/// The recording's terms are looked for in its data folder, or (when the season was loaded from the metadata) in its
+/// folder in the output.
+fn terms_of_service(ctx: &RunContext, recording: &Recording, output_root: &Path) -> Result<PathBuf, anyhow::Error> {
+    let tos = match &recording.tos {
+        Some(tos) => tos,
+        None => return Ok(ctx.static_dir.join("ToS.txt")),
+    };
+    let candidates = [
+        recording.stereo_mix.folder_ondisk().map(|folder| folder.join(tos)),
+        Some(output_root.join(&recording.data_folder).join(tos)),
+    ];
+    candidates
+        .iter()
+        .flatten()
+        .find(|path| path.is_file())
+        .cloned()
+        .with_context(|| format!("Can't find {}, the terms of service of {}", tos, recording.title))
+}
+
+/// Writes a page at each old slug that sends the browser on to the new one
+fn write_redirects(output: &Output, season: &Season, written: &mut Vec<PathBuf>) -> Result<(), anyhow::Error> {
+    for (old, new) in &season.redirects {
+        let target = format!("../{}/", slug::encode_path_segment(new));
+        output.create_dir(&output.root.join(old))?;
+        let f = output.root.join(old).join("index.html");
+        let page = format!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n    <!-- Generated by {} -->\n    \
+                 <meta http-equiv=\"refresh\" content=\"0; url={}\" />\n    <link rel=\"canonical\" href=\"{}\" />\n\
+                 </head>\n<body>\n    <a href=\"{}\">This recording has moved</a>\n</body>\n</html>\n",
+            GENERATOR, target, target, target
+        );
+        output.write(&f, page.as_bytes())?;
+        output.note(format_args!("Wrote redirect from {} to {}", f.display(), new));
+        written.push(f);
+    }
+    Ok(())
+}
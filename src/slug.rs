@@ -0,0 +1,156 @@
+//! Slugs: the names of recordings in URLs
+//!
+//! A recording's data_folder is where its files are on disk, and is often full of spaces and capitals.  Its slug is
+//! what's used for its page and anchors instead, and is either given in the JSON or made from the title.
+
+use crate::error::CbError;
+
+/// Makes a slug out of any text: lowercase ASCII letters and digits, with a single `-` between words
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars() {
+        let c = match c {
+            'à'..='å' | 'À'..='Å' => 'a',
+            'è'..='ë' | 'È'..='Ë' => 'e',
+            'ì'..='ï' | 'Ì'..='Ï' => 'i',
+            'ò'..='ö' | 'Ò'..='Ö' => 'o',
+            'ù'..='ü' | 'Ù'..='Ü' => 'u',
+            'ç' | 'Ç' => 'c',
+            'ñ' | 'Ñ' => 'n',
+            c => c.to_ascii_lowercase(),
+        };
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+    slug
+}
+
+/// The slug of a recording: the one from its JSON, or else one made from its title (or its data_folder, if the title
+/// has nothing usable in it)
+pub fn for_recording(explicit: Option<&str>, title: &str, data_folder: &str) -> String {
+    match explicit {
+        Some(slug) => slug.to_string(),
+        None => match slugify(title) {
+            from_title if from_title.is_empty() => slugify(data_folder),
+            from_title => from_title,
+        },
+    }
+}
+
+/// True if `slug` is what [`slugify`] would make of it
+pub fn is_valid(slug: &str) -> bool {
+    !slug.is_empty() && slugify(slug) == slug
+}
+
+/// Checks the `(slug, data_folder)` of every recording in a season
+///
+/// Every recording needs its own slug, which can't be the data_folder of a different recording either (since its page
+/// would then end up in that recording's folder).
+pub fn check(pages: &[(&str, &str)]) -> Result<(), CbError> {
+    for (slug, data_folder) in pages {
+        if !is_valid(slug) {
+            return Err(CbError::InvalidSlug {
+                slug: slug.to_string(),
+                recording: data_folder.to_string(),
+            });
+        }
+        let clashes: Vec<String> = pages
+            .iter()
+            .filter(|(other_slug, other_folder)| other_slug == slug || other_folder == slug)
+            .map(|(_, other_folder)| other_folder.to_string())
+            .collect();
+        if clashes.len() > 1 {
+            return Err(CbError::DuplicateSlug {
+                slug: slug.to_string(),
+                recordings: clashes,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Percent-encodes one segment of a URL path (like a data_folder with spaces in it)
+pub fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Percent-encodes a relative path to a file (like `ogg/Kick & Snare.ogg`), keeping the `/`s between folders
+///
+/// Every link to a file in the site goes through this, so that the name in the URL always decodes back to the name
+/// of the link in IPFS.
+pub fn encode_path(path: &str) -> String {
+    path.split('/').map(encode_path_segment).collect::<Vec<_>>().join("/")
+}
+
+/// Decodes a percent-encoded segment of a URL path, the other way from [`encode_path_segment`]
+///
+/// A `%` that isn't followed by two hex digits is kept as it is.
+pub fn decode_path(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slugify("S01E03 - Jam 3"), "s01e03-jam-3");
+        assert_eq!(slugify("  Café  Crème!! "), "cafe-creme");
+        assert_eq!(slugify("Jam #4 (part 2/3)"), "jam-4-part-2-3");
+        assert_eq!(slugify("???"), "");
+        assert_eq!(for_recording(None, "???", "Jam 3"), "jam-3");
+        assert_eq!(for_recording(Some("third"), "Jam 3", "jam3"), "third");
+        assert!(is_valid("s01e03-jam-3"));
+        assert!(!is_valid("S01E03"));
+        assert!(!is_valid("jam--3"));
+        assert!(!is_valid(""));
+        assert!(check(&[("jam-1", "jam1"), ("jam-2", "jam2")]).is_ok());
+        assert!(check(&[("jam1", "jam1"), ("jam2", "jam2")]).is_ok());
+        assert!(matches!(
+            check(&[("jam", "jam1"), ("jam", "jam2")]),
+            Err(CbError::DuplicateSlug { recordings, .. }) if recordings == ["jam1", "jam2"]
+        ));
+        // jam2's page would overwrite whatever is in jam1's folder
+        assert!(matches!(
+            check(&[("jam-1", "jam1"), ("jam1", "jam2")]),
+            Err(CbError::DuplicateSlug { .. })
+        ));
+        assert!(matches!(check(&[("Jam 1", "jam1")]), Err(CbError::InvalidSlug { .. })));
+        assert_eq!(encode_path_segment("S01 Jam#3"), "S01%20Jam%233");
+        assert_eq!(encode_path("ogg/Kick & Snare.ogg"), "ogg/Kick%20%26%20Snare.ogg");
+    }
+}
@@ -0,0 +1,187 @@
+//! A copy of the season's JSON in the output, so that each published root says what it was made from
+//!
+//! Generation writes season.json and every recording JSON it lists into [`SOURCE_DIR`], with the include files expanded
+//! (the snapshot's season.json lists each recording itself).  A `$schema` that's a local file is copied along, and
+//! pointed at where it ends up, so that the snapshot can be checked on its own.  Fields named in `[source] sensitive`
+//! are left out wherever they are, and the keys are sorted, so the same season always makes the same snapshot.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::{context::RunContext, error::CbError, types::SeasonInner};
+
+/// The folder in the output root with the snapshot
+pub const SOURCE_DIR: &str = "source";
+
+/// What a snapshot has in it, as paths relative to [`SOURCE_DIR`] and their contents
+pub type Snapshot = Vec<(PathBuf, Vec<u8>)>;
+
+/// Takes out every field named in `sensitive`, at any depth
+fn redact(value: &mut Value, sensitive: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for key in sensitive {
+                map.remove(key);
+            }
+            map.values_mut().for_each(|v| redact(v, sensitive));
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact(v, sensitive)),
+        _ => {}
+    }
+}
+
+/// Keeps whichever of `name` and `name-2`, `name-3`... isn't taken yet in `taken`
+fn unique_name(name: &str, taken: &[String]) -> String {
+    if !taken.iter().any(|t| t == name) {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem, format!(".{}", ext)),
+        None => (name, String::new()),
+    };
+    (2..)
+        .map(|n| format!("{}-{}{}", stem, n, ext))
+        .find(|candidate| !taken.iter().any(|t| t == candidate))
+        .unwrap()
+}
+
+struct Builder<'a> {
+    sensitive: &'a [String],
+    files: Snapshot,
+    /// The local schemas copied so far, and their names in `schema/`
+    schemas: Vec<(PathBuf, String)>,
+}
+
+impl Builder<'_> {
+    /// Points `json`'s `$schema` at the snapshot's copy of it, if it's a local file, from a file `depth` folders below
+    /// the snapshot's root
+    fn rewrite_schema(&mut self, json_path: &Path, json: &mut Value, depth: usize) -> Result<(), CbError> {
+        let schema = match json.get("$schema").and_then(Value::as_str) {
+            Some(schema) if schema.starts_with("./") || schema.starts_with("../") => schema,
+            _ => return Ok(()),
+        };
+        let schema_path = json_path.parent().unwrap_or_else(|| Path::new("")).join(schema);
+        let canonical = schema_path.canonicalize().unwrap_or_else(|_| schema_path.clone());
+        let name = match self.schemas.iter().find(|(path, _)| *path == canonical) {
+            Some((_, name)) => name.clone(),
+            None => {
+                let file_name = schema_path
+                    .file_name()
+                    .map_or_else(|| "schema.json".to_string(), |n| n.to_string_lossy().to_string());
+                let taken: Vec<String> = self.schemas.iter().map(|(_, name)| name.clone()).collect();
+                let name = unique_name(&file_name, &taken);
+                let contents = std::fs::read(&schema_path)
+                    .map_err(|e| CbError::io(format!("Failed to read {}", schema_path.display()), e))?;
+                self.files.push((Path::new("schema").join(&name), contents));
+                self.schemas.push((canonical, name.clone()));
+                name
+            }
+        };
+        let up = if depth == 0 {
+            "./".to_string()
+        } else {
+            "../".repeat(depth)
+        };
+        json["$schema"] = Value::String(format!("{}schema/{}", up, name));
+        Ok(())
+    }
+
+    fn add_json(&mut self, path: PathBuf, mut json: Value) -> Result<(), CbError> {
+        redact(&mut json, self.sensitive);
+        let bytes = crate::to_json_bytes(&json).map_err(|e| CbError::step("snapshot the season's JSON", e))?;
+        self.files.push((path, bytes));
+        Ok(())
+    }
+}
+
+/// Makes the snapshot of `season_json` and the recordings it lists
+pub fn snapshot(ctx: &RunContext, season_json: &Path) -> Result<Snapshot, CbError> {
+    let mut builder = Builder {
+        sensitive: &ctx.source.sensitive,
+        files: Vec::new(),
+        schemas: Vec::new(),
+    };
+
+    let mut season = crate::get_validated_json(season_json)?;
+    let inner: SeasonInner = serde_json::from_value(season.clone())
+        .map_err(|e| CbError::parse(format!("Unexpected contents in {}", season_json.display()), e))?;
+
+    let mut names: Vec<String> = Vec::new();
+    for listed in inner.recording_paths(season_json)? {
+        let file_name = listed
+            .path
+            .file_name()
+            .map_or_else(|| listed.name.clone(), |n| n.to_string_lossy().to_string());
+        let name = unique_name(&file_name, &names);
+        let mut recording = match crate::get_validated_json(&listed.path) {
+            Ok(recording) => recording,
+            // loading the season already reported it, and it isn't part of what's published
+            Err(_) if ctx.keep_going => continue,
+            Err(e) => return Err(listed.context(e)),
+        };
+        builder.rewrite_schema(&listed.path, &mut recording, 1)?;
+        builder.add_json(Path::new("recordings").join(&name), recording)?;
+        names.push(name);
+    }
+
+    builder.rewrite_schema(season_json, &mut season, 0)?;
+    season["recordings"] = names
+        .iter()
+        .map(|name| Value::String(format!("recordings/{}", name)))
+        .collect();
+    builder.add_json(PathBuf::from("season.json"), season)?;
+
+    Ok(builder.files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write(
+            "season.json",
+            r#"{"$schema": "./schemas/s.json", "title": "S", "recordings": ["a/jam.json", {"include": "more.json"}]}"#,
+        );
+        write("more.json", r#"{"recordings": ["b/jam.json"]}"#);
+        write(
+            "a/jam.json",
+            r#"{"title": "A", "torrent": "secret", "tracks": [{"id": 1, "torrent": "x"}]}"#,
+        );
+        write("b/jam.json", r#"{"$schema": "../schemas/s.json", "title": "B"}"#);
+        write("schemas/s.json", "{}");
+
+        let ctx = RunContext {
+            source: crate::context::SourceSettings {
+                snapshot: true,
+                sensitive: vec!["torrent".to_string()],
+            },
+            ..RunContext::default()
+        };
+        let files = snapshot(&ctx, &dir.path().join("season.json")).unwrap();
+        let json = |name: &str| -> Value {
+            let (_, bytes) = files.iter().find(|(path, _)| path == Path::new(name)).unwrap();
+            serde_json::from_slice(bytes).unwrap()
+        };
+        assert_eq!(files.len(), 4);
+        assert_eq!(json("schema/s.json"), serde_json::json!({}));
+        assert_eq!(json("season.json")["$schema"], "./schema/s.json");
+        assert_eq!(
+            json("season.json")["recordings"],
+            serde_json::json!(["recordings/jam.json", "recordings/jam-2.json"])
+        );
+        assert_eq!(
+            json("recordings/jam.json"),
+            serde_json::json!({"title": "A", "tracks": [{"id": 1}]})
+        );
+        assert_eq!(json("recordings/jam-2.json")["$schema"], "../schema/s.json");
+    }
+}
@@ -0,0 +1,157 @@
+//! Spectrogram images of each track
+//!
+//! These give a quick picture of what's in a stem before downloading it.  They're drawn by ffmpeg's showspectrumpic
+//! filter, and only redrawn when the flac is newer than the image.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    analysis::parallel_for_each,
+    context::{RunContext, SpectrogramSettings},
+    progress::{self, ProgressEvent, Stage},
+    types::Season,
+};
+
+/// The ffmpeg filter that draws a spectrogram
+fn filter(settings: &SpectrogramSettings) -> String {
+    format!(
+        "showspectrumpic=s={}x{}:legend={}",
+        settings.width,
+        settings.height,
+        if settings.legend { 1 } else { 0 }
+    )
+}
+
+/// True if `png` exists and is at least as new as `flac`
+pub fn is_up_to_date(flac: &Path, png: &Path) -> bool {
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|md| md.modified()).ok();
+    match (modified(flac), modified(png)) {
+        (Some(flac), Some(png)) => png >= flac,
+        _ => false,
+    }
+}
+
+/// Draws the spectrogram of `flac` into `png`
+pub fn render(ctx: &RunContext, flac: &Path, png: &Path) -> anyhow::Result<()> {
+    // draw into a temporary file, so that an interrupted run doesn't leave an image that looks up to date
+    let tmp = png.with_extension("tmp.png");
+    let output = Command::new(&ctx.tools.ffmpeg)
+        .arg("-nostats")
+        .arg("-y")
+        .arg("-i")
+        .arg(flac)
+        .arg("-lavfi")
+        .arg(filter(&ctx.spectrogram))
+        .arg("-frames:v")
+        .arg("1")
+        .arg(&tmp)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run ffmpeg on {}", flac.display()))?;
+
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&tmp);
+        bail!(
+            "ffmpeg returned {:?} drawing the spectrogram of {}: {}",
+            output.status,
+            flac.display(),
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .last()
+                .unwrap_or("")
+                .trim()
+        );
+    }
+    std::fs::rename(&tmp, png).with_context(|| format!("Failed to write {}", png.display()))
+}
+
+/// Draws the spectrogram of every selected track whose image is missing or older than its flac
+///
+/// Up to `ctx.jobs` images are drawn at once.  Returns the number of images that were drawn.
+pub fn render_missing(ctx: &RunContext, season: &Season) -> anyhow::Result<usize> {
+    ctx.stage(Stage::Analyze, || {
+        let mut todo: Vec<(PathBuf, PathBuf)> = Vec::new();
+        for rec in ctx.only.select(season)? {
+            for track in std::iter::once(&rec.stereo_mix).chain(&rec.tracks) {
+                if let (Some(flac), Some(png)) = (track.flac_ondisk(), track.spectrogram_ondisk()) {
+                    if !is_up_to_date(&flac, &png) {
+                        todo.push((flac, png));
+                    }
+                }
+            }
+        }
+        let total = todo.len();
+
+        let done = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+
+        parallel_for_each(ctx.jobs, todo, |(flac, png)| {
+            let start = Instant::now();
+            if let Err(e) = render(ctx, &flac, &png) {
+                errors.lock().unwrap().push(e);
+            }
+            ctx.timings
+                .record_item(Stage::Analyze, png.display().to_string(), start.elapsed());
+            progress::emit(ProgressEvent::ItemProcessed {
+                stage: Stage::Analyze,
+                item: png.display().to_string(),
+                index: done.fetch_add(1, Ordering::SeqCst) + 1,
+                total,
+            });
+        });
+
+        let errors = errors.into_inner().unwrap();
+        progress::emit(ProgressEvent::Summary {
+            stage: Stage::Analyze,
+            processed: total,
+            errors: errors.len(),
+            warnings: 0,
+        });
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+        Ok(total)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn up_to_date() {
+        assert_eq!(
+            filter(&SpectrogramSettings::default()),
+            "showspectrumpic=s=1024x512:legend=1"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let flac = dir.path().join("a.flac");
+        let png = dir.path().join("a.spectrogram.png");
+        std::fs::write(&flac, b"flac").unwrap();
+        assert!(!is_up_to_date(&flac, &png));
+
+        std::fs::write(&png, b"png").unwrap();
+        assert!(is_up_to_date(&flac, &png));
+
+        // the flac was replaced after the image was drawn
+        let later = std::fs::metadata(&png).unwrap().modified().unwrap() + std::time::Duration::from_secs(10);
+        std::fs::File::options()
+            .write(true)
+            .open(&flac)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(!is_up_to_date(&flac, &png));
+    }
+}
@@ -0,0 +1,257 @@
+//! The small files that runs keep their state in between them
+//!
+//! There are two kinds.  Logs, like the roots history, are JSON lines that only ever get added to.  Documents, like the
+//! priming state or the media info cache, are a single JSON value that's replaced whole.  Both are written atomically,
+//! so that a run that dies halfway leaves the old file rather than half of the new one.
+//!
+//! Nothing here grows without bound over years of runs: once a log goes past its [`Retention`], its newest entries are
+//! kept and the older ones are moved to the end of `<name>.1`, which is kept to the same retention.  A log that ends in something
+//! that isn't a whole entry (from an older version, or a copy that was cut off) is truncated back to its last whole
+//! entry, and a document that can't be read is moved to `<name>.1` and started over, with a warning either way instead
+//! of failing every run after it.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::CbError;
+
+/// How much of a log (or a cache) to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub max_entries: usize,
+    pub max_bytes: u64,
+}
+
+impl Retention {
+    /// How many of the newest entries fit, given the size of each entry, oldest first
+    ///
+    /// The newest entry is always kept, even when it's bigger than `max_bytes` on its own.
+    pub fn keep(&self, sizes: &[u64]) -> usize {
+        let mut bytes = 0;
+        let mut kept = 0;
+        for size in sizes.iter().rev() {
+            if kept > 0 && (kept == self.max_entries || bytes + size > self.max_bytes) {
+                break;
+            }
+            bytes += size;
+            kept += 1;
+        }
+        kept
+    }
+}
+
+/// Where the older contents of `path` are moved to
+pub fn archive_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".1");
+    path.with_file_name(name)
+}
+
+/// Whether `name` is the archive of the file called `of`
+pub fn is_archive_of(name: &std::ffi::OsStr, of: &str) -> bool {
+    name.to_str()
+        .and_then(|name| name.strip_suffix(".1"))
+        .is_some_and(|name| name == of)
+}
+
+/// Moves `path` to its archive, if it exists
+pub fn archive(path: &Path) -> Result<(), CbError> {
+    match std::fs::rename(path, archive_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(CbError::io(format!("Failed to move {} aside", path.display()), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), CbError> {
+    crate::write_atomically(path, contents).map_err(|e| CbError::io(format!("Failed to write {}", path.display()), e))
+}
+
+/// Splits a log into its whole entries, returning them along with the length of the part of `bytes` they cover
+fn parse_log<T: DeserializeOwned>(bytes: &[u8]) -> (Vec<(T, &[u8])>, usize) {
+    let mut entries = Vec::new();
+    let mut valid = 0;
+    for line in bytes.split_inclusive(|&b| b == b'\n') {
+        let json = line.strip_suffix(b"\n").unwrap_or(line);
+        if !json.iter().all(u8::is_ascii_whitespace) {
+            match serde_json::from_slice(json) {
+                Ok(entry) => entries.push((entry, json)),
+                Err(_) => break,
+            }
+        }
+        valid += line.len();
+    }
+    (entries, valid)
+}
+
+/// Reads the lines of a log that are whole entries, along with the entries
+///
+/// If anything after them isn't, the file is truncated to them.
+fn read_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<(T, Vec<u8>)>, CbError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+    };
+    let (entries, valid) = parse_log(&bytes);
+    let entries: Vec<(T, Vec<u8>)> = entries
+        .into_iter()
+        .map(|(entry, json)| (entry, json.to_vec()))
+        .collect();
+    if valid < bytes.len() {
+        say!(
+            "Warning: {} ends in {} bytes that aren't a whole entry, truncating it to the {} entries before them",
+            path.display(),
+            bytes.len() - valid,
+            entries.len()
+        );
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid as u64))
+            .map_err(|e| CbError::io(format!("Failed to truncate {}", path.display()), e))?;
+    }
+    Ok(entries)
+}
+
+/// Reads a log, oldest entry first.  A missing file is an empty log
+pub fn read_log<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, CbError> {
+    Ok(read_lines(path)?.into_iter().map(|(entry, _)| entry).collect())
+}
+
+/// Adds `entry` to the end of a log, moving its oldest entries to the archive if it's gone past `retention`
+pub fn append_log<T: Serialize + DeserializeOwned>(
+    path: &Path, entry: &T, retention: Retention,
+) -> Result<(), CbError> {
+    let mut lines: Vec<Vec<u8>> = read_lines::<T>(path)?.into_iter().map(|(_, line)| line).collect();
+    lines.push(serde_json::to_vec(entry).map_err(|e| CbError::step(format!("write {}", path.display()), e))?);
+
+    let sizes: Vec<u64> = lines.iter().map(|line| line.len() as u64 + 1).collect();
+    let older = lines.len() - retention.keep(&sizes);
+    if older > 0 {
+        let archive = archive_path(path);
+        let mut archived: Vec<Vec<u8>> = read_lines::<T>(&archive)?.into_iter().map(|(_, line)| line).collect();
+        archived.extend_from_slice(&lines[..older]);
+        let sizes: Vec<u64> = archived.iter().map(|line| line.len() as u64 + 1).collect();
+        write(
+            &archive,
+            &join_lines(&archived[archived.len() - retention.keep(&sizes)..]),
+        )?;
+    }
+    write(path, &join_lines(&lines[older..]))
+}
+
+fn join_lines(lines: &[Vec<u8>]) -> Vec<u8> {
+    lines
+        .iter()
+        .flat_map(|line| line.iter().chain(b"\n"))
+        .copied()
+        .collect()
+}
+
+/// Reads a document, or None if there isn't one
+///
+/// A document that isn't what's expected is moved to the archive, and treated as missing.
+pub fn read_doc<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, CbError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(doc) => Ok(Some(doc)),
+        Err(e) => {
+            say!(
+                "Warning: starting {} over, it couldn't be read ({}) and was moved to {}",
+                path.display(),
+                e,
+                archive_path(path).display()
+            );
+            archive(path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Replaces a document
+pub fn write_doc<T: Serialize>(path: &Path, doc: &T) -> Result<(), CbError> {
+    let bytes = crate::to_json_bytes(doc).map_err(|e| CbError::step(format!("write {}", path.display()), e))?;
+    write(path, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETENTION: Retention = Retention {
+        max_entries: 3,
+        max_bytes: 1024,
+    };
+
+    #[test]
+    fn keep() {
+        assert_eq!(RETENTION.keep(&[]), 0);
+        assert_eq!(RETENTION.keep(&[10, 10]), 2);
+        assert_eq!(RETENTION.keep(&[10, 10, 10, 10, 10]), 3);
+        assert_eq!(RETENTION.keep(&[10, 1010, 20]), 1);
+        assert_eq!(RETENTION.keep(&[2000]), 1);
+    }
+
+    #[test]
+    fn rotates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+        for n in 0..5u32 {
+            append_log(&path, &n, RETENTION).unwrap();
+        }
+        assert_eq!(read_log::<u32>(&path).unwrap(), [2, 3, 4]);
+        assert_eq!(read_log::<u32>(&archive_path(&path)).unwrap(), [0, 1]);
+        append_log(&path, &5u32, RETENTION).unwrap();
+        assert_eq!(read_log::<u32>(&path).unwrap(), [3, 4, 5]);
+        assert_eq!(read_log::<u32>(&archive_path(&path)).unwrap(), [0, 1, 2]);
+        append_log(&path, &6u32, RETENTION).unwrap();
+        assert_eq!(read_log::<u32>(&archive_path(&path)).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn partially_written_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.jsonl");
+        std::fs::write(&path, "{\"a\": 1}\n\n{\"a\": 2}\n{\"a\": 3, \"b").unwrap();
+
+        let entries: Vec<serde_json::Value> = read_log(&path).unwrap();
+        assert_eq!(entries, [serde_json::json!({"a": 1}), serde_json::json!({"a": 2})]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"a\": 1}\n\n{\"a\": 2}\n");
+
+        append_log(&path, &serde_json::json!({"a": 4}), RETENTION).unwrap();
+        let entries: Vec<serde_json::Value> = read_log(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2], serde_json::json!({"a": 4}));
+    }
+
+    #[test]
+    fn corrupt_doc() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        assert_eq!(read_doc::<Vec<u32>>(&path).unwrap(), None);
+        std::fs::write(&path, "[1, 2").unwrap();
+        assert_eq!(read_doc::<Vec<u32>>(&path).unwrap(), None);
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(archive_path(&path)).unwrap(), "[1, 2");
+
+        write_doc(&path, &vec![1u32, 2]).unwrap();
+        assert_eq!(read_doc::<Vec<u32>>(&path).unwrap(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn archive_names() {
+        assert_eq!(archive_path(Path::new("a/b.json")), Path::new("a/b.json.1"));
+        assert!(is_archive_of("b.json.1".as_ref(), "b.json"));
+        assert!(!is_archive_of("b.json".as_ref(), "b.json"));
+    }
+}
@@ -0,0 +1,276 @@
+//! Download counts, from the access logs of our own gateway (the `import-stats` subcommand)
+//!
+//! Each request in a log is matched with the files the season publishes: the flac, ogg, low-quality ogg, mp3 and opus of
+//! every track, in the recording's data folder (or under its slug).  The counts go into the stats file, which the
+//! recording pages show next to each track.  The stats file also remembers the logs it has counted (by their SHA-256),
+//! so importing a log twice doesn't count it twice.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::Path,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum,
+    error::CbError,
+    types::{Recording, Season, Track},
+};
+
+/// The request path and status of a GET in the combined log format (the default of nginx and Apache)
+pub const COMBINED_LOG_FORMAT: &str = r#"^\S+ \S+ \S+ \[[^\]]*\] "GET (?P<path>\S+)[^"]*" (?P<status>\d{3}) "#;
+
+/// How many of the paths that didn't match anything are listed after an import
+const UNMATCHED_SHOWN: usize = 10;
+
+/// The contents of the stats file
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// The SHA-256 of every log that has been imported
+    pub imported_logs: BTreeSet<String>,
+    /// Downloads of each track, by the recording's data folder and then the track id
+    pub downloads: BTreeMap<String, BTreeMap<u8, u64>>,
+}
+
+impl Stats {
+    /// Reads the stats file, or starts new stats if there isn't one yet
+    pub fn load(path: &Path) -> Result<Stats, CbError> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| CbError::parse(format!("Unexpected contents in {}", path.display()), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Stats::default()),
+            Err(e) => Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CbError> {
+        let bytes = crate::to_json_bytes(self).expect("the stats are always valid JSON");
+        crate::write_atomically(path, &bytes).map_err(|e| CbError::io(format!("Failed to write {}", path.display()), e))
+    }
+
+    /// The download counts of a recording's tracks
+    pub fn for_recording(&self, recording: &Recording) -> Downloads {
+        Downloads(self.downloads.get(&recording.data_folder).cloned().unwrap_or_default())
+    }
+}
+
+/// How often each track of a recording was downloaded, for its page
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Downloads(BTreeMap<u8, u64>);
+
+impl Downloads {
+    pub fn count(&self, track: &Track) -> u64 {
+        self.0.get(&track.id).copied().unwrap_or(0)
+    }
+
+    /// Like "downloaded 1,234 times", or nothing if the track hasn't been downloaded
+    pub fn text(&self, track: &Track) -> String {
+        downloaded(self.count(track))
+    }
+}
+
+fn downloaded(times: u64) -> String {
+    match times {
+        0 => String::new(),
+        1 => "downloaded once".to_string(),
+        n => format!("downloaded {} times", thousands(n)),
+    }
+}
+
+/// `1234567` as `1,234,567`
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// What an import counted
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The log had been imported before, so nothing was counted
+    pub already_imported: bool,
+    /// Lines of the log that the format matched
+    pub requests: usize,
+    /// Lines that the format didn't match
+    pub unparsed: usize,
+    /// Successful requests for a track's file
+    pub counted: usize,
+    /// Requests for pages, stylesheets and other files that aren't tracks
+    pub other_files: usize,
+    /// Successful requests for audio that isn't in the season, by path
+    pub unmatched: BTreeMap<String, usize>,
+}
+
+impl ImportReport {
+    pub fn summary(&self) -> String {
+        if self.already_imported {
+            return "This log has already been imported, nothing was counted\n".to_string();
+        }
+        let mut out = format!(
+            "Counted {} downloads from {} requests ({} for pages and other files, {} lines that didn't match the log format)\n",
+            self.counted, self.requests, self.other_files, self.unparsed
+        );
+        if !self.unmatched.is_empty() {
+            let total: usize = self.unmatched.values().sum();
+            out.push_str(&format!(
+                "{} downloads of audio that isn't in the season, the most common:\n",
+                total
+            ));
+            let mut unmatched: Vec<_> = self.unmatched.iter().collect();
+            unmatched.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (path, count) in unmatched.into_iter().take(UNMATCHED_SHOWN) {
+                out.push_str(&format!("{:>8}  {}\n", count, path));
+            }
+        }
+        out
+    }
+}
+
+/// Every path the season publishes a track's file at, with the recording's data folder and the track's id
+fn published_files(season: &Season) -> HashMap<String, (&str, u8)> {
+    let mut files = HashMap::new();
+    for recording in &season.recordings {
+        let mut folders = vec![recording.data_folder.as_str()];
+        if !recording.slug.is_empty() && recording.slug != recording.data_folder {
+            folders.push(&recording.slug);
+        }
+        for track in std::iter::once(&recording.stereo_mix).chain(&recording.tracks) {
+            let names = std::iter::once(&track.flac)
+                .chain(&track.vorbis)
+                .chain(&track.lq_ogg)
+                .chain(&track.mp3)
+                .chain(&track.opus);
+            for name in names {
+                for folder in &folders {
+                    files.insert(
+                        format!("{}/{}", folder, name),
+                        (recording.data_folder.as_str(), track.id),
+                    );
+                }
+            }
+        }
+    }
+    files
+}
+
+/// Where a requested path is in the published root: without the query, the `/ipfs/<cid>` or `/ipns/<name>` in front,
+/// and the percent-encoding
+fn published_path(request: &str) -> String {
+    let path = request.split(['?', '#']).next().unwrap_or("");
+    let path = path.trim_start_matches('/');
+    let path = match path.split_once('/') {
+        Some(("ipfs", rest)) | Some(("ipns", rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        _ => path,
+    };
+    percent_decode(path)
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn is_audio(path: &str) -> bool {
+    let path = path.to_lowercase();
+    [".flac", ".ogg", ".mp3", ".opus", ".wav"]
+        .iter()
+        .any(|ext| path.ends_with(ext))
+}
+
+/// Adds the downloads in `log` to `stats`
+///
+/// `format` has to capture the request path as `path`, and can capture the HTTP status as `status`.  Only requests
+/// with a 200 status are downloads: the 206 responses are parts of a file (usually a player seeking).
+pub fn import(stats: &mut Stats, season: &Season, log: &[u8], format: &Regex) -> ImportReport {
+    let mut report = ImportReport::default();
+    let digest = checksum::sha256_bytes(log);
+    if stats.imported_logs.contains(&digest) {
+        report.already_imported = true;
+        return report;
+    }
+
+    let files = published_files(season);
+    for line in String::from_utf8_lossy(log).lines().filter(|l| !l.trim().is_empty()) {
+        let captures = match format.captures(line) {
+            Some(captures) => captures,
+            None => {
+                report.unparsed += 1;
+                continue;
+            }
+        };
+        report.requests += 1;
+        if captures.name("status").is_some_and(|s| s.as_str() != "200") {
+            continue;
+        }
+        let path = published_path(captures.name("path").map_or("", |p| p.as_str()));
+        match files.get(&path) {
+            Some((folder, id)) => {
+                *stats
+                    .downloads
+                    .entry(folder.to_string())
+                    .or_default()
+                    .entry(*id)
+                    .or_default() += 1;
+                report.counted += 1;
+            }
+            None if is_audio(&path) => *report.unmatched.entry(path).or_default() += 1,
+            None => report.other_files += 1,
+        }
+    }
+    stats.imported_logs.insert(digest);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            published_path("/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/jam%201/ogg/a.ogg?filename=a.ogg"),
+            "jam 1/ogg/a.ogg"
+        );
+        assert_eq!(published_path("/ipns/mm.em32.net/jam1/a.flac"), "jam1/a.flac");
+        // a subdomain gateway has the root in the host name
+        assert_eq!(published_path("/jam1/a.flac"), "jam1/a.flac");
+        assert_eq!(
+            published_path("/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh"),
+            ""
+        );
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(thousands(7), "7");
+        assert_eq!(thousands(1234), "1,234");
+        assert_eq!(thousands(1_234_567), "1,234,567");
+        assert_eq!(downloaded(1234), "downloaded 1,234 times");
+        assert_eq!(downloaded(1), "downloaded once");
+        assert_eq!(downloaded(0), "");
+    }
+}
@@ -0,0 +1,180 @@
+//! Tags of recordings
+//!
+//! In the JSON a tag is just a string, but it ends up as text on the pages, in the season index's filter (and later
+//! URLs), and as a category in feeds.  A [`Tag`] keeps the text to show apart from its [`slug`](crate::slug::slugify),
+//! which is what's used everywhere else.  Tags with the same slug are the same tag, however they're written.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{error::CbError, types::Recording};
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tag {
+    /// What the pages show, with its spaces tidied up
+    pub display: String,
+    /// For ids, URLs and feed categories
+    pub slug: String,
+}
+
+impl Tag {
+    /// Makes a tag out of a string from a recording's JSON.  Fails if there's nothing in it to make a slug from
+    pub fn new(text: &str, recording: &str) -> Result<Tag, CbError> {
+        let display = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let slug = crate::slug::slugify(&display);
+        if slug.is_empty() {
+            return Err(CbError::InvalidTag {
+                tag: text.to_string(),
+                recording: recording.to_string(),
+            });
+        }
+        Ok(Tag { display, slug })
+    }
+}
+
+impl std::fmt::Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.display)
+    }
+}
+
+/// Metadata from before tags had slugs has plain strings
+impl<'de> Deserialize<'de> for Tag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Plain(String),
+            Full { display: String, slug: String },
+        }
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Plain(display) => Tag {
+                slug: crate::slug::slugify(&display),
+                display,
+            },
+            Stored::Full { display, slug } => Tag { display, slug },
+        })
+    }
+}
+
+/// The tags of a recording, from its JSON, without the ones that repeat an earlier tag
+pub fn from_json(tags: &[String], recording: &str) -> Result<Vec<Tag>, CbError> {
+    let mut unique: Vec<Tag> = Vec::new();
+    for text in tags {
+        let tag = Tag::new(text, recording)?;
+        if !unique.iter().any(|t| t.slug == tag.slug) {
+            unique.push(tag);
+        }
+    }
+    Ok(unique)
+}
+
+/// Makes every recording show each tag the same way, and returns a warning for each tag that was written differently
+///
+/// The way most recordings write a tag wins, or the first of those in the season on a tie.
+pub fn unify(recordings: &mut [Recording]) -> Vec<String> {
+    // by slug, then by how it's written: the recordings that write it that way
+    let mut variants: BTreeMap<String, Vec<(String, Vec<String>)>> = BTreeMap::new();
+    for rec in recordings.iter() {
+        for tag in &rec.tags {
+            let ways = variants.entry(tag.slug.clone()).or_default();
+            match ways.iter_mut().find(|(display, _)| *display == tag.display) {
+                Some((_, users)) => users.push(rec.title.clone()),
+                None => ways.push((tag.display.clone(), vec![rec.title.clone()])),
+            }
+        }
+    }
+
+    let mut warnings = Vec::new();
+    for (slug, ways) in variants {
+        if ways.len() < 2 {
+            continue;
+        }
+        // max_by_key keeps the last of equals, so go backwards to keep the first
+        let chosen = ways
+            .iter()
+            .rev()
+            .max_by_key(|(_, users)| users.len())
+            .map(|(display, _)| display.clone())
+            .unwrap_or_default();
+        let listed: Vec<String> = ways
+            .iter()
+            .map(|(display, users)| format!("{:?} ({})", display, users.join(", ")))
+            .collect();
+        warnings.push(format!(
+            "The tags {} are all the same tag, so they're all shown as {:?}",
+            listed.join(" and "),
+            chosen
+        ));
+        for tag in recordings.iter_mut().flat_map(|rec| rec.tags.iter_mut()) {
+            if tag.slug == slug {
+                tag.display = chosen.clone();
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let tag = Tag::new("  Acid   Techno ", "jam").unwrap();
+        assert_eq!(tag.display, "Acid Techno");
+        assert_eq!(tag.slug, "acid-techno");
+        assert!(matches!(Tag::new("!!!", "jam"), Err(CbError::InvalidTag { .. })));
+
+        let tags = from_json(
+            &["Techno".to_string(), "techno".to_string(), "house".to_string()],
+            "jam",
+        )
+        .unwrap();
+        assert_eq!(
+            tags.iter().map(|t| t.slug.as_str()).collect::<Vec<_>>(),
+            ["techno", "house"]
+        );
+
+        // old metadata has plain strings
+        let old: Vec<Tag> = serde_json::from_str(r#"["Acid Techno", {"display": "House", "slug": "house"}]"#).unwrap();
+        assert_eq!(old[0], tag);
+        assert_eq!(old[1].slug, "house");
+        assert_eq!(
+            serde_json::to_value(&tag).unwrap(),
+            serde_json::json!({"display": "Acid Techno", "slug": "acid-techno"})
+        );
+    }
+
+    #[test]
+    fn variants() {
+        let recording = |title: &str, tags: &[&str]| -> Recording {
+            let track = serde_json::json!({
+                "id": 1, "name": "Stereo mix", "flac": "mix.flac", "vorbis": null, "mp3": null, "patch_notes": null,
+                "media_info": {"@type": "Audio", "Format": "FLAC", "Channels": "2", "SamplingRate": "48000",
+                    "BitDepth": "24", "Duration": "1.0"},
+                "flac_bytes": 0, "ogg_bytes": 0, "mp3_bytes": 0
+            });
+            serde_json::from_value(serde_json::json!({
+                "title": title, "data_folder": title, "stereo_mix": track, "recorded_date": "2021/01/01",
+                "torrent": null, "tracks": [], "tags": tags, "bpm": null, "youtube_url": null
+            }))
+            .unwrap()
+        };
+        let mut recordings = vec![
+            recording("jam1", &["Techno"]),
+            recording("jam2", &["techno", "house"]),
+            recording("jam3", &["techno"]),
+        ];
+        let warnings = unify(&mut recordings);
+        assert_eq!(
+            warnings,
+            [
+                r#"The tags "Techno" (jam1) and "techno" (jam2, jam3) are all the same tag, so they're all shown as "techno""#
+            ]
+        );
+        assert_eq!(recordings[0].tags[0].display, "techno");
+        assert_eq!(recordings[1].tags[1].display, "house");
+    }
+}
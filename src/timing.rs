@@ -0,0 +1,171 @@
+//! How long each stage of a run took
+//!
+//! Stages are timed by [`RunContext::stage`](crate::context::RunContext::stage), and the slow parts of conversion and
+//! patching record each item they process, so that we can show where the time goes at the end of a run.
+
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::progress::Stage;
+
+/// How many of the slowest items are shown for each stage
+pub const SLOWEST_ITEMS: usize = 5;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StageTiming {
+    pub stage: Stage,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ItemTiming {
+    pub stage: Stage,
+    pub item: String,
+    pub duration_ms: u64,
+}
+
+#[derive(Default, Debug)]
+struct TimingData {
+    stages: Vec<StageTiming>,
+    items: Vec<ItemTiming>,
+}
+
+/// Collects timings for a run.  Clones share the same data
+#[derive(Default, Debug, Clone)]
+pub struct Timings {
+    data: Arc<Mutex<TimingData>>,
+}
+
+impl Timings {
+    pub fn record_stage(&self, stage: Stage, duration: Duration) {
+        self.data.lock().unwrap().stages.push(StageTiming {
+            stage,
+            duration_ms: duration.as_millis() as u64,
+        });
+    }
+
+    pub fn record_item(&self, stage: Stage, item: impl Into<String>, duration: Duration) {
+        self.data.lock().unwrap().items.push(ItemTiming {
+            stage,
+            item: item.into(),
+            duration_ms: duration.as_millis() as u64,
+        });
+    }
+
+    /// All the stages that were run, in the order they finished
+    pub fn stages(&self) -> Vec<StageTiming> {
+        self.data.lock().unwrap().stages.clone()
+    }
+
+    /// The slowest [`SLOWEST_ITEMS`] items of every stage, slowest first
+    pub fn slowest_items(&self) -> Vec<ItemTiming> {
+        let data = self.data.lock().unwrap();
+        let mut slowest = Vec::new();
+        for stage in data.stages.iter().map(|s| s.stage) {
+            if slowest.iter().any(|i: &ItemTiming| i.stage == stage) {
+                continue;
+            }
+            let mut items: Vec<_> = data.items.iter().filter(|i| i.stage == stage).cloned().collect();
+            items.sort_by_key(|i| std::cmp::Reverse(i.duration_ms));
+            slowest.extend(items.into_iter().take(SLOWEST_ITEMS));
+        }
+        slowest
+    }
+
+    /// A human-readable table of the stage durations, and the slowest items
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        let stages = self.stages();
+        if stages.is_empty() {
+            return out;
+        }
+        let _ = writeln!(out, "Timings:");
+        for s in &stages {
+            let _ = writeln!(out, "  {:<10} {}", format!("{:?}", s.stage), format_ms(s.duration_ms));
+        }
+        let total: u64 = stages.iter().map(|s| s.duration_ms).sum();
+        let _ = writeln!(out, "  {:<10} {}", "Total", format_ms(total));
+
+        let slowest = self.slowest_items();
+        let mut shown = Vec::new();
+        for stage in stages.iter().map(|s| s.stage) {
+            let items: Vec<_> = slowest.iter().filter(|i| i.stage == stage).collect();
+            if items.is_empty() || shown.contains(&stage) {
+                continue;
+            }
+            shown.push(stage);
+            let _ = writeln!(out, "Slowest items in {:?}:", stage);
+            for item in items {
+                let _ = writeln!(out, "  {:>9}  {}", format_ms(item.duration_ms), item.item);
+            }
+        }
+        out
+    }
+}
+
+fn format_ms(ms: u64) -> String {
+    if ms < 60_000 {
+        format!("{}s", crate::one_decimal(ms, 1000))
+    } else {
+        format!("{}m {}s", ms / 60_000, (ms % 60_000) / 1000)
+    }
+}
+
+/// `YYYY-MM-DD HH:MM` for a Unix timestamp
+pub fn utc_time(secs: u64) -> String {
+    // days to a civil date, from Howard Hinnant's date algorithms
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        assert_eq!(utc_time(0), "1970-01-01 00:00");
+        assert_eq!(utc_time(951_782_400), "2000-02-29 00:00");
+        assert_eq!(utc_time(1_700_000_000), "2023-11-14 22:13");
+    }
+
+    #[test]
+    fn slowest() {
+        let timings = Timings::default();
+        for i in 0..8 {
+            timings.record_item(Stage::Convert, format!("{}.ogg", i), Duration::from_millis(i * 100));
+        }
+        timings.record_item(Stage::Patch, "index.html", Duration::from_millis(5));
+        timings.record_stage(Stage::Convert, Duration::from_secs(75));
+        timings.record_stage(Stage::Patch, Duration::from_millis(250));
+
+        let slowest = timings.slowest_items();
+        let names: Vec<_> = slowest.iter().map(|i| i.item.as_str()).collect();
+        assert_eq!(names, ["7.ogg", "6.ogg", "5.ogg", "4.ogg", "3.ogg", "index.html"]);
+
+        let table = timings.table();
+        assert!(table.contains("Convert    1m 15s"));
+        assert!(table.contains("Total      1m 15s"));
+        assert!(table.contains("Slowest items in Patch:"));
+    }
+}
@@ -1,118 +1,553 @@
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     path::{Path, PathBuf},
 };
 
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 
-use crate::MediaInfo;
+#[cfg(feature = "cli")]
+use colored::Colorize;
+
+#[cfg(not(feature = "cli"))]
+use crate::plain::Colorize;
+use crate::{
+    analysis::LoudnessInfo,
+    completeness::Completeness,
+    context::RunContext,
+    dates,
+    error::CbError,
+    mirror::{Mirror, MirrorLinks},
+    progress::{self, ProgressEvent, Stage},
+    related, slug,
+    tag::{self, Tag},
+    MediaInfo,
+};
 
 #[derive(Deserialize, Debug)]
 /// This is the raw JSON struct
 pub(crate) struct SeasonInner {
     #[serde(rename = "$schema")]
-    schema: String,
+    pub schema: String,
     pub title: String,
-    pub recordings: Vec<String>,
+    /// Who made the recordings, unless a recording says otherwise
+    #[serde(default)]
+    pub artist: Option<String>,
+    pub recordings: Vec<RecordingEntry>,
+    #[serde(default)]
+    pub low_quality_ogg: Option<OggProfile>,
+    /// Offered for every recording
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+}
+
+/// How to make the smaller ogg of each stereo mix that the pages stream (`low_quality_ogg` in season.json)
+///
+/// It's made next to the full-quality ogg as `<FLACBASE>.lq.ogg`, which stays the download.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OggProfile {
+    /// Vorbis quality, from -1 to 10 (ffmpeg's `-q:a`)
+    #[serde(default)]
+    pub quality: Option<f32>,
+    /// Average bitrate, in place of a quality (ffmpeg's `-b:a`)
+    #[serde(default)]
+    pub bitrate_kbps: Option<u32>,
+}
+
+/// The playlist that streams the low-quality oggs, next to playlist.m3u
+pub const LQ_PLAYLIST: &str = "playlist.lq.m3u";
+
+impl OggProfile {
+    /// The ffmpeg options that pick the encoder and its settings
+    pub fn encoder_args(&self) -> Vec<String> {
+        let mut args = vec!["-c:a".to_string(), "libvorbis".to_string()];
+        if let Some(quality) = self.quality {
+            args.extend(["-q:a".to_string(), quality.to_string()]);
+        }
+        if let Some(kbps) = self.bitrate_kbps {
+            args.extend(["-b:a".to_string(), format!("{}k", kbps)]);
+        }
+        args
+    }
+}
+
+/// An entry in the recordings array of season.json (or of an include file)
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum RecordingEntry {
+    /// A recording JSON, relative to the file that lists it
+    Path(String),
+    /// `{"include": "2021-q1.json"}`: another file with a recordings array, relative to the file that lists it
+    Include { include: String },
+}
+
+/// The contents of an include file
+#[derive(Deserialize, Debug)]
+struct RecordingList {
+    recordings: Vec<RecordingEntry>,
+}
+
+/// How many include files can be nested inside each other
+pub const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// A recording JSON listed in season.json, either directly or through include files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ListedRecording {
+    /// As it's written in the file that lists it
+    pub name: String,
+    pub path: PathBuf,
+    /// season.json, then the include files it was found through (just season.json if it wasn't included)
+    pub chain: Vec<PathBuf>,
+}
+
+impl ListedRecording {
+    /// Says which include files a recording came from, if it failed to load
+    pub fn context(&self, e: CbError) -> CbError {
+        if self.chain.len() > 1 {
+            CbError::Included {
+                path: self.path.clone(),
+                chain: self.chain.clone(),
+                source: Box::new(e),
+            }
+        } else {
+            e
+        }
+    }
+}
+
+impl SeasonInner {
+    /// Every recording JSON in the season, in order, with the include files expanded in place
+    pub fn recording_paths(&self, season_json: &Path) -> Result<Vec<ListedRecording>, CbError> {
+        let mut listed = Vec::new();
+        expand_recordings(&self.recordings, &mut vec![season_json.to_path_buf()], &mut listed)?;
+        Ok(listed)
+    }
+}
+
+/// Adds the recordings in `entries` (from the last file in `chain`) to `listed`
+fn expand_recordings(
+    entries: &[RecordingEntry], chain: &mut Vec<PathBuf>, listed: &mut Vec<ListedRecording>,
+) -> Result<(), CbError> {
+    let root = chain
+        .last()
+        .and_then(|p| p.parent())
+        .unwrap_or_else(|| Path::new(""))
+        .to_owned();
+    for entry in entries {
+        match entry {
+            RecordingEntry::Path(name) => listed.push(ListedRecording {
+                name: name.clone(),
+                path: root.join(name),
+                chain: chain.clone(),
+            }),
+            RecordingEntry::Include { include } => {
+                let path = root.join(include);
+                let mut with_include = chain.clone();
+                with_include.push(path.clone());
+                let canonical = path.canonicalize().ok();
+                if canonical.is_some() && chain.iter().any(|p| p.canonicalize().ok() == canonical) {
+                    return Err(CbError::IncludeCycle { chain: with_include });
+                }
+                if chain.len() > MAX_INCLUDE_DEPTH {
+                    return Err(CbError::IncludeTooDeep {
+                        chain: with_include,
+                        limit: MAX_INCLUDE_DEPTH,
+                    });
+                }
+
+                let list: RecordingList = serde_json::from_value(crate::get_validated_json(&path)?)
+                    .map_err(|e| CbError::parse(format!("Unexpected contents in {}", path.display()), e))?;
+                chain.push(path);
+                expand_recordings(&list.recordings, chain, listed)?;
+                chain.pop();
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Season {
+    /// The build of cb_processor that produced this (see [`crate::GENERATOR`])
+    #[serde(default)]
+    pub generator: String,
     pub title: String,
+    /// Who made the recordings (see [`Recording::artist`] for who made each one).  Empty in metadata from older versions
+    #[serde(default)]
+    pub artist: String,
     pub recordings: Vec<Recording>,
+    /// Old slugs, and the slugs they were changed to, so that old links keep working
+    #[serde(default)]
+    pub redirects: BTreeMap<String, String>,
+    /// The low-quality oggs for streaming, if the season has them
+    #[serde(default)]
+    pub low_quality_ogg: Option<OggProfile>,
+    /// Recordings that `--keep-going` left out because they couldn't be loaded.  Never saved in the metadata
+    #[serde(skip)]
+    pub skipped: Vec<SkippedRecording>,
     //pub(crate) ondisk_root: PathBuf,
 }
 
+/// A recording that couldn't be loaded, and was left out of the season instead of failing it (`--keep-going`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedRecording {
+    /// The recording JSON
+    pub path: PathBuf,
+    /// The recording's title, if the JSON is readable enough to have one
+    pub title: Option<String>,
+    pub error: String,
+}
+
+impl SkippedRecording {
+    fn new(path: &Path, error: CbError) -> SkippedRecording {
+        let title = std::fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+            .and_then(|json| json.get("title")?.as_str().map(str::to_string));
+        SkippedRecording {
+            path: path.to_path_buf(),
+            title,
+            error: format!("{:#}", anyhow::Error::new(error)),
+        }
+    }
+
+    /// What to call the recording on the season index
+    pub fn name(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            self.path
+                .file_stem()
+                .map_or_else(String::new, |stem| stem.to_string_lossy().to_string())
+        })
+    }
+}
+
 impl Season {
     pub fn load<P: AsRef<Path>>(
-        json: P, ondisk_root: Option<&Path>, cache: Option<&Season>,
-    ) -> Result<Self, anyhow::Error> {
-        let json = json.as_ref();
-        let json_root = json.parent().unwrap();
+        ctx: &RunContext, json: P, ondisk_root: Option<&Path>, cache: Option<&Season>,
+    ) -> Result<Self, CbError> {
+        Season::load_with(ctx, json.as_ref(), ondisk_root, cache)
+    }
 
-        let inner = crate::get_validated_json(json)?;
-        let inner: SeasonInner = serde_json::from_value(inner)?;
+    /// Loads the recordings of a season that are in a partly read cache, without the data dir
+    ///
+    /// Only the recordings that were read into `cache` (see
+    /// [`chunked_metadata::load_selected`](crate::chunked_metadata::load_selected)) are in the season.
+    pub fn load_selected<P: AsRef<Path>>(ctx: &RunContext, json: P, cache: &Season) -> Result<Self, CbError> {
+        Season::load_with(ctx, json.as_ref(), None, Some(cache))
+    }
 
-        let mut recordings = Vec::new();
+    fn load_with(
+        ctx: &RunContext, json: &Path, ondisk_root: Option<&Path>, cache: Option<&Season>,
+    ) -> Result<Self, CbError> {
+        let inner = crate::get_validated_json(json)?;
+        let inner: SeasonInner = serde_json::from_value(inner)
+            .map_err(|e| CbError::parse(format!("Unexpected contents in {}", json.display()), e))?;
 
-        if let Some(cache) = cache {
-            for (rec_path, cache) in inner.recordings.iter().zip(cache.recordings.iter()) {
-                let recording = Recording::load(&json_root.join(rec_path), ondisk_root, Some(cache))?;
-                recordings.push(recording);
+        // each recording's JSON is read and checked against its schema once, here.  A recording that can't even be
+        // checked for being a draft fails to load like any other broken recording
+        let mut rec_jsons = Vec::new();
+        for listed in inner.recording_paths(json)? {
+            match crate::get_validated_json(&listed.path) {
+                Ok(rec_json) if is_draft(&rec_json) => {
+                    if cache.is_none() && is_included_draft(ctx, &rec_json) {
+                        rec_jsons.push((listed, Ok(rec_json)));
+                    }
+                }
+                rec_json => rec_jsons.push((listed, rec_json)),
             }
-        } else {
-            for rec_path in &inner.recordings {
-                let recording = Recording::load(&json_root.join(rec_path), ondisk_root, None)?;
-                recordings.push(recording);
+        }
+
+        let mut recordings = Vec::new();
+        let mut skipped = Vec::new();
+        for (listed, rec_json) in rec_jsons {
+            // the cache is matched up by data_folder, since drafts and recordings that failed to load aren't in it.  A
+            // recording that isn't in it (like a draft that was published since) can't be loaded without the audio
+            let cache = match (cache, &rec_json) {
+                (Some(cache), Ok(rec_json)) => match cached_recording(cache, rec_json) {
+                    Some(cached) => Some(cached),
+                    None => continue,
+                },
+                _ => None,
+            };
+            let loaded =
+                rec_json.and_then(|rec_json| Recording::from_json(ctx, &listed.path, rec_json, ondisk_root, cache));
+            let loaded = loaded.and_then(|mut rec| {
+                if inner.low_quality_ogg.is_some() {
+                    rec.stereo_mix.add_lq_ogg(cache.map(|c| &c.stereo_mix));
+                }
+                rec.mirrors.splice(0..0, inner.mirrors.iter().cloned());
+                if rec.artist.is_empty() {
+                    rec.artist = inner.artist.clone().ok_or_else(|| CbError::MissingArtist {
+                        recording: rec.title.clone(),
+                    })?;
+                }
+                Ok(rec)
+            });
+            match loaded {
+                Ok(rec) => recordings.push(rec),
+                Err(e) if ctx.keep_going => {
+                    let skip = SkippedRecording::new(&listed.path, listed.context(e));
+                    say!("Skipping {}: {}", listed.name, skip.error);
+                    skipped.push(skip);
+                }
+                Err(e @ CbError::MissingArtist { .. }) => return Err(e),
+                Err(e) => return Err(listed.context(e)),
             }
         }
+        ctx.record_skipped(&skipped);
 
-        Ok(Season {
+        let mut season = Season {
+            generator: crate::GENERATOR.to_string(),
             title: inner.title,
+            artist: inner.artist.unwrap_or_default(),
             recordings,
+            redirects: BTreeMap::new(),
+            low_quality_ogg: inner.low_quality_ogg,
+            skipped,
             //ondisk_root: ondisk_root.to_owned(),
-        })
+        };
+        if let Some(cache) = cache {
+            season.carry_over(cache);
+        }
+        season.check_slugs()?;
+        let mut warnings = tag::unify(&mut season.recordings);
+        warnings.extend(related::resolve(&mut season.recordings));
+        for warning in warnings {
+            say!("{}: {}", "WARNING".yellow(), warning);
+            progress::emit(ProgressEvent::Warning {
+                stage: Stage::Load,
+                message: warning,
+            });
+        }
+        Ok(season)
     }
+
+    /// Keeps what's in an earlier run's metadata that can't be found again: the archive.org links (which only
+    /// `--archive-upload` finds), and the slugs that recordings used to have (which become redirects)
+    pub fn carry_over(&mut self, cached: &Season) {
+        for (old, new) in &cached.redirects {
+            self.redirects.entry(old.clone()).or_insert_with(|| new.clone());
+        }
+        for rec in &mut self.recordings {
+            let old = match cached.recordings.iter().find(|c| c.data_folder == rec.data_folder) {
+                Some(old) => old,
+                None => continue,
+            };
+            if rec.archive_org_url.is_none() {
+                rec.archive_org_url = old.archive_org_url.clone();
+            }
+            // metadata from before slugs existed used the data_folder in URLs
+            let old_slug = if old.slug.is_empty() {
+                &old.data_folder
+            } else {
+                &old.slug
+            };
+            if *old_slug != rec.slug {
+                self.redirects.insert(old_slug.clone(), rec.slug.clone());
+            }
+        }
+
+        // follow chains of renames, and forget redirects away from slugs that are in use again
+        let current: Vec<&str> = self.recordings.iter().map(|r| r.slug.as_str()).collect();
+        self.redirects.retain(|old, _| !current.contains(&old.as_str()));
+        let redirects = self.redirects.clone();
+        for new in self.redirects.values_mut() {
+            let mut hops = 0;
+            while let Some(next) = redirects.get(new.as_str()) {
+                *new = next.clone();
+                hops += 1;
+                if hops > redirects.len() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Fails if two recordings would have their pages in the same folder (see [`slug::check`])
+    pub fn check_slugs(&self) -> Result<(), CbError> {
+        let pages: Vec<(&str, &str)> = self
+            .recordings
+            .iter()
+            .map(|r| (r.slug.as_str(), r.data_folder.as_str()))
+            .collect();
+        slug::check(&pages)
+    }
+}
+
+/// The cached version of `track`, unless its flac has been renamed since
+///
+/// A renamed flac is usually a new file (or at least a fixed one), so nothing about it can be taken from the cache.
+fn cached_track<'a>(data_folder: &str, track: &TrackInner, cached: Option<&'a Track>) -> Option<&'a Track> {
+    let cached = cached?;
+    if cached.flac != track.flac {
+        say!(
+            "{}: track {} renamed from {} to {}, refreshing",
+            data_folder,
+            track.id,
+            cached.flac,
+            track.flac
+        );
+        return None;
+    }
+    Some(cached)
+}
+
+/// A recording's description can be kept in this file in its data folder, instead of in the JSON
+pub const DESCRIPTION_FILE: &str = "README.md";
+
+/// The [`DESCRIPTION_FILE`] in `folder`, if there is one
+pub(crate) fn read_description(folder: &Path) -> Result<Option<String>, CbError> {
+    let path = folder.join(DESCRIPTION_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(description) => Ok(Some(description)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(CbError::io(format!("Failed to read {}", path.display()), e)),
+    }
+}
+
+/// Draft recordings don't have any audio files yet, so they are left out of the loaded Season
+pub(crate) fn is_draft(inner: &serde_json::Value) -> bool {
+    inner.get("draft").and_then(|d| d.as_bool()).unwrap_or(false)
+}
+
+/// The recording of `cache` with the same data_folder as the recording JSON `inner`
+fn cached_recording<'a>(cache: &'a Season, inner: &serde_json::Value) -> Option<&'a Recording> {
+    let data_folder = inner.get("data_folder").and_then(|d| d.as_str());
+    cache
+        .recordings
+        .iter()
+        .find(|c| Some(c.data_folder.as_str()) == data_folder)
+}
+
+/// Whether the draft with the JSON `inner` is loaded anyway, because of [`RunContext::include_drafts`]
+fn is_included_draft(ctx: &RunContext, inner: &serde_json::Value) -> bool {
+    if !ctx.include_drafts {
+        return false;
+    }
+    ["data_folder", "title"]
+        .iter()
+        .filter_map(|key| inner.get(*key).and_then(|name| name.as_str()))
+        .any(|name| ctx.only.matches_name(name))
 }
 
 #[derive(Deserialize, Debug)]
 pub(crate) struct RecordingInner {
     #[serde(rename = "$schema")]
+    #[allow(dead_code)]
     schema: String,
 
     pub title: String,
     pub data_folder: String,
+    /// Name in URLs; made from the title if it isn't given
+    #[serde(default)]
+    pub slug: Option<String>,
     pub stereo_mix: TrackInner,
     pub recorded_date: String,
+    /// In place of the season's artist, for guest-hosted sessions
+    #[serde(default)]
+    pub artist: Option<String>,
     pub youtube_url: Option<String>,
     pub torrent: Option<String>,
     pub bpm: Option<String>,
     pub tracks: Vec<TrackInner>,
     pub tags: Vec<String>,
+    #[serde(default)]
+    pub draft: bool,
+    /// Markdown, shown above the tracks (or put in a README.md in the data folder instead)
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Terms for this recording, in place of the global ToS.txt (relative to the data folder)
+    #[serde(default)]
+    pub tos: Option<String>,
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+    /// Other takes of the same patch, by data_folder or slug (see [`crate::related`])
+    #[serde(default)]
+    pub related: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Recording {
     pub title: String,
     pub data_folder: String,
+    /// Name of the recording's page (see [`crate::slug`]).  Empty in metadata from before slugs
+    #[serde(default)]
+    pub slug: String,
     pub stereo_mix: Track,
     pub recorded_date: String,
+    /// Who made it: the recording's own artist, or else the season's (which [`Season::load`] fills in, so this is empty
+    /// when a recording without its own is loaded by itself)
+    #[serde(default)]
+    pub artist: String,
     pub torrent: Option<String>,
     pub tracks: Vec<Track>,
-    pub tags: Vec<String>,
+    pub tags: Vec<Tag>,
     pub bpm: Option<String>,
     pub youtube_url: Option<String>,
+    /// Item page on archive.org, filled in by `--archive-upload`
+    #[serde(default)]
+    pub archive_org_url: Option<String>,
+    /// Markdown, from the JSON or the data folder's [`DESCRIPTION_FILE`]
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Terms for this recording that are published as its ToS.txt, relative to the data folder
+    #[serde(default)]
+    pub tos: Option<String>,
+    /// Which of the [`Facet`](crate::completeness::Facet)s the recording has, worked out when it's loaded
+    #[serde(default)]
+    pub completeness: Completeness,
+    /// Where else the files can be downloaded from: the season's mirrors, then the recording's own
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+    /// The slugs of the other takes of the same patch, whichever side named the other (which [`Season::load`] works
+    /// out, so this is what the JSON says when a recording is loaded by itself)
+    #[serde(default)]
+    pub related: Vec<String>,
     //ondisk_root: PathBuf,
 }
 impl Recording {
     /// Load info about a recording, given a path to its json file
     pub fn load<P: AsRef<Path>>(
-        json: P, ondisk_root: Option<&Path>, cache: Option<&Recording>,
-    ) -> Result<Self, anyhow::Error> {
+        ctx: &RunContext, json: P, ondisk_root: Option<&Path>, cache: Option<&Recording>,
+    ) -> Result<Self, CbError> {
         let json = json.as_ref();
-        let _json_root = json.parent().unwrap();
+        Recording::from_json(ctx, json, crate::get_validated_json(json)?, ondisk_root, cache)
+    }
 
-        let inner = crate::get_validated_json(json)?;
-        let inner: RecordingInner = serde_json::from_value(inner)?;
+    /// [`Recording::load`], from the contents of `json` that were already read and validated
+    fn from_json(
+        ctx: &RunContext, json: &Path, inner: serde_json::Value, ondisk_root: Option<&Path>, cache: Option<&Recording>,
+    ) -> Result<Self, CbError> {
+        let inner: RecordingInner = serde_json::from_value(inner)
+            .map_err(|e| CbError::parse(format!("Unexpected contents in {}", json.display()), e))?;
 
         let ondisk_root = ondisk_root.map(|p| p.join(&inner.data_folder));
 
+        // without the audio, the README can only be known from an earlier run
+        let description = match (&inner.description, &ondisk_root) {
+            (Some(description), _) => Some(description.clone()),
+            (None, Some(folder)) => read_description(folder)?,
+            (None, None) => cache.and_then(|c| c.description.clone()),
+        };
+
+        let data_folder = &inner.data_folder;
         let tracks = if let Some(cache) = cache {
             // gotta find the corresponding track from the cache
             inner
                 .tracks
                 .into_iter()
                 .map(|tr| {
-                    let tr_id = tr.id;
-                    Track::from_inner(tr, ondisk_root.as_deref(), cache.tracks.iter().find(|t| t.id == tr_id)).unwrap()
+                    let cached = cached_track(data_folder, &tr, cache.tracks.iter().find(|t| t.id == tr.id));
+                    Track::from_inner(ctx, tr, ondisk_root.as_deref(), cached)
                 })
-                .collect()
+                .collect::<Result<_, _>>()?
         } else {
             inner
                 .tracks
                 .into_iter()
-                .map(|tr| Track::from_inner(tr, ondisk_root.as_deref(), None).unwrap())
-                .collect()
+                .map(|tr| Track::from_inner(ctx, tr, ondisk_root.as_deref(), None))
+                .collect::<Result<_, _>>()?
         };
         // let tracks = inner
         //     .tracks
@@ -120,86 +555,220 @@ impl Recording {
         //     .map(|tr| Track::from_inner(tr, &ondisk_root).unwrap())
         //     .collect();
 
-        let stereo_mix = Track::from_inner(
-            inner.stereo_mix,
-            ondisk_root.as_deref(),
+        let slug = slug::for_recording(inner.slug.as_deref(), &inner.title, &inner.data_folder);
+
+        let cached = cached_track(
+            &inner.data_folder,
+            &inner.stereo_mix,
             cache.as_ref().map(|c| &c.stereo_mix),
-        )?;
+        );
+        let stereo_mix = Track::from_inner(ctx, inner.stereo_mix, ondisk_root.as_deref(), cached)?;
 
-        Ok(Recording {
+        let tags = tag::from_json(&inner.tags, &inner.title)?;
+        let mut recording = Recording {
+            slug,
             title: inner.title,
             data_folder: inner.data_folder,
             stereo_mix,
             recorded_date: inner.recorded_date,
+            artist: inner.artist.unwrap_or_default(),
             youtube_url: inner.youtube_url,
             torrent: inner.torrent,
             bpm: inner.bpm,
             tracks,
-            tags: inner.tags,
+            tags,
+            archive_org_url: cache.and_then(|c| c.archive_org_url.clone()),
+            description,
+            tos: inner.tos,
+            completeness: Completeness::default(),
+            mirrors: inner.mirrors,
+            related: inner.related,
             //ondisk_root: ondisk_root.to_owned(),
-        })
+        };
+        recording.completeness = Completeness::of(&recording);
+        Ok(recording)
+    }
+    /// Where the page's relative links point to, when the page isn't in the data folder
+    ///
+    /// The page lives in a folder named after the slug, but the audio (and everything else the page links to) is in
+    /// the data folder.
+    pub fn page_base(&self) -> Option<String> {
+        if self.slug == self.data_folder {
+            None
+        } else {
+            Some(format!("../{}/", slug::encode_path_segment(&self.data_folder)))
+        }
+    }
+
+    /// Each mirror's links to the flacs of the stereo mix and the tracks that can be downloaded
+    pub fn mirror_links(&self) -> Vec<MirrorLinks<'_>> {
+        self.mirrors
+            .iter()
+            .map(|mirror| MirrorLinks {
+                label: &mirror.label,
+                files: std::iter::once(&self.stereo_mix)
+                    .chain(self.tracks.iter().filter(|t| t.downloadable()))
+                    .map(|track| {
+                        let name = track.flac.rsplit('/').next().unwrap_or_default().to_string();
+                        (name, mirror.expand(&self.data_folder, &track.flac))
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// The HTML id of the recording's row on the season index, for links like `#rec-first-jam`
+    ///
+    /// Like the track anchors, it comes from the slug rather than the order of the recordings, so that links keep
+    /// working as the season grows.
+    pub fn anchor(&self) -> String {
+        format!("rec-{}", self.slug)
+    }
+
+    /// The tracks, under their group headings
+    ///
+    /// Groups are sorted by name, with tracks that have no group last (under "Other"), and the tracks in each group are
+    /// sorted by id.  If no track has a group, this is a single list of every track with no heading, in the order of
+    /// the JSON.
+    pub fn track_groups(&self) -> Vec<TrackGroup<'_>> {
+        if self.tracks.iter().all(|t| t.group.is_none()) {
+            return vec![TrackGroup {
+                name: None,
+                tracks: self.tracks.iter().collect(),
+            }];
+        }
+        let mut tracks: Vec<&Track> = self.tracks.iter().collect();
+        // None sorts first, but ungrouped tracks go at the end
+        tracks.sort_by(|a, b| (a.group.is_none(), &a.group, a.id).cmp(&(b.group.is_none(), &b.group, b.id)));
+        let mut groups: Vec<TrackGroup> = Vec::new();
+        for track in tracks {
+            let name = track.group.as_deref().unwrap_or("Other");
+            match groups.last_mut() {
+                Some(group) if group.name == Some(name) => group.tracks.push(track),
+                _ => groups.push(TrackGroup {
+                    name: Some(name),
+                    tracks: vec![track],
+                }),
+            }
+        }
+        groups
     }
+
     pub fn format_info(&self) -> String {
-        let sample_rate: f32 = self.stereo_mix.media_info.sample_rate.parse().unwrap();
+        let media_info = &self.stereo_mix.media_info;
+        let (channels, sample_rate) = match (media_info.channels(), media_info.sample_rate()) {
+            (Ok(channels), Ok(sample_rate)) => (channels, sample_rate),
+            _ => return "unknown format".to_string(),
+        };
+
+        let mut info = format!("{}ch {}kHz", channels, crate::one_decimal(u64::from(sample_rate), 1000));
+        if let Some(bit_depth) = media_info.bit_depth() {
+            info.push_str(&format!(" {}bit", bit_depth));
+        }
+        info
+    }
 
-        format!(
-            "{}ch {:.1}kHz {}bit",
-            self.stereo_mix.media_info.channels,
-            sample_rate / 1000.0,
-            self.stereo_mix.media_info.bit_depth
-        )
+    /// The day it was recorded on, if `recorded_date` can be read (see [`crate::dates`])
+    pub fn recorded_on(&self) -> Option<NaiveDate> {
+        dates::parse(&self.recorded_date)
+    }
+
+    /// `recorded_date` in `format` (a strftime format), or as it's written if it can't be read
+    pub fn display_date(&self, format: &str) -> String {
+        dates::display(&self.recorded_date, format)
     }
 
     pub fn duration(&self) -> String {
-        let sec: f32 = self.stereo_mix.media_info.duration.parse().unwrap();
-        let sec = sec.floor() as u64;
+        let sec = match self.stereo_mix.media_info.duration_secs() {
+            Ok(sec) => sec.floor() as u64,
+            Err(_) => return "?".to_string(),
+        };
         if sec <= 59 {
             format!("{}s", sec)
         } else {
-            let min = (sec as f32 / 60.0).floor() as u64;
-            let sec = sec - (min * 60);
-            format!("{}m {}s", min, sec)
+            format!("{}m {}s", sec / 60, sec % 60)
         }
     }
 
+    /// The size of all the flacs that can be downloaded
     pub fn flac_size_str(&self) -> String {
         let total_bytes = self
             .tracks
             .iter()
+            .filter(|t| t.downloadable())
             .fold(self.stereo_mix.flac_size_bytes(), |v, t| v + t.flac_size_bytes());
         format!("{}MB", total_bytes / 1024 / 1024)
     }
 
+    /// The size of all the oggs that can be downloaded
     pub fn ogg_size_str(&self) -> String {
         let total_bytes = self
             .tracks
             .iter()
+            .filter(|t| t.downloadable())
             .fold(self.stereo_mix.ogg_size_bytes(), |v, t| v + t.ogg_size_bytes());
         format!("{}MB", total_bytes / 1024 / 1024)
     }
 }
 
+/// Tracks that share a `group`, see [`Recording::track_groups`]
+#[derive(Debug, Clone)]
+pub struct TrackGroup<'a> {
+    /// The heading to show, if any
+    pub name: Option<&'a str>,
+    pub tracks: Vec<&'a Track>,
+}
+
 /// This structure is loaded directly from the JSON files in the data directdory
 #[derive(Deserialize, Debug)]
 pub(crate) struct TrackInner {
     pub id: u8,
     pub name: String,
     pub flac: String,
-    vorbis: String,
+    /// Missing for archival recordings that only have the flacs
+    #[serde(default)]
+    vorbis: Option<String>,
     mp3: Option<String>,
+    #[serde(default)]
+    opus: Option<String>,
     pub patch_notes: Option<String>,
+    /// Heading to put the track under on the recording page (like "Drums")
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub usage: Usage,
+}
+
+/// What listeners may do with a track (`usage` in the recording's JSON)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Usage {
+    /// Download it and use it in remixes
+    #[default]
+    Full,
+    /// Only stream it: the stem has sampled material that can be shared for listening but not for remixing, so its
+    /// files aren't offered for download
+    ListenOnly,
 }
 
 impl TrackInner {
-    pub fn vorbis(&self) -> Cow<Path> {
-        if self.vorbis.contains("{FLACBASE}") {
-            let t = Path::new(&self.flac);
-            let base = t.file_stem().expect("No filestem on flac").to_string_lossy();
-            Cow::Owned(PathBuf::from(self.vorbis.replace("{FLACBASE}", &base)))
-        } else {
-            Cow::Borrowed(Path::new(&self.vorbis))
+    pub fn vorbis<'a>(&'a self) -> Option<Cow<'a, Path>> {
+        match &self.vorbis {
+            None => None,
+            Some(vorbis) if vorbis.contains("{FLACBASE}") => {
+                let t = Path::new(&self.flac);
+                let base = t.file_stem().expect("No filestem on flac").to_string_lossy();
+                Some(Cow::Owned(PathBuf::from(vorbis.replace("{FLACBASE}", &base))))
+            }
+            Some(vorbis) => Some(Cow::Borrowed(Path::new(vorbis.as_str()))),
         }
     }
+    /// Where the low-quality ogg would be, if this is a stereo mix with an ogg (see [`OggProfile`])
+    pub fn lq_ogg(&self) -> Option<PathBuf> {
+        let vorbis = self.vorbis()?;
+        let base = Path::new(&self.flac).file_stem()?.to_string_lossy();
+        Some(PathBuf::from(lq_ogg_path(&vorbis.to_string_lossy(), &base)))
+    }
     pub fn mp3<'a>(&'a self) -> Option<Cow<'a, Path>> {
         match &self.mp3 {
             None => None,
@@ -211,87 +780,318 @@ impl TrackInner {
             Some(mp3) => Some(Cow::Borrowed(Path::new(mp3.as_str()))),
         }
     }
+    pub fn opus<'a>(&'a self) -> Option<Cow<'a, Path>> {
+        match &self.opus {
+            None => None,
+            Some(opus) if opus.contains("{FLACBASE}") => {
+                let t = Path::new(&self.flac);
+                let base = t.file_stem().expect("No filestem on flac").to_string_lossy();
+                Some(Cow::Owned(PathBuf::from(opus.replace("{FLACBASE}", &base))))
+            }
+            Some(opus) => Some(Cow::Borrowed(Path::new(opus.as_str()))),
+        }
+    }
 }
 
 /// This structure is used to save the metadata.json files
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Track {
     pub id: u8,
     pub name: String,
     pub flac: String,
-    pub vorbis: String,
+    /// `None` if there is no public ogg, only the flac
+    pub vorbis: Option<String>,
     pub mp3: Option<String>,
+    /// Missing from metadata written before opus files were made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opus: Option<String>,
     pub patch_notes: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Missing from metadata written before it existed, when every track was [`Usage::Full`]
+    #[serde(default)]
+    pub usage: Usage,
 
     /// Folder on the current machine can this track be found
+    #[serde(serialize_with = "crate::serialize_slashed::option")]
     ondisk_root: Option<PathBuf>,
 
     /// Technical info about this track
     pub media_info: MediaInfo,
+    /// Technical info about the ogg and mp3 files, if they have been made
+    #[serde(default)]
+    pub ogg_info: Option<MediaInfo>,
+    #[serde(default)]
+    pub mp3_info: Option<MediaInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opus_info: Option<MediaInfo>,
+    /// Filled in by `--measure-loudness`
+    #[serde(default)]
+    pub loudness: Option<LoudnessInfo>,
+    /// Spectrogram image (relative to the data folder, like `vorbis`), if one has been made with `--spectrograms`
+    #[serde(default)]
+    pub spectrogram: Option<String>,
+    /// Waveform peaks (relative to the data folder, like `vorbis`), if they have been worked out with `--peaks`
+    #[serde(default)]
+    pub peaks: Option<String>,
+    /// The smaller ogg that's streamed in place of `vorbis`.  Only stereo mixes have one, when the season has an
+    /// [`OggProfile`]
+    #[serde(default)]
+    pub lq_ogg: Option<String>,
 
     pub flac_bytes: u64,
     pub ogg_bytes: u64,
     pub mp3_bytes: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub opus_bytes: u64,
+    #[serde(default)]
+    pub lq_ogg_bytes: u64,
+    /// The CID of each of the track's files, by their path in the data folder.  Only known for tracks that were
+    /// imported from a published root (see `import-from-ipfs`), and kept until the track is loaded from disk
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cids: BTreeMap<String, String>,
+    /// What `import-from-ipfs` couldn't find out about the track's files, and so is missing from the rest of this
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub import_warnings: Vec<String>,
 }
 
 impl Track {
+    /// The HTML id of the track's row on the recording page, for links like `#track-14`
+    pub fn anchor(&self) -> String {
+        format!("track-{}", self.id)
+    }
+
     pub(crate) fn from_inner(
-        inner: TrackInner, ondisk_root: Option<&Path>, cache: Option<&Track>,
-    ) -> Result<Self, anyhow::Error> {
-        let flac_bytes = ondisk_root
-            .and_then(|p| std::fs::metadata(p.join(&inner.flac)).ok())
-            .map(|md| md.len())
-            .unwrap_or_else(|| {
-                cache
-                    .map(|c| c.flac_bytes)
-                    .unwrap_or_else(|| panic!("Can't construct track for {:?}", inner))
-            });
+        ctx: &RunContext, inner: TrackInner, ondisk_root: Option<&Path>, cache: Option<&Track>,
+    ) -> Result<Self, CbError> {
+        let missing_flac = || CbError::MissingFile {
+            path: ondisk_root.map_or_else(|| PathBuf::from(&inner.flac), |p| p.join(&inner.flac)),
+        };
+        let flac_bytes = match ondisk_root.and_then(|p| std::fs::metadata(p.join(&inner.flac)).ok()) {
+            Some(md) => md.len(),
+            None => cache.map(|c| c.flac_bytes).ok_or_else(missing_flac)?,
+        };
 
         let ogg_bytes = ondisk_root
-            .and_then(|p| std::fs::metadata(p.join(&inner.vorbis())).ok())
+            .and_then(|p| inner.vorbis().and_then(|ogg| std::fs::metadata(p.join(ogg)).ok()))
             .map(|md| md.len())
             .unwrap_or_else(|| cache.map(|c| c.ogg_bytes).unwrap_or(0));
 
         let mp3_bytes = ondisk_root
             .and_then(|p| inner.mp3().and_then(|mp3| std::fs::metadata(p.join(mp3)).ok()))
             .map(|md| md.len())
-            .unwrap_or_else(|| cache.map(|c| c.ogg_bytes).unwrap_or(0));
+            .unwrap_or_else(|| cache.map(|c| c.mp3_bytes).unwrap_or(0));
+
+        let opus_bytes = ondisk_root
+            .and_then(|p| inner.opus().and_then(|opus| std::fs::metadata(p.join(opus)).ok()))
+            .map(|md| md.len())
+            .unwrap_or_else(|| cache.map(|c| c.opus_bytes).unwrap_or(0));
+
+        let media_info: MediaInfo = match ondisk_root {
+            Some(p) => MediaInfo::new(ctx, p.join(&inner.flac))?,
+            None => cache.map(|c| c.media_info.clone()).ok_or_else(missing_flac)?,
+        };
 
-        let media_info: MediaInfo = ondisk_root
-            .map(|p| MediaInfo::new(p.join(&inner.flac)).unwrap())
-            .unwrap_or_else(|| cache.map(|c| c.media_info.clone()).unwrap());
+        // the derived files might not have been converted yet, so it's fine if these are missing
+        let ogg_info = match ondisk_root
+            .and_then(|p| inner.vorbis().map(|ogg| p.join(ogg)))
+            .filter(|p| p.exists())
+        {
+            Some(ogg) => MediaInfo::new(ctx, ogg).ok(),
+            None => cache.and_then(|c| c.ogg_info.clone()),
+        };
+        let mp3_info = match ondisk_root
+            .and_then(|p| inner.mp3().map(|mp3| p.join(mp3)))
+            .filter(|p| p.exists())
+        {
+            Some(mp3) => MediaInfo::new(ctx, mp3).ok(),
+            None => cache.and_then(|c| c.mp3_info.clone()),
+        };
+        let opus_info = match ondisk_root
+            .and_then(|p| inner.opus().map(|opus| p.join(opus)))
+            .filter(|p| p.exists())
+        {
+            Some(opus) => MediaInfo::new(ctx, opus).ok(),
+            None => cache.and_then(|c| c.opus_info.clone()),
+        };
 
         let flac_basename = {
             let t = Path::new(&inner.flac);
             t.file_stem().expect("no flac file stem").to_string_lossy().to_string()
         };
+        let vorbis = inner.vorbis.map(|vorbis| vorbis.replace("{FLACBASE}", &flac_basename));
+
+        let spectrogram = spectrogram_path(vorbis.as_deref().unwrap_or(&inner.flac), &flac_basename);
+        let spectrogram = match ondisk_root {
+            Some(p) => Some(spectrogram).filter(|s| p.join(s).exists()),
+            None => cache.and_then(|c| c.spectrogram.clone()),
+        };
+        let peaks = peaks_path(vorbis.as_deref().unwrap_or(&inner.flac), &flac_basename);
+        let peaks = match ondisk_root {
+            Some(p) => Some(peaks).filter(|s| p.join(s).exists()),
+            None => cache.and_then(|c| c.peaks.clone()),
+        };
 
         Ok(Track {
             media_info,
+            ogg_info,
+            mp3_info,
+            opus_info,
+            loudness: cache.and_then(|c| c.loudness.clone()),
+            spectrogram,
+            peaks,
             id: inner.id,
             name: inner.name,
             flac: inner.flac,
-            vorbis: inner.vorbis.replace("{FLACBASE}", &flac_basename),
+            vorbis,
             mp3: inner.mp3.map(|mp3| mp3.replace("{FLACBASE}", &flac_basename)),
+            opus: inner.opus.map(|opus| opus.replace("{FLACBASE}", &flac_basename)),
             patch_notes: inner.patch_notes,
+            group: inner.group,
+            usage: inner.usage,
             ondisk_root: ondisk_root.map(Path::to_owned),
+            lq_ogg: None,
             flac_bytes,
             ogg_bytes,
             mp3_bytes,
+            opus_bytes,
+            lq_ogg_bytes: 0,
+            // only an import knows these, and a track on disk might not match them anymore
+            cids: cache
+                .filter(|_| ondisk_root.is_none())
+                .map(|c| c.cids.clone())
+                .unwrap_or_default(),
+            import_warnings: cache
+                .filter(|_| ondisk_root.is_none())
+                .map(|c| c.import_warnings.clone())
+                .unwrap_or_default(),
         })
     }
 
+    /// A track that nothing is known about but its id and flac, to fill in and then load the season from as its cache
+    /// (see [`crate::ipfs_import`])
+    #[cfg(feature = "ipfs")]
+    pub(crate) fn unknown(id: u8, flac: &str) -> Track {
+        Track {
+            id,
+            name: String::new(),
+            flac: flac.to_string(),
+            vorbis: None,
+            mp3: None,
+            opus: None,
+            patch_notes: None,
+            group: None,
+            usage: Usage::Full,
+            ondisk_root: None,
+            media_info: MediaInfo {
+                t: "Audio".to_string(),
+                format: String::new(),
+                channels: String::new(),
+                sample_rate: String::new(),
+                bit_depth: String::new(),
+                duration: String::new(),
+                bitrate: None,
+            },
+            ogg_info: None,
+            mp3_info: None,
+            opus_info: None,
+            loudness: None,
+            spectrogram: None,
+            peaks: None,
+            lq_ogg: None,
+            flac_bytes: 0,
+            ogg_bytes: 0,
+            mp3_bytes: 0,
+            opus_bytes: 0,
+            lq_ogg_bytes: 0,
+            cids: BTreeMap::new(),
+            import_warnings: Vec::new(),
+        }
+    }
+
+    /// Gives a stereo mix its low-quality ogg, whether or not it's been made yet (flac-only ones don't get one)
+    pub(crate) fn add_lq_ogg(&mut self, cache: Option<&Track>) {
+        let vorbis = match &self.vorbis {
+            Some(vorbis) => vorbis,
+            None => return,
+        };
+        let flac_basename = match Path::new(&self.flac).file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            None => return,
+        };
+        let lq_ogg = lq_ogg_path(vorbis, &flac_basename);
+        self.lq_ogg_bytes = self
+            .ondisk_root
+            .as_ref()
+            .and_then(|p| std::fs::metadata(p.join(&lq_ogg)).ok())
+            .map(|md| md.len())
+            .unwrap_or_else(|| cache.filter(|c| c.flac == self.flac).map_or(0, |c| c.lq_ogg_bytes));
+        self.lq_ogg = Some(lq_ogg);
+    }
+
+    /// What the player streams: the low-quality ogg if there is one, or else the ogg
+    pub fn stream(&self) -> Option<&String> {
+        self.lq_ogg.as_ref().or(self.vorbis.as_ref())
+    }
+
+    /// Whether there's an ogg, mp3 or opus to play in the browser (flac-only tracks just get a download link)
+    pub fn playable(&self) -> bool {
+        self.vorbis.is_some() || self.mp3.is_some() || self.opus.is_some()
+    }
+
+    /// Whether the track's files are offered for download (see [`Usage`])
+    pub fn downloadable(&self) -> bool {
+        self.usage == Usage::Full
+    }
+
+    /// The recording's folder, which the track's paths are relative to
+    pub fn folder_ondisk(&self) -> Option<&Path> {
+        self.ondisk_root.as_deref()
+    }
     pub fn flac_ondisk(&self) -> Option<PathBuf> {
         self.ondisk_root.as_ref().map(|p| p.join(&self.flac))
     }
     pub fn ogg_ondisk(&self) -> Option<PathBuf> {
-        self.ondisk_root.as_ref().map(|p| p.join(&self.vorbis))
+        self.ondisk_root
+            .as_ref()
+            .and_then(|p| self.vorbis.as_ref().and_then(|ogg| linked_file(p, ogg)))
+    }
+
+    /// Where the spectrogram for this track goes, whether or not it has been made yet
+    pub fn spectrogram_ondisk(&self) -> Option<PathBuf> {
+        let flac_basename = Path::new(&self.flac).file_stem()?.to_string_lossy();
+        self.ondisk_root.as_ref().map(|p| {
+            p.join(spectrogram_path(
+                self.vorbis.as_ref().unwrap_or(&self.flac),
+                &flac_basename,
+            ))
+        })
+    }
+
+    /// Where the peaks of this track go, whether or not they have been worked out yet
+    pub fn peaks_ondisk(&self) -> Option<PathBuf> {
+        let flac_basename = Path::new(&self.flac).file_stem()?.to_string_lossy();
+        self.ondisk_root
+            .as_ref()
+            .map(|p| p.join(peaks_path(self.vorbis.as_ref().unwrap_or(&self.flac), &flac_basename)))
+    }
+
+    pub fn lq_ogg_ondisk(&self) -> Option<PathBuf> {
+        self.ondisk_root
+            .as_ref()
+            .and_then(|p| self.lq_ogg.as_ref().and_then(|lq| linked_file(p, lq)))
     }
 
     pub fn mp3_ondisk(&self) -> Option<PathBuf> {
         self.ondisk_root
             .as_ref()
-            .and_then(|p| self.mp3.as_ref().map(|mp3| p.join(&mp3)))
+            .and_then(|p| self.mp3.as_ref().and_then(|mp3| linked_file(p, mp3)))
+    }
+
+    pub fn opus_ondisk(&self) -> Option<PathBuf> {
+        self.ondisk_root
+            .as_ref()
+            .and_then(|p| self.opus.as_ref().and_then(|opus| linked_file(p, opus)))
     }
 
     pub fn flac_size_str(&self) -> String {
@@ -318,6 +1118,38 @@ impl Track {
         self.mp3_bytes
     }
 
+    pub fn opus_size_str(&self) -> String {
+        format!("{}MB", self.opus_bytes / 1024 / 1024)
+    }
+
+    pub fn opus_size_bytes(&self) -> u64 {
+        self.opus_bytes
+    }
+
+    /// Loudness, for display (like "-19.4 LUFS, LRA 5.5 LU, peak -0.5 dBFS")
+    pub fn loudness_str(&self) -> String {
+        match &self.loudness {
+            Some(l) => format!(
+                "{:.1} LUFS, LRA {:.1} LU, peak {:.1} dBFS",
+                l.integrated, l.range, l.true_peak
+            ),
+            None => String::new(),
+        }
+    }
+
+    /// The bitrate of the ogg, for showing next to its size (like " 192kbps")
+    pub fn ogg_bitrate_str(&self) -> String {
+        bitrate_str(&self.ogg_info)
+    }
+
+    pub fn mp3_bitrate_str(&self) -> String {
+        bitrate_str(&self.mp3_info)
+    }
+
+    pub fn opus_bitrate_str(&self) -> String {
+        bitrate_str(&self.opus_info)
+    }
+
     pub fn patch_notes(&self) -> &str {
         if let Some(s) = &self.patch_notes {
             s.as_ref()
@@ -326,3 +1158,153 @@ impl Track {
         }
     }
 }
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+fn bitrate_str(info: &Option<MediaInfo>) -> String {
+    match info.as_ref().and_then(|i| i.bitrate_kbps()) {
+        Some(kbps) => format!(" {}kbps", kbps),
+        None => String::new(),
+    }
+}
+
+/// Where a file that a recording's page links to (relative to its data folder) is on disk, or `None` for a link to
+/// another site (like a torrent on a tracker)
+///
+/// Validation and generation both look for the linked files through this, so that they agree on what's missing.
+pub fn linked_file<P: AsRef<Path>>(folder: &Path, link: P) -> Option<PathBuf> {
+    let link = link.as_ref();
+    if link.to_string_lossy().contains("://") {
+        None
+    } else {
+        Some(folder.join(link))
+    }
+}
+
+/// The low-quality ogg of a stereo mix is `<FLACBASE>.lq.ogg`, next to its ogg
+pub(crate) fn lq_ogg_path(vorbis: &str, flac_basename: &str) -> String {
+    let name = format!("{}.lq.ogg", flac_basename);
+    match vorbis.rfind('/') {
+        Some(idx) => format!("{}/{}", &vorbis[..idx], name),
+        None => name,
+    }
+}
+
+/// The spectrogram for a track is `<FLACBASE>.spectrogram.png`, next to the ogg (or the flac, if there is no ogg)
+pub(crate) fn spectrogram_path(vorbis: &str, flac_basename: &str) -> String {
+    let name = format!("{}.spectrogram.png", flac_basename);
+    match vorbis.rfind('/') {
+        Some(idx) => format!("{}/{}", &vorbis[..idx], name),
+        None => name,
+    }
+}
+
+/// The waveform peaks of a track are `<FLACBASE>.peaks.json`, next to the ogg (or the flac, if there is no ogg)
+pub(crate) fn peaks_path(vorbis: &str, flac_basename: &str) -> String {
+    let name = format!("{}.peaks.json", flac_basename);
+    match vorbis.rfind('/') {
+        Some(idx) => format!("{}/{}", &vorbis[..idx], name),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, json: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+
+    fn season(dir: &Path, recordings: &str) -> (PathBuf, SeasonInner) {
+        let path = dir.join("season.json");
+        let json = format!(r#"{{"$schema": "none", "title": "S", "recordings": {}}}"#, recordings);
+        write(&path, &json);
+        (path, serde_json::from_str(&json).unwrap())
+    }
+
+    #[test]
+    fn includes() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            &dir.path().join("lists/q1.json"),
+            r#"{"recordings": ["../b.json", {"include": "more/q2.json"}]}"#,
+        );
+        write(
+            &dir.path().join("lists/more/q2.json"),
+            r#"{"recordings": ["../../c.json"]}"#,
+        );
+        let (season_json, inner) = season(dir.path(), r#"["a.json", {"include": "lists/q1.json"}, "d.json"]"#);
+
+        let listed = inner.recording_paths(&season_json).unwrap();
+        let names: Vec<_> = listed.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["a.json", "../b.json", "../../c.json", "d.json"]);
+        assert_eq!(listed[2].path, dir.path().join("lists/more/../../c.json"));
+        assert_eq!(
+            listed[2].chain,
+            [
+                season_json.clone(),
+                dir.path().join("lists/q1.json"),
+                dir.path().join("lists/more/q2.json")
+            ]
+        );
+        assert_eq!(listed[3].chain, [season_json]);
+
+        // only recordings that came through an include say so when they fail to load
+        let missing = || CbError::MissingFile {
+            path: PathBuf::from("c.json"),
+        };
+        assert!(matches!(listed[0].context(missing()), CbError::MissingFile { .. }));
+        let message = listed[2].context(missing()).to_string();
+        let q2 = dir.path().join("lists/more/q2.json");
+        assert!(
+            message.ends_with(&format!("lists/q1.json -> {}", q2.display())),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn include_cycles_and_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join("a.json"), r#"{"recordings": [{"include": "b.json"}]}"#);
+        write(
+            &dir.path().join("b.json"),
+            r#"{"recordings": [{"include": "./a.json"}]}"#,
+        );
+        let (season_json, inner) = season(dir.path(), r#"[{"include": "a.json"}]"#);
+        match inner.recording_paths(&season_json) {
+            Err(CbError::IncludeCycle { chain }) => assert_eq!(chain.len(), 4),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+
+        // each list includes the next one
+        for n in 0..MAX_INCLUDE_DEPTH + 1 {
+            write(
+                &dir.path().join(format!("deep{}.json", n)),
+                &format!(
+                    r#"{{"recordings": ["r{}.json", {{"include": "deep{}.json"}}]}}"#,
+                    n,
+                    n + 1
+                ),
+            );
+        }
+        write(
+            &dir.path().join(format!("deep{}.json", MAX_INCLUDE_DEPTH)),
+            r#"{"recordings": []}"#,
+        );
+        let (season_json, inner) = season(dir.path(), r#"[{"include": "deep1.json"}]"#);
+        assert_eq!(
+            inner.recording_paths(&season_json).unwrap().len(),
+            MAX_INCLUDE_DEPTH - 1
+        );
+        let (season_json, inner) = season(dir.path(), r#"[{"include": "deep0.json"}]"#);
+        assert!(matches!(
+            inner.recording_paths(&season_json),
+            Err(CbError::IncludeTooDeep { .. })
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+//! Checking for newer releases of cb_processor
+//!
+//! None of this is allowed to fail a run: if anything goes wrong (no network, a strange response, an unwritable cache)
+//! we just don't say anything.
+
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+use crate::context::RunContext;
+
+/// The GitLab releases for this project
+const RELEASES_URL: &str = "https://gitlab.com/api/v4/projects/22680986/releases";
+
+/// Automatic checks are done at most this often
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Set this (to anything) to turn off the automatic check
+pub const NO_UPDATE_CHECK_VAR: &str = "CB_NO_UPDATE_CHECK";
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// Parses a version like "v1.2.3" into its numeric parts
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim().trim_start_matches('v');
+    // ignore any pre-release or build suffix
+    let version = version.split(['-', '+']).next()?;
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// True if `latest` is a newer version than `current`
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+/// Picks the newest version out of a releases API response
+fn latest_release(body: &str) -> Option<String> {
+    let releases: Vec<Release> = serde_json::from_str(body).ok()?;
+    releases
+        .into_iter()
+        .filter_map(|r| parse_version(&r.tag_name).map(|v| (v, r.tag_name)))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, tag)| tag)
+}
+
+fn fetch_latest(ctx: &RunContext) -> Option<String> {
+    let client = ctx.http_client("update check", Duration::from_secs(3)).ok()?;
+    let resp = client.get(RELEASES_URL).send().ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    latest_release(&resp.text().ok()?)
+}
+
+fn cache_file() -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_dir.join("cb_processor").join("latest_release"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Returns the latest release we found less than a day ago, if there is one
+fn cached_latest() -> Option<String> {
+    let cached = std::fs::read_to_string(cache_file()?).ok()?;
+    let mut lines = cached.lines();
+    let checked_at: u64 = lines.next()?.parse().ok()?;
+    if now().saturating_sub(checked_at) > CHECK_INTERVAL.as_secs() {
+        return None;
+    }
+    Some(lines.next().unwrap_or("").to_string())
+}
+
+fn save_cache(latest: &str) {
+    if let Some(path) = cache_file() {
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let _ = std::fs::write(path, format!("{}\n{}\n", now(), latest));
+    }
+}
+
+/// Checks for a newer release, returning a one-line notice if there is one
+///
+/// With `force` the releases API is always queried.  Otherwise it is queried at most once a day, and not at all if
+/// `CB_NO_UPDATE_CHECK` is set.  An `--offline` run never checks.
+pub fn check(ctx: &RunContext, force: bool) -> Option<String> {
+    if ctx.offline || (!force && std::env::var_os(NO_UPDATE_CHECK_VAR).is_some()) {
+        return None;
+    }
+    let latest = match cached_latest().filter(|_| !force) {
+        Some(latest) => latest,
+        None => {
+            let latest = fetch_latest(ctx).unwrap_or_default();
+            save_cache(&latest);
+            latest
+        }
+    };
+
+    let current = env!("CARGO_PKG_VERSION");
+    if is_newer(&latest, current) {
+        Some(format!(
+            "A newer cb_processor ({}) is available, you're running {}",
+            latest, current
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        assert!(is_newer("v0.2.0", "0.1.0"));
+        assert!(is_newer("0.10.0", "0.9.3"));
+        assert!(!is_newer("v0.1.0", "0.1.0"));
+        assert!(!is_newer("v0.1.0-rc1", "0.1.0"));
+        assert!(!is_newer("nightly", "0.1.0"));
+
+        let body = r#"[{"tag_name": "v0.2.0"}, {"tag_name": "nightly"}, {"tag_name": "v0.10.1"}]"#;
+        assert_eq!(latest_release(body).as_deref(), Some("v0.10.1"));
+        assert_eq!(latest_release("not json"), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! Videos of the live streams (`youtube_url` in a recording's JSON)
+//!
+//! Recording pages embed the video, but only once it's clicked: until then there's just a thumbnail, and the player
+//! comes from youtube-nocookie.com.
+
+/// A video, and where to start playing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Video {
+    pub id: String,
+    /// Seconds into the video (from `t=` in the URL)
+    pub start: Option<u32>,
+}
+
+impl Video {
+    /// The player to put in an iframe.  It only gets loaded after a click, so it can start playing right away
+    pub fn embed_url(&self) -> String {
+        let mut url = format!("https://www.youtube-nocookie.com/embed/{}?autoplay=1", self.id);
+        if let Some(start) = self.start {
+            url.push_str(&format!("&start={}", start));
+        }
+        url
+    }
+
+    pub fn thumbnail_url(&self) -> String {
+        format!("https://i.ytimg.com/vi/{}/hqdefault.jpg", self.id)
+    }
+}
+
+/// Gets the video out of a `youtube.com/watch?v=` or `youtu.be/` URL
+///
+/// Anything else (other hosts, playlists, channels, IDs that aren't 11 characters) is `None`, and is reported by
+/// validation.
+pub fn parse(url: &str) -> Option<Video> {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"))?;
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let host = host.to_ascii_lowercase();
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("m."))
+        .unwrap_or(&host);
+
+    let param = |name: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    let id = match host {
+        "youtu.be" => path,
+        "youtube.com" if path == "watch" => param("v")?,
+        _ => return None,
+    };
+    if id.len() != 11 || !id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        return None;
+    }
+    let start = match param("t").or_else(|| param("start")) {
+        Some(t) => Some(parse_time(t)?),
+        None => None,
+    };
+
+    Some(Video {
+        id: id.to_string(),
+        start,
+    })
+}
+
+/// `90`, `90s`, `1m30s` or `1h2m3s`, or `None` if it doesn't fit in a `u32`
+fn parse_time(t: &str) -> Option<u32> {
+    if let Ok(secs) = t.parse() {
+        return Some(secs);
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in t.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'h' | 'm' | 's' => {
+                let n: u32 = number.parse().ok()?;
+                let unit = match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                total = total.checked_add(n.checked_mul(unit)?)?;
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    if number.is_empty() {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let video = |id: &str, start| {
+            Some(Video {
+                id: id.to_string(),
+                start,
+            })
+        };
+        assert_eq!(
+            parse("https://www.youtube.com/watch?v=abcdefghijk"),
+            video("abcdefghijk", None)
+        );
+        assert_eq!(
+            parse("https://youtube.com/watch?feature=share&v=a-c_efghijk&t=1h2m3s"),
+            video("a-c_efghijk", Some(3723))
+        );
+        assert_eq!(
+            parse("http://m.youtube.com/watch?v=abcdefghijk#comments"),
+            video("abcdefghijk", None)
+        );
+        assert_eq!(
+            parse("https://youtu.be/abcdefghijk?t=90"),
+            video("abcdefghijk", Some(90))
+        );
+
+        assert_eq!(parse("https://www.youtube.com/watch?v=short"), None);
+        assert_eq!(parse("https://www.youtube.com/playlist?list=abcdefghijk"), None);
+        assert_eq!(parse("https://vimeo.com/abcdefghijk"), None);
+        assert_eq!(parse("youtu.be/abcdefghijk"), None);
+        assert_eq!(parse("https://youtu.be/abcdefghijk?t=soon"), None);
+        // too long to be a time in the video, rather than overflowing
+        assert_eq!(parse("https://youtu.be/abcdefghijk?t=9999999h"), None);
+        assert_eq!(parse("https://youtu.be/abcdefghijk?t=1193046h28m16s"), None);
+        assert_eq!(
+            parse("https://youtu.be/abcdefghijk?t=1193046h28m15s"),
+            video("abcdefghijk", Some(u32::MAX))
+        );
+
+        let video = parse("https://youtu.be/abcdefghijk?t=1m30s").unwrap();
+        assert_eq!(
+            video.embed_url(),
+            "https://www.youtube-nocookie.com/embed/abcdefghijk?autoplay=1&start=90"
+        );
+    }
+}
@@ -0,0 +1,874 @@
+//! Tests that run the cb_processor binary itself
+#![cfg(feature = "cli")]
+
+mod support;
+
+use std::{
+    path::Path,
+    process::{Command, Output},
+};
+
+/// Every environment variable that can stand in for a command line argument
+const ENV_VARS: &[&str] = &[
+    "CB_INPUT",
+    "CB_DATA_DIR",
+    "CB_OUTPUT",
+    "CB_METADATA",
+    "CB_HASH",
+    "CB_CONFIG",
+    "CB_STATIC_DIR",
+    "CB_OFFLINE",
+];
+
+fn cb_processor() -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_cb_processor"));
+    for var in ENV_VARS {
+        cmd.env_remove(var);
+    }
+    cmd.env("NO_COLOR", "1")
+        .env("CLICOLOR", "0")
+        .env("CB_NO_UPDATE_CHECK", "1");
+    cmd
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+/// Creates a new season called `title` in `dir`
+fn init_season(dir: &Path, title: &str) {
+    let output = cb_processor()
+        .arg("init")
+        .arg(dir)
+        .arg("--title")
+        .arg(title)
+        .arg("--artist")
+        .arg("Tester")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "init failed: {:?}", output);
+}
+
+#[test]
+fn add_recording() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+    let folder = dir.path().join("audio/S01E01");
+    std::fs::create_dir_all(&folder).unwrap();
+    for name in [
+        "Band - S01E01 - Jam - 01-210305_2015.flac",
+        "Band - S01E01 - Jam - 02-210305_2015.flac",
+        "Band - S01E01 - Jam - 03-210305_2015.flac",
+    ] {
+        std::fs::copy(support::fixtures().join("silence.flac"), folder.join(name)).unwrap();
+    }
+    let season_json = dir.path().join("data/season.json");
+
+    let output = cb_processor()
+        .arg("add-recording")
+        .arg(&folder)
+        .arg("--input")
+        .arg(&season_json)
+        .arg("--data")
+        .arg(dir.path().join("audio"))
+        .arg("--title")
+        .arg("S01E01 - Jam")
+        .arg("--date")
+        .arg("2021/03/05")
+        .arg("--tag")
+        .arg("live")
+        .arg("--stereo-mix-id")
+        .arg("1")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "add-recording failed: {:?}", output);
+
+    // written next to the other recordings, with the ids taken from the filenames
+    let json_path = dir.path().join("data/recordings/S01E01.json");
+    let recording: serde_json::Value = serde_json::from_slice(&std::fs::read(&json_path).unwrap()).unwrap();
+    assert_eq!(recording["$schema"], "../schema/recording.json");
+    assert_eq!(recording["title"], "S01E01 - Jam");
+    assert_eq!(recording["recorded_date"], "2021/03/05");
+    assert_eq!(recording["data_folder"], "S01E01");
+    assert_eq!(recording["tags"], serde_json::json!(["live"]));
+    assert_eq!(recording["stereo_mix"]["id"], 1);
+    assert_eq!(
+        recording["stereo_mix"]["flac"],
+        "Band - S01E01 - Jam - 01-210305_2015.flac"
+    );
+    assert_eq!(recording["stereo_mix"]["vorbis"], "ogg/{FLACBASE}.ogg");
+    let ids: Vec<_> = recording["tracks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["id"].clone())
+        .collect();
+    assert_eq!(ids, [2, 3]);
+
+    let season: serde_json::Value = serde_json::from_slice(&std::fs::read(&season_json).unwrap()).unwrap();
+    assert_eq!(
+        season["recordings"],
+        serde_json::json!(["recordings/example.json", "recordings/S01E01.json"])
+    );
+
+    // the season still loads, with the new recording in it
+    let output = cb_processor()
+        .arg("--validate")
+        .arg("--input")
+        .arg(&season_json)
+        .arg("--data")
+        .arg(dir.path().join("audio"))
+        .output()
+        .unwrap();
+    assert!(stdout(&output).contains("S01E01 - Jam"), "{}", stdout(&output));
+
+    // adding the same folder again would overwrite the recording
+    let output = cb_processor()
+        .arg("add-recording")
+        .arg(&folder)
+        .arg("--input")
+        .arg(&season_json)
+        .arg("--data")
+        .arg(dir.path().join("audio"))
+        .args([
+            "--title",
+            "Again",
+            "--date",
+            "2021/03/05",
+            "--tag",
+            "live",
+            "--stereo-mix-id",
+            "1",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+}
+
+#[test]
+fn help_lists_env_vars() {
+    let output = cb_processor().arg("--help").output().unwrap();
+    let help = stdout(&output);
+    for var in ENV_VARS {
+        assert!(help.contains(var), "--help doesn't mention {}", var);
+    }
+}
+
+#[test]
+fn paths_from_env() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "From env");
+
+    let output = cb_processor()
+        .arg("--validate")
+        .env("CB_INPUT", dir.path().join("data/season.json"))
+        .env("CB_DATA_DIR", dir.path().join("audio"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "validate failed: {:?}", output);
+    assert!(stdout(&output).contains("Checking season From env"));
+}
+
+#[test]
+fn flags_win_over_env() {
+    let env_dir = tempfile::tempdir().unwrap();
+    init_season(env_dir.path(), "From env");
+    let flag_dir = tempfile::tempdir().unwrap();
+    init_season(flag_dir.path(), "From flag");
+
+    let output = cb_processor()
+        .arg("--validate")
+        .arg("--input")
+        .arg(flag_dir.path().join("data/season.json"))
+        .env("CB_INPUT", env_dir.path().join("data/season.json"))
+        .env("CB_DATA_DIR", env_dir.path().join("audio"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "validate failed: {:?}", output);
+    assert!(stdout(&output).contains("Checking season From flag"));
+}
+
+#[test]
+fn missing_arguments_are_usage_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+
+    let output = cb_processor()
+        .env("CB_INPUT", dir.path().join("data/season.json"))
+        .env("CB_OUTPUT", dir.path().join("output"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("either --data or --metadata must be provided"));
+
+    // a share that would expire further out than a time can say
+    for days in ["0", "36526", "18446744073709551615"] {
+        let output = cb_processor()
+            .args(["--offline", "share", "jam1", "--data"])
+            .arg(dir.path().join("audio"))
+            .arg("--input")
+            .arg(dir.path().join("data/season.json"))
+            .args(["--expires-in", days])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("up to 36525"),
+            "{:?}",
+            output
+        );
+    }
+}
+
+#[test]
+fn only_must_match_something() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+
+    let output = cb_processor()
+        .arg("--input")
+        .arg(dir.path().join("data/season.json"))
+        .arg("--data")
+        .arg(dir.path().join("audio"))
+        .arg("--output")
+        .arg(dir.path().join("output"))
+        .arg("--only")
+        .arg("S09*")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("didn't match any recordings"));
+    assert!(!dir.path().join("output/index.html").exists());
+}
+
+#[test]
+fn keep_going_past_broken_recordings() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+    let season_json = dir.path().join("data/season.json");
+    let mut season: serde_json::Value = serde_json::from_slice(&std::fs::read(&season_json).unwrap()).unwrap();
+    season["recordings"].as_array_mut().unwrap().push("broken.json".into());
+    std::fs::write(&season_json, serde_json::to_vec_pretty(&season).unwrap()).unwrap();
+    std::fs::write(dir.path().join("data/broken.json"), "{\"title\": \"Broken\",").unwrap();
+    let generate = |keep_going: bool| {
+        let mut cmd = cb_processor();
+        cmd.arg("--input")
+            .arg(&season_json)
+            .arg("--data")
+            .arg(dir.path().join("audio"))
+            .arg("--output")
+            .arg(dir.path().join("output"));
+        if keep_going {
+            cmd.arg("--keep-going");
+        }
+        cmd.output().unwrap()
+    };
+
+    let output = generate(false);
+    assert!(!output.status.success());
+    assert!(!dir.path().join("output/index.html").exists());
+
+    // the rest of the season is still generated, but the run fails
+    let output = generate(true);
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("couldn't be loaded, and were left out"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("1 recordings couldn't be loaded"));
+    let index = std::fs::read_to_string(dir.path().join("output/index.html")).unwrap();
+    assert!(index.contains("<li>broken</li>"));
+}
+
+#[test]
+fn diff_output_writes_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+    let season_json = dir.path().join("data/season.json");
+    let generate = |diff: bool| {
+        let mut cmd = cb_processor();
+        cmd.arg("--input")
+            .arg(&season_json)
+            .arg("--data")
+            .arg(dir.path().join("audio"))
+            .arg("--output")
+            .arg(dir.path().join("output"));
+        if diff {
+            cmd.arg("--diff-output").arg("--diff-lines").arg("5");
+        }
+        cmd.output().unwrap()
+    };
+
+    // everything would be new
+    let output = generate(true);
+    assert!(!output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).contains("+++ "));
+    assert!(!dir.path().join("output").exists());
+
+    assert!(generate(false).status.success());
+    let output = generate(true);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).contains("0 files would change"));
+
+    let index = std::fs::read(dir.path().join("output/index.html")).unwrap();
+    let season = std::fs::read_to_string(&season_json).unwrap();
+    std::fs::write(&season_json, season.replace("\"Season\"", "\"Renamed\"")).unwrap();
+    let output = generate(true);
+    assert!(!output.status.success());
+    let out = stdout(&output);
+    assert!(
+        out.contains("-                <h2>Modular Mayhem Archive -- Season</h2>"),
+        "{}",
+        out
+    );
+    assert!(out.contains("more lines"));
+    // the page, and the snapshot of season.json
+    assert!(out.contains("2 files would change"));
+    assert_eq!(std::fs::read(dir.path().join("output/index.html")).unwrap(), index);
+}
+
+#[test]
+fn locked_output() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+    let output_dir = dir.path().join("output");
+    std::fs::create_dir(&output_dir).unwrap();
+    let generate = |force_unlock: bool| {
+        let mut cmd = cb_processor();
+        cmd.arg("--input")
+            .arg(dir.path().join("data/season.json"))
+            .arg("--data")
+            .arg(dir.path().join("audio"))
+            .arg("--output")
+            .arg(&output_dir);
+        if force_unlock {
+            cmd.arg("--force-unlock");
+        }
+        cmd.output().unwrap()
+    };
+
+    // this test is still running, so its lock can't be taken over
+    let lock = cb_processor::lock::acquire(&output_dir, cb_processor::lock::OUTPUT_LOCK, false).unwrap();
+    for force_unlock in [false, true].iter().copied() {
+        let output = generate(force_unlock);
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(&format!("pid {} on", std::process::id())), "{}", stderr);
+    }
+    assert!(!output_dir.join("index.html").exists());
+
+    // a run that crashed leaves its lock behind
+    let path = lock.path().to_path_buf();
+    let mut holder: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    holder["pid"] = 99_999_999.into();
+    std::fs::write(&path, holder.to_string()).unwrap();
+    std::mem::forget(lock);
+    let output = generate(false);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no longer running"));
+
+    let output = generate(true);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(output_dir.join("index.html").exists());
+    // and the lock is gone once the run is over
+    assert!(!path.exists());
+    assert!(!dir.path().join("audio").join(cb_processor::lock::DATA_LOCK).exists());
+}
+
+#[test]
+fn offline_refuses_network() {
+    for args in [&["--prime", "--hash", support::FAKE_ROOT][..], &["--check-update"]] {
+        let output = cb_processor().arg("--offline").args(args).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains(&format!("{} needs the network, and this run is --offline", args[0])),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn rollback() {
+    let dir = tempfile::tempdir().unwrap();
+    let ipfs = dir.path().join("ipfs");
+    let fake = support::fake_ipfs(&ipfs, &["index.html"]);
+    let history = dir.path().join("roots_history.jsonl");
+    std::fs::write(
+        &history,
+        format!(
+            "{{\"root\":\"{}\",\"published\":1700000000}}\n{{\"root\":\"{}\",\"published\":1700000060}}\n",
+            support::FAKE_ROOT,
+            support::FAKE_ADDED
+        ),
+    )
+    .unwrap();
+    let config = dir.path().join("cb_processor.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "roots_history = {:?}\n[tools]\nipfs = {:?}\n",
+            history.display().to_string(),
+            fake.display().to_string()
+        ),
+    )
+    .unwrap();
+    let rollback = |to: &str| {
+        cb_processor()
+            .arg("--config")
+            .arg(&config)
+            .arg("rollback")
+            .arg("--to")
+            .arg(to)
+            .arg("--yes")
+            .output()
+            .unwrap()
+    };
+
+    // the latest root is what's published already
+    assert!(!rollback("2").status.success());
+
+    // an old root that was garbage collected can't be published again
+    let missing = ipfs.join(format!("objects/{}.missing", support::FAKE_ROOT));
+    std::fs::write(&missing, "").unwrap();
+    let output = rollback("1");
+    assert!(!output.status.success());
+    assert!(
+        stdout(&output).contains("is not in the local IPFS repo"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(!std::fs::read_to_string(ipfs.join("calls"))
+        .unwrap()
+        .contains("name publish"));
+
+    std::fs::remove_file(&missing).unwrap();
+    let output = cb_processor()
+        .env("CB_OFFLINE", "1")
+        .arg("--config")
+        .arg(&config)
+        .arg("rollback")
+        .arg("--to")
+        .arg("1")
+        .arg("--yes")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rollback needs the network"));
+    assert!(!std::fs::read_to_string(ipfs.join("calls")).unwrap().contains("pin add"));
+
+    let output = rollback("1");
+    assert!(output.status.success(), "{:?}", output);
+    let calls = std::fs::read_to_string(ipfs.join("calls")).unwrap();
+    assert!(
+        calls.contains(&format!("pin add --progress=false /ipfs/{}", support::FAKE_ROOT)),
+        "{}",
+        calls
+    );
+    assert!(
+        calls.contains(&format!("name publish --key=self /ipfs/{}", support::FAKE_ROOT)),
+        "{}",
+        calls
+    );
+
+    let entries = cb_processor::history::load(&history).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[2].root, support::FAKE_ROOT);
+    assert_eq!(entries[2].rolled_back_from.as_deref(), Some(support::FAKE_ADDED));
+}
+
+#[cfg(unix)]
+#[test]
+fn patch_confirmation() {
+    let dir = tempfile::tempdir().unwrap();
+    let ipfs = dir.path().join("ipfs");
+    let fake = support::fake_ipfs(&ipfs, &["index.html"]);
+    let output_dir = dir.path().join("output");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    std::fs::write(output_dir.join("index.html"), "<html></html>").unwrap();
+    let config = dir.path().join("cb_processor.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "roots_history = {:?}\n[tools]\nipfs = {:?}\n",
+            dir.path().join("roots_history.jsonl").display().to_string(),
+            fake.display().to_string()
+        ),
+    )
+    .unwrap();
+    let patch = |yes: bool| {
+        let mut cmd = cb_processor();
+        cmd.arg("--config")
+            .arg(&config)
+            .arg("--patch")
+            .arg("--hash")
+            .arg(support::FAKE_ROOT)
+            .arg("--output")
+            .arg(&output_dir)
+            .stdin(std::process::Stdio::null());
+        if yes {
+            cmd.arg("--yes");
+        }
+        cmd.output().unwrap()
+    };
+
+    // the prompt says which root is replaced, and nobody can answer it
+    let output = patch(false);
+    assert!(!output.status.success());
+    assert!(
+        stdout(&output).contains(&format!("About to replace the root {}", support::FAKE_ROOT)),
+        "{}",
+        stdout(&output)
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Use --yes"));
+    assert!(!std::fs::read_to_string(ipfs.join("calls"))
+        .unwrap()
+        .contains("object patch"));
+
+    let output = patch(true);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(std::fs::read_to_string(ipfs.join("calls"))
+        .unwrap()
+        .contains("object patch"));
+    // the sizes come before the new root's own line, and CI finds the root by its label (the timings come last)
+    let stdout = stdout(&output);
+    let lines: Vec<_> = stdout.lines().collect();
+    let sizes = lines.iter().position(|l| l.starts_with("Size of ")).unwrap();
+    assert!(lines[sizes..].contains(&support::FAKE_ADDED), "{}", stdout);
+    let labelled: Vec<_> = lines
+        .iter()
+        .filter_map(|l| l.strip_prefix("New root object "))
+        .collect();
+    assert_eq!(labelled, [support::FAKE_ADDED]);
+}
+
+#[cfg(unix)]
+#[test]
+fn fetch_from_latest_root() {
+    let fixture = support::FixtureSeason::new();
+    let ctx = support::fake_tools_context();
+    let season = fixture.converted(&ctx);
+    let metadata = fixture.root.join("metadata.json");
+    cb_processor::write_metadata(&season, &metadata, false).unwrap();
+    let ipfs = fixture.root.join("ipfs");
+    let fake = support::fake_ipfs(&ipfs, &[]);
+    let kick = fixture.audio.join("jam1/ogg/jam1_kick.ogg");
+    std::fs::rename(&kick, ipfs.join(format!("objects/{}.data", support::FAKE_ADDED))).unwrap();
+
+    let history = fixture.root.join("roots_history.jsonl");
+    let config = fixture.root.join("cb_processor.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "roots_history = {:?}\n[tools]\nipfs = {:?}\n",
+            history.display().to_string(),
+            fake.display().to_string()
+        ),
+    )
+    .unwrap();
+    let fetch = || {
+        cb_processor()
+            .arg("--config")
+            .arg(&config)
+            .arg("--fetch")
+            .arg("--input")
+            .arg(&fixture.season_json)
+            .arg("--data")
+            .arg(&fixture.audio)
+            .arg("--metadata")
+            .arg(&metadata)
+            .arg("--formats")
+            .arg("ogg")
+            .output()
+            .unwrap()
+    };
+
+    // without --hash or any published root, there's nothing to fetch from
+    let output = fetch();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("since there are no roots in"));
+
+    // otherwise the last root that was published is used
+    std::fs::write(
+        &history,
+        format!(
+            "{{\"root\":\"{}\",\"published\":1}}\n{{\"root\":\"{}\",\"published\":2}}\n",
+            support::FAKE_ADDED,
+            support::FAKE_ROOT
+        ),
+    )
+    .unwrap();
+    let output = fetch();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&output).contains("Fetched 1 files"), "{}", stdout(&output));
+    assert!(kick.exists());
+    let calls = std::fs::read_to_string(ipfs.join("calls")).unwrap();
+    assert!(
+        calls.contains(&format!(
+            "resolve -r /ipfs/{}/jam1/ogg/jam1_kick.ogg",
+            support::FAKE_ROOT
+        )),
+        "{}",
+        calls
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn drift_check_season_folder() {
+    let fixture = support::FixtureSeason::new();
+    let ctx = support::fake_tools_context();
+    let season = fixture.converted(&ctx);
+    let metadata = fixture.root.join("metadata.json");
+    cb_processor::write_metadata(&season, &metadata, false).unwrap();
+    // as drift-check regenerates it, without the audio on disk
+    let cached = cb_processor::load_metadata(&metadata).unwrap();
+    let season = cb_processor::types::Season::load(&ctx, &fixture.season_json, None, Some(&cached)).unwrap();
+    cb_processor::write_metadata(&season, &metadata, true).unwrap();
+
+    // the root has the season in S01, where its metadata.json was made by another build
+    let ipfs = fixture.root.join("ipfs");
+    let fake = support::fake_ipfs(&ipfs, &["S01/"]);
+    std::fs::write(
+        ipfs.join(format!("objects/{}.json", support::FAKE_FOLDER)),
+        format!(
+            r#"{{"Links": [{{"Name": "metadata.json", "Hash": "{}", "Size": 1}}]}}"#,
+            support::FAKE_ROOT
+        ),
+    )
+    .unwrap();
+    let published = ipfs.join(format!("objects/{}.data", support::FAKE_ROOT));
+    let ours = std::fs::read_to_string(&metadata).unwrap();
+    let theirs = ours.replace(cb_processor::GENERATOR, "cb_processor 0.0.1 (0000000)");
+    assert_ne!(ours, theirs);
+    std::fs::write(&published, &theirs).unwrap();
+
+    let config = fixture.root.join("cb_processor.toml");
+    std::fs::write(&config, format!("[tools]\nipfs = {:?}\n", fake.display().to_string())).unwrap();
+    let report = fixture.root.join("drift.json");
+    let drift_check = || {
+        let output = cb_processor()
+            .arg("--config")
+            .arg(&config)
+            .arg("drift-check")
+            .arg("--input")
+            .arg(&fixture.season_json)
+            .arg("--metadata")
+            .arg(&metadata)
+            .arg("--hash")
+            .arg(support::FAKE_ROOT)
+            .arg("--path")
+            .arg("S01")
+            .arg("--json")
+            .arg(&report)
+            .output()
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+        (output, report)
+    };
+    let changed = |report: &serde_json::Value, path: &str| {
+        report["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|change| change["path"] == path)
+    };
+
+    // the fake has nothing else in S01, so the pages would be added to it
+    let (output, report) = drift_check();
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains(&format!("{}/S01 has drifted", support::FAKE_ROOT)),
+        "{:?}",
+        output
+    );
+    assert_eq!(report["path"], "S01");
+    assert!(changed(&report, "index.html"), "{}", report);
+    assert!(!changed(&report, "metadata.json"), "{}", report);
+
+    // but a metadata.json that says something else has drifted
+    std::fs::write(&published, theirs.replace(&season.title, "Another season")).unwrap();
+    let (_, report) = drift_check();
+    assert!(changed(&report, "metadata.json"), "{}", report);
+}
+
+#[test]
+fn metrics_file() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+    let metrics = dir.path().join("cb_processor.prom");
+    let config = dir.path().join("cb_processor.toml");
+    std::fs::write(&config, format!("metrics = {:?}\n", metrics.display().to_string())).unwrap();
+    let validate = || {
+        cb_processor()
+            .arg("--config")
+            .arg(&config)
+            .arg("--validate")
+            .arg("--input")
+            .arg(dir.path().join("data/season.json"))
+            .arg("--data")
+            .arg(dir.path().join("audio"))
+            .output()
+            .unwrap()
+    };
+
+    let output = validate();
+    assert!(output.status.success(), "{:?}", output);
+    let first = std::fs::read_to_string(&metrics).unwrap();
+    assert!(
+        first.contains("# TYPE cb_processor_validation_errors gauge\n"),
+        "{}",
+        first
+    );
+    assert!(first.contains("\ncb_processor_validation_errors 0\n"), "{}", first);
+    assert!(
+        first.contains("cb_processor_stage_last_run_timestamp_seconds{stage=\"validate\"}"),
+        "{}",
+        first
+    );
+
+    // the file is replaced, not appended to
+    assert!(validate().status.success());
+    let second = std::fs::read_to_string(&metrics).unwrap();
+    assert_eq!(second.matches("# TYPE").count(), first.matches("# TYPE").count());
+    let leftovers: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".tmp"))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}
+
+#[test]
+fn fmt() {
+    let dir = tempfile::tempdir().unwrap();
+    init_season(dir.path(), "Season");
+    let season_json = dir.path().join("data/season.json");
+    let example = dir.path().join("data/recordings/example.json");
+    // hand-edited: tracks out of order, CRLF line endings, and a field the schema doesn't know about
+    let mut recording: serde_json::Value = serde_json::from_slice(&std::fs::read(&example).unwrap()).unwrap();
+    recording["tracks"].as_array_mut().unwrap().insert(
+        0,
+        serde_json::json!({"id": 3, "name": "Snare", "flac": "example_snare.flac"}),
+    );
+    recording["notes"] = "kept".into();
+    let edited = serde_json::to_string_pretty(&recording).unwrap().replace('\n', "\r\n");
+    std::fs::write(&example, edited).unwrap();
+    let fmt = |check: bool| {
+        let mut cmd = cb_processor();
+        cmd.arg("fmt").arg("--input").arg(&season_json);
+        if check {
+            cmd.arg("--check");
+        }
+        cmd.output().unwrap()
+    };
+
+    let output = fmt(true);
+    assert!(!output.status.success());
+    assert!(
+        stdout(&output).contains("example.json is not formatted"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(std::fs::read_to_string(&example).unwrap().contains("\r\n"));
+
+    assert!(fmt(false).status.success());
+    let formatted = std::fs::read_to_string(&example).unwrap();
+    assert!(!formatted.contains('\r'));
+    assert!(
+        formatted.starts_with("{\n  \"$schema\": \"../schema/recording.json\",\n  \"title\""),
+        "{}",
+        formatted
+    );
+    assert!(formatted.ends_with("  \"notes\": \"kept\"\n}\n"), "{}", formatted);
+    assert!(formatted.find("example_kick.flac").unwrap() < formatted.find("example_snare.flac").unwrap());
+
+    let output = fmt(true);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(stdout(&output).contains("Everything is formatted already"));
+}
+
+#[test]
+fn gateways_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("prime_state.json");
+    let dead = |failed: usize| serde_json::json!({ "succeeded": 0, "failed": failed });
+    std::fs::write(
+        &state,
+        serde_json::json!({
+            "root": support::FAKE_ROOT,
+            "primed": {},
+            "health": {
+                "https://ipfs.io/ipfs/{v0}": { "runs": [dead(3), dead(1)] },
+                "https://{base32}.ipfs.dweb.link": { "runs": [{ "succeeded": 3, "failed": 1 }] },
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let config = dir.path().join("cb_processor.toml");
+    std::fs::write(
+        &config,
+        format!("prime_state = {:?}\nunhealthy_after = 2\n", state.display().to_string()),
+    )
+    .unwrap();
+
+    let output = cb_processor()
+        .args(["--offline", "--config"])
+        .arg(&config)
+        .args(["gateways", "status", "--json"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    let out = stdout(&output);
+    // nothing but the JSON, which can be piped into jq
+    let rows: serde_json::Value = serde_json::from_str(&out).unwrap();
+    let row = |gateway: &str| {
+        rows.as_array()
+            .unwrap()
+            .iter()
+            .find(|r| r["gateway"] == gateway)
+            .unwrap()
+    };
+    assert_eq!(row("https://ipfs.io/ipfs/{v0}")["unhealthy"], true);
+    assert_eq!(row("https://{base32}.ipfs.dweb.link")["success_percent"], 75);
+    assert_eq!(row("https://{base32}.ipfs.dweb.link")["unhealthy"], false);
+    assert_eq!(row("https://gateway.pinata.cloud/ipfs/{base32}")["runs"], 0);
+
+    // nothing was primed, or written
+    let unchanged: serde_json::Value = serde_json::from_slice(&std::fs::read(&state).unwrap()).unwrap();
+    assert_eq!(unchanged["primed"], serde_json::json!({}));
+}
+
+#[test]
+fn history_report_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let history = dir.path().join("roots_history.jsonl");
+    std::fs::write(
+        &history,
+        format!("{{\"root\":\"{}\",\"published\":1700000000}}\n", support::FAKE_ROOT),
+    )
+    .unwrap();
+    let config = dir.path().join("cb_processor.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[tools]\nipfs = {:?}\n",
+            dir.path().join("no-ipfs").display().to_string()
+        ),
+    )
+    .unwrap();
+    let output = cb_processor()
+        .arg("--config")
+        .arg(&config)
+        .args(["history-report", "--json", "--history"])
+        .arg(&history)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    // the version is on stderr, so stdout is only the JSON
+    let rows: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(rows[0]["root"], support::FAKE_ROOT);
+    assert_eq!(rows[0]["total"], serde_json::Value::Null);
+    assert!(String::from_utf8_lossy(&output.stderr).contains(cb_processor::VERSION));
+}
@@ -0,0 +1,87 @@
+//! Generates the fixture season twice, with the folders listed in a different order each time, and checks that the
+//! output comes out byte for byte the same
+//!
+//! This is in its own test program, since shuffling the listings (see [`cb_processor::listing::shuffle`]) affects
+//! everything else running in the same one.
+#![cfg(all(unix, feature = "templates"))]
+
+mod support;
+
+use std::path::{Path, PathBuf};
+
+use cb_processor::{
+    listing,
+    manifest::{MANIFEST_FILE, SOURCE_DATE_EPOCH_VAR},
+    pipeline::{ConvertOptions, Generation},
+    progress, Pipeline,
+};
+use support::{fake_tools_context, FixtureSeason};
+
+/// Every file under `dir`, relative to it
+fn files(dir: &Path, prefix: &Path, found: &mut Vec<PathBuf>) {
+    for entry in listing::sorted(dir).unwrap() {
+        let rel = prefix.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            files(&entry.path(), &rel, found);
+        } else {
+            found.push(rel);
+        }
+    }
+}
+
+#[test]
+fn same_output_in_any_order() {
+    progress::enable_log(false);
+    std::env::set_var(SOURCE_DATE_EPOCH_VAR, "1700000000");
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    let pipeline = Pipeline::new(&ctx);
+    let season = pipeline.load(&fixture.season_json, Some(&fixture.audio), None).unwrap();
+    let options = ConvertOptions {
+        peaks: true,
+        ..ConvertOptions::default()
+    };
+    pipeline.convert(&season, &fixture.audio, options).unwrap();
+
+    // the files are reported in the order they were written, which should be the same too
+    let (outputs, written): (Vec<PathBuf>, Vec<Vec<PathBuf>>) = [1, 2]
+        .iter()
+        .map(|seed| {
+            listing::shuffle(Some(*seed));
+            let output = fixture.root.join(format!("output{}", seed));
+            let metadata = output.join("metadata.json");
+            let season = pipeline.load(&fixture.season_json, Some(&fixture.audio), None).unwrap();
+            let generation = Generation {
+                season_json: &fixture.season_json,
+                output: &output,
+                data_dir: Some(&fixture.audio),
+                metadata: Some(&metadata),
+                force_metadata: false,
+            };
+            let generated = pipeline.generate(&season, generation).unwrap();
+            assert!(generated.written.contains(&metadata));
+            let written = generated
+                .written
+                .iter()
+                .map(|path| path.strip_prefix(&output).unwrap().to_path_buf())
+                .collect();
+            (output, written)
+        })
+        .unzip();
+    listing::shuffle(None);
+    assert_eq!(written[0], written[1]);
+
+    let mut first = Vec::new();
+    files(&outputs[0], Path::new(""), &mut first);
+    let mut second = Vec::new();
+    files(&outputs[1], Path::new(""), &mut second);
+    assert_eq!(first, second);
+    assert!(first.contains(&PathBuf::from(MANIFEST_FILE)));
+    for file in &first {
+        assert!(
+            std::fs::read(outputs[0].join(file)).unwrap() == std::fs::read(outputs[1].join(file)).unwrap(),
+            "{} came out different",
+            file.display()
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Generates the season in tests/fixtures/season, patches it into a real IPFS repo and reads it back out through the
+//! gateway
+//!
+//! This needs ipfs installed (a version that still has `ipfs object`), so it's ignored unless asked for with
+//! `cargo test --test ipfs_daemon -- --ignored`.  ffmpeg and mediainfo are still the fakes in tests/fixtures/bin, and
+//! the daemon has a throwaway repo and runs offline (see [`support::IpfsDaemon`]).
+#![cfg(all(unix, feature = "templates", feature = "ipfs"))]
+
+mod support;
+
+use std::collections::BTreeMap;
+
+use cb_processor::{
+    gateway::{self, Gateway},
+    pipeline::{ConvertOptions, Generation, Pipeline},
+};
+use support::{FixtureSeason, IpfsDaemon};
+
+#[test]
+#[ignore = "needs ipfs installed"]
+fn generate_patch_and_fetch() {
+    let daemon = IpfsDaemon::start();
+    let ctx = daemon.context();
+    let pipeline = Pipeline::new(&ctx);
+
+    let fixture = FixtureSeason::new();
+    let season_json = &fixture.season_json;
+    // like a real season, the pages go next to the audio, so that the flacs and oggs are published too
+    let audio = &fixture.audio;
+    let metadata = audio.join("metadata.json");
+
+    let season = pipeline.load(season_json, Some(audio), None).unwrap();
+    let converted = pipeline.convert(&season, audio, ConvertOptions::default()).unwrap();
+    assert_eq!(converted.conversions.len(), 3);
+    assert_eq!(pipeline.validate(season_json, audio).unwrap().errors, 0);
+    let season = pipeline.load(season_json, Some(audio), None).unwrap();
+    let to = Generation {
+        season_json,
+        output: audio,
+        data_dir: Some(audio),
+        metadata: Some(&metadata),
+        force_metadata: false,
+    };
+    pipeline.generate(&season, to).unwrap();
+
+    // everything is new to the empty root
+    let empty: cid::Cid = daemon.empty_root().parse().unwrap();
+    let plan = pipeline.plan_patch(&empty, audio).unwrap();
+    assert!(!plan.changes.is_empty());
+    let patched = pipeline.patch(&empty, audio, plan).unwrap();
+    assert_ne!(patched.new_root, empty);
+    let report = patched.report.as_ref().unwrap();
+    assert!(report.new_sizes.total > report.old_sizes.total);
+
+    // the DAG matches the output, so there's nothing left to patch
+    let discrepancies = cb_processor::ipfs::verify_patch(&ctx, &patched.new_root, audio).unwrap();
+    assert!(discrepancies.is_empty(), "{:#?}", discrepancies);
+    assert!(pipeline
+        .plan_patch(&patched.new_root, audio)
+        .unwrap()
+        .changes
+        .is_empty());
+    assert!(cb_processor::ipfs::missing_blocks(&ctx, &patched.new_root)
+        .unwrap()
+        .is_empty());
+
+    // and the gateway serves the files that were generated and converted, under the same URLs priming uses
+    let local = Gateway {
+        template: format!("{}/ipfs/{{v0}}", daemon.gateway),
+        headers: BTreeMap::new(),
+        token_env: None,
+    };
+    let root_url = local.root_url(&patched.new_root).unwrap();
+    let client = gateway::client(&ctx).unwrap();
+    for path in [
+        "index.html",
+        "metadata.json",
+        "s01e01-jam-1/index.html",
+        "jam1/jam1_kick.flac",
+        "jam1/ogg/jam1_stereo.ogg",
+    ] {
+        let url = gateway::link_url(&root_url, path).unwrap();
+        let response = client.get(url.clone()).send().unwrap();
+        assert!(response.status().is_success(), "{} answered {}", url, response.status());
+        let served = response.bytes().unwrap();
+        assert_eq!(&served[..], &std::fs::read(audio.join(path)).unwrap()[..], "{}", path);
+    }
+}
@@ -0,0 +1,110 @@
+//! Runs the fixture season through the [`Pipeline`] alone, the way a program embedding the library would
+#![cfg(all(unix, feature = "templates"))]
+
+mod support;
+
+use cb_processor::{
+    pipeline::{ConvertOptions, Generation},
+    progress, Pipeline,
+};
+use support::{fake_tools_context, FixtureSeason};
+
+#[test]
+fn fixture_pipeline() {
+    progress::enable_log(false);
+    let fixture = FixtureSeason::new();
+    let (season_json, audio, output) = (&fixture.season_json, &fixture.audio, &fixture.output);
+    let metadata = output.join("metadata.json");
+    let ctx = fake_tools_context();
+    let pipeline = Pipeline::new(&ctx);
+
+    // nothing has been converted yet, so every ogg is missing
+    let report = pipeline.validate(season_json, audio).unwrap();
+    assert_eq!(report.errors, 3);
+
+    let season = pipeline.load(season_json, Some(audio), None).unwrap();
+    let options = ConvertOptions {
+        peaks: true,
+        ..ConvertOptions::default()
+    };
+    let converted = pipeline.convert(&season, audio, options).unwrap();
+    assert_eq!(converted.conversions.len(), 3);
+    assert!(converted.peaks > 0);
+    assert_eq!(pipeline.validate(season_json, audio).unwrap().errors, 0);
+
+    let season = pipeline.load(season_json, Some(audio), Some(&metadata)).unwrap();
+    let generation = Generation {
+        season_json,
+        output,
+        data_dir: Some(audio),
+        metadata: Some(&metadata),
+        force_metadata: false,
+    };
+    let generated = pipeline.generate(&season, generation).unwrap();
+    assert!(generated.written.contains(&output.join("index.html")));
+    assert!(generated.written.contains(&metadata));
+    assert!(generated.written.contains(&output.join("source/recordings/jam1.json")));
+    assert!(generated.stale.is_empty());
+
+    // the metadata is enough to generate again without the audio
+    let cached = pipeline.load(season_json, None, Some(&metadata)).unwrap();
+    assert_eq!(cached.recordings.len(), season.recordings.len());
+}
+
+#[cfg(feature = "ipfs")]
+#[test]
+fn patch_and_publish() {
+    use std::str::FromStr;
+
+    use cb_processor::{
+        context::{HookConfig, HooksConfig},
+        error::CbError,
+        gateway::PrimeOptions,
+    };
+    use support::{fake_ipfs, FAKE_ADDED, FAKE_ROOT};
+
+    progress::enable_log(false);
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output");
+    std::fs::create_dir_all(&output).unwrap();
+    std::fs::write(output.join("index.html"), "<html></html>").unwrap();
+    let published = dir.path().join("published");
+
+    let mut ctx = fake_tools_context();
+    ctx.tools.ipfs = fake_ipfs(&dir.path().join("ipfs"), &[]);
+    ctx.roots_history = dir.path().join("roots_history.jsonl");
+    ctx.hooks = HooksConfig {
+        post_publish: Some(HookConfig {
+            command: format!("echo \"$CB_NEW_ROOT_CID\" > {}", published.display()),
+            allow_failure: false,
+        }),
+        ..HooksConfig::default()
+    };
+    let pipeline = Pipeline::new(&ctx);
+
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let plan = pipeline.plan_patch(&root, &output).unwrap();
+    assert_eq!(plan.num_changes(), 1);
+    let patched = pipeline.patch(&root, &output, plan).unwrap();
+    assert_eq!(patched.new_root.to_string(), FAKE_ADDED);
+    assert_eq!(patched.report.as_ref().unwrap().changes, 1);
+    // the page that changed, which is what --prime-first primes before the rest
+    assert_eq!(patched.report.as_ref().unwrap().changed, ["index.html"]);
+    assert!(std::fs::read_to_string(&ctx.roots_history)
+        .unwrap()
+        .contains(FAKE_ADDED));
+
+    let report = pipeline.publish(&patched, "self", false).unwrap();
+    assert_eq!(report.root, FAKE_ADDED);
+    assert!(report.notification.is_none());
+    assert_eq!(std::fs::read_to_string(&published).unwrap().trim(), FAKE_ADDED);
+
+    let offline = cb_processor::context::RunContext {
+        offline: true,
+        ..fake_tools_context()
+    };
+    assert!(matches!(
+        Pipeline::new(&offline).prime(&root, &PrimeOptions::default()),
+        Err(CbError::Offline { .. })
+    ));
+}
@@ -0,0 +1,146 @@
+use cb_processor::{load_metadata, types::Season, write_metadata, MetadataWriter};
+use serde_json::json;
+
+/// Builds a season with `n` recordings, in the same form as a metadata.json file
+fn season_with_recordings(n: usize) -> Season {
+    let track = |id: u8| {
+        json!({
+            "id": id,
+            "name": format!("Track {}", id),
+            "flac": format!("{}.flac", id),
+            "vorbis": format!("ogg/{}.ogg", id),
+            "mp3": null,
+            "patch_notes": null,
+            "ondisk_root": null,
+            "media_info": {
+                "@type": "Audio",
+                "Format": "FLAC",
+                "Channels": "2",
+                "SamplingRate": "48000",
+                "BitDepth": "24",
+                "Duration": "123.456"
+            },
+            "flac_bytes": 1000,
+            "ogg_bytes": 100,
+            "mp3_bytes": 0
+        })
+    };
+    let recordings: Vec<_> = (0..n)
+        .map(|i| {
+            json!({
+                "title": format!("Recording {}", i),
+                "data_folder": format!("rec{}", i),
+                "stereo_mix": track(1),
+                "recorded_date": "2021/01/01",
+                "torrent": null,
+                "tracks": [track(2), track(3)],
+                "tags": [],
+                "bpm": null,
+                "youtube_url": null
+            })
+        })
+        .collect();
+
+    serde_json::from_value(json!({ "title": "Test season", "recordings": recordings })).unwrap()
+}
+
+#[test]
+fn missing_directory_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("no-such-dir").join("metadata.json");
+
+    let err = write_metadata(&season_with_recordings(1), &path, false).unwrap_err();
+    let msg = format!("{:#}", err);
+    assert!(msg.contains("no-such-dir"), "error doesn't name the path: {}", msg);
+}
+
+#[test]
+fn refuses_to_shrink_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("metadata.json");
+
+    write_metadata(&season_with_recordings(3), &path, false).unwrap();
+
+    // growing (or staying the same size) is fine
+    write_metadata(&season_with_recordings(3), &path, false).unwrap();
+    write_metadata(&season_with_recordings(4), &path, false).unwrap();
+
+    // shrinking needs to be forced
+    assert!(write_metadata(&season_with_recordings(2), &path, false).is_err());
+    let old: Season = serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(old.recordings.len(), 4);
+
+    write_metadata(&season_with_recordings(2), &path, true).unwrap();
+    let new: Season = serde_json::from_reader(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(new.recordings.len(), 2);
+}
+
+#[test]
+fn written_one_recording_at_a_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("metadata.json");
+    let season = season_with_recordings(3);
+
+    let mut writer = MetadataWriter::create(&path, &season.generator, &season.title, &season.artist).unwrap();
+    for recording in &season.recordings {
+        writer.push(recording).unwrap();
+    }
+    // nothing is replaced until it's finished
+    assert!(!path.exists());
+    writer
+        .finish(&season.redirects, season.low_quality_ogg.as_ref(), false)
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), serde_json::to_vec(&season).unwrap());
+
+    // one that's given up on leaves the old file, and nothing next to it
+    let mut writer = MetadataWriter::create(&path, &season.generator, "Other season", &season.artist).unwrap();
+    writer.push(&season.recordings[0]).unwrap();
+    drop(writer);
+    assert_eq!(std::fs::read(&path).unwrap(), serde_json::to_vec(&season).unwrap());
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn split_per_recording() {
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("metadata.index.json");
+    let recordings = dir.path().join("metadata");
+    let season = season_with_recordings(3);
+
+    write_metadata(&season, &index, false).unwrap();
+    for i in 0..3 {
+        assert!(recordings.join(format!("rec{}.json", i)).exists());
+    }
+    let loaded = load_metadata(&index).unwrap();
+    assert_eq!(
+        serde_json::to_vec(&loaded).unwrap(),
+        serde_json::to_vec(&season).unwrap()
+    );
+
+    // like metadata.json, shrinking needs to be forced, and then the recordings that are gone are deleted
+    assert!(write_metadata(&season_with_recordings(2), &index, false).is_err());
+    write_metadata(&season_with_recordings(2), &index, true).unwrap();
+    assert!(!recordings.join("rec2.json").exists());
+    assert_eq!(load_metadata(&index).unwrap().recordings.len(), 2);
+
+    // a recording that changed without the index is noticed
+    std::fs::write(recordings.join("rec1.json"), "{}").unwrap();
+    let message = match load_metadata(&index) {
+        Ok(_) => panic!("a changed recording was loaded"),
+        Err(e) => format!("{:#}", e),
+    };
+    assert!(message.contains("rec1.json has changed"), "{}", message);
+}
+
+#[test]
+fn index_falls_back_to_metadata_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let season = season_with_recordings(2);
+    write_metadata(&season, &dir.path().join("metadata.json"), false).unwrap();
+
+    // until it's written, the index is read from the metadata.json it replaces
+    let index = dir.path().join("metadata.index.json");
+    assert_eq!(load_metadata(&index).unwrap().recordings.len(), 2);
+    write_metadata(&season_with_recordings(1), &index, true).unwrap();
+    assert_eq!(load_metadata(&index).unwrap().recordings.len(), 1);
+}
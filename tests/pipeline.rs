@@ -0,0 +1,1399 @@
+//! Runs the whole pipeline (validate, convert, generate) on the season in tests/fixtures/season
+//!
+//! The real ffmpeg and mediainfo are replaced by the scripts in tests/fixtures/bin, so this runs anywhere.  The pages
+//! and metadata are compared against tests/fixtures/golden; run with `UPDATE_GOLDEN=1` to accept changes to them.
+#![cfg(all(unix, feature = "templates"))]
+
+mod support;
+
+use std::path::Path;
+
+#[cfg(feature = "ipfs")]
+use cb_processor::share;
+use cb_processor::{
+    asset_map, checksum,
+    error::CbError,
+    hasher::HasherPool,
+    manifest::BuildManifest,
+    media_cache::MediaInfoCache,
+    peaks,
+    pipeline::{ConvertOptions, Generation, Pipeline},
+    precompress, quarantine, stats,
+    types::{Season, Usage},
+    Freshness,
+};
+use support::{copy_dir, edit_json, fake_tools_context, fixtures, FixtureSeason};
+
+/// Compares a generated file with its golden copy, after replacing the parts that change from run to run
+fn check_golden(root: &Path, generated: &Path, golden: &str) {
+    let contents = std::fs::read_to_string(generated).unwrap();
+    let contents = contents
+        .replace(cb_processor::GENERATOR, "{GENERATOR}")
+        .replace(&root.display().to_string(), "{ROOT}");
+
+    let golden = fixtures().join("golden").join(golden);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+        std::fs::write(&golden, contents).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&golden)
+        .unwrap_or_else(|e| panic!("Failed to read {} ({}), run with UPDATE_GOLDEN=1", golden.display(), e));
+    if contents != expected {
+        let line = contents
+            .lines()
+            .zip(expected.lines())
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| contents.lines().count().min(expected.lines().count()))
+            + 1;
+        panic!(
+            "{} doesn't match {} (first difference on line {}), run with UPDATE_GOLDEN=1 if this is intended",
+            generated.display(),
+            golden.display(),
+            line
+        );
+    }
+}
+
+#[test]
+fn fixture_season() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+
+    // nothing has been converted yet, so every ogg is missing
+    assert_eq!(fixture.validate(&ctx), 3);
+
+    let season = fixture.load(&ctx);
+    let conversions = cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(conversions.len(), 3);
+    for conversion in &conversions {
+        assert!(
+            conversion.output.exists(),
+            "{} wasn't made",
+            conversion.output.display()
+        );
+        // the fixture flacs are 24 bit
+        assert!(conversion.filters.contains("dither_method=triangular"));
+
+        let (_, record) = cb_processor::provenance::find(&conversion.output).unwrap().unwrap();
+        assert_eq!(record.ffmpeg_version, "ffmpeg version fake");
+        assert_eq!(record.input_sha256, checksum::sha256_file(&conversion.input).unwrap());
+        assert_eq!(record.command.last().unwrap(), &conversion.output.display().to_string());
+    }
+    // everything is converted, so there's nothing left to do
+    assert!(cb_processor::convert_all(&ctx, &season).unwrap().is_empty());
+    assert_eq!(fixture.validate(&ctx), 0);
+
+    // load again, now that the oggs exist
+    let season = fixture.load(&ctx);
+    assert_eq!(season.recordings.len(), 2);
+    let kick = &season.recordings[0].tracks[0];
+    assert_eq!(kick.media_info.format, "FLAC");
+    assert_eq!(kick.ogg_info.as_ref().unwrap().format, "Vorbis");
+
+    // the fixture flacs are all copies of the same file
+    let duplicates = cb_processor::duplicates::find_duplicates(&ctx, &season).unwrap();
+    assert_eq!(duplicates.groups.len(), 1);
+    assert_eq!(duplicates.groups[0].files.len(), 3);
+    assert_eq!(duplicates.duplicated_bytes, 2 * duplicates.groups[0].bytes);
+
+    cb_processor::write_season_index(&ctx, &season, &fixture.output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    cb_processor::write_metadata(&season, &fixture.output.join("metadata.json"), false).unwrap();
+    cb_processor::write_badges(&ctx, &season, &fixture.output).unwrap();
+
+    check_golden(&fixture.root, &fixture.output.join("index.html"), "index.html");
+    // jam1's slug comes from its title, jam2's is given and is the same as its data_folder
+    assert_eq!(season.recordings[0].slug, "s01e01-jam-1");
+    check_golden(
+        &fixture.root,
+        &fixture.output.join("s01e01-jam-1/index.html"),
+        "s01e01-jam-1/index.html",
+    );
+    assert!(fixture.output.join("jam1/style.css").exists());
+    check_golden(
+        &fixture.root,
+        &fixture.output.join("jam2/index.html"),
+        "jam2/index.html",
+    );
+    // jam2 names jam1 by its data_folder, and the relation goes both ways
+    assert_eq!(season.recordings[0].related, ["jam2"]);
+    assert_eq!(season.recordings[1].related, ["s01e01-jam-1"]);
+    check_golden(
+        &fixture.root,
+        &fixture.output.join("compare/jam2--s01e01-jam-1/index.html"),
+        "compare/jam2--s01e01-jam-1/index.html",
+    );
+    check_golden(&fixture.root, &fixture.output.join("playlist.m3u"), "playlist.m3u");
+    check_golden(&fixture.root, &fixture.output.join("metadata.json"), "metadata.json");
+    for badge in ["recordings.svg", "hours.svg", "size.svg"] {
+        check_golden(
+            &fixture.root,
+            &fixture.output.join("badges").join(badge),
+            &format!("badges/{}", badge),
+        );
+    }
+
+    // once the manifest lists a page, it's only written again if it would come out different; a stand-in of the same
+    // size shows that it wasn't, while the playlist that was cut short is
+    let page = fixture.output.join("jam2/index.html");
+    let playlist = fixture.output.join("playlist.m3u");
+    BuildManifest::record(
+        &HasherPool::default(),
+        &fixture.output,
+        &[page.clone(), playlist.clone()],
+        false,
+    )
+    .unwrap();
+    let stand_in = vec![b'x'; std::fs::metadata(&page).unwrap().len() as usize];
+    std::fs::write(&page, &stand_in).unwrap();
+    std::fs::write(&playlist, "#EXTM3U\n").unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    assert_eq!(std::fs::read(&page).unwrap(), stand_in);
+    check_golden(&fixture.root, &playlist, "playlist.m3u");
+
+    // the metadata can be used in place of the data dir
+    let cached: Season = serde_json::from_slice(&std::fs::read(fixture.output.join("metadata.json")).unwrap()).unwrap();
+    let from_metadata = Season::load(&ctx, &fixture.season_json, None, Some(&cached)).unwrap();
+    assert_eq!(
+        serde_json::to_value(&from_metadata.recordings[1].stereo_mix.media_info).unwrap(),
+        serde_json::to_value(&season.recordings[1].stereo_mix.media_info).unwrap()
+    );
+    assert!(from_metadata.redirects.is_empty());
+
+    // a page whose slug changed leaves a redirect behind
+    let mut renamed: Season =
+        serde_json::from_slice(&std::fs::read(fixture.output.join("metadata.json")).unwrap()).unwrap();
+    renamed.recordings[0].slug = "first-jam".to_string();
+    let reloaded = Season::load(&ctx, &fixture.season_json, None, Some(&renamed)).unwrap();
+    assert_eq!(reloaded.redirects["first-jam"], "s01e01-jam-1");
+    cb_processor::write_all_recording_index(&ctx, &reloaded, &fixture.output).unwrap();
+    let redirect = std::fs::read_to_string(fixture.output.join("first-jam/index.html")).unwrap();
+    assert!(redirect.contains("url=../s01e01-jam-1/"));
+}
+
+#[test]
+fn corrupt_flac_is_quarantined() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    let ffmpeg = fixture.script(
+        "ffmpeg",
+        &format!(
+            "#!/bin/sh\ncase \"$*\" in *jam1_kick*) echo 'Invalid data found' >&2; exit 1;; esac\nexec {} \"$@\"\n",
+            ctx.tools.ffmpeg.display()
+        ),
+    );
+    let working_ffmpeg = std::mem::replace(&mut ctx.tools.ffmpeg, ffmpeg);
+
+    let season = fixture.load(&ctx);
+    for _ in 0..quarantine::QUARANTINE_AFTER {
+        // the other flacs still get converted
+        assert!(cb_processor::convert_all(&ctx, &season).is_err());
+        assert!(fixture.audio.join("jam2/ogg/jam2_stereo.ogg").exists());
+        assert!(!fixture.audio.join("jam1/ogg/jam1_kick.ogg").exists());
+    }
+    // now it's skipped, and validation wants someone to look at it
+    assert!(cb_processor::convert_all(&ctx, &season).unwrap().is_empty());
+    let quarantine = quarantine::Quarantine::load(&fixture.audio.join("jam1")).unwrap();
+    let quarantined: Vec<_> = quarantine.quarantined().map(|(source, _)| source.as_str()).collect();
+    assert_eq!(quarantined, ["jam1_kick.flac"]);
+    // the missing ogg, and the quarantine
+    assert_eq!(fixture.validate(&ctx), 2);
+
+    ctx.tools.ffmpeg = working_ffmpeg;
+    assert_eq!(quarantine::clear(&ctx, &season).unwrap(), 1);
+    assert_eq!(cb_processor::convert_all(&ctx, &season).unwrap().len(), 1);
+    assert!(!fixture.audio.join("jam1").join(quarantine::QUARANTINE_FILE).exists());
+    assert_eq!(fixture.validate(&ctx), 0);
+}
+
+#[test]
+fn interrupted_conversions() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    // the kick dies halfway, and the stereo mix of jam2 comes out empty
+    let last = "eval \"out=\\${$#}\"";
+    let ffmpeg = fixture.script(
+        "ffmpeg",
+        &format!(
+            "#!/bin/sh\n{}\ncase \"$*\" in\n*jam1_kick*) echo half > \"$out\"; exit 1;;\n*jam2_stereo*) : > \"$out\"; exit 0;;\nesac\nexec {} \"$@\"\n",
+            last,
+            ctx.tools.ffmpeg.display()
+        ),
+    );
+    let working_ffmpeg = std::mem::replace(&mut ctx.tools.ffmpeg, ffmpeg);
+
+    let season = fixture.load(&ctx);
+    assert!(cb_processor::convert_all(&ctx, &season).is_err());
+    let kick = fixture.audio.join("jam1/ogg/jam1_kick.ogg");
+    let stereo = fixture.audio.join("jam1/ogg/jam1_stereo.ogg");
+    assert!(!kick.exists());
+    assert!(!fixture.audio.join("jam2/ogg/jam2_stereo.ogg").exists());
+    assert!(stereo.exists());
+    // and nothing's left of the attempts
+    let leftovers = |folder: &str| {
+        std::fs::read_dir(fixture.audio.join(folder).join("ogg"))
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(".tmp.")
+            })
+            .count()
+    };
+    assert_eq!(leftovers("jam1") + leftovers("jam2"), 0);
+
+    // a conversion that's much shorter than the flac is broken too, and what was already there stays
+    ctx.tools.ffmpeg = working_ffmpeg;
+    let mediainfo = std::fs::read_to_string(&ctx.tools.mediainfo).unwrap();
+    let short = fixture.script("short", &mediainfo.replace("\"1.091\"", "\"0.2\""));
+    ctx.tools.mediainfo = fixture.script(
+        "mediainfo",
+        &format!(
+            "#!/bin/sh\ncase \"$*\" in *.tmp.*) exec {} \"$@\";; esac\nexec {} \"$@\"\n",
+            short.display(),
+            ctx.tools.mediainfo.display()
+        ),
+    );
+    let before = std::fs::metadata(&stereo).unwrap().modified().unwrap();
+    let e = cb_processor::convert_all(&ctx, &season).unwrap_err();
+    let message = std::error::Error::source(&e).unwrap().to_string();
+    assert!(message.contains("is 0.2s long, but the flac is 1.1s"), "{}", message);
+    assert!(!kick.exists());
+    assert_eq!(std::fs::metadata(&stereo).unwrap().modified().unwrap(), before);
+}
+
+#[test]
+fn parallel_conversions() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    ctx.jobs = 4;
+    let ffmpeg = fixture.script(
+        "ffmpeg",
+        &format!(
+            "#!/bin/sh\ncase \"$*\" in *jam1_kick*|*jam2_stereo*) echo 'Invalid data found' >&2; exit 1;; esac\nexec {} \"$@\"\n",
+            ctx.tools.ffmpeg.display()
+        ),
+    );
+    let working_ffmpeg = std::mem::replace(&mut ctx.tools.ffmpeg, ffmpeg);
+
+    // both failures are recorded, and the conversion in the same folder as one of them isn't lost
+    let season = fixture.load(&ctx);
+    assert!(cb_processor::convert_all(&ctx, &season).is_err());
+    assert!(fixture.audio.join("jam1/ogg/jam1_stereo.ogg").exists());
+    let failed = |folder: &str| {
+        let quarantine = quarantine::Quarantine::load(&fixture.audio.join(folder)).unwrap();
+        quarantine.sources.keys().cloned().collect::<Vec<_>>()
+    };
+    assert_eq!(failed("jam1"), ["jam1_kick.flac"]);
+    assert_eq!(failed("jam2"), ["jam2_stereo.flac"]);
+    assert!(
+        cb_processor::provenance::find(&fixture.audio.join("jam1/ogg/jam1_stereo.ogg"))
+            .unwrap()
+            .is_some()
+    );
+
+    // what was made comes back in the season's order, whichever finished first
+    ctx.tools.ffmpeg = working_ffmpeg;
+    let made: Vec<_> = cb_processor::convert_all(&ctx, &season)
+        .unwrap()
+        .into_iter()
+        .map(|conversion| conversion.output)
+        .collect();
+    assert_eq!(
+        made,
+        [
+            fixture.audio.join("jam1/ogg/jam1_kick.ogg"),
+            fixture.audio.join("jam2/ogg/jam2_stereo.ogg")
+        ]
+    );
+    assert_eq!(fixture.validate(&ctx), 0);
+}
+
+#[test]
+fn descriptions() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+
+    std::fs::write(
+        fixture.audio.join("jam2/README.md"),
+        "With **Jane** on bass.\n\n- track 2 got lost <sorry>",
+    )
+    .unwrap();
+    let season = fixture.load(&ctx);
+    assert_eq!(season.recordings[0].description, None);
+    std::fs::create_dir_all(&fixture.output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("jam2/index.html")).unwrap();
+    assert!(page.contains("<p>With <strong>Jane</strong> on bass.</p>\n<ul>\n<li>track 2 got lost &lt;sorry&gt;</li>"));
+
+    // it's kept in the metadata for when there's no data dir
+    cb_processor::write_metadata(&season, &fixture.output.join("metadata.json"), false).unwrap();
+    let cached: Season = serde_json::from_slice(&std::fs::read(fixture.output.join("metadata.json")).unwrap()).unwrap();
+    let from_metadata = Season::load(&ctx, &fixture.season_json, None, Some(&cached)).unwrap();
+    assert_eq!(
+        from_metadata.recordings[1].description,
+        season.recordings[1].description
+    );
+
+    // but it can't be in both places
+    edit_json(&fixture.recording_json("jam2"), |json| {
+        json["description"] = "Also here".into()
+    });
+    // (the other 3 are the oggs that haven't been made)
+    assert_eq!(fixture.validate(&ctx), 4);
+}
+
+#[test]
+fn renamed_flac_is_refreshed() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+
+    let cached = fixture.converted(&ctx);
+    assert!(cached.recordings[0].tracks[0].ogg_info.is_some());
+
+    // fix a "typo" in the kick's file name, and make it a different file while we're at it
+    std::fs::remove_file(fixture.audio.join("jam1/jam1_kick.flac")).unwrap();
+    std::fs::copy(
+        fixtures().join("season/audio/jam1/jam1_kick.flac"),
+        fixture.audio.join("jam1/jam1_kick_drum.flac"),
+    )
+    .unwrap();
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(fixture.audio.join("jam1/jam1_kick_drum.flac"))
+        .and_then(|mut f| std::io::Write::write_all(&mut f, b"padding"))
+        .unwrap();
+    let jam1_json = fixture.recording_json("jam1");
+    let jam1 = std::fs::read_to_string(&jam1_json).unwrap();
+    std::fs::write(&jam1_json, jam1.replace("jam1_kick.flac", "jam1_kick_drum.flac")).unwrap();
+
+    let reloaded = Season::load(&ctx, &fixture.season_json, Some(&fixture.audio), Some(&cached)).unwrap();
+    let kick = &reloaded.recordings[0].tracks[0];
+    assert_eq!(kick.flac, "jam1_kick_drum.flac");
+    assert_eq!(
+        kick.flac_size_bytes(),
+        cached.recordings[0].tracks[0].flac_size_bytes() + 7
+    );
+    // the old ogg was made from the old name, so there's no ogg for this one yet
+    assert_eq!(kick.ogg_info, None);
+
+    // without the data dir, there's nothing to refresh from
+    assert!(matches!(
+        Season::load(&ctx, &fixture.season_json, None, Some(&cached)),
+        Err(cb_processor::error::CbError::MissingFile { .. })
+    ));
+}
+
+#[test]
+fn recording_terms() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+
+    edit_json(&fixture.recording_json("jam2"), |json| {
+        json["tos"] = "credits.txt".into()
+    });
+    // the 3 oggs, and the terms
+    assert_eq!(fixture.validate(&ctx), 4);
+
+    std::fs::write(fixture.audio.join("jam2/credits.txt"), "Guest: Jane, CC BY-NC").unwrap();
+    let season = fixture.load(&ctx);
+    std::fs::create_dir_all(&fixture.output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(fixture.output.join("jam2/ToS.txt")).unwrap(),
+        "Guest: Jane, CC BY-NC"
+    );
+    assert_eq!(
+        std::fs::read(fixture.output.join("jam1/ToS.txt")).unwrap(),
+        std::fs::read(ctx.static_dir.join("ToS.txt")).unwrap()
+    );
+    let page = std::fs::read_to_string(fixture.output.join("jam2/index.html")).unwrap();
+    assert!(page.contains("(this recording has its own terms)"));
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(!page.contains("its own terms"));
+
+    // generating into the data dir leaves the terms alone, even when they're called ToS.txt
+    std::fs::rename(
+        fixture.audio.join("jam2/credits.txt"),
+        fixture.audio.join("jam2/ToS.txt"),
+    )
+    .unwrap();
+    edit_json(&fixture.recording_json("jam2"), |json| json["tos"] = "ToS.txt".into());
+    let season = fixture.load(&ctx);
+    let written = cb_processor::write_all_recording_index(&ctx, &season, &fixture.audio).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(fixture.audio.join("jam2/ToS.txt")).unwrap(),
+        "Guest: Jane, CC BY-NC"
+    );
+    assert!(!written.contains(&fixture.audio.join("jam2/ToS.txt")));
+}
+
+#[test]
+fn artists() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+
+    // a guest-hosted session
+    edit_json(&fixture.recording_json("jam2"), |json| {
+        json["artist"] = "Guest Host".into()
+    });
+    let season = fixture.converted(&ctx);
+    assert_eq!(season.recordings[0].artist, "Colin Benders");
+    assert_eq!(season.recordings[1].artist, "Guest Host");
+    std::fs::create_dir_all(&fixture.output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let playlist = std::fs::read_to_string(fixture.output.join("playlist.m3u")).unwrap();
+    assert!(playlist.contains(",Colin Benders - S01E01 - Jam 1\n"));
+    assert!(playlist.contains(",Guest Host - S01E02 - Jam 2\n"));
+    let page = std::fs::read_to_string(fixture.output.join("jam2/index.html")).unwrap();
+    assert!(page.contains("Guest Host, recorded on"));
+
+    // without the season's artist, jam1 has nobody
+    edit_json(&fixture.season_json, |json| {
+        json.as_object_mut().unwrap().remove("artist");
+    });
+    assert_eq!(fixture.validate(&ctx), 1);
+    match Season::load(&ctx, &fixture.season_json, Some(&fixture.audio), None) {
+        Err(e @ cb_processor::error::CbError::MissingArtist { .. }) => {
+            assert!(e.to_string().contains("\"artist\": \"Colin Benders\""))
+        }
+        other => panic!("expected a missing artist, got {:?}", other.map(|s| s.title)),
+    }
+}
+
+#[test]
+#[cfg(feature = "schema")]
+fn invalid_schemas() {
+    use cb_processor::error::CbError;
+
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    edit_json(&fixture.recording_json("jam1"), |json| {
+        json["$schema"] = "../schema/broken.json".into()
+    });
+
+    // only jam2's missing ogg is counted alongside the schema, as jam1 can't be checked
+    let jam2_errors = 1;
+    for (broken, pointer) in [("syntax.json", ""), ("bad_type.json", "/properties/title")] {
+        std::fs::copy(
+            fixtures().join("bad_schemas").join(broken),
+            fixture.root.join("data/schema/broken.json"),
+        )
+        .unwrap();
+        assert_eq!(fixture.validate(&ctx), 1 + jam2_errors, "{}", broken);
+        match Season::load(&ctx, &fixture.season_json, Some(&fixture.audio), None) {
+            Err(CbError::InvalidSchema {
+                schema, pointer: found, ..
+            }) => {
+                assert!(schema.ends_with("broken.json"));
+                assert_eq!(found, pointer, "{}", broken);
+            }
+            other => panic!("expected a broken schema, got {:?}", other.map(|s| s.title)),
+        }
+    }
+}
+
+#[test]
+fn precompressed_copies() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    ctx.precompress.gzip = true;
+    ctx.precompress.brotli = true;
+    ctx.precompress.min_size = 600;
+    let season = fixture.load(&ctx);
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    let generate = |ctx: &cb_processor::context::RunContext, season: &Season| {
+        let mut written = cb_processor::write_season_index(ctx, season, &fixture.output).unwrap();
+        written.extend(cb_processor::write_all_recording_index(ctx, season, &fixture.output).unwrap());
+        cb_processor::write_metadata(season, &fixture.output.join("metadata.json"), true).unwrap();
+        written.push(fixture.output.join("metadata.json"));
+        let compressed = precompress::write_siblings(ctx, &fixture.output, &written).unwrap();
+        written.extend(compressed.iter().cloned());
+        BuildManifest::record(&ctx.hashes, &fixture.output, &written, true).unwrap();
+        compressed
+    };
+    let gunzip_len = |path: &Path| {
+        let gz = std::fs::read(path).unwrap();
+        let mut len = [0; 4];
+        len.copy_from_slice(&gz[gz.len() - 4..]);
+        u64::from(u32::from_le_bytes(len))
+    };
+
+    let mut season = fixture.load(&ctx);
+    let compressed = generate(&ctx, &season);
+    let page = fixture.output.join("jam2/index.html");
+    let page_gz = fixture.output.join("jam2/index.html.gz");
+    assert!(compressed.contains(&page_gz));
+    assert!(compressed.contains(&fixture.output.join("metadata.json.br")));
+    assert_eq!(gunzip_len(&page_gz), std::fs::metadata(&page).unwrap().len());
+    assert_eq!(
+        std::fs::read(fixture.output.join("jam2/index.html.br")).unwrap(),
+        [&b"brotli:"[..], &std::fs::read(&page).unwrap()].concat()
+    );
+    // the playlist is too small, and ToS.txt isn't one of the text formats
+    assert!(!fixture.output.join("playlist.m3u.gz").exists());
+    assert!(!fixture.output.join("jam2/ToS.txt.gz").exists());
+    let manifest = BuildManifest::load(&fixture.output).unwrap().unwrap();
+    assert_eq!(
+        manifest.files["jam2/index.html.gz"].category,
+        cb_processor::manifest::Category::Html
+    );
+
+    // a page that changes gets new copies, while the ones for pages that didn't are left alone
+    let index_gz = fixture.output.join("index.html.gz");
+    std::fs::write(
+        &index_gz,
+        vec![b'x'; std::fs::metadata(&index_gz).unwrap().len() as usize],
+    )
+    .unwrap();
+    season.recordings[1].description = Some("A much longer description of the jam. ".repeat(10));
+    generate(&ctx, &season);
+    assert_eq!(gunzip_len(&page_gz), std::fs::metadata(&page).unwrap().len());
+    assert!(std::fs::read(&index_gz).unwrap().iter().all(|&b| b == b'x'));
+
+    // turning brotli off deletes its copies, which would otherwise hold old pages
+    ctx.precompress.brotli = false;
+    let compressed = generate(&ctx, &season);
+    assert!(!fixture.output.join("jam2/index.html.br").exists());
+    assert!(compressed.iter().all(|path| path.extension().unwrap() == "gz"));
+    let manifest = BuildManifest::load(&fixture.output).unwrap().unwrap();
+    assert!(!manifest.files.keys().any(|name| name.ends_with(".br")));
+}
+
+#[test]
+fn hashed_assets() {
+    let fixture = FixtureSeason::new();
+    let static_dir = fixture.root.join("static");
+    copy_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("static"), &static_dir);
+    let mut ctx = fake_tools_context();
+    ctx.static_dir = static_dir.clone();
+    ctx.hashed_assets = true;
+    let season = fixture.converted(&ctx);
+    let generate = || {
+        let mut written = cb_processor::write_season_index(&ctx, &season, &fixture.output).unwrap();
+        written.extend(cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap());
+        BuildManifest::record(&ctx.hashes, &fixture.output, &written, true).unwrap()
+    };
+    let style_name = || {
+        asset_map::hashed_name(
+            "style.css",
+            &checksum::sha256_file(&static_dir.join("style.css")).unwrap(),
+        )
+    };
+
+    generate();
+    let old = style_name();
+    let index = std::fs::read_to_string(fixture.output.join("index.html")).unwrap();
+    assert!(index.contains(&format!("href=\"{}\"", old)), "{}", index);
+    assert!(index.contains("href=\"css/all."));
+    let page = std::fs::read_to_string(fixture.output.join("jam2/index.html")).unwrap();
+    assert!(page.contains(&format!("href=\"{}\"", old)));
+    // the plain copies are still there, for anything that links to them
+    for name in ["style.css", old.as_str(), "jam2/style.css", &format!("jam2/{}", old)] {
+        assert!(fixture.output.join(name).exists(), "{}", name);
+    }
+    let manifest = BuildManifest::load(&fixture.output).unwrap().unwrap();
+    assert!(manifest.files.contains_key("style.css"));
+    assert!(manifest.files.contains_key(&old));
+
+    // a new stylesheet gets a new name, and the old one is left for clean
+    std::fs::write(static_dir.join("style.css"), "body { color: red }").unwrap();
+    let stale = generate();
+    let new = style_name();
+    assert_ne!(new, old);
+    assert!(std::fs::read_to_string(fixture.output.join("index.html"))
+        .unwrap()
+        .contains(&new));
+    assert!(stale.contains(&old), "{:?}", stale);
+}
+
+#[test]
+fn anchors() {
+    // the golden pages have them
+    let golden = fixtures().join("golden");
+    let index = std::fs::read_to_string(golden.join("index.html")).unwrap();
+    assert!(index.contains(r##"<tr id="rec-s01e01-jam-1" class="rec""##));
+    assert!(index.contains(r##"href="#rec-jam2""##));
+    let page = std::fs::read_to_string(golden.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains(r##"<tr id="track-2" class="track">"##));
+    assert!(page.contains(r##"href="../s01e01-jam-1/#track-2""##));
+
+    // and they don't depend on the order of the recordings or tracks
+    let fixture = FixtureSeason::new();
+    edit_json(&fixture.season_json, |json| {
+        json["recordings"].as_array_mut().unwrap().reverse();
+    });
+    edit_json(&fixture.recording_json("jam1"), |jam1| {
+        let mut snare = jam1["tracks"][0].clone();
+        snare["id"] = 3.into();
+        snare["name"] = "Snare".into();
+        jam1["tracks"].as_array_mut().unwrap().insert(0, snare);
+    });
+
+    let ctx = fake_tools_context();
+    let season = fixture.load(&ctx);
+    cb_processor::write_season_index(&ctx, &season, &fixture.output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let index = std::fs::read_to_string(fixture.output.join("index.html")).unwrap();
+    assert!(index.find(r#"id="rec-jam2""#).unwrap() < index.find(r#"id="rec-s01e01-jam-1""#).unwrap());
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains(r#"<tr id="track-2" class="track">"#));
+    assert!(page.contains(r#"<tr id="track-3" class="track">"#));
+}
+
+#[test]
+fn keep_going() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    edit_json(&fixture.recording_json("jam1"), |json| {
+        json["tracks"] = "kick, snare".into()
+    });
+
+    assert!(Season::load(&ctx, &fixture.season_json, Some(&fixture.audio), None).is_err());
+    assert!(cb_processor::validate_and_print(&ctx, &fixture.season_json, &fixture.audio).is_err());
+
+    ctx.keep_going = true;
+    for _ in 0..2 {
+        let season = fixture.load(&ctx);
+        assert_eq!(season.recordings.len(), 1);
+        assert_eq!(season.skipped.len(), 1);
+        assert_eq!(season.skipped[0].name(), "S01E01 - Jam 1");
+        assert!(season.skipped[0].error.contains("jam1.json"));
+    }
+    // loading the season again doesn't report it twice
+    assert_eq!(ctx.skipped.lock().unwrap().len(), 1);
+    // jam1 and jam2's missing ogg
+    assert_eq!(fixture.validate(&ctx), 2);
+
+    let season = fixture.load(&ctx);
+    cb_processor::write_season_index(&ctx, &season, &fixture.output).unwrap();
+    let index = std::fs::read_to_string(fixture.output.join("index.html")).unwrap();
+    assert!(index.contains("<li>S01E01 - Jam 1</li>"));
+    assert!(!index.contains("rec-s01e01-jam-1"));
+}
+
+#[test]
+fn low_quality_oggs() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    edit_json(&fixture.season_json, |json| {
+        json["low_quality_ogg"] = serde_json::json!({"quality": 2});
+    });
+
+    // the oggs, and a low-quality ogg for each of the two stereo mixes
+    assert_eq!(fixture.validate(&ctx), 5);
+    let season = fixture.load(&ctx);
+    let conversions = cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(conversions.len(), 5);
+    let lq: Vec<_> = conversions.iter().filter(|c| !c.encoder_args.is_empty()).collect();
+    assert_eq!(lq.len(), 2);
+    assert!(lq[0].output.to_string_lossy().ends_with(".lq.ogg"));
+    let (_, record) = cb_processor::provenance::find(&lq[0].output).unwrap().unwrap();
+    assert!(record.command.windows(2).any(|w| w == ["-q:a", "2"]));
+    assert_eq!(fixture.validate(&ctx), 0);
+
+    let season = fixture.load(&ctx);
+    let mix = &season.recordings[0].stereo_mix;
+    let lq_ogg = mix.lq_ogg.clone().unwrap();
+    assert!(mix.lq_ogg_bytes > 0);
+    assert!(season.recordings[0].tracks.iter().all(|t| t.lq_ogg.is_none()));
+
+    // the player streams the low-quality ogg, and the download is still the full-quality one
+    cb_processor::write_season_index(&ctx, &season, &fixture.output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains(&format!("<source src=\"{}\" type=\"audio/ogg\" />", lq_ogg)));
+    assert!(page.contains(&format!(
+        "<a href=\"{}\" download>Ogg</a>",
+        mix.vorbis.as_ref().unwrap()
+    )));
+    let index = std::fs::read_to_string(fixture.output.join("index.html")).unwrap();
+    assert!(index.contains(&format!("data-recmix=\"jam1//{}\"", lq_ogg.replace('/', "&#x2f;"))));
+    let playlist = std::fs::read_to_string(fixture.output.join("playlist.m3u")).unwrap();
+    let lq_playlist = std::fs::read_to_string(fixture.output.join("playlist.lq.m3u")).unwrap();
+    assert_eq!(lq_playlist.lines().count(), playlist.lines().count());
+    assert!(lq_playlist.contains(&format!("/jam1/{}", lq_ogg)));
+    assert!(!playlist.contains(".lq.ogg"));
+
+    // and it's all kept in the metadata
+    cb_processor::write_metadata(&season, &fixture.output.join("metadata.json"), false).unwrap();
+    let cached: Season = serde_json::from_slice(&std::fs::read(fixture.output.join("metadata.json")).unwrap()).unwrap();
+    let from_metadata = Season::load(&ctx, &fixture.season_json, None, Some(&cached)).unwrap();
+    assert_eq!(from_metadata.recordings[0].stereo_mix.lq_ogg_bytes, mix.lq_ogg_bytes);
+    assert_eq!(from_metadata.low_quality_ogg, season.low_quality_ogg);
+}
+
+#[test]
+fn tagged_files() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    let pipeline = Pipeline::new(&ctx);
+    let season = pipeline.load(&fixture.season_json, Some(&fixture.audio), None).unwrap();
+    let conversions = pipeline
+        .convert(&season, &fixture.audio, ConvertOptions::default())
+        .unwrap()
+        .conversions;
+
+    // the stereo mix is named after the recording, and the other tracks after themselves
+    let stereo = &conversions[0];
+    assert!(stereo.output.ends_with("jam1/ogg/jam1_stereo.ogg"));
+    assert_eq!(stereo.tags.title, "S01E01 - Jam 1");
+    assert_eq!(stereo.tags.artist, "Colin Benders");
+    assert_eq!(stereo.tags.date, "2021-01-02");
+    assert_eq!(stereo.tags.track, 1);
+    let kick = conversions
+        .iter()
+        .find(|c| c.output.ends_with("jam1_kick.ogg"))
+        .unwrap();
+    assert_eq!(
+        (kick.tags.title.as_str(), kick.tags.album.as_str()),
+        ("Kick", "S01E01 - Jam 1")
+    );
+    let (_, record) = cb_processor::provenance::find(&stereo.output).unwrap().unwrap();
+    assert!(record
+        .command
+        .windows(2)
+        .any(|w| w == ["-metadata", "title=S01E01 - Jam 1"]));
+    assert!(record
+        .command
+        .windows(2)
+        .any(|w| w == ["-metadata", "genre=techno, ambient"]));
+
+    // retagging rewrites what's there without converting anything
+    let options = ConvertOptions {
+        retag: true,
+        ..ConvertOptions::default()
+    };
+    let report = pipeline.convert(&season, &fixture.audio, options).unwrap();
+    assert!(report.conversions.is_empty());
+    assert_eq!(report.retagged.len(), conversions.len());
+    assert_eq!(
+        std::fs::read_to_string(&stereo.output).unwrap(),
+        "converted from jam1_stereo.ogg\n"
+    );
+    assert!(!stereo.output.with_file_name(".tmp.jam1_stereo.ogg").exists());
+}
+
+#[test]
+fn opus_files() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    edit_json(&fixture.recording_json("jam1"), |json| {
+        json["stereo_mix"]["opus"] = "opus/{FLACBASE}.opus".into()
+    });
+
+    // the oggs, and the stereo mix's opus
+    assert_eq!(fixture.validate(&ctx), 4);
+    let season = fixture.load(&ctx);
+    let conversions = cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(conversions.len(), 4);
+    let opus: Vec<_> = conversions
+        .iter()
+        .filter(|c| c.output.extension().is_some_and(|ext| ext == "opus"))
+        .collect();
+    assert_eq!(opus.len(), 1);
+    assert!(opus[0].output.ends_with("jam1/opus/jam1_stereo.opus"));
+    assert_eq!(opus[0].encoder_args, ["-c:a", "libopus"]);
+    assert_eq!(fixture.validate(&ctx), 0);
+
+    let season = fixture.load(&ctx);
+    let mix = &season.recordings[0].stereo_mix;
+    assert_eq!(mix.opus.as_deref(), Some("opus/jam1_stereo.opus"));
+    assert!(mix.opus_size_bytes() > 0);
+    assert!(season.recordings[0].tracks.iter().all(|t| t.opus.is_none()));
+
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains("<source src=\"opus/jam1_stereo.opus\" type=\"audio/ogg; codecs=opus\" />"));
+    assert!(page.contains("<a href=\"opus/jam1_stereo.opus\" download>Opus</a>"));
+
+    // metadata from before there were opus files doesn't mention them, and still loads
+    cb_processor::write_metadata(&season, &fixture.output.join("metadata.json"), false).unwrap();
+    let mut cached: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(fixture.output.join("metadata.json")).unwrap()).unwrap();
+    let cached_mix = cached["recordings"][0]["stereo_mix"].as_object_mut().unwrap();
+    assert_eq!(cached_mix["opus_bytes"], mix.opus_bytes);
+    for key in ["opus", "opus_info", "opus_bytes"] {
+        cached_mix.remove(key);
+    }
+    let cached: Season = serde_json::from_value(cached).unwrap();
+    assert!(cached.recordings[0].stereo_mix.opus.is_none());
+    assert_eq!(cached.recordings[0].stereo_mix.opus_bytes, 0);
+}
+
+#[test]
+fn stale_conversions() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    let season = fixture.load(&ctx);
+    assert!(cb_processor::convert_all(&ctx, &season)
+        .unwrap()
+        .iter()
+        .all(|c| !c.replaced));
+
+    // jam1's stereo mix is replaced with a new master after its ogg was made
+    let flac = fixture.audio.join("jam1/jam1_stereo.flac");
+    let earlier = std::fs::metadata(&flac).unwrap().modified().unwrap() - std::time::Duration::from_secs(10);
+    std::fs::File::options()
+        .write(true)
+        .open(fixture.audio.join("jam1/ogg/jam1_stereo.ogg"))
+        .unwrap()
+        .set_modified(earlier)
+        .unwrap();
+    let season = fixture.load(&ctx);
+    let mix = &season.recordings[0].stereo_mix;
+    assert_eq!(
+        cb_processor::freshness(mix, &mix.ogg_ondisk().unwrap(), mix.ogg_info.as_ref()),
+        Freshness::Stale
+    );
+    assert_eq!(cb_processor::count_up_to_date(&ctx, &season).unwrap(), 2);
+    let conversions = cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(conversions.len(), 1);
+    assert_eq!(conversions[0].input, flac);
+    assert!(conversions[0].replaced);
+    assert!(cb_processor::convert_all(&ctx, &season).unwrap().is_empty());
+
+    // an ogg that isn't as long as its flac is stale too, however new it is
+    let mut kick = season.recordings[0].tracks[0].clone();
+    let ogg = kick.ogg_ondisk().unwrap();
+    assert_eq!(
+        cb_processor::freshness(&kick, &ogg, kick.ogg_info.as_ref()),
+        Freshness::UpToDate
+    );
+    kick.ogg_info.as_mut().unwrap().duration = "3.5".to_string();
+    assert_eq!(
+        cb_processor::freshness(&kick, &ogg, kick.ogg_info.as_ref()),
+        Freshness::Stale
+    );
+
+    // --force-reconvert converts everything again
+    ctx.force_reconvert = true;
+    assert_eq!(cb_processor::count_up_to_date(&ctx, &season).unwrap(), 0);
+    let conversions = cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(conversions.len(), 3);
+    assert!(conversions.iter().all(|c| c.replaced));
+}
+
+#[test]
+fn converted_files_are_cached() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    let cache = std::sync::Arc::new(MediaInfoCache::open(&fixture.audio, false, ctx.retention.media_cache));
+    ctx.media_cache = Some(cache.clone());
+    let season = fixture.load(&ctx);
+    assert_eq!(cb_processor::convert_all(&ctx, &season).unwrap().len(), 3);
+
+    // what the conversion found out about the oggs is what the next load uses, without running mediainfo again
+    let ogg = fixture.audio.join("jam1/ogg/jam1_stereo.ogg");
+    assert_eq!(cache.get(&ogg).unwrap().duration, "1.091");
+    ctx.tools.mediainfo = fixture.root.join("no-mediainfo");
+    let season = fixture.load(&ctx);
+    assert_eq!(
+        season.recordings[0].stereo_mix.ogg_info.as_ref().unwrap().duration,
+        "1.091"
+    );
+}
+
+#[test]
+fn nested_stems() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+
+    // jam3's stems are sorted into folders, and its oggs and mp3s go in the same folders
+    let flac = fixture.root.join("audio/jam1/jam1_stereo.flac");
+    for name in [
+        "jam3_stereo.flac",
+        "stems/drums/02 kick.flac",
+        "stems/synths/03 pad.flac",
+    ] {
+        let path = fixture.audio.join("jam3").join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::copy(&flac, path).unwrap();
+    }
+    let jam3 = serde_json::json!({
+        "$schema": "../schema/recording.json",
+        "title": "S01E03 - Jam 3",
+        "recorded_date": "2021/01/16",
+        "data_folder": "jam3",
+        "stereo_mix": {"id": 1, "name": "Stereo mix", "flac": "jam3_stereo.flac", "vorbis": "ogg/{FLACBASE}.ogg"},
+        "tags": ["techno"],
+        "tracks": [
+            {
+                "id": 2,
+                "name": "Kick",
+                "flac": "stems/drums/02 kick.flac",
+                "vorbis": "ogg/stems/drums/{FLACBASE}.ogg",
+                "mp3": "mp3/stems/drums/{FLACBASE}.mp3"
+            },
+            {"id": 3, "name": "Pad", "flac": "stems/synths/03 pad.flac", "vorbis": "ogg/stems/synths/{FLACBASE}.ogg"}
+        ]
+    });
+    std::fs::write(
+        fixture.root.join("data/recordings/jam3.json"),
+        serde_json::to_vec_pretty(&jam3).unwrap(),
+    )
+    .unwrap();
+    edit_json(&fixture.season_json, |json| {
+        json["recordings"]
+            .as_array_mut()
+            .unwrap()
+            .push("recordings/jam3.json".into());
+    });
+
+    // every ogg, and the kick's mp3
+    assert_eq!(fixture.validate(&ctx), 7);
+    let season = fixture.load(&ctx);
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(fixture.validate(&ctx), 0);
+    assert!(fixture.audio.join("jam3/ogg/stems/drums/02 kick.ogg").exists());
+    assert!(fixture.audio.join("jam3/mp3/stems/drums/02 kick.mp3").exists());
+    assert!(fixture.audio.join("jam3/ogg/stems/synths/03 pad.ogg").exists());
+
+    let season = fixture.load(&ctx);
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("s01e03-jam-3/index.html")).unwrap();
+    assert!(page.contains("stems/drums/02%20kick.flac"), "{}", page);
+    assert!(page.contains("ogg/stems/synths/03%20pad.ogg"));
+    assert!(page.contains("mp3/stems/drums/02%20kick.mp3"));
+    assert!(fixture.output.join("jam3/style.css").exists());
+
+    // audio that's already published is left alone however deep it is, so only the new oggs are added
+    #[cfg(feature = "ipfs")]
+    {
+        use std::str::FromStr;
+        use support::{fake_ipfs, FAKE_FOLDER, FAKE_ROOT};
+
+        let ipfs = fixture.root.join("ipfs");
+        ctx.tools.ipfs = fake_ipfs(&ipfs, &["jam3/"]);
+        let links: Vec<_> = ["stems", "drums", "synths", "mp3"]
+            .iter()
+            .map(|name| serde_json::json!({"Name": name, "Hash": FAKE_FOLDER, "Size": 1}))
+            .chain(
+                ["jam3_stereo.flac", "02 kick.flac", "03 pad.flac", "02 kick.mp3"]
+                    .iter()
+                    .map(|name| serde_json::json!({"Name": name, "Hash": FAKE_ROOT, "Size": 1})),
+            )
+            .collect();
+        std::fs::write(
+            ipfs.join("objects").join(format!("{}.json", FAKE_FOLDER)),
+            serde_json::to_vec(&serde_json::json!({ "Links": links })).unwrap(),
+        )
+        .unwrap();
+        ctx.only = cb_processor::select::Selector::new(["jam3"]).unwrap();
+        let plan =
+            cb_processor::ipfs::plan_patch(&ctx, &cid::Cid::from_str(FAKE_ROOT).unwrap(), &fixture.audio).unwrap();
+        let changed: Vec<_> = plan.changed_links().into_iter().map(|c| c.path).collect();
+        assert_eq!(changed, ["jam3/ogg"]);
+    }
+}
+
+#[test]
+fn unreadable_duration() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    let season = fixture.converted(&ctx);
+
+    // jam1's metadata has a duration that can't be read
+    let metadata = fixture.root.join("metadata.json");
+    cb_processor::write_metadata(&season, &metadata, false).unwrap();
+    let mut json: serde_json::Value = serde_json::from_slice(&std::fs::read(&metadata).unwrap()).unwrap();
+    json["recordings"][0]["stereo_mix"]["media_info"]["Duration"] = "about a minute".into();
+    let cached: Season = serde_json::from_value(json).unwrap();
+    let season = Season::load(&ctx, &fixture.season_json, None, Some(&cached)).unwrap();
+
+    // the playlist says its length is unknown, and the rest of the season is written as usual
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let playlist = std::fs::read_to_string(fixture.output.join("playlist.m3u")).unwrap();
+    assert!(
+        playlist.contains("#EXTINF:-1,Colin Benders - S01E01 - Jam 1\n"),
+        "{}",
+        playlist
+    );
+    assert!(
+        playlist.contains("#EXTINF:1,Colin Benders - S01E02 - Jam 2\n"),
+        "{}",
+        playlist
+    );
+    assert!(fixture.output.join("s01e01-jam-1/index.html").exists());
+    assert!(fixture.output.join("jam2/index.html").exists());
+}
+
+#[test]
+fn missing_linked_files() {
+    let fixture = FixtureSeason::new();
+    edit_json(&fixture.recording_json("jam1"), |json| {
+        json["torrent"] = "jam1.torrent".into()
+    });
+    let mut ctx = fake_tools_context();
+    let season = fixture.load(&ctx);
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    std::fs::remove_file(fixture.audio.join("jam1/ogg/jam1_kick.ogg")).unwrap();
+
+    // the page is written without the links to the missing ogg and torrent
+    let season = fixture.load(&ctx);
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(!page.contains("jam1_kick.ogg"), "{}", page);
+    assert!(!page.contains("jam1.torrent"), "{}", page);
+    assert!(page.contains("jam1_stereo.ogg"), "{}", page);
+
+    ctx.strict_links = true;
+    let e = cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap_err();
+    let message = format!("{:#}", e);
+    assert!(message.contains("jam1_kick.ogg"), "{}", message);
+    assert!(message.contains("jam1.torrent"), "{}", message);
+}
+
+#[test]
+fn broken_references() {
+    let fixture = FixtureSeason::new();
+    edit_json(&fixture.recording_json("jam1"), |json| {
+        json["description"] = "The [notes](notes%20v2.pdf) and the [plan](../plan.txt)".into()
+    });
+    let ctx = fake_tools_context();
+    let pipeline = Pipeline::new(&ctx);
+    let season = pipeline.load(&fixture.season_json, Some(&fixture.audio), None).unwrap();
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    let season = pipeline.load(&fixture.season_json, Some(&fixture.audio), None).unwrap();
+    let generation = || Generation {
+        season_json: &fixture.season_json,
+        output: &fixture.output,
+        data_dir: Some(&fixture.audio),
+        metadata: None,
+        force_metadata: false,
+    };
+
+    // the page's links are relative to the recording's audio, which has neither file
+    match pipeline.generate(&season, generation()).unwrap_err() {
+        CbError::BrokenReferences { references } => {
+            let targets: Vec<_> = references.iter().map(|r| r.target.as_deref().unwrap()).collect();
+            assert_eq!(targets, ["jam1/notes v2.pdf", "plan.txt"], "{:#?}", references);
+            assert!(references.iter().all(|r| r.page == "s01e01-jam-1/index.html"));
+        }
+        e => panic!("{}", e),
+    }
+
+    // they're found next to the flacs, or in the output
+    std::fs::write(fixture.audio.join("jam1/notes v2.pdf"), "notes").unwrap();
+    std::fs::write(fixture.output.join("plan.txt"), "plan").unwrap();
+    pipeline.generate(&season, generation()).unwrap();
+}
+
+#[test]
+fn metadata_index() {
+    let fixture = FixtureSeason::new();
+    let index = fixture.output.join("metadata.index.json");
+    let mut ctx = fake_tools_context();
+    let season = fixture.load(&ctx);
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    let pipeline = Pipeline::new(&ctx);
+    let season = pipeline
+        .load(&fixture.season_json, Some(&fixture.audio), Some(&index))
+        .unwrap();
+    let generated = pipeline
+        .generate(
+            &season,
+            Generation {
+                season_json: &fixture.season_json,
+                output: &fixture.output,
+                data_dir: Some(&fixture.audio),
+                metadata: Some(&index),
+                force_metadata: false,
+            },
+        )
+        .unwrap();
+    assert!(generated.written.contains(&fixture.output.join("metadata/jam1.json")));
+    let manifest = BuildManifest::load(&fixture.output).unwrap().unwrap();
+    assert!(manifest.files.contains_key("metadata/jam2.json"));
+
+    // with --only, the other recordings aren't read at all
+    std::fs::remove_file(fixture.output.join("metadata/jam1.json")).unwrap();
+    ctx.only = cb_processor::select::Selector::new(["jam2"]).unwrap();
+    let pipeline = Pipeline::new(&ctx);
+    let cached = pipeline.load(&fixture.season_json, None, Some(&index)).unwrap();
+    assert_eq!(cached.recordings.len(), 1);
+    assert_eq!(cached.recordings[0].data_folder, "jam2");
+    assert_eq!(
+        serde_json::to_value(&cached.recordings[0].stereo_mix.media_info).unwrap(),
+        serde_json::to_value(&season.recordings[1].stereo_mix.media_info).unwrap()
+    );
+}
+
+#[test]
+fn generate_selected_from_index() {
+    let fixture = FixtureSeason::new();
+    let index = fixture.output.join("metadata.index.json");
+    let mut ctx = fake_tools_context();
+    let season = fixture.converted(&ctx);
+    let generation = |data_dir, force_metadata| Generation {
+        season_json: &fixture.season_json,
+        output: &fixture.output,
+        data_dir,
+        metadata: Some(&index),
+        force_metadata,
+    };
+    Pipeline::new(&ctx)
+        .generate(&season, generation(Some(&fixture.audio), false))
+        .unwrap();
+    let jam1 = fixture.output.join("metadata/jam1.json");
+    let before = std::fs::read(&jam1).unwrap();
+
+    // generating only jam2 from the index, even with --force-metadata, leaves jam1 as it was
+    ctx.only = cb_processor::select::Selector::new(["jam2"]).unwrap();
+    let pipeline = Pipeline::new(&ctx);
+    let selected = pipeline.load(&fixture.season_json, None, Some(&index)).unwrap();
+    assert_eq!(selected.recordings.len(), 1);
+    for force_metadata in [false, true] {
+        let generated = pipeline.generate(&selected, generation(None, force_metadata)).unwrap();
+        assert!(generated.written.contains(&fixture.output.join("metadata/jam2.json")));
+        assert!(!generated.written.contains(&jam1));
+        assert_eq!(std::fs::read(&jam1).unwrap(), before);
+    }
+
+    // and the whole season still loads from the index
+    ctx.only = cb_processor::select::Selector::default();
+    let reloaded = Pipeline::new(&ctx)
+        .load(&fixture.season_json, None, Some(&index))
+        .unwrap();
+    let folders: Vec<_> = reloaded.recordings.iter().map(|r| r.data_folder.as_str()).collect();
+    assert_eq!(folders, ["jam1", "jam2"]);
+}
+
+#[test]
+fn listen_only_stems() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+
+    // the fixture's kick is streamed, but not offered for download
+    let season = fixture.converted(&ctx);
+    let kick = &season.recordings[0].tracks[0];
+    assert_eq!(kick.usage, Usage::ListenOnly);
+    assert!(!kick.downloadable());
+    assert!(season.recordings[0].stereo_mix.downloadable());
+    assert!(season.recordings[0].mirror_links()[0]
+        .files
+        .iter()
+        .all(|(name, _)| name != "jam1_kick.flac"));
+    assert_eq!(fixture.validate(&ctx), 0);
+
+    // a recording is published as its stereo mix, so that can't be listen only
+    edit_json(&fixture.recording_json("jam1"), |json| {
+        json["stereo_mix"]["usage"] = "listen_only".into()
+    });
+    assert_eq!(fixture.validate(&ctx), 1);
+}
+
+#[test]
+#[cfg(feature = "ipfs")]
+fn shared_drafts() {
+    let fixture = FixtureSeason::new();
+    let ctx = fake_tools_context();
+    let season = fixture.load(&ctx);
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    edit_json(&fixture.recording_json("jam1"), |json| json["draft"] = true.into());
+
+    // the draft is left out of the season, but not out of its share
+    let season = fixture.load(&ctx);
+    assert!(season.recordings.iter().all(|r| r.data_folder != "jam1"));
+    let shared = fixture.root.join("share");
+    let recording = share::prepare(&ctx, &fixture.season_json, &fixture.audio, "jam1", &shared).unwrap();
+    assert_eq!(recording.slug, "s01e01-jam-1");
+    let page = std::fs::read_to_string(shared.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains("S01E01 - Jam 1"));
+    assert!(shared.join("jam1/jam1_stereo.flac").exists());
+    assert!(shared.join("jam1/ogg/jam1_stereo.ogg").exists());
+    // the kick can be listened to but not downloaded
+    assert!(shared.join("jam1/ogg/jam1_kick.ogg").exists());
+    assert!(!shared.join("jam1/jam1_kick.flac").exists());
+    // and nothing else of the season is in it
+    assert!(!shared.join("jam2").exists());
+    assert!(!shared.join("index.html").exists());
+    let playlist = std::fs::read_to_string(shared.join("playlist.m3u")).unwrap();
+    assert!(!playlist.contains("jam2"));
+
+    let e = share::prepare(
+        &ctx,
+        &fixture.season_json,
+        &fixture.audio,
+        "jam3",
+        &fixture.root.join("share3"),
+    )
+    .unwrap_err();
+    assert!(e.to_string().contains("\"jam3\""), "{}", e);
+}
+
+#[test]
+fn download_counts() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    ctx.stats_file = fixture.root.join("stats.json");
+    let season = fixture.converted(&ctx);
+
+    let get = |path: &str, status: u16| {
+        format!(
+            "203.0.113.7 - - [02/Jan/2021:10:00:00 +0000] \"GET {} HTTP/1.1\" {} 1234 \"-\" \"curl/7.68.0\"\n",
+            path, status
+        )
+    };
+    let log = [
+        get(
+            "/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/jam1/jam1_kick.flac",
+            200,
+        ),
+        get(
+            "/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/s01e01-jam-1/ogg/jam1_kick.ogg?filename=kick.ogg",
+            200,
+        ),
+        // a player seeking
+        get(
+            "/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/jam1/ogg/jam1_kick.ogg",
+            206,
+        ),
+        get(
+            "/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/jam1/jam1_stereo.flac",
+            200,
+        ),
+        get(
+            "/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/jam9/jam9_stereo.flac",
+            200,
+        ),
+        get("/ipfs/QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh/index.html", 200),
+        "not a log line\n".to_string(),
+    ]
+    .concat();
+    let format = regex::Regex::new(stats::COMBINED_LOG_FORMAT).unwrap();
+
+    let mut counts = stats::Stats::load(&ctx.stats_file).unwrap();
+    let report = stats::import(&mut counts, &season, log.as_bytes(), &format);
+    assert_eq!(report.requests, 6);
+    assert_eq!(report.unparsed, 1);
+    assert_eq!(report.counted, 3);
+    assert_eq!(report.other_files, 1);
+    assert_eq!(report.unmatched.get("jam9/jam9_stereo.flac"), Some(&1));
+    counts.save(&ctx.stats_file).unwrap();
+
+    // the same log again doesn't count
+    let mut counts = stats::Stats::load(&ctx.stats_file).unwrap();
+    assert!(stats::import(&mut counts, &season, log.as_bytes(), &format).already_imported);
+    assert_eq!(counts.downloads["jam1"][&2], 2);
+
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains("downloaded 2 times"), "{}", page);
+    assert!(page.contains("downloaded once"), "{}", page);
+}
+
+#[test]
+fn waveform_peaks() {
+    let fixture = FixtureSeason::new();
+    let mut ctx = fake_tools_context();
+    ctx.peak_buckets = 4;
+    let season = fixture.load(&ctx);
+    cb_processor::convert_all(&ctx, &season).unwrap();
+    assert_eq!(peaks::render_missing(&ctx, &season).unwrap(), 3);
+    // only the flacs that changed are decoded again
+    assert_eq!(peaks::render_missing(&ctx, &season).unwrap(), 0);
+
+    // the fake ffmpeg "decodes" a line of text, far fewer samples than the flac says it has, so it's all one bucket
+    let json = std::fs::read(fixture.audio.join("jam1/ogg/jam1_kick.peaks.json")).unwrap();
+    let kick: peaks::Peaks = serde_json::from_slice(&json).unwrap();
+    assert_eq!((kick.version, kick.channels, kick.bits), (2, 1, 8));
+    assert_eq!(kick.samples_per_pixel, 52345_u64.div_ceil(4));
+    assert_eq!(kick.length, 1);
+    assert_eq!(kick.data.len(), 2);
+    assert!(kick.data[0] <= kick.data[1]);
+
+    let season = fixture.load(&ctx);
+    cb_processor::write_all_recording_index(&ctx, &season, &fixture.output).unwrap();
+    let page = std::fs::read_to_string(fixture.output.join("s01e01-jam-1/index.html")).unwrap();
+    assert!(page.contains("data-peaks=\"ogg/jam1_kick.peaks.json\""), "{}", page);
+}
+
+#[cfg(feature = "ipfs")]
+#[test]
+fn import_from_ipfs() {
+    use std::str::FromStr;
+    use support::{fake_ipfs, FAKE_ADDED, FAKE_FOLDER, FAKE_ROOT};
+
+    const JAM2_FOLDER: &str = "QmS4ustL54uo8FzR9455qaxZwuMiUhyvMcX9Ba8nUH4uVv";
+    const FLAC: &str = "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH";
+    const UNREADABLE_FLAC: &str = "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+    const OGG: &str = "QmPZ9gcCEpqKTo6aq61g2nXGUhM4iCL3ewB6LDXZCtioEB";
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut ctx = fake_tools_context();
+    let ipfs = dir.path().join("ipfs");
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &[]);
+    let objects = ipfs.join("objects");
+    let folder = |cid: &str, links: &[(&str, &str, u64)]| {
+        let links: Vec<_> = links
+            .iter()
+            .map(|(name, hash, size)| serde_json::json!({"Name": name, "Hash": hash, "Size": size}))
+            .collect();
+        std::fs::write(
+            objects.join(format!("{}.json", cid)),
+            serde_json::json!({ "Links": links }).to_string(),
+        )
+        .unwrap();
+    };
+    folder(FAKE_ROOT, &[("jam1", FAKE_FOLDER, 200), ("jam2", JAM2_FOLDER, 60)]);
+    folder(
+        FAKE_FOLDER,
+        &[
+            ("jam1_stereo.flac", FLAC, 60),
+            ("jam1_kick.flac", UNREADABLE_FLAC, 70),
+            ("ogg", FAKE_ADDED, 62),
+        ],
+    );
+    folder(FAKE_ADDED, &[("jam1_stereo.ogg", OGG, 32), ("jam1_kick.ogg", OGG, 30)]);
+    // jam2's ogg was never published
+    folder(JAM2_FOLDER, &[("jam2_stereo.flac", FLAC, 60)]);
+    std::fs::copy(
+        fixtures().join("season/audio/jam1/jam1_stereo.flac"),
+        objects.join(format!("{}.data", FLAC)),
+    )
+    .unwrap();
+    std::fs::write(objects.join(format!("{}.data", OGG)), "ogg").unwrap();
+
+    let season_json = fixtures().join("season/data/season.json");
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let (season, report) = cb_processor::ipfs_import::import_season(&ctx, &season_json, &root).unwrap();
+    assert_eq!((report.found, report.analyzed), (5, 4));
+    assert_eq!(report.warnings.len(), 2, "{:?}", report.warnings);
+    assert!(report.warnings[0].starts_with("`S01E01 - Jam 1` track 2: couldn't read the media info of jam1_kick.flac"));
+    assert_eq!(
+        report.warnings[1],
+        format!("`S01E02 - Jam 2` track 1: ogg/jam2_stereo.ogg is not in {}", FAKE_ROOT)
+    );
+
+    let jam1 = &season.recordings[0];
+    assert_eq!(jam1.stereo_mix.media_info.format, "FLAC");
+    assert_eq!((jam1.stereo_mix.flac_bytes, jam1.stereo_mix.ogg_bytes), (60, 32));
+    assert_eq!(jam1.stereo_mix.cids["jam1_stereo.flac"], FLAC);
+    assert_eq!(jam1.stereo_mix.cids["ogg/jam1_stereo.ogg"], OGG);
+    assert!(jam1.stereo_mix.import_warnings.is_empty());
+    // what's known about the kick comes from its ogg
+    let kick = &jam1.tracks[0];
+    assert_eq!(kick.name, "Kick");
+    assert_eq!(kick.flac_bytes, 70);
+    assert_eq!(kick.media_info.format, "Vorbis");
+    assert_eq!(kick.import_warnings.len(), 1);
+    let jam2 = &season.recordings[1];
+    assert_eq!((jam2.stereo_mix.flac_bytes, jam2.stereo_mix.ogg_bytes), (60, 0));
+    assert!(jam2.stereo_mix.ogg_info.is_none());
+
+    // the metadata can be generated from like any other
+    let md_file = dir.path().join("metadata.json");
+    cb_processor::write_metadata(&season, &md_file, false).unwrap();
+    let cached: Season = serde_json::from_slice(&std::fs::read(&md_file).unwrap()).unwrap();
+    let season = Season::load(&ctx, &season_json, None, Some(&cached)).unwrap();
+    assert_eq!(season.recordings[0].tracks[0].import_warnings.len(), 1);
+    assert_eq!(season.recordings[0].stereo_mix.cids.len(), 2);
+    let output = dir.path().join("output");
+    cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+    assert!(output.join("s01e01-jam-1/index.html").exists());
+}
@@ -0,0 +1,418 @@
+//! Shared by the integration tests and the benchmarks (included with `#[path]` from benches/), so that both work on
+//! the same kinds of season
+#![allow(dead_code)]
+
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    time::Duration,
+};
+
+use cb_processor::{
+    context::{MediaInfoBackend, RunContext},
+    types::Season,
+};
+use serde_json::json;
+
+pub fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+pub fn copy_dir(from: &Path, to: &Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in from.read_dir().unwrap() {
+        let entry = entry.unwrap();
+        let dst = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &dst);
+        } else {
+            std::fs::copy(entry.path(), dst).unwrap();
+        }
+    }
+}
+
+/// A context that uses the fake tools
+pub fn fake_tools_context() -> RunContext {
+    let mut ctx = RunContext::default();
+    ctx.tools.ffmpeg = fixtures().join("bin/ffmpeg");
+    ctx.tools.mediainfo = fixtures().join("bin/mediainfo");
+    ctx.tools.brotli = fixtures().join("bin/brotli");
+    ctx.media_info_backend = MediaInfoBackend::Mediainfo;
+    ctx.static_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("static");
+    ctx.jobs = 1;
+    ctx
+}
+
+/// A copy of the season in tests/fixtures/season, in a temporary folder that tests can change as they like
+pub struct FixtureSeason {
+    pub dir: tempfile::TempDir,
+    /// Where the copy is, canonicalized so that it matches the paths the library reports
+    pub root: PathBuf,
+    pub season_json: PathBuf,
+    pub audio: PathBuf,
+    /// Where the pages go, which doesn't exist until something is generated into it
+    pub output: PathBuf,
+}
+
+impl FixtureSeason {
+    pub fn new() -> FixtureSeason {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        copy_dir(&fixtures().join("season"), &root);
+        FixtureSeason {
+            season_json: root.join("data/season.json"),
+            audio: root.join("audio"),
+            output: root.join("output"),
+            root,
+            dir,
+        }
+    }
+
+    /// The JSON file of the recording in `data_folder`
+    pub fn recording_json(&self, data_folder: &str) -> PathBuf {
+        self.root.join("data/recordings").join(format!("{}.json", data_folder))
+    }
+
+    /// Loads the season with its audio
+    pub fn load(&self, ctx: &RunContext) -> Season {
+        Season::load(ctx, &self.season_json, Some(&self.audio), None).unwrap()
+    }
+
+    /// Converts every flac, and loads the season again now that the oggs exist
+    pub fn converted(&self, ctx: &RunContext) -> Season {
+        cb_processor::convert_all(ctx, &self.load(ctx)).unwrap();
+        self.load(ctx)
+    }
+
+    /// How many problems validating the season finds
+    pub fn validate(&self, ctx: &RunContext) -> usize {
+        cb_processor::validate_and_print(ctx, &self.season_json, &self.audio).unwrap()
+    }
+
+    /// Writes an executable script called `name` next to the season, returning its path
+    pub fn script(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.root.join(name);
+        write_script(&path, contents);
+        path
+    }
+}
+
+/// Changes a JSON file in place
+pub fn edit_json(path: &Path, edit: impl FnOnce(&mut serde_json::Value)) {
+    let mut json = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+    edit(&mut json);
+    std::fs::write(path, serde_json::to_vec_pretty(&json).unwrap()).unwrap();
+}
+
+/// Writes a script that can be run
+pub fn write_script(path: &Path, contents: &str) {
+    std::fs::write(path, contents).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+/// A season that only exists as JSON and metadata (there's no audio), made up to be as big as needed
+pub struct SyntheticSeason {
+    pub dir: tempfile::TempDir,
+    pub season_json: PathBuf,
+    /// What metadata.json would contain for this season
+    pub metadata: Season,
+}
+
+/// Makes a season of `recordings` recordings, each with a stereo mix and `tracks` other tracks
+pub fn synthetic_season(recordings: usize, tracks: usize) -> SyntheticSeason {
+    let dir = tempfile::tempdir().unwrap();
+    let data = dir.path().join("data");
+    copy_dir(&fixtures().join("season/data/schema"), &data.join("schema"));
+    std::fs::create_dir_all(data.join("recordings")).unwrap();
+
+    let track = |id: usize, name: &str, flac: &str| {
+        json!({
+            "id": id,
+            "name": name,
+            "flac": flac,
+            "vorbis": "ogg/{FLACBASE}.ogg",
+        })
+    };
+    let media_info = json!({
+        "@type": "Audio",
+        "Format": "FLAC",
+        "Channels": "2",
+        "SamplingRate": "48000",
+        "BitDepth": "24",
+        "Duration": "3600.000",
+    });
+    let cached_track = |id: usize, name: &str, flac: &str| {
+        let base = flac.trim_end_matches(".flac");
+        json!({
+            "id": id,
+            "name": name,
+            "flac": flac,
+            "vorbis": format!("ogg/{}.ogg", base),
+            "mp3": null,
+            "patch_notes": null,
+            "ondisk_root": null,
+            "media_info": media_info,
+            "ogg_info": null,
+            "flac_bytes": 1_000_000_000u64,
+            "ogg_bytes": 80_000_000u64,
+            "mp3_bytes": 0,
+        })
+    };
+
+    let mut paths = Vec::new();
+    let mut cached = Vec::new();
+    for r in 0..recordings {
+        let folder = format!("jam{:03}", r);
+        let title = format!("S01E{:03} - Jam {}", r, r);
+        let date = format!("2021/{:02}/{:02}", r % 12 + 1, r % 28 + 1);
+        let tags = json!(["techno", format!("tag{}", r % 7)]);
+        let stereo = format!("{}_stereo.flac", folder);
+        let others: Vec<(usize, String, String)> = (0..tracks)
+            .map(|t| {
+                (
+                    t + 2,
+                    format!("Track {}", t + 1),
+                    format!("{}_track{:02}.flac", folder, t + 1),
+                )
+            })
+            .collect();
+
+        let recording = json!({
+            "$schema": "../schema/recording.json",
+            "title": title,
+            "recorded_date": date,
+            "data_folder": folder,
+            "bpm": "120",
+            "stereo_mix": track(1, "Stereo mix", &stereo),
+            "tags": tags,
+            "tracks": others.iter().map(|(id, name, flac)| track(*id, name, flac)).collect::<Vec<_>>(),
+        });
+        let path = format!("recordings/{}.json", folder);
+        std::fs::write(data.join(&path), serde_json::to_vec_pretty(&recording).unwrap()).unwrap();
+        paths.push(path);
+
+        cached.push(json!({
+            "title": title,
+            "data_folder": folder,
+            "stereo_mix": cached_track(1, "Stereo mix", &stereo),
+            "recorded_date": date,
+            "torrent": null,
+            "tracks": others.iter().map(|(id, name, flac)| cached_track(*id, name, flac)).collect::<Vec<_>>(),
+            "tags": tags,
+            "bpm": "120",
+            "youtube_url": null,
+        }));
+    }
+
+    let season_json = data.join("season.json");
+    let season = json!({
+        "$schema": "./schema/season.json",
+        "title": "Synthetic Season",
+        "artist": "Synthesizer",
+        "recordings": paths,
+    });
+    std::fs::write(&season_json, serde_json::to_vec_pretty(&season).unwrap()).unwrap();
+
+    let metadata = serde_json::from_value(json!({
+        "title": "Synthetic Season",
+        "recordings": cached,
+    }))
+    .unwrap();
+    SyntheticSeason {
+        dir,
+        season_json,
+        metadata,
+    }
+}
+
+/// The root object that the fake ipfs starts out with
+pub const FAKE_ROOT: &str = "QmPkzy9kPR9U5V3bNdHix3DcfR86e2dNefnGMkX9CVo1Wh";
+/// Every folder under the fake root (which is always empty)
+pub const FAKE_FOLDER: &str = "QmXdCEDuqTgR2gfmVUyYCojvmxqRuQaL97RGNDjozrYCxE";
+/// What the fake ipfs says anything it adds is
+pub const FAKE_ADDED: &str = "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn";
+/// What the fake ipfs says the total size of anything is
+pub const FAKE_DAG_SIZE: u64 = 123_456;
+
+/// Sets up a fake `ipfs` command in `dir`, whose root object has a link for each of `names`
+///
+/// Names ending in `/` are folders.  The fake answers `object get` from JSON files (or fails, for an object with a
+/// `<cid>.missing` file in `objects`), `cat` from `<cid>.data` files (or fails, if there isn't one), `add` with
+/// [`FAKE_ADDED`], `object patch` with a root of [`FAKE_ADDED`], `resolve` of any path with [`FAKE_ADDED`], and
+/// `pin add` and `name publish` without doing anything; anything else fails.  Every command it gets is appended to `calls` in `dir`.  Returns the path of the
+/// command, to use as `ctx.tools.ipfs`.
+pub fn fake_ipfs(dir: &Path, names: &[&str]) -> PathBuf {
+    let objects = dir.join("objects");
+    std::fs::create_dir_all(&objects).unwrap();
+    let links: Vec<_> = names
+        .iter()
+        .map(|name| {
+            let (name, hash) = match name.strip_suffix('/') {
+                Some(folder) => (folder, FAKE_FOLDER),
+                None => (*name, FAKE_ROOT),
+            };
+            json!({"Name": name, "Hash": hash, "Size": 1})
+        })
+        .collect();
+    std::fs::write(
+        objects.join(format!("{}.json", FAKE_ROOT)),
+        serde_json::to_vec(&json!({ "Links": links })).unwrap(),
+    )
+    .unwrap();
+
+    let script = dir.join("ipfs");
+    write_script(
+        &script,
+        &format!(
+            r#"#!/bin/sh
+# fake ipfs, made by tests/support
+echo "$*" >> "{calls}"
+while [ "${{1#--}}" != "$1" ]; do shift; done
+case "$1 $2" in
+    "object get")
+        if [ -f "{objects}/$3.missing" ]; then echo "Error: block was not found locally (offline)" >&2; exit 1; fi
+        if [ -f "{objects}/$3.json" ]; then cat "{objects}/$3.json"; else echo '{{"Links": []}}'; fi ;;
+    "cat "*)
+        for arg; do last=$arg; done
+        data="{objects}/${{last#/ipfs/}}.data"
+        if [ -f "$data" ]; then cat "$data"; else echo "Error: no link named $3" >&2; exit 1; fi ;;
+    "add "*) echo {added} ;;
+    "object patch") echo '{{"Hash": "{added}"}}' ;;
+    "resolve -r") echo /ipfs/{added} ;;
+    "files stat") echo '{{"CumulativeSize": {dag_size}}}' ;;
+    "pin add") echo "pinned $4 recursively" ;;
+    "name publish") echo "Published to fake: $4" ;;
+    *) echo "the fake ipfs can't $*" >&2; exit 1 ;;
+esac
+"#,
+            objects = objects.display(),
+            calls = dir.join("calls").display(),
+            added = FAKE_ADDED,
+            dag_size = FAKE_DAG_SIZE
+        ),
+    );
+    script
+}
+
+/// How long a real IPFS daemon gets to start
+const DAEMON_STARTUP: Duration = Duration::from_secs(60);
+
+/// A real `ipfs daemon` with a throwaway repo, for the tests that need one (which are `#[ignore]`d, since they need
+/// ipfs installed)
+///
+/// The repo is made with the `test` profile, so the API and the gateway listen on random ports of 127.0.0.1 and
+/// nothing is bootstrapped, and the daemon runs offline.  It's killed when this is dropped, which also happens when an
+/// assertion fails, and its repo is deleted after.
+pub struct IpfsDaemon {
+    /// Killed before `repo` is deleted, since fields are dropped after [`Drop::drop`]
+    child: Child,
+    /// The daemon's `IPFS_PATH`
+    pub repo: tempfile::TempDir,
+    /// The multiaddr of the API, to use as `ctx.ipfs_api`
+    pub api: String,
+    /// The gateway's base URL, like `http://127.0.0.1:8080`
+    pub gateway: String,
+}
+
+impl IpfsDaemon {
+    /// Initializes a repo in a temporary folder and starts a daemon on it, waiting until it's ready
+    pub fn start() -> IpfsDaemon {
+        let repo = tempfile::tempdir().unwrap();
+        let init = Command::new("ipfs")
+            .args(["init", "--profile=test"])
+            .env("IPFS_PATH", repo.path())
+            .output()
+            .expect("Failed to run `ipfs init`, is ipfs installed?");
+        assert!(init.status.success(), "ipfs init failed: {:?}", init);
+
+        let mut child = Command::new("ipfs")
+            .args(["daemon", "--offline"])
+            .env("IPFS_PATH", repo.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .unwrap();
+        // read the output on another thread, so that waiting for it can time out (and the pipe never fills up)
+        let stdout = child.stdout.take().unwrap();
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                // nobody listens once the daemon is ready
+                let _ = sender.send(line);
+            }
+        });
+        let mut daemon = IpfsDaemon {
+            child,
+            repo,
+            api: String::new(),
+            gateway: String::new(),
+        };
+
+        let mut seen = Vec::new();
+        loop {
+            let line = match lines.recv_timeout(DAEMON_STARTUP) {
+                Ok(line) => line,
+                Err(e) => panic!("The ipfs daemon didn't get ready ({}), it said: {:#?}", e, seen),
+            };
+            // "RPC API server listening on /ip4/127.0.0.1/tcp/38521", or "API server ..." before kubo 0.18, and
+            // "Gateway server listening on ...", or "Gateway (readonly) server ..."
+            if let Some((server, addr)) = line.split_once(" server listening on ") {
+                if server.starts_with("Gateway") {
+                    let port = addr.trim().rsplit('/').next().unwrap();
+                    daemon.gateway = format!("http://127.0.0.1:{}", port);
+                } else if server.ends_with("API") {
+                    daemon.api = addr.trim().to_string();
+                }
+            }
+            if line.contains("Daemon is ready") {
+                break;
+            }
+            seen.push(line);
+        }
+        assert!(
+            !daemon.api.is_empty(),
+            "The ipfs daemon didn't say where its API is: {:#?}",
+            seen
+        );
+        assert!(
+            !daemon.gateway.is_empty(),
+            "The ipfs daemon didn't say where its gateway is: {:#?}",
+            seen
+        );
+        daemon
+    }
+
+    /// The fake tools, with ipfs talking to this daemon
+    pub fn context(&self) -> RunContext {
+        let mut ctx = fake_tools_context();
+        ctx.ipfs_api = Some(self.api.clone());
+        ctx
+    }
+
+    /// Makes an empty folder, to patch a root into, returning its CID
+    pub fn empty_root(&self) -> String {
+        let output = self
+            .context()
+            .ipfs_command()
+            .args(["object", "new", "unixfs-dir"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "ipfs object new failed: {:?}", output);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+}
+
+impl Drop for IpfsDaemon {
+    fn drop(&mut self) {
+        // already gone if it died
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,481 @@
+//! Loading, generating and patch planning on a big made-up season (the same one the benchmarks use)
+#![cfg(all(unix, feature = "templates", feature = "ipfs"))]
+
+mod support;
+
+use std::str::FromStr;
+
+use cb_processor::{error::CbError, ipfs::LinkChange, types::Season};
+use support::{fake_ipfs, fake_tools_context, synthetic_season, FAKE_ADDED, FAKE_DAG_SIZE, FAKE_FOLDER, FAKE_ROOT};
+
+#[test]
+fn load_from_metadata() {
+    let synthetic = synthetic_season(100, 3);
+    let ctx = fake_tools_context();
+    let mut season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    assert_eq!(season.recordings.len(), 100);
+    assert_eq!(season.recordings[42].data_folder, "jam042");
+    assert_eq!(season.recordings[42].tracks.len(), 3);
+    assert_eq!(season.recordings[42].duration(), "60m 0s");
+
+    // no groups means a single list, in the original order
+    let recording = &season.recordings[42];
+    let groups = recording.track_groups();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].name, None);
+    assert_eq!(groups[0].tracks.len(), 3);
+
+    let recording = &mut season.recordings[42];
+    recording.tracks[0].group = Some("Synths".to_string());
+    recording.tracks[2].group = Some("Drums".to_string());
+    let recording = &season.recordings[42];
+    let groups: Vec<_> = recording
+        .track_groups()
+        .iter()
+        .map(|g| (g.name.unwrap(), g.tracks.iter().map(|t| t.id).collect::<Vec<_>>()))
+        .collect();
+    let ids: Vec<_> = recording.tracks.iter().map(|t| t.id).collect();
+    assert_eq!(
+        groups,
+        [
+            ("Drums", vec![ids[2]]),
+            ("Synths", vec![ids[0]]),
+            ("Other", vec![ids[1]])
+        ]
+    );
+
+    // what's written to metadata.json can be loaded again
+    let written = serde_json::to_vec(&season).unwrap();
+    let reloaded: Season = serde_json::from_slice(&written).unwrap();
+    assert_eq!(serde_json::to_vec(&reloaded).unwrap(), written);
+}
+
+#[test]
+fn published_draft() {
+    let synthetic = synthetic_season(3, 1);
+    let ctx = fake_tools_context();
+    // the metadata is from before jam001 was published, so it doesn't have it
+    let mut metadata = synthetic.metadata;
+    metadata.recordings.remove(1);
+    metadata.recordings[1].stereo_mix.flac_bytes = 42;
+
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&metadata)).unwrap();
+    let folders: Vec<_> = season.recordings.iter().map(|r| r.data_folder.as_str()).collect();
+    assert_eq!(folders, ["jam000", "jam002"]);
+    assert_eq!(season.recordings[1].stereo_mix.flac_bytes, 42);
+    assert_eq!(season.recordings[1].stereo_mix.flac, "jam002_stereo.flac");
+}
+
+#[test]
+fn plan_patch_with_fake_ipfs() {
+    let synthetic = synthetic_season(4, 1);
+    let mut ctx = fake_tools_context();
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let output = synthetic.dir.path().join("output");
+    cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+
+    ctx.tools.ipfs = fake_ipfs(
+        &synthetic.dir.path().join("ipfs"),
+        &["index.html", "jam000/", "jam001/", "gone.txt"],
+    );
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let plan = cb_processor::ipfs::plan_patch(&ctx, &root, &output).unwrap();
+
+    let change = |name: &str| {
+        plan.changes.iter().find(|c| match c {
+            LinkChange::Add { name: n, .. }
+            | LinkChange::Replace { name: n, .. }
+            | LinkChange::Patch { name: n, .. } => n == name,
+        })
+    };
+    assert!(matches!(change("index.html"), Some(LinkChange::Replace { .. })));
+    assert!(matches!(change("jam000"), Some(LinkChange::Patch { .. })));
+    assert!(matches!(change("jam003"), Some(LinkChange::Add { .. })));
+    assert!(matches!(change("style.css"), Some(LinkChange::Add { .. })));
+    assert!(change("gone.txt").is_none());
+
+    let sizes = cb_processor::ipfs::DagSizes::get(&ctx, &root).unwrap();
+    assert_eq!(sizes.total, FAKE_DAG_SIZE);
+    let names: Vec<_> = sizes.links.iter().map(|l| l.name.as_str()).collect();
+    assert_eq!(names, ["gone.txt", "index.html", "jam000", "jam001"]);
+}
+
+#[test]
+fn only_patch_with_fake_ipfs() {
+    let synthetic = synthetic_season(3, 1);
+    let mut ctx = fake_tools_context();
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let output = synthetic.dir.path().join("output");
+    cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_metadata(&season, &output.join("metadata.json"), false).unwrap();
+
+    ctx.tools.ipfs = fake_ipfs(
+        &synthetic.dir.path().join("ipfs"),
+        &["index.html", "css/", "jam000/", "jam001/", "s01e000-jam-0/"],
+    );
+    ctx.only = cb_processor::select::Selector::new(["jam001"]).unwrap();
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let plan = cb_processor::ipfs::plan_patch(&ctx, &root, &output).unwrap();
+    let mut changed: Vec<_> = plan
+        .changes
+        .iter()
+        .map(|c| match c {
+            LinkChange::Add { name, .. } | LinkChange::Replace { name, .. } | LinkChange::Patch { name, .. } => {
+                name.as_str()
+            }
+        })
+        .collect();
+    changed.sort_unstable();
+
+    // the selected recording's page is patched along with its data folder, and the styles are always patched
+    assert!(changed.contains(&"jam001"), "{:?}", changed);
+    assert!(changed.contains(&"s01e001-jam-1"), "{:?}", changed);
+    assert!(changed.contains(&"css"), "{:?}", changed);
+    assert!(changed.contains(&"index.html"), "{:?}", changed);
+    for left in ["jam000", "s01e000-jam-0", "jam002", "s01e002-jam-2"] {
+        assert!(!changed.contains(&left), "{} was patched: {:?}", left, changed);
+    }
+}
+
+#[test]
+fn drift_with_fake_ipfs() {
+    let synthetic = synthetic_season(2, 1);
+    let mut ctx = fake_tools_context();
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let generated = synthetic.dir.path().join("generated");
+    cb_processor::write_season_index(&ctx, &season, &generated).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &generated).unwrap();
+
+    // the published folders have audio in them, which isn't generated
+    ctx.tools.ipfs = fake_ipfs(
+        &synthetic.dir.path().join("ipfs"),
+        &["index.html", "jam000/", "jam001/", "s01e000-jam-0/"],
+    );
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let published = synthetic.dir.path().join(format!("ipfs/objects/{}.data", FAKE_ROOT));
+    let page = std::fs::read_to_string(generated.join("index.html")).unwrap();
+    let other_build = page.replace(cb_processor::GENERATOR, "cb_processor 0.0.1 (0000000)");
+    assert_ne!(page, other_build);
+
+    // a page made by another build of cb_processor hasn't drifted, even though its hash differs
+    std::fs::write(&published, &other_build).unwrap();
+    let plan = cb_processor::ipfs::plan_drift(&ctx, &root, &generated).unwrap();
+    assert!(!plan.changed_links().iter().any(|c| c.path == "index.html"));
+
+    std::fs::write(&published, other_build.replace("</body>", "<p>edited</p></body>")).unwrap();
+    let plan = cb_processor::ipfs::plan_drift(&ctx, &root, &generated).unwrap();
+    let changed = plan.changed_links();
+    let find = |path: &str| changed.iter().find(|c| c.path == path);
+
+    // the fake ipfs hashes everything to FAKE_ADDED
+    let index = find("index.html").unwrap();
+    assert_eq!(index.old.as_deref(), Some(FAKE_ROOT));
+    assert_eq!(index.new, FAKE_ADDED);
+    assert_eq!(find("jam000/style.css").unwrap().old, None);
+    assert!(find("s01e000-jam-0/index.html").is_some());
+    assert!(find("s01e001-jam-1").is_some());
+    assert_eq!(changed.len(), plan.num_changes());
+}
+
+#[test]
+fn check_root_with_fake_ipfs() {
+    let synthetic = synthetic_season(4, 1);
+    let mut ctx = fake_tools_context();
+    let season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let output = synthetic.dir.path().join("output");
+    cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_metadata(&season, &output.join("metadata.json"), false).unwrap();
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+
+    // the published root is a version of the season from before jam002 and jam003, and its metadata.json (which the
+    // fake ipfs cats from FAKE_ROOT.data) is for the same season
+    let ipfs = synthetic.dir.path().join("ipfs");
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &["index.html", "jam000/", "jam001/", "metadata.json"]);
+    let published = ipfs.join("objects").join(format!("{}.data", FAKE_ROOT));
+    std::fs::write(
+        &published,
+        format!(r#"{{"generator":"","title":{:?},"recordings":["#, season.title),
+    )
+    .unwrap();
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert_eq!(check.expected.len(), 5);
+    assert_eq!(check.missing, ["jam002", "jam003"]);
+    assert_eq!(check.seasons.len(), 1);
+    assert_eq!(check.seasons[0].published.as_deref(), Some(season.title.as_str()));
+    assert!(check.passed(), "{}", check.verdict());
+
+    // the root of another season
+    std::fs::write(&published, r#"{"generator":"","title":"Another season","recordings":["#).unwrap();
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert!(!check.passed());
+    assert!(check.verdict().contains("\"Another season\""), "{}", check.verdict());
+    let e = check.enforce(false).unwrap_err();
+    assert!(matches!(e, CbError::WrongRoot { .. }), "{}", e);
+    assert!(e.to_string().contains("--force"), "{}", e);
+    check.enforce(true).unwrap();
+    // which patch_root_object won't patch either
+    let e = cb_processor::ipfs::patch_root_object(&ctx, &root, &output).unwrap_err();
+    assert!(matches!(e, CbError::WrongRoot { .. }), "{}", e);
+    assert!(!std::fs::read_to_string(ipfs.join("calls"))
+        .unwrap()
+        .contains("object patch"));
+
+    // something else entirely, without a metadata.json
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &["cat.jpg", "jam000/"]);
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert_eq!(check.fraction_found(), 0.2);
+    assert_eq!(check.problems().len(), 1);
+    assert!(
+        check.problems()[0].contains("missing index.html, jam001"),
+        "{:?}",
+        check.problems()
+    );
+    ctx.expected_root_links = 0.2;
+    assert!(cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap().passed());
+
+    // a new site starts from an empty root
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &[]);
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert!(check.empty);
+    assert!(check.passed());
+}
+
+#[test]
+fn check_root_with_seasons_in_folders() {
+    let first = synthetic_season(3, 1);
+    let second = synthetic_season(2, 1);
+    let mut ctx = fake_tools_context();
+    // the output of the deploy: each season is generated into a folder of its own, and there's nothing at the top
+    let output = first.dir.path().join("deploy_output");
+    for (synthetic, folder, title) in [(&first, "S01", "Season 1"), (&second, "S02", "Season 2")] {
+        let mut season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+        season.title = title.to_string();
+        std::fs::create_dir_all(output.join(folder)).unwrap();
+        cb_processor::write_metadata(&season, &output.join(folder).join("metadata.json"), false).unwrap();
+    }
+    std::fs::create_dir_all(output.join("css")).unwrap();
+
+    // both seasons are in the root, without jam002 of the first yet, and their metadata.json (which the fake cats from
+    // FAKE_ROOT.data for both) is for the first season
+    let ipfs = first.dir.path().join("ipfs");
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &["S01/", "S02/", "css/"]);
+    std::fs::write(
+        ipfs.join("objects").join(format!("{}.json", FAKE_FOLDER)),
+        serde_json::to_vec(&serde_json::json!({ "Links": [
+            {"Name": "index.html", "Hash": FAKE_ROOT, "Size": 1},
+            {"Name": "metadata.json", "Hash": FAKE_ROOT, "Size": 1},
+            {"Name": "jam000", "Hash": FAKE_FOLDER, "Size": 1},
+            {"Name": "jam001", "Hash": FAKE_FOLDER, "Size": 1},
+        ]}))
+        .unwrap(),
+    )
+    .unwrap();
+    let published = ipfs.join("objects").join(format!("{}.data", FAKE_ROOT));
+    std::fs::write(&published, r#"{"generator":"","title":"Season 1","recordings":["#).unwrap();
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert_eq!(
+        check.expected,
+        [
+            "S01/index.html",
+            "S01/jam000",
+            "S01/jam001",
+            "S01/jam002",
+            "S02/index.html",
+            "S02/jam000",
+            "S02/jam001"
+        ]
+    );
+    assert_eq!(check.missing, ["S01/jam002"]);
+    let prefixes: Vec<_> = check.seasons.iter().map(|season| season.prefix.as_str()).collect();
+    assert_eq!(prefixes, ["S01/", "S02/"]);
+
+    // so S02's is for the wrong season
+    assert_eq!(check.problems().len(), 1);
+    assert!(
+        check.problems()[0].contains("S02/metadata.json is for the season \"Season 1\", not \"Season 2\""),
+        "{:?}",
+        check.problems()
+    );
+
+    // a root from before the second season is missing its links, but has no title of it to get wrong
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &["S01/", "css/"]);
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert_eq!(
+        check.missing,
+        ["S01/jam002", "S02/index.html", "S02/jam000", "S02/jam001"]
+    );
+    assert_eq!(check.seasons[1].published, None);
+    assert_eq!(check.problems().len(), 1);
+    assert!(
+        check.problems()[0].contains("3 of the 7 links"),
+        "{:?}",
+        check.problems()
+    );
+    ctx.expected_root_links = 0.4;
+    let check = cb_processor::ipfs::check_root(&ctx, &root, &output).unwrap();
+    assert!(check.passed(), "{}", check.verdict());
+    assert!(
+        check
+            .verdict()
+            .contains("and its S01/metadata.json is for \"Season 1\"\n"),
+        "{}",
+        check.verdict()
+    );
+}
+
+#[test]
+fn verify_with_fake_ipfs() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("output");
+    std::fs::create_dir_all(output.join("jam000/ogg")).unwrap();
+    std::fs::write(output.join("index.html"), "<html></html>").unwrap();
+    std::fs::write(output.join("jam000/ogg/jam000.ogg"), "ogg").unwrap();
+
+    let mut ctx = fake_tools_context();
+    ctx.tools.ipfs = fake_ipfs(&dir.path().join("ipfs"), &["index.html", "jam000/", "old.html"]);
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+
+    // every link in the fake ipfs is 1 byte, and every folder is empty
+    let found = cb_processor::ipfs::verify_patch(&ctx, &root, &output).unwrap();
+    let found: Vec<_> = found.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        found,
+        [
+            "index.html is 13 bytes, but its link in IPFS is 1 bytes",
+            "jam000/ogg is missing from IPFS",
+            "old.html is in IPFS, but not in the output"
+        ]
+    );
+
+    ctx.legacy_links = vec!["old.html".to_string()];
+    assert_eq!(cb_processor::ipfs::verify_patch(&ctx, &root, &output).unwrap().len(), 2);
+}
+
+/// Undoes percent-encoding, to check that a URL names exactly the file it should
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            decoded.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+#[test]
+fn awkward_file_names() {
+    const FLAC: &str = "Jam & Jelly #1 (café).flac";
+    const OGG: &str = "ogg/Jam & Jelly #1 (café).ogg";
+
+    let synthetic = synthetic_season(1, 1);
+    let mut ctx = fake_tools_context();
+    let mut season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    season.recordings[0].stereo_mix.flac = FLAC.to_string();
+    season.recordings[0].stereo_mix.vorbis = Some(OGG.to_string());
+    let output = synthetic.dir.path().join("output");
+    cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+
+    // every link on the page that points at the stereo mix (the first two are for downloading everything)
+    let page = std::fs::read_to_string(output.join("s01e000-jam-0/index.html")).unwrap();
+    let links: Vec<_> = page
+        .split('"')
+        .filter(|s| s.contains("Jelly"))
+        .map(percent_decode)
+        .collect();
+    assert_eq!(links, [OGG, FLAC, OGG, FLAC, OGG]);
+    assert!(page.contains("Jam%20%26%20Jelly%20%231%20%28caf%C3%A9%29.flac"));
+
+    let m3u = std::fs::read_to_string(output.join("playlist.m3u")).unwrap();
+    let url = m3u.lines().find(|l| l.contains("Jelly")).unwrap();
+    assert_eq!(percent_decode(url.rsplit("/jam000/").next().unwrap()), OGG);
+
+    // the link in IPFS is the file name, as it is on disk
+    std::fs::create_dir_all(output.join("jam000/ogg")).unwrap();
+    std::fs::write(output.join("jam000").join(OGG), "ogg").unwrap();
+    ctx.tools.ipfs = fake_ipfs(&synthetic.dir.path().join("ipfs"), &["jam000/"]);
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    let plan = cb_processor::ipfs::plan_patch(&ctx, &root, &output).unwrap();
+    let folder = plan
+        .changes
+        .iter()
+        .find_map(|c| match c {
+            LinkChange::Patch { name, plan } if name == "jam000" => Some(plan),
+            _ => None,
+        })
+        .unwrap();
+    assert!(folder
+        .changes
+        .iter()
+        .any(|c| matches!(c, LinkChange::Add { name, .. } if name == "ogg")));
+
+    for root_url in cb_processor::gateway::root_urls(&root).unwrap() {
+        let url = cb_processor::gateway::link_url(&root_url, &format!("jam000/{}", OGG)).unwrap();
+        assert_eq!(url.fragment(), None);
+        assert_eq!(url.query(), None);
+        let path = percent_decode(url.path());
+        assert!(path.ends_with(&format!("/jam000/{}", OGG)), "{} is {}", url, path);
+    }
+}
+
+#[test]
+fn flac_only_recording() {
+    let synthetic = synthetic_season(2, 1);
+    let ctx = fake_tools_context();
+    let mut season = Season::load(&ctx, &synthetic.season_json, None, Some(&synthetic.metadata)).unwrap();
+    let archival = &mut season.recordings[1];
+    archival.stereo_mix.vorbis = None;
+    archival.tracks[0].vorbis = None;
+    let output = synthetic.dir.path().join("output");
+    cb_processor::write_season_index(&ctx, &season, &output).unwrap();
+    cb_processor::write_all_recording_index(&ctx, &season, &output).unwrap();
+
+    let m3u = std::fs::read_to_string(output.join("playlist.m3u")).unwrap();
+    assert_eq!(m3u.lines().filter(|l| l.starts_with("#EXTINF")).count(), 1);
+    assert!(!m3u.contains("jam001"));
+
+    let page = std::fs::read_to_string(output.join("s01e001-jam-1/index.html")).unwrap();
+    assert!(!page.contains(".ogg"));
+    assert!(!page.contains("<audio"));
+    assert!(page.contains("jam001_stereo.flac"));
+    let page = std::fs::read_to_string(output.join("s01e000-jam-0/index.html")).unwrap();
+    assert!(page.contains("ogg/jam000_stereo.ogg"));
+
+    let index = std::fs::read_to_string(output.join("index.html")).unwrap();
+    assert!(index.contains("data-recmix=\"jam000"));
+    assert_eq!(index.matches("data-recmix").count(), 1);
+}
+
+#[test]
+fn missing_blocks_with_fake_ipfs() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut ctx = fake_tools_context();
+    let ipfs = dir.path().join("ipfs");
+    ctx.tools.ipfs = fake_ipfs(&ipfs, &["index.html", "jam000/"]);
+    let root = cid::Cid::from_str(FAKE_ROOT).unwrap();
+    assert!(cb_processor::ipfs::missing_blocks(&ctx, &root).unwrap().is_empty());
+    // everything is looked up offline
+    let calls = std::fs::read_to_string(ipfs.join("calls")).unwrap();
+    assert!(
+        calls.lines().all(|call| call.starts_with("--offline object get")),
+        "{}",
+        calls
+    );
+
+    std::fs::write(ipfs.join(format!("objects/{}.missing", FAKE_FOLDER)), "").unwrap();
+    assert_eq!(
+        cb_processor::ipfs::missing_blocks(&ctx, &root).unwrap(),
+        [format!("{}/jam000", FAKE_ROOT)]
+    );
+    std::fs::write(ipfs.join(format!("objects/{}.missing", FAKE_ROOT)), "").unwrap();
+    assert_eq!(cb_processor::ipfs::missing_blocks(&ctx, &root).unwrap(), [FAKE_ROOT]);
+}